@@ -0,0 +1,70 @@
+// Copyright 2022 CeresDB Project Authors. Licensed under Apache-2.0.
+
+//! Benchmark of routing concurrently by the route caches with different shard
+//! amounts, and every thread evicts its table now and then to take the write
+//! locks of the shards.
+//!
+//! Run it by `cargo bench --bench route_cache`.
+
+use std::{thread, time::Instant};
+
+use ceresdb_client::{model::route::Endpoint, MemoryRouteCache, RouteCache, RouteEntry, RouteKey};
+
+const THREADS: usize = 64;
+const TABLES_PER_THREAD: usize = 16;
+const ROUTES_PER_THREAD: usize = 20_000;
+
+fn route_entry(i: usize) -> RouteEntry {
+    RouteEntry {
+        endpoints: vec![Endpoint::new(format!("192.168.0.{}", i % 256), 8831)],
+        epoch: 0,
+        generation: i as u64,
+        expire_at: None,
+    }
+}
+
+fn route_key(i: usize) -> RouteKey {
+    ("db".to_string(), format!("table{i}"))
+}
+
+fn bench_concurrent_route(cache: &MemoryRouteCache) {
+    let entries = (0..THREADS * TABLES_PER_THREAD)
+        .map(|i| (route_key(i), route_entry(i)))
+        .collect();
+    cache.insert_batch(entries);
+
+    let begin = Instant::now();
+    thread::scope(|s| {
+        for thread in 0..THREADS {
+            s.spawn(move || {
+                let tables: Vec<_> = (0..TABLES_PER_THREAD)
+                    .map(|i| thread * TABLES_PER_THREAD + i)
+                    .collect();
+                for i in 0..ROUTES_PER_THREAD {
+                    let table = tables[i % TABLES_PER_THREAD];
+                    let keys = [route_key(table)];
+                    // Evict and route again like a cache miss.
+                    if i % 100 == 99 {
+                        cache.remove(&keys[0]);
+                        cache.insert_batch(vec![(keys[0].clone(), route_entry(table))]);
+                    }
+                    assert!(cache.get_batch(&keys)[0].is_some());
+                }
+            });
+        }
+    });
+
+    println!(
+        "shards:{}, routes:{}, elapsed:{:?}",
+        cache.shard_amount(),
+        THREADS * ROUTES_PER_THREAD,
+        begin.elapsed()
+    );
+}
+
+fn main() {
+    bench_concurrent_route(&MemoryRouteCache::with_shard_amount(2));
+    bench_concurrent_route(&MemoryRouteCache::with_shard_amount(8));
+    bench_concurrent_route(&MemoryRouteCache::default());
+    bench_concurrent_route(&MemoryRouteCache::with_shard_amount(1024));
+}
@@ -24,10 +24,7 @@ async fn create_table(client: &Arc<dyn DbClient>, rpc_ctx: &RpcContext) {
                 t timestamp NOT NULL,
                 TIMESTAMP KEY(t)) ENGINE=Analytic with
 (enable_ttl='false')"#;
-    let req = SqlQueryRequest {
-        tables: vec!["ceresdb".to_string()],
-        sql: create_table_sql.to_string(),
-    };
+    let req = SqlQueryRequest::new(vec!["ceresdb".to_string()], create_table_sql.to_string());
     let resp = client
         .sql_query(rpc_ctx, &req)
         .await
@@ -37,10 +34,7 @@ async fn create_table(client: &Arc<dyn DbClient>, rpc_ctx: &RpcContext) {
 
 async fn drop_table(client: &Arc<dyn DbClient>, rpc_ctx: &RpcContext) {
     let drop_table_sql = "DROP TABLE ceresdb";
-    let req = SqlQueryRequest {
-        tables: vec!["ceresdb".to_string()],
-        sql: drop_table_sql.to_string(),
-    };
+    let req = SqlQueryRequest::new(vec!["ceresdb".to_string()], drop_table_sql.to_string());
     let _resp = client
         .sql_query(rpc_ctx, &req)
         .await
@@ -103,10 +97,10 @@ async fn write(client: &Arc<dyn DbClient>, rpc_ctx: &RpcContext) {
 }
 
 async fn sql_query(client: &Arc<dyn DbClient>, rpc_ctx: &RpcContext) {
-    let req = SqlQueryRequest {
-        tables: vec!["ceresdb".to_string()],
-        sql: "select * from ceresdb;".to_string(),
-    };
+    let req = SqlQueryRequest::new(
+        vec!["ceresdb".to_string()],
+        "select * from ceresdb;".to_string(),
+    );
     let resp = client
         .sql_query(rpc_ctx, &req)
         .await
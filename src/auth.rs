@@ -0,0 +1,328 @@
+// Copyright 2022 CeresDB Project Authors. Licensed under Apache-2.0.
+
+//! Authentication by the tokens from a pluggable provider
+
+use std::{
+    fmt,
+    future::Future,
+    sync::{Arc, PoisonError, RwLock},
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use tonic::{
+    metadata::{Ascii, MetadataValue},
+    Code, Status,
+};
+
+use crate::{
+    clock::{Clock, SystemClock},
+    Error, Result,
+};
+
+/// Metadata key of the token in the request.
+pub(crate) const AUTHORIZATION_KEY: &str = "authorization";
+
+/// Provider of the token sent as the `authorization` metadata of every rpc.
+#[async_trait]
+pub trait AuthProvider: Send + Sync {
+    /// Get the current token, which is sent verbatim, e.g. `Bearer xxx`.
+    ///
+    /// It is called before every rpc, so it should return the cached token
+    /// quickly.
+    async fn get_token(&self) -> Result<String>;
+
+    /// Refresh the token after it is rejected by the server, and the rejected
+    /// rpc is retried once with the token got after refreshing.
+    async fn refresh(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Provider of a token never changed.
+#[derive(Debug, Clone)]
+pub struct StaticTokenProvider {
+    token: String,
+}
+
+impl StaticTokenProvider {
+    pub fn new(token: String) -> Self {
+        Self { token }
+    }
+}
+
+#[async_trait]
+impl AuthProvider for StaticTokenProvider {
+    async fn get_token(&self) -> Result<String> {
+        Ok(self.token.clone())
+    }
+}
+
+/// Cache the token of the inner provider, and fetch a new one `refresh_ahead`
+/// before it expires.
+pub struct CachingProvider<P> {
+    inner: P,
+    expiry: Duration,
+    refresh_ahead: Duration,
+    /// The cached token and when it is fetched.
+    cached: RwLock<Option<(String, Instant)>>,
+    // Make sure only one fetching is in progress.
+    fetch_lock: tokio::sync::Mutex<()>,
+    clock: Arc<dyn Clock>,
+}
+
+impl<P: AuthProvider> CachingProvider<P> {
+    pub fn new(inner: P, expiry: Duration, refresh_ahead: Duration) -> Self {
+        Self {
+            inner,
+            expiry,
+            refresh_ahead,
+            cached: RwLock::new(None),
+            fetch_lock: tokio::sync::Mutex::new(()),
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    /// Expire the cached token by the `clock` instead of the system time.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    fn fresh_token(&self) -> Option<String> {
+        let now = self.clock.now();
+        let cached = self.cached.read().unwrap_or_else(PoisonError::into_inner);
+        cached
+            .as_ref()
+            .filter(|(_, fetched_at)| now - *fetched_at + self.refresh_ahead < self.expiry)
+            .map(|(token, _)| token.clone())
+    }
+
+    async fn fetch_token(&self) -> Result<String> {
+        let token = self.inner.get_token().await?;
+        *self.cached.write().unwrap_or_else(PoisonError::into_inner) =
+            Some((token.clone(), self.clock.now()));
+        Ok(token)
+    }
+}
+
+#[async_trait]
+impl<P: AuthProvider> AuthProvider for CachingProvider<P> {
+    async fn get_token(&self) -> Result<String> {
+        if let Some(token) = self.fresh_token() {
+            return Ok(token);
+        }
+
+        let _fetch_guard = self.fetch_lock.lock().await;
+        // The token may be fetched by others during waiting for the lock.
+        if let Some(token) = self.fresh_token() {
+            return Ok(token);
+        }
+        self.fetch_token().await
+    }
+
+    async fn refresh(&self) -> Result<()> {
+        let _fetch_guard = self.fetch_lock.lock().await;
+        self.inner.refresh().await?;
+        self.fetch_token().await.map(|_| ())
+    }
+}
+
+/// Inject the token into the rpcs, and refresh it on the unauthenticated
+/// status.
+pub(crate) struct Authenticator {
+    provider: Arc<dyn AuthProvider>,
+    // Make sure only one refreshing is in progress.
+    refresh_lock: tokio::sync::Mutex<()>,
+}
+
+impl Authenticator {
+    pub fn new(provider: Arc<dyn AuthProvider>) -> Self {
+        Self {
+            provider,
+            refresh_lock: tokio::sync::Mutex::new(()),
+        }
+    }
+
+    /// Call the rpc with the token, and retry once with the refreshed token if
+    /// it is rejected.
+    ///
+    /// The outer error is from the provider, and the inner one is the status
+    /// of the rpc.
+    pub async fn call<O, C, Fut>(&self, call: C) -> Result<std::result::Result<O, Status>>
+    where
+        C: Fn(MetadataValue<Ascii>) -> Fut,
+        Fut: Future<Output = std::result::Result<O, Status>>,
+    {
+        let token = self.provider.get_token().await?;
+        match call(Self::metadata_value(&token)?).await {
+            Err(status) if status.code() == Code::Unauthenticated => {
+                self.refresh(&token).await?;
+                let token = self.provider.get_token().await?;
+                Ok(call(Self::metadata_value(&token)?).await)
+            }
+            result => Ok(result),
+        }
+    }
+
+    /// Call the rpc with the token only once, because the request can't be
+    /// sent again, e.g. a stream of requests.
+    ///
+    /// The token is still refreshed if it is rejected, so that the next rpc
+    /// will use the refreshed one.
+    pub async fn call_once<O, C, Fut>(&self, call: C) -> Result<std::result::Result<O, Status>>
+    where
+        C: FnOnce(MetadataValue<Ascii>) -> Fut,
+        Fut: Future<Output = std::result::Result<O, Status>>,
+    {
+        let token = self.provider.get_token().await?;
+        let result = call(Self::metadata_value(&token)?).await;
+        if matches!(&result, Err(status) if status.code() == Code::Unauthenticated) {
+            self.refresh(&token).await?;
+        }
+
+        Ok(result)
+    }
+
+    /// Refresh the `rejected` token, and nothing to do if it has been
+    /// refreshed by others.
+    async fn refresh(&self, rejected: &str) -> Result<()> {
+        let _refresh_guard = self.refresh_lock.lock().await;
+        if self.provider.get_token().await? != rejected {
+            return Ok(());
+        }
+
+        self.provider.refresh().await
+    }
+
+    fn metadata_value(token: &str) -> Result<MetadataValue<Ascii>> {
+        token
+            .parse()
+            .map_err(|_| Error::Client("token contains invalid characters".to_string()))
+    }
+}
+
+impl fmt::Debug for Authenticator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Authenticator")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use async_trait::async_trait;
+    use futures::future;
+    use tonic::Status;
+
+    use super::{AuthProvider, Authenticator, CachingProvider, StaticTokenProvider};
+    use crate::{clock::ManualClock, Result};
+
+    /// Provider whose token changes after every refresh, and counts the calls.
+    #[derive(Default)]
+    struct VersionedProvider {
+        version: AtomicUsize,
+        gets: AtomicUsize,
+        refreshes: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl AuthProvider for Arc<VersionedProvider> {
+        async fn get_token(&self) -> Result<String> {
+            self.gets.fetch_add(1, Ordering::Relaxed);
+            Ok(format!("token-{}", self.version.load(Ordering::Relaxed)))
+        }
+
+        async fn refresh(&self) -> Result<()> {
+            self.refreshes.fetch_add(1, Ordering::Relaxed);
+            self.version.fetch_add(1, Ordering::Relaxed);
+            Ok(())
+        }
+    }
+
+    /// Rpc only accepting the `valid_token`, and yield before returning so
+    /// that the concurrent calls are interleaved.
+    async fn rpc(token: String, valid_token: &str) -> std::result::Result<String, Status> {
+        tokio::task::yield_now().await;
+        if token == valid_token {
+            Ok(token)
+        } else {
+            Err(Status::unauthenticated("token expired"))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_refresh_and_retry() {
+        let provider = Arc::new(VersionedProvider::default());
+        let authenticator = Authenticator::new(Arc::new(provider.clone()));
+
+        let result = authenticator
+            .call(|token| rpc(token.to_str().unwrap().to_string(), "token-1"))
+            .await
+            .unwrap();
+        assert_eq!(result.unwrap(), "token-1");
+        assert_eq!(provider.refreshes.load(Ordering::Relaxed), 1);
+
+        // Retry only once.
+        let result = authenticator
+            .call(|token| rpc(token.to_str().unwrap().to_string(), "token-0"))
+            .await
+            .unwrap();
+        assert!(result.is_err());
+        assert_eq!(provider.refreshes.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn test_refresh_once_on_concurrent_failures() {
+        let provider = Arc::new(VersionedProvider::default());
+        let authenticator = Authenticator::new(Arc::new(provider.clone()));
+
+        let calls = (0..8).map(|_| {
+            authenticator.call(|token| rpc(token.to_str().unwrap().to_string(), "token-1"))
+        });
+        for result in future::join_all(calls).await {
+            assert_eq!(result.unwrap().unwrap(), "token-1");
+        }
+        assert_eq!(provider.refreshes.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn test_caching_provider() {
+        let provider = Arc::new(VersionedProvider::default());
+        let clock = ManualClock::new();
+        let caching = CachingProvider::new(
+            provider.clone(),
+            Duration::from_millis(100),
+            Duration::from_millis(50),
+        )
+        .with_clock(Arc::new(clock.clone()));
+
+        for _ in 0..3 {
+            assert_eq!(caching.get_token().await.unwrap(), "token-0");
+        }
+        assert_eq!(provider.gets.load(Ordering::Relaxed), 1);
+
+        // Fetched again ahead of the expiry.
+        clock.advance(Duration::from_millis(60));
+        assert_eq!(caching.get_token().await.unwrap(), "token-0");
+        assert_eq!(provider.gets.load(Ordering::Relaxed), 2);
+
+        caching.refresh().await.unwrap();
+        assert_eq!(caching.get_token().await.unwrap(), "token-1");
+        assert_eq!(provider.gets.load(Ordering::Relaxed), 3);
+    }
+
+    #[tokio::test]
+    async fn test_static_token_provider() {
+        let provider = StaticTokenProvider::new("Bearer abc".to_string());
+        provider.refresh().await.unwrap();
+        assert_eq!(provider.get_token().await.unwrap(), "Bearer abc");
+    }
+}
@@ -0,0 +1,347 @@
+// Copyright 2022 CeresDB Project Authors. Licensed under Apache-2.0.
+
+//! Capture of the encoded requests sent to the servers
+
+use std::{
+    fmt,
+    fs::{File, OpenOptions},
+    io::{self, Read, Write},
+    path::Path,
+    sync::{Arc, Mutex, PoisonError},
+};
+
+use async_trait::async_trait;
+use ceresdbproto::storage::{
+    RouteRequest as RouteRequestPb, SqlQueryRequest as QueryRequestPb,
+    SqlQueryResponse as QueryResponsePb, WriteRequest as WriteRequestPb,
+    WriteResponse as WriteResponsePb,
+};
+use futures::{
+    channel::mpsc::{self, UnboundedReceiver},
+    future::{self, Either},
+    StreamExt,
+};
+use prost::Message;
+
+use crate::{
+    interceptor::OperationKind,
+    model::route::Endpoint,
+    rpc_client::{RouteResponse, RpcClient, RpcContext, RpcResponse, ServerCapabilities},
+    Error, Result,
+};
+
+/// Sink of the requests sent to the servers, which is called with the
+/// protobuf encoded requests right before they are sent to every endpoint, so
+/// the request partitioned by the routes is captured once per partition.
+///
+/// The hooks are called before the [`RequestInterceptor`]s, so the requests
+/// aborted by them are captured too, and the bytes are the same as the ones
+/// encoded on the wire before compression.
+///
+/// The requests are encoded only if the capture is set, and the hooks are
+/// called synchronously in the rpcs, so they should be fast.
+///
+/// [`RequestInterceptor`]: crate::RequestInterceptor
+pub trait RequestCapture: fmt::Debug + Send + Sync {
+    /// Called with the encoded [`WriteRequest`](WriteRequestPb), including
+    /// every message of the stream writes.
+    fn on_write(&self, database: &str, endpoint: &Endpoint, encoded: &[u8]);
+
+    /// Called with the encoded [`SqlQueryRequest`](QueryRequestPb).
+    fn on_sql_query(&self, _database: &str, _endpoint: &Endpoint, _encoded: &[u8]) {}
+}
+
+/// Request captured by the [`FileCapture`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CapturedRequest {
+    /// Either [`Write`](OperationKind::Write) or
+    /// [`SqlQuery`](OperationKind::SqlQuery).
+    pub kind: OperationKind,
+    pub database: String,
+    pub endpoint: String,
+    pub encoded: Vec<u8>,
+}
+
+/// Capture appending the requests to a file, and every record is the kind
+/// byte (`0` for the write, `1` for the sql query) followed by the database,
+/// the endpoint and the encoded request, each prefixed by its length as the
+/// big-endian `u32`.
+///
+/// Every record is written by one call to the file without buffering, and
+/// the failures are logged and ignored.
+#[derive(Debug)]
+pub struct FileCapture {
+    file: Mutex<File>,
+}
+
+impl FileCapture {
+    /// Open the file at `path` to append the records to, and it is created if
+    /// not exists.
+    pub fn create(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| {
+                Error::Client(format!(
+                    "failed to open capture file, path:{}, err:{e}",
+                    path.display()
+                ))
+            })?;
+
+        Ok(Self {
+            file: Mutex::new(file),
+        })
+    }
+
+    /// Read all the records written by the [`FileCapture`] from the `reader`.
+    pub fn read_records(mut reader: impl Read) -> io::Result<Vec<CapturedRequest>> {
+        let mut records = Vec::new();
+        loop {
+            let mut kind = [0; 1];
+            match reader.read_exact(&mut kind) {
+                Ok(()) => (),
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(records),
+                Err(e) => return Err(e),
+            }
+            let kind = match kind[0] {
+                0 => OperationKind::Write,
+                1 => OperationKind::SqlQuery,
+                kind => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("unknown kind of captured request:{kind}"),
+                    ))
+                }
+            };
+
+            let database = read_string(&mut reader)?;
+            let endpoint = read_string(&mut reader)?;
+            let encoded = read_bytes(&mut reader)?;
+            records.push(CapturedRequest {
+                kind,
+                database,
+                endpoint,
+                encoded,
+            });
+        }
+    }
+
+    fn append(&self, kind: u8, database: &str, endpoint: &Endpoint, encoded: &[u8]) {
+        let endpoint = endpoint.to_string();
+        let mut record =
+            Vec::with_capacity(1 + 12 + database.len() + endpoint.len() + encoded.len());
+        record.push(kind);
+        for bytes in [database.as_bytes(), endpoint.as_bytes(), encoded] {
+            record.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+            record.extend_from_slice(bytes);
+        }
+
+        if let Err(_e) = self
+            .file
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .write_all(&record)
+        {
+            #[cfg(feature = "tracing")]
+            tracing::warn!(error = %_e, "failed to write the captured request");
+        }
+    }
+}
+
+impl RequestCapture for FileCapture {
+    fn on_write(&self, database: &str, endpoint: &Endpoint, encoded: &[u8]) {
+        self.append(0, database, endpoint, encoded);
+    }
+
+    fn on_sql_query(&self, database: &str, endpoint: &Endpoint, encoded: &[u8]) {
+        self.append(1, database, endpoint, encoded);
+    }
+}
+
+fn read_bytes(reader: &mut impl Read) -> io::Result<Vec<u8>> {
+    let mut len = [0; 4];
+    reader.read_exact(&mut len)?;
+    let mut bytes = vec![0; u32::from_be_bytes(len) as usize];
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+fn read_string(reader: &mut impl Read) -> io::Result<String> {
+    String::from_utf8(read_bytes(reader)?)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Rpc client passing the requests to the [`RequestCapture`] before sending
+/// them to the `endpoint` by the inner client.
+pub(crate) struct CapturingRpcClient {
+    inner: Arc<dyn RpcClient>,
+    endpoint: Endpoint,
+    capture: Arc<dyn RequestCapture>,
+}
+
+impl CapturingRpcClient {
+    pub fn new(
+        inner: Arc<dyn RpcClient>,
+        endpoint: Endpoint,
+        capture: Arc<dyn RequestCapture>,
+    ) -> Self {
+        Self {
+            inner,
+            endpoint,
+            capture,
+        }
+    }
+
+    fn capture_write(&self, req: &WriteRequestPb) {
+        let database = req.context.as_ref().map(|ctx| ctx.database.as_str());
+        self.capture.on_write(
+            database.unwrap_or_default(),
+            &self.endpoint,
+            &req.encode_to_vec(),
+        );
+    }
+}
+
+#[async_trait]
+impl RpcClient for CapturingRpcClient {
+    async fn sql_query(
+        &self,
+        ctx: &RpcContext,
+        req: QueryRequestPb,
+    ) -> Result<RpcResponse<QueryResponsePb>> {
+        let database = req.context.as_ref().map(|ctx| ctx.database.as_str());
+        self.capture.on_sql_query(
+            database.unwrap_or_default(),
+            &self.endpoint,
+            &req.encode_to_vec(),
+        );
+        self.inner.sql_query(ctx, req).await
+    }
+
+    async fn write(
+        &self,
+        ctx: &RpcContext,
+        req: WriteRequestPb,
+    ) -> Result<RpcResponse<WriteResponsePb>> {
+        self.capture_write(&req);
+        self.inner.write(ctx, req).await
+    }
+
+    async fn route(&self, ctx: &RpcContext, req: RouteRequestPb) -> Result<RouteResponse> {
+        self.inner.route(ctx, req).await
+    }
+
+    async fn stream_write(
+        &self,
+        ctx: &RpcContext,
+        mut reqs: UnboundedReceiver<WriteRequestPb>,
+    ) -> Result<RpcResponse<WriteResponsePb>> {
+        let (sender, receiver) = mpsc::unbounded();
+        let forward = async move {
+            while let Some(req) = reqs.next().await {
+                self.capture_write(&req);
+                if sender.unbounded_send(req).is_err() {
+                    break;
+                }
+            }
+        };
+
+        // The stream write may complete before the stream of requests ends,
+        // e.g. on failures.
+        let write = self.inner.stream_write(ctx, receiver);
+        match future::select(Box::pin(forward), Box::pin(write)).await {
+            Either::Left(((), write)) => write.await,
+            Either::Right((resp, _)) => resp,
+        }
+    }
+
+    async fn probe_capabilities(&self, ctx: &RpcContext) -> Result<ServerCapabilities> {
+        self.inner.probe_capabilities(ctx).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use ceresdbproto::storage::{
+        RequestContext as RequestContextPb, SqlQueryRequest as QueryRequestPb,
+        WriteRequest as WriteRequestPb, WriteResponse as WriteResponsePb,
+    };
+    use futures::{channel::mpsc, future};
+    use prost::Message;
+
+    use super::{CapturingRpcClient, FileCapture};
+    use crate::{
+        interceptor::OperationKind,
+        model::route::Endpoint,
+        rpc_client::{MockRpcClient, RpcClient, RpcContext},
+    };
+
+    #[tokio::test]
+    async fn test_file_capture() {
+        let path = std::env::temp_dir().join(format!("ceresdb-capture-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let endpoint = Endpoint::new("192.168.0.1".to_string(), 11);
+        let client = CapturingRpcClient::new(
+            // Every write is accepted.
+            Arc::new(MockRpcClient::default().on_write(|_, _| {
+                future::ready(Ok(WriteResponsePb {
+                    header: None,
+                    success: 1,
+                    failed: 0,
+                }
+                .into()))
+            })),
+            endpoint.clone(),
+            Arc::new(FileCapture::create(&path).unwrap()),
+        );
+
+        let reqs: Vec<_> = ["db1", "db2", "db3"]
+            .into_iter()
+            .map(|database| WriteRequestPb {
+                context: Some(RequestContextPb {
+                    database: database.to_string(),
+                }),
+                table_requests: Vec::new(),
+            })
+            .collect();
+        let ctx = RpcContext::default();
+        client.write(&ctx, reqs[0].clone()).await.unwrap();
+        let (sender, receiver) = mpsc::unbounded();
+        sender.unbounded_send(reqs[1].clone()).unwrap();
+        sender.unbounded_send(reqs[2].clone()).unwrap();
+        drop(sender);
+        let resp = client.stream_write(&ctx, receiver).await.unwrap();
+        assert_eq!(resp.resp.success, 2);
+        let query = QueryRequestPb {
+            context: Some(RequestContextPb {
+                database: "db1".to_string(),
+            }),
+            tables: Vec::new(),
+            sql: "SELECT 1".to_string(),
+        };
+        client.sql_query(&ctx, query.clone()).await.unwrap();
+
+        let records = FileCapture::read_records(std::fs::File::open(&path).unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(records.len(), 4);
+        for (record, req) in records.iter().zip(&reqs) {
+            assert_eq!(record.kind, OperationKind::Write);
+            assert_eq!(record.database, req.context.as_ref().unwrap().database);
+            assert_eq!(record.endpoint, endpoint.to_string());
+            assert_eq!(
+                &WriteRequestPb::decode(record.encoded.as_slice()).unwrap(),
+                req
+            );
+        }
+        assert_eq!(records[3].kind, OperationKind::SqlQuery);
+        assert_eq!(records[3].database, "db1");
+        assert_eq!(
+            QueryRequestPb::decode(records[3].encoded.as_slice()).unwrap(),
+            query
+        );
+    }
+}
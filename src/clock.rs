@@ -0,0 +1,192 @@
+// Copyright 2022 CeresDB Project Authors. Licensed under Apache-2.0.
+
+//! Source of the time used by the client
+
+#[cfg(any(test, feature = "testing"))]
+use std::sync::{Arc, Mutex};
+use std::{
+    fmt,
+    future::Future,
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+#[cfg(any(test, feature = "testing"))]
+use futures::channel::oneshot;
+
+/// Source of the time, including the cache ttls, the retry backoffs, the
+/// circuit breaker cooldowns, the hedge delays, the health check intervals and
+/// the latencies.
+///
+/// The timeouts of the rpcs are the grpc deadlines, which don't depend on it.
+#[async_trait]
+pub trait Clock: fmt::Debug + Send + Sync {
+    fn now(&self) -> Instant;
+
+    async fn sleep(&self, duration: Duration);
+}
+
+/// Clock of the system time, and the sleeping is done by the tokio timer.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+#[async_trait]
+impl Clock for SystemClock {
+    #[inline]
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    async fn sleep(&self, duration: Duration) {
+        tokio::time::sleep(duration).await
+    }
+}
+
+/// Require the `future` to complete within the `duration` measured by the
+/// `clock`, and none is returned if it times out.
+pub(crate) async fn timeout<F: Future>(
+    clock: &dyn Clock,
+    duration: Duration,
+    future: F,
+) -> Option<F::Output> {
+    tokio::select! {
+        biased;
+        output = future => Some(output),
+        _ = clock.sleep(duration) => None,
+    }
+}
+
+/// Clock whose time only moves forward by [`advance`](ManualClock::advance),
+/// so the tests depending on the time can run without waiting.
+///
+/// The clones share the same time.
+#[cfg(any(test, feature = "testing"))]
+#[derive(Debug, Clone)]
+pub struct ManualClock {
+    state: Arc<Mutex<ManualState>>,
+}
+
+#[cfg(any(test, feature = "testing"))]
+#[derive(Debug)]
+struct ManualState {
+    now: Instant,
+    /// The deadlines of the sleepers and the senders to wake them.
+    sleepers: Vec<(Instant, oneshot::Sender<()>)>,
+}
+
+#[cfg(any(test, feature = "testing"))]
+impl ManualClock {
+    pub fn new() -> Self {
+        Self {
+            state: Arc::new(Mutex::new(ManualState {
+                now: Instant::now(),
+                sleepers: Vec::new(),
+            })),
+        }
+    }
+
+    /// Move the time forward by `duration`, and wake the sleepers whose
+    /// deadlines are reached.
+    pub fn advance(&self, duration: Duration) {
+        let mut state = self.state.lock().unwrap();
+        state.now += duration;
+        let now = state.now;
+        let (woken, sleeping) = state
+            .sleepers
+            .drain(..)
+            .partition(|(deadline, _)| *deadline <= now);
+        state.sleepers = sleeping;
+        drop(state);
+
+        for (_, waker) in woken {
+            let _ = waker.send(());
+        }
+    }
+
+    /// Get the number of the sleepers not woken yet, which helps to wait until
+    /// a task goes to sleep.
+    pub fn sleepers(&self) -> usize {
+        let mut state = self.state.lock().unwrap();
+        state.sleepers.retain(|(_, waker)| !waker.is_canceled());
+        state.sleepers.len()
+    }
+}
+
+#[cfg(any(test, feature = "testing"))]
+impl Default for ManualClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(any(test, feature = "testing"))]
+#[async_trait]
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        self.state.lock().unwrap().now
+    }
+
+    async fn sleep(&self, duration: Duration) {
+        if duration.is_zero() {
+            return;
+        }
+        let woken = {
+            let mut state = self.state.lock().unwrap();
+            let (waker, woken) = oneshot::channel();
+            let deadline = state.now + duration;
+            state.sleepers.push((deadline, waker));
+            woken
+        };
+        let _ = woken.await;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::{timeout, Clock, ManualClock};
+
+    #[tokio::test]
+    async fn test_manual_clock() {
+        let clock = ManualClock::new();
+        let begin = clock.now();
+
+        let sleep = tokio::spawn({
+            let clock = clock.clone();
+            async move { clock.sleep(Duration::from_secs(10)).await }
+        });
+        while clock.sleepers() == 0 {
+            tokio::task::yield_now().await;
+        }
+
+        // Not woken before the deadline.
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(clock.sleepers(), 1);
+        clock.advance(Duration::from_secs(5));
+        sleep.await.unwrap();
+        assert_eq!(clock.sleepers(), 0);
+        assert_eq!(clock.now() - begin, Duration::from_secs(10));
+    }
+
+    #[tokio::test]
+    async fn test_timeout() {
+        let clock = ManualClock::new();
+        let ready = timeout(&clock, Duration::from_secs(1), async { 1 }).await;
+        assert_eq!(ready, Some(1));
+
+        let pending = timeout(
+            &clock,
+            Duration::from_secs(1),
+            futures::future::pending::<()>(),
+        );
+        let advance = async {
+            while clock.sleepers() == 0 {
+                tokio::task::yield_now().await;
+            }
+            clock.advance(Duration::from_secs(1));
+        };
+        let (timed_out, _) = futures::future::join(pending, advance).await;
+        assert_eq!(timed_out, None);
+    }
+}
@@ -4,6 +4,8 @@
 
 use std::time::Duration;
 
+use crate::rpc_client::SessionSettings;
+
 /// Config for the underlying grpc client
 #[derive(Debug, Clone)]
 pub struct RpcConfig {
@@ -17,6 +19,9 @@ pub struct RpcConfig {
     pub max_send_msg_len: i32,
     /// The max length of the message received from server.
     ///
+    /// It is only checked on the query responses after they are received,
+    /// because no limit can be enforced on receiving by the grpc client.
+    ///
     /// -1 means unlimited, and the default value is 1GB.
     pub max_recv_msg_len: i32,
     /// The interval for htt2 ping frames.
@@ -40,10 +45,672 @@ pub struct RpcConfig {
     ///
     /// Default value is 60s.
     pub default_sql_query_timeout: Duration,
+    /// Timeout for route operation.
+    ///
+    /// Default value is 5s.
+    pub default_route_timeout: Duration,
     /// Timeout for connection.
     ///
     /// Default value is 3s.
     pub connect_timeout: Duration,
+    /// Number of the consecutive transport failures, after which the channel
+    /// will be rebuilt from scratch.
+    ///
+    /// Default value is 3.
+    pub max_consecutive_failures: usize,
+    /// Rebuild the channel to an endpoint given as hostname on the first
+    /// transport failure, so that the hostname is resolved again by the new
+    /// channel, e.g. after the pod behind the DNS record is restarted.
+    ///
+    /// It only refreshes the address of the endpoint itself, and the endpoints
+    /// in the route cache are still evicted by the failed requests or the
+    /// route epoch. It is disabled by default, and the channel is rebuilt
+    /// after [`max_consecutive_failures`](Self::max_consecutive_failures)
+    /// then.
+    pub refresh_dns_on_failure: bool,
+    /// Max age of the channel to an endpoint, after which it is rebuilt
+    /// proactively, so that the hostname of the endpoint is resolved again
+    /// even if the channel never fails, e.g. the IPs behind the DNS record
+    /// change during a maintenance.
+    ///
+    /// The replacement is built by the first request finding the channel
+    /// expired, while the old channel keeps serving the other requests until
+    /// the replacement is ready, so no request fails by the rebuilding. The
+    /// old channel is kept if the rebuilding fails. It is disabled by default.
+    pub max_channel_age: Option<Duration>,
+    /// Timeout after which the channel to an endpoint without any request is
+    /// closed, and a new one is built by the next request, so that no request
+    /// is sent over the connection silently dropped by the intermediaries,
+    /// e.g. the NATs with aggressive timeouts.
+    ///
+    /// It complements the [`keep_alive_interval`](Self::keep_alive_interval)
+    /// for the intermediaries not honoring the pings, and the idle channel is
+    /// closed when the next request finds it idle. It is disabled by default.
+    pub idle_timeout: Option<Duration>,
+    /// Threshold to log the slow requests.
+    ///
+    /// It is disabled for all operations by default.
+    pub slow_request_threshold: SlowRequestThreshold,
+    /// Compress the messages with gzip or not.
+    ///
+    /// It is disabled by default.
+    pub enable_compression: bool,
+    /// The min size of the message to compress, and the smaller messages are
+    /// sent uncompressed even if compression is enabled.
+    ///
+    /// Default value is 1KB.
+    pub compression_min_size: usize,
+    /// Probe the newer features supported by every server on the first
+    /// contact, see [`ServerCapabilities`], and the unsupported ones are
+    /// disabled for the server without failing the requests, e.g. the streams
+    /// of writes are sent by the unary writes then.
+    ///
+    /// The capabilities are probed in the background once the channel is
+    /// built or rebuilt, so no request waits for the probing, and the
+    /// features are kept enabled until they are found unsupported. The
+    /// streaming write answered unimplemented by the server is also disabled
+    /// before the probing completes, and the writes are resent by the unary
+    /// writes then. It is disabled by default.
+    ///
+    /// [`ServerCapabilities`]: crate::ServerCapabilities
+    pub negotiate_capabilities: bool,
+    /// Delay after which the sql_query is hedged to another replica of the
+    /// routed table, if the one sent to the routed endpoint hasn't completed.
+    ///
+    /// At most one hedged query is issued for every sql_query, and the query
+    /// of the table without another replica or writes are never hedged. It
+    /// only works in `Direct` mode and is disabled by default.
+    pub sql_query_hedge_delay: Option<Duration>,
+    /// The max number of the hedged queries in flight, and the slow queries
+    /// beyond it are not hedged, to avoid amplifying the load of the slow
+    /// servers.
+    ///
+    /// Default value is 16.
+    pub sql_query_hedge_max_in_flight: usize,
+    /// Short-circuit the requests to an endpoint after consecutive transport
+    /// failures, and no circuit breaker if not set.
+    ///
+    /// In `Direct` mode, the short-circuited requests to the data nodes are
+    /// sent to the default endpoint instead, otherwise they fail fast. It is
+    /// disabled by default.
+    pub circuit_breaker: Option<CircuitBreakerConfig>,
+    /// Reconnect to an endpoint with the exponentially growing backoffs when
+    /// the channel fails to connect, e.g. during a brief server restart,
+    /// before the request gives up, and no reconnection if not set.
+    ///
+    /// It is disabled by default.
+    pub reconnect: Option<ReconnectConfig>,
+    /// Probe the endpoints in the route cache and the default endpoint in the
+    /// background, and no health check if not set.
+    ///
+    /// It only works in `Direct` mode, where the queries routed to the
+    /// unhealthy endpoints are sent to the default endpoint instead, and the
+    /// writes to them fail fast. It is disabled by default.
+    pub health_check: Option<HealthCheckConfig>,
+    /// Track the health of every endpoint by the outcomes of the requests to
+    /// it, without any probe, and no tracking if not set.
+    ///
+    /// It only works in `Direct` mode, where the unhealthy replicas of a table
+    /// are picked only if all of them are unhealthy, see
+    /// [`DbClient::is_healthy`](crate::DbClient::is_healthy). It is disabled
+    /// by default.
+    pub passive_health: Option<PassiveHealthConfig>,
+    /// Send the sql queries on a table to the endpoint that acknowledged the
+    /// latest write of it by the client for a while, so that the queries
+    /// right after the writes see them even if the table is served by
+    /// multiple replicas or moved meanwhile, and no such pinning if not set.
+    ///
+    /// The pinned endpoint overrides the
+    /// [`load_balance_policy`](Self::load_balance_policy) unless it is
+    /// unhealthy, and the queries are not hedged then. It only works in
+    /// `Direct` mode and is disabled by default.
+    pub read_your_writes: Option<ReadYourWritesConfig>,
+    /// Max number of the queries in flight at a time for a
+    /// `sql_query_batch`.
+    pub sql_query_batch_concurrency: usize,
+    /// How often the default endpoints are rediscovered if the
+    /// [`discovery`](crate::Builder::discovery) is set.
+    ///
+    /// Default value is 30s.
+    pub discovery_refresh_interval: Duration,
+    /// Proxy to connect to the servers through, and the proxy is taken from
+    /// the env vars `HTTPS_PROXY`/`HTTP_PROXY` and `NO_PROXY` if not set.
+    ///
+    /// Only the http proxies supporting the `CONNECT` method are supported,
+    /// see [`ProxyConfig`].
+    pub proxy: Option<ProxyConfig>,
+    /// The part of the [`timeout`](crate::RpcContext::timeout) of a request
+    /// that can be spent on routing in `Direct` mode, and the rest is left for
+    /// executing the request.
+    ///
+    /// It only takes effect if the timeout is set.
+    pub routing_budget: RoutingBudget,
+    /// The number of the shards of the route cache in `Direct` mode, and more
+    /// shards reduce the lock contention under high concurrency, see
+    /// [`RouterImpl::with_shard_amount`](crate::router::RouterImpl::with_shard_amount).
+    ///
+    /// The default of the `DashMap` is used if not set, which is 4 times the
+    /// number of the cpu cores rounded up to a power of two.
+    ///
+    /// It is ignored if the route cache is replaced by
+    /// [`Builder::route_cache`](crate::Builder::route_cache).
+    pub route_cache_shard_amount: Option<usize>,
+    /// How the endpoint of a table is picked in `Direct` mode if the route
+    /// service returns multiple candidates for it, e.g. the replicas.
+    ///
+    /// Default value is [`LoadBalancePolicy::First`].
+    pub load_balance_policy: LoadBalancePolicy,
+    /// Fail the requests on the tables unknown to the route service with
+    /// [`Error::RouteNotFound`](crate::Error::RouteNotFound) in `Direct` mode,
+    /// instead of sending them to the default endpoint, which catches the
+    /// misconfigured tables rather than writing them to the wrong place.
+    ///
+    /// Default value is false.
+    pub strict_routing: bool,
+    /// Default settings of the sessions the sql queries run in, which are
+    /// overridden by the ones set in the
+    /// [`RpcContext`](crate::RpcContext::settings).
+    ///
+    /// No setting is sent by default.
+    pub session_settings: SessionSettings,
+    /// How the session settings are sent to the server.
+    ///
+    /// Default value is [`SettingsTransport::Metadata`].
+    pub session_settings_transport: SettingsTransport,
+    /// Max number of the rows sent to an endpoint by one write rpc, and the
+    /// larger writes are split into the sub-requests whose responses are
+    /// merged.
+    ///
+    /// The tables are packed into the sub-requests as a whole if possible,
+    /// and only the tables with more rows than it are split themselves. It
+    /// is disabled by default.
+    pub max_rows_per_write: Option<usize>,
+    /// Max number of the sub-requests of a split write in flight at a time,
+    /// see [`max_rows_per_write`](Self::max_rows_per_write).
+    ///
+    /// Default value is 1, i.e. the sub-requests are sent sequentially.
+    pub split_write_concurrency: usize,
+}
+
+/// Config of the circuit breaker of every endpoint.
+#[derive(Debug, Clone)]
+pub struct CircuitBreakerConfig {
+    /// The number of the consecutive transport failures to open the breaker.
+    pub failure_threshold: usize,
+    /// How long the requests are short-circuited after the breaker opens,
+    /// and then one request is sent to probe whether the endpoint recovers.
+    pub cooldown: Duration,
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        Self {
+            failure_threshold: 5,
+            cooldown: Duration::from_secs(10),
+        }
+    }
+}
+
+/// Config of reconnecting the channel to an endpoint, see
+/// [`RpcConfig::reconnect`].
+///
+/// The reconnections of a request stop once the next backoff would exceed its
+/// timeout, and they are made within every attempt if the
+/// [`retry_policy`](crate::Builder::retry_policy) is set.
+#[derive(Debug, Clone)]
+pub struct ReconnectConfig {
+    /// The backoff before the first reconnection.
+    pub initial_backoff: Duration,
+    /// The upper bound of the backoff.
+    pub max_backoff: Duration,
+    /// The factor by which the backoff grows for every reconnection.
+    pub multiplier: u32,
+    /// The max number of the reconnections of a request.
+    pub max_reconnects: usize,
+}
+
+impl Default for ReconnectConfig {
+    fn default() -> Self {
+        Self {
+            initial_backoff: Duration::from_millis(50),
+            max_backoff: Duration::from_secs(2),
+            multiplier: 2,
+            max_reconnects: 5,
+        }
+    }
+}
+
+impl ReconnectConfig {
+    /// Get the backoff before the reconnection after `reconnects` ones.
+    pub(crate) fn backoff(&self, reconnects: usize) -> Duration {
+        let exp = u32::try_from(reconnects).unwrap_or(u32::MAX);
+        let backoff = self
+            .multiplier
+            .checked_pow(exp)
+            .and_then(|factor| self.initial_backoff.checked_mul(factor))
+            .unwrap_or(self.max_backoff);
+        backoff.min(self.max_backoff)
+    }
+}
+
+/// Config of the spill of the [`ResilientWriter`](crate::ResilientWriter).
+#[derive(Debug, Clone)]
+pub struct SpillConfig {
+    /// The max bytes of the spill file, beyond which the batches are handled
+    /// according to the [`full_policy`](Self::full_policy).
+    ///
+    /// Default value is 256MB.
+    pub max_spill_bytes: u64,
+    /// What to do if a batch doesn't fit into the spill.
+    ///
+    /// Default value is [`SpillFullPolicy::Error`].
+    pub full_policy: SpillFullPolicy,
+    /// The max number of the batches replayed concurrently, and the batches
+    /// are replayed strictly in order if it is 1.
+    ///
+    /// Default value is 1.
+    pub replay_concurrency: usize,
+    /// The backoff before replaying again after the first failure, which is
+    /// doubled for every consecutive failure.
+    ///
+    /// Default value is 100ms.
+    pub initial_replay_backoff: Duration,
+    /// The upper bound of the backoff between the replays.
+    ///
+    /// Default value is 10s.
+    pub max_replay_backoff: Duration,
+}
+
+impl Default for SpillConfig {
+    fn default() -> Self {
+        Self {
+            max_spill_bytes: 256 << 20,
+            full_policy: SpillFullPolicy::default(),
+            replay_concurrency: 1,
+            initial_replay_backoff: Duration::from_millis(100),
+            max_replay_backoff: Duration::from_secs(10),
+        }
+    }
+}
+
+impl SpillConfig {
+    /// Get the backoff before the replay after `failures` consecutive ones.
+    pub(crate) fn replay_backoff(&self, failures: u32) -> Duration {
+        let backoff = 2u32
+            .checked_pow(failures.saturating_sub(1))
+            .and_then(|factor| self.initial_replay_backoff.checked_mul(factor))
+            .unwrap_or(self.max_replay_backoff);
+        backoff.min(self.max_replay_backoff)
+    }
+}
+
+/// What the [`ResilientWriter`](crate::ResilientWriter) does if a batch
+/// doesn't fit into the spill.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SpillFullPolicy {
+    /// Wait until the replayed batches make room for it.
+    Block,
+    /// Drop the oldest batches to make room for it.
+    DropOldest,
+    /// Fail the write with [`Error::SpillFull`](crate::Error::SpillFull).
+    #[default]
+    Error,
+}
+
+/// Policy to pick the endpoint of a table among the candidates returned by
+/// the route service, and the only candidate is always picked.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LoadBalancePolicy {
+    /// Pick the first candidate.
+    #[default]
+    First,
+    /// Pick the candidates in turn.
+    RoundRobin,
+    /// Pick a candidate at random.
+    Random,
+    /// Pick the candidate with the fewest requests in flight from the client,
+    /// and the earlier one on a tie.
+    LeastConnections,
+}
+
+/// How the [`SessionSettings`] of the sql queries are sent to the server.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SettingsTransport {
+    /// Send the settings as the grpc metadata, i.e. `x-ceresdb-timezone`,
+    /// `x-ceresdb-consistency` and `x-ceresdb-setting-{name}` for the extra
+    /// ones.
+    #[default]
+    Metadata,
+    /// Prepend the settings to the sql as the `SET` statements in the same
+    /// request, for the servers supporting the settings only in sql, e.g.
+    /// `SET timezone = 'UTC'; SELECT ...`.
+    ///
+    /// The sql is rewritten right before it is sent, so the hooks, the
+    /// captures and the errors still see the original one.
+    Sql,
+}
+
+/// Budget of the time spent on routing out of the timeout of a request, which
+/// is the smaller of the `fraction` of the timeout and the `cap`.
+#[derive(Debug, Clone)]
+pub struct RoutingBudget {
+    /// The max fraction of the timeout spent on routing, in `[0, 1]`.
+    ///
+    /// Default value is 0.5.
+    pub fraction: f64,
+    /// The max time spent on routing regardless of the timeout, and no cap if
+    /// not set.
+    ///
+    /// It is not set by default.
+    pub cap: Option<Duration>,
+}
+
+impl Default for RoutingBudget {
+    fn default() -> Self {
+        Self {
+            fraction: 0.5,
+            cap: None,
+        }
+    }
+}
+
+/// Config of the background health checker of the endpoints.
+#[derive(Debug, Clone)]
+pub struct HealthCheckConfig {
+    /// How often every endpoint is probed.
+    ///
+    /// Default value is 10s.
+    pub interval: Duration,
+    /// The probe not completed within the timeout is regarded as failed.
+    ///
+    /// Default value is 1s.
+    pub timeout: Duration,
+}
+
+impl Default for HealthCheckConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(10),
+            timeout: Duration::from_secs(1),
+        }
+    }
+}
+
+/// Config of the [`ClientMetrics`](crate::ClientMetrics).
+#[derive(Debug, Clone)]
+pub struct MetricsConfig {
+    /// The max number of the endpoints having their own labels, and the
+    /// others share the label `other`, so that the number of the series is
+    /// bounded.
+    ///
+    /// Default value is 64.
+    pub max_endpoints: usize,
+    /// The max number of the databases having their own labels, and the
+    /// others share the label `other` like the endpoints.
+    ///
+    /// Default value is 64.
+    pub max_databases: usize,
+    /// The upper bounds of the buckets of the latency histograms in seconds.
+    ///
+    /// Default value is the one of the Prometheus clients, i.e. from 5ms to
+    /// 10s.
+    pub latency_buckets: Vec<f64>,
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        Self {
+            max_endpoints: 64,
+            max_databases: 64,
+            latency_buckets: vec![
+                0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+            ],
+        }
+    }
+}
+
+/// Config of tracking the health of the endpoints by the outcomes of the
+/// requests, see [`RpcConfig::passive_health`].
+///
+/// Only the transport failures count, as the endpoint answering with an error
+/// is reachable.
+#[derive(Debug, Clone)]
+pub struct PassiveHealthConfig {
+    /// The number of the latest requests to an endpoint the failure rate is
+    /// computed over.
+    ///
+    /// Default value is 20.
+    pub window: usize,
+    /// The endpoint is unhealthy once the failure rate in the window reaches
+    /// it.
+    ///
+    /// Default value is 0.5.
+    pub failure_rate_threshold: f64,
+    /// The minimum number of the requests in the window to judge the
+    /// endpoint.
+    ///
+    /// Default value is 5.
+    pub min_requests: usize,
+    /// The number of the consecutive successes after which the unhealthy
+    /// endpoint is healthy again, and the window is reset then.
+    ///
+    /// Default value is 3.
+    pub recovery_successes: usize,
+}
+
+impl Default for PassiveHealthConfig {
+    fn default() -> Self {
+        Self {
+            window: 20,
+            failure_rate_threshold: 0.5,
+            min_requests: 5,
+            recovery_successes: 3,
+        }
+    }
+}
+
+/// Config of pinning the queries to the endpoints of the latest writes, see
+/// [`RpcConfig::read_your_writes`].
+#[derive(Debug, Clone)]
+pub struct ReadYourWritesConfig {
+    /// How long the queries on a table are pinned to the endpoint after the
+    /// latest write of it, and the normal routing resumes then.
+    ///
+    /// Default value is 5s.
+    pub window: Duration,
+    /// The max number of the tables whose latest writes are tracked, and the
+    /// ones written earliest are forgotten beyond it.
+    ///
+    /// Default value is 1024.
+    pub max_tables: usize,
+}
+
+impl Default for ReadYourWritesConfig {
+    fn default() -> Self {
+        Self {
+            window: Duration::from_secs(5),
+            max_tables: 1024,
+        }
+    }
+}
+
+/// Config of the proxy to connect to the servers through.
+///
+/// The connection is tunneled through the proxy by the http `CONNECT` method,
+/// because neither tonic nor hyper supports proxies. Therefore, the tls to the
+/// proxy, i.e. the `https://` proxy uri, is not supported, and the
+/// [`connect_timeout`](RpcConfig::connect_timeout) includes the time to set
+/// up the tunnel.
+#[derive(Debug, Clone)]
+pub struct ProxyConfig {
+    /// Uri of the proxy, e.g. `http://proxy.example.com:3128`, and the port
+    /// is 80 if not given.
+    pub uri: String,
+    /// Username and password to authenticate to the proxy by the basic scheme,
+    /// which override the ones in the userinfo of the `uri`.
+    pub credentials: Option<(String, String)>,
+}
+
+impl ProxyConfig {
+    pub fn new(uri: String) -> Self {
+        Self {
+            uri,
+            credentials: None,
+        }
+    }
+}
+
+/// Config of the client-side cache of the sql query responses.
+///
+/// Only the queries with [`cache_ttl`](crate::SqlQueryRequest::cache_ttl) set
+/// are cached.
+#[derive(Debug, Clone)]
+pub struct QueryCacheConfig {
+    /// The max number of the cached responses.
+    ///
+    /// Default value is 1024.
+    pub max_entries: usize,
+    /// The max total size of the cached responses, measured by the encoded
+    /// responses, and the least recently used ones are evicted beyond it.
+    ///
+    /// Default value is 64MB.
+    pub max_bytes: usize,
+    /// Invalidate the cached responses of the queries involving the written
+    /// tables after every write.
+    ///
+    /// It is enabled by default.
+    pub invalidate_on_write: bool,
+}
+
+impl Default for QueryCacheConfig {
+    fn default() -> Self {
+        Self {
+            max_entries: 1024,
+            // 64MB
+            max_bytes: 64 * (1 << 20),
+            invalidate_on_write: true,
+        }
+    }
+}
+
+/// Config of the [`FileRouteCache`](crate::FileRouteCache).
+#[derive(Debug, Clone)]
+pub struct FileRouteCacheConfig {
+    /// How often the changed routes are flushed to the file in the
+    /// background, and zero disables the periodic flushing.
+    ///
+    /// Default value is 10s.
+    pub flush_interval: Duration,
+    /// The max age of the routes loaded from the file, and the snapshot older
+    /// than it is ignored on open. The loaded routes expire once they are that
+    /// old.
+    ///
+    /// Default value is 5min.
+    pub max_staleness: Duration,
+}
+
+impl Default for FileRouteCacheConfig {
+    fn default() -> Self {
+        Self {
+            flush_interval: Duration::from_secs(10),
+            max_staleness: Duration::from_secs(300),
+        }
+    }
+}
+
+/// Config of the guardrails against the unbounded sql queries, see
+/// [`Builder::query_guard`](crate::Builder::query_guard), and nothing is
+/// bounded by default.
+///
+/// It can be overridden by [`RpcContext::query_guard`] for the trusted
+/// callers.
+///
+/// [`RpcContext::query_guard`]: crate::RpcContext::query_guard
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct QueryGuardConfig {
+    /// The max number of the rows returned by a `SELECT`, which is bounded by
+    /// appending `LIMIT max_rows` if it has no `LIMIT`.
+    ///
+    /// The `SELECT` with a higher `LIMIT` or the sql of multiple statements
+    /// fails with [`Error::Client`](crate::Error::Client) as it can't be
+    /// bounded safely, and the other statements, e.g. `SHOW`, are left
+    /// unchanged.
+    pub max_rows: Option<usize>,
+    /// The max size of the record batches decoded from the response, beyond
+    /// which the decoding is aborted with
+    /// [`Error::ResponseTooLarge`](crate::Error::ResponseTooLarge), and the
+    /// rows decoded so far are available by
+    /// [`Error::partial_response`](crate::Error::partial_response).
+    pub max_response_bytes: Option<usize>,
+}
+
+/// Config of the statistics of the writes per table, see
+/// [`DbClient::write_stats`](crate::DbClient::write_stats).
+#[derive(Debug, Clone)]
+pub struct WriteStatsConfig {
+    /// The max number of the tables tracked, and the least recently written
+    /// one is evicted beyond it.
+    ///
+    /// Default value is 1024.
+    pub max_tables: usize,
+}
+
+impl Default for WriteStatsConfig {
+    fn default() -> Self {
+        Self { max_tables: 1024 }
+    }
+}
+
+/// Config of merging the concurrent writes to the same endpoint into one rpc,
+/// see [`Builder::group_commit`](crate::Builder::group_commit).
+#[derive(Debug, Clone)]
+pub struct GroupCommitConfig {
+    /// How long the first write to an endpoint waits for the others to be
+    /// merged with it, which bounds the latency added to the writes.
+    ///
+    /// Default value is 2ms.
+    pub window: Duration,
+    /// The max number of the writes merged into one rpc, and the merged
+    /// writes are sent as soon as the number is reached.
+    ///
+    /// Default value is 64.
+    pub max_writes: usize,
+}
+
+impl Default for GroupCommitConfig {
+    fn default() -> Self {
+        Self {
+            window: Duration::from_millis(2),
+            max_writes: 64,
+        }
+    }
+}
+
+/// Config of creating the tables not found on write.
+///
+/// The table is inferred from the points written to it: the tags are the tag
+/// columns, the fields are the value columns, and the timestamp key is the
+/// column `timestamp`.
+#[derive(Debug, Clone, Default)]
+pub struct AutoCreateTableConfig {
+    /// Template of the `CREATE TABLE` sql overriding the inferred one, whose
+    /// `{table}` is replaced with the quoted table name and `{columns}` with
+    /// the inferred column definitions including the timestamp key, e.g.
+    /// `CREATE TABLE IF NOT EXISTS {table} ({columns}) ENGINE=Analytic WITH
+    /// (ttl='7d')`.
+    ///
+    /// The inferred sql is used if not set.
+    pub ddl_template: Option<String>,
+}
+
+/// Threshold of the elapsed time, beyond which the request is regarded as
+/// slow, for every operation.
+#[derive(Debug, Clone, Default)]
+pub struct SlowRequestThreshold {
+    /// Threshold for write operation, and no log if not set.
+    pub write: Option<Duration>,
+    /// Threshold for sql_query operation, and no log if not set.
+    pub sql_query: Option<Duration>,
 }
 
 impl Default for RpcConfig {
@@ -59,7 +726,35 @@ impl Default for RpcConfig {
             keep_alive_while_idle: true,
             default_write_timeout: Duration::from_secs(5),
             default_sql_query_timeout: Duration::from_secs(60),
+            default_route_timeout: Duration::from_secs(5),
             connect_timeout: Duration::from_secs(3),
+            max_consecutive_failures: 3,
+            refresh_dns_on_failure: false,
+            max_channel_age: None,
+            idle_timeout: None,
+            slow_request_threshold: SlowRequestThreshold::default(),
+            enable_compression: false,
+            // 1KB
+            compression_min_size: 1 << 10,
+            negotiate_capabilities: false,
+            sql_query_hedge_delay: None,
+            sql_query_hedge_max_in_flight: 16,
+            circuit_breaker: None,
+            reconnect: None,
+            health_check: None,
+            passive_health: None,
+            read_your_writes: None,
+            sql_query_batch_concurrency: 8,
+            discovery_refresh_interval: Duration::from_secs(30),
+            proxy: None,
+            routing_budget: RoutingBudget::default(),
+            route_cache_shard_amount: None,
+            load_balance_policy: LoadBalancePolicy::default(),
+            strict_routing: false,
+            session_settings: SessionSettings::default(),
+            session_settings_transport: SettingsTransport::default(),
+            max_rows_per_write: None,
+            split_write_concurrency: 1,
         }
     }
 }
@@ -0,0 +1,295 @@
+// Copyright 2022 CeresDB Project Authors. Licensed under Apache-2.0.
+
+//! Create the tables not found on write
+
+use std::{borrow::Cow, sync::Arc};
+
+use crate::{
+    config::AutoCreateTableConfig,
+    db_client::DbClient,
+    errors::RouteBasedWriteError,
+    model::{
+        ddl::TableDefinition,
+        sql_query::Request as SqlQueryRequest,
+        write::{Request as WriteRequest, Response as WriteResponse},
+    },
+    rpc_client::RpcContext,
+    util::is_table_not_found,
+    Error, Result,
+};
+
+/// Wrap the client to create the tables not found on write by the definitions
+/// inferred from the points, and the write of them is retried once.
+///
+/// Only the [`write`](DbClient::write) creates the tables.
+pub(crate) struct AutoCreateTableClient {
+    client: Arc<dyn DbClient>,
+    config: AutoCreateTableConfig,
+}
+
+impl AutoCreateTableClient {
+    pub fn new(client: Arc<dyn DbClient>, config: AutoCreateTableConfig) -> Self {
+        Self { client, config }
+    }
+
+    /// Create the `tables` by the definitions inferred from their points in
+    /// `req`.
+    async fn create_tables(
+        &self,
+        ctx: &RpcContext,
+        req: &WriteRequest,
+        tables: &[String],
+    ) -> Result<()> {
+        for table in tables {
+            let points = req.point_groups.get(table).map(Vec::as_slice);
+            let def = TableDefinition::infer(table, points.unwrap_or_default())?;
+            let sql = match &self.config.ddl_template {
+                Some(template) => def.create_table_sql_by_template(template)?,
+                None => def.create_table_sql(),
+            };
+            let create_req = SqlQueryRequest::new(vec![table.clone()], sql);
+            self.client.sql_query(ctx, &create_req).await?;
+        }
+
+        Ok(())
+    }
+}
+
+#[inline]
+fn is_table_not_found_error(e: &Error) -> bool {
+    match e {
+        Error::Server(e) => is_table_not_found(e.code, &e.msg),
+        Error::RouteOverridden { source, .. } => is_table_not_found_error(source),
+        _ => false,
+    }
+}
+
+/// Find the tables of `req` whose write fails for the tables not found.
+///
+/// All the tables written together with a table not found fail, so they are
+/// regarded as not found too.
+fn tables_not_found(req: &WriteRequest, result: &Result<WriteResponse>) -> Vec<String> {
+    match result {
+        Err(e) if is_table_not_found_error(e) => req.point_groups.keys().cloned().collect(),
+        Err(Error::RouteBasedWriteError(e)) => e
+            .errors
+            .iter()
+            .filter(|(_, e)| is_table_not_found_error(e))
+            .flat_map(|(tables, _)| tables.iter().cloned())
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// Merge the result of retrying the `retried_tables` into the `failed` write,
+/// whose errors for the tables not found are replaced.
+fn merge_retried(
+    failed: RouteBasedWriteError,
+    retried_tables: Vec<String>,
+    retried: Result<WriteResponse>,
+) -> Result<WriteResponse> {
+    let RouteBasedWriteError { ok, errors } = failed;
+    let mut tables_result_pairs = vec![(ok.0, Ok(ok.1))];
+    tables_result_pairs.extend(
+        errors
+            .into_iter()
+            .filter(|(_, e)| !is_table_not_found_error(e))
+            .map(|(tables, e)| (tables, Err(e))),
+    );
+    match retried {
+        Err(Error::RouteBasedWriteError(e)) => {
+            tables_result_pairs.push((e.ok.0, Ok(e.ok.1)));
+            tables_result_pairs.extend(e.errors.into_iter().map(|(tables, e)| (tables, Err(e))));
+        }
+        retried => tables_result_pairs.push((retried_tables, retried)),
+    }
+
+    let merged: RouteBasedWriteError = tables_result_pairs.into();
+    if merged.all_ok() {
+        Ok(merged.ok.1)
+    } else {
+        Err(Error::RouteBasedWriteError(merged))
+    }
+}
+
+forward_db_client! {
+    impl DbClient for AutoCreateTableClient {
+        forward [
+            sql_query, write_stream, sql_query_paged, connection_states, config, clock,
+            resolve_route_uncached, route_tables, route_replicas, check_database,
+            add_route_override, remove_route_override, is_healthy, last_write_endpoint,
+            server_capabilities, evict_routes_by_endpoint, hedge_stats, group_commit_stats,
+            channel_stats, circuit_breaker_states, write_stats, reset_write_stats, cache_stats,
+        ] to self.client,
+            context ctx => Cow::Borrowed(ctx);
+
+        async fn write(&self, ctx: &RpcContext, req: &WriteRequest) -> Result<WriteResponse> {
+            let result = self.client.write(ctx, req).await;
+            let tables = tables_not_found(req, &result);
+            if tables.is_empty() {
+                return result;
+            }
+
+            self.create_tables(ctx, req, &tables).await?;
+            let mut retry_req = req.empty_like();
+            for table in &tables {
+                if let Some(points) = req.point_groups.get(table) {
+                    retry_req.point_groups.insert(table.clone(), points.clone());
+                }
+            }
+            let retried = self.client.write(ctx, &retry_req).await;
+
+            match result {
+                Err(Error::RouteBasedWriteError(failed)) => merge_retried(failed, tables, retried),
+                _ => retried,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        collections::HashSet,
+        sync::{Arc, Mutex},
+    };
+
+    use futures::future;
+
+    use super::AutoCreateTableClient;
+    use crate::{
+        config::AutoCreateTableConfig,
+        db_client::{DbClient, MockDbClient},
+        errors::{RouteBasedWriteError, ServerError},
+        model::{
+            sql_query::Response as SqlQueryResponse,
+            value::Value,
+            write::{point::PointBuilder, Request as WriteRequest, Response as WriteResponse},
+        },
+        rpc_client::RpcContext,
+        Error,
+    };
+
+    /// Tables created by the recorded sqls of the mock client.
+    #[derive(Default)]
+    struct Tables {
+        created: Mutex<HashSet<String>>,
+        sqls: Mutex<Vec<String>>,
+    }
+
+    /// Client failing the writes of the tables not created by the recorded
+    /// sqls, and it fails by table like the route based client if
+    /// `route_based` is set.
+    fn mock_client(tables: Arc<Tables>, route_based: bool) -> Arc<MockDbClient> {
+        let written_tables = tables.clone();
+        let client = MockDbClient::default()
+            .on_sql_query(move |_ctx, req| {
+                tables.sqls.lock().unwrap().push(req.sql.clone());
+                tables.created.lock().unwrap().insert(req.tables[0].clone());
+                future::ok(SqlQueryResponse::default())
+            })
+            .on_write(move |_ctx, req| {
+                let created = written_tables.created.lock().unwrap();
+                let tables_result_pairs: Vec<_> = req
+                    .point_groups
+                    .iter()
+                    .map(|(table, points)| {
+                        let result = if created.contains(table) {
+                            Ok(WriteResponse::new(points.len() as u32, 0))
+                        } else {
+                            Err(Error::Server(ServerError {
+                                code: 400,
+                                msg: format!("Table not found, table:{table}"),
+                            }))
+                        };
+                        (vec![table.clone()], result)
+                    })
+                    .collect();
+
+                let error: RouteBasedWriteError = tables_result_pairs.into();
+                future::ready(if error.all_ok() {
+                    Ok(error.ok.1)
+                } else if route_based {
+                    Err(Error::RouteBasedWriteError(error))
+                } else {
+                    Err(error.errors.into_iter().next().unwrap().1)
+                })
+            });
+        Arc::new(client)
+    }
+
+    fn write_request(tables: &[&str]) -> WriteRequest {
+        let mut req = WriteRequest::default();
+        for table in tables {
+            let point = PointBuilder::new(table.to_string())
+                .timestamp(1_700_000_000_000)
+                .tag("host".to_string(), Value::String("a".to_string()))
+                .field("cpu".to_string(), Value::Double(0.5))
+                .field("mem".to_string(), Value::Null)
+                .build()
+                .unwrap();
+            req.add_point(point);
+        }
+        req
+    }
+
+    #[tokio::test]
+    async fn test_auto_create_table() {
+        let ctx = RpcContext::default();
+        let tables = Arc::new(Tables::default());
+        tables.created.lock().unwrap().insert("t1".to_string());
+        let mock = mock_client(tables.clone(), false);
+        let client = AutoCreateTableClient::new(mock, AutoCreateTableConfig::default());
+
+        // All the tables written together are created if not exists, and the null
+        // column is left out.
+        let resp = client.write(&ctx, &write_request(&["t1", "t2"])).await;
+        assert_eq!(resp.unwrap().success, 2);
+        let mut sqls = tables.sqls.lock().unwrap().clone();
+        sqls.sort();
+        assert_eq!(
+            sqls,
+            ["t1", "t2"].map(|table| format!(
+                "CREATE TABLE IF NOT EXISTS `{table}` (`host` string TAG, `cpu` double, \
+                 `timestamp` timestamp NOT NULL, TIMESTAMP KEY(`timestamp`)) ENGINE=Analytic"
+            ))
+        );
+
+        // Only the tables not found are created and retried by the route based client.
+        let tables = Arc::new(Tables::default());
+        tables.created.lock().unwrap().insert("t1".to_string());
+        let mock = mock_client(tables.clone(), true);
+        let config = AutoCreateTableConfig {
+            ddl_template: Some(
+                "CREATE TABLE IF NOT EXISTS {table} ({columns}) ENGINE=Analytic WITH (ttl='7d')"
+                    .to_string(),
+            ),
+        };
+        let client = AutoCreateTableClient::new(mock, config);
+        let resp = client.write(&ctx, &write_request(&["t1", "t2"])).await;
+        assert_eq!(resp.unwrap().success, 2);
+        assert_eq!(
+            *tables.sqls.lock().unwrap(),
+            [
+                "CREATE TABLE IF NOT EXISTS `t2` (`host` string TAG, `cpu` double, \
+                 `timestamp` timestamp NOT NULL, TIMESTAMP KEY(`timestamp`)) ENGINE=Analytic \
+                 WITH (ttl='7d')"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_invalid_template() {
+        let tables = Arc::new(Tables::default());
+        let config = AutoCreateTableConfig {
+            ddl_template: Some("CREATE TABLE {table}".to_string()),
+        };
+        let client = AutoCreateTableClient::new(mock_client(tables.clone(), false), config);
+
+        let resp = client
+            .write(&RpcContext::default(), &write_request(&["t1"]))
+            .await;
+        assert!(matches!(resp, Err(Error::Client(_))));
+        assert!(tables.sqls.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,348 @@
+// Copyright 2022 CeresDB Project Authors. Licensed under Apache-2.0.
+
+//! Blocking facade of the client
+
+use std::{
+    collections::HashMap,
+    fmt,
+    future::Future,
+    sync::{Arc, Mutex, PoisonError},
+};
+
+use futures::StreamExt;
+use tokio::runtime::{self, Runtime};
+
+use crate::{
+    db_client::{Builder, ConnectionState, DbClient, TableWriteStats},
+    model::{
+        ddl::TableDefinition,
+        route::{Endpoint, Route, TableRoute},
+        schema::TableSchema,
+        sql_query::{Request as SqlQueryRequest, Response as SqlQueryResponse},
+        write::{Request as WriteRequest, Response as WriteResponse},
+    },
+    rpc_client::RpcContext,
+    Error, Result, RpcConfig,
+};
+
+/// Current-thread tokio runtime driving the [`BlockingDbClient`]s, which is
+/// built on the first call.
+///
+/// It is cheap to clone, and the clones share the same runtime, so one
+/// runtime can serve multiple blocking clients.
+#[derive(Clone, Default)]
+pub struct BlockingRuntime {
+    runtime: Arc<Mutex<Option<Arc<Runtime>>>>,
+}
+
+impl fmt::Debug for BlockingRuntime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let built = self
+            .runtime
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .is_some();
+        f.debug_struct("BlockingRuntime")
+            .field("built", &built)
+            .finish()
+    }
+}
+
+impl BlockingRuntime {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Run the `fut` to completion on the runtime.
+    ///
+    /// It fails instead of blocking if it is called within an async runtime.
+    pub fn block_on<F: Future>(&self, fut: F) -> Result<F::Output> {
+        ensure_not_in_runtime()?;
+        Ok(self.runtime()?.block_on(fut))
+    }
+
+    fn runtime(&self) -> Result<Arc<Runtime>> {
+        let mut runtime = self.runtime.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(runtime) = &*runtime {
+            return Ok(runtime.clone());
+        }
+
+        let built = runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .map_err(|e| Error::Client(format!("failed to build blocking runtime, err:{e}")))?;
+        Ok(runtime.insert(Arc::new(built)).clone())
+    }
+}
+
+fn ensure_not_in_runtime() -> Result<()> {
+    if runtime::Handle::try_current().is_ok() {
+        return Err(Error::Client(
+            "blocking client can't be called within an async runtime, use the DbClient instead"
+                .to_string(),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Client running the requests of the wrapped [`DbClient`] to completion on a
+/// [`BlockingRuntime`], for the synchronous code without an async runtime.
+///
+/// All the calls fail with [`Error::Client`] if they are made within an
+/// async runtime, where the wrapped client should be used instead.
+pub struct BlockingDbClient {
+    client: Arc<dyn DbClient>,
+    runtime: BlockingRuntime,
+}
+
+impl BlockingDbClient {
+    /// Wrap the `client` with a runtime of its own.
+    pub fn new(client: Arc<dyn DbClient>) -> Self {
+        Self {
+            client,
+            runtime: BlockingRuntime::default(),
+        }
+    }
+
+    /// Build the client by the `builder` within the context of the `runtime`,
+    /// so the background tasks of the client, e.g. the health checks, are
+    /// spawned on it.
+    pub fn build(builder: Builder, runtime: BlockingRuntime) -> Result<Self> {
+        ensure_not_in_runtime()?;
+        let client = {
+            let runtime = runtime.runtime()?;
+            let _guard = runtime.enter();
+            builder.build()
+        };
+
+        Ok(Self { client, runtime })
+    }
+
+    /// Drive the requests on the `runtime`, which may be shared with other
+    /// blocking clients.
+    pub fn with_runtime(mut self, runtime: BlockingRuntime) -> Self {
+        self.runtime = runtime;
+        self
+    }
+
+    /// Get the runtime driving the requests.
+    pub fn runtime(&self) -> &BlockingRuntime {
+        &self.runtime
+    }
+
+    /// Get the wrapped client.
+    pub fn inner(&self) -> &Arc<dyn DbClient> {
+        &self.client
+    }
+
+    /// Blocking version of [`DbClient::sql_query`].
+    pub fn sql_query(&self, ctx: &RpcContext, req: &SqlQueryRequest) -> Result<SqlQueryResponse> {
+        self.runtime.block_on(self.client.sql_query(ctx, req))?
+    }
+
+    /// Blocking version of [`DbClient::write`].
+    pub fn write(&self, ctx: &RpcContext, req: &WriteRequest) -> Result<WriteResponse> {
+        self.runtime.block_on(self.client.write(ctx, req))?
+    }
+
+    /// Blocking version of [`DbClient::sql_query_batch`].
+    pub fn sql_query_batch(
+        &self,
+        ctx: &RpcContext,
+        reqs: &[SqlQueryRequest],
+    ) -> Result<Vec<Result<SqlQueryResponse>>> {
+        self.runtime
+            .block_on(self.client.sql_query_batch(ctx, reqs))
+    }
+
+    /// Blocking version of [`DbClient::sql_query_paged`], and all the pages
+    /// are collected, so it fails on the first failed page.
+    pub fn sql_query_paged(
+        &self,
+        ctx: &RpcContext,
+        req: &SqlQueryRequest,
+        page_size: usize,
+    ) -> Result<Vec<SqlQueryResponse>> {
+        let pages = self.client.sql_query_paged(ctx, req, page_size);
+        self.runtime
+            .block_on(pages.collect::<Vec<_>>())?
+            .into_iter()
+            .collect()
+    }
+
+    /// Blocking version of [`DbClient::create_table`].
+    pub fn create_table(&self, ctx: &RpcContext, def: &TableDefinition) -> Result<u64> {
+        self.runtime.block_on(self.client.create_table(ctx, def))?
+    }
+
+    /// Blocking version of [`DbClient::drop_table`].
+    pub fn drop_table(&self, ctx: &RpcContext, table: &str, if_exists: bool) -> Result<u64> {
+        self.runtime
+            .block_on(self.client.drop_table(ctx, table, if_exists))?
+    }
+
+    /// Blocking version of [`DbClient::describe_table`].
+    pub fn describe_table(&self, ctx: &RpcContext, table: &str) -> Result<TableSchema> {
+        self.runtime
+            .block_on(self.client.describe_table(ctx, table))?
+    }
+
+    /// Blocking version of [`DbClient::resolve_route_uncached`].
+    pub fn resolve_route_uncached(
+        &self,
+        ctx: &RpcContext,
+        table: &str,
+    ) -> Result<Option<Endpoint>> {
+        self.runtime
+            .block_on(self.client.resolve_route_uncached(ctx, table))?
+    }
+
+    /// Blocking version of [`DbClient::route_tables`].
+    pub fn route_tables(
+        &self,
+        ctx: &RpcContext,
+        tables: &[String],
+        force_refresh: bool,
+    ) -> Result<HashMap<String, TableRoute>> {
+        self.runtime
+            .block_on(self.client.route_tables(ctx, tables, force_refresh))?
+    }
+
+    /// Blocking version of [`DbClient::route_replicas`].
+    pub fn route_replicas(
+        &self,
+        ctx: &RpcContext,
+        tables: &[String],
+    ) -> Result<HashMap<String, Route>> {
+        self.runtime
+            .block_on(self.client.route_replicas(ctx, tables))?
+    }
+
+    /// Blocking version of [`DbClient::check_database`].
+    pub fn check_database(&self, ctx: &RpcContext) -> Result<bool> {
+        self.runtime.block_on(self.client.check_database(ctx))?
+    }
+
+    /// See [`DbClient::validate_write`].
+    pub fn validate_write(&self, req: &WriteRequest) -> Result<()> {
+        self.client.validate_write(req)
+    }
+
+    /// See [`DbClient::connection_states`].
+    pub fn connection_states(&self) -> Vec<(Endpoint, ConnectionState)> {
+        self.client.connection_states()
+    }
+
+    /// See [`DbClient::config`].
+    pub fn config(&self) -> RpcConfig {
+        self.client.config()
+    }
+
+    /// See [`DbClient::write_stats`].
+    pub fn write_stats(&self) -> Vec<TableWriteStats> {
+        self.client.write_stats()
+    }
+
+    /// See [`DbClient::reset_write_stats`].
+    pub fn reset_write_stats(&self) {
+        self.client.reset_write_stats()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{sync::Arc, time::Duration};
+
+    use futures::stream::{self, StreamExt};
+
+    use super::{BlockingDbClient, BlockingRuntime};
+    use crate::{
+        db_client::MockDbClient,
+        model::{
+            sql_query::{
+                Output as SqlQueryOutput, Request as SqlQueryRequest, Response as SqlQueryResponse,
+            },
+            write::{Request as WriteRequest, Response as WriteResponse},
+        },
+        rpc_client::RpcContext,
+        Error, Result,
+    };
+
+    /// Answer the query `SELECT n` with `n` affected rows after a sleep, which
+    /// needs the timer of the runtime.
+    async fn select(req: SqlQueryRequest) -> Result<SqlQueryResponse> {
+        tokio::time::sleep(Duration::from_millis(1)).await;
+        match req.sql.trim_start_matches("SELECT ").parse() {
+            Ok(affected_rows) => Ok(SqlQueryResponse {
+                output: SqlQueryOutput::AffectedRows(affected_rows),
+                ..Default::default()
+            }),
+            Err(_) => Err(Error::Client(format!("invalid sql:{}", req.sql))),
+        }
+    }
+
+    /// Client sleeping before every response, and serving the paged queries
+    /// in one page.
+    fn sleeping_client() -> Arc<MockDbClient> {
+        let client = MockDbClient::default()
+            .on_sql_query(|_ctx, req| select(req))
+            .on_write(|_ctx, _req| async {
+                tokio::time::sleep(Duration::from_millis(1)).await;
+                Ok(WriteResponse::new(1, 0))
+            })
+            .on_sql_query_paged(|_ctx, req, _page_size| stream::once(select(req)).boxed());
+        Arc::new(client)
+    }
+
+    fn query(sql: &str) -> SqlQueryRequest {
+        SqlQueryRequest::new(vec!["test_table".to_string()], sql.to_string())
+    }
+
+    #[test]
+    fn test_blocking_client() {
+        let ctx = RpcContext::default();
+        let runtime = BlockingRuntime::new();
+        let client1 = BlockingDbClient::new(sleeping_client()).with_runtime(runtime.clone());
+        let client2 = BlockingDbClient::new(sleeping_client()).with_runtime(runtime);
+
+        let resp = client1.sql_query(&ctx, &query("SELECT 3")).unwrap();
+        assert_eq!(resp.affected_rows(), Some(3));
+        let resp = client2.write(&ctx, &WriteRequest::default()).unwrap();
+        assert_eq!(resp.success, 1);
+        assert!(matches!(
+            client2.sql_query(&ctx, &query("invalid")),
+            Err(Error::Client(_))
+        ));
+        let results = client1
+            .sql_query_batch(&ctx, &[query("SELECT 1"), query("SELECT 2")])
+            .unwrap();
+        assert_eq!(results.len(), 2);
+        let pages = client2
+            .sql_query_paged(&ctx, &query("SELECT 4"), 10)
+            .unwrap();
+        assert_eq!(pages[0].affected_rows(), Some(4));
+        assert_eq!(client1.resolve_route_uncached(&ctx, "t").unwrap(), None);
+
+        // The runtime is built once and shared by the clients.
+        let runtime1 = client1.runtime().runtime().unwrap();
+        let runtime2 = client2.runtime().runtime().unwrap();
+        assert!(Arc::ptr_eq(&runtime1, &runtime2));
+    }
+
+    #[tokio::test]
+    async fn test_blocking_client_in_runtime() {
+        let client = BlockingDbClient::new(sleeping_client());
+        let ctx = RpcContext::default();
+        assert!(matches!(
+            client.sql_query(&ctx, &query("SELECT 1")),
+            Err(Error::Client(_))
+        ));
+        assert!(matches!(
+            client.write(&ctx, &WriteRequest::default()),
+            Err(Error::Client(_))
+        ));
+        // The checks without rpcs still work.
+        assert!(client.validate_write(&WriteRequest::default()).is_ok());
+    }
+}
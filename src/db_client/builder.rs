@@ -2,12 +2,43 @@
 
 //! Client builder
 
-use std::sync::Arc;
+use std::{collections::HashMap, sync::Arc};
+
+use tokio::runtime::Handle;
 
 use crate::{
-    db_client::{raw::RawImpl, route_based::RouteBasedImpl, DbClient},
-    rpc_client::RpcClientImplFactory,
-    RpcConfig,
+    auth::{AuthProvider, Authenticator},
+    capture::RequestCapture,
+    clock::{Clock, SystemClock},
+    config::{
+        AutoCreateTableConfig, GroupCommitConfig, QueryCacheConfig, QueryGuardConfig,
+        WriteStatsConfig,
+    },
+    db_client::{
+        auto_create::AutoCreateTableClient,
+        default_context::DefaultContextClient,
+        discovery::DiscoveryProvider,
+        group_commit::GroupCommitter,
+        health_check::{HealthProbe, TcpProbe},
+        query_cache::QueryCachingClient,
+        raw::RawImpl,
+        retry::RetryPolicy,
+        route_based::RouteBasedImpl,
+        slow_request::{SlowRequestHook, SlowRequestInfo, SlowRequestLogger},
+        write_stats::WriteStatsRecorder,
+        DbClient, Operation,
+    },
+    interceptor::{Interceptors, RequestInterceptor},
+    metrics::ClientMetrics,
+    model::{
+        route::Endpoint,
+        warning::{ServerWarning, WarningHook},
+    },
+    route_cache::RouteCache,
+    router::{OverrideKey, RelatedTables, RouteChangeHook, RouteOverrides},
+    rpc_client::{RpcClientImplFactory, RpcContext},
+    spawner::Spawner,
+    Error, Result, RpcConfig,
 };
 
 /// Access mode to CeresDB server(s).
@@ -28,8 +59,30 @@ pub enum Mode {
 pub struct Builder {
     mode: Mode,
     endpoint: String,
+    fallback_router_endpoint: Option<String>,
+    discovery: Option<Arc<dyn DiscoveryProvider>>,
     default_database: Option<String>,
+    default_context: Option<RpcContext>,
     rpc_config: RpcConfig,
+    slow_request_hook: Option<SlowRequestHook>,
+    warning_hook: Option<WarningHook>,
+    authenticator: Option<Arc<Authenticator>>,
+    retry_policy: Option<Arc<dyn RetryPolicy>>,
+    query_cache: Option<QueryCacheConfig>,
+    query_guard: QueryGuardConfig,
+    write_stats: Option<WriteStatsConfig>,
+    group_commit: Option<GroupCommitConfig>,
+    auto_create_table: Option<AutoCreateTableConfig>,
+    interceptors: Interceptors,
+    capture: Option<Arc<dyn RequestCapture>>,
+    health_probe: Arc<dyn HealthProbe>,
+    related_tables: Option<RelatedTables>,
+    route_change_hook: Option<RouteChangeHook>,
+    route_cache: Option<Arc<dyn RouteCache>>,
+    route_overrides: HashMap<OverrideKey, Endpoint>,
+    metrics: Option<ClientMetrics>,
+    runtime: Option<Handle>,
+    clock: Arc<dyn Clock>,
 }
 
 impl Builder {
@@ -38,8 +91,30 @@ impl Builder {
         Self {
             mode,
             endpoint,
+            fallback_router_endpoint: None,
+            discovery: None,
             rpc_config: RpcConfig::default(),
             default_database: None,
+            default_context: None,
+            slow_request_hook: None,
+            warning_hook: None,
+            authenticator: None,
+            retry_policy: None,
+            query_cache: None,
+            query_guard: QueryGuardConfig::default(),
+            write_stats: None,
+            group_commit: None,
+            auto_create_table: None,
+            interceptors: Interceptors::default(),
+            capture: None,
+            health_probe: Arc::new(TcpProbe),
+            related_tables: None,
+            route_change_hook: None,
+            route_cache: None,
+            route_overrides: HashMap::new(),
+            metrics: None,
+            runtime: None,
+            clock: Arc::new(SystemClock),
         }
     }
 
@@ -49,26 +124,454 @@ impl Builder {
         self
     }
 
+    /// Fill the fields not set in the [`RpcContext`] of every request by the
+    /// `ctx`, e.g. the database and the timeout shared by all the requests,
+    /// which can be sent with the default context by
+    /// [`DbClient::sql_query_default_ctx`] and [`DbClient::write_default_ctx`].
+    ///
+    /// The fields set in the context of a request win one by one, and the
+    /// session settings are merged one by one too. The
+    /// [`Priority::Normal`](crate::Priority::Normal) of a request is regarded
+    /// as not set. The database of the `ctx` wins over the
+    /// [`default_database`](Builder::default_database).
+    #[inline]
+    pub fn default_context(mut self, ctx: RpcContext) -> Self {
+        self.default_context = Some(ctx);
+        self
+    }
+
+    /// Set the endpoint of the route service to fall back to, when the route
+    /// service at `endpoint` fails or can't resolve some tables.
+    ///
+    /// It only works in [`Mode::Direct`].
+    #[inline]
+    pub fn fallback_router_endpoint(mut self, endpoint: String) -> Self {
+        self.fallback_router_endpoint = Some(endpoint);
+        self
+    }
+
+    /// Discover the default endpoints by the `provider` instead of using the
+    /// `endpoint`, and they are refreshed every
+    /// [`discovery_refresh_interval`](RpcConfig::discovery_refresh_interval).
+    ///
+    /// The route rpcs and the tables unknown to the route service go to the
+    /// first discovered endpoint, and the others are failed over to. It only
+    /// works in [`Mode::Direct`].
+    #[inline]
+    pub fn discovery(mut self, provider: Arc<dyn DiscoveryProvider>) -> Self {
+        self.discovery = Some(provider);
+        self
+    }
+
     #[inline]
     pub fn rpc_config(mut self, rpc_config: RpcConfig) -> Self {
         self.rpc_config = rpc_config;
         self
     }
 
+    /// Set the hook called on the requests slower than the
+    /// [`slow_request_threshold`](RpcConfig::slow_request_threshold).
+    #[inline]
+    pub fn on_slow_request(
+        mut self,
+        hook: impl Fn(SlowRequestInfo) + Send + Sync + 'static,
+    ) -> Self {
+        self.slow_request_hook = Some(SlowRequestHook::new(hook));
+        self
+    }
+
+    /// Set the hook called on every [`ServerWarning`] reported along with the
+    /// responses, e.g. to log or alert on them centrally, and the warnings
+    /// are still returned in the responses.
+    #[inline]
+    pub fn on_warning(
+        mut self,
+        hook: impl Fn(Operation, ServerWarning) + Send + Sync + 'static,
+    ) -> Self {
+        self.warning_hook = Some(WarningHook::new(hook));
+        self
+    }
+
+    /// Set the provider of the token sent as the `authorization` metadata of
+    /// every rpc, and the token is refreshed once on the unauthenticated
+    /// status.
+    #[inline]
+    pub fn auth_provider(mut self, provider: Arc<dyn AuthProvider>) -> Self {
+        self.authenticator = Some(Arc::new(Authenticator::new(provider)));
+        self
+    }
+
+    /// Set the policy to retry the failed requests, and no request is retried
+    /// by default.
+    ///
+    /// The requests to the route service are never retried.
+    #[inline]
+    pub fn retry_policy(mut self, retry_policy: Arc<dyn RetryPolicy>) -> Self {
+        self.retry_policy = Some(retry_policy);
+        self
+    }
+
+    /// Add an interceptor called around every rpc, and the interceptors are
+    /// called in the order of adding, see [`RequestInterceptor`]. The
+    /// interceptors can be chained into one by [`Interceptors`].
+    #[inline]
+    pub fn interceptor(mut self, interceptor: Arc<dyn RequestInterceptor>) -> Self {
+        self.interceptors.push(interceptor);
+        self
+    }
+
+    /// Pass the encoded write and sql query requests to the `capture` right
+    /// before they are sent, e.g. to audit or replay them, see
+    /// [`RequestCapture`].
+    #[inline]
+    pub fn request_capture(mut self, capture: Arc<dyn RequestCapture>) -> Self {
+        self.capture = Some(capture);
+        self
+    }
+
+    /// Cache the responses of the sql queries with
+    /// [`cache_ttl`](crate::SqlQueryRequest::cache_ttl) set, and no response
+    /// is cached by default.
+    #[inline]
+    pub fn query_cache(mut self, config: QueryCacheConfig) -> Self {
+        self.query_cache = Some(config);
+        self
+    }
+
+    /// Bound the rows and the decoded size of the responses of the sql
+    /// queries, e.g. against the unbounded scans from an ad-hoc query ui, and
+    /// nothing is bounded by default, see [`QueryGuardConfig`].
+    ///
+    /// The trusted callers can override it by
+    /// [`RpcContext::query_guard`](crate::RpcContext::query_guard).
+    #[inline]
+    pub fn query_guard(mut self, config: QueryGuardConfig) -> Self {
+        self.query_guard = config;
+        self
+    }
+
+    /// Track the statistics of the writes per table, see
+    /// [`DbClient::write_stats`], and no write is tracked by default.
+    #[inline]
+    pub fn write_stats(mut self, config: WriteStatsConfig) -> Self {
+        self.write_stats = Some(config);
+        self
+    }
+
+    /// Merge the concurrent writes to the same endpoint into one rpc, and no
+    /// write is merged by default, see [`DbClient::group_commit_stats`].
+    ///
+    /// The points of every write are counted in its response, while the
+    /// failed points of a merged rpc can't be told apart, so the writes merged
+    /// into it get [`Error::UnattributedWriteFailure`] if only some of its
+    /// points fail, and an error of it is returned to all of them. It only
+    /// works in [`Mode::Direct`].
+    #[inline]
+    pub fn group_commit(mut self, config: GroupCommitConfig) -> Self {
+        self.group_commit = Some(config);
+        self
+    }
+
+    /// Create the tables not found on write by the definitions inferred from
+    /// the points, and retry the write of them once, and no table is created
+    /// by default.
+    #[inline]
+    pub fn auto_create_table(mut self, config: AutoCreateTableConfig) -> Self {
+        self.auto_create_table = Some(config);
+        self
+    }
+
+    /// Set the probe used by the health checker, and the endpoints are probed
+    /// by [`TcpProbe`] by default.
+    ///
+    /// It only works if the [`health_check`](RpcConfig::health_check) is set.
+    #[inline]
+    pub fn health_probe(mut self, probe: Arc<dyn HealthProbe>) -> Self {
+        self.health_probe = probe;
+        self
+    }
+
+    /// Derive the tables related to a routed table, e.g. `metrics_2024_02`
+    /// for `metrics_2024_01`, and their routes are resolved in the same route
+    /// rpc on the cache miss to warm the cache.
+    ///
+    /// It only works in the [`Direct`](Mode::Direct) mode.
+    #[inline]
+    pub fn prefetch_related_tables(
+        mut self,
+        related: impl Fn(&str) -> Vec<String> + Send + Sync + 'static,
+    ) -> Self {
+        self.related_tables = Some(RelatedTables::new(related));
+        self
+    }
+
+    /// Set the hook called when a route refresh finds a table placed on
+    /// another endpoint, e.g. to alert on the rebalances or the node
+    /// failures, and the hook counts the changes.
+    ///
+    /// It only works in the [`Direct`](Mode::Direct) mode.
+    #[inline]
+    pub fn on_route_changed(mut self, hook: RouteChangeHook) -> Self {
+        self.route_change_hook = Some(hook);
+        self
+    }
+
+    /// Cache the routes of the tables in the `cache` instead of the memory,
+    /// e.g. in the [`FileRouteCache`](crate::FileRouteCache) to keep them
+    /// across the restarts, and the
+    /// [`route_cache_shard_amount`](RpcConfig::route_cache_shard_amount) is
+    /// ignored.
+    ///
+    /// Only the routes of the route service at `endpoint` are cached in it,
+    /// not the ones of the fallback. It only works in the
+    /// [`Direct`](Mode::Direct) mode.
+    #[inline]
+    pub fn route_cache(mut self, cache: Arc<dyn RouteCache>) -> Self {
+        self.route_cache = Some(cache);
+        self
+    }
+
+    /// Pin the tables to the endpoints in all the databases, e.g. to route
+    /// around a misbehaving route service, and they can be changed at runtime
+    /// by [`DbClient::add_route_override`].
+    ///
+    /// The pinned tables take precedence over the cached routes and are never
+    /// routed by the route service, and their routes are not evicted on the
+    /// failures, which are returned as [`Error::RouteOverridden`]. It only
+    /// works in [`Mode::Direct`].
+    ///
+    /// [`Error::RouteOverridden`]: crate::Error::RouteOverridden
+    pub fn route_overrides(mut self, overrides: HashMap<String, Endpoint>) -> Self {
+        let overrides = overrides
+            .into_iter()
+            .map(|(table, endpoint)| ((None, table), endpoint));
+        self.route_overrides.extend(overrides);
+        self
+    }
+
+    /// Pin the tables in the `database` to the endpoints like
+    /// [`route_overrides`](Self::route_overrides), and they take precedence
+    /// over the ones pinned in all the databases.
+    pub fn database_route_overrides(
+        mut self,
+        database: String,
+        overrides: HashMap<String, Endpoint>,
+    ) -> Self {
+        let overrides = overrides
+            .into_iter()
+            .map(|(table, endpoint)| ((Some(database.clone()), table), endpoint));
+        self.route_overrides.extend(overrides);
+        self
+    }
+
+    /// Record the requests, the retries, the route cache lookups and the
+    /// circuit breaker states of the client in the `metrics`, and keep a clone
+    /// of it to read them, e.g. to serve them in the Prometheus text format by
+    /// `ClientMetrics::render_prometheus` with the `prometheus` feature.
+    #[inline]
+    pub fn metrics(mut self, metrics: ClientMetrics) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Spawn the background tasks of the client on the runtime of the
+    /// `handle`, including the health checker, the discovery refresher, the
+    /// group commits and the tasks driving the connections, so that the
+    /// client keeps working after the runtimes it is used in are dropped, e.g.
+    /// the ones of `#[tokio::test]`.
+    ///
+    /// The runtime current when building is used by default.
+    #[inline]
+    pub fn runtime(mut self, handle: Handle) -> Self {
+        self.runtime = Some(handle);
+        self
+    }
+
+    /// Measure the time by the `clock` instead of the system time, e.g. a
+    /// [`ManualClock`](crate::ManualClock) to fast-forward the time in tests.
+    #[cfg(feature = "testing")]
+    #[inline]
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Build the client like [`build`](Self::build), but fail if no runtime is
+    /// set by [`runtime`](Self::runtime) and it is not called in the context
+    /// of a tokio runtime.
+    pub fn try_build(mut self) -> Result<Arc<dyn DbClient>> {
+        if self.runtime.is_none() {
+            let handle = Handle::try_current().map_err(|e| {
+                Error::Client(format!(
+                    "no runtime to spawn the background tasks on, set one by Builder::runtime, err:{e}"
+                ))
+            })?;
+            self.runtime = Some(handle);
+        }
+
+        Ok(self.build())
+    }
+
+    /// Build the client, and the background tasks are spawned on the runtime
+    /// current when they start if no runtime is set by
+    /// [`runtime`](Self::runtime) or current when building.
     pub fn build(self) -> Arc<dyn DbClient> {
-        let rpc_client_factory = Arc::new(RpcClientImplFactory::new(self.rpc_config));
-
-        match self.mode {
-            Mode::Direct => Arc::new(RouteBasedImpl::new(
-                rpc_client_factory,
-                self.endpoint,
-                self.default_database,
-            )),
-            Mode::Proxy => Arc::new(RawImpl::new(
-                rpc_client_factory,
-                self.endpoint,
-                self.default_database,
-            )),
+        let spawner = Spawner::new(self.runtime.or_else(|| Handle::try_current().ok()));
+        let slow_request_logger = SlowRequestLogger::new(
+            self.rpc_config.slow_request_threshold.clone(),
+            self.slow_request_hook,
+        );
+        let route_timeout = self.rpc_config.default_route_timeout;
+        let max_consecutive_failures = self.rpc_config.max_consecutive_failures;
+        let refresh_dns_on_failure = self.rpc_config.refresh_dns_on_failure;
+        let max_channel_age = self.rpc_config.max_channel_age;
+        let idle_timeout = self.rpc_config.idle_timeout;
+        let sql_query_hedge_delay = self.rpc_config.sql_query_hedge_delay;
+        let sql_query_hedge_max_in_flight = self.rpc_config.sql_query_hedge_max_in_flight;
+        let circuit_breaker = self.rpc_config.circuit_breaker.clone();
+        let reconnect = self.rpc_config.reconnect.clone();
+        let negotiate_capabilities = self.rpc_config.negotiate_capabilities;
+        let health_check = self.rpc_config.health_check.clone();
+        let passive_health = self.rpc_config.passive_health.clone();
+        let read_your_writes = self.rpc_config.read_your_writes.clone();
+        let discovery_refresh_interval = self.rpc_config.discovery_refresh_interval;
+        let routing_budget = self.rpc_config.routing_budget.clone();
+        let route_cache_shard_amount = self.rpc_config.route_cache_shard_amount;
+        let load_balance_policy = self.rpc_config.load_balance_policy;
+        let strict_routing = self.rpc_config.strict_routing;
+        let max_rows_per_write = self.rpc_config.max_rows_per_write;
+        let split_write_concurrency = self.rpc_config.split_write_concurrency;
+        let rpc_client_factory = Arc::new(
+            RpcClientImplFactory::new(
+                self.rpc_config,
+                self.authenticator,
+                self.interceptors.with_clock(self.clock.clone()),
+            )
+            .with_capture(self.capture)
+            .with_metrics(self.metrics.clone())
+            .with_spawner(spawner.clone()),
+        );
+        let default_database = self.default_database.clone();
+        let write_stats = self
+            .write_stats
+            .map(|config| Arc::new(WriteStatsRecorder::new(config, self.clock.clone())));
+        let group_committer = self.group_commit.map(|config| {
+            Arc::new(GroupCommitter::new(config, self.clock.clone()).with_spawner(spawner.clone()))
+        });
+
+        let client: Arc<dyn DbClient> = match self.mode {
+            Mode::Direct => Arc::new(
+                RouteBasedImpl::new(
+                    rpc_client_factory,
+                    self.endpoint,
+                    self.fallback_router_endpoint,
+                    self.default_database,
+                    slow_request_logger,
+                    route_timeout,
+                    max_consecutive_failures,
+                )
+                .with_routing_budget(routing_budget)
+                .with_route_cache_shard_amount(route_cache_shard_amount)
+                .with_load_balance_policy(load_balance_policy)
+                .with_strict_routing(strict_routing)
+                .with_hedge(sql_query_hedge_delay, sql_query_hedge_max_in_flight)
+                .with_refresh_dns_on_failure(refresh_dns_on_failure)
+                .with_max_channel_age(max_channel_age)
+                .with_idle_timeout(idle_timeout)
+                .with_retry_policy(self.retry_policy)
+                .with_circuit_breaker(circuit_breaker)
+                .with_reconnect(reconnect)
+                .with_negotiate_capabilities(negotiate_capabilities)
+                .with_write_split(max_rows_per_write, split_write_concurrency)
+                .with_health_check(health_check, self.health_probe)
+                .with_passive_health(passive_health)
+                .with_read_your_writes(read_your_writes)
+                .with_related_tables(self.related_tables)
+                .with_route_change_hook(self.route_change_hook)
+                .with_route_cache(self.route_cache)
+                .with_route_overrides(RouteOverrides::new(self.route_overrides))
+                .with_discovery(self.discovery, discovery_refresh_interval)
+                .with_write_stats(write_stats)
+                .with_warning_hook(self.warning_hook)
+                .with_group_committer(group_committer)
+                .with_query_guard(self.query_guard.clone())
+                .with_metrics(self.metrics)
+                .with_spawner(spawner)
+                .with_clock(self.clock.clone()),
+            ),
+            Mode::Proxy => Arc::new(
+                RawImpl::new(
+                    rpc_client_factory,
+                    self.endpoint,
+                    self.default_database,
+                    slow_request_logger,
+                    max_consecutive_failures,
+                )
+                .with_refresh_dns_on_failure(refresh_dns_on_failure)
+                .with_max_channel_age(max_channel_age)
+                .with_idle_timeout(idle_timeout)
+                .with_retry_policy(self.retry_policy)
+                .with_circuit_breaker(circuit_breaker)
+                .with_reconnect(reconnect)
+                .with_negotiate_capabilities(negotiate_capabilities)
+                .with_write_split(max_rows_per_write, split_write_concurrency)
+                .with_write_stats(write_stats)
+                .with_warning_hook(self.warning_hook)
+                .with_query_guard(self.query_guard)
+                .with_metrics(self.metrics)
+                .with_spawner(spawner)
+                .with_clock(self.clock.clone()),
+            ),
+        };
+
+        let client: Arc<dyn DbClient> = match self.auto_create_table {
+            Some(config) => Arc::new(AutoCreateTableClient::new(client, config)),
+            None => client,
+        };
+
+        let client: Arc<dyn DbClient> = match self.query_cache {
+            Some(config) => Arc::new(
+                QueryCachingClient::new(client, config, default_database).with_clock(self.clock),
+            ),
+            None => client,
+        };
+
+        match self.default_context {
+            Some(ctx) => Arc::new(DefaultContextClient::new(client, ctx)),
+            None => client,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::{Builder, Mode};
+    use crate::{
+        config::{AutoCreateTableConfig, QueryCacheConfig},
+        rpc_client::RpcContext,
+        RpcConfig,
+    };
+
+    #[tokio::test]
+    async fn test_config() {
+        let rpc_config = RpcConfig {
+            default_sql_query_timeout: Duration::from_secs(7),
+            max_recv_msg_len: 1024,
+            ..Default::default()
+        };
+        for mode in [Mode::Direct, Mode::Proxy] {
+            // The config is passed through all the wrapping clients as is.
+            let client = Builder::new("127.0.0.1:8831".to_string(), mode)
+                .rpc_config(rpc_config.clone())
+                .auto_create_table(AutoCreateTableConfig::default())
+                .query_cache(QueryCacheConfig::default())
+                .default_context(RpcContext::default().database("public".to_string()))
+                .build();
+            let config = client.config();
+            assert_eq!(config.default_sql_query_timeout, Duration::from_secs(7));
+            assert_eq!(config.max_recv_msg_len, 1024);
         }
     }
 }
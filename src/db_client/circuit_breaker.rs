@@ -0,0 +1,182 @@
+// Copyright 2022 CeresDB Project Authors. Licensed under Apache-2.0.
+
+//! Circuit breaker of the requests to an endpoint
+
+use std::{
+    sync::{Mutex, PoisonError},
+    time::Instant,
+};
+
+use crate::config::CircuitBreakerConfig;
+
+/// State of the circuit breaker of an endpoint.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BreakerState {
+    /// The requests are sent normally.
+    Closed,
+    /// The requests are short-circuited until the cooldown ends.
+    Open {
+        /// When the cooldown ends.
+        until: Instant,
+    },
+    /// The cooldown has ended, and only one request is sent to probe whether
+    /// the endpoint recovers.
+    HalfOpen,
+}
+
+#[derive(Debug)]
+struct BreakerStatus {
+    state: BreakerState,
+    consecutive_failures: usize,
+    // When the probing request in the half-open state is sent, and another
+    // probe is allowed if it hasn't completed within the cooldown.
+    probing_since: Option<Instant>,
+}
+
+/// Short-circuit the requests to an endpoint for a cooldown after
+/// `failure_threshold` consecutive failures, and let one request probe the
+/// endpoint after the cooldown.
+///
+/// The current time is passed in by the owner, which reads it from its clock.
+#[derive(Debug)]
+pub(crate) struct CircuitBreaker {
+    config: CircuitBreakerConfig,
+    status: Mutex<BreakerStatus>,
+}
+
+impl CircuitBreaker {
+    pub fn new(config: CircuitBreakerConfig) -> Self {
+        Self {
+            config,
+            status: Mutex::new(BreakerStatus {
+                state: BreakerState::Closed,
+                consecutive_failures: 0,
+                probing_since: None,
+            }),
+        }
+    }
+
+    pub fn state(&self, now: Instant) -> BreakerState {
+        let mut status = self.status.lock().unwrap_or_else(PoisonError::into_inner);
+        Self::maybe_half_open(&mut status, now);
+        status.state.clone()
+    }
+
+    /// Check whether the request is allowed, and the allowed request in the
+    /// half-open state becomes the probing one.
+    pub fn try_acquire(&self, now: Instant) -> bool {
+        let mut status = self.status.lock().unwrap_or_else(PoisonError::into_inner);
+        Self::maybe_half_open(&mut status, now);
+        match status.state {
+            BreakerState::Closed => true,
+            BreakerState::Open { .. } => false,
+            BreakerState::HalfOpen => match status.probing_since {
+                Some(since) if now.saturating_duration_since(since) < self.config.cooldown => false,
+                _ => {
+                    status.probing_since = Some(now);
+                    true
+                }
+            },
+        }
+    }
+
+    pub fn on_success(&self) {
+        let mut status = self.status.lock().unwrap_or_else(PoisonError::into_inner);
+        status.state = BreakerState::Closed;
+        status.consecutive_failures = 0;
+        status.probing_since = None;
+    }
+
+    pub fn on_failure(&self, now: Instant) {
+        let mut status = self.status.lock().unwrap_or_else(PoisonError::into_inner);
+        status.consecutive_failures += 1;
+        let trip = status.state == BreakerState::HalfOpen
+            || status.consecutive_failures >= self.config.failure_threshold;
+        if trip {
+            status.state = BreakerState::Open {
+                until: now + self.config.cooldown,
+            };
+            status.probing_since = None;
+        }
+    }
+
+    /// Release the probing request whose result tells nothing about the
+    /// endpoint, e.g. rejected by the client before sent.
+    pub fn release(&self) {
+        self.status
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .probing_since = None;
+    }
+
+    fn maybe_half_open(status: &mut BreakerStatus, now: Instant) {
+        if let BreakerState::Open { until } = status.state {
+            if until <= now {
+                status.state = BreakerState::HalfOpen;
+                status.probing_since = None;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::{BreakerState, CircuitBreaker};
+    use crate::{
+        clock::{Clock, ManualClock},
+        config::CircuitBreakerConfig,
+    };
+
+    #[test]
+    fn test_circuit_breaker() {
+        let clock = ManualClock::new();
+        let cooldown = Duration::from_secs(10);
+        let breaker = CircuitBreaker::new(CircuitBreakerConfig {
+            failure_threshold: 2,
+            cooldown,
+        });
+
+        assert!(breaker.try_acquire(clock.now()));
+        breaker.on_failure(clock.now());
+        assert_eq!(breaker.state(clock.now()), BreakerState::Closed);
+        breaker.on_success();
+        breaker.on_failure(clock.now());
+        assert_eq!(breaker.state(clock.now()), BreakerState::Closed);
+        breaker.on_failure(clock.now());
+        assert_eq!(
+            breaker.state(clock.now()),
+            BreakerState::Open {
+                until: clock.now() + cooldown
+            }
+        );
+        assert!(!breaker.try_acquire(clock.now()));
+
+        // Only one probing request in the half-open state, and its failure opens the
+        // breaker again.
+        clock.advance(cooldown);
+        assert_eq!(breaker.state(clock.now()), BreakerState::HalfOpen);
+        assert!(breaker.try_acquire(clock.now()));
+        assert!(!breaker.try_acquire(clock.now()));
+        breaker.on_failure(clock.now());
+        assert!(matches!(
+            breaker.state(clock.now()),
+            BreakerState::Open { .. }
+        ));
+
+        // Another probe is allowed if the probing one hangs for the cooldown.
+        clock.advance(cooldown);
+        assert!(breaker.try_acquire(clock.now()));
+        clock.advance(cooldown / 2);
+        assert!(!breaker.try_acquire(clock.now()));
+        clock.advance(cooldown / 2);
+        assert!(breaker.try_acquire(clock.now()));
+
+        // The success of the probing request closes the breaker.
+        breaker.on_success();
+        assert_eq!(breaker.state(clock.now()), BreakerState::Closed);
+        assert!(breaker.try_acquire(clock.now()));
+        assert!(breaker.try_acquire(clock.now()));
+    }
+}
@@ -0,0 +1,122 @@
+// Copyright 2022 CeresDB Project Authors. Licensed under Apache-2.0.
+
+//! Client pinned to a database
+
+use std::{borrow::Cow, sync::Arc};
+
+use crate::{db_client::DbClient, model::route::Endpoint, rpc_client::RpcContext, Result};
+
+/// Client whose requests are always sent to the pinned database, no matter
+/// what database is set in the [`RpcContext`].
+///
+/// It shares the connections and route cache with the underlying client, so
+/// it is cheap to build one for every database.
+#[derive(Clone)]
+pub struct DatabaseScopedClient {
+    client: Arc<dyn DbClient>,
+    database: String,
+}
+
+impl DatabaseScopedClient {
+    pub fn new(client: Arc<dyn DbClient>, database: String) -> Self {
+        Self { client, database }
+    }
+
+    /// Get the pinned database.
+    pub fn database(&self) -> &str {
+        &self.database
+    }
+
+    #[inline]
+    fn pin_database(&self, ctx: &RpcContext) -> RpcContext {
+        ctx.clone().database(self.database.clone())
+    }
+}
+
+impl dyn DbClient {
+    /// Get a client pinned to the `database`.
+    pub fn with_database(self: &Arc<Self>, database: &str) -> DatabaseScopedClient {
+        DatabaseScopedClient::new(self.clone(), database.to_string())
+    }
+}
+
+forward_db_client! {
+    impl DbClient for DatabaseScopedClient {
+        forward [
+            sql_query, write, write_stream, sql_query_paged, connection_states, config, clock,
+            resolve_route_uncached, route_tables, route_replicas, check_database, is_healthy,
+            last_write_endpoint, server_capabilities, evict_routes_by_endpoint, hedge_stats,
+            group_commit_stats, channel_stats, circuit_breaker_states, write_stats,
+            reset_write_stats, cache_stats,
+        ] to self.client,
+            context ctx => Cow::Owned(self.pin_database(ctx));
+
+        fn add_route_override(
+            &self,
+            _database: Option<&str>,
+            table: &str,
+            endpoint: Endpoint,
+        ) -> Result<Option<Endpoint>> {
+            self.client
+                .add_route_override(Some(&self.database), table, endpoint)
+        }
+
+        fn remove_route_override(
+            &self,
+            _database: Option<&str>,
+            table: &str,
+        ) -> Result<Option<Endpoint>> {
+            self.client
+                .remove_route_override(Some(&self.database), table)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, Mutex};
+
+    use futures::{stream, StreamExt, TryStreamExt};
+
+    use crate::{
+        db_client::{DbClient, MockDbClient},
+        model::sql_query::{Request as SqlQueryRequest, Response as SqlQueryResponse},
+        rpc_client::RpcContext,
+    };
+
+    type Databases = Arc<Mutex<Vec<Option<String>>>>;
+
+    /// Client serving the pages in one go, recording the databases of the
+    /// paged queries.
+    fn paged_client(databases: Databases) -> MockDbClient {
+        MockDbClient::default().on_sql_query_paged(move |ctx, _req, _page_size| {
+            databases.lock().unwrap().push(ctx.database);
+            stream::iter((0..3).map(|_| Ok(SqlQueryResponse::default()))).boxed()
+        })
+    }
+
+    #[tokio::test]
+    async fn test_sql_query_paged() {
+        let databases = Databases::default();
+        let client: Arc<dyn DbClient> = Arc::new(paged_client(databases.clone()));
+        let scoped = client.with_database("metrics");
+
+        let ctx = RpcContext::default().database("public".to_string());
+        let req = SqlQueryRequest::new(
+            vec!["test_table".to_string()],
+            "SELECT * FROM test_table".to_string(),
+        );
+        let pages: Vec<_> = scoped
+            .sql_query_paged(&ctx, &req, 2)
+            .try_collect()
+            .await
+            .unwrap();
+
+        // All the pages are from the single paged query to the pinned database.
+        assert_eq!(pages.len(), 3);
+        assert_eq!(
+            *databases.lock().unwrap(),
+            vec![Some("metrics".to_string())]
+        );
+    }
+}
@@ -0,0 +1,123 @@
+// Copyright 2022 CeresDB Project Authors. Licensed under Apache-2.0.
+
+//! Deadline of the requests split between routing and execution
+
+use std::{
+    future::Future,
+    time::{Duration, Instant},
+};
+
+use tonic::Code;
+
+use crate::{
+    clock::{self, Clock},
+    config::RoutingBudget,
+    errors::TimeoutPhase,
+    rpc_client::RpcContext,
+    Error, Result,
+};
+
+/// Deadline computed from the timeout of the context at the entry of a
+/// request, and the time is spent on routing first within the
+/// [`RoutingBudget`], then the rest is left for the execution.
+///
+/// Nothing is limited if the context has no timeout.
+pub(crate) struct Deadline<'a> {
+    clock: &'a dyn Clock,
+    // The timeout and the deadline derived from it.
+    deadline: Option<(Duration, Instant)>,
+}
+
+impl<'a> Deadline<'a> {
+    pub fn start(clock: &'a dyn Clock, ctx: &RpcContext) -> Self {
+        let deadline = ctx.timeout.map(|timeout| (timeout, clock.now() + timeout));
+        Self { clock, deadline }
+    }
+
+    /// Run the `route` within the `budget`, and the context passed to it has
+    /// the budget as the timeout, so do the route rpcs.
+    pub async fn route<T, R, Fut>(
+        &self,
+        budget: &RoutingBudget,
+        ctx: &RpcContext,
+        route: R,
+    ) -> Result<T>
+    where
+        R: FnOnce(RpcContext) -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        let (timeout, deadline) = match self.deadline {
+            Some(deadline) => deadline,
+            None => return route(ctx.clone()).await,
+        };
+
+        let mut limit = timeout.mul_f64(budget.fraction.clamp(0.0, 1.0));
+        if let Some(cap) = budget.cap {
+            limit = limit.min(cap);
+        }
+        let limit = limit.min(deadline.saturating_duration_since(self.clock.now()));
+        let route = route(ctx.clone().timeout(limit));
+        match clock::timeout(self.clock, limit, route).await {
+            Some(result) => self.tag(TimeoutPhase::Routing, result),
+            None => Err(self.timed_out(TimeoutPhase::Routing)),
+        }
+    }
+
+    /// Get the context to execute the request with the rest of the time as
+    /// the timeout.
+    pub fn execution_ctx(&self, ctx: &RpcContext) -> Result<RpcContext> {
+        let deadline = match self.deadline {
+            Some((_, deadline)) => deadline,
+            None => return Ok(ctx.clone()),
+        };
+
+        let remaining = deadline.saturating_duration_since(self.clock.now());
+        if remaining.is_zero() {
+            return Err(self.timed_out(TimeoutPhase::Execution));
+        }
+        Ok(ctx.clone().timeout(remaining))
+    }
+
+    /// Run the `execution` within the rest of the time, and it is cancelled
+    /// once the deadline is exceeded, even if the rpcs in it don't respect
+    /// their own timeouts.
+    pub async fn execute<T, Fut>(&self, execution: Fut) -> Result<T>
+    where
+        Fut: Future<Output = Result<T>>,
+    {
+        let deadline = match self.deadline {
+            Some((_, deadline)) => deadline,
+            None => return execution.await,
+        };
+
+        let remaining = deadline.saturating_duration_since(self.clock.now());
+        match clock::timeout(self.clock, remaining, execution).await {
+            Some(result) => self.tag_execution(result),
+            None => Err(self.timed_out(TimeoutPhase::Execution)),
+        }
+    }
+
+    /// Tag the deadline exceeded error of the execution with the phase.
+    pub fn tag_execution<T>(&self, result: Result<T>) -> Result<T> {
+        self.tag(TimeoutPhase::Execution, result)
+    }
+
+    fn tag<T>(&self, phase: TimeoutPhase, result: Result<T>) -> Result<T> {
+        match result {
+            Err(Error::Rpc(e)) if e.code() == Code::DeadlineExceeded && self.deadline.is_some() => {
+                Err(self.timed_out(phase))
+            }
+            result => result,
+        }
+    }
+
+    fn timed_out(&self, phase: TimeoutPhase) -> Error {
+        Error::Timeout {
+            phase,
+            timeout: self
+                .deadline
+                .map(|(timeout, _)| timeout)
+                .unwrap_or_default(),
+        }
+    }
+}
@@ -0,0 +1,160 @@
+// Copyright 2022 CeresDB Project Authors. Licensed under Apache-2.0.
+
+//! Client filling the unset fields of the contexts by a default one
+
+use std::{borrow::Cow, sync::Arc};
+
+use crate::{db_client::DbClient, rpc_client::RpcContext};
+
+/// Wrap the client to fill the fields not set in the context of every request
+/// by the `default_context`, see
+/// [`Builder::default_context`](crate::Builder::default_context).
+pub(crate) struct DefaultContextClient {
+    client: Arc<dyn DbClient>,
+    default_context: RpcContext,
+}
+
+impl DefaultContextClient {
+    pub fn new(client: Arc<dyn DbClient>, default_context: RpcContext) -> Self {
+        Self {
+            client,
+            default_context,
+        }
+    }
+
+    #[inline]
+    fn resolve_context(&self, ctx: &RpcContext) -> RpcContext {
+        self.default_context.overridden_by(ctx)
+    }
+}
+
+forward_db_client! {
+    impl DbClient for DefaultContextClient {
+        forward [
+            sql_query, write, write_stream, sql_query_paged, connection_states, config, clock,
+            resolve_route_uncached, route_tables, route_replicas, check_database,
+            add_route_override, remove_route_override, is_healthy, last_write_endpoint,
+            server_capabilities, evict_routes_by_endpoint, hedge_stats, group_commit_stats,
+            channel_stats, circuit_breaker_states, write_stats, reset_write_stats, cache_stats,
+        ] to self.client,
+            context ctx => Cow::Owned(self.resolve_context(ctx));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use futures::future;
+
+    use super::DefaultContextClient;
+    use crate::{
+        db_client::{DbClient, MockDbClient},
+        model::{
+            sql_query::{Request as SqlQueryRequest, Response as SqlQueryResponse},
+            write::{Request as WriteRequest, Response as WriteResponse},
+        },
+        rpc_client::{Consistency, Priority, RpcContext, SessionSettings},
+    };
+
+    /// Context of the last request of the recording client.
+    #[derive(Default)]
+    struct LastContext(Mutex<Option<RpcContext>>);
+
+    type ContextFields = (
+        Option<String>,
+        Option<Duration>,
+        Option<Consistency>,
+        Priority,
+        Option<SessionSettings>,
+    );
+
+    impl LastContext {
+        fn fields(&self) -> ContextFields {
+            let ctx = self.0.lock().unwrap().clone().unwrap();
+            context_fields(ctx)
+        }
+    }
+
+    fn context_fields(ctx: RpcContext) -> ContextFields {
+        (
+            ctx.database,
+            ctx.timeout,
+            ctx.consistency,
+            ctx.priority,
+            ctx.settings,
+        )
+    }
+
+    /// Client recording the context of the last request.
+    fn recording_client(last_ctx: Arc<LastContext>) -> Arc<MockDbClient> {
+        let queried_ctx = last_ctx.clone();
+        let client = MockDbClient::default()
+            .on_sql_query(move |ctx, _req| {
+                *queried_ctx.0.lock().unwrap() = Some(ctx);
+                future::ok(SqlQueryResponse::default())
+            })
+            .on_write(move |ctx, _req| {
+                *last_ctx.0.lock().unwrap() = Some(ctx);
+                future::ok(WriteResponse::new(0, 0))
+            });
+        Arc::new(client)
+    }
+
+    #[tokio::test]
+    async fn test_default_context() {
+        let last_ctx = Arc::new(LastContext::default());
+        let default_context = RpcContext::default()
+            .database("db".to_string())
+            .timeout(Duration::from_secs(5))
+            .priority(Priority::Low)
+            .settings(
+                SessionSettings::default()
+                    .timezone("UTC".to_string())
+                    .setting("a".to_string(), "1".to_string()),
+            );
+        let client =
+            DefaultContextClient::new(recording_client(last_ctx.clone()), default_context.clone());
+        let req = SqlQueryRequest::new(vec!["table".to_string()], "SELECT 1".to_string());
+
+        // The default context is used as is.
+        client.sql_query_default_ctx(&req).await.unwrap();
+        assert_eq!(last_ctx.fields(), context_fields(default_context.clone()));
+        client
+            .write_default_ctx(&WriteRequest::default())
+            .await
+            .unwrap();
+        assert_eq!(last_ctx.fields(), context_fields(default_context.clone()));
+
+        // The fields set in the context of the request win one by one.
+        let ctx = RpcContext::default()
+            .timeout(Duration::from_secs(1))
+            .consistency(Consistency::Strong)
+            .settings(SessionSettings::default().setting("b".to_string(), "2".to_string()));
+        client.sql_query(&ctx, &req).await.unwrap();
+        let expected = RpcContext::default()
+            .database("db".to_string())
+            .timeout(Duration::from_secs(1))
+            .consistency(Consistency::Strong)
+            .priority(Priority::Low)
+            .settings(
+                SessionSettings::default()
+                    .timezone("UTC".to_string())
+                    .setting("a".to_string(), "1".to_string())
+                    .setting("b".to_string(), "2".to_string()),
+            );
+        assert_eq!(last_ctx.fields(), context_fields(expected));
+
+        let ctx = RpcContext::default()
+            .database("other_db".to_string())
+            .priority(Priority::High);
+        client.write(&ctx, &WriteRequest::default()).await.unwrap();
+        let (database, timeout, _, priority, _) = last_ctx.fields();
+        assert_eq!(database.as_deref(), Some("other_db"));
+        assert_eq!(timeout, Some(Duration::from_secs(5)));
+        assert_eq!(priority, Priority::High);
+    }
+}
@@ -0,0 +1,322 @@
+// Copyright 2022 CeresDB Project Authors. Licensed under Apache-2.0.
+
+//! Discovery of the default endpoints
+
+use std::{
+    fmt,
+    sync::{Arc, Mutex, PoisonError},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use ceresdbproto::storage::{
+    RouteRequest as RouteRequestPb, SqlQueryRequest as QueryRequestPb,
+    SqlQueryResponse as QueryResponsePb, WriteRequest as WriteRequestPb,
+    WriteResponse as WriteResponsePb,
+};
+use futures::channel::mpsc::UnboundedReceiver;
+use tokio::task::JoinHandle;
+
+use crate::{
+    clock::Clock,
+    model::route::Endpoint,
+    router::DefaultEndpoints,
+    rpc_client::{
+        ErrorContextRpcClient, RouteResponse, RpcClient, RpcClientFactory, RpcContext, RpcResponse,
+    },
+    spawner::Spawner,
+    Error, Result,
+};
+
+/// Provider of the default endpoints of the cluster, which is called
+/// periodically to follow the scaling of the cluster.
+#[async_trait]
+pub trait DiscoveryProvider: fmt::Debug + Send + Sync {
+    async fn endpoints(&self) -> Result<Vec<Endpoint>>;
+}
+
+/// Fixed list of the endpoints.
+#[derive(Debug, Clone)]
+pub struct StaticList(pub Vec<Endpoint>);
+
+#[async_trait]
+impl DiscoveryProvider for StaticList {
+    async fn endpoints(&self) -> Result<Vec<Endpoint>> {
+        Ok(self.0.clone())
+    }
+}
+
+/// Discover the endpoints by resolving the `host` to all its A/AAAA records
+/// on every call, and all of them serve at the `port`.
+#[derive(Debug, Clone)]
+pub struct DnsDiscovery {
+    host: String,
+    port: u16,
+}
+
+impl DnsDiscovery {
+    pub fn new(host: String, port: u16) -> Self {
+        Self { host, port }
+    }
+}
+
+#[async_trait]
+impl DiscoveryProvider for DnsDiscovery {
+    async fn endpoints(&self) -> Result<Vec<Endpoint>> {
+        let addrs = tokio::net::lookup_host((self.host.as_str(), self.port))
+            .await
+            .map_err(|e| Error::Client(format!("failed to resolve host:{}, err:{e}", self.host)))?;
+
+        // Sorted so that the primary doesn't change with the order of the records.
+        let mut endpoints: Vec<_> = addrs
+            .map(|addr| Endpoint::new(addr.ip().to_string(), addr.port() as u32))
+            .collect();
+        endpoints.sort_by_key(|endpoint| endpoint.to_string());
+        endpoints.dedup();
+
+        Ok(endpoints)
+    }
+}
+
+impl DefaultEndpoints {
+    /// Replace the endpoints by the ones got from the `provider`, and the
+    /// removed ones are returned.
+    ///
+    /// The endpoints are kept if the `provider` fails or finds nothing.
+    pub async fn refresh(&self, provider: &dyn DiscoveryProvider) -> Result<Vec<Endpoint>> {
+        let endpoints = provider.endpoints().await?;
+        if endpoints.is_empty() {
+            return Err(Error::Client("no endpoint is discovered".to_string()));
+        }
+
+        Ok(self.swap(endpoints))
+    }
+}
+
+/// The task refreshing the default endpoints in the background every
+/// interval, which is stopped when dropped.
+#[derive(Debug)]
+pub(crate) struct DiscoveryRefresher {
+    handle: JoinHandle<()>,
+}
+
+impl DiscoveryRefresher {
+    /// Refresh the `endpoints` by the `provider` every `interval` measured by
+    /// the `clock` on the runtime of the `spawner`, starting after one
+    /// interval, and the removed endpoints are passed to `on_removed`.
+    pub fn start<R>(
+        spawner: &Spawner,
+        provider: Arc<dyn DiscoveryProvider>,
+        interval: Duration,
+        clock: Arc<dyn Clock>,
+        endpoints: Arc<DefaultEndpoints>,
+        on_removed: R,
+    ) -> Self
+    where
+        R: Fn(&[Endpoint]) + Send + 'static,
+    {
+        let handle = spawner.spawn(async move {
+            loop {
+                clock.sleep(interval).await;
+                match endpoints.refresh(provider.as_ref()).await {
+                    Ok(removed) => on_removed(&removed),
+                    Err(_e) => {
+                        #[cfg(feature = "tracing")]
+                        tracing::warn!(error = %_e, "failed to refresh the default endpoints");
+                    }
+                }
+            }
+        });
+
+        Self { handle }
+    }
+}
+
+impl Drop for DiscoveryRefresher {
+    fn drop(&mut self) {
+        self.handle.abort();
+    }
+}
+
+/// Rpc client to the primary of the default endpoints, and the client to the
+/// previous primary is dropped once another one becomes the primary, so its
+/// channel is closed after the rpcs in flight complete.
+pub(crate) struct PrimaryRpcClient<F: RpcClientFactory> {
+    factory: Arc<F>,
+    endpoints: Arc<DefaultEndpoints>,
+    primary: Mutex<Option<(Endpoint, Arc<dyn RpcClient>)>>,
+    clock: Arc<dyn Clock>,
+}
+
+impl<F: RpcClientFactory> PrimaryRpcClient<F> {
+    pub fn new(factory: Arc<F>, endpoints: Arc<DefaultEndpoints>, clock: Arc<dyn Clock>) -> Self {
+        Self {
+            factory,
+            endpoints,
+            primary: Mutex::new(None),
+            clock,
+        }
+    }
+
+    async fn client(&self) -> Result<Arc<dyn RpcClient>> {
+        let endpoint = self
+            .endpoints
+            .primary()
+            .ok_or_else(|| Error::Client("no default endpoint is discovered".to_string()))?;
+        if let Some((primary, client)) =
+            &*self.primary.lock().unwrap_or_else(PoisonError::into_inner)
+        {
+            if primary == &endpoint {
+                return Ok(client.clone());
+            }
+        }
+
+        let client = self.factory.build(endpoint.to_string()).await?;
+        let client: Arc<dyn RpcClient> = Arc::new(ErrorContextRpcClient::new(
+            client,
+            endpoint.to_string(),
+            self.clock.clone(),
+        ));
+        *self.primary.lock().unwrap_or_else(PoisonError::into_inner) =
+            Some((endpoint, client.clone()));
+        Ok(client)
+    }
+}
+
+#[async_trait]
+impl<F: RpcClientFactory> RpcClient for PrimaryRpcClient<F> {
+    async fn sql_query(
+        &self,
+        ctx: &RpcContext,
+        req: QueryRequestPb,
+    ) -> Result<RpcResponse<QueryResponsePb>> {
+        self.client().await?.sql_query(ctx, req).await
+    }
+
+    async fn write(
+        &self,
+        ctx: &RpcContext,
+        req: WriteRequestPb,
+    ) -> Result<RpcResponse<WriteResponsePb>> {
+        self.client().await?.write(ctx, req).await
+    }
+
+    async fn route(&self, ctx: &RpcContext, req: RouteRequestPb) -> Result<RouteResponse> {
+        self.client().await?.route(ctx, req).await
+    }
+
+    async fn stream_write(
+        &self,
+        ctx: &RpcContext,
+        reqs: UnboundedReceiver<WriteRequestPb>,
+    ) -> Result<RpcResponse<WriteResponsePb>> {
+        self.client().await?.stream_write(ctx, reqs).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, Mutex};
+
+    use async_trait::async_trait;
+    use ceresdbproto::storage::RouteRequest as RouteRequestPb;
+
+    use super::{DiscoveryProvider, DnsDiscovery, PrimaryRpcClient};
+    use crate::{
+        clock::SystemClock,
+        model::route::Endpoint,
+        router::DefaultEndpoints,
+        rpc_client::{MockRpcClient, MockRpcClientFactory, RpcClient, RpcContext},
+        Error, Result,
+    };
+
+    /// Provider returning the endpoints set, and failing if none.
+    #[derive(Debug, Default)]
+    struct MockProvider(Mutex<Option<Vec<Endpoint>>>);
+
+    #[async_trait]
+    impl DiscoveryProvider for MockProvider {
+        async fn endpoints(&self) -> Result<Vec<Endpoint>> {
+            self.0
+                .lock()
+                .unwrap()
+                .clone()
+                .ok_or_else(|| Error::Unknown("discovery failed".to_string()))
+        }
+    }
+
+    fn endpoint(n: u32) -> Endpoint {
+        Endpoint::new(format!("192.168.0.{n}"), 8831)
+    }
+
+    #[tokio::test]
+    async fn test_refresh() {
+        let provider = MockProvider::default();
+        let endpoints = DefaultEndpoints::default();
+
+        *provider.0.lock().unwrap() = Some(vec![endpoint(1), endpoint(2)]);
+        assert!(endpoints.refresh(&provider).await.unwrap().is_empty());
+        assert_eq!(*endpoints.load(), vec![endpoint(1), endpoint(2)]);
+
+        *provider.0.lock().unwrap() = Some(vec![endpoint(2), endpoint(3)]);
+        assert_eq!(
+            endpoints.refresh(&provider).await.unwrap(),
+            vec![endpoint(1)]
+        );
+        assert_eq!(endpoints.primary(), Some(endpoint(2)));
+
+        // The endpoints are kept if nothing is discovered or the discovery fails.
+        *provider.0.lock().unwrap() = Some(Vec::new());
+        assert!(endpoints.refresh(&provider).await.is_err());
+        *provider.0.lock().unwrap() = None;
+        assert!(endpoints.refresh(&provider).await.is_err());
+        assert_eq!(*endpoints.load(), vec![endpoint(2), endpoint(3)]);
+    }
+
+    #[tokio::test]
+    async fn test_primary_rpc_client() {
+        // The endpoints of the built clients are recorded.
+        let built = Arc::new(Mutex::new(Vec::new()));
+        let factory = {
+            let built = built.clone();
+            MockRpcClientFactory::new(move |endpoint| {
+                built.lock().unwrap().push(endpoint);
+                MockRpcClient::default()
+            })
+        };
+        let endpoints = Arc::new(DefaultEndpoints::default());
+        let client =
+            PrimaryRpcClient::new(Arc::new(factory), endpoints.clone(), Arc::new(SystemClock));
+        let ctx = RpcContext::default();
+
+        assert!(client.route(&ctx, RouteRequestPb::default()).await.is_err());
+
+        endpoints.swap(vec![endpoint(1), endpoint(2)]);
+        for _ in 0..2 {
+            client.route(&ctx, RouteRequestPb::default()).await.unwrap();
+        }
+        // Adding an endpoint behind the primary changes nothing.
+        endpoints.swap(vec![endpoint(1), endpoint(2), endpoint(3)]);
+        client.route(&ctx, RouteRequestPb::default()).await.unwrap();
+        assert_eq!(*built.lock().unwrap(), vec![endpoint(1).to_string()]);
+
+        // Follow the new primary once the old one is removed.
+        endpoints.swap(vec![endpoint(2), endpoint(3)]);
+        client.route(&ctx, RouteRequestPb::default()).await.unwrap();
+        assert_eq!(
+            *built.lock().unwrap(),
+            vec![endpoint(1).to_string(), endpoint(2).to_string()]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_dns_discovery() {
+        let discovery = DnsDiscovery::new("localhost".to_string(), 8831);
+        let endpoints = discovery.endpoints().await.unwrap();
+        assert!(!endpoints.is_empty());
+        assert!(endpoints.iter().all(|endpoint| endpoint.port == 8831));
+
+        let discovery = DnsDiscovery::new("invalid host".to_string(), 8831);
+        assert!(discovery.endpoints().await.is_err());
+    }
+}
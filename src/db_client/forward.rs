@@ -0,0 +1,500 @@
+// Copyright 2022 CeresDB Project Authors. Licensed under Apache-2.0.
+
+//! Forwarding of the [`DbClient`] methods to the wrapped client
+
+use std::borrow::Cow;
+
+use futures::{
+    channel::mpsc,
+    future,
+    stream::{self, BoxStream},
+    FutureExt, SinkExt, StreamExt,
+};
+
+use crate::{
+    db_client::DbClient,
+    model::sql_query::{Request as SqlQueryRequest, Response as SqlQueryResponse},
+    rpc_client::RpcContext,
+    Result,
+};
+
+/// Implement the [`DbClient`] for a client wrapping another one, by the
+/// methods written out and the listed ones forwarded to the wrapped client.
+///
+/// The forwarded requests carry the `Cow<RpcContext>` mapped from the context
+/// `ctx` of every request, e.g. `Cow::Borrowed(ctx)` to forward it as is, and
+/// the methods without any context, e.g. the route overrides, are forwarded
+/// as is.
+///
+/// ```ignore
+/// forward_db_client! {
+///     impl DbClient for Wrapper {
+///         forward [sql_query, write, channel_stats] to self.client,
+///             context ctx => Cow::Borrowed(ctx);
+///
+///         fn cache_stats(&self) -> QueryCacheStats { .. }
+///     }
+/// }
+/// ```
+macro_rules! forward_db_client {
+    (
+        impl DbClient for $ty:ty {
+            forward [$($method:ident),* $(,)?] to $this:ident . $client:ident,
+                context $ctx:ident => $mapped:expr;
+
+            $($custom:tt)*
+        }
+    ) => {
+        forward_db_client!(
+            @munch ($ty, $this, $client, $ctx, $mapped) [$($method)*] [] $($custom)*
+        );
+    };
+
+    (
+        @munch ($ty:ty, $this:ident, $client:ident, $ctx:ident, $mapped:expr)
+        [] [$($forwarded:tt)*] $($custom:tt)*
+    ) => {
+        #[::async_trait::async_trait]
+        impl $crate::db_client::DbClient for $ty {
+            $($custom)*
+
+            $($forwarded)*
+        }
+    };
+
+    (
+        @munch ($ty:ty, $this:ident, $client:ident, $ctx:ident, $mapped:expr)
+        [sql_query $($rest:ident)*] [$($forwarded:tt)*] $($custom:tt)*
+    ) => {
+        forward_db_client!(
+            @munch ($ty, $this, $client, $ctx, $mapped) [$($rest)*] [
+                $($forwarded)*
+                async fn sql_query(
+                    &$this,
+                    $ctx: &$crate::rpc_client::RpcContext,
+                    req: &$crate::model::sql_query::Request,
+                ) -> $crate::Result<$crate::model::sql_query::Response> {
+                    $this.$client.sql_query(&$mapped, req).await
+                }
+            ] $($custom)*
+        );
+    };
+
+    (
+        @munch ($ty:ty, $this:ident, $client:ident, $ctx:ident, $mapped:expr)
+        [write $($rest:ident)*] [$($forwarded:tt)*] $($custom:tt)*
+    ) => {
+        forward_db_client!(
+            @munch ($ty, $this, $client, $ctx, $mapped) [$($rest)*] [
+                $($forwarded)*
+                async fn write(
+                    &$this,
+                    $ctx: &$crate::rpc_client::RpcContext,
+                    req: &$crate::model::write::Request,
+                ) -> $crate::Result<$crate::model::write::Response> {
+                    $this.$client.write(&$mapped, req).await
+                }
+            ] $($custom)*
+        );
+    };
+
+    (
+        @munch ($ty:ty, $this:ident, $client:ident, $ctx:ident, $mapped:expr)
+        [write_stream $($rest:ident)*] [$($forwarded:tt)*] $($custom:tt)*
+    ) => {
+        forward_db_client!(
+            @munch ($ty, $this, $client, $ctx, $mapped) [$($rest)*] [
+                $($forwarded)*
+                async fn write_stream(
+                    &$this,
+                    $ctx: &$crate::rpc_client::RpcContext,
+                    reqs: ::futures::stream::BoxStream<'_, $crate::model::write::Request>,
+                ) -> $crate::Result<$crate::model::write::Response> {
+                    $this.$client.write_stream(&$mapped, reqs).await
+                }
+            ] $($custom)*
+        );
+    };
+
+    (
+        @munch ($ty:ty, $this:ident, $client:ident, $ctx:ident, $mapped:expr)
+        [sql_query_paged $($rest:ident)*] [$($forwarded:tt)*] $($custom:tt)*
+    ) => {
+        forward_db_client!(
+            @munch ($ty, $this, $client, $ctx, $mapped) [$($rest)*] [
+                $($forwarded)*
+                fn sql_query_paged<'a>(
+                    &'a $this,
+                    $ctx: &'a $crate::rpc_client::RpcContext,
+                    req: &'a $crate::model::sql_query::Request,
+                    page_size: usize,
+                ) -> ::futures::stream::BoxStream<
+                    'a,
+                    $crate::Result<$crate::model::sql_query::Response>,
+                > {
+                    $crate::db_client::forward::forward_sql_query_paged(
+                        &*$this.$client,
+                        $mapped,
+                        req,
+                        page_size,
+                    )
+                }
+            ] $($custom)*
+        );
+    };
+
+    (
+        @munch ($ty:ty, $this:ident, $client:ident, $ctx:ident, $mapped:expr)
+        [connection_states $($rest:ident)*] [$($forwarded:tt)*] $($custom:tt)*
+    ) => {
+        forward_db_client!(
+            @munch ($ty, $this, $client, $ctx, $mapped) [$($rest)*] [
+                $($forwarded)*
+                fn connection_states(
+                    &$this,
+                ) -> Vec<($crate::model::route::Endpoint, $crate::db_client::ConnectionState)> {
+                    $this.$client.connection_states()
+                }
+            ] $($custom)*
+        );
+    };
+
+    (
+        @munch ($ty:ty, $this:ident, $client:ident, $ctx:ident, $mapped:expr)
+        [config $($rest:ident)*] [$($forwarded:tt)*] $($custom:tt)*
+    ) => {
+        forward_db_client!(
+            @munch ($ty, $this, $client, $ctx, $mapped) [$($rest)*] [
+                $($forwarded)*
+                fn config(&$this) -> $crate::RpcConfig {
+                    $this.$client.config()
+                }
+            ] $($custom)*
+        );
+    };
+
+    (
+        @munch ($ty:ty, $this:ident, $client:ident, $ctx:ident, $mapped:expr)
+        [clock $($rest:ident)*] [$($forwarded:tt)*] $($custom:tt)*
+    ) => {
+        forward_db_client!(
+            @munch ($ty, $this, $client, $ctx, $mapped) [$($rest)*] [
+                $($forwarded)*
+                fn clock(&$this) -> ::std::sync::Arc<dyn $crate::clock::Clock> {
+                    $this.$client.clock()
+                }
+            ] $($custom)*
+        );
+    };
+
+    (
+        @munch ($ty:ty, $this:ident, $client:ident, $ctx:ident, $mapped:expr)
+        [resolve_route_uncached $($rest:ident)*] [$($forwarded:tt)*] $($custom:tt)*
+    ) => {
+        forward_db_client!(
+            @munch ($ty, $this, $client, $ctx, $mapped) [$($rest)*] [
+                $($forwarded)*
+                async fn resolve_route_uncached(
+                    &$this,
+                    $ctx: &$crate::rpc_client::RpcContext,
+                    table: &str,
+                ) -> $crate::Result<Option<$crate::model::route::Endpoint>> {
+                    $this.$client.resolve_route_uncached(&$mapped, table).await
+                }
+            ] $($custom)*
+        );
+    };
+
+    (
+        @munch ($ty:ty, $this:ident, $client:ident, $ctx:ident, $mapped:expr)
+        [route_tables $($rest:ident)*] [$($forwarded:tt)*] $($custom:tt)*
+    ) => {
+        forward_db_client!(
+            @munch ($ty, $this, $client, $ctx, $mapped) [$($rest)*] [
+                $($forwarded)*
+                async fn route_tables(
+                    &$this,
+                    $ctx: &$crate::rpc_client::RpcContext,
+                    tables: &[String],
+                    force_refresh: bool,
+                ) -> $crate::Result<
+                    ::std::collections::HashMap<String, $crate::model::route::TableRoute>,
+                > {
+                    $this.$client.route_tables(&$mapped, tables, force_refresh).await
+                }
+            ] $($custom)*
+        );
+    };
+
+    (
+        @munch ($ty:ty, $this:ident, $client:ident, $ctx:ident, $mapped:expr)
+        [route_replicas $($rest:ident)*] [$($forwarded:tt)*] $($custom:tt)*
+    ) => {
+        forward_db_client!(
+            @munch ($ty, $this, $client, $ctx, $mapped) [$($rest)*] [
+                $($forwarded)*
+                async fn route_replicas(
+                    &$this,
+                    $ctx: &$crate::rpc_client::RpcContext,
+                    tables: &[String],
+                ) -> $crate::Result<
+                    ::std::collections::HashMap<String, $crate::model::route::Route>,
+                > {
+                    $this.$client.route_replicas(&$mapped, tables).await
+                }
+            ] $($custom)*
+        );
+    };
+
+    (
+        @munch ($ty:ty, $this:ident, $client:ident, $ctx:ident, $mapped:expr)
+        [check_database $($rest:ident)*] [$($forwarded:tt)*] $($custom:tt)*
+    ) => {
+        forward_db_client!(
+            @munch ($ty, $this, $client, $ctx, $mapped) [$($rest)*] [
+                $($forwarded)*
+                async fn check_database(
+                    &$this,
+                    $ctx: &$crate::rpc_client::RpcContext,
+                ) -> $crate::Result<bool> {
+                    $this.$client.check_database(&$mapped).await
+                }
+            ] $($custom)*
+        );
+    };
+
+    (
+        @munch ($ty:ty, $this:ident, $client:ident, $ctx:ident, $mapped:expr)
+        [add_route_override $($rest:ident)*] [$($forwarded:tt)*] $($custom:tt)*
+    ) => {
+        forward_db_client!(
+            @munch ($ty, $this, $client, $ctx, $mapped) [$($rest)*] [
+                $($forwarded)*
+                fn add_route_override(
+                    &$this,
+                    database: Option<&str>,
+                    table: &str,
+                    endpoint: $crate::model::route::Endpoint,
+                ) -> $crate::Result<Option<$crate::model::route::Endpoint>> {
+                    $this.$client.add_route_override(database, table, endpoint)
+                }
+            ] $($custom)*
+        );
+    };
+
+    (
+        @munch ($ty:ty, $this:ident, $client:ident, $ctx:ident, $mapped:expr)
+        [remove_route_override $($rest:ident)*] [$($forwarded:tt)*] $($custom:tt)*
+    ) => {
+        forward_db_client!(
+            @munch ($ty, $this, $client, $ctx, $mapped) [$($rest)*] [
+                $($forwarded)*
+                fn remove_route_override(
+                    &$this,
+                    database: Option<&str>,
+                    table: &str,
+                ) -> $crate::Result<Option<$crate::model::route::Endpoint>> {
+                    $this.$client.remove_route_override(database, table)
+                }
+            ] $($custom)*
+        );
+    };
+
+    (
+        @munch ($ty:ty, $this:ident, $client:ident, $ctx:ident, $mapped:expr)
+        [is_healthy $($rest:ident)*] [$($forwarded:tt)*] $($custom:tt)*
+    ) => {
+        forward_db_client!(
+            @munch ($ty, $this, $client, $ctx, $mapped) [$($rest)*] [
+                $($forwarded)*
+                fn is_healthy(&$this, endpoint: &$crate::model::route::Endpoint) -> bool {
+                    $this.$client.is_healthy(endpoint)
+                }
+            ] $($custom)*
+        );
+    };
+
+    (
+        @munch ($ty:ty, $this:ident, $client:ident, $ctx:ident, $mapped:expr)
+        [last_write_endpoint $($rest:ident)*] [$($forwarded:tt)*] $($custom:tt)*
+    ) => {
+        forward_db_client!(
+            @munch ($ty, $this, $client, $ctx, $mapped) [$($rest)*] [
+                $($forwarded)*
+                fn last_write_endpoint(
+                    &$this,
+                    $ctx: &$crate::rpc_client::RpcContext,
+                    table: &str,
+                ) -> Option<$crate::model::route::Endpoint> {
+                    $this.$client.last_write_endpoint(&$mapped, table)
+                }
+            ] $($custom)*
+        );
+    };
+
+    (
+        @munch ($ty:ty, $this:ident, $client:ident, $ctx:ident, $mapped:expr)
+        [server_capabilities $($rest:ident)*] [$($forwarded:tt)*] $($custom:tt)*
+    ) => {
+        forward_db_client!(
+            @munch ($ty, $this, $client, $ctx, $mapped) [$($rest)*] [
+                $($forwarded)*
+                fn server_capabilities(
+                    &$this,
+                    endpoint: &$crate::model::route::Endpoint,
+                ) -> Option<$crate::rpc_client::ServerCapabilities> {
+                    $this.$client.server_capabilities(endpoint)
+                }
+            ] $($custom)*
+        );
+    };
+
+    (
+        @munch ($ty:ty, $this:ident, $client:ident, $ctx:ident, $mapped:expr)
+        [evict_routes_by_endpoint $($rest:ident)*] [$($forwarded:tt)*] $($custom:tt)*
+    ) => {
+        forward_db_client!(
+            @munch ($ty, $this, $client, $ctx, $mapped) [$($rest)*] [
+                $($forwarded)*
+                fn evict_routes_by_endpoint(
+                    &$this,
+                    endpoint: &$crate::model::route::Endpoint,
+                ) -> $crate::Result<usize> {
+                    $this.$client.evict_routes_by_endpoint(endpoint)
+                }
+            ] $($custom)*
+        );
+    };
+
+    (
+        @munch ($ty:ty, $this:ident, $client:ident, $ctx:ident, $mapped:expr)
+        [hedge_stats $($rest:ident)*] [$($forwarded:tt)*] $($custom:tt)*
+    ) => {
+        forward_db_client!(
+            @munch ($ty, $this, $client, $ctx, $mapped) [$($rest)*] [
+                $($forwarded)*
+                fn hedge_stats(&$this) -> $crate::db_client::HedgeStats {
+                    $this.$client.hedge_stats()
+                }
+            ] $($custom)*
+        );
+    };
+
+    (
+        @munch ($ty:ty, $this:ident, $client:ident, $ctx:ident, $mapped:expr)
+        [group_commit_stats $($rest:ident)*] [$($forwarded:tt)*] $($custom:tt)*
+    ) => {
+        forward_db_client!(
+            @munch ($ty, $this, $client, $ctx, $mapped) [$($rest)*] [
+                $($forwarded)*
+                fn group_commit_stats(&$this) -> $crate::db_client::GroupCommitStats {
+                    $this.$client.group_commit_stats()
+                }
+            ] $($custom)*
+        );
+    };
+
+    (
+        @munch ($ty:ty, $this:ident, $client:ident, $ctx:ident, $mapped:expr)
+        [channel_stats $($rest:ident)*] [$($forwarded:tt)*] $($custom:tt)*
+    ) => {
+        forward_db_client!(
+            @munch ($ty, $this, $client, $ctx, $mapped) [$($rest)*] [
+                $($forwarded)*
+                fn channel_stats(
+                    &$this,
+                ) -> Vec<($crate::model::route::Endpoint, $crate::db_client::ChannelStats)> {
+                    $this.$client.channel_stats()
+                }
+            ] $($custom)*
+        );
+    };
+
+    (
+        @munch ($ty:ty, $this:ident, $client:ident, $ctx:ident, $mapped:expr)
+        [circuit_breaker_states $($rest:ident)*] [$($forwarded:tt)*] $($custom:tt)*
+    ) => {
+        forward_db_client!(
+            @munch ($ty, $this, $client, $ctx, $mapped) [$($rest)*] [
+                $($forwarded)*
+                fn circuit_breaker_states(
+                    &$this,
+                ) -> Vec<($crate::model::route::Endpoint, $crate::db_client::BreakerState)> {
+                    $this.$client.circuit_breaker_states()
+                }
+            ] $($custom)*
+        );
+    };
+
+    (
+        @munch ($ty:ty, $this:ident, $client:ident, $ctx:ident, $mapped:expr)
+        [write_stats $($rest:ident)*] [$($forwarded:tt)*] $($custom:tt)*
+    ) => {
+        forward_db_client!(
+            @munch ($ty, $this, $client, $ctx, $mapped) [$($rest)*] [
+                $($forwarded)*
+                fn write_stats(&$this) -> Vec<$crate::db_client::TableWriteStats> {
+                    $this.$client.write_stats()
+                }
+            ] $($custom)*
+        );
+    };
+
+    (
+        @munch ($ty:ty, $this:ident, $client:ident, $ctx:ident, $mapped:expr)
+        [reset_write_stats $($rest:ident)*] [$($forwarded:tt)*] $($custom:tt)*
+    ) => {
+        forward_db_client!(
+            @munch ($ty, $this, $client, $ctx, $mapped) [$($rest)*] [
+                $($forwarded)*
+                fn reset_write_stats(&$this) {
+                    $this.$client.reset_write_stats()
+                }
+            ] $($custom)*
+        );
+    };
+
+    (
+        @munch ($ty:ty, $this:ident, $client:ident, $ctx:ident, $mapped:expr)
+        [cache_stats $($rest:ident)*] [$($forwarded:tt)*] $($custom:tt)*
+    ) => {
+        forward_db_client!(
+            @munch ($ty, $this, $client, $ctx, $mapped) [$($rest)*] [
+                $($forwarded)*
+                fn cache_stats(&$this) -> $crate::db_client::QueryCacheStats {
+                    $this.$client.cache_stats()
+                }
+            ] $($custom)*
+        );
+    };
+}
+
+/// Query by pages through the `client` in the `ctx` mapped by the wrapper.
+///
+/// The pages are queried by the `client` itself, which routes only once for
+/// all of them, and they are forwarded from the driver owning the mapped
+/// context.
+pub(crate) fn forward_sql_query_paged<'a>(
+    client: &'a dyn DbClient,
+    ctx: Cow<'a, RpcContext>,
+    req: &'a SqlQueryRequest,
+    page_size: usize,
+) -> BoxStream<'a, Result<SqlQueryResponse>> {
+    let ctx = match ctx {
+        Cow::Borrowed(ctx) => return client.sql_query_paged(ctx, req, page_size),
+        Cow::Owned(ctx) => ctx,
+    };
+
+    let (mut tx, rx) = mpsc::channel(0);
+    let driver = async move {
+        let mut pages = client.sql_query_paged(&ctx, req, page_size);
+        while let Some(page) = pages.next().await {
+            if tx.send(page).await.is_err() {
+                break;
+            }
+        }
+    };
+
+    let driver = driver.into_stream().filter_map(|_| future::ready(None));
+    stream::select(driver, rx).boxed()
+}
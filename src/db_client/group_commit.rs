@@ -0,0 +1,331 @@
+// Copyright 2022 CeresDB Project Authors. Licensed under Apache-2.0.
+
+//! Group commit of the concurrent writes to the same endpoint
+
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, PoisonError,
+    },
+    time::{Duration, Instant},
+};
+
+use futures::channel::oneshot;
+
+use crate::{
+    clock::Clock,
+    config::GroupCommitConfig,
+    model::{
+        route::Endpoint,
+        value::TimestampMs,
+        write::{Request as WriteRequest, Response as WriteResponse},
+    },
+    rpc_client::{Priority, RpcContext},
+    spawner::Spawner,
+    Error, Result,
+};
+
+/// Statistics about the writes merged by the group commit.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct GroupCommitStats {
+    /// The number of the writes merged, and a write partitioned across the
+    /// endpoints is counted once per endpoint.
+    pub writes: u64,
+    /// The number of the rpcs sent for the merged writes.
+    pub rpcs: u64,
+    /// The total time the writes wait for their groups to be sent.
+    pub total_delay: Duration,
+    /// The max time a write waits for its group to be sent, which is bounded
+    /// by the [`window`](GroupCommitConfig::window).
+    pub max_delay: Duration,
+}
+
+/// The writes are merged only if they are sent to the same endpoint and
+/// database with the same priority and the same options resolving the
+/// timestamps.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct GroupKey {
+    endpoint: Endpoint,
+    database: Option<String>,
+    priority: Priority,
+    default_timestamp: Option<TimestampMs>,
+    timestamp_required: bool,
+    min_timestamp: Option<TimestampMs>,
+    max_value_bytes: Option<usize>,
+}
+
+impl GroupKey {
+    fn new(endpoint: &Endpoint, ctx: &RpcContext, req: &WriteRequest) -> Self {
+        Self {
+            endpoint: endpoint.clone(),
+            database: ctx.database.clone(),
+            priority: ctx.priority,
+            default_timestamp: req.default_timestamp,
+            timestamp_required: req.timestamp_required,
+            min_timestamp: req.min_timestamp,
+            max_value_bytes: req.max_value_bytes,
+        }
+    }
+}
+
+struct PendingWrite {
+    req: WriteRequest,
+    enqueued_at: Instant,
+    tx: oneshot::Sender<Result<WriteResponse>>,
+}
+
+struct PendingGroup {
+    id: u64,
+    writes: Vec<PendingWrite>,
+    /// Hand the writes over to the flusher once the group is full.
+    full: oneshot::Sender<Vec<PendingWrite>>,
+}
+
+/// Merger of the concurrent writes to the same endpoint into one rpc.
+///
+/// The first write to an endpoint opens a group, which is sent by a task of
+/// its own when the [`window`](GroupCommitConfig::window) elapses or the group
+/// is full, so it is sent even if the first write is cancelled. The response
+/// of the merged rpc is split back to the writes by their points, and if only
+/// some points of it fail, the writes get
+/// [`Error::UnattributedWriteFailure`] because the server doesn't tell which
+/// ones fail.
+pub(crate) struct GroupCommitter {
+    config: GroupCommitConfig,
+    clock: Arc<dyn Clock>,
+    groups: Mutex<HashMap<GroupKey, PendingGroup>>,
+    next_id: AtomicU64,
+    stats: Mutex<GroupCommitStats>,
+    spawner: Spawner,
+}
+
+impl GroupCommitter {
+    pub fn new(config: GroupCommitConfig, clock: Arc<dyn Clock>) -> Self {
+        Self {
+            config,
+            clock,
+            groups: Mutex::new(HashMap::new()),
+            next_id: AtomicU64::new(0),
+            stats: Mutex::new(GroupCommitStats::default()),
+            spawner: Spawner::default(),
+        }
+    }
+
+    /// Send the groups by the tasks on the runtime of the `spawner`.
+    pub fn with_spawner(mut self, spawner: Spawner) -> Self {
+        self.spawner = spawner;
+        self
+    }
+
+    pub fn stats(&self) -> GroupCommitStats {
+        *self.stats.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Write the `req` to the `endpoint` along with the concurrent writes to
+    /// it, and the merged request is sent by the `send` of the write opening
+    /// the group, with its context except the cancellation token.
+    pub async fn write<S, Fut>(
+        self: &Arc<Self>,
+        endpoint: &Endpoint,
+        ctx: &RpcContext,
+        req: WriteRequest,
+        send: S,
+    ) -> Result<WriteResponse>
+    where
+        S: FnOnce(RpcContext, WriteRequest) -> Fut + Send + 'static,
+        Fut: Future<Output = Result<WriteResponse>> + Send + 'static,
+    {
+        let key = GroupKey::new(endpoint, ctx, &req);
+        let (tx, rx) = oneshot::channel();
+        let write = PendingWrite {
+            req,
+            enqueued_at: self.clock.now(),
+            tx,
+        };
+
+        if let Some((id, full)) = self.enqueue(key.clone(), write) {
+            let committer = self.clone();
+            // The group is shared by the writes of other contexts.
+            let mut ctx = ctx.clone();
+            ctx.cancel = None;
+            self.spawner.spawn(async move {
+                let writes = committer.wait_for_group(&key, id, full).await;
+                committer.commit(writes, |req| send(ctx, req)).await;
+            });
+        }
+
+        match rx.await {
+            Ok(result) => result,
+            Err(_) => Err(Error::Client(
+                "group commit is aborted before the write is sent".to_string(),
+            )),
+        }
+    }
+
+    /// Add the `write` to its group, and the id of the group and the receiver
+    /// of the full group are returned if the group is opened by it.
+    fn enqueue(
+        &self,
+        key: GroupKey,
+        write: PendingWrite,
+    ) -> Option<(u64, oneshot::Receiver<Vec<PendingWrite>>)> {
+        let mut groups = self.groups.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut opened = None;
+        let group = groups.entry(key.clone()).or_insert_with(|| {
+            let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+            let (full, full_rx) = oneshot::channel();
+            opened = Some((id, full_rx));
+            PendingGroup {
+                id,
+                writes: Vec::new(),
+                full,
+            }
+        });
+
+        group.writes.push(write);
+        if group.writes.len() >= self.config.max_writes {
+            if let Some(group) = groups.remove(&key) {
+                let _ = group.full.send(group.writes);
+            }
+        }
+
+        opened
+    }
+
+    /// Wait until the window elapses or the group is full, and take the
+    /// writes of the group.
+    async fn wait_for_group(
+        &self,
+        key: &GroupKey,
+        id: u64,
+        mut full: oneshot::Receiver<Vec<PendingWrite>>,
+    ) -> Vec<PendingWrite> {
+        tokio::select! {
+            biased;
+            writes = &mut full => return writes.unwrap_or_default(),
+            _ = self.clock.sleep(self.config.window) => {}
+        }
+
+        {
+            let mut groups = self.groups.lock().unwrap_or_else(PoisonError::into_inner);
+            if matches!(groups.get(key), Some(group) if group.id == id) {
+                return groups
+                    .remove(key)
+                    .map(|group| group.writes)
+                    .unwrap_or_default();
+            }
+        }
+        // The group is full right when the window elapses.
+        full.await.unwrap_or_default()
+    }
+
+    /// Send the merged `writes` by `send`, and split the response back to
+    /// them.
+    async fn commit<S, Fut>(&self, writes: Vec<PendingWrite>, send: S)
+    where
+        S: FnOnce(WriteRequest) -> Fut,
+        Fut: Future<Output = Result<WriteResponse>>,
+    {
+        if writes.is_empty() {
+            return;
+        }
+
+        let mut merged = writes[0].req.empty_like();
+        let now = self.clock.now();
+        let mut delays = Vec::with_capacity(writes.len());
+        let mut pending = Vec::with_capacity(writes.len());
+        for write in writes {
+            let mut points = 0;
+            for (table, table_points) in write.req.point_groups {
+                points += table_points.len() as u32;
+                merged
+                    .point_groups
+                    .entry(table)
+                    .or_default()
+                    .extend(table_points);
+            }
+            delays.push(now.saturating_duration_since(write.enqueued_at));
+            pending.push((points, write.tx));
+        }
+        self.record(&delays);
+
+        match send(merged).await {
+            Ok(resp) if pending.len() == 1 => {
+                let (_, tx) = pending.remove(0);
+                let _ = tx.send(Ok(resp));
+            }
+            Ok(resp) => {
+                for (points, tx) in pending {
+                    // The warnings of the merged write can't be attributed to
+                    // the writes, so every write gets all of them.
+                    let mut own_resp = match (resp.success, resp.failed) {
+                        (_, 0) => WriteResponse::new(points, 0),
+                        (0, _) => WriteResponse::new(0, points),
+                        (merged_success, merged_failed) => {
+                            let _ = tx.send(Err(Error::UnattributedWriteFailure {
+                                points,
+                                merged_success,
+                                merged_failed,
+                            }));
+                            continue;
+                        }
+                    };
+                    own_resp.execution_info = resp.execution_info.clone();
+                    own_resp.warnings = resp.warnings.clone();
+                    let _ = tx.send(Ok(own_resp));
+                }
+            }
+            Err(e) => {
+                for (_, tx) in pending {
+                    let _ = tx.send(Err(copy_error(&e)));
+                }
+            }
+        }
+    }
+
+    fn record(&self, delays: &[Duration]) {
+        let mut stats = self.stats.lock().unwrap_or_else(PoisonError::into_inner);
+        stats.writes += delays.len() as u64;
+        stats.rpcs += 1;
+        for delay in delays {
+            stats.total_delay += *delay;
+            stats.max_delay = stats.max_delay.max(*delay);
+        }
+    }
+}
+
+/// Copy the error of the merged write for every write in it, and the errors
+/// not cloneable are copied as [`Error::Unknown`] with their messages.
+fn copy_error(e: &Error) -> Error {
+    match e {
+        Error::Server(server_error) => Error::Server(server_error.clone()),
+        Error::Rpc(e) => Error::Rpc(e.clone()),
+        Error::Connect { addr, source } => Error::Connect {
+            addr: addr.clone(),
+            source: source.to_string().into(),
+        },
+        Error::Client(msg) => Error::Client(msg.clone()),
+        Error::RequestTooLarge {
+            limit,
+            estimated_size,
+        } => Error::RequestTooLarge {
+            limit: *limit,
+            estimated_size: *estimated_size,
+        },
+        Error::CircuitOpen { endpoint } => Error::CircuitOpen {
+            endpoint: endpoint.clone(),
+        },
+        Error::EndpointUnhealthy { endpoint } => Error::EndpointUnhealthy {
+            endpoint: endpoint.clone(),
+        },
+        Error::Timeout { phase, timeout } => Error::Timeout {
+            phase: *phase,
+            timeout: *timeout,
+        },
+        Error::Cancelled => Error::Cancelled,
+        Error::AuthFail(status) => Error::AuthFail(status.clone()),
+        e => Error::Unknown(e.to_string()),
+    }
+}
@@ -0,0 +1,205 @@
+// Copyright 2022 CeresDB Project Authors. Licensed under Apache-2.0.
+
+//! Background health checker of the endpoints
+
+use std::{collections::HashSet, fmt, sync::Arc};
+
+use async_trait::async_trait;
+use dashmap::DashSet;
+use futures::future::join_all;
+use tokio::task::JoinHandle;
+
+use crate::{
+    clock::{self, Clock},
+    config::HealthCheckConfig,
+    model::route::Endpoint,
+    spawner::Spawner,
+};
+
+/// Probe whether an endpoint is healthy.
+#[async_trait]
+pub trait HealthProbe: fmt::Debug + Send + Sync {
+    async fn probe(&self, endpoint: &Endpoint) -> bool;
+}
+
+/// Probe by connecting to the endpoint over tcp.
+#[derive(Debug, Clone, Default)]
+pub struct TcpProbe;
+
+#[async_trait]
+impl HealthProbe for TcpProbe {
+    async fn probe(&self, endpoint: &Endpoint) -> bool {
+        let port = match u16::try_from(endpoint.port) {
+            Ok(port) => port,
+            Err(_) => return false,
+        };
+        let addr = endpoint.addr.trim_start_matches('[').trim_end_matches(']');
+        tokio::net::TcpStream::connect((addr, port)).await.is_ok()
+    }
+}
+
+/// Health of the probed endpoints, and the endpoints never probed are regarded
+/// healthy.
+#[derive(Debug, Default)]
+pub(crate) struct HealthStates {
+    unhealthy: DashSet<Endpoint>,
+}
+
+impl HealthStates {
+    #[inline]
+    pub fn is_healthy(&self, endpoint: &Endpoint) -> bool {
+        !self.unhealthy.contains(endpoint)
+    }
+
+    /// Probe every distinct endpoint once concurrently, and the endpoints not
+    /// probed any more are forgotten.
+    pub async fn check_once(
+        &self,
+        config: &HealthCheckConfig,
+        probe: &dyn HealthProbe,
+        clock: &dyn Clock,
+        endpoints: Vec<Endpoint>,
+    ) {
+        let endpoints: HashSet<_> = endpoints.into_iter().collect();
+        let probes = endpoints.iter().map(|endpoint| async move {
+            let healthy = clock::timeout(clock, config.timeout, probe.probe(endpoint))
+                .await
+                .unwrap_or(false);
+            (endpoint, healthy)
+        });
+
+        for (endpoint, healthy) in join_all(probes).await {
+            if healthy {
+                self.unhealthy.remove(endpoint);
+            } else {
+                self.unhealthy.insert(endpoint.clone());
+            }
+        }
+        self.unhealthy
+            .retain(|endpoint| endpoints.contains(endpoint));
+    }
+}
+
+/// The task probing the endpoints in the background every interval, which is
+/// stopped when dropped.
+#[derive(Debug)]
+pub(crate) struct HealthChecker {
+    handle: JoinHandle<()>,
+}
+
+impl HealthChecker {
+    /// Start probing the endpoints got by `endpoints` immediately and then
+    /// every interval measured by the `clock` on the runtime of the `spawner`,
+    /// and stop once it returns none.
+    pub fn start<E>(
+        spawner: &Spawner,
+        config: HealthCheckConfig,
+        probe: Arc<dyn HealthProbe>,
+        clock: Arc<dyn Clock>,
+        states: Arc<HealthStates>,
+        endpoints: E,
+    ) -> Self
+    where
+        E: Fn() -> Option<Vec<Endpoint>> + Send + 'static,
+    {
+        let handle = spawner.spawn(async move {
+            loop {
+                let begin = clock.now();
+                let endpoints = match endpoints() {
+                    Some(endpoints) => endpoints,
+                    None => return,
+                };
+                states
+                    .check_once(&config, probe.as_ref(), clock.as_ref(), endpoints)
+                    .await;
+                // The next check is delayed if this one takes longer than the interval.
+                let elapsed = clock.now() - begin;
+                clock.sleep(config.interval.saturating_sub(elapsed)).await;
+            }
+        });
+
+        Self { handle }
+    }
+}
+
+impl Drop for HealthChecker {
+    fn drop(&mut self) {
+        self.handle.abort();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{sync::Mutex, time::Duration};
+
+    use async_trait::async_trait;
+
+    use super::{HealthProbe, HealthStates};
+    use crate::{clock::SystemClock, config::HealthCheckConfig, model::route::Endpoint};
+
+    /// Probe recording the probed endpoints, and the endpoint `down` is
+    /// unhealthy while the endpoint `slow` times out.
+    #[derive(Debug, Default)]
+    struct RecordingProbe {
+        probed: Mutex<Vec<Endpoint>>,
+        down: Option<Endpoint>,
+        slow: Option<Endpoint>,
+    }
+
+    #[async_trait]
+    impl HealthProbe for RecordingProbe {
+        async fn probe(&self, endpoint: &Endpoint) -> bool {
+            self.probed.lock().unwrap().push(endpoint.clone());
+            if self.slow.as_ref() == Some(endpoint) {
+                tokio::time::sleep(Duration::from_secs(10)).await;
+            }
+            self.down.as_ref() != Some(endpoint)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_check_once() {
+        let config = HealthCheckConfig {
+            interval: Duration::from_secs(10),
+            timeout: Duration::from_millis(10),
+        };
+        let endpoint1 = Endpoint::new("192.168.0.1".to_string(), 11);
+        let endpoint2 = Endpoint::new("192.168.0.2".to_string(), 12);
+        let endpoint3 = Endpoint::new("192.168.0.3".to_string(), 13);
+        let states = HealthStates::default();
+        assert!(states.is_healthy(&endpoint1));
+
+        // Every endpoint is probed once even if it appears many times.
+        let probe = RecordingProbe {
+            down: Some(endpoint1.clone()),
+            slow: Some(endpoint3.clone()),
+            ..Default::default()
+        };
+        let endpoints = vec![
+            endpoint1.clone(),
+            endpoint2.clone(),
+            endpoint1.clone(),
+            endpoint3.clone(),
+        ];
+        states
+            .check_once(&config, &probe, &SystemClock, endpoints)
+            .await;
+        let mut probed = probe.probed.lock().unwrap().clone();
+        probed.sort_by_key(|endpoint| endpoint.to_string());
+        assert_eq!(
+            probed,
+            [endpoint1.clone(), endpoint2.clone(), endpoint3.clone()]
+        );
+        assert!(!states.is_healthy(&endpoint1));
+        assert!(states.is_healthy(&endpoint2));
+        assert!(!states.is_healthy(&endpoint3));
+
+        // Recover, and the endpoint no longer probed is forgotten.
+        let probe = RecordingProbe::default();
+        states
+            .check_once(&config, &probe, &SystemClock, vec![endpoint1.clone()])
+            .await;
+        assert!(states.is_healthy(&endpoint1));
+        assert!(states.is_healthy(&endpoint3));
+    }
+}
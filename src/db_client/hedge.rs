@@ -0,0 +1,349 @@
+// Copyright 2022 CeresDB Project Authors. Licensed under Apache-2.0.
+
+//! Hedged requests
+
+use std::{
+    future::Future,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use crate::{clock::Clock, metrics::ClientMetrics, Result};
+
+/// Statistics about the hedged requests.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct HedgeStats {
+    /// The number of the hedged requests issued.
+    pub issued: u64,
+    /// The number of the hedged requests whose responses are taken.
+    pub won: u64,
+    /// The number of the hedged requests not issued because of the limit of
+    /// the ones in flight.
+    pub throttled: u64,
+}
+
+/// Issue a hedged request if the primary one hasn't completed within the
+/// delay.
+///
+/// At most one hedged request is issued for every primary one, and the first
+/// successful response is taken while the other request is cancelled. No
+/// hedged request is issued while `max_in_flight` ones are in flight.
+#[derive(Debug)]
+pub(crate) struct Hedger {
+    delay: Duration,
+    max_in_flight: usize,
+    in_flight: AtomicUsize,
+    issued: AtomicU64,
+    won: AtomicU64,
+    throttled: AtomicU64,
+}
+
+/// A hedged request in flight, which is counted until it is dropped.
+struct InFlight<'a>(&'a AtomicUsize);
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+impl Hedger {
+    pub fn new(delay: Duration, max_in_flight: usize) -> Self {
+        Self {
+            delay,
+            max_in_flight,
+            in_flight: AtomicUsize::new(0),
+            issued: AtomicU64::new(0),
+            won: AtomicU64::new(0),
+            throttled: AtomicU64::new(0),
+        }
+    }
+
+    pub fn stats(&self) -> HedgeStats {
+        HedgeStats {
+            issued: self.issued.load(Ordering::Relaxed),
+            won: self.won.load(Ordering::Relaxed),
+            throttled: self.throttled.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Count a hedged request in flight, and none if there are
+    /// `max_in_flight` ones already.
+    fn enter(&self) -> Option<InFlight<'_>> {
+        self.in_flight
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |in_flight| {
+                (in_flight < self.max_in_flight).then_some(in_flight + 1)
+            })
+            .ok()?;
+        Some(InFlight(&self.in_flight))
+    }
+
+    /// Add the `hedges` to the stats, and record them in the `metrics` too.
+    fn record(&self, metrics: Option<&ClientMetrics>, hedges: HedgeStats) {
+        self.issued.fetch_add(hedges.issued, Ordering::Relaxed);
+        self.won.fetch_add(hedges.won, Ordering::Relaxed);
+        self.throttled
+            .fetch_add(hedges.throttled, Ordering::Relaxed);
+        if let Some(metrics) = metrics {
+            metrics.record_hedges(hedges);
+        }
+    }
+
+    /// Run the `primary` request, and the `hedge` one after the delay measured
+    /// by the `clock`, and the hedged requests are recorded in the `metrics`.
+    pub async fn run<T, P, H, Fut>(
+        &self,
+        clock: &dyn Clock,
+        metrics: Option<&ClientMetrics>,
+        primary: P,
+        hedge: H,
+    ) -> Result<T>
+    where
+        P: Future<Output = Result<T>>,
+        H: FnOnce() -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        tokio::pin!(primary);
+        tokio::select! {
+            result = &mut primary => return result,
+            _ = clock.sleep(self.delay) => {}
+        }
+
+        let _in_flight = match self.enter() {
+            Some(in_flight) => in_flight,
+            None => {
+                let throttled = HedgeStats {
+                    throttled: 1,
+                    ..Default::default()
+                };
+                self.record(metrics, throttled);
+                return primary.await;
+            }
+        };
+        let issued = HedgeStats {
+            issued: 1,
+            ..Default::default()
+        };
+        self.record(metrics, issued);
+        let hedge = hedge();
+        tokio::pin!(hedge);
+        // The request not taken is cancelled by dropping its future.
+        let (result, hedge_taken) = tokio::select! {
+            result = &mut primary => match result {
+                Ok(v) => (Ok(v), false),
+                Err(_) => (hedge.await, true),
+            },
+            result = &mut hedge => match result {
+                Ok(v) => (Ok(v), true),
+                Err(_) => (primary.await, false),
+            },
+        };
+
+        if hedge_taken && result.is_ok() {
+            let won = HedgeStats {
+                won: 1,
+                ..Default::default()
+            };
+            self.record(metrics, won);
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use ceresdbproto::storage::{
+        sql_query_response::Output as OutputPb, Endpoint as EndpointPb, Route as RoutePb,
+        RouteResponse as RouteResponsePb, SqlQueryResponse as QueryResponsePb,
+    };
+    use dashmap::DashMap;
+    use futures::future;
+
+    use super::{HedgeStats, Hedger};
+    use crate::{
+        clock::SystemClock,
+        db_client::{route_based::RouteBasedImpl, slow_request::SlowRequestLogger, DbClient},
+        metrics::ClientMetrics,
+        model::{route::Endpoint, sql_query::Request as SqlQueryRequest},
+        rpc_client::{MockRpcClient, MockRpcClientFactory, RouteResponse, RpcContext},
+        Result,
+    };
+
+    /// Set the flag if the query is dropped before completion.
+    struct CancelGuard {
+        completed: bool,
+        cancelled: Arc<AtomicBool>,
+    }
+
+    impl Drop for CancelGuard {
+        fn drop(&mut self) {
+            if !self.completed {
+                self.cancelled.store(true, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Client routing the table to the `replicas` by the default endpoint, and
+    /// the query to every replica takes its delay to return its affected rows,
+    /// while the cancelled ones are flagged in `cancelled`.
+    fn replicas_client(
+        replicas: Vec<(Endpoint, Duration, u32)>,
+        cancelled: Arc<DashMap<String, Arc<AtomicBool>>>,
+    ) -> RouteBasedImpl<MockRpcClientFactory> {
+        let default_endpoint = Endpoint::new("192.168.0.5".to_string(), 15);
+        let replica_endpoints: Vec<_> = replicas
+            .iter()
+            .map(|(endpoint, ..)| endpoint.clone())
+            .collect();
+        let factory = MockRpcClientFactory::new(move |endpoint| {
+            let replica_endpoints = replica_endpoints.clone();
+            let rpc_client = MockRpcClient::default().on_route(move |_, req| {
+                let routes = req
+                    .tables
+                    .iter()
+                    .flat_map(|table| {
+                        replica_endpoints.iter().map(|endpoint| RoutePb {
+                            table: table.clone(),
+                            endpoint: Some(EndpointPb {
+                                ip: endpoint.addr.clone(),
+                                port: endpoint.port,
+                            }),
+                        })
+                    })
+                    .collect();
+                future::ready(Ok(RouteResponse {
+                    epoch: None,
+                    resp: RouteResponsePb {
+                        header: None,
+                        routes,
+                    },
+                }))
+            });
+            let (delay, affected_rows) = match replicas
+                .iter()
+                .find(|(replica, ..)| replica.to_string() == endpoint)
+            {
+                Some((_, delay, affected_rows)) => (*delay, *affected_rows),
+                None => return rpc_client,
+            };
+            let cancelled = cancelled.entry(endpoint).or_default().clone();
+            rpc_client.on_sql_query(move |_, _| {
+                let cancelled = cancelled.clone();
+                async move {
+                    let mut guard = CancelGuard {
+                        completed: false,
+                        cancelled,
+                    };
+                    tokio::time::sleep(delay).await;
+                    guard.completed = true;
+
+                    Ok(QueryResponsePb {
+                        header: None,
+                        output: Some(OutputPb::AffectedRows(affected_rows)),
+                    }
+                    .into())
+                }
+            })
+        });
+        RouteBasedImpl::new(
+            Arc::new(factory),
+            default_endpoint.to_string(),
+            None,
+            Some("db".to_string()),
+            SlowRequestLogger::default(),
+            Duration::from_secs(5),
+            3,
+        )
+        .with_hedge(Some(Duration::from_millis(50)), 16)
+    }
+
+    fn query() -> SqlQueryRequest {
+        SqlQueryRequest::new(vec!["table".to_string()], "select * from table".to_string())
+    }
+
+    #[tokio::test]
+    async fn test_hedged_query() {
+        let slow_endpoint = Endpoint::new("192.168.0.1".to_string(), 11);
+        let fast_endpoint = Endpoint::new("192.168.0.2".to_string(), 12);
+        let cancelled: Arc<DashMap<String, Arc<AtomicBool>>> = Arc::default();
+        let replicas = vec![
+            (slow_endpoint.clone(), Duration::from_secs(5), 1),
+            (fast_endpoint, Duration::from_millis(10), 2),
+        ];
+        let metrics = ClientMetrics::default();
+        let client =
+            replicas_client(replicas, cancelled.clone()).with_metrics(Some(metrics.clone()));
+
+        let resp = client
+            .sql_query(&RpcContext::default(), &query())
+            .await
+            .unwrap();
+
+        // The query routed to the slow replica is hedged to the other one, and
+        // the faster hedged query wins while the slow one is cancelled.
+        assert_eq!(resp.affected_rows(), Some(2));
+        let expected = HedgeStats {
+            issued: 1,
+            won: 1,
+            throttled: 0,
+        };
+        assert_eq!(client.hedge_stats(), expected);
+        assert_eq!(metrics.hedges(), expected);
+        let slow_cancelled = cancelled.get(&slow_endpoint.to_string()).unwrap();
+        assert!(slow_cancelled.load(Ordering::Relaxed));
+    }
+
+    #[tokio::test]
+    async fn test_no_other_replica() {
+        let slow_endpoint = Endpoint::new("192.168.0.1".to_string(), 11);
+        let replicas = vec![(slow_endpoint, Duration::from_millis(200), 1)];
+        let client = replicas_client(replicas, Arc::default());
+
+        let resp = client
+            .sql_query(&RpcContext::default(), &query())
+            .await
+            .unwrap();
+
+        // Nowhere to hedge the slow query to.
+        assert_eq!(resp.affected_rows(), Some(1));
+        assert_eq!(client.hedge_stats(), HedgeStats::default());
+    }
+
+    #[tokio::test]
+    async fn test_max_in_flight() {
+        let hedger = Hedger::new(Duration::from_millis(10), 1);
+        let run = |affected_rows: u32| {
+            let primary = async move {
+                tokio::time::sleep(Duration::from_millis(200)).await;
+                Ok(affected_rows)
+            };
+            let hedge = || future::pending::<Result<u32>>();
+            hedger.run(&SystemClock, None, primary, hedge)
+        };
+
+        // The second slow request isn't hedged while the first hedged one is
+        // in flight.
+        let (first, second) = tokio::join!(run(1), run(2));
+        assert_eq!((first.unwrap(), second.unwrap()), (1, 2));
+        assert_eq!(
+            hedger.stats(),
+            HedgeStats {
+                issued: 1,
+                won: 0,
+                throttled: 1,
+            }
+        );
+
+        // The slot is released after the hedged request is done with.
+        run(3).await.unwrap();
+        assert_eq!(hedger.stats().issued, 2);
+    }
+}
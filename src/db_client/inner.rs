@@ -2,87 +2,2255 @@
 
 //! Inner client
 
-use std::sync::Arc;
+use std::{
+    borrow::Cow,
+    future::Future,
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex, PoisonError, RwLock,
+    },
+    time::{Duration, Instant},
+};
 
 use ceresdbproto::storage;
-use tokio::sync::OnceCell;
+use futures::{
+    channel::mpsc::{self, UnboundedReceiver},
+    future, Stream, StreamExt, TryStreamExt,
+};
+use prost::Message;
+use tonic::Code;
 
 use crate::{
+    clock::{Clock, SystemClock},
+    config::{CircuitBreakerConfig, QueryGuardConfig, ReconnectConfig},
+    db_client::{
+        circuit_breaker::{BreakerState, CircuitBreaker},
+        passive_health::PassiveHealth,
+        retry::RetryPolicy,
+        write_stats::WriteStatsRecorder,
+        Operation,
+    },
+    interceptor::OperationKind,
+    metrics::ClientMetrics,
     model::{
+        execution_info::ExecutionInfo,
+        route::Endpoint,
         sql_query::{Request as SqlQueryRequest, Response as SqlQueryResponse},
-        write::{Request as WriteRequest, Response as WriteResponse, WriteTableRequestPbsBuilder},
+        warning::{ServerWarning, WarningHook},
+        write::{
+            row_count, split_table_requests, DroppedPoints, Request as WriteRequest,
+            Response as WriteResponse, WriteTableRequestPbsBuilder,
+        },
     },
-    rpc_client::{RpcClient, RpcClientFactory, RpcContext},
-    Result,
+    rpc_client::{
+        ErrorContextRpcClient, RpcClient, RpcClientFactory, RpcContext, RpcResponse,
+        ServerCapabilities,
+    },
+    spawner::Spawner,
+    util::record_span_outcome,
+    Error, Result, RpcConfig,
 };
 
+/// The max number of the requests sent by one streaming write rpc.
+const STREAM_WRITE_SEGMENT_LEN: usize = 256;
+
+/// Connection state of the channel to a server.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConnectionState {
+    /// No connection has been tried.
+    Idle,
+    /// The connection is being established.
+    Connecting,
+    /// The connection is established and the last request reaches the server.
+    Connected,
+    /// Failed to connect or the server is unavailable for the last request.
+    Failed {
+        /// When the consecutive failures begin.
+        since: Instant,
+        last_error: String,
+    },
+}
+
+/// Statistics about the rebuildings of the channel to a server.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ChannelStats {
+    /// The number of the channels rebuilt for exceeding the
+    /// [`max_channel_age`](crate::RpcConfig::max_channel_age).
+    pub rebuilds_on_age: u64,
+    /// The number of the channels dropped on the transport failures, which
+    /// are rebuilt by the next requests.
+    pub rebuilds_on_failure: u64,
+    /// The number of the channels closed for exceeding the
+    /// [`idle_timeout`](crate::RpcConfig::idle_timeout), which are rebuilt by
+    /// the next requests.
+    pub rebuilds_on_idle: u64,
+    /// The number of the reconnections after the failures to connect, see
+    /// [`RpcConfig::reconnect`](crate::RpcConfig::reconnect).
+    pub reconnects: u64,
+}
+
+/// The capabilities of the server filled by the probing in the background.
+type CapabilitiesSlot = Arc<Mutex<Option<ServerCapabilities>>>;
+
+/// The built client along with the capabilities of the server probed through
+/// it, and when it is built and used the last time.
+struct BuiltClient {
+    client: Arc<dyn RpcClient>,
+    capabilities: CapabilitiesSlot,
+    built_at: Instant,
+    last_used: Mutex<Instant>,
+}
+
+impl BuiltClient {
+    fn new(client: Arc<dyn RpcClient>, capabilities: CapabilitiesSlot, now: Instant) -> Self {
+        Self {
+            client,
+            capabilities,
+            built_at: now,
+            last_used: Mutex::new(now),
+        }
+    }
+
+    fn touch(&self, now: Instant) {
+        let mut last_used = self
+            .last_used
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        *last_used = (*last_used).max(now);
+    }
+
+    fn idle_for(&self, now: Instant) -> Duration {
+        now.saturating_duration_since(
+            *self
+                .last_used
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner),
+        )
+    }
+}
+
+#[derive(Debug)]
+struct ConnectionStatus {
+    state: ConnectionState,
+    consecutive_failures: usize,
+}
+
 /// Inner client for both standalone and route based modes.
 ///
-/// Now, [`InnerClient`] just wraps [`RpcClient`] simply.
+/// The underlying [`RpcClient`] is built lazily on the first request. A
+/// failed building is not cached, and the built client will be dropped after
+/// `max_consecutive_failures` consecutive transport failures, so the next
+/// request will reconnect from scratch. The client to a hostname is dropped on
+/// the first transport failure instead if `refresh_dns_on_failure` is set, so
+/// that the hostname is resolved again. The request building the client
+/// reconnects with the backoffs within its timeout if the reconnection is set.
+/// The capabilities of the server are probed in the background after every
+/// building if the negotiation is set, and the streams of writes are sent by
+/// the unary writes to the server not supporting the streaming write, which is
+/// also found by the streaming write answered unimplemented before the probing
+/// completes.
+///
+/// The failed requests are retried if the retry policy is set, and every
+/// attempt is short-circuited if the circuit breaker is set and open.
 pub(crate) struct InnerClient<F: RpcClientFactory> {
     factory: Arc<F>,
     endpoint: String,
-    inner_client: OnceCell<Arc<dyn RpcClient>>,
+    max_consecutive_failures: usize,
+    refresh_dns_on_failure: bool,
+    max_channel_age: Option<Duration>,
+    idle_timeout: Option<Duration>,
+    retry_policy: Option<Arc<dyn RetryPolicy>>,
+    circuit_breaker: Option<CircuitBreaker>,
+    passive_health: Option<Arc<PassiveHealth>>,
+    reconnect: Option<ReconnectConfig>,
+    negotiate_capabilities: bool,
+    spawner: Spawner,
+    max_rows_per_write: Option<usize>,
+    split_write_concurrency: usize,
+    write_stats: Option<Arc<WriteStatsRecorder>>,
+    warning_hook: Option<WarningHook>,
+    query_guard: QueryGuardConfig,
+    metrics: Option<ClientMetrics>,
+    clock: Arc<dyn Clock>,
+    inner_client: RwLock<Option<BuiltClient>>,
+    // Make sure only one building is in progress.
+    build_lock: tokio::sync::Mutex<()>,
+    status: Mutex<ConnectionStatus>,
+    in_flight: AtomicUsize,
+    rebuilds_on_age: AtomicU64,
+    rebuilds_on_failure: AtomicU64,
+    rebuilds_on_idle: AtomicU64,
+    reconnects: AtomicU64,
+}
+
+/// Count a request in flight until it is dropped.
+struct InFlightGuard<'a>(&'a AtomicUsize);
+
+impl<'a> InFlightGuard<'a> {
+    fn new(in_flight: &'a AtomicUsize) -> Self {
+        in_flight.fetch_add(1, Ordering::Relaxed);
+        Self(in_flight)
+    }
+}
+
+impl Drop for InFlightGuard<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
 }
 
 impl<F: RpcClientFactory> InnerClient<F> {
-    pub fn new(factory: Arc<F>, endpoint: String) -> Self {
+    pub fn new(factory: Arc<F>, endpoint: String, max_consecutive_failures: usize) -> Self {
         InnerClient {
             factory,
             endpoint,
-            inner_client: OnceCell::new(),
+            max_consecutive_failures,
+            refresh_dns_on_failure: false,
+            max_channel_age: None,
+            idle_timeout: None,
+            retry_policy: None,
+            circuit_breaker: None,
+            passive_health: None,
+            reconnect: None,
+            negotiate_capabilities: false,
+            spawner: Spawner::default(),
+            max_rows_per_write: None,
+            split_write_concurrency: 1,
+            write_stats: None,
+            warning_hook: None,
+            query_guard: QueryGuardConfig::default(),
+            metrics: None,
+            clock: Arc::new(SystemClock),
+            inner_client: RwLock::new(None),
+            build_lock: tokio::sync::Mutex::new(()),
+            status: Mutex::new(ConnectionStatus {
+                state: ConnectionState::Idle,
+                consecutive_failures: 0,
+            }),
+            in_flight: AtomicUsize::new(0),
+            rebuilds_on_age: AtomicU64::new(0),
+            rebuilds_on_failure: AtomicU64::new(0),
+            rebuilds_on_idle: AtomicU64::new(0),
+            reconnects: AtomicU64::new(0),
+        }
+    }
+
+    /// Rebuild the client on the first transport failure if the endpoint is a
+    /// hostname rather than an ip address.
+    pub fn with_refresh_dns_on_failure(mut self, refresh_dns_on_failure: bool) -> Self {
+        self.refresh_dns_on_failure = refresh_dns_on_failure && Self::is_hostname(&self.endpoint);
+        self
+    }
+
+    /// Rebuild the channel once it is older than the `max_channel_age`, and
+    /// it is never rebuilt for the age if none.
+    pub fn with_max_channel_age(mut self, max_channel_age: Option<Duration>) -> Self {
+        self.max_channel_age = max_channel_age;
+        self
+    }
+
+    /// Close the channel without any request for the `idle_timeout`, and it
+    /// is never closed for idleness if none.
+    pub fn with_idle_timeout(mut self, idle_timeout: Option<Duration>) -> Self {
+        self.idle_timeout = idle_timeout;
+        self
+    }
+
+    /// Retry the failed requests according to the `retry_policy`, and no retry
+    /// if it is none.
+    pub fn with_retry_policy(mut self, retry_policy: Option<Arc<dyn RetryPolicy>>) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    /// Short-circuit the requests after consecutive transport failures
+    /// according to the `config`, and no circuit breaker if it is none.
+    pub fn with_circuit_breaker(mut self, config: Option<CircuitBreakerConfig>) -> Self {
+        self.circuit_breaker = config.map(CircuitBreaker::new);
+        self
+    }
+
+    /// Record the outcomes of the requests in the `passive_health` shared by
+    /// the clients to all the endpoints, and nothing is recorded if it is
+    /// none.
+    pub fn with_passive_health(mut self, passive_health: Option<Arc<PassiveHealth>>) -> Self {
+        self.passive_health = passive_health;
+        self
+    }
+
+    /// Reconnect with the backoffs when the client fails to connect according
+    /// to the `config`, and no reconnection if it is none.
+    pub fn with_reconnect(mut self, config: Option<ReconnectConfig>) -> Self {
+        self.reconnect = config;
+        self
+    }
+
+    /// Probe the capabilities of the server after every building of the
+    /// client if `negotiate_capabilities` is set.
+    pub fn with_negotiate_capabilities(mut self, negotiate_capabilities: bool) -> Self {
+        self.negotiate_capabilities = negotiate_capabilities;
+        self
+    }
+
+    /// Probe the capabilities of the server on the runtime of the `spawner`.
+    pub fn with_spawner(mut self, spawner: Spawner) -> Self {
+        self.spawner = spawner;
+        self
+    }
+
+    /// Split the writes with more rows than the `max_rows_per_write` into the
+    /// sub-requests, and at most `concurrency` of them are in flight at a
+    /// time.
+    pub fn with_write_split(
+        mut self,
+        max_rows_per_write: Option<usize>,
+        concurrency: usize,
+    ) -> Self {
+        self.max_rows_per_write = max_rows_per_write;
+        self.split_write_concurrency = concurrency.max(1);
+        self
+    }
+
+    /// Record the writes per table into the `write_stats`, which may be
+    /// shared with the clients to other endpoints.
+    pub fn with_write_stats(mut self, write_stats: Option<Arc<WriteStatsRecorder>>) -> Self {
+        self.write_stats = write_stats;
+        self
+    }
+
+    /// Call the `warning_hook` on every warning reported by the endpoint.
+    pub fn with_warning_hook(mut self, warning_hook: Option<WarningHook>) -> Self {
+        self.warning_hook = warning_hook;
+        self
+    }
+
+    /// Bound the sql queries by the `query_guard` unless it is overridden by
+    /// their contexts.
+    pub fn with_query_guard(mut self, query_guard: QueryGuardConfig) -> Self {
+        self.query_guard = query_guard;
+        self
+    }
+
+    /// Record the retries and the breaker states in the `metrics`.
+    pub fn with_metrics(mut self, metrics: Option<ClientMetrics>) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// Measure the time by the `clock`, including the retry backoffs, the
+    /// circuit breaker cooldowns and the latencies.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    fn is_hostname(endpoint: &str) -> bool {
+        match endpoint.parse::<Endpoint>() {
+            Ok(endpoint) => endpoint
+                .addr
+                .trim_start_matches('[')
+                .trim_end_matches(']')
+                .parse::<IpAddr>()
+                .is_err(),
+            Err(_) => false,
         }
     }
 
     #[inline]
-    async fn init(&self) -> Result<Arc<dyn RpcClient>> {
-        self.factory.build(self.endpoint.clone()).await
+    pub fn endpoint(&self) -> &str {
+        &self.endpoint
+    }
+
+    #[inline]
+    pub fn connection_state(&self) -> ConnectionState {
+        self.status
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .state
+            .clone()
+    }
+
+    /// Get the number of the requests in flight, including the ones waiting
+    /// for the retry backoffs.
+    #[inline]
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::Relaxed)
+    }
+
+    #[inline]
+    pub fn config(&self) -> RpcConfig {
+        self.factory.config()
     }
 
+    pub fn channel_stats(&self) -> ChannelStats {
+        ChannelStats {
+            rebuilds_on_age: self.rebuilds_on_age.load(Ordering::Relaxed),
+            rebuilds_on_failure: self.rebuilds_on_failure.load(Ordering::Relaxed),
+            rebuilds_on_idle: self.rebuilds_on_idle.load(Ordering::Relaxed),
+            reconnects: self.reconnects.load(Ordering::Relaxed),
+        }
+    }
+
+    #[inline]
+    pub fn breaker_state(&self) -> Option<BreakerState> {
+        self.circuit_breaker
+            .as_ref()
+            .map(|breaker| breaker.state(self.clock.now()))
+    }
+
+    /// The capabilities of the server probed by the built client, and none if
+    /// no client is built, the negotiation is not set, or the probing fails or
+    /// is in progress.
+    pub fn server_capabilities(&self) -> Option<ServerCapabilities> {
+        *self
+            .inner_client
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .as_ref()?
+            .capabilities
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Probe the capabilities of the server through the newly built `client`
+    /// in the background with its own context, so that the request building
+    /// the client is never delayed, and fill them into the `slot`.
+    fn spawn_probe(
+        &self,
+        client: Arc<dyn RpcClient>,
+        database: Option<String>,
+    ) -> CapabilitiesSlot {
+        let slot = CapabilitiesSlot::default();
+        if !self.negotiate_capabilities {
+            return slot;
+        }
+
+        let ctx = RpcContext {
+            database,
+            ..Default::default()
+        };
+        let probed_slot = slot.clone();
+        self.spawner.spawn(async move {
+            let probed = match client.probe_capabilities(&ctx).await {
+                Ok(probed) => probed,
+                Err(_) => return,
+            };
+            let mut capabilities = probed_slot.lock().unwrap_or_else(PoisonError::into_inner);
+            // The streaming write found unimplemented meanwhile stays disabled.
+            let stream_write =
+                probed.stream_write && !matches!(*capabilities, Some(found) if !found.stream_write);
+            *capabilities = Some(ServerCapabilities {
+                stream_write,
+                ..probed
+            });
+        });
+        slot
+    }
+
+    /// Disable the streaming write for the built client once the server
+    /// answers it unimplemented, e.g. before the probing completes, and return
+    /// whether it is newly disabled.
+    fn disable_stream_write(&self) -> bool {
+        if !self.negotiate_capabilities {
+            return false;
+        }
+        let inner_client = self
+            .inner_client
+            .read()
+            .unwrap_or_else(PoisonError::into_inner);
+        let built = match inner_client.as_ref() {
+            Some(built) => built,
+            None => return false,
+        };
+
+        let mut capabilities = built
+            .capabilities
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if matches!(*capabilities, Some(found) if !found.stream_write) {
+            return false;
+        }
+        *capabilities = Some(ServerCapabilities {
+            stream_write: false,
+            compression: matches!(*capabilities, Some(found) if found.compression),
+        });
+        true
+    }
+
+    /// Get the built client unless it is expired by the max channel age, and
+    /// the expired one is returned as the error, which is none if no client is
+    /// built or the idle one is closed.
+    fn fresh_client(&self) -> std::result::Result<Arc<dyn RpcClient>, Option<Arc<dyn RpcClient>>> {
+        let now = self.clock.now();
+        self.close_idle_client(now);
+        match self
+            .inner_client
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .as_ref()
+        {
+            Some(built) => {
+                built.touch(now);
+                match self.max_channel_age {
+                    Some(max_age) if now.saturating_duration_since(built.built_at) >= max_age => {
+                        Err(Some(built.client.clone()))
+                    }
+                    _ => Ok(built.client.clone()),
+                }
+            }
+            None => Err(None),
+        }
+    }
+
+    /// Close the client without any request for the idle timeout, and the
+    /// next request will rebuild it.
+    fn close_idle_client(&self, now: Instant) {
+        let idle_timeout = match self.idle_timeout {
+            Some(idle_timeout) => idle_timeout,
+            None => return,
+        };
+        let is_idle = |built: &Option<BuiltClient>| matches!(built, Some(built) if built.idle_for(now) >= idle_timeout);
+        if !is_idle(
+            &self
+                .inner_client
+                .read()
+                .unwrap_or_else(PoisonError::into_inner),
+        ) {
+            return;
+        }
+
+        let mut inner_client = self
+            .inner_client
+            .write()
+            .unwrap_or_else(PoisonError::into_inner);
+        // The client may be closed or rebuilt by others.
+        if is_idle(&inner_client) {
+            *inner_client = None;
+            self.rebuilds_on_idle.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Get the built client or build a new one.
+    ///
+    /// The expired client is rebuilt by one request, while the others keep
+    /// using it until the new one is built. The `ctx` with the rest of the
+    /// timeout is returned with the client if the time is spent on the
+    /// reconnections.
+    async fn get_or_build(
+        &self,
+        ctx: &RpcContext,
+    ) -> Result<(Arc<dyn RpcClient>, Option<RpcContext>)> {
+        let expired = match self.fresh_client() {
+            Ok(client) => return Ok((client, None)),
+            Err(expired) => expired,
+        };
+        let _build_guard = match &expired {
+            Some(expired) => match self.build_lock.try_lock() {
+                Ok(guard) => guard,
+                // Being rebuilt by others.
+                Err(_) => return Ok((expired.clone(), None)),
+            },
+            None => self.build_lock.lock().await,
+        };
+        // The client may be built by others during waiting for the lock.
+        let expired = match self.fresh_client() {
+            Ok(client) => return Ok((client, None)),
+            Err(expired) => expired,
+        };
+
+        if expired.is_none() {
+            self.status
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .state = ConnectionState::Connecting;
+        }
+        // The expired client is kept if the rebuilding fails, so no reconnection.
+        let (client, reconnect_ctx) = match expired {
+            Some(_) => (self.build_client().await, None),
+            None => self.build_with_reconnect(ctx).await,
+        };
+        let built_at = self.clock.now();
+        match (&client, expired) {
+            (Ok(client), expired) => {
+                if expired.is_some() {
+                    self.rebuilds_on_age.fetch_add(1, Ordering::Relaxed);
+                }
+                *self
+                    .inner_client
+                    .write()
+                    .unwrap_or_else(PoisonError::into_inner) = Some(BuiltClient::new(
+                    client.clone(),
+                    self.spawn_probe(client.clone(), ctx.database.clone()),
+                    built_at,
+                ));
+                self.on_success();
+            }
+            (Err(_), Some(expired)) => {
+                // Keep the expired client, which is rebuilt again after another
+                // max age or dropped by its failures.
+                if let Some(built) = self
+                    .inner_client
+                    .write()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .as_mut()
+                {
+                    built.built_at = built_at;
+                }
+                return Ok((expired, None));
+            }
+            (Err(e), None) => {
+                // Nothing to drop, the failed building is never cached.
+                let _ = self.on_failure(e);
+            }
+        }
+
+        client.map(|client| (client, reconnect_ctx))
+    }
+
+    /// Build the client filling the context of the grpc errors.
+    async fn build_client(&self) -> Result<Arc<dyn RpcClient>> {
+        let client = self.factory.build(self.endpoint.clone()).await?;
+        Ok(Arc::new(ErrorContextRpcClient::new(
+            client,
+            self.endpoint.clone(),
+            self.clock.clone(),
+        )))
+    }
+
+    /// Build the client, and reconnect with the backoffs on the failures to
+    /// connect if the reconnection is set, unless the next backoff exceeds the
+    /// timeout of the `ctx`.
+    ///
+    /// The `ctx` with the rest of the timeout is returned if any reconnection
+    /// is made.
+    async fn build_with_reconnect(
+        &self,
+        ctx: &RpcContext,
+    ) -> (Result<Arc<dyn RpcClient>>, Option<RpcContext>) {
+        let deadline = ctx.timeout.map(|timeout| self.clock.now() + timeout);
+        let mut reconnects = 0;
+        let result = loop {
+            let result = self.build_client().await;
+            let config = match (&result, &self.reconnect) {
+                (Err(Error::Connect { .. }), Some(config))
+                    if reconnects < config.max_reconnects =>
+                {
+                    config
+                }
+                _ => break result,
+            };
+            let backoff = config.backoff(reconnects);
+            if matches!(deadline, Some(deadline) if self.clock.now() + backoff >= deadline) {
+                break result;
+            }
+
+            #[cfg(feature = "tracing")]
+            if let Err(e) = &result {
+                tracing::debug!(
+                    endpoint = %self.endpoint,
+                    error = %e,
+                    ?backoff,
+                    "reconnect after the failure to connect"
+                );
+            }
+            self.clock.sleep(backoff).await;
+            reconnects += 1;
+            self.reconnects.fetch_add(1, Ordering::Relaxed);
+        };
+
+        let reconnect_ctx = match deadline {
+            Some(deadline) if reconnects > 0 => Some(
+                ctx.clone()
+                    .timeout(deadline.saturating_duration_since(self.clock.now())),
+            ),
+            _ => None,
+        };
+        (result, reconnect_ctx)
+    }
+
+    /// Update the connection status according to the result of rpc.
+    fn observe<T>(&self, result: &Result<T>) {
+        match result {
+            Err(e @ Error::Rpc(rpc_error)) if rpc_error.code() == Code::Unavailable => {
+                if self.on_failure(e) || self.refresh_dns_on_failure {
+                    // Drop the broken client, and the next request will rebuild it.
+                    if self
+                        .inner_client
+                        .write()
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .take()
+                        .is_some()
+                    {
+                        self.rebuilds_on_failure.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+            }
+            _ => {
+                // The channel is idle since the response, not the request.
+                if let Some(built) = self
+                    .inner_client
+                    .read()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .as_ref()
+                {
+                    built.touch(self.clock.now());
+                }
+                self.on_success();
+            }
+        }
+    }
+
+    fn on_success(&self) {
+        let mut status = self.status.lock().unwrap_or_else(PoisonError::into_inner);
+        status.state = ConnectionState::Connected;
+        status.consecutive_failures = 0;
+    }
+
+    /// Record the failure, and return true if the consecutive failures reach
+    /// the limit.
+    fn on_failure(&self, e: &Error) -> bool {
+        let mut status = self.status.lock().unwrap_or_else(PoisonError::into_inner);
+        let since = match &status.state {
+            ConnectionState::Failed { since, .. } => *since,
+            _ => self.clock.now(),
+        };
+        status.state = ConnectionState::Failed {
+            since,
+            last_error: e.to_string(),
+        };
+        status.consecutive_failures += 1;
+
+        status.consecutive_failures >= self.max_consecutive_failures
+    }
+
+    fn execution_info(&self, request_bytes: usize, response_bytes: usize) -> ExecutionInfo {
+        ExecutionInfo {
+            endpoint: self.endpoint.parse().ok(),
+            request_bytes,
+            response_bytes,
+            // Set after all the retries are done.
+            latency: Duration::ZERO,
+            retries: 0,
+            partitions: Vec::new(),
+        }
+    }
+
+    /// Fill the latency of the whole call since `begin`, including the
+    /// connecting and the backoffs, and the `retries` in it.
+    fn finish_execution_info(&self, info: &mut ExecutionInfo, begin: Instant, retries: usize) {
+        info.latency = self.clock.now() - begin;
+        info.retries = retries;
+    }
+
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            name = "sql_query",
+            skip_all,
+            fields(
+                endpoint = %self.endpoint,
+                table_count = req.tables.len(),
+                outcome = tracing::field::Empty,
+            )
+        )
+    )]
     pub async fn sql_query_internal(
         &self,
         ctx: &RpcContext,
         req: &SqlQueryRequest,
     ) -> Result<SqlQueryResponse> {
-        assert!(ctx.database.is_some());
+        let begin = self.clock.now();
+        let (result, retries) = self
+            .call_with_retry(
+                ctx,
+                |ctx| async move { self.sql_query_once(&ctx, req).await },
+            )
+            .await;
+        self.record_retries(OperationKind::SqlQuery, retries);
+        let result = match result {
+            Ok(mut resp) => {
+                self.finish_execution_info(&mut resp.execution_info, begin, retries);
+                Ok(resp)
+            }
+            Err(Error::ResponseTooLarge {
+                limit,
+                endpoint,
+                partial: Some(mut partial),
+            }) => {
+                self.finish_execution_info(&mut partial.execution_info, begin, retries);
+                Err(Error::ResponseTooLarge {
+                    limit,
+                    endpoint,
+                    partial: Some(partial),
+                })
+            }
+            Err(e) => Err(e),
+        };
+        record_span_outcome(&result);
+        result
+    }
+
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            name = "write",
+            skip_all,
+            fields(
+                endpoint = %self.endpoint,
+                table_count = req.point_groups.len(),
+                outcome = tracing::field::Empty,
+            )
+        )
+    )]
+    pub async fn write_internal(
+        &self,
+        ctx: &RpcContext,
+        req: &WriteRequest,
+    ) -> Result<WriteResponse> {
+        let result = self.write_with_retry(ctx, req).await;
+        record_span_outcome(&result);
+        result
+    }
+
+    async fn write_with_retry(
+        &self,
+        ctx: &RpcContext,
+        req: &WriteRequest,
+    ) -> Result<WriteResponse> {
+        // Build the table requests once for all the attempts.
+        let table_requests = WriteTableRequestPbsBuilder(req.clone()).build()?;
+        let max_rows = match self.max_rows_per_write {
+            Some(max_rows) if row_count(&table_requests) > max_rows => max_rows,
+            _ => return self.write_table_requests(ctx, &table_requests).await,
+        };
+
+        // The sub-requests written before the failed one are not rolled back.
+        let sub_requests = split_table_requests(table_requests, max_rows);
+        // Iterate by the indexes, since the closure taking references isn't
+        // general enough for the futures boxed by `async_trait`.
+        let resps: Vec<_> = futures::stream::iter(0..sub_requests.len())
+            .map(|i| self.write_table_requests(ctx, &sub_requests[i]))
+            .buffered(self.split_write_concurrency)
+            .try_collect()
+            .await?;
+        Ok(WriteResponse::merge(resps))
+    }
+
+    async fn write_table_requests(
+        &self,
+        ctx: &RpcContext,
+        table_requests: &[storage::WriteTableRequest],
+    ) -> Result<WriteResponse> {
+        let begin = self.clock.now();
+        let (result, retries) = self
+            .call_with_retry(ctx, |ctx| async move {
+                self.write_once(&ctx, table_requests.to_vec()).await
+            })
+            .await;
+        self.record_retries(OperationKind::Write, retries);
+        if let Some(write_stats) = &self.write_stats {
+            let database = ctx.database.as_deref().unwrap_or_default();
+            write_stats.record(database, table_requests, result.is_ok());
+        }
+
+        result.map(|mut resp| {
+            self.finish_execution_info(&mut resp.execution_info, begin, retries);
+            resp
+        })
+    }
+
+    /// Write the requests in `reqs` by the streaming write rpc, and the
+    /// responses of all the requests are aggregated.
+    ///
+    /// The stream is reopened every [`STREAM_WRITE_SEGMENT_LEN`] requests. A
+    /// segment failed for the transport is resent once on the reconnected
+    /// client, so its requests may be written twice, which is harmless as the
+    /// writes are idempotent on the primary keys. An invalid request fails the
+    /// whole stream, and the requests before it may have been written.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            name = "write_stream",
+            skip_all,
+            fields(endpoint = %self.endpoint, outcome = tracing::field::Empty)
+        )
+    )]
+    pub async fn write_stream_internal<S>(
+        &self,
+        ctx: &RpcContext,
+        mut reqs: S,
+    ) -> Result<WriteResponse>
+    where
+        S: Stream<Item = WriteRequest> + Send + Unpin,
+    {
+        let result = ctx
+            .run_cancellable(self.write_stream_segments(ctx, &mut reqs))
+            .await;
+        record_span_outcome(&result);
+        result
+    }
+
+    async fn write_stream_segments<S>(
+        &self,
+        ctx: &RpcContext,
+        reqs: &mut S,
+    ) -> Result<WriteResponse>
+    where
+        S: Stream<Item = WriteRequest> + Send + Unpin,
+    {
+        let database = ctx.database.clone().ok_or(Error::NoDatabase)?;
+        Self::check_max_send_msg_len_override(ctx)?;
+
+        let begin = self.clock.now();
+        let mut resp = WriteResponse::new(0, 0);
+        let mut dropped = DroppedPoints::default();
+        let mut execution_info = self.execution_info(0, 0);
+        let mut segment = Vec::with_capacity(STREAM_WRITE_SEGMENT_LEN);
+        let mut ended = false;
+        while !ended {
+            segment.clear();
+            match reqs.next().await {
+                Some(req) => segment.push(Self::stream_write_req_pb(&database, req, &mut dropped)?),
+                None => break,
+            }
+
+            let mut result = self
+                .write_segment(ctx, &database, reqs, &mut segment, &mut dropped, &mut ended)
+                .await;
+            if matches!(&result, Err(Error::Rpc(e)) if e.code() == Code::Unimplemented)
+                && self.disable_stream_write()
+            {
+                result = self.resend_segment(ctx, &segment).await;
+            }
+            if matches!(&result, Err(e) if Self::is_transport_error(e)) {
+                ctx.check_cancelled()?;
+                execution_info.retries += 1;
+                result = self.resend_segment(ctx, &segment).await;
+            }
+
+            if let Some(write_stats) = &self.write_stats {
+                for req_pb in &segment {
+                    write_stats.record(&database, &req_pb.table_requests, result.is_ok());
+                }
+            }
+            let RpcResponse {
+                warnings,
+                resp: resp_pb,
+            } = result?;
+            resp.success += resp_pb.success;
+            resp.failed += resp_pb.failed;
+            resp.warnings
+                .extend(self.report_warnings(Operation::Write, warnings));
+            execution_info.request_bytes += segment.iter().map(Message::encoded_len).sum::<usize>();
+            execution_info.response_bytes += resp_pb.encoded_len();
+        }
+
+        execution_info.latency = self.clock.now() - begin;
+        self.record_retries(OperationKind::StreamWrite, execution_info.retries);
+        resp.execution_info = execution_info;
+        dropped.count_in(&mut resp);
+        Ok(resp)
+    }
+
+    /// Open a stream with the first request in `segment`, and feed it with the
+    /// following requests in `reqs` until the segment is full or `reqs` ends,
+    /// which is marked in `ended`. All the requests fed are recorded in
+    /// `segment`, and the points dropped by the validation and the
+    /// normalization are counted in `dropped`.
+    async fn write_segment<S>(
+        &self,
+        ctx: &RpcContext,
+        database: &str,
+        reqs: &mut S,
+        segment: &mut Vec<storage::WriteRequest>,
+        dropped: &mut DroppedPoints,
+        ended: &mut bool,
+    ) -> Result<RpcResponse<storage::WriteResponse>>
+    where
+        S: Stream<Item = WriteRequest> + Send + Unpin,
+    {
+        let (tx, rx) = mpsc::unbounded();
+        let _ = tx.unbounded_send(segment[0].clone());
+        let feed = async move {
+            while segment.len() < STREAM_WRITE_SEGMENT_LEN {
+                let req_pb = match reqs.next().await {
+                    Some(req) => Self::stream_write_req_pb(database, req, dropped)?,
+                    None => {
+                        *ended = true;
+                        break;
+                    }
+                };
+                // The stream is closed if the rpc has failed, and the segment
+                // will be resent.
+                let closed = tx.unbounded_send(req_pb.clone()).is_err();
+                segment.push(req_pb);
+                if closed {
+                    break;
+                }
+            }
+            Ok(())
+        };
+
+        self.stream_write_once(ctx, rx, feed).await
+    }
+
+    async fn resend_segment(
+        &self,
+        ctx: &RpcContext,
+        segment: &[storage::WriteRequest],
+    ) -> Result<RpcResponse<storage::WriteResponse>> {
+        let (tx, rx) = mpsc::unbounded();
+        for req_pb in segment {
+            let _ = tx.unbounded_send(req_pb.clone());
+        }
+        drop(tx);
+
+        self.stream_write_once(ctx, rx, future::ready(Ok(()))).await
+    }
+
+    /// Call the streaming write rpc with `rx` while `feed` is feeding it.
+    async fn stream_write_once<Fut>(
+        &self,
+        ctx: &RpcContext,
+        rx: UnboundedReceiver<storage::WriteRequest>,
+        feed: Fut,
+    ) -> Result<RpcResponse<storage::WriteResponse>>
+    where
+        Fut: Future<Output = Result<()>>,
+    {
+        self.acquire_breaker()?;
+        let result = match self.get_or_build(ctx).await {
+            Ok((client_handle, reconnect_ctx)) => {
+                let ctx = reconnect_ctx.as_ref().unwrap_or(ctx);
+                let stream_write = !matches!(
+                    self.server_capabilities(),
+                    Some(capabilities) if !capabilities.stream_write
+                );
+                let write = async {
+                    if stream_write {
+                        client_handle.stream_write(ctx, rx).await
+                    } else {
+                        Self::unary_write_all(client_handle.as_ref(), ctx, rx).await
+                    }
+                };
+                let (result, fed) = future::join(write, feed).await;
+                self.observe(&result);
+                fed.and(result)
+            }
+            Err(e) => Err(e),
+        };
+        self.record_breaker(&result);
+        self.record_health(&result);
 
-        let client_handle = self.inner_client.get_or_try_init(|| self.init()).await?;
-        let req_ctx = storage::RequestContext {
-            database: ctx.database.clone().unwrap(),
+        result
+    }
+
+    /// Write the requests in `rx` one by one through the unary rpc, for the
+    /// server not supporting the streaming write.
+    async fn unary_write_all(
+        client: &dyn RpcClient,
+        ctx: &RpcContext,
+        mut rx: UnboundedReceiver<storage::WriteRequest>,
+    ) -> Result<RpcResponse<storage::WriteResponse>> {
+        let mut merged = RpcResponse::from(storage::WriteResponse::default());
+        while let Some(req_pb) = rx.next().await {
+            let RpcResponse { warnings, resp } = client.write(ctx, req_pb).await?;
+            merged.warnings.extend(warnings);
+            merged.resp.success += resp.success;
+            merged.resp.failed += resp.failed;
+        }
+
+        Ok(merged)
+    }
+
+    /// Prepare and convert the request, and the points dropped by the
+    /// validation and the normalization are counted in `dropped`.
+    fn stream_write_req_pb(
+        database: &str,
+        req: WriteRequest,
+        dropped: &mut DroppedPoints,
+    ) -> Result<storage::WriteRequest> {
+        let (prepared, dropped_points) = req.prepare()?;
+        let req = match prepared {
+            Cow::Borrowed(_) => req,
+            Cow::Owned(prepared) => prepared,
         };
+        dropped.invalid += dropped_points.invalid;
+        dropped.duplicated += dropped_points.duplicated;
+
+        Ok(storage::WriteRequest {
+            context: Some(storage::RequestContext {
+                database: database.to_string(),
+            }),
+            table_requests: WriteTableRequestPbsBuilder(req).build()?,
+        })
+    }
+
+    /// Call until it succeeds or the retry policy gives up, and the number of
+    /// the retries is returned with the result.
+    ///
+    /// All the attempts share the timeout of the `ctx`, i.e. every attempt is
+    /// called with the rest of the time as the timeout, and no more retry if
+    /// the time runs out after the backoff. The attempt or backoff in progress
+    /// is dropped once the `ctx` is cancelled, and no more attempt is made.
+    async fn call_with_retry<T, C, Fut>(&self, ctx: &RpcContext, call: C) -> (Result<T>, usize)
+    where
+        C: Fn(RpcContext) -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        let _in_flight = InFlightGuard::new(&self.in_flight);
+        let deadline = ctx.timeout.map(|timeout| self.clock.now() + timeout);
+        let mut retries = 0;
+        loop {
+            if let Err(e) = ctx.check_cancelled() {
+                return (Err(e), retries);
+            }
+
+            let attempt_ctx = match deadline {
+                Some(deadline) => ctx
+                    .clone()
+                    .timeout(deadline.saturating_duration_since(self.clock.now())),
+                None => ctx.clone(),
+            };
+            // The cancelled attempt releases the breaker without recording.
+            let result = self
+                .call_through_breaker(|| ctx.run_cancellable(call(attempt_ctx)))
+                .await;
+            let backoff = match (&result, &self.retry_policy) {
+                (Err(Error::Cancelled), _) => None,
+                (Err(e), Some(policy)) => policy.next_backoff(retries + 1, e),
+                _ => None,
+            };
+
+            match backoff {
+                Some(backoff) if matches!(deadline, Some(deadline) if self.clock.now() + backoff >= deadline) => {
+                    return (result, retries)
+                }
+                Some(backoff) => {
+                    let sleep = async {
+                        self.clock.sleep(backoff).await;
+                        Ok(())
+                    };
+                    if let Err(e) = ctx.run_cancellable(sleep).await {
+                        return (Err(e), retries);
+                    }
+                    retries += 1;
+                }
+                None => return (result, retries),
+            }
+        }
+    }
+
+    /// Call unless the circuit breaker is open, and record the result in the
+    /// breaker.
+    async fn call_through_breaker<T, C, Fut>(&self, call: C) -> Result<T>
+    where
+        C: FnOnce() -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        self.acquire_breaker()?;
+        let result = call().await;
+        self.record_breaker(&result);
+        self.record_health(&result);
+        result
+    }
+
+    fn acquire_breaker(&self) -> Result<()> {
+        match &self.circuit_breaker {
+            Some(breaker) if !breaker.try_acquire(self.clock.now()) => Err(Error::CircuitOpen {
+                endpoint: self.endpoint.clone(),
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    fn record_breaker<T>(&self, result: &Result<T>) {
+        let breaker = match &self.circuit_breaker {
+            Some(breaker) => breaker,
+            None => return,
+        };
+        match result {
+            Err(e) if Self::is_transport_error(e) => breaker.on_failure(self.clock.now()),
+            // The server is reachable.
+            Ok(_) | Err(Error::Rpc(_)) | Err(Error::Server(_)) => breaker.on_success(),
+            _ => breaker.release(),
+        }
+        if let Some(metrics) = &self.metrics {
+            metrics.record_breaker_state(&self.endpoint, &breaker.state(self.clock.now()));
+        }
+    }
+
+    fn record_retries(&self, op: OperationKind, retries: usize) {
+        if let Some(metrics) = &self.metrics {
+            metrics.record_retries(op, &self.endpoint, retries);
+        }
+    }
+
+    /// Record the result in the passive health like in the breaker.
+    fn record_health<T>(&self, result: &Result<T>) {
+        let passive_health = match &self.passive_health {
+            Some(passive_health) => passive_health,
+            None => return,
+        };
+        match result {
+            Err(e) if Self::is_transport_error(e) => passive_health.record(&self.endpoint, false),
+            Ok(_) | Err(Error::Rpc(_)) | Err(Error::Server(_)) => {
+                passive_health.record(&self.endpoint, true)
+            }
+            _ => {}
+        }
+    }
+
+    fn is_transport_error(e: &Error) -> bool {
+        match e {
+            Error::Connect { .. } => true,
+            Error::Rpc(e) => e.code() == Code::Unavailable,
+            _ => false,
+        }
+    }
+
+    /// Check the overridden max send message length, which should be positive
+    /// or -1.
+    fn check_max_send_msg_len_override(ctx: &RpcContext) -> Result<()> {
+        match ctx.max_send_msg_len_override {
+            Some(len) if len <= 0 && len != -1 => Err(Error::Client(format!(
+                "invalid max send message length override:{len}, it should be positive or -1"
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    async fn sql_query_once(
+        &self,
+        ctx: &RpcContext,
+        req: &SqlQueryRequest,
+    ) -> Result<SqlQueryResponse> {
+        let database = ctx.database.clone().ok_or(Error::NoDatabase)?;
+        Self::check_max_send_msg_len_override(ctx)?;
+        let query_guard = ctx.query_guard.as_ref().unwrap_or(&self.query_guard);
+        let sql = match query_guard.max_rows {
+            Some(max_rows) => req.bounded_sql(max_rows)?.into_owned(),
+            None => req.sql.clone(),
+        };
+
+        let (client_handle, reconnect_ctx) = self.get_or_build(ctx).await?;
+        let ctx = reconnect_ctx.as_ref().unwrap_or(ctx);
+        let req_ctx = storage::RequestContext { database };
         let req_pb = storage::SqlQueryRequest {
             context: Some(req_ctx),
             tables: req.tables.clone(),
-            sql: req.sql.clone(),
+            sql,
         };
 
-        client_handle
-            .as_ref()
-            .sql_query(ctx, req_pb)
-            .await
-            .and_then(SqlQueryResponse::try_from)
+        let request_bytes = req_pb.encoded_len();
+        let resp = client_handle.as_ref().sql_query(ctx, req_pb).await;
+        self.observe(&resp);
+
+        let RpcResponse {
+            warnings,
+            resp: resp_pb,
+        } = resp?;
+        let execution_info = self.execution_info(request_bytes, resp_pb.encoded_len());
+        let warnings = self.report_warnings(Operation::SqlQuery, warnings);
+        let decoded = SqlQueryResponse::decode(resp_pb, req, query_guard.max_response_bytes);
+        let mut resp = match decoded {
+            Ok(resp) => resp,
+            Err(Error::ResponseTooLarge { limit, partial, .. }) => {
+                let partial = partial.map(|mut partial| {
+                    partial.execution_info = execution_info;
+                    partial.warnings = warnings;
+                    partial
+                });
+                return Err(Error::ResponseTooLarge {
+                    limit,
+                    endpoint: self.endpoint.clone(),
+                    partial,
+                });
+            }
+            Err(e) => return Err(e),
+        };
+        resp.execution_info = execution_info;
+        resp.warnings = warnings;
+
+        Ok(resp)
     }
 
-    pub async fn write_internal(
+    async fn write_once(
         &self,
         ctx: &RpcContext,
-        req: &WriteRequest,
+        table_requests: Vec<storage::WriteTableRequest>,
     ) -> Result<WriteResponse> {
-        assert!(ctx.database.is_some());
+        let database = ctx.database.clone().ok_or(Error::NoDatabase)?;
+        Self::check_max_send_msg_len_override(ctx)?;
 
-        let client_handle = self.inner_client.get_or_try_init(|| self.init()).await?;
-        let req_ctx = storage::RequestContext {
-            database: ctx.database.clone().unwrap(),
-        };
-        let write_table_request_pbs = WriteTableRequestPbsBuilder(req.clone()).build();
+        let (client_handle, reconnect_ctx) = self.get_or_build(ctx).await?;
+        let ctx = reconnect_ctx.as_ref().unwrap_or(ctx);
+        let req_ctx = storage::RequestContext { database };
         let req_pb = storage::WriteRequest {
             context: Some(req_ctx),
-            table_requests: write_table_request_pbs,
+            table_requests,
         };
 
-        client_handle
-            .write(ctx, req_pb)
+        let request_bytes = req_pb.encoded_len();
+        let resp = client_handle.write(ctx, req_pb).await;
+        self.observe(&resp);
+
+        let RpcResponse {
+            warnings,
+            resp: resp_pb,
+        } = resp?;
+        let execution_info = self.execution_info(request_bytes, resp_pb.encoded_len());
+        let mut resp = WriteResponse::from(resp_pb);
+        resp.execution_info = execution_info;
+        resp.warnings = self.report_warnings(Operation::Write, warnings);
+
+        Ok(resp)
+    }
+
+    /// Record the endpoint in the `warnings` reported by it, and call the
+    /// warning hook on them.
+    fn report_warnings(
+        &self,
+        operation: Operation,
+        mut warnings: Vec<ServerWarning>,
+    ) -> Vec<ServerWarning> {
+        for warning in &mut warnings {
+            warning.endpoint = Some(self.endpoint.clone());
+            if let Some(hook) = &self.warning_hook {
+                hook.call(operation, warning.clone());
+            }
+        }
+
+        warnings
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #[cfg(feature = "tracing")]
+    use std::collections::HashMap;
+    use std::{
+        sync::{
+            atomic::{AtomicBool, AtomicUsize, Ordering},
+            Arc, Mutex,
+        },
+        time::Duration,
+    };
+
+    use arrow::{
+        array::{ArrayRef, Int64Array},
+        ipc::writer::StreamWriter,
+        record_batch::RecordBatch,
+    };
+    use ceresdbproto::storage::{
+        arrow_payload::Compression, sql_query_response::Output as OutputPb, ArrowPayload,
+        SqlQueryResponse as QueryResponsePb, WriteResponse as WriteResponsePb,
+    };
+    use futures::{future, stream, StreamExt};
+    use tokio_util::sync::CancellationToken;
+    use tonic::Status;
+    #[cfg(feature = "tracing")]
+    use tracing::span::{Attributes, Id, Record};
+    #[cfg(feature = "tracing")]
+    use tracing_subscriber::{
+        layer::{Context, SubscriberExt},
+        registry::LookupSpan,
+        Layer,
+    };
+
+    use super::{ChannelStats, ConnectionState, InnerClient, STREAM_WRITE_SEGMENT_LEN};
+    use crate::{
+        clock::{Clock, ManualClock},
+        config::{QueryGuardConfig, ReconnectConfig},
+        db_client::retry::ExponentialBackoff,
+        interceptor::OperationKind,
+        model::{
+            sql_query::Request as SqlQueryRequest,
+            value::Value,
+            write::{point::PointBuilder, row_count, Request as WriteRequest},
+        },
+        rpc_client::{
+            MockRpcClient, MockRpcClientFactory, RpcClient, RpcContext, ServerCapabilities,
+        },
+        Error,
+    };
+
+    fn connection_reset() -> Error {
+        Error::Rpc(tonic::Status::unavailable("connection reset").into())
+    }
+
+    /// Factory whose first `failed_builds` buildings fail, and the first
+    /// `unavailable_writes` writes of the first built client fail, where the
+    /// streaming writes fail after all the requests are sent.
+    fn flaky_factory(failed_builds: usize, unavailable_writes: usize) -> MockRpcClientFactory {
+        MockRpcClientFactory::with_build(move |endpoint, build_idx| {
+            if build_idx < failed_builds {
+                return future::ready(Err(Error::Connect {
+                    addr: endpoint,
+                    source: "connection refused".into(),
+                }));
+            }
+
+            let unavailable_writes = if build_idx == failed_builds {
+                unavailable_writes
+            } else {
+                0
+            };
+            let writes = Arc::new(AtomicUsize::new(0));
+            let stream_writes = writes.clone();
+            let client = MockRpcClient::default()
+                .with_failure(move |operation| {
+                    let unavailable = operation == OperationKind::Write
+                        && writes.fetch_add(1, Ordering::Relaxed) < unavailable_writes;
+                    unavailable.then(connection_reset)
+                })
+                .on_stream_write(move |_, reqs| {
+                    let writes = stream_writes.clone();
+                    async move {
+                        let success = reqs.count().await as u32;
+                        if writes.fetch_add(1, Ordering::Relaxed) < unavailable_writes {
+                            return Err(connection_reset());
+                        }
+
+                        Ok(WriteResponsePb {
+                            header: None,
+                            success,
+                            failed: 0,
+                        }
+                        .into())
+                    }
+                });
+            let client: Arc<dyn RpcClient> = Arc::new(client);
+            future::ready(Ok(client))
+        })
+    }
+
+    #[tokio::test]
+    async fn test_reconnect_after_connect_failure() {
+        let factory = Arc::new(flaky_factory(1, 0));
+        let client = InnerClient::new(factory.clone(), "127.0.0.1:8831".to_string(), 3);
+        assert_eq!(client.connection_state(), ConnectionState::Idle);
+
+        let ctx = RpcContext::default().database("public".to_string());
+        let write_res = client.write_internal(&ctx, &WriteRequest::default()).await;
+        assert!(write_res.is_err());
+        assert!(matches!(
+            client.connection_state(),
+            ConnectionState::Failed { .. }
+        ));
+
+        // The failed building is not cached, and recover on the next request.
+        let write_res = client.write_internal(&ctx, &WriteRequest::default()).await;
+        assert!(write_res.is_ok());
+        assert_eq!(client.connection_state(), ConnectionState::Connected);
+        assert_eq!(factory.builds(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_reconnect_with_backoff() {
+        let config = ReconnectConfig {
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(10),
+            multiplier: 2,
+            max_reconnects: 2,
+        };
+        let ctx = RpcContext::default().database("public".to_string());
+        let req = WriteRequest::default();
+
+        // Reconnect after the backoffs of 1s and 2s measured by the clock.
+        let clock = ManualClock::new();
+        let begin = clock.now();
+        let factory = Arc::new(flaky_factory(2, 0));
+        let client = InnerClient::new(factory.clone(), "127.0.0.1:8831".to_string(), 10)
+            .with_reconnect(Some(config.clone()))
+            .with_clock(Arc::new(clock.clone()));
+        let write = client.write_internal(&ctx, &req);
+        let advance = async {
+            for backoff in [1, 2] {
+                while clock.sleepers() == 0 {
+                    tokio::task::yield_now().await;
+                }
+                clock.advance(Duration::from_secs(backoff));
+            }
+        };
+        let (resp, _) = future::join(write, advance).await;
+        assert!(resp.is_ok());
+        assert_eq!(clock.now() - begin, Duration::from_secs(3));
+        assert_eq!(factory.builds(), 3);
+        assert_eq!(client.channel_stats().reconnects, 2);
+        assert_eq!(client.connection_state(), ConnectionState::Connected);
+
+        // Give up after the max reconnections.
+        let factory = Arc::new(flaky_factory(3, 0));
+        let client = InnerClient::new(factory.clone(), "127.0.0.1:8831".to_string(), 10)
+            .with_reconnect(Some(config.clone()))
+            .with_clock(Arc::new(clock.clone()));
+        let write = client.write_internal(&ctx, &req);
+        let advance = async {
+            for backoff in [1, 2] {
+                while clock.sleepers() == 0 {
+                    tokio::task::yield_now().await;
+                }
+                clock.advance(Duration::from_secs(backoff));
+            }
+        };
+        let (resp, _) = future::join(write, advance).await;
+        assert!(matches!(resp, Err(Error::Connect { .. })));
+        assert_eq!(factory.builds(), 3);
+        assert!(matches!(
+            client.connection_state(),
+            ConnectionState::Failed { .. }
+        ));
+
+        // Give up once the next backoff exceeds the timeout.
+        let factory = Arc::new(flaky_factory(2, 0));
+        let client = InnerClient::new(factory.clone(), "127.0.0.1:8831".to_string(), 10)
+            .with_reconnect(Some(config))
+            .with_clock(Arc::new(clock.clone()));
+        let ctx = ctx.timeout(Duration::from_secs(2));
+        let write = client.write_internal(&ctx, &req);
+        let advance = async {
+            while clock.sleepers() == 0 {
+                tokio::task::yield_now().await;
+            }
+            clock.advance(Duration::from_secs(1));
+        };
+        let (resp, _) = future::join(write, advance).await;
+        assert!(matches!(resp, Err(Error::Connect { .. })));
+        assert_eq!(factory.builds(), 2);
+        assert_eq!(client.channel_stats().reconnects, 1);
+        assert_eq!(clock.sleepers(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_rebuild_after_consecutive_failures() {
+        let factory = Arc::new(flaky_factory(0, 2));
+        let client = InnerClient::new(factory.clone(), "127.0.0.1:8831".to_string(), 2);
+        let ctx = RpcContext::default().database("public".to_string());
+
+        for _ in 0..2 {
+            let write_res = client.write_internal(&ctx, &WriteRequest::default()).await;
+            assert!(write_res.is_err());
+        }
+        assert_eq!(factory.builds(), 1);
+
+        // The broken client is dropped, and a new one is built.
+        let write_res = client.write_internal(&ctx, &WriteRequest::default()).await;
+        assert!(write_res.is_ok());
+        assert_eq!(factory.builds(), 2);
+        assert_eq!(client.connection_state(), ConnectionState::Connected);
+    }
+
+    #[tokio::test]
+    async fn test_refresh_dns_on_failure() {
+        let ctx = RpcContext::default().database("public".to_string());
+        let cases = [
+            ("ceresdb.local:8831", true, 2),
+            ("ceresdb.local:8831", false, 1),
+            ("127.0.0.1:8831", true, 1),
+            ("::1:8831", true, 1),
+        ];
+        for (endpoint, refresh_dns_on_failure, expected_builds) in cases {
+            let factory = Arc::new(flaky_factory(0, 1));
+            let client = InnerClient::new(factory.clone(), endpoint.to_string(), 3)
+                .with_refresh_dns_on_failure(refresh_dns_on_failure);
+
+            let write_res = client.write_internal(&ctx, &WriteRequest::default()).await;
+            assert!(write_res.is_err());
+            let _ = client.write_internal(&ctx, &WriteRequest::default()).await;
+            assert_eq!(factory.builds(), expected_builds);
+            assert_eq!(
+                client.channel_stats().rebuilds_on_failure,
+                expected_builds as u64 - 1
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_retry() {
+        let policy = ExponentialBackoff {
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(10),
+            multiplier: 2,
+            max_retries: 3,
+        };
+        let ctx = RpcContext::default().database("public".to_string());
+
+        // Recover after retrying on the failures to connect and the unavailable status.
+        let factory = Arc::new(flaky_factory(1, 2));
+        let client = InnerClient::new(factory.clone(), "127.0.0.1:8831".to_string(), 10)
+            .with_retry_policy(Some(Arc::new(policy.clone())));
+        let resp = client
+            .write_internal(&ctx, &WriteRequest::default())
             .await
-            .map(|resp_pb| resp_pb.into())
+            .unwrap();
+        assert_eq!(resp.execution_info.retries, 3);
+        assert_eq!(factory.builds(), 2);
+
+        // Give up after the max retries.
+        let factory = Arc::new(flaky_factory(0, 5));
+        let client = InnerClient::new(factory, "127.0.0.1:8831".to_string(), 10)
+            .with_retry_policy(Some(Arc::new(policy)));
+        let write_res = client.write_internal(&ctx, &WriteRequest::default()).await;
+        assert!(matches!(write_res, Err(Error::Rpc(_))));
+    }
+
+    #[tokio::test]
+    async fn test_retry_backoff_by_clock() {
+        let backoff = Duration::from_secs(60);
+        let policy = ExponentialBackoff {
+            initial_backoff: backoff,
+            max_backoff: backoff,
+            multiplier: 1,
+            max_retries: 1,
+        };
+        let ctx = RpcContext::default().database("public".to_string());
+        let clock = ManualClock::new();
+        let begin = clock.now();
+        let factory = Arc::new(flaky_factory(0, 1));
+        let client = InnerClient::new(factory, "127.0.0.1:8831".to_string(), 10)
+            .with_retry_policy(Some(Arc::new(policy)))
+            .with_clock(Arc::new(clock.clone()));
+
+        // The retry waits for the backoff measured by the clock.
+        let req = WriteRequest::default();
+        let write = client.write_internal(&ctx, &req);
+        let advance = async {
+            while clock.sleepers() == 0 {
+                tokio::task::yield_now().await;
+            }
+            clock.advance(backoff);
+        };
+        let (resp, _) = future::join(write, advance).await;
+        let info = resp.unwrap().execution_info;
+        assert_eq!(info.retries, 1);
+        assert_eq!(clock.now() - begin, backoff);
+        // The latency covers the whole call, including the backoff.
+        assert_eq!(info.latency, backoff);
+        assert_eq!(info.endpoint, "127.0.0.1:8831".parse().ok());
+    }
+
+    #[tokio::test]
+    async fn test_retry_within_timeout() {
+        let backoff = Duration::from_secs(60);
+        let policy = ExponentialBackoff {
+            initial_backoff: backoff,
+            max_backoff: backoff,
+            multiplier: 1,
+            max_retries: 1,
+        };
+        let clock = ManualClock::new();
+        let factory = Arc::new(flaky_factory(0, 1));
+        let client = InnerClient::new(factory, "127.0.0.1:8831".to_string(), 10)
+            .with_retry_policy(Some(Arc::new(policy)))
+            .with_clock(Arc::new(clock.clone()));
+
+        // No retry if the time runs out after the backoff.
+        let ctx = RpcContext::default()
+            .database("public".to_string())
+            .timeout(backoff / 2);
+        let write_res = client.write_internal(&ctx, &WriteRequest::default()).await;
+        assert!(matches!(write_res, Err(Error::Rpc(_))));
+        assert_eq!(clock.sleepers(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_cancel_retry() {
+        let backoff = Duration::from_secs(60);
+        let policy = ExponentialBackoff {
+            initial_backoff: backoff,
+            max_backoff: backoff,
+            multiplier: 1,
+            max_retries: 3,
+        };
+        let clock = ManualClock::new();
+        let factory = Arc::new(flaky_factory(0, 5));
+        let client = InnerClient::new(factory.clone(), "127.0.0.1:8831".to_string(), 10)
+            .with_retry_policy(Some(Arc::new(policy)))
+            .with_clock(Arc::new(clock.clone()));
+        let token = CancellationToken::new();
+        let ctx = RpcContext::default()
+            .database("public".to_string())
+            .cancel(token.clone());
+
+        // The backoff is interrupted without advancing the clock.
+        let req = WriteRequest::default();
+        let write = client.write_internal(&ctx, &req);
+        let cancel = async {
+            while clock.sleepers() == 0 {
+                tokio::task::yield_now().await;
+            }
+            token.cancel();
+        };
+        let (write_res, _) = future::join(write, cancel).await;
+        assert!(matches!(write_res, Err(Error::Cancelled)));
+        assert_eq!(factory.builds(), 1);
+
+        // No rpc is made with the cancelled token.
+        let factory = Arc::new(flaky_factory(0, 0));
+        let client = InnerClient::new(factory.clone(), "127.0.0.1:8831".to_string(), 10);
+        let write_res = client.write_internal(&ctx, &req).await;
+        assert!(matches!(write_res, Err(Error::Cancelled)));
+        assert_eq!(factory.builds(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_invalid_max_send_msg_len_override() {
+        let factory = Arc::new(flaky_factory(0, 0));
+        let client = InnerClient::new(factory, "127.0.0.1:8831".to_string(), 3);
+        let ctx = RpcContext::default().database("public".to_string());
+
+        for len in [0, -2] {
+            let ctx = ctx.clone().max_send_msg_len_override(len);
+            let write_res = client.write_internal(&ctx, &WriteRequest::default()).await;
+            assert!(matches!(write_res, Err(Error::Client(_))));
+        }
+        for len in [1 << 30, -1] {
+            let ctx = ctx.clone().max_send_msg_len_override(len);
+            let write_res = client.write_internal(&ctx, &WriteRequest::default()).await;
+            assert!(write_res.is_ok());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_write_stream_resend() {
+        let factory = Arc::new(flaky_factory(0, 1));
+        let client = InnerClient::new(factory, "127.0.0.1:8831".to_string(), 3);
+        let ctx = RpcContext::default().database("public".to_string());
+
+        let req_num = STREAM_WRITE_SEGMENT_LEN + 10;
+        let reqs = stream::iter(0..req_num).map(|i| {
+            let point = PointBuilder::new("test_table".to_string())
+                .timestamp(1_700_000_000_000 + i as i64)
+                .field("value".to_string(), Value::Int64(42))
+                .build()
+                .unwrap();
+            let mut req = WriteRequest::default();
+            req.add_point(point);
+            req
+        });
+
+        // The first segment fails and is resent, and the second one succeeds.
+        let resp = client.write_stream_internal(&ctx, reqs).await.unwrap();
+        assert_eq!(resp.success as usize, req_num);
+        assert_eq!(resp.execution_info.retries, 1);
+        assert!(resp.execution_info.request_bytes > 0);
+        assert_eq!(client.connection_state(), ConnectionState::Connected);
+
+        // The invalid request fails the stream.
+        let point = PointBuilder::new("test_table".to_string())
+            .field("value".to_string(), Value::Int64(42))
+            .build()
+            .unwrap();
+        let mut req = WriteRequest::default();
+        req.add_point(point);
+        let write_res = client
+            .write_stream_internal(&ctx, stream::iter([req]))
+            .await;
+        assert!(matches!(write_res, Err(Error::Client(_))));
+    }
+
+    /// Factory resolving the endpoint to a new address on every building, as
+    /// the DNS record is switched, and the rebuildings take `rebuild_delay`.
+    /// The address of every write is recorded in `written`.
+    fn switching_factory(
+        rebuild_delay: Duration,
+        written: Arc<Mutex<Vec<usize>>>,
+    ) -> MockRpcClientFactory {
+        MockRpcClientFactory::with_build(move |_, address| {
+            let written = written.clone();
+            async move {
+                if address > 0 {
+                    tokio::time::sleep(rebuild_delay).await;
+                }
+                let client = MockRpcClient::default().on_write(move |_, _| {
+                    written.lock().unwrap().push(address);
+                    future::ready(Ok(WriteResponsePb::default().into()))
+                });
+                let client: Arc<dyn RpcClient> = Arc::new(client);
+                Ok(client)
+            }
+        })
+    }
+
+    #[tokio::test]
+    async fn test_max_channel_age() {
+        let max_age = Duration::from_secs(60);
+        let clock = ManualClock::new();
+        let written = Arc::new(Mutex::new(Vec::new()));
+        let factory = Arc::new(switching_factory(
+            Duration::from_millis(100),
+            written.clone(),
+        ));
+        let client = InnerClient::new(factory.clone(), "ceresdb.local:8831".to_string(), 3)
+            .with_max_channel_age(Some(max_age))
+            .with_clock(Arc::new(clock.clone()));
+        let ctx = RpcContext::default().database("public".to_string());
+        let req = WriteRequest::default();
+        let write = || client.write_internal(&ctx, &req);
+
+        write().await.unwrap();
+        clock.advance(max_age - Duration::from_secs(1));
+        write().await.unwrap();
+        assert_eq!(factory.builds(), 1);
+
+        // The expired channel serves the other writes during the rebuilding,
+        // and the traffic moves to the new address without any failure.
+        clock.advance(Duration::from_secs(1));
+        let (rebuilding, other) = future::join(write(), async {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            write().await
+        })
+        .await;
+        rebuilding.unwrap();
+        other.unwrap();
+        write().await.unwrap();
+
+        assert_eq!(*written.lock().unwrap(), vec![0, 0, 0, 1, 1]);
+        assert_eq!(
+            client.channel_stats(),
+            ChannelStats {
+                rebuilds_on_age: 1,
+                rebuilds_on_failure: 0,
+                rebuilds_on_idle: 0,
+                reconnects: 0,
+            }
+        );
+        assert_eq!(client.connection_state(), ConnectionState::Connected);
+    }
+    #[tokio::test]
+    async fn test_idle_timeout() {
+        let idle_timeout = Duration::from_secs(30);
+        let clock = ManualClock::new();
+        let written = Arc::new(Mutex::new(Vec::new()));
+        let factory = Arc::new(switching_factory(Duration::ZERO, written.clone()));
+        let client = InnerClient::new(factory.clone(), "127.0.0.1:8831".to_string(), 3)
+            .with_idle_timeout(Some(idle_timeout))
+            .with_clock(Arc::new(clock.clone()));
+        let ctx = RpcContext::default().database("public".to_string());
+        let req = WriteRequest::default();
+        let write = || client.write_internal(&ctx, &req);
+
+        // The channel in use is never closed.
+        for _ in 0..3 {
+            write().await.unwrap();
+            clock.advance(idle_timeout - Duration::from_secs(1));
+        }
+        assert_eq!(factory.builds(), 1);
+
+        // The idle channel is closed, and the next write goes to a new one.
+        clock.advance(Duration::from_secs(1));
+        write().await.unwrap();
+        assert_eq!(*written.lock().unwrap(), vec![0, 0, 0, 1]);
+        assert_eq!(client.channel_stats().rebuilds_on_idle, 1);
+    }
+
+    /// Rpc client recording the sql of every query, whose response has a
+    /// record batch of two rows for each of the `batches`.
+    fn query_rpc_client(batches: usize, sqls: Arc<Mutex<Vec<String>>>) -> MockRpcClient {
+        MockRpcClient::default().on_sql_query(move |_, req| {
+            sqls.lock().unwrap().push(req.sql);
+
+            let record_batches = (0..batches as i64)
+                .map(|i| {
+                    let batch = RecordBatch::try_from_iter(vec![(
+                        "ts",
+                        Arc::new(Int64Array::from(vec![i * 2, i * 2 + 1])) as ArrayRef,
+                    )])
+                    .unwrap();
+                    let mut bytes = Vec::new();
+                    let mut writer = StreamWriter::try_new(&mut bytes, &batch.schema()).unwrap();
+                    writer.write(&batch).unwrap();
+                    writer.finish().unwrap();
+                    drop(writer);
+                    bytes
+                })
+                .collect();
+            future::ready(Ok(QueryResponsePb {
+                header: None,
+                output: Some(OutputPb::Arrow(ArrowPayload {
+                    record_batches,
+                    compression: Compression::None as i32,
+                })),
+            }
+            .into()))
+        })
+    }
+
+    #[tokio::test]
+    async fn test_query_guard() {
+        let sqls = Arc::new(Mutex::new(Vec::new()));
+        let factory = {
+            let sqls = sqls.clone();
+            Arc::new(MockRpcClientFactory::new(move |_| {
+                query_rpc_client(3, sqls.clone())
+            }))
+        };
+        let client = InnerClient::new(factory, "127.0.0.1:8831".to_string(), 3).with_query_guard(
+            QueryGuardConfig {
+                max_rows: Some(100),
+                max_response_bytes: Some(1),
+            },
+        );
+        let ctx = RpcContext::default().database("public".to_string());
+        let req = |sql: &str| SqlQueryRequest::new(vec!["t".to_string()], sql.to_string());
+
+        // The sql is bounded, and the decoding is aborted with the partial
+        // response filled like a complete one.
+        let err = client
+            .sql_query_internal(&ctx, &req("SELECT * FROM t"))
+            .await
+            .unwrap_err();
+        match &err {
+            Error::ResponseTooLarge {
+                limit, endpoint, ..
+            } => {
+                assert_eq!(*limit, 1);
+                assert_eq!(endpoint, "127.0.0.1:8831");
+            }
+            e => panic!("unexpected error:{e:?}"),
+        }
+        let partial = err.partial_response().unwrap();
+        assert!(partial.rows().is_empty());
+        assert!(partial.execution_info.response_bytes > 0);
+        assert_eq!(
+            *sqls.lock().unwrap(),
+            vec!["SELECT * FROM t LIMIT 100".to_string()]
+        );
+
+        // The sql which can't be bounded is not sent.
+        let err = client
+            .sql_query_internal(&ctx, &req("SELECT * FROM t LIMIT 1000"))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::Client(_)), "err:{err:?}");
+        assert_eq!(sqls.lock().unwrap().len(), 1);
+
+        // The trusted context overrides the guard as a whole.
+        let trusted = ctx.clone().query_guard(QueryGuardConfig {
+            max_rows: None,
+            max_response_bytes: None,
+        });
+        let resp = client
+            .sql_query_internal(&trusted, &req("SELECT * FROM t LIMIT 1000"))
+            .await
+            .unwrap();
+        assert_eq!(resp.rows().len(), 6);
+        assert_eq!(
+            sqls.lock().unwrap().last().unwrap(),
+            "SELECT * FROM t LIMIT 1000"
+        );
+    }
+
+    /// The tables with their rows of every write.
+    type RecordedWrites = Arc<Mutex<Vec<Vec<(String, usize)>>>>;
+
+    /// Rpc client recording the tables and the rows of every write.
+    fn write_recording_rpc_client(writes: RecordedWrites) -> MockRpcClient {
+        MockRpcClient::default().on_write(move |_, req| {
+            let tables = req
+                .table_requests
+                .iter()
+                .map(|t| (t.table.clone(), row_count(std::slice::from_ref(t))))
+                .collect();
+            let rows = row_count(&req.table_requests);
+            writes.lock().unwrap().push(tables);
+
+            future::ready(Ok(WriteResponsePb {
+                header: None,
+                success: rows as u32,
+                failed: 0,
+            }
+            .into()))
+        })
+    }
+
+    #[tokio::test]
+    async fn test_split_write() {
+        let writes = Arc::new(Mutex::new(Vec::new()));
+        let factory = {
+            let writes = writes.clone();
+            Arc::new(MockRpcClientFactory::new(move |_| {
+                write_recording_rpc_client(writes.clone())
+            }))
+        };
+        let ctx = RpcContext::default().database("public".to_string());
+        let mut req = WriteRequest::default();
+        for (table, rows) in [("t1", 5), ("t2", 1)] {
+            for i in 0..rows {
+                let point = PointBuilder::new(table.to_string())
+                    .timestamp(1_700_000_000_000 + i)
+                    .field("value".to_string(), Value::Int64(i))
+                    .build()
+                    .unwrap();
+                req.add_point(point);
+            }
+        }
+
+        for concurrency in [1, 4] {
+            writes.lock().unwrap().clear();
+            let client = InnerClient::new(factory.clone(), "127.0.0.1:8831".to_string(), 3)
+                .with_write_split(Some(2), concurrency);
+            let resp = client.write_internal(&ctx, &req).await.unwrap();
+            assert_eq!((resp.success, resp.failed), (6, 0));
+            assert_eq!(resp.execution_info.partitions.len(), 3);
+
+            // Every sub-request is within the limit, and every table except
+            // the one larger than the limit is in a single sub-request.
+            let writes = writes.lock().unwrap().clone();
+            assert_eq!(writes.len(), 3);
+            assert!(writes
+                .iter()
+                .all(|tables| tables.iter().map(|(_, rows)| rows).sum::<usize>() <= 2));
+            let parts_of = |table: &str| -> Vec<usize> {
+                writes
+                    .iter()
+                    .flatten()
+                    .filter(|(t, _)| t == table)
+                    .map(|(_, rows)| *rows)
+                    .collect()
+            };
+            assert_eq!(parts_of("t1").iter().sum::<usize>(), 5);
+            assert_eq!(parts_of("t2"), vec![1]);
+        }
+
+        // No split within the limit.
+        writes.lock().unwrap().clear();
+        let client =
+            InnerClient::new(factory, "127.0.0.1:8831".to_string(), 3).with_write_split(Some(6), 1);
+        client.write_internal(&ctx, &req).await.unwrap();
+        assert_eq!(writes.lock().unwrap().len(), 1);
+    }
+
+    /// The rpcs received by a server, with the number of the requests of the
+    /// streaming writes.
+    #[derive(Debug, PartialEq, Eq)]
+    enum ReceivedRpc {
+        Write,
+        StreamWrite(usize),
+    }
+
+    /// Factory building the clients to the server recording the rpcs it
+    /// receives, which is upgraded to support the streaming write once
+    /// `stream_write` is set.
+    fn versioned_factory(
+        stream_write: Arc<AtomicBool>,
+        received: Arc<Mutex<Vec<ReceivedRpc>>>,
+    ) -> MockRpcClientFactory {
+        MockRpcClientFactory::new(move |_| {
+            let stream_write = stream_write.load(Ordering::Relaxed);
+            let write_received = received.clone();
+            let stream_received = received.clone();
+            MockRpcClient::default()
+                .on_write(move |_, _| {
+                    write_received.lock().unwrap().push(ReceivedRpc::Write);
+                    future::ready(Ok(WriteResponsePb {
+                        header: None,
+                        success: 1,
+                        failed: 0,
+                    }
+                    .into()))
+                })
+                .on_stream_write(move |_, reqs| {
+                    let received = stream_received.clone();
+                    async move {
+                        if !stream_write {
+                            return Err(Error::Rpc(
+                                Status::unimplemented("unknown method StreamWrite").into(),
+                            ));
+                        }
+                        let reqs = reqs.count().await;
+                        received
+                            .lock()
+                            .unwrap()
+                            .push(ReceivedRpc::StreamWrite(reqs));
+                        Ok(WriteResponsePb {
+                            header: None,
+                            success: reqs as u32,
+                            failed: 0,
+                        }
+                        .into())
+                    }
+                })
+        })
+    }
+
+    /// Wait for the probing in the background to complete.
+    async fn wait_probed(client: &InnerClient<MockRpcClientFactory>, stream_write: bool) {
+        let expected = Some(ServerCapabilities {
+            stream_write,
+            compression: false,
+        });
+        let probed = async {
+            while client.server_capabilities() != expected {
+                tokio::task::yield_now().await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(1), probed)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_negotiate_capabilities() {
+        let idle_timeout = Duration::from_secs(60);
+        let clock = ManualClock::new();
+        let stream_write = Arc::new(AtomicBool::new(false));
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let factory = Arc::new(versioned_factory(stream_write.clone(), received.clone()));
+        let client = InnerClient::new(factory.clone(), "127.0.0.1:8831".to_string(), 3)
+            .with_negotiate_capabilities(true)
+            .with_idle_timeout(Some(idle_timeout))
+            .with_clock(Arc::new(clock.clone()));
+        let ctx = RpcContext::default().database("public".to_string());
+        let reqs = || {
+            stream::iter(0..3).map(|i| {
+                let point = PointBuilder::new("test_table".to_string())
+                    .timestamp(1_700_000_000_000 + i)
+                    .field("value".to_string(), Value::Int64(i))
+                    .build()
+                    .unwrap();
+                let mut req = WriteRequest::default();
+                req.add_point(point);
+                req
+            })
+        };
+        assert_eq!(client.server_capabilities(), None);
+
+        // The old server gets the unary writes without any error, even before
+        // the probing in the background completes.
+        let resp = client.write_stream_internal(&ctx, reqs()).await.unwrap();
+        assert_eq!((resp.success, resp.failed), (3, 0));
+        wait_probed(&client, false).await;
+        assert_eq!(
+            received.lock().unwrap().drain(..).collect::<Vec<_>>(),
+            vec![ReceivedRpc::Write, ReceivedRpc::Write, ReceivedRpc::Write]
+        );
+
+        // The capabilities are probed again once the channel is rebuilt after
+        // the upgrade, and the new server gets one streaming write besides the
+        // empty probe.
+        stream_write.store(true, Ordering::Relaxed);
+        clock.advance(idle_timeout);
+        let resp = client.write_stream_internal(&ctx, reqs()).await.unwrap();
+        assert_eq!((resp.success, resp.failed), (3, 0));
+        wait_probed(&client, true).await;
+        let mut probed: Vec<_> = received.lock().unwrap().drain(..).collect();
+        probed.sort_by_key(|rpc| matches!(rpc, ReceivedRpc::StreamWrite(0)));
+        assert_eq!(
+            probed,
+            vec![ReceivedRpc::StreamWrite(3), ReceivedRpc::StreamWrite(0)]
+        );
+
+        // Nothing is probed without the negotiation, and the streaming write
+        // is sent as is.
+        let client = InnerClient::new(factory.clone(), "127.0.0.1:8831".to_string(), 3);
+        client.write_stream_internal(&ctx, reqs()).await.unwrap();
+        assert_eq!(client.server_capabilities(), None);
+        assert_eq!(
+            received.lock().unwrap().drain(..).collect::<Vec<_>>(),
+            vec![ReceivedRpc::StreamWrite(3)]
+        );
+    }
+
+    #[cfg(feature = "tracing")]
+    #[tokio::test]
+    async fn test_span_fields() {
+        let recorder = SpanRecorder::default();
+        let subscriber = tracing_subscriber::registry().with(recorder.clone());
+        let _guard = tracing::subscriber::set_default(subscriber);
+        let ctx = RpcContext::default().database("public".to_string());
+        let factory = Arc::new(flaky_factory(0, 1));
+        let client = InnerClient::new(factory, "127.0.0.1:8831".to_string(), 3);
+
+        let point = PointBuilder::new("test_table".to_string())
+            .timestamp(1_700_000_000_000)
+            .field("value".to_string(), Value::Int64(1))
+            .build()
+            .unwrap();
+        let mut req = WriteRequest::default();
+        req.add_point(point);
+        // Only the first write is unavailable.
+        assert!(client.write_internal(&ctx, &req).await.is_err());
+        assert!(client.write_internal(&ctx, &req).await.is_ok());
+
+        let expected = |outcome: &str| {
+            [
+                ("endpoint", "127.0.0.1:8831"),
+                ("table_count", "1"),
+                ("outcome", outcome),
+            ]
+            .into_iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect::<HashMap<_, _>>()
+        };
+        assert_eq!(
+            recorder.spans("write"),
+            vec![expected("error"), expected("ok")]
+        );
+    }
+
+    /// Layer recording the fields of the spans when they are closed.
+    #[cfg(feature = "tracing")]
+    #[derive(Clone, Default)]
+    struct SpanRecorder {
+        closed: Arc<Mutex<Vec<(&'static str, SpanFields)>>>,
+    }
+
+    #[cfg(feature = "tracing")]
+    type SpanFields = HashMap<String, String>;
+
+    #[cfg(feature = "tracing")]
+    impl SpanRecorder {
+        /// The fields of the spans named `name` in the order they are closed.
+        fn spans(&self, name: &str) -> Vec<SpanFields> {
+            let closed = self.closed.lock().unwrap();
+            closed
+                .iter()
+                .filter(|(span_name, _)| *span_name == name)
+                .map(|(_, fields)| fields.clone())
+                .collect()
+        }
+    }
+
+    #[cfg(feature = "tracing")]
+    struct FieldVisitor<'a>(&'a mut SpanFields);
+
+    #[cfg(feature = "tracing")]
+    impl tracing::field::Visit for FieldVisitor<'_> {
+        fn record_str(&mut self, field: &tracing::field::Field, value: &str) {
+            self.0.insert(field.name().to_string(), value.to_string());
+        }
+
+        fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+            self.0
+                .insert(field.name().to_string(), format!("{value:?}"));
+        }
+    }
+
+    #[cfg(feature = "tracing")]
+    impl<S> Layer<S> for SpanRecorder
+    where
+        S: tracing::Subscriber + for<'a> LookupSpan<'a>,
+    {
+        fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+            let mut fields = SpanFields::new();
+            attrs.record(&mut FieldVisitor(&mut fields));
+            ctx.span(id).unwrap().extensions_mut().insert(fields);
+        }
+
+        fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+            let span = ctx.span(id).unwrap();
+            let mut extensions = span.extensions_mut();
+            let fields = extensions.get_mut::<SpanFields>().unwrap();
+            values.record(&mut FieldVisitor(fields));
+        }
+
+        fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+            let span = ctx.span(&id).unwrap();
+            let fields = span.extensions_mut().remove::<SpanFields>().unwrap();
+            self.closed.lock().unwrap().push((span.name(), fields));
+        }
     }
 }
@@ -0,0 +1,157 @@
+// Copyright 2022 CeresDB Project Authors. Licensed under Apache-2.0.
+
+//! Mock client
+
+use std::{future::Future, sync::Arc};
+
+use async_trait::async_trait;
+use futures::{future::BoxFuture, stream::BoxStream, FutureExt};
+
+use crate::{
+    clock::{Clock, SystemClock},
+    db_client::{paged_sql_query, DbClient},
+    model::{
+        route::Endpoint,
+        sql_query::{Request as SqlQueryRequest, Response as SqlQueryResponse},
+        write::{Request as WriteRequest, Response as WriteResponse},
+    },
+    rpc_client::RpcContext,
+    Result, RpcConfig,
+};
+
+/// Hook serving the requests of an operation.
+type Hook<Req, Resp> =
+    Arc<dyn Fn(RpcContext, Req) -> BoxFuture<'static, Result<Resp>> + Send + Sync>;
+
+/// Hook serving the paged queries, with the page size.
+type PagedHook = Arc<
+    dyn Fn(RpcContext, SqlQueryRequest, usize) -> BoxStream<'static, Result<SqlQueryResponse>>
+        + Send
+        + Sync,
+>;
+
+/// Client used for testing, whose requests are served by the hooks set by the
+/// `on_*` methods, and the operations without any hook get the default
+/// responses:
+///  - sql_query: the empty response.
+///  - write: all the points are written successfully.
+///  - sql_query_paged: the pages queried by the sql_query one by one, like the
+///    default implementation.
+///  - resolve_route_uncached: none.
+///
+/// The config and the clock are the ones set by
+/// [`with_config`](Self::with_config) and [`with_clock`](Self::with_clock),
+/// and the default ones otherwise.
+#[derive(Clone, Default)]
+pub struct MockDbClient {
+    config: RpcConfig,
+    clock: Option<Arc<dyn Clock>>,
+    sql_query: Option<Hook<SqlQueryRequest, SqlQueryResponse>>,
+    write: Option<Hook<WriteRequest, WriteResponse>>,
+    sql_query_paged: Option<PagedHook>,
+}
+
+fn hook<Req, Resp, F, Fut>(f: F) -> Hook<Req, Resp>
+where
+    F: Fn(RpcContext, Req) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<Resp>> + Send + 'static,
+{
+    Arc::new(move |ctx, req| f(ctx, req).boxed())
+}
+
+impl MockDbClient {
+    pub fn with_config(mut self, config: RpcConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = Some(clock);
+        self
+    }
+
+    pub fn on_sql_query<F, Fut>(mut self, f: F) -> Self
+    where
+        F: Fn(RpcContext, SqlQueryRequest) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<SqlQueryResponse>> + Send + 'static,
+    {
+        self.sql_query = Some(hook(f));
+        self
+    }
+
+    pub fn on_write<F, Fut>(mut self, f: F) -> Self
+    where
+        F: Fn(RpcContext, WriteRequest) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<WriteResponse>> + Send + 'static,
+    {
+        self.write = Some(hook(f));
+        self
+    }
+
+    pub fn on_sql_query_paged<F>(mut self, f: F) -> Self
+    where
+        F: Fn(RpcContext, SqlQueryRequest, usize) -> BoxStream<'static, Result<SqlQueryResponse>>
+            + Send
+            + Sync
+            + 'static,
+    {
+        self.sql_query_paged = Some(Arc::new(f));
+        self
+    }
+}
+
+#[async_trait]
+impl DbClient for MockDbClient {
+    async fn sql_query(&self, ctx: &RpcContext, req: &SqlQueryRequest) -> Result<SqlQueryResponse> {
+        match &self.sql_query {
+            Some(sql_query) => sql_query(ctx.clone(), req.clone()).await,
+            None => Ok(SqlQueryResponse::default()),
+        }
+    }
+
+    async fn write(&self, ctx: &RpcContext, req: &WriteRequest) -> Result<WriteResponse> {
+        match &self.write {
+            Some(write) => write(ctx.clone(), req.clone()).await,
+            None => {
+                let points: usize = req.point_groups.values().map(Vec::len).sum();
+                Ok(WriteResponse::new(points as u32, 0))
+            }
+        }
+    }
+
+    fn sql_query_paged<'a>(
+        &'a self,
+        ctx: &'a RpcContext,
+        req: &'a SqlQueryRequest,
+        page_size: usize,
+    ) -> BoxStream<'a, Result<SqlQueryResponse>> {
+        match &self.sql_query_paged {
+            Some(sql_query_paged) => sql_query_paged(ctx.clone(), req.clone(), page_size),
+            None => paged_sql_query(
+                req,
+                page_size,
+                ctx.cancel.clone(),
+                move |page_req| async move { self.sql_query(ctx, &page_req).await },
+            ),
+        }
+    }
+
+    fn config(&self) -> RpcConfig {
+        self.config.clone()
+    }
+
+    fn clock(&self) -> Arc<dyn Clock> {
+        match &self.clock {
+            Some(clock) => clock.clone(),
+            None => Arc::new(SystemClock),
+        }
+    }
+
+    async fn resolve_route_uncached(
+        &self,
+        _ctx: &RpcContext,
+        _table: &str,
+    ) -> Result<Option<Endpoint>> {
+        Ok(None)
+    }
+}
@@ -2,27 +2,486 @@
 
 //! This module provides the definition and implementations of the `DbClient`.
 
+#![cfg_attr(not(test), deny(clippy::unwrap_used))]
+
+#[macro_use]
+mod forward;
+
+mod auto_create;
+#[cfg(feature = "blocking")]
+mod blocking;
 mod builder;
+mod circuit_breaker;
+mod database_scoped;
+mod deadline;
+mod default_context;
+mod discovery;
+mod group_commit;
+mod health_check;
+mod hedge;
 mod inner;
+#[cfg(test)]
+mod mock_db_client;
+mod paginated;
+mod passive_health;
+mod query_cache;
 mod raw;
+mod resilient;
+mod retry;
 mod route_based;
+mod slow_request;
+mod write_affinity;
+mod write_stats;
+
+use std::{collections::HashMap, future::Future, sync::Arc};
 
 use async_trait::async_trait;
+#[cfg(feature = "blocking")]
+pub use blocking::{BlockingDbClient, BlockingRuntime};
 pub use builder::{Builder, Mode};
+pub use circuit_breaker::BreakerState;
+pub use database_scoped::DatabaseScopedClient;
+pub use discovery::{DiscoveryProvider, DnsDiscovery, StaticList};
+use futures::{
+    stream::{self, BoxStream},
+    StreamExt,
+};
+pub use group_commit::GroupCommitStats;
+pub use health_check::{HealthProbe, TcpProbe};
+pub use hedge::HedgeStats;
+pub use inner::{ChannelStats, ConnectionState};
+#[cfg(test)]
+pub(crate) use mock_db_client::MockDbClient;
+pub use paginated::PaginatedQuery;
+pub use query_cache::QueryCacheStats;
+pub use resilient::{ResilientWriter, WriteOutcome};
+pub use retry::{ExponentialBackoff, RetryPolicy};
+pub use slow_request::{Operation, SlowRequestHook, SlowRequestInfo};
+use tokio_util::sync::CancellationToken;
+pub use write_stats::TableWriteStats;
 
 use crate::{
+    clock::{Clock, SystemClock},
     model::{
-        sql_query::{Request as SqlQueryRequest, Response as SqlQueryResponse},
+        ddl::{drop_table_sql, TableDefinition},
+        explain::{explain_request, QueryPlan},
+        route::{Endpoint, Route, TableRoute},
+        schema::{describe_table_request, TableSchema},
+        sql_query::{
+            row::Row, MultiStatementRequest, Request as SqlQueryRequest,
+            Response as SqlQueryResponse, StatementResult,
+        },
+        value::Value,
         write::{Request as WriteRequest, Response as WriteResponse},
     },
-    rpc_client::RpcContext,
-    Result,
+    rpc_client::{check_msg_len, RpcContext, ServerCapabilities},
+    Result, RpcConfig,
 };
 
 #[async_trait]
 pub trait DbClient: Send + Sync {
     async fn sql_query(&self, ctx: &RpcContext, req: &SqlQueryRequest) -> Result<SqlQueryResponse>;
     async fn write(&self, ctx: &RpcContext, req: &WriteRequest) -> Result<WriteResponse>;
+
+    /// Query with the default context, see
+    /// [`Builder::default_context`](crate::Builder::default_context).
+    async fn sql_query_default_ctx(&self, req: &SqlQueryRequest) -> Result<SqlQueryResponse> {
+        self.sql_query(&RpcContext::default(), req).await
+    }
+
+    /// Write with the default context, see
+    /// [`Builder::default_context`](crate::Builder::default_context).
+    async fn write_default_ctx(&self, req: &WriteRequest) -> Result<WriteResponse> {
+        self.write(&RpcContext::default(), req).await
+    }
+
+    /// Run the independent queries in `reqs` concurrently, and the results are
+    /// returned in the same order as `reqs`.
+    ///
+    /// At most
+    /// [`sql_query_batch_concurrency`](crate::RpcConfig::sql_query_batch_concurrency)
+    /// queries are in flight at a time, and every query is routed by its own
+    /// tables in [`Mode::Direct`].
+    async fn sql_query_batch(
+        &self,
+        ctx: &RpcContext,
+        reqs: &[SqlQueryRequest],
+    ) -> Vec<Result<SqlQueryResponse>> {
+        let concurrency = self.config().sql_query_batch_concurrency.max(1);
+        // Iterate by the indexes, since the closure taking references isn't
+        // general enough for the futures boxed by `async_trait`.
+        stream::iter(0..reqs.len())
+            .map(|i| self.sql_query(ctx, &reqs[i]))
+            .buffered(concurrency)
+            .collect()
+            .await
+    }
+
+    /// Run the statements of the multi-statement `req` one by one in order,
+    /// e.g. the migration scripts, and the results of the statements run are
+    /// returned in the same order.
+    ///
+    /// All the statements are routed by the same tables of the `req`, so they
+    /// are sent to the same endpoint. If the
+    /// [`stop_on_error`](MultiStatementRequest::stop_on_error) is set, the
+    /// statements after the failed one are not run, and the last result is
+    /// the error. The statements are timed by the [`clock`](Self::clock).
+    async fn sql_query_multi(
+        &self,
+        ctx: &RpcContext,
+        req: &MultiStatementRequest,
+    ) -> Vec<StatementResult> {
+        let clock = self.clock();
+        let mut results = Vec::with_capacity(req.statements.len());
+        for i in 0..req.statements.len() {
+            let begin = clock.now();
+            let result = self.sql_query(ctx, &req.statement_request(i)).await;
+            let failed = result.is_err();
+            results.push(StatementResult {
+                sql: req.statements[i].clone(),
+                result,
+                elapsed: clock.now() - begin,
+            });
+            if failed && req.stop_on_error {
+                break;
+            }
+        }
+
+        results
+    }
+
+    /// Write the requests in `reqs` as they come, and the responses of all the
+    /// requests are aggregated.
+    ///
+    /// The requests are sent by long-lived streaming write rpcs in
+    /// [`Mode::Direct`] and [`Mode::Proxy`], and a segment of them failed for
+    /// the transport is resent once, so some rows may be written twice, which
+    /// is harmless as the writes are idempotent on the primary keys. The
+    /// requests before an invalid one may have been written when it fails.
+    ///
+    /// The default implementation writes the requests one by one, and the
+    /// execution info of every request is a partition of the aggregated one.
+    async fn write_stream(
+        &self,
+        ctx: &RpcContext,
+        mut reqs: BoxStream<'_, WriteRequest>,
+    ) -> Result<WriteResponse> {
+        let mut resps = Vec::new();
+        while let Some(req) = reqs.next().await {
+            resps.push(self.write(ctx, &req).await?);
+        }
+
+        Ok(WriteResponse::concat(resps))
+    }
+
+    /// Query by pages, and every page contains at most `page_size` rows.
+    ///
+    /// The pages are fetched by appending `LIMIT` and `OFFSET` to the sql, so
+    /// the sql in `req` shouldn't contain them. The stream stops after a page
+    /// with fewer rows than `page_size`, and only the first page may be empty.
+    ///
+    /// See [`PaginatedQuery`] to fetch the pages by independent queries one at
+    /// a time.
+    fn sql_query_paged<'a>(
+        &'a self,
+        ctx: &'a RpcContext,
+        req: &'a SqlQueryRequest,
+        page_size: usize,
+    ) -> BoxStream<'a, Result<SqlQueryResponse>> {
+        paged_sql_query(
+            req,
+            page_size,
+            ctx.cancel.clone(),
+            move |page_req| async move { self.sql_query(ctx, &page_req).await },
+        )
+    }
+
+    /// Run all the client-side checks of the write request without sending
+    /// it, so the request is checked as if it were written.
+    ///
+    /// It fails if:
+    /// - a table has no point, or a point belongs to another table;
+    /// - a point is rejected by the [`Strict`](crate::ValidationMode::Strict)
+    ///   validation, whatever the [`validation`](WriteRequest::validation) of
+    ///   the request is;
+    /// - a point has no fields or a column with the reserved name;
+    /// - a column of a table is both tag and field, or of different data types
+    ///   in the points, while the null is compatible with any type;
+    /// - the timestamp of a point is missing or too small according to the
+    ///   options of the request;
+    /// - the encoded request exceeds the
+    ///   [`max_send_msg_len`](crate::RpcConfig::max_send_msg_len).
+    fn validate_write(&self, req: &WriteRequest) -> Result<()> {
+        let req_len = req.check()?;
+        check_msg_len(req_len, self.config().max_send_msg_len)
+    }
+
+    /// Get the connection states of the channels to all the known endpoints,
+    /// and none is known by default.
+    fn connection_states(&self) -> Vec<(Endpoint, ConnectionState)> {
+        Vec::new()
+    }
+
+    /// Get a snapshot of the effective config the client is running with, and
+    /// it is the default config by default.
+    fn config(&self) -> RpcConfig {
+        RpcConfig::default()
+    }
+
+    /// Get the clock measuring the time of the client, see
+    /// [`Builder::clock`](crate::Builder::clock), and it is the system clock
+    /// by default.
+    fn clock(&self) -> Arc<dyn Clock> {
+        Arc::new(SystemClock)
+    }
+
+    /// Resolve where the `table` routes by a fresh route rpc, without reading
+    /// or updating the route cache.
+    ///
+    /// It is only supported in [`Mode::Direct`].
+    async fn resolve_route_uncached(
+        &self,
+        _ctx: &RpcContext,
+        _table: &str,
+    ) -> Result<Option<Endpoint>> {
+        Err(crate::Error::Client(
+            "resolving the uncached route is not supported in this mode".to_string(),
+        ))
+    }
+
+    /// Route the tables through the route cache like writes, and the routes
+    /// are fetched from remote and repopulated into the cache if
+    /// `force_refresh` is set.
+    ///
+    /// It is only supported in [`Mode::Direct`].
+    async fn route_tables(
+        &self,
+        _ctx: &RpcContext,
+        _tables: &[String],
+        _force_refresh: bool,
+    ) -> Result<HashMap<String, TableRoute>> {
+        Err(crate::Error::Client(
+            "routing tables is not supported in this mode".to_string(),
+        ))
+    }
+
+    /// Route the tables through the route cache like
+    /// [`route_tables`](DbClient::route_tables), and all the replicas of the
+    /// tables routed by the route service are returned with their roles, e.g.
+    /// to send the reads to the followers.
+    ///
+    /// The tables routed to the default endpoint or not routed are absent. It
+    /// is only supported in [`Mode::Direct`].
+    async fn route_replicas(
+        &self,
+        _ctx: &RpcContext,
+        _tables: &[String],
+    ) -> Result<HashMap<String, Route>> {
+        Err(crate::Error::Client(
+            "routing tables is not supported in this mode".to_string(),
+        ))
+    }
+
+    /// Pin the `table` to the `endpoint` in the `database`, or in all the
+    /// databases if it is none, like the
+    /// [`route_overrides`](crate::Builder::route_overrides), and the endpoint
+    /// it was pinned to is returned.
+    ///
+    /// It is only supported in [`Mode::Direct`].
+    fn add_route_override(
+        &self,
+        _database: Option<&str>,
+        _table: &str,
+        _endpoint: Endpoint,
+    ) -> Result<Option<Endpoint>> {
+        Err(crate::Error::Client(
+            "route overrides are not supported in this mode".to_string(),
+        ))
+    }
+
+    /// Unpin the `table` in the `database`, or in all the databases if it is
+    /// none, and the endpoint it was pinned to is returned.
+    ///
+    /// It is only supported in [`Mode::Direct`].
+    fn remove_route_override(
+        &self,
+        _database: Option<&str>,
+        _table: &str,
+    ) -> Result<Option<Endpoint>> {
+        Err(crate::Error::Client(
+            "route overrides are not supported in this mode".to_string(),
+        ))
+    }
+
+    /// Whether the `endpoint` is healthy judged by the outcomes of the recent
+    /// requests to it, and the unhealthy replicas of a table are avoided, see
+    /// [`RpcConfig::passive_health`].
+    ///
+    /// It is always true unless the passive health is tracked in
+    /// [`Mode::Direct`].
+    fn is_healthy(&self, _endpoint: &Endpoint) -> bool {
+        true
+    }
+
+    /// The endpoint that acknowledged the latest write of the `table` in the
+    /// database of the `ctx`, to which the queries on it are pinned, see
+    /// [`RpcConfig::read_your_writes`].
+    ///
+    /// It is none once the window passes, or unless the read-your-writes is
+    /// enabled in [`Mode::Direct`].
+    fn last_write_endpoint(&self, _ctx: &RpcContext, _table: &str) -> Option<Endpoint> {
+        None
+    }
+
+    /// The capabilities of the server at the `endpoint` probed on the latest
+    /// building of the channel to it, see
+    /// [`RpcConfig::negotiate_capabilities`].
+    ///
+    /// It is none if the negotiation is not enabled, no channel to the
+    /// `endpoint` is built or the probing failed.
+    fn server_capabilities(&self, _endpoint: &Endpoint) -> Option<ServerCapabilities> {
+        None
+    }
+
+    /// Evict the cached routes of all the tables routed to the `endpoint` in
+    /// all the databases, e.g. when the node is taken out of service, and the
+    /// number of the evicted routes is returned.
+    ///
+    /// The routes to an endpoint found unreachable by a request are evicted
+    /// automatically. It is only supported in [`Mode::Direct`].
+    fn evict_routes_by_endpoint(&self, _endpoint: &Endpoint) -> Result<usize> {
+        Err(crate::Error::Client(
+            "evicting routes is not supported in this mode".to_string(),
+        ))
+    }
+
+    /// Get the statistics about the hedged queries.
+    ///
+    /// Only the client in [`Mode::Direct`] with
+    /// [`sql_query_hedge_delay`](crate::RpcConfig::sql_query_hedge_delay) set
+    /// hedges the queries.
+    fn hedge_stats(&self) -> HedgeStats {
+        HedgeStats::default()
+    }
+
+    /// Get the statistics about the rebuildings of the channels to all the
+    /// known endpoints, see [`RpcConfig::max_channel_age`],
+    /// [`RpcConfig::idle_timeout`] and [`RpcConfig::refresh_dns_on_failure`].
+    fn channel_stats(&self) -> Vec<(Endpoint, ChannelStats)> {
+        Vec::new()
+    }
+
+    /// Get the states of the circuit breakers of all the known endpoints.
+    ///
+    /// It is empty unless the
+    /// [`circuit_breaker`](crate::RpcConfig::circuit_breaker) is set.
+    fn circuit_breaker_states(&self) -> Vec<(Endpoint, BreakerState)> {
+        Vec::new()
+    }
+
+    /// Get the statistics about the cached sql query responses.
+    ///
+    /// Only the client built with the
+    /// [`query_cache`](crate::Builder::query_cache) caches the responses.
+    fn cache_stats(&self) -> QueryCacheStats {
+        QueryCacheStats::default()
+    }
+
+    /// Get the statistics of the writes per table, sorted by the points
+    /// written in descending order.
+    ///
+    /// Only the client built with the
+    /// [`write_stats`](crate::Builder::write_stats) tracks the writes, and the
+    /// writes are attributed to the tables by the requests sent to every
+    /// endpoint, so a table in a failed partition of the write is counted as
+    /// failed while the others in the same write succeed.
+    fn write_stats(&self) -> Vec<TableWriteStats> {
+        Vec::new()
+    }
+
+    /// Clear the statistics of the writes per table.
+    fn reset_write_stats(&self) {}
+
+    /// Get the statistics about the writes merged by the group commit.
+    ///
+    /// Only the client in [`Mode::Direct`] built with the
+    /// [`group_commit`](crate::Builder::group_commit) merges the writes.
+    fn group_commit_stats(&self) -> GroupCommitStats {
+        GroupCommitStats::default()
+    }
+
+    /// Create the table by its definition, and the affected rows are returned.
+    async fn create_table(&self, ctx: &RpcContext, def: &TableDefinition) -> Result<u64> {
+        let req = SqlQueryRequest::new(vec![def.table().to_string()], def.create_table_sql());
+        let resp = self.sql_query(ctx, &req).await?;
+
+        Ok(resp.affected_rows().unwrap_or_default())
+    }
+
+    /// Drop the `table`, and don't fail if it doesn't exist and `if_exists` is
+    /// set.
+    async fn drop_table(&self, ctx: &RpcContext, table: &str, if_exists: bool) -> Result<u64> {
+        let req = SqlQueryRequest::new(vec![table.to_string()], drop_table_sql(table, if_exists)?);
+        let resp = self.sql_query(ctx, &req).await?;
+
+        Ok(resp.affected_rows().unwrap_or_default())
+    }
+
+    /// Read the schema of the `table` by `DESCRIBE`, e.g. to check the points
+    /// against the columns and their types before writing them.
+    async fn describe_table(&self, ctx: &RpcContext, table: &str) -> Result<TableSchema> {
+        let req = describe_table_request(table)?;
+        let resp = self.sql_query(ctx, &req).await?;
+
+        TableSchema::from_describe_response(table, &resp)
+    }
+
+    /// Explain the query of the `req` by `EXPLAIN` without running it, e.g. to
+    /// debug the slow queries, and the plan is parsed into the [`QueryPlan`].
+    async fn explain(&self, ctx: &RpcContext, req: &SqlQueryRequest) -> Result<QueryPlan> {
+        let resp = self.sql_query(ctx, &explain_request(req, false)).await?;
+
+        QueryPlan::from_explain_response(&resp)
+    }
+
+    /// Run the query of the `req` by `EXPLAIN ANALYZE`, and the plan carries
+    /// the actual rows and the elapsed time of the operators if the server
+    /// reports them.
+    async fn explain_analyze(&self, ctx: &RpcContext, req: &SqlQueryRequest) -> Result<QueryPlan> {
+        let resp = self.sql_query(ctx, &explain_request(req, true)).await?;
+
+        QueryPlan::from_explain_response(&resp)
+    }
+
+    /// Check whether the database of the `ctx` exists by `SHOW DATABASES`, e.g.
+    /// to fail fast on a misspelled default database at startup instead of
+    /// failing every request.
+    ///
+    /// The built clients check their default database if none is set in the
+    /// `ctx`, while the default implementation has no default database to
+    /// fall back to, and fails with [`Error::NoDatabase`] instead.
+    ///
+    /// [`Error::NoDatabase`]: crate::Error::NoDatabase
+    async fn check_database(&self, ctx: &RpcContext) -> Result<bool> {
+        let database = ctx.database.as_deref().ok_or(crate::Error::NoDatabase)?;
+        let resp = self.sql_query(ctx, &show_databases_request()).await?;
+
+        Ok(is_database_listed(&resp, database))
+    }
+}
+
+/// Request listing the databases, which has no tables to route.
+pub(crate) fn show_databases_request() -> SqlQueryRequest {
+    SqlQueryRequest::new(Vec::new(), "SHOW DATABASES".to_string())
+}
+
+/// Whether the `database` is listed in the response of the
+/// [`show_databases_request`].
+pub(crate) fn is_database_listed(resp: &SqlQueryResponse, database: &str) -> bool {
+    resp.rows()
+        .iter()
+        .flat_map(Row::columns)
+        .any(|column| matches!(column.value(), Value::String(name) if name == database))
 }
 
 pub(crate) fn resolve_database(
@@ -38,3 +497,411 @@ pub(crate) fn resolve_database(
         (None, None) => Err(crate::Error::NoDatabase),
     }
 }
+
+/// Fetch the pages of `req` one by one with `query_page`, and the stream
+/// fails with [`Error::Cancelled`](crate::Error::Cancelled) before the next
+/// page once the `cancel` token is cancelled.
+pub(crate) fn paged_sql_query<'a, Q, Fut>(
+    req: &SqlQueryRequest,
+    page_size: usize,
+    cancel: Option<CancellationToken>,
+    query_page: Q,
+) -> BoxStream<'a, Result<SqlQueryResponse>>
+where
+    Q: Fn(SqlQueryRequest) -> Fut + Send + 'a,
+    Fut: Future<Output = Result<SqlQueryResponse>> + Send + 'a,
+{
+    let sql = match req.pageable_sql(page_size) {
+        Ok(sql) => sql,
+        Err(e) => return stream::once(async { Err(e) }).boxed(),
+    };
+    // The pages are never cached.
+    let page_req = SqlQueryRequest {
+        cache_ttl: None,
+        ..req.clone()
+    };
+
+    // The state is the offset of the next page, and none means no more pages.
+    stream::unfold(Some(0), move |next_offset| {
+        let cancelled = matches!(&cancel, Some(token) if token.is_cancelled());
+        let page = next_offset.filter(|_| !cancelled).map(|offset| {
+            query_page(SqlQueryRequest {
+                sql: format!("{sql} LIMIT {page_size} OFFSET {offset}"),
+                ..page_req.clone()
+            })
+        });
+
+        async move {
+            if cancelled {
+                return next_offset.map(|_| (Err(crate::Error::Cancelled), None));
+            }
+
+            let (offset, page) = next_offset.zip(page)?;
+            match page.await {
+                Ok(resp) => {
+                    let row_count = resp.rows().len();
+                    // Empty page after the first one means the last page is full.
+                    if row_count == 0 && offset > 0 {
+                        return None;
+                    }
+
+                    let next_offset = (row_count >= page_size).then_some(offset + page_size);
+                    Some((Ok(resp), next_offset))
+                }
+                Err(e) => Some((Err(e), None)),
+            }
+        }
+    })
+    .boxed()
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc, Mutex,
+        },
+        time::Duration,
+    };
+
+    use futures::{future, stream, StreamExt};
+    use tokio_util::sync::CancellationToken;
+
+    use super::{paged_sql_query, DbClient, MockDbClient};
+    use crate::{
+        clock::ManualClock,
+        model::{
+            sql_query::{
+                row::RowBuilder, Output as SqlQueryOutput, Request as SqlQueryRequest,
+                Response as SqlQueryResponse,
+            },
+            value::Value,
+            write::{Request as WriteRequest, Response as WriteResponse, RetriedPartition},
+        },
+        rpc_client::RpcContext,
+        Error, RpcConfig,
+    };
+
+    /// Serve the pages of a table with `total` rows, and return the row counts
+    /// of the pages and the executed sqls.
+    async fn query_pages(sql: &str, total: usize, page_size: usize) -> (Vec<usize>, Vec<String>) {
+        let executed = Mutex::new(Vec::new());
+        let req = SqlQueryRequest::new(vec!["test_table".to_string()], sql.to_string());
+        let pages = paged_sql_query(&req, page_size, None, |page_req| {
+            executed.lock().unwrap().push(page_req.sql.clone());
+            let words: Vec<_> = page_req.sql.split_whitespace().collect();
+            let limit: usize = words[words.len() - 3].parse().unwrap();
+            let offset: usize = words[words.len() - 1].parse().unwrap();
+            let row_values = (offset..total.min(offset + limit))
+                .map(|v| vec![Value::UInt64(v as u64)])
+                .collect();
+            let rows = RowBuilder {
+                col_idx_to_name: vec!["value".to_string()],
+                row_values,
+            }
+            .build();
+
+            async move {
+                Ok(SqlQueryResponse {
+                    output: SqlQueryOutput::ResultSet { rows, schema: None },
+                    ..Default::default()
+                })
+            }
+        })
+        .map(|page| page.unwrap().rows().len())
+        .collect()
+        .await;
+
+        (pages, executed.into_inner().unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_paged_sql_query() {
+        let (pages, executed) = query_pages("SELECT * FROM test_table", 5, 2).await;
+        assert_eq!(pages, vec![2, 2, 1]);
+        assert_eq!(
+            executed,
+            vec![
+                "SELECT * FROM test_table LIMIT 2 OFFSET 0",
+                "SELECT * FROM test_table LIMIT 2 OFFSET 2",
+                "SELECT * FROM test_table LIMIT 2 OFFSET 4",
+            ]
+        );
+
+        // The last page is full.
+        let (pages, executed) = query_pages("SELECT * FROM test_table ; ;\n", 4, 2).await;
+        assert_eq!(pages, vec![2, 2]);
+        assert_eq!(executed.len(), 3);
+        assert_eq!(executed[0], "SELECT * FROM test_table LIMIT 2 OFFSET 0");
+
+        // The first page is empty.
+        let (pages, executed) = query_pages("SELECT * FROM test_table", 0, 2).await;
+        assert_eq!(pages, vec![0]);
+        assert_eq!(executed.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_paged_sql_query_with_limit() {
+        let req = SqlQueryRequest::new(
+            vec!["test_table".to_string()],
+            "SELECT * FROM test_table limit 10".to_string(),
+        );
+        // The invalid sql is rejected before any page is queried.
+        let pages: Vec<_> = paged_sql_query(&req, 2, None, |_| {
+            future::ready(Ok(SqlQueryResponse::default()))
+        })
+        .collect()
+        .await;
+        assert_eq!(pages.len(), 1);
+        assert!(matches!(pages[0], Err(Error::Client(_))));
+    }
+
+    #[tokio::test]
+    async fn test_paged_sql_query_cancelled() {
+        let req = SqlQueryRequest::new(
+            vec!["test_table".to_string()],
+            "SELECT * FROM test_table".to_string(),
+        );
+        let token = CancellationToken::new();
+        let queried = AtomicUsize::new(0);
+        let mut pages = paged_sql_query(&req, 2, Some(token.clone()), |_| {
+            queried.fetch_add(1, Ordering::Relaxed);
+            let rows = RowBuilder {
+                col_idx_to_name: vec!["value".to_string()],
+                row_values: vec![vec![Value::UInt64(0)]; 2],
+            }
+            .build();
+            future::ready(Ok(SqlQueryResponse {
+                output: SqlQueryOutput::ResultSet { rows, schema: None },
+                ..Default::default()
+            }))
+        });
+
+        assert!(pages.next().await.unwrap().is_ok());
+        token.cancel();
+        assert!(matches!(pages.next().await, Some(Err(Error::Cancelled))));
+        assert!(pages.next().await.is_none());
+        drop(pages);
+        assert_eq!(queried.into_inner(), 1);
+    }
+
+    /// Client answering the query `SELECT n` with `n` affected rows after
+    /// yielding a few times, and recording the max number of the queries in
+    /// flight.
+    fn in_flight_client(max_in_flight: Arc<AtomicUsize>) -> MockDbClient {
+        let in_flight = Arc::new(AtomicUsize::new(0));
+        MockDbClient::default()
+            .with_config(RpcConfig {
+                sql_query_batch_concurrency: 2,
+                ..Default::default()
+            })
+            .on_sql_query(move |_ctx, req| {
+                let in_flight = in_flight.clone();
+                let max_in_flight = max_in_flight.clone();
+                async move {
+                    let current = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                    max_in_flight.fetch_max(current, Ordering::SeqCst);
+                    for _ in 0..3 {
+                        tokio::task::yield_now().await;
+                    }
+                    in_flight.fetch_sub(1, Ordering::SeqCst);
+
+                    match req.sql.trim_start_matches("SELECT ").parse() {
+                        Ok(affected_rows) => Ok(SqlQueryResponse {
+                            output: SqlQueryOutput::AffectedRows(affected_rows),
+                            ..Default::default()
+                        }),
+                        Err(_) => Err(Error::Client(format!("invalid sql:{}", req.sql))),
+                    }
+                }
+            })
+    }
+
+    #[tokio::test]
+    async fn test_sql_query_batch() {
+        let max_in_flight = Arc::new(AtomicUsize::new(0));
+        let client = in_flight_client(max_in_flight.clone());
+        let reqs: Vec<_> = ["SELECT 1", "SELECT 2", "invalid", "SELECT 4", "SELECT 5"]
+            .into_iter()
+            .map(|sql| SqlQueryRequest::new(vec!["test_table".to_string()], sql.to_string()))
+            .collect();
+
+        let results = client.sql_query_batch(&RpcContext::default(), &reqs).await;
+        let affected_rows: Vec<_> = results
+            .iter()
+            .map(|result| result.as_ref().ok().and_then(|resp| resp.affected_rows()))
+            .collect();
+        // The failed query doesn't affect the others.
+        assert_eq!(
+            affected_rows,
+            vec![Some(1), Some(2), None, Some(4), Some(5)]
+        );
+        assert_eq!(max_in_flight.load(Ordering::SeqCst), 2);
+
+        assert!(client
+            .sql_query_batch(&RpcContext::default(), &[])
+            .await
+            .is_empty());
+    }
+
+    type Executed = Arc<Mutex<Vec<(Vec<String>, String)>>>;
+
+    /// Client running the scripted statements, which fail if they start with
+    /// `FAIL`, and recording the executed ones.
+    fn scripted_client(executed: Executed) -> MockDbClient {
+        MockDbClient::default().on_sql_query(move |_ctx, req| {
+            executed
+                .lock()
+                .unwrap()
+                .push((req.tables.clone(), req.sql.clone()));
+            let output = match req.sql.split_whitespace().next() {
+                Some("FAIL") => Err(Error::Client(format!("failed sql:{}", req.sql))),
+                Some("INSERT") => Ok(SqlQueryOutput::AffectedRows(2)),
+                _ => Ok(SqlQueryOutput::default()),
+            };
+
+            future::ready(output.map(|output| SqlQueryResponse {
+                output,
+                ..Default::default()
+            }))
+        })
+    }
+
+    #[tokio::test]
+    async fn test_sql_query_multi() {
+        let sql = "CREATE TABLE t (ts timestamp NOT NULL, TIMESTAMP KEY(ts));\n\
+                   INSERT INTO t (ts) VALUES (1), (2);\n\
+                   FAIL 'the third; one';\n\
+                   SELECT * FROM t;";
+        let tables = vec!["t".to_string()];
+        let ctx = RpcContext::default();
+
+        let executed = Executed::default();
+        let client = scripted_client(executed.clone());
+        let req = SqlQueryRequest::multi(sql)
+            .unwrap()
+            .with_tables(tables.clone());
+        let results = client.sql_query_multi(&ctx, &req).await;
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[0].result.as_ref().unwrap().affected_rows(), None);
+        assert_eq!(results[1].result.as_ref().unwrap().affected_rows(), Some(2));
+        assert_eq!(results[2].sql, "FAIL 'the third; one'");
+        assert!(matches!(results[2].result, Err(Error::Client(_))));
+        let executed = executed.lock().unwrap().clone();
+        assert_eq!(
+            executed,
+            req.statements[..3]
+                .iter()
+                .map(|sql| (tables.clone(), sql.clone()))
+                .collect::<Vec<_>>()
+        );
+
+        // Continue after the failed statement.
+        let executed = Executed::default();
+        let client = scripted_client(executed.clone());
+        let req = req.stop_on_error(false);
+        let results = client.sql_query_multi(&ctx, &req).await;
+        let statuses: Vec<_> = results.iter().map(|result| result.result.is_ok()).collect();
+        assert_eq!(statuses, vec![true, true, false, true]);
+        let executed: Vec<_> = executed
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(_, sql)| sql.clone())
+            .collect();
+        assert_eq!(executed, req.statements);
+    }
+
+    #[tokio::test]
+    async fn test_write_stream() {
+        let client = MockDbClient::default().on_write(|_ctx, _req| {
+            let mut resp = WriteResponse::new(2, 1);
+            resp.execution_info.request_bytes = 100;
+            resp.execution_info.latency = Duration::from_millis(10);
+            resp.retried_partitions = vec![RetriedPartition {
+                endpoint: "127.0.0.1:8831".to_string(),
+                tables: vec!["t".to_string()],
+                attempts: 2,
+            }];
+            future::ok(resp)
+        });
+
+        let reqs = stream::iter([WriteRequest::default(), WriteRequest::default()]).boxed();
+        let resp = client
+            .write_stream(&RpcContext::default(), reqs)
+            .await
+            .unwrap();
+        assert_eq!((resp.success, resp.failed), (4, 2));
+        assert_eq!(resp.retried_partitions.len(), 2);
+        let info = &resp.execution_info;
+        assert_eq!(info.request_bytes, 200);
+        assert_eq!(info.latency, Duration::from_millis(20));
+        assert_eq!(info.partitions.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_sql_query_multi_elapsed() {
+        let clock = ManualClock::new();
+        let client = {
+            let clock = clock.clone();
+            MockDbClient::default()
+                .with_clock(Arc::new(clock.clone()))
+                .on_sql_query(move |_ctx, _req| {
+                    clock.advance(Duration::from_millis(10));
+                    future::ok(SqlQueryResponse::default())
+                })
+        };
+
+        let req = SqlQueryRequest::multi("SELECT 1; SELECT 2;").unwrap();
+        let results = client.sql_query_multi(&RpcContext::default(), &req).await;
+        let elapsed: Vec<_> = results.iter().map(|result| result.elapsed).collect();
+        assert_eq!(elapsed, vec![Duration::from_millis(10); 2]);
+    }
+
+    /// Client listing the `databases` for `SHOW DATABASES`.
+    fn databases_client(databases: Vec<&'static str>) -> MockDbClient {
+        MockDbClient::default().on_sql_query(move |_ctx, req| {
+            assert_eq!(req.sql, "SHOW DATABASES");
+            let rows = RowBuilder {
+                col_idx_to_name: vec!["Databases".to_string()],
+                row_values: databases
+                    .iter()
+                    .map(|database| vec![Value::String(database.to_string())])
+                    .collect(),
+            }
+            .build();
+
+            future::ok(SqlQueryResponse {
+                output: SqlQueryOutput::ResultSet { rows, schema: None },
+                ..Default::default()
+            })
+        })
+    }
+
+    #[tokio::test]
+    async fn test_check_database() {
+        let client: Arc<dyn DbClient> = Arc::new(databases_client(vec!["public", "metrics"]));
+        let ctx = RpcContext::default();
+        assert!(client
+            .check_database(&ctx.clone().database("metrics".to_string()))
+            .await
+            .unwrap());
+        // The names are matched exactly.
+        assert!(!client
+            .check_database(&ctx.clone().database("metric".to_string()))
+            .await
+            .unwrap());
+        assert!(matches!(
+            client.check_database(&ctx).await,
+            Err(Error::NoDatabase)
+        ));
+
+        // The scoped client checks the pinned database.
+        assert!(client
+            .with_database("public")
+            .check_database(&ctx)
+            .await
+            .unwrap());
+    }
+}
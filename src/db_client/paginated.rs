@@ -0,0 +1,236 @@
+// Copyright 2022 CeresDB Project Authors. Licensed under Apache-2.0.
+
+//! Query paginated by `LIMIT` and `OFFSET`
+
+use futures::{
+    stream::{self, BoxStream},
+    StreamExt,
+};
+
+use crate::{
+    db_client::DbClient,
+    model::sql_query::{Request as SqlQueryRequest, Response as SqlQueryResponse},
+    rpc_client::RpcContext,
+    Result,
+};
+
+/// Query fetched page by page with the successive
+/// [`sql_query`](DbClient::sql_query)s, by appending `LIMIT` and the
+/// increasing `OFFSET` to the base sql, e.g. for the UIs paging through a
+/// table.
+///
+/// Unlike [`DbClient::sql_query_paged`], every page is an independent query,
+/// which is routed and cached by the [`cache_ttl`](SqlQueryRequest::cache_ttl)
+/// on its own, and the pages can be fetched one at a time by
+/// [`next_page`](Self::next_page). It is done after a page with fewer rows
+/// than the page size, and only the first page may be empty, so no empty page
+/// is returned if the number of the rows is a multiple of the page size.
+#[derive(Debug, Clone)]
+pub struct PaginatedQuery {
+    req: SqlQueryRequest,
+    sql: String,
+    page_size: usize,
+    /// The offset of the next page, and none means no more pages.
+    next_offset: Option<usize>,
+}
+
+impl PaginatedQuery {
+    /// Page the query `req` by `page_size` rows, and it fails if the page size
+    /// is zero or the sql already contains `LIMIT` or `OFFSET`.
+    pub fn new(req: SqlQueryRequest, page_size: usize) -> Result<Self> {
+        let sql = req.pageable_sql(page_size)?;
+        Ok(Self {
+            req,
+            sql,
+            page_size,
+            next_offset: Some(0),
+        })
+    }
+
+    pub fn page_size(&self) -> usize {
+        self.page_size
+    }
+
+    /// Get the offset of the next page, and none if all the pages are
+    /// fetched.
+    pub fn next_offset(&self) -> Option<usize> {
+        self.next_offset
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.next_offset.is_none()
+    }
+
+    /// Fetch the next page by the `client`, and none if all the pages are
+    /// fetched.
+    ///
+    /// The failed page is not skipped, so it is fetched again on the next
+    /// call.
+    pub async fn next_page(
+        &mut self,
+        client: &dyn DbClient,
+        ctx: &RpcContext,
+    ) -> Option<Result<SqlQueryResponse>> {
+        let offset = self.next_offset?;
+        let page_req = SqlQueryRequest {
+            sql: format!("{} LIMIT {} OFFSET {offset}", self.sql, self.page_size),
+            ..self.req.clone()
+        };
+        let resp = match client.sql_query(ctx, &page_req).await {
+            Ok(resp) => resp,
+            Err(e) => return Some(Err(e)),
+        };
+
+        let row_count = resp.rows().len();
+        // Empty page after the first one means the last page is full.
+        if row_count == 0 && offset > 0 {
+            self.next_offset = None;
+            return None;
+        }
+
+        self.next_offset = (row_count >= self.page_size).then_some(offset + self.page_size);
+        Some(Ok(resp))
+    }
+
+    /// Fetch all the remaining pages by the `client` as a stream, which ends
+    /// after the first failed page.
+    pub fn into_stream<'a>(
+        self,
+        client: &'a dyn DbClient,
+        ctx: &'a RpcContext,
+    ) -> BoxStream<'a, Result<SqlQueryResponse>> {
+        // The state is none after a failed page.
+        stream::unfold(Some(self), move |query| async move {
+            let mut query = query?;
+            match query.next_page(client, ctx).await? {
+                Ok(resp) => Some((Ok(resp), Some(query))),
+                Err(e) => Some((Err(e), None)),
+            }
+        })
+        .boxed()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    };
+
+    use futures::{future, StreamExt};
+
+    use super::PaginatedQuery;
+    use crate::{
+        db_client::MockDbClient,
+        model::{
+            sql_query::{
+                row::RowBuilder, Output as SqlQueryOutput, Request as SqlQueryRequest,
+                Response as SqlQueryResponse,
+            },
+            value::Value,
+        },
+        rpc_client::RpcContext,
+        Error, Result,
+    };
+
+    /// Queries of the table client, and the next one fails if `fail_next` is
+    /// set.
+    #[derive(Default)]
+    struct Queries {
+        fail_next: AtomicBool,
+        executed: Mutex<Vec<String>>,
+    }
+
+    /// Client serving the pages of a table with `total` rows.
+    fn table_client(total: usize, queries: Arc<Queries>) -> MockDbClient {
+        MockDbClient::default().on_sql_query(move |_ctx, req| {
+            queries.executed.lock().unwrap().push(req.sql.clone());
+            if queries.fail_next.swap(false, Ordering::Relaxed) {
+                return future::err(Error::Client("injected failure".to_string()));
+            }
+
+            let words: Vec<_> = req.sql.split_whitespace().collect();
+            let limit: usize = words[words.len() - 3].parse().unwrap();
+            let offset: usize = words[words.len() - 1].parse().unwrap();
+            let row_values = (offset..total.min(offset + limit))
+                .map(|v| vec![Value::UInt64(v as u64)])
+                .collect();
+            let rows = RowBuilder {
+                col_idx_to_name: vec!["value".to_string()],
+                row_values,
+            }
+            .build();
+
+            future::ok(SqlQueryResponse {
+                output: SqlQueryOutput::ResultSet { rows, schema: None },
+                ..Default::default()
+            })
+        })
+    }
+
+    fn query(sql: &str, page_size: usize) -> Result<PaginatedQuery> {
+        let req = SqlQueryRequest::new(vec!["test_table".to_string()], sql.to_string());
+        PaginatedQuery::new(req, page_size)
+    }
+
+    async fn page_sizes(total: usize, page_size: usize) -> (Vec<usize>, usize) {
+        let queries = Arc::new(Queries::default());
+        let client = table_client(total, queries.clone());
+        let ctx = RpcContext::default();
+        let pages: Vec<_> = query("SELECT * FROM test_table;", page_size)
+            .unwrap()
+            .into_stream(&client, &ctx)
+            .map(|page| page.unwrap().rows().len())
+            .collect()
+            .await;
+        let executed = queries.executed.lock().unwrap().len();
+
+        (pages, executed)
+    }
+
+    #[tokio::test]
+    async fn test_paginated_query() {
+        // Stop after the short page.
+        assert_eq!(page_sizes(5, 2).await, (vec![2, 2, 1], 3));
+        // The empty page after the full last page is not returned.
+        assert_eq!(page_sizes(4, 2).await, (vec![2, 2], 3));
+        assert_eq!(page_sizes(0, 2).await, (vec![0], 1));
+
+        assert!(matches!(query("SELECT * FROM t", 0), Err(Error::Client(_))));
+        assert!(matches!(
+            query("SELECT * FROM t LIMIT 10", 2),
+            Err(Error::Client(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_paginated_query_next_page() {
+        let queries = Arc::new(Queries::default());
+        let client = table_client(3, queries.clone());
+        let ctx = RpcContext::default();
+        let mut query = query("SELECT * FROM test_table", 2).unwrap();
+
+        let page = query.next_page(&client, &ctx).await.unwrap().unwrap();
+        assert_eq!(page.rows().len(), 2);
+        assert_eq!(query.next_offset(), Some(2));
+
+        // The failed page is fetched again.
+        queries.fail_next.store(true, Ordering::Relaxed);
+        assert!(query.next_page(&client, &ctx).await.unwrap().is_err());
+        assert_eq!(query.next_offset(), Some(2));
+        let page = query.next_page(&client, &ctx).await.unwrap().unwrap();
+        assert_eq!(page.rows().len(), 1);
+
+        assert!(query.is_done());
+        assert!(query.next_page(&client, &ctx).await.is_none());
+        assert_eq!(
+            queries.executed.lock().unwrap().as_slice(),
+            [
+                "SELECT * FROM test_table LIMIT 2 OFFSET 0",
+                "SELECT * FROM test_table LIMIT 2 OFFSET 2",
+                "SELECT * FROM test_table LIMIT 2 OFFSET 2",
+            ]
+        );
+    }
+}
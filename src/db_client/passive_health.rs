@@ -0,0 +1,127 @@
+// Copyright 2022 CeresDB Project Authors. Licensed under Apache-2.0.
+
+//! Health of the endpoints tracked by the outcomes of the requests
+
+use std::collections::VecDeque;
+
+use dashmap::DashMap;
+
+use crate::{config::PassiveHealthConfig, model::route::Endpoint};
+
+#[derive(Debug, Default)]
+struct EndpointHealth {
+    // The latest outcomes in the window, true for the successes.
+    outcomes: VecDeque<bool>,
+    failures: usize,
+    unhealthy: bool,
+    consecutive_successes: usize,
+}
+
+/// Health of the endpoints judged by the failure rates of the latest requests
+/// to them, and the endpoints never requested are regarded healthy.
+///
+/// The unhealthy endpoint is healthy again after the consecutive successes,
+/// which come from the requests still sent to it, e.g. when all the replicas
+/// of a table are unhealthy.
+#[derive(Debug)]
+pub(crate) struct PassiveHealth {
+    config: PassiveHealthConfig,
+    endpoints: DashMap<String, EndpointHealth>,
+}
+
+impl PassiveHealth {
+    pub fn new(config: PassiveHealthConfig) -> Self {
+        Self {
+            config,
+            endpoints: DashMap::new(),
+        }
+    }
+
+    pub fn is_healthy(&self, endpoint: &Endpoint) -> bool {
+        !matches!(self.endpoints.get(&endpoint.to_string()), Some(health) if health.unhealthy)
+    }
+
+    /// Record the outcome of a request to the `endpoint` in the form of
+    /// `addr:port`.
+    pub fn record(&self, endpoint: &str, success: bool) {
+        let mut health = self.endpoints.entry(endpoint.to_string()).or_default();
+        if health.unhealthy {
+            if !success {
+                health.consecutive_successes = 0;
+                return;
+            }
+            health.consecutive_successes += 1;
+            if health.consecutive_successes >= self.config.recovery_successes {
+                *health = EndpointHealth::default();
+            }
+            return;
+        }
+
+        health.outcomes.push_back(success);
+        if !success {
+            health.failures += 1;
+        }
+        while health.outcomes.len() > self.config.window.max(1) {
+            if health.outcomes.pop_front() == Some(false) {
+                health.failures -= 1;
+            }
+        }
+
+        let requests = health.outcomes.len();
+        if requests >= self.config.min_requests
+            && health.failures as f64 >= self.config.failure_rate_threshold * requests as f64
+        {
+            health.unhealthy = true;
+            health.consecutive_successes = 0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::PassiveHealth;
+    use crate::{config::PassiveHealthConfig, model::route::Endpoint};
+
+    #[test]
+    fn test_passive_health() {
+        let health = PassiveHealth::new(PassiveHealthConfig {
+            window: 4,
+            failure_rate_threshold: 0.5,
+            min_requests: 3,
+            recovery_successes: 2,
+        });
+        let endpoint = Endpoint::new("192.168.0.1".to_string(), 11);
+        let addr = endpoint.to_string();
+        assert!(health.is_healthy(&endpoint));
+
+        // Too few requests to judge.
+        health.record(&addr, false);
+        health.record(&addr, true);
+        assert!(health.is_healthy(&endpoint));
+
+        // The old failure rolls out of the window.
+        for _ in 0..4 {
+            health.record(&addr, true);
+        }
+        health.record(&addr, false);
+        assert!(health.is_healthy(&endpoint));
+        health.record(&addr, false);
+        assert!(!health.is_healthy(&endpoint));
+
+        // A failure restarts the recovery.
+        health.record(&addr, true);
+        health.record(&addr, false);
+        health.record(&addr, true);
+        assert!(!health.is_healthy(&endpoint));
+        health.record(&addr, true);
+        assert!(health.is_healthy(&endpoint));
+
+        // The window is reset after the recovery.
+        health.record(&addr, false);
+        health.record(&addr, true);
+        assert!(health.is_healthy(&endpoint));
+
+        let other = Endpoint::new("192.168.0.2".to_string(), 12);
+        assert!(health.is_healthy(&other));
+    }
+}
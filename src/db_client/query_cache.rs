@@ -0,0 +1,550 @@
+// Copyright 2022 CeresDB Project Authors. Licensed under Apache-2.0.
+
+//! Cache of the sql query responses
+
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, BTreeSet, HashMap},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, PoisonError,
+    },
+    time::{Duration, Instant},
+};
+
+use futures::{stream::BoxStream, StreamExt};
+
+use crate::{
+    clock::{Clock, SystemClock},
+    config::QueryCacheConfig,
+    db_client::DbClient,
+    model::{
+        execution_info::ExecutionInfo,
+        sql_query::{
+            request::top_level_tokens, Request as SqlQueryRequest, Response as SqlQueryResponse,
+        },
+        write::{Request as WriteRequest, Response as WriteResponse},
+    },
+    rpc_client::RpcContext,
+    Result,
+};
+
+/// Statistics about the cache of the sql query responses.
+///
+/// Only the queries marked cacheable are counted.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct QueryCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+/// Key of the query cache: (database, normalized sql, projected columns).
+///
+/// The projected columns are part of the key because the other columns are
+/// skipped when decoding, even if the sql isn't rewritten to select only them.
+type CacheKey = (String, String, Option<Vec<String>>);
+
+#[derive(Debug)]
+struct CacheEntry {
+    resp: SqlQueryResponse,
+    tables: Vec<String>,
+    expire_at: Instant,
+    bytes: usize,
+    /// Position in the lru order.
+    seq: u64,
+}
+
+#[derive(Debug, Default)]
+struct CacheState {
+    entries: HashMap<CacheKey, CacheEntry>,
+    /// The keys ordered from the least recently used.
+    lru: BTreeMap<u64, CacheKey>,
+    total_bytes: usize,
+    next_seq: u64,
+}
+
+impl CacheState {
+    fn remove(&mut self, key: &CacheKey) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.lru.remove(&entry.seq);
+            self.total_bytes -= entry.bytes;
+        }
+    }
+
+    fn next_seq(&mut self) -> u64 {
+        self.next_seq += 1;
+        self.next_seq
+    }
+}
+
+/// Cache of the sql query responses, and the least recently used ones are
+/// evicted when the limits are exceeded.
+#[derive(Debug)]
+pub(crate) struct QueryCache {
+    config: QueryCacheConfig,
+    state: Mutex<CacheState>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl QueryCache {
+    pub fn new(config: QueryCacheConfig) -> Self {
+        Self {
+            config,
+            state: Mutex::new(CacheState::default()),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    pub fn stats(&self) -> QueryCacheStats {
+        QueryCacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Get the cached response, and the expired one is removed.
+    ///
+    /// The execution info of the cached response is empty because no rpc is
+    /// sent.
+    fn get(&self, key: &CacheKey, now: Instant) -> Option<SqlQueryResponse> {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        let expired = match state.entries.get(key) {
+            Some(entry) => entry.expire_at <= now,
+            None => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                return None;
+            }
+        };
+        if expired {
+            state.remove(key);
+            self.misses.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+
+        let seq = state.next_seq();
+        let CacheState { entries, lru, .. } = &mut *state;
+        let entry = entries.get_mut(key)?;
+        lru.remove(&entry.seq);
+        lru.insert(seq, key.clone());
+        entry.seq = seq;
+        self.hits.fetch_add(1, Ordering::Relaxed);
+
+        Some(SqlQueryResponse {
+            execution_info: ExecutionInfo::default(),
+            ..entry.resp.clone()
+        })
+    }
+
+    /// Cache the response until `ttl` after `now`, and its size is measured by
+    /// the encoded response.
+    fn insert(
+        &self,
+        key: CacheKey,
+        resp: SqlQueryResponse,
+        tables: Vec<String>,
+        now: Instant,
+        ttl: Duration,
+    ) {
+        let bytes = resp.execution_info.response_bytes;
+        if self.config.max_entries == 0 || bytes > self.config.max_bytes {
+            return;
+        }
+
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        state.remove(&key);
+        let seq = state.next_seq();
+        state.lru.insert(seq, key.clone());
+        state.total_bytes += bytes;
+        state.entries.insert(
+            key,
+            CacheEntry {
+                resp,
+                tables,
+                expire_at: now + ttl,
+                bytes,
+                seq,
+            },
+        );
+
+        while state.entries.len() > self.config.max_entries
+            || state.total_bytes > self.config.max_bytes
+        {
+            match state.lru.pop_first() {
+                Some((_, lru_key)) => state.remove(&lru_key),
+                None => break,
+            }
+        }
+    }
+
+    /// Invalidate the cached responses of the queries involving the `tables`.
+    fn invalidate<'a>(&self, database: &str, tables: impl Iterator<Item = &'a String>) {
+        let tables: Vec<_> = tables.collect();
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        let invalidated: Vec<_> = state
+            .entries
+            .iter()
+            .filter(|((db, ..), entry)| {
+                db == database && entry.tables.iter().any(|t| tables.contains(&t))
+            })
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in invalidated {
+            state.remove(&key);
+        }
+    }
+}
+
+/// Normalize the sql by collapsing the whitespaces and comments between the
+/// top level tokens and removing the trailing semicolons, so that the same
+/// query formatted differently hits the cache.
+///
+/// The quoted text and the text in the parentheses are kept as is, and the sql
+/// with unbalanced quotes, parentheses or comments is not normalized at all.
+fn normalize_sql(sql: &str) -> String {
+    let mut tokens = match top_level_tokens(sql) {
+        Some(tokens) => tokens,
+        None => return sql.to_string(),
+    };
+    while matches!(tokens.last(), Some(token) if &sql[token.clone()] == ";") {
+        tokens.pop();
+    }
+
+    let mut normalized = String::with_capacity(sql.len());
+    let mut prev_end = None;
+    for token in tokens {
+        if matches!(prev_end, Some(end) if end < token.start) {
+            normalized.push(' ');
+        }
+        normalized.push_str(&sql[token.clone()]);
+        prev_end = Some(token.end);
+    }
+    normalized
+}
+
+/// Client caching the responses of the queries marked cacheable by
+/// [`cache_ttl`](SqlQueryRequest::cache_ttl).
+///
+/// Only the successful responses are cached, and the paged queries are never
+/// cached.
+pub(crate) struct QueryCachingClient {
+    client: Arc<dyn DbClient>,
+    cache: QueryCache,
+    default_database: Option<String>,
+    clock: Arc<dyn Clock>,
+}
+
+impl QueryCachingClient {
+    pub fn new(
+        client: Arc<dyn DbClient>,
+        config: QueryCacheConfig,
+        default_database: Option<String>,
+    ) -> Self {
+        Self {
+            client,
+            cache: QueryCache::new(config),
+            default_database,
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    /// Expire the cached responses by the `clock` instead of the system time.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    #[inline]
+    fn database<'a>(&'a self, ctx: &'a RpcContext) -> Option<&'a String> {
+        ctx.database.as_ref().or(self.default_database.as_ref())
+    }
+}
+
+forward_db_client! {
+    impl DbClient for QueryCachingClient {
+        forward [
+            sql_query_paged, connection_states, config, clock, resolve_route_uncached, route_tables,
+            route_replicas, check_database, add_route_override, remove_route_override,
+            is_healthy, last_write_endpoint, server_capabilities, evict_routes_by_endpoint,
+            hedge_stats, group_commit_stats, channel_stats, circuit_breaker_states,
+            write_stats, reset_write_stats,
+        ] to self.client,
+            context ctx => Cow::Borrowed(ctx);
+
+        async fn sql_query(
+            &self,
+            ctx: &RpcContext,
+            req: &SqlQueryRequest,
+        ) -> Result<SqlQueryResponse> {
+            let keeps_arrow = req.keep_record_batches || req.keep_arrow_ipc_bytes;
+            let (ttl, database) = match (req.cache_ttl, self.database(ctx)) {
+                (Some(ttl), Some(database)) if !keeps_arrow => (ttl, database),
+                _ => return self.client.sql_query(ctx, req).await,
+            };
+
+            let columns = req
+                .projection
+                .as_ref()
+                .map(|projection| projection.columns.clone());
+            let key = (database.clone(), normalize_sql(&req.sql), columns);
+            if let Some(resp) = self.cache.get(&key, self.clock.now()) {
+                return Ok(resp);
+            }
+
+            let resp = self.client.sql_query(ctx, req).await?;
+            self.cache
+                .insert(key, resp.clone(), req.tables.clone(), self.clock.now(), ttl);
+            Ok(resp)
+        }
+
+        async fn write(&self, ctx: &RpcContext, req: &WriteRequest) -> Result<WriteResponse> {
+            let result = self.client.write(ctx, req).await;
+            // Invalidate even if the write fails, because some rows may be written.
+            if self.cache.config.invalidate_on_write {
+                if let Some(database) = self.database(ctx) {
+                    self.cache.invalidate(database, req.point_groups.keys());
+                }
+            }
+
+            result
+        }
+
+        async fn write_stream(
+            &self,
+            ctx: &RpcContext,
+            reqs: BoxStream<'_, WriteRequest>,
+        ) -> Result<WriteResponse> {
+            if !self.cache.config.invalidate_on_write {
+                return self.client.write_stream(ctx, reqs).await;
+            }
+
+            let mut tables = BTreeSet::new();
+            let reqs = reqs
+                .inspect(|req| tables.extend(req.point_groups.keys().cloned()))
+                .boxed();
+            let result = self.client.write_stream(ctx, reqs).await;
+            if let Some(database) = self.database(ctx) {
+                self.cache.invalidate(database, tables.iter());
+            }
+
+            result
+        }
+
+        fn cache_stats(&self) -> QueryCacheStats {
+            self.cache.stats()
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use futures::future;
+
+    use super::{normalize_sql, QueryCacheStats, QueryCachingClient};
+    use crate::{
+        clock::ManualClock,
+        config::QueryCacheConfig,
+        db_client::{DbClient, MockDbClient},
+        model::{
+            sql_query::{
+                Output as SqlQueryOutput, Request as SqlQueryRequest, Response as SqlQueryResponse,
+            },
+            value::Value,
+            write::{point::PointBuilder, Request as WriteRequest},
+        },
+        rpc_client::RpcContext,
+    };
+
+    /// Client caching the responses of a client counting the queries, and the
+    /// response of every query is of `response_bytes` bytes.
+    fn caching_client(response_bytes: usize, config: QueryCacheConfig) -> QueryCachingClient {
+        let queries = AtomicUsize::new(0);
+        let client = MockDbClient::default().on_sql_query(move |_ctx, _req| {
+            let queries = queries.fetch_add(1, Ordering::Relaxed) + 1;
+            let mut resp = SqlQueryResponse {
+                output: SqlQueryOutput::AffectedRows(queries as u64),
+                ..Default::default()
+            };
+            resp.execution_info.response_bytes = response_bytes;
+            future::ok(resp)
+        });
+        QueryCachingClient::new(Arc::new(client), config, Some("public".to_string()))
+    }
+
+    fn query(table: &str, ttl: Option<Duration>) -> SqlQueryRequest {
+        SqlQueryRequest::new(vec![table.to_string()], format!("SELECT * FROM {table}"))
+            .with_cache_ttl(ttl)
+    }
+
+    async fn affected_rows(client: &QueryCachingClient, req: &SqlQueryRequest) -> u64 {
+        client
+            .sql_query(&RpcContext::default(), req)
+            .await
+            .unwrap()
+            .affected_rows()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_normalize_sql() {
+        assert_eq!(
+            normalize_sql("  SELECT *\n  FROM   t\tWHERE a = 1 ;; "),
+            "SELECT * FROM t WHERE a = 1"
+        );
+        assert_eq!(
+            normalize_sql("SELECT * /* all */ FROM t -- comment\nWHERE a>=1;"),
+            "SELECT * FROM t WHERE a>=1"
+        );
+
+        // The whitespaces in the quotes are kept.
+        assert_eq!(
+            normalize_sql("SELECT  \"a  b\" FROM t WHERE name = 'a  b'"),
+            "SELECT \"a  b\" FROM t WHERE name = 'a  b'"
+        );
+        assert_ne!(
+            normalize_sql("SELECT * FROM t WHERE name = 'a  b'"),
+            normalize_sql("SELECT * FROM t WHERE name = 'a b'")
+        );
+        assert_eq!(normalize_sql("SELECT 'a  b"), "SELECT 'a  b");
+    }
+
+    #[tokio::test]
+    async fn test_ttl_expiry() {
+        let clock = ManualClock::new();
+        let client =
+            caching_client(10, QueryCacheConfig::default()).with_clock(Arc::new(clock.clone()));
+        let ttl = Duration::from_secs(60);
+        let req = query("t1", Some(ttl));
+
+        assert_eq!(affected_rows(&client, &req).await, 1);
+        let reformatted = SqlQueryRequest {
+            sql: format!("  {}\n;", req.sql),
+            ..req.clone()
+        };
+        assert_eq!(affected_rows(&client, &reformatted).await, 1);
+        assert_eq!(client.cache_stats(), QueryCacheStats { hits: 1, misses: 1 });
+
+        clock.advance(ttl - Duration::from_secs(1));
+        assert_eq!(affected_rows(&client, &req).await, 1);
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(affected_rows(&client, &req).await, 2);
+        assert_eq!(client.cache_stats(), QueryCacheStats { hits: 2, misses: 2 });
+
+        // The queries not marked cacheable are neither cached nor counted.
+        let uncached = query("t1", None);
+        assert_eq!(affected_rows(&client, &uncached).await, 3);
+        assert_eq!(affected_rows(&client, &uncached).await, 4);
+        assert_eq!(client.cache_stats(), QueryCacheStats { hits: 2, misses: 2 });
+
+        // Neither are the queries keeping the arrow data.
+        let keeping_arrow = req.clone().with_arrow_ipc_bytes(true);
+        assert_eq!(affected_rows(&client, &keeping_arrow).await, 5);
+        assert_eq!(affected_rows(&client, &keeping_arrow).await, 6);
+        assert_eq!(client.cache_stats(), QueryCacheStats { hits: 2, misses: 2 });
+    }
+
+    #[tokio::test]
+    async fn test_size_based_eviction() {
+        let ttl = Some(Duration::from_secs(60));
+        let config = QueryCacheConfig {
+            max_entries: 2,
+            max_bytes: 1 << 20,
+            invalidate_on_write: true,
+        };
+        let client = caching_client(10, config);
+        let (req1, req2, req3) = (query("t1", ttl), query("t2", ttl), query("t3", ttl));
+
+        // The least recently used one is evicted when exceeding the max entries.
+        assert_eq!(affected_rows(&client, &req1).await, 1);
+        assert_eq!(affected_rows(&client, &req2).await, 2);
+        assert_eq!(affected_rows(&client, &req1).await, 1);
+        assert_eq!(affected_rows(&client, &req3).await, 3);
+        assert_eq!(affected_rows(&client, &req1).await, 1);
+        assert_eq!(affected_rows(&client, &req2).await, 4);
+
+        // Evicted when exceeding the max bytes, and the too large one is never cached.
+        let config = QueryCacheConfig {
+            max_entries: 100,
+            max_bytes: 25,
+            invalidate_on_write: true,
+        };
+        let client = caching_client(10, config.clone());
+        assert_eq!(affected_rows(&client, &req1).await, 1);
+        assert_eq!(affected_rows(&client, &req2).await, 2);
+        assert_eq!(affected_rows(&client, &req3).await, 3);
+        assert_eq!(affected_rows(&client, &req1).await, 4);
+
+        let client = caching_client(30, config);
+        assert_eq!(affected_rows(&client, &req1).await, 1);
+        assert_eq!(affected_rows(&client, &req1).await, 2);
+    }
+
+    #[tokio::test]
+    async fn test_write_invalidation() {
+        let ttl = Some(Duration::from_secs(60));
+        let (req1, req2) = (query("t1", ttl), query("t2", ttl));
+        let point = PointBuilder::new("t1".to_string())
+            .timestamp(1_700_000_000_000)
+            .field("value".to_string(), Value::Int64(42))
+            .build()
+            .unwrap();
+        let mut write_req = WriteRequest::default();
+        write_req.add_point(point);
+
+        for invalidate_on_write in [true, false] {
+            let config = QueryCacheConfig {
+                invalidate_on_write,
+                ..Default::default()
+            };
+            let client = caching_client(10, config);
+            assert_eq!(affected_rows(&client, &req1).await, 1);
+            assert_eq!(affected_rows(&client, &req2).await, 2);
+
+            client
+                .write(&RpcContext::default(), &write_req)
+                .await
+                .unwrap();
+            let expected = if invalidate_on_write { 3 } else { 1 };
+            assert_eq!(affected_rows(&client, &req1).await, expected);
+            assert_eq!(affected_rows(&client, &req2).await, 2);
+
+            // The cache is per database.
+            let ctx = RpcContext::default().database("other".to_string());
+            let resp = client.sql_query(&ctx, &req2).await.unwrap();
+            assert_ne!(resp.affected_rows(), Some(2));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_projection() {
+        let client = caching_client(10, QueryCacheConfig::default());
+        // The sql is left unchanged by the projections, so only the projected
+        // columns tell the queries apart.
+        let req = SqlQueryRequest::new(
+            vec!["t1".to_string()],
+            "SELECT ts, host FROM t1".to_string(),
+        )
+        .with_cache_ttl(Some(Duration::from_secs(60)));
+        let ts_only = req.clone().with_columns(&["ts"]);
+        let host_only = req.clone().with_columns(&["host"]);
+        assert!(ts_only.is_projection_ignored());
+        assert_eq!(ts_only.sql, host_only.sql);
+
+        assert_eq!(affected_rows(&client, &req).await, 1);
+        assert_eq!(affected_rows(&client, &ts_only).await, 2);
+        assert_eq!(affected_rows(&client, &host_only).await, 3);
+        assert_eq!(affected_rows(&client, &ts_only).await, 2);
+        assert_eq!(affected_rows(&client, &host_only).await, 3);
+        assert_eq!(affected_rows(&client, &req).await, 1);
+        assert_eq!(client.cache_stats(), QueryCacheStats { hits: 3, misses: 3 });
+    }
+}
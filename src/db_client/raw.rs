@@ -2,33 +2,212 @@
 
 //! Client for standalone mode
 
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
 
 use async_trait::async_trait;
+use futures::{
+    stream::{self, BoxStream},
+    StreamExt,
+};
 
 use crate::{
-    db_client::{inner::InnerClient, DbClient},
+    clock::{Clock, SystemClock},
+    config::{CircuitBreakerConfig, QueryGuardConfig, ReconnectConfig},
+    db_client::{
+        inner::InnerClient, is_database_listed, paged_sql_query, retry::RetryPolicy,
+        show_databases_request, slow_request::SlowRequestLogger, write_stats::WriteStatsRecorder,
+        BreakerState, ChannelStats, ConnectionState, DbClient, TableWriteStats,
+    },
+    metrics::ClientMetrics,
     model::{
+        route::Endpoint,
         sql_query::{Request as SqlQueryRequest, Response as SqlQueryResponse},
+        warning::WarningHook,
         write::{Request as WriteRequest, Response as WriteResponse},
     },
-    rpc_client::{RpcClientFactory, RpcContext},
-    Result,
+    rpc_client::{RpcClientFactory, RpcContext, ServerCapabilities},
+    spawner::Spawner,
+    Error, Result, RpcConfig,
 };
 
 /// Client for ceresdb of standalone mode.
 ///
 /// Now, [`RawImpl`] just wraps [`InnerClient`] simply.
+///
+/// Cloning is cheap, and the clones share the connection and its state, so the
+/// client is only configured by the `with_*` methods in the
+/// [`Builder`](crate::Builder), before it is cloned.
 pub struct RawImpl<F: RpcClientFactory> {
-    inner_client: InnerClient<F>,
+    inner_client: Arc<InnerClient<F>>,
     default_database: Option<String>,
+    slow_request_logger: SlowRequestLogger,
+    write_stats: Option<Arc<WriteStatsRecorder>>,
+    clock: Arc<dyn Clock>,
 }
 
 impl<F: RpcClientFactory> RawImpl<F> {
-    pub fn new(factory: Arc<F>, endpoint: String, default_database: Option<String>) -> Self {
+    pub fn new(
+        factory: Arc<F>,
+        endpoint: String,
+        default_database: Option<String>,
+        slow_request_logger: SlowRequestLogger,
+        max_consecutive_failures: usize,
+    ) -> Self {
         Self {
-            inner_client: InnerClient::new(factory, endpoint),
+            inner_client: Arc::new(InnerClient::new(
+                factory,
+                endpoint,
+                max_consecutive_failures,
+            )),
             default_database,
+            slow_request_logger,
+            write_stats: None,
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    /// Rebuild the channel on the first transport failure if the endpoint is a
+    /// hostname, see [`RpcConfig::refresh_dns_on_failure`].
+    ///
+    /// [`RpcConfig::refresh_dns_on_failure`]: crate::RpcConfig::refresh_dns_on_failure
+    pub(crate) fn with_refresh_dns_on_failure(self, refresh_dns_on_failure: bool) -> Self {
+        self.map_inner_client(|client| client.with_refresh_dns_on_failure(refresh_dns_on_failure))
+    }
+
+    /// Rebuild the channel once it is older than the `max_channel_age`, see
+    /// [`RpcConfig::max_channel_age`].
+    ///
+    /// [`RpcConfig::max_channel_age`]: crate::RpcConfig::max_channel_age
+    pub(crate) fn with_max_channel_age(self, max_channel_age: Option<Duration>) -> Self {
+        self.map_inner_client(|client| client.with_max_channel_age(max_channel_age))
+    }
+
+    /// Close the channel without any request for the `idle_timeout`, see
+    /// [`RpcConfig::idle_timeout`].
+    ///
+    /// [`RpcConfig::idle_timeout`]: crate::RpcConfig::idle_timeout
+    pub(crate) fn with_idle_timeout(self, idle_timeout: Option<Duration>) -> Self {
+        self.map_inner_client(|client| client.with_idle_timeout(idle_timeout))
+    }
+
+    /// Retry the failed requests according to the `retry_policy`, and no retry
+    /// if it is none.
+    pub(crate) fn with_retry_policy(self, retry_policy: Option<Arc<dyn RetryPolicy>>) -> Self {
+        self.map_inner_client(|client| client.with_retry_policy(retry_policy))
+    }
+
+    /// Short-circuit the requests after consecutive transport failures, see
+    /// [`RpcConfig::circuit_breaker`].
+    ///
+    /// [`RpcConfig::circuit_breaker`]: crate::RpcConfig::circuit_breaker
+    pub(crate) fn with_circuit_breaker(self, config: Option<CircuitBreakerConfig>) -> Self {
+        self.map_inner_client(|client| client.with_circuit_breaker(config))
+    }
+
+    /// Reconnect with the backoffs when failing to connect, see
+    /// [`RpcConfig::reconnect`].
+    ///
+    /// [`RpcConfig::reconnect`]: crate::RpcConfig::reconnect
+    pub(crate) fn with_reconnect(self, config: Option<ReconnectConfig>) -> Self {
+        self.map_inner_client(|client| client.with_reconnect(config))
+    }
+
+    /// Probe the capabilities of the server on every building of the channel,
+    /// see [`RpcConfig::negotiate_capabilities`].
+    ///
+    /// [`RpcConfig::negotiate_capabilities`]: crate::RpcConfig::negotiate_capabilities
+    pub(crate) fn with_negotiate_capabilities(self, negotiate_capabilities: bool) -> Self {
+        self.map_inner_client(|client| client.with_negotiate_capabilities(negotiate_capabilities))
+    }
+
+    /// Split the writes with more rows than the `max_rows_per_write`, see
+    /// [`RpcConfig::max_rows_per_write`].
+    ///
+    /// [`RpcConfig::max_rows_per_write`]: crate::RpcConfig::max_rows_per_write
+    pub(crate) fn with_write_split(
+        self,
+        max_rows_per_write: Option<usize>,
+        concurrency: usize,
+    ) -> Self {
+        self.map_inner_client(|client| client.with_write_split(max_rows_per_write, concurrency))
+    }
+
+    /// Record the writes per table into the `write_stats`, and nothing is
+    /// recorded if it is none.
+    pub(crate) fn with_write_stats(mut self, write_stats: Option<Arc<WriteStatsRecorder>>) -> Self {
+        self.write_stats = write_stats.clone();
+        self.map_inner_client(|client| client.with_write_stats(write_stats))
+    }
+
+    /// Call the `warning_hook` on every warning reported by the server.
+    pub(crate) fn with_warning_hook(self, warning_hook: Option<WarningHook>) -> Self {
+        self.map_inner_client(|client| client.with_warning_hook(warning_hook))
+    }
+
+    /// Bound the sql queries by the `query_guard`, see
+    /// [`Builder::query_guard`](crate::Builder::query_guard).
+    pub(crate) fn with_query_guard(self, query_guard: QueryGuardConfig) -> Self {
+        self.map_inner_client(|client| client.with_query_guard(query_guard))
+    }
+
+    /// Record the retries and the breaker states in the `metrics`, see
+    /// [`Builder::metrics`](crate::Builder::metrics).
+    pub(crate) fn with_metrics(self, metrics: Option<ClientMetrics>) -> Self {
+        self.map_inner_client(|client| client.with_metrics(metrics))
+    }
+
+    /// Probe the capabilities of the server on the runtime of the `spawner`,
+    /// see [`Builder::runtime`](crate::Builder::runtime).
+    pub(crate) fn with_spawner(self, spawner: Spawner) -> Self {
+        self.map_inner_client(|client| client.with_spawner(spawner))
+    }
+
+    /// Measure the time by the `clock` instead of the system time.
+    pub(crate) fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock.clone();
+        self.map_inner_client(|client| client.with_clock(clock))
+    }
+
+    /// Configure the inner client, which is only done by the
+    /// [`Builder`](crate::Builder) before the client is cloned.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the inner client is shared with any clone.
+    fn map_inner_client(mut self, f: impl FnOnce(InnerClient<F>) -> InnerClient<F>) -> Self {
+        let inner_client = Arc::try_unwrap(self.inner_client)
+            .unwrap_or_else(|_| panic!("the client must be configured before it is cloned"));
+        self.inner_client = Arc::new(f(inner_client));
+        self
+    }
+
+    /// Validate and normalize the request before writing it, and the points
+    /// dropped are counted in the response.
+    async fn write_validated(&self, ctx: &RpcContext, req: &WriteRequest) -> Result<WriteResponse> {
+        let (req, dropped) = req.prepare()?;
+        let mut resp = self.inner_client.write_internal(ctx, &req).await?;
+        dropped.count_in(&mut resp);
+        Ok(resp)
+    }
+
+    #[inline]
+    fn endpoints(&self) -> Vec<Endpoint> {
+        self.inner_client
+            .endpoint()
+            .parse::<Endpoint>()
+            .into_iter()
+            .collect()
+    }
+}
+
+impl<F: RpcClientFactory> Clone for RawImpl<F> {
+    fn clone(&self) -> Self {
+        Self {
+            inner_client: self.inner_client.clone(),
+            default_database: self.default_database.clone(),
+            slow_request_logger: self.slow_request_logger.clone(),
+            write_stats: self.write_stats.clone(),
+            clock: self.clock.clone(),
         }
     }
 }
@@ -36,12 +215,309 @@ impl<F: RpcClientFactory> RawImpl<F> {
 #[async_trait]
 impl<F: RpcClientFactory> DbClient for RawImpl<F> {
     async fn sql_query(&self, ctx: &RpcContext, req: &SqlQueryRequest) -> Result<SqlQueryResponse> {
-        let ctx = crate::db_client::resolve_database(ctx, &self.default_database)?;
-        self.inner_client.sql_query_internal(&ctx, req).await
+        let begin = self.clock.now();
+        let result = match crate::db_client::resolve_database(ctx, &self.default_database) {
+            Ok(ctx) => self.inner_client.sql_query_internal(&ctx, req).await,
+            Err(e) => Err(e),
+        };
+
+        self.slow_request_logger.observe_sql_query(
+            ctx.database.as_ref().or(self.default_database.as_ref()),
+            req,
+            self.endpoints(),
+            self.clock.now() - begin,
+            &result,
+        );
+        result
     }
 
     async fn write(&self, ctx: &RpcContext, req: &WriteRequest) -> Result<WriteResponse> {
+        let begin = self.clock.now();
+        let result = match crate::db_client::resolve_database(ctx, &self.default_database) {
+            Ok(ctx) => self.write_validated(&ctx, req).await,
+            Err(e) => Err(e),
+        };
+
+        self.slow_request_logger.observe_write(
+            ctx.database.as_ref().or(self.default_database.as_ref()),
+            req,
+            self.endpoints(),
+            self.clock.now() - begin,
+            &result,
+        );
+        result
+    }
+
+    async fn write_stream(
+        &self,
+        ctx: &RpcContext,
+        reqs: BoxStream<'_, WriteRequest>,
+    ) -> Result<WriteResponse> {
         let ctx = crate::db_client::resolve_database(ctx, &self.default_database)?;
-        self.inner_client.write_internal(&ctx, req).await
+        self.inner_client.write_stream_internal(&ctx, reqs).await
+    }
+
+    fn sql_query_paged<'a>(
+        &'a self,
+        ctx: &'a RpcContext,
+        req: &'a SqlQueryRequest,
+        page_size: usize,
+    ) -> BoxStream<'a, Result<SqlQueryResponse>> {
+        let ctx = match crate::db_client::resolve_database(ctx, &self.default_database) {
+            Ok(ctx) => ctx,
+            Err(e) => return stream::once(async { Err(e) }).boxed(),
+        };
+
+        paged_sql_query(req, page_size, ctx.cancel.clone(), move |page_req| {
+            let ctx = ctx.clone();
+            async move { self.inner_client.sql_query_internal(&ctx, &page_req).await }
+        })
+    }
+
+    async fn check_database(&self, ctx: &RpcContext) -> Result<bool> {
+        let ctx = crate::db_client::resolve_database(ctx, &self.default_database)?;
+        let resp = self
+            .inner_client
+            .sql_query_internal(&ctx, &show_databases_request())
+            .await?;
+
+        Ok(is_database_listed(
+            &resp,
+            ctx.database.as_deref().unwrap_or_default(),
+        ))
+    }
+
+    fn config(&self) -> RpcConfig {
+        self.inner_client.config()
+    }
+
+    fn clock(&self) -> Arc<dyn Clock> {
+        self.clock.clone()
+    }
+
+    fn connection_states(&self) -> Vec<(Endpoint, ConnectionState)> {
+        // The invalid endpoint can never be connected, so no state for it.
+        self.endpoints()
+            .into_iter()
+            .map(|endpoint| (endpoint, self.inner_client.connection_state()))
+            .collect()
+    }
+
+    fn server_capabilities(&self, endpoint: &Endpoint) -> Option<ServerCapabilities> {
+        if !self.endpoints().contains(endpoint) {
+            return None;
+        }
+        self.inner_client.server_capabilities()
+    }
+
+    fn channel_stats(&self) -> Vec<(Endpoint, ChannelStats)> {
+        let stats = self.inner_client.channel_stats();
+        self.endpoints()
+            .into_iter()
+            .map(|endpoint| (endpoint, stats))
+            .collect()
+    }
+
+    fn circuit_breaker_states(&self) -> Vec<(Endpoint, BreakerState)> {
+        match self.inner_client.breaker_state() {
+            Some(state) => self
+                .endpoints()
+                .into_iter()
+                .map(|endpoint| (endpoint, state.clone()))
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    async fn resolve_route_uncached(
+        &self,
+        _ctx: &RpcContext,
+        _table: &str,
+    ) -> Result<Option<Endpoint>> {
+        Err(Error::Client(
+            "resolving route is not supported in proxy mode".to_string(),
+        ))
+    }
+
+    fn write_stats(&self) -> Vec<TableWriteStats> {
+        self.write_stats
+            .as_deref()
+            .map(WriteStatsRecorder::stats)
+            .unwrap_or_default()
+    }
+
+    fn reset_write_stats(&self) {
+        if let Some(write_stats) = &self.write_stats {
+            write_stats.reset();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, Mutex};
+
+    use ceresdbproto::storage::{
+        sql_query_response::Output as OutputPb, SqlQueryResponse as QueryResponsePb,
+    };
+    use futures::future;
+
+    use super::RawImpl;
+    use crate::{
+        db_client::{slow_request::SlowRequestLogger, ConnectionState, DbClient, Operation},
+        interceptor::OperationKind,
+        model::{
+            sql_query::Request as SqlQueryRequest,
+            value::Value,
+            warning::{ServerWarning, WarningHook},
+            write::{point::PointBuilder, Request as WriteRequest},
+        },
+        rpc_client::{MockRpcClient, MockRpcClientFactory, RpcContext, RpcResponse},
+        Error,
+    };
+
+    /// Rpc client whose queries are warned as deprecated.
+    fn deprecating_rpc_client() -> MockRpcClient {
+        MockRpcClient::default().on_sql_query(|_, _| {
+            future::ready(Ok(RpcResponse {
+                warnings: vec![ServerWarning {
+                    code: 7,
+                    message: "syntax is deprecated".to_string(),
+                    table: None,
+                    endpoint: None,
+                }],
+                resp: QueryResponsePb {
+                    header: None,
+                    output: Some(OutputPb::AffectedRows(0)),
+                },
+            }))
+        })
+    }
+
+    #[tokio::test]
+    async fn test_clone() {
+        // The built clients share the counts of the rpcs.
+        let rpc_client = deprecating_rpc_client();
+        let factory = {
+            let rpc_client = rpc_client.clone();
+            Arc::new(MockRpcClientFactory::new(move |_| rpc_client.clone()))
+        };
+        let client = RawImpl::new(
+            factory.clone(),
+            "127.0.0.1:8831".to_string(),
+            Some("public".to_string()),
+            SlowRequestLogger::default(),
+            3,
+        );
+        let cloned = client.clone();
+
+        let point = PointBuilder::new("test_table".to_string())
+            .timestamp(1_700_000_000_000)
+            .field("value".to_string(), Value::Int64(42))
+            .build()
+            .unwrap();
+        let mut req = WriteRequest::default();
+        req.add_point(point);
+        let ctx = RpcContext::default();
+        client.write(&ctx, &req).await.unwrap();
+        cloned.write(&ctx, &req).await.unwrap();
+
+        // Both handles write through the same connection.
+        assert_eq!(factory.builds(), 1);
+        assert_eq!(rpc_client.calls(OperationKind::Write), 2);
+        drop(client);
+        assert_eq!(cloned.connection_states()[0].1, ConnectionState::Connected);
+    }
+    #[tokio::test]
+    async fn test_warnings() {
+        let hooked = Arc::new(Mutex::new(Vec::new()));
+        let hooked_clone = hooked.clone();
+        let client = RawImpl::new(
+            Arc::new(MockRpcClientFactory::new(|_| deprecating_rpc_client())),
+            "127.0.0.1:8831".to_string(),
+            Some("public".to_string()),
+            SlowRequestLogger::default(),
+            3,
+        )
+        .with_warning_hook(Some(WarningHook::new(move |operation, warning| {
+            hooked_clone.lock().unwrap().push((operation, warning));
+        })));
+
+        let req = SqlQueryRequest::new(
+            vec!["test_table".to_string()],
+            "SELECT * FROM test_table".to_string(),
+        );
+        let resp = client
+            .sql_query(&RpcContext::default(), &req)
+            .await
+            .unwrap();
+        let expected = ServerWarning {
+            code: 7,
+            message: "syntax is deprecated".to_string(),
+            table: None,
+            endpoint: Some("127.0.0.1:8831".to_string()),
+        };
+        assert_eq!(resp.warnings, vec![expected.clone()]);
+        assert!(!resp.is_partial());
+        assert_eq!(
+            *hooked.lock().unwrap(),
+            vec![(Operation::SqlQuery, expected)]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_rpc_error_context() {
+        // All the rpcs fail as the bad requests.
+        let factory = MockRpcClientFactory::new(|_| {
+            MockRpcClient::default().with_failure(|operation| {
+                let message = match operation {
+                    OperationKind::SqlQuery => "bad sql",
+                    _ => "bad write",
+                };
+                Some(Error::Rpc(tonic::Status::invalid_argument(message).into()))
+            })
+        });
+        let client = RawImpl::new(
+            Arc::new(factory),
+            "127.0.0.1:8831".to_string(),
+            Some("public".to_string()),
+            SlowRequestLogger::default(),
+            3,
+        );
+        let ctx = RpcContext::default();
+
+        let req = SqlQueryRequest::new(
+            vec!["test_table".to_string()],
+            "SELECT * FROM test_table".to_string(),
+        );
+        match client.sql_query(&ctx, &req).await {
+            Err(Error::Rpc(e)) => {
+                assert_eq!(e.operation, Some(OperationKind::SqlQuery));
+                assert_eq!(e.endpoint.as_deref(), Some("127.0.0.1:8831"));
+                assert_eq!(e.tables, vec!["test_table".to_string()]);
+                assert_eq!(e.code(), tonic::Code::InvalidArgument);
+                assert_eq!(e.message(), "bad sql");
+                assert!(e.elapsed.is_some());
+            }
+            result => panic!("unexpected result:{result:?}"),
+        }
+
+        let point = PointBuilder::new("test_table".to_string())
+            .timestamp(1_700_000_000_000)
+            .field("value".to_string(), Value::Int64(42))
+            .build()
+            .unwrap();
+        let mut req = WriteRequest::default();
+        req.add_point(point);
+        match client.write(&ctx, &req).await {
+            Err(Error::Rpc(e)) => {
+                assert_eq!(e.operation, Some(OperationKind::Write));
+                assert_eq!(e.endpoint.as_deref(), Some("127.0.0.1:8831"));
+                assert_eq!(e.tables, vec!["test_table".to_string()]);
+                assert_eq!(e.code(), tonic::Code::InvalidArgument);
+                assert!(e.elapsed.is_some());
+            }
+            result => panic!("unexpected result:{result:?}"),
+        }
     }
 }
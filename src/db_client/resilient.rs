@@ -0,0 +1,1076 @@
+// Copyright 2022 CeresDB Project Authors. Licensed under Apache-2.0.
+
+//! Writer spilling the failed writes to a local file and replaying them
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex, MutexGuard, PoisonError,
+    },
+};
+
+use ceresdbproto::storage::WriteRequest as WriteRequestPb;
+use futures::future::join_all;
+use prost::Message;
+use tokio::{sync::Notify, task::JoinHandle};
+use tonic::Code;
+
+use crate::{
+    config::{SpillConfig, SpillFullPolicy},
+    db_client::DbClient,
+    model::write::{
+        points_from_pb, Request as WriteRequest, Response as WriteResponse,
+        WriteTableRequestPbsBuilder,
+    },
+    rpc_client::RpcContext,
+    util::crc32,
+    Error, Result,
+};
+
+/// Name of the spill file in the directory of the [`ResilientWriter`].
+const SPILL_FILE: &str = "spill.wal";
+
+/// The length and the checksum of the payload before every record.
+const RECORD_HEADER_LEN: u64 = 8;
+
+const BATCH_RECORD: u8 = 0;
+const ACK_RECORD: u8 = 1;
+
+/// Outcome of the write of the [`ResilientWriter`].
+#[derive(Debug)]
+pub enum WriteOutcome {
+    /// The request is written as a whole.
+    Written(WriteResponse),
+    /// The request, or the part of it failing to write, is spilled as the
+    /// batch `batch_id` to be replayed, and `written` is the response of the
+    /// rest if any.
+    ///
+    /// The `errors` are the tables failing with the errors not spilled, which
+    /// are neither written nor spilled.
+    Spilled {
+        batch_id: u64,
+        written: Option<WriteResponse>,
+        errors: Vec<(Vec<String>, Error)>,
+    },
+}
+
+/// Writer spilling the writes failing on the outages of the server to a file
+/// in its directory, and the spilled batches are replayed by the client in
+/// the background with backoffs until they are written.
+///
+/// The writes are spilled if they fail because the endpoint is unavailable,
+/// unhealthy or times out, and for the partially failed writes in the route
+/// based mode, only the tables failing that way are spilled, and the tables
+/// failing with the other errors are returned along with the spilled batch.
+/// The other errors are returned as is. While any batch is pending, the writes
+/// are spilled directly to keep them behind the pending ones.
+///
+/// The batches are replayed strictly in order unless the
+/// [`replay_concurrency`](SpillConfig::replay_concurrency) is larger than 1,
+/// and every batch is replayed with the database of its write, so it is
+/// routed by the tables as the original write. The batches are loaded from
+/// the file on open, so they survive the restarts of the process.
+///
+/// A batch is written at least once, and it is replayed again only if the
+/// process stops between the replay and its acknowledgement in the file.
+///
+/// The spill file is a sequence of records, and every record is the length
+/// and the CRC-32 of its payload followed by the payload. The payload is
+/// either a batch, i.e. the id, the database and the encoded
+/// [`WriteRequestPb`], or the acknowledgement of the batch removed. The file
+/// is truncated once all the batches are removed.
+pub struct ResilientWriter {
+    spill: Arc<Spill>,
+    replayer: JoinHandle<()>,
+}
+
+impl ResilientWriter {
+    /// Open the writer spilling to the directory `dir`, which is created if
+    /// not exists, and the pending batches in it are replayed.
+    ///
+    /// The torn or corrupted tail of the spill file is truncated. It must be
+    /// called in a tokio runtime.
+    pub fn open(
+        client: Arc<dyn DbClient>,
+        dir: impl AsRef<Path>,
+        config: SpillConfig,
+    ) -> Result<Self> {
+        let dir = dir.as_ref();
+        fs::create_dir_all(dir).map_err(|e| {
+            Error::Client(format!(
+                "failed to create spill directory, path:{}, err:{e}",
+                dir.display()
+            ))
+        })?;
+
+        let spill = Arc::new(Spill::open(client, dir.join(SPILL_FILE), config)?);
+        let replayer = tokio::spawn(replay(spill.clone()));
+
+        Ok(Self { spill, replayer })
+    }
+
+    /// Write the request by the client, and it is spilled if it fails on the
+    /// outages of the server or any batch is pending.
+    ///
+    /// It fails if the request is invalid, or it can't be spilled according
+    /// to the [`full_policy`](SpillConfig::full_policy).
+    pub async fn write(&self, ctx: &RpcContext, req: &WriteRequest) -> Result<WriteOutcome> {
+        if self.pending_batches() > 0 {
+            let batch_id = self.spill.append(ctx, req).await?;
+            return Ok(WriteOutcome::Spilled {
+                batch_id,
+                written: None,
+                errors: Vec::new(),
+            });
+        }
+
+        match self.spill.client.write(ctx, req).await {
+            Ok(resp) => Ok(WriteOutcome::Written(resp)),
+            Err(Error::RouteBasedWriteError(e)) if any_spillable(&e.errors) => {
+                let (spillable, errors) = split_spillable(e.errors);
+                let failed = failed_part(req, &spillable);
+                let batch_id = self.spill.append(ctx, &failed).await?;
+                Ok(WriteOutcome::Spilled {
+                    batch_id,
+                    written: Some(e.ok.1),
+                    errors,
+                })
+            }
+            Err(_e) if is_spillable(&_e) => {
+                #[cfg(feature = "tracing")]
+                tracing::warn!(error = %_e, "failed to write, and the request is spilled");
+                let batch_id = self.spill.append(ctx, req).await?;
+                Ok(WriteOutcome::Spilled {
+                    batch_id,
+                    written: None,
+                    errors: Vec::new(),
+                })
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// The number of the batches to replay.
+    pub fn pending_batches(&self) -> usize {
+        self.spill.pending_batches.load(Ordering::Acquire)
+    }
+
+    /// The size of the batches to replay in the spill file.
+    pub fn spilled_bytes(&self) -> u64 {
+        self.spill.spilled_bytes.load(Ordering::Acquire)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.spill.path
+    }
+}
+
+impl Drop for ResilientWriter {
+    fn drop(&mut self) {
+        self.replayer.abort();
+    }
+}
+
+/// A write spilled, and the `pb` is the encoded [`WriteRequestPb`] without
+/// the context.
+#[derive(Debug, Clone)]
+struct Batch {
+    id: u64,
+    database: Option<String>,
+    pb: Arc<[u8]>,
+}
+
+impl Batch {
+    fn record_len(&self) -> u64 {
+        let database_len = self
+            .database
+            .as_ref()
+            .map_or(0, |database| 4 + database.len());
+        RECORD_HEADER_LEN + (1 + 8 + 1 + database_len + self.pb.len()) as u64
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut payload = vec![BATCH_RECORD];
+        payload.extend_from_slice(&self.id.to_be_bytes());
+        match &self.database {
+            Some(database) => {
+                payload.push(1);
+                payload.extend_from_slice(&(database.len() as u32).to_be_bytes());
+                payload.extend_from_slice(database.as_bytes());
+            }
+            None => payload.push(0),
+        }
+        payload.extend_from_slice(&self.pb);
+        encode_record(&payload)
+    }
+}
+
+enum Record {
+    Batch(Batch),
+    Ack(u64),
+}
+
+struct SpillState {
+    file: File,
+    file_len: u64,
+    batches: VecDeque<Batch>,
+    /// The size of the records of the `batches`.
+    live_bytes: u64,
+    next_id: u64,
+}
+
+struct Spill {
+    client: Arc<dyn DbClient>,
+    config: SpillConfig,
+    path: PathBuf,
+    // The file is written synchronously under the lock, so the state is only
+    // locked in the blocking pool by the async code, see `Spill::blocking`.
+    state: Mutex<SpillState>,
+    // Mirrors of the state read without the lock.
+    pending_batches: AtomicUsize,
+    spilled_bytes: AtomicU64,
+    // The id of the oldest pending batch, and the max if there is none.
+    oldest_id: AtomicU64,
+    // Notified when a batch is appended, to wake up the replayer.
+    appended: Notify,
+    // Notified when batches are removed, to wake up the blocked writes.
+    removed: Notify,
+}
+
+impl Spill {
+    fn open(client: Arc<dyn DbClient>, path: PathBuf, config: SpillConfig) -> Result<Self> {
+        let bytes = match fs::read(&path) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => {
+                return Err(Error::Client(format!(
+                    "failed to read spill file, path:{}, err:{e}",
+                    path.display()
+                )))
+            }
+        };
+
+        let mut batches = BTreeMap::new();
+        let mut next_id = 0;
+        let mut rest = bytes.as_slice();
+        while !rest.is_empty() {
+            let (record, len) = match decode_record(rest) {
+                Ok(decoded) => decoded,
+                Err(_e) => {
+                    #[cfg(feature = "tracing")]
+                    tracing::warn!(
+                        error = %_e,
+                        offset = bytes.len() - rest.len(),
+                        "the corrupted tail of the spill file is truncated"
+                    );
+                    break;
+                }
+            };
+            match record {
+                Record::Batch(batch) => {
+                    next_id = next_id.max(batch.id + 1);
+                    batches.insert(batch.id, batch);
+                }
+                Record::Ack(id) => {
+                    batches.remove(&id);
+                }
+            }
+            rest = &rest[len..];
+        }
+
+        let batches: VecDeque<_> = batches.into_values().collect();
+        let live_bytes = batches.iter().map(Batch::record_len).sum();
+        let file = open_for_append(&path)?;
+        let pending_batches = AtomicUsize::new(batches.len());
+        let spilled_bytes = AtomicU64::new(live_bytes);
+        let oldest_id = AtomicU64::new(batches.front().map_or(u64::MAX, |batch| batch.id));
+        let spill = Self {
+            client,
+            config,
+            path,
+            state: Mutex::new(SpillState {
+                file,
+                file_len: bytes.len() as u64,
+                batches,
+                live_bytes,
+                next_id,
+            }),
+            pending_batches,
+            spilled_bytes,
+            oldest_id,
+            appended: Notify::new(),
+            removed: Notify::new(),
+        };
+        // Drop the removed batches and the corrupted tail.
+        {
+            let mut state = spill.lock();
+            if state.file_len != state.live_bytes {
+                spill.compact(&mut state)?;
+            }
+        }
+
+        Ok(spill)
+    }
+
+    #[inline]
+    fn lock(&self) -> MutexGuard<'_, SpillState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Run `f` in the blocking pool, because it locks the state which is held
+    /// during the file I/O.
+    async fn blocking<T, F>(self: &Arc<Self>, f: F) -> Result<T>
+    where
+        F: FnOnce(&Spill) -> Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let spill = self.clone();
+        tokio::task::spawn_blocking(move || f(&spill))
+            .await
+            .map_err(|e| Error::Client(format!("spill task failed, err:{e}")))?
+    }
+
+    /// Update the mirrors of the state after it is changed.
+    fn sync_stats(&self, state: &SpillState) {
+        self.pending_batches
+            .store(state.batches.len(), Ordering::Release);
+        self.spilled_bytes
+            .store(state.live_bytes, Ordering::Release);
+        let oldest_id = state.batches.front().map_or(u64::MAX, |batch| batch.id);
+        self.oldest_id.store(oldest_id, Ordering::Release);
+    }
+
+    /// Append the request as a batch, and return its id.
+    async fn append(self: &Arc<Self>, ctx: &RpcContext, req: &WriteRequest) -> Result<u64> {
+        let (req, _) = req.prepare()?;
+        let pb = encode_request(req.into_owned())?;
+        loop {
+            // Created before the check, so the removals after it are not
+            // missed.
+            let removed = self.removed.notified();
+            let database = ctx.database.clone();
+            let batch_pb = pb.clone();
+            let appended = self
+                .blocking(move |spill| spill.try_append(&database, &batch_pb))
+                .await?;
+            if let Some(id) = appended {
+                self.appended.notify_one();
+                return Ok(id);
+            }
+            removed.await;
+        }
+    }
+
+    /// Append the batch if there is room for it, and none is returned if it
+    /// has to wait according to the [`SpillFullPolicy::Block`].
+    fn try_append(&self, database: &Option<String>, pb: &Arc<[u8]>) -> Result<Option<u64>> {
+        let mut state = self.lock();
+        let batch = Batch {
+            id: state.next_id,
+            database: database.clone(),
+            pb: pb.clone(),
+        };
+        let batch_size = batch.record_len();
+        let limit = self.config.max_spill_bytes;
+        let full = |state: &SpillState| Error::SpillFull {
+            size: state.live_bytes,
+            batch_size,
+            limit,
+        };
+        if batch_size > limit {
+            return Err(full(&state));
+        }
+        while state.live_bytes + batch_size > limit {
+            match self.config.full_policy {
+                SpillFullPolicy::Block => return Ok(None),
+                SpillFullPolicy::Error => return Err(full(&state)),
+                SpillFullPolicy::DropOldest => {
+                    let oldest = state.batches[0].id;
+                    #[cfg(feature = "tracing")]
+                    tracing::warn!(
+                        batch_id = oldest,
+                        "the spill is full, and the oldest batch is dropped"
+                    );
+                    self.remove(&mut state, oldest)?;
+                }
+            }
+        }
+        if state.file_len + batch_size > limit {
+            self.compact(&mut state)?;
+        }
+
+        self.append_record(&mut state, &batch.encode())?;
+        state.next_id += 1;
+        state.live_bytes += batch_size;
+        state.batches.push_back(batch.clone());
+        self.sync_stats(&state);
+
+        Ok(Some(batch.id))
+    }
+
+    /// Remove the batch, and it is acknowledged in the file.
+    fn remove(&self, state: &mut SpillState, id: u64) -> Result<()> {
+        let index = state.batches.iter().position(|batch| batch.id == id);
+        let batch = match index.and_then(|index| state.batches.remove(index)) {
+            Some(batch) => batch,
+            // Dropped as the oldest one during the replay.
+            None => return Ok(()),
+        };
+        state.live_bytes -= batch.record_len();
+        self.sync_stats(state);
+        self.removed.notify_waiters();
+
+        if state.batches.is_empty() || state.file_len > self.config.max_spill_bytes {
+            return self.compact(state);
+        }
+        let mut payload = vec![ACK_RECORD];
+        payload.extend_from_slice(&id.to_be_bytes());
+        self.append_record(state, &encode_record(&payload))
+    }
+
+    /// Replace the batch by the part of it failing to replay, and the record
+    /// of the later one wins on load.
+    fn replace(&self, state: &mut SpillState, id: u64, pb: Arc<[u8]>) -> Result<()> {
+        let batch = match state.batches.iter_mut().find(|batch| batch.id == id) {
+            Some(batch) => batch,
+            None => return Ok(()),
+        };
+        let old_len = batch.record_len();
+        batch.pb = pb;
+        let new_len = batch.record_len();
+        let record = batch.encode();
+        state.live_bytes = state.live_bytes - old_len + new_len;
+        self.sync_stats(state);
+        self.append_record(state, &record)
+    }
+
+    fn append_record(&self, state: &mut SpillState, record: &[u8]) -> Result<()> {
+        state
+            .file
+            .write_all(record)
+            .map_err(|e| self.io_error("write", e))?;
+        state.file_len += record.len() as u64;
+        Ok(())
+    }
+
+    /// Rewrite the file with the records of the pending batches only, and it
+    /// is truncated if there is none.
+    fn compact(&self, state: &mut SpillState) -> Result<()> {
+        if state.batches.is_empty() {
+            state
+                .file
+                .set_len(0)
+                .map_err(|e| self.io_error("truncate", e))?;
+            state.file_len = 0;
+            return Ok(());
+        }
+
+        let mut buf = Vec::with_capacity(state.live_bytes as usize);
+        for batch in &state.batches {
+            buf.extend_from_slice(&batch.encode());
+        }
+        let mut tmp_path = self.path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        fs::write(&tmp_path, &buf)
+            .and_then(|_| fs::rename(&tmp_path, &self.path))
+            .map_err(|e| self.io_error("compact", e))?;
+        state.file = open_for_append(&self.path)?;
+        state.file_len = buf.len() as u64;
+        Ok(())
+    }
+
+    fn io_error(&self, op: &str, e: io::Error) -> Error {
+        Error::Client(format!(
+            "failed to {op} spill file, path:{}, err:{e}",
+            self.path.display()
+        ))
+    }
+
+    /// Replay the batch, and return whether it is done with.
+    async fn replay_batch(self: &Arc<Self>, batch: &Batch) -> bool {
+        let req = match decode_request(&batch.pb) {
+            Ok(req) => req,
+            Err(_e) => {
+                #[cfg(feature = "tracing")]
+                tracing::warn!(error = %_e, batch_id = batch.id, "the malformed batch is dropped");
+                return self.complete(batch.id, None).await;
+            }
+        };
+        let ctx = RpcContext {
+            database: batch.database.clone(),
+            ..Default::default()
+        };
+
+        // The batch may be dropped as the oldest one since it is taken, and
+        // the ids of the pending batches are ascending.
+        if batch.id < self.oldest_id.load(Ordering::Acquire) {
+            return true;
+        }
+        match self.client.write(&ctx, &req).await {
+            Ok(_) => self.complete(batch.id, None).await,
+            Err(Error::RouteBasedWriteError(e)) if any_spillable(&e.errors) => {
+                // The tables failing with the other errors are dropped from the
+                // batch.
+                let (spillable, _errors) = split_spillable(e.errors);
+                #[cfg(feature = "tracing")]
+                for (tables, e) in &_errors {
+                    tracing::warn!(error = %e, batch_id = batch.id, ?tables, "failed to replay, and the tables are dropped");
+                }
+                match encode_request(failed_part(&req, &spillable)) {
+                    Ok(pb) => {
+                        let id = batch.id;
+                        let replaced = self
+                            .blocking(move |spill| spill.replace(&mut spill.lock(), id, pb))
+                            .await;
+                        if let Err(_e) = replaced {
+                            #[cfg(feature = "tracing")]
+                            tracing::warn!(error = %_e, batch_id = batch.id, "failed to replace the batch");
+                        }
+                        false
+                    }
+                    Err(_) => self.complete(batch.id, None).await,
+                }
+            }
+            Err(e) if is_spillable(&e) => false,
+            Err(e) => self.complete(batch.id, Some(e)).await,
+        }
+    }
+
+    /// Remove the batch done with, and the `error` is why it is dropped.
+    async fn complete(self: &Arc<Self>, id: u64, _error: Option<Error>) -> bool {
+        #[cfg(feature = "tracing")]
+        if let Some(e) = &_error {
+            tracing::warn!(error = %e, batch_id = id, "failed to replay, and the batch is dropped");
+        }
+        let removed = self
+            .blocking(move |spill| spill.remove(&mut spill.lock(), id))
+            .await;
+        if let Err(_e) = removed {
+            #[cfg(feature = "tracing")]
+            tracing::warn!(error = %_e, batch_id = id, "failed to remove the replayed batch");
+        }
+        true
+    }
+}
+
+/// Replay the pending batches from the head, and back off on the failures.
+async fn replay(spill: Arc<Spill>) {
+    let concurrency = spill.config.replay_concurrency.max(1);
+    let mut failures = 0;
+    loop {
+        let batches: Vec<_> = spill
+            .blocking(move |spill| {
+                let state = spill.lock();
+                Ok(state.batches.iter().take(concurrency).cloned().collect())
+            })
+            .await
+            .unwrap_or_default();
+        if batches.is_empty() {
+            spill.appended.notified().await;
+            continue;
+        }
+
+        let done = join_all(batches.iter().map(|batch| spill.replay_batch(batch))).await;
+        if done.into_iter().all(|done| done) {
+            failures = 0;
+        } else {
+            failures += 1;
+            tokio::time::sleep(spill.config.replay_backoff(failures)).await;
+        }
+    }
+}
+
+/// Whether the write failing with the error is spilled, i.e. it fails on the
+/// outages of the server.
+fn is_spillable(error: &Error) -> bool {
+    match error {
+        Error::Connect { .. }
+        | Error::CircuitOpen { .. }
+        | Error::EndpointUnhealthy { .. }
+        | Error::Timeout { .. } => true,
+        Error::Rpc(e) => matches!(e.code(), Code::Unavailable | Code::DeadlineExceeded),
+        Error::RouteOverridden { source, .. } => is_spillable(source),
+        _ => false,
+    }
+}
+
+fn any_spillable(errors: &[(Vec<String>, Error)]) -> bool {
+    errors.iter().any(|(_, e)| is_spillable(e))
+}
+
+type TableErrors = Vec<(Vec<String>, Error)>;
+
+/// Split the errors into the spillable ones and the others.
+fn split_spillable(errors: TableErrors) -> (TableErrors, TableErrors) {
+    errors.into_iter().partition(|(_, e)| is_spillable(e))
+}
+
+/// Build the request of the tables failing to write.
+fn failed_part(req: &WriteRequest, errors: &[(Vec<String>, Error)]) -> WriteRequest {
+    let mut failed = req.empty_like();
+    for table in errors.iter().flat_map(|(tables, _)| tables) {
+        if let Some(points) = req.point_groups.get(table) {
+            failed.point_groups.insert(table.clone(), points.clone());
+        }
+    }
+    failed
+}
+
+fn encode_request(req: WriteRequest) -> Result<Arc<[u8]>> {
+    let pb = WriteRequestPb {
+        context: None,
+        table_requests: WriteTableRequestPbsBuilder(req).build()?,
+    };
+    Ok(pb.encode_to_vec().into())
+}
+
+/// Decode the request of the batch, and the timestamps are resolved and
+/// checked when it is spilled.
+fn decode_request(pb: &[u8]) -> Result<WriteRequest> {
+    let pb = WriteRequestPb::decode(pb)
+        .map_err(|e| Error::Client(format!("failed to decode spilled batch, err:{e}")))?;
+    let mut req = WriteRequest {
+        min_timestamp: None,
+        max_value_bytes: None,
+        ..Default::default()
+    };
+    for table_request in pb.table_requests {
+        req.add_points(points_from_pb(table_request)?);
+    }
+    Ok(req)
+}
+
+fn encode_record(payload: &[u8]) -> Vec<u8> {
+    let mut record = Vec::with_capacity(RECORD_HEADER_LEN as usize + payload.len());
+    record.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    record.extend_from_slice(&crc32(payload).to_be_bytes());
+    record.extend_from_slice(payload);
+    record
+}
+
+/// Decode the record at the head of the `bytes`, and return it with its
+/// length.
+fn decode_record(bytes: &[u8]) -> io::Result<(Record, usize)> {
+    let invalid_data = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+    let u32_at = |bytes: &[u8], offset: usize| -> io::Result<u32> {
+        let mut buf = [0; 4];
+        buf.copy_from_slice(
+            bytes
+                .get(offset..offset + 4)
+                .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?,
+        );
+        Ok(u32::from_be_bytes(buf))
+    };
+
+    let payload_len = u32_at(bytes, 0)? as usize;
+    let crc = u32_at(bytes, 4)?;
+    let header_len = RECORD_HEADER_LEN as usize;
+    let payload = bytes
+        .get(header_len..header_len + payload_len)
+        .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+    if crc32(payload) != crc {
+        return Err(invalid_data("checksum mismatch"));
+    }
+
+    let id = match payload.get(1..9) {
+        Some(id) => {
+            let mut buf = [0; 8];
+            buf.copy_from_slice(id);
+            u64::from_be_bytes(buf)
+        }
+        None => return Err(invalid_data("record is too short")),
+    };
+    let record = match (payload[0], payload.get(9)) {
+        (ACK_RECORD, None) => Record::Ack(id),
+        (BATCH_RECORD, Some(0)) => Record::Batch(Batch {
+            id,
+            database: None,
+            pb: payload[10..].into(),
+        }),
+        (BATCH_RECORD, Some(1)) => {
+            let database_len = u32_at(payload, 10)? as usize;
+            let database = payload
+                .get(14..14 + database_len)
+                .ok_or_else(|| invalid_data("database is too long"))?;
+            let database = String::from_utf8(database.to_vec())
+                .map_err(|_| invalid_data("database is not utf8"))?;
+            Record::Batch(Batch {
+                id,
+                database: Some(database),
+                pb: payload[14 + database_len..].into(),
+            })
+        }
+        _ => return Err(invalid_data("unknown record")),
+    };
+
+    Ok((record, header_len + payload_len))
+}
+
+fn open_for_append(path: &Path) -> Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|e| {
+            Error::Client(format!(
+                "failed to open spill file, path:{}, err:{e}",
+                path.display()
+            ))
+        })
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        fs::{self, OpenOptions},
+        path::PathBuf,
+        process,
+        sync::{
+            atomic::{AtomicBool, AtomicUsize, Ordering},
+            Arc, Mutex,
+        },
+        time::Duration,
+    };
+
+    use futures::future;
+
+    use super::{ResilientWriter, WriteOutcome, SPILL_FILE};
+    use crate::{
+        config::{SpillConfig, SpillFullPolicy},
+        db_client::MockDbClient,
+        errors::ServerError,
+        model::{
+            value::Value,
+            write::{point::PointBuilder, Request as WriteRequest, Response as WriteResponse},
+        },
+        rpc_client::RpcContext,
+        util::crc32,
+        Error,
+    };
+
+    /// Server recording the database and the `request_id` of the points
+    /// written, and it is unavailable while `down`.
+    #[derive(Default)]
+    struct Server {
+        down: AtomicBool,
+        written: Mutex<Vec<(Option<String>, String)>>,
+    }
+
+    impl Server {
+        fn written_ids(&self) -> Vec<String> {
+            let written = self.written.lock().unwrap();
+            written.iter().map(|(_, id)| id.clone()).collect()
+        }
+    }
+
+    /// Client writing to the `server`, which doesn't serve the queries.
+    fn mock_client(server: Arc<Server>) -> Arc<MockDbClient> {
+        let client = MockDbClient::default()
+            .on_sql_query(|_ctx, req| {
+                future::err(Error::Client(format!("unexpected query:{}", req.sql)))
+            })
+            .on_write(move |ctx, req| {
+                if server.down.load(Ordering::SeqCst) {
+                    return future::err(Error::Rpc(
+                        tonic::Status::unavailable("connection refused").into(),
+                    ));
+                }
+
+                let mut written = server.written.lock().unwrap();
+                let mut success = 0;
+                for point in req.point_groups.values().flatten() {
+                    let id = point.fields["request_id"].as_str().unwrap();
+                    written.push((ctx.database.clone(), id));
+                    success += 1;
+                }
+                future::ok(WriteResponse::new(success, 0))
+            });
+        Arc::new(client)
+    }
+
+    fn temp_dir() -> PathBuf {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let id = COUNTER.fetch_add(1, Ordering::SeqCst);
+        std::env::temp_dir().join(format!("ceresdb-spill-{}-{id}", process::id()))
+    }
+
+    fn spill_config() -> SpillConfig {
+        SpillConfig {
+            initial_replay_backoff: Duration::from_millis(5),
+            max_replay_backoff: Duration::from_millis(20),
+            ..Default::default()
+        }
+    }
+
+    fn request(id: usize) -> WriteRequest {
+        let point = PointBuilder::new("demo".to_string())
+            .timestamp(1_700_000_000_000 + id as i64)
+            .tag("host", Value::String("web".to_string()))
+            .field("request_id", Value::String(id.to_string()))
+            .build()
+            .unwrap();
+        let mut req = WriteRequest::default();
+        req.add_point(point);
+        req
+    }
+
+    async fn wait_replayed(writer: &ResilientWriter) {
+        for _ in 0..500 {
+            if writer.pending_batches() == 0 {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("batches are not replayed");
+    }
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[tokio::test]
+    async fn test_spill_and_replay_across_restart() {
+        let dir = temp_dir();
+        let server = Arc::new(Server::default());
+        let client = mock_client(server.clone());
+        let ctx = RpcContext::default().database("db".to_string());
+
+        server.down.store(true, Ordering::SeqCst);
+        let writer = ResilientWriter::open(client.clone(), &dir, spill_config()).unwrap();
+        for id in 0..5 {
+            let outcome = writer.write(&ctx, &request(id)).await.unwrap();
+            assert!(matches!(
+                outcome,
+                WriteOutcome::Spilled { batch_id, written: None, .. } if batch_id == id as u64
+            ));
+        }
+        assert_eq!(writer.pending_batches(), 5);
+        let spilled_bytes = writer.spilled_bytes();
+        drop(writer);
+
+        // The pending batches are loaded on restart, and the writes keep
+        // behind them.
+        let writer = ResilientWriter::open(client.clone(), &dir, spill_config()).unwrap();
+        assert_eq!(writer.pending_batches(), 5);
+        assert_eq!(writer.spilled_bytes(), spilled_bytes);
+        let outcome = writer.write(&ctx, &request(5)).await.unwrap();
+        assert!(matches!(outcome, WriteOutcome::Spilled { batch_id: 5, .. }));
+        assert!(server.written_ids().is_empty());
+
+        server.down.store(false, Ordering::SeqCst);
+        wait_replayed(&writer).await;
+        let outcome = writer.write(&ctx, &request(6)).await.unwrap();
+        assert!(matches!(outcome, WriteOutcome::Written(_)));
+
+        let expected: Vec<_> = (0..7).map(|id| id.to_string()).collect();
+        assert_eq!(server.written_ids(), expected);
+        let written = server.written.lock().unwrap().clone();
+        assert!(written
+            .iter()
+            .all(|(database, _)| database.as_deref() == Some("db")));
+        assert_eq!(fs::metadata(writer.path()).unwrap().len(), 0);
+        assert_eq!(writer.spilled_bytes(), 0);
+
+        drop(writer);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_spill_full_policy() {
+        let dir = temp_dir();
+        let server = Arc::new(Server::default());
+        let client = mock_client(server.clone());
+        let ctx = RpcContext::default();
+        server.down.store(true, Ordering::SeqCst);
+
+        let writer =
+            ResilientWriter::open(client.clone(), dir.join("size"), spill_config()).unwrap();
+        writer.write(&ctx, &request(0)).await.unwrap();
+        let batch_size = writer.spilled_bytes();
+        drop(writer);
+
+        let config = |full_policy| SpillConfig {
+            max_spill_bytes: 2 * batch_size,
+            full_policy,
+            ..spill_config()
+        };
+
+        let writer = ResilientWriter::open(
+            client.clone(),
+            dir.join("error"),
+            config(SpillFullPolicy::Error),
+        )
+        .unwrap();
+        writer.write(&ctx, &request(1)).await.unwrap();
+        writer.write(&ctx, &request(2)).await.unwrap();
+        let err = writer.write(&ctx, &request(3)).await.unwrap_err();
+        assert!(matches!(
+            err,
+            Error::SpillFull { size, batch_size: size_of_batch, limit }
+                if size == 2 * batch_size && size_of_batch == batch_size && limit == 2 * batch_size
+        ));
+        assert_eq!(writer.pending_batches(), 2);
+        drop(writer);
+
+        let writer = ResilientWriter::open(
+            client.clone(),
+            dir.join("drop_oldest"),
+            config(SpillFullPolicy::DropOldest),
+        )
+        .unwrap();
+        for id in 1..=3 {
+            writer.write(&ctx, &request(id)).await.unwrap();
+        }
+        assert_eq!(writer.pending_batches(), 2);
+        server.down.store(false, Ordering::SeqCst);
+        wait_replayed(&writer).await;
+        assert_eq!(server.written_ids(), vec!["2", "3"]);
+        drop(writer);
+
+        server.written.lock().unwrap().clear();
+        server.down.store(true, Ordering::SeqCst);
+        let writer = Arc::new(
+            ResilientWriter::open(
+                client.clone(),
+                dir.join("block"),
+                config(SpillFullPolicy::Block),
+            )
+            .unwrap(),
+        );
+        writer.write(&ctx, &request(1)).await.unwrap();
+        writer.write(&ctx, &request(2)).await.unwrap();
+        let blocked = {
+            let writer = writer.clone();
+            tokio::spawn(async move { writer.write(&RpcContext::default(), &request(3)).await })
+        };
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!blocked.is_finished());
+
+        server.down.store(false, Ordering::SeqCst);
+        let outcome = blocked.await.unwrap().unwrap();
+        assert!(matches!(outcome, WriteOutcome::Spilled { .. }));
+        wait_replayed(&writer).await;
+        assert_eq!(server.written_ids(), vec!["1", "2", "3"]);
+
+        drop(writer);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_truncate_corrupted_tail() {
+        let dir = temp_dir();
+        let server = Arc::new(Server::default());
+        let client = mock_client(server.clone());
+        server.down.store(true, Ordering::SeqCst);
+
+        let writer = ResilientWriter::open(client.clone(), &dir, spill_config()).unwrap();
+        for id in 0..3 {
+            writer
+                .write(&RpcContext::default(), &request(id))
+                .await
+                .unwrap();
+        }
+        let batch_size = writer.spilled_bytes() / 3;
+        drop(writer);
+
+        // The last record is torn.
+        let path = dir.join(SPILL_FILE);
+        let file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_len(3 * batch_size - 3).unwrap();
+        drop(file);
+
+        let writer = ResilientWriter::open(client.clone(), &dir, spill_config()).unwrap();
+        assert_eq!(writer.pending_batches(), 2);
+        assert_eq!(fs::metadata(&path).unwrap().len(), 2 * batch_size);
+        let outcome = writer
+            .write(&RpcContext::default(), &request(3))
+            .await
+            .unwrap();
+        assert!(matches!(outcome, WriteOutcome::Spilled { batch_id: 2, .. }));
+
+        // The record with the corrupted payload is truncated along with the
+        // records after it.
+        drop(writer);
+        let mut bytes = fs::read(&path).unwrap();
+        let offset = batch_size as usize + 20;
+        bytes[offset] ^= 0xFF;
+        fs::write(&path, bytes).unwrap();
+
+        let writer = ResilientWriter::open(client.clone(), &dir, spill_config()).unwrap();
+        assert_eq!(writer.pending_batches(), 1);
+        server.down.store(false, Ordering::SeqCst);
+        wait_replayed(&writer).await;
+        assert_eq!(server.written_ids(), vec!["0"]);
+
+        drop(writer);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_spill_failed_tables() {
+        let dir = temp_dir();
+        // The table `down` is unavailable, and the table `invalid` is rejected.
+        let client = MockDbClient::default().on_write(|_ctx, req| {
+            let results: Vec<_> = req
+                .point_groups
+                .iter()
+                .map(|(table, points)| {
+                    let result = match table.as_str() {
+                        "down" => Err(Error::Rpc(
+                            tonic::Status::unavailable("connection refused").into(),
+                        )),
+                        "invalid" => Err(Error::Server(ServerError {
+                            code: 400,
+                            msg: "invalid table".to_string(),
+                        })),
+                        _ => Ok(WriteResponse::new(points.len() as u32, 0)),
+                    };
+                    (vec![table.clone()], result)
+                })
+                .collect();
+            future::err(Error::RouteBasedWriteError(results.into()))
+        });
+        let writer = ResilientWriter::open(Arc::new(client), &dir, spill_config()).unwrap();
+
+        let mut req = WriteRequest::default();
+        for table in ["demo", "down", "invalid"] {
+            let point = PointBuilder::new(table.to_string())
+                .timestamp(1_700_000_000_000)
+                .field("request_id", Value::String(table.to_string()))
+                .build()
+                .unwrap();
+            req.add_point(point);
+        }
+        let outcome = writer.write(&RpcContext::default(), &req).await.unwrap();
+        match outcome {
+            WriteOutcome::Spilled {
+                batch_id: 0,
+                written: Some(written),
+                errors,
+            } => {
+                assert_eq!(written.success, 1);
+                assert_eq!(errors.len(), 1);
+                assert_eq!(errors[0].0, vec!["invalid".to_string()]);
+                assert!(matches!(errors[0].1, Error::Server(_)));
+            }
+            outcome => panic!("unexpected outcome:{outcome:?}"),
+        }
+        drop(writer);
+
+        // Only the unavailable table is spilled.
+        let server = Arc::new(Server::default());
+        let writer =
+            ResilientWriter::open(mock_client(server.clone()), &dir, spill_config()).unwrap();
+        wait_replayed(&writer).await;
+        assert_eq!(server.written_ids(), vec!["down"]);
+
+        drop(writer);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,152 @@
+// Copyright 2022 CeresDB Project Authors. Licensed under Apache-2.0.
+
+//! Retry policy of the requests
+
+use std::{fmt, time::Duration};
+
+use tonic::Code;
+
+use crate::Error;
+
+/// Policy deciding whether and when to retry a failed request.
+pub trait RetryPolicy: fmt::Debug + Send + Sync {
+    /// Get the backoff before the next attempt, and none means no more retry.
+    ///
+    /// `attempt` is the number of the attempts already made, starting from 1,
+    /// and `error` is the error of the last attempt, whose
+    /// [`retry_after`](Error::retry_after) hint may be honored.
+    fn next_backoff(&self, attempt: usize, error: &Error) -> Option<Duration>;
+}
+
+/// Retry the transport failures with exponentially growing backoffs.
+///
+/// Only the failures to connect and the unavailable status are retried, in
+/// which case the request is unlikely to have been handled by the server. The
+/// request rejected with a [`retry_after`](Error::retry_after) hint is retried
+/// too, after exactly the hinted delay rather than the backoff.
+#[derive(Debug, Clone)]
+pub struct ExponentialBackoff {
+    /// The backoff before the first retry.
+    pub initial_backoff: Duration,
+    /// The upper bound of the backoff.
+    pub max_backoff: Duration,
+    /// The factor by which the backoff grows for every retry.
+    pub multiplier: u32,
+    /// The max number of the retries.
+    pub max_retries: usize,
+}
+
+impl Default for ExponentialBackoff {
+    fn default() -> Self {
+        Self {
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(5),
+            multiplier: 2,
+            max_retries: 3,
+        }
+    }
+}
+
+impl ExponentialBackoff {
+    fn is_retryable(error: &Error) -> bool {
+        match error {
+            Error::Connect { .. } => true,
+            Error::Rpc(e) => e.code() == Code::Unavailable,
+            _ => false,
+        }
+    }
+}
+
+impl RetryPolicy for ExponentialBackoff {
+    fn next_backoff(&self, attempt: usize, error: &Error) -> Option<Duration> {
+        if attempt > self.max_retries {
+            return None;
+        }
+        if let Some(retry_after) = error.retry_after() {
+            return Some(retry_after);
+        }
+        if !Self::is_retryable(error) {
+            return None;
+        }
+
+        let exp = u32::try_from(attempt - 1).unwrap_or(u32::MAX);
+        let backoff = self
+            .multiplier
+            .checked_pow(exp)
+            .and_then(|factor| self.initial_backoff.checked_mul(factor))
+            .unwrap_or(self.max_backoff);
+        Some(backoff.min(self.max_backoff))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::{ExponentialBackoff, RetryPolicy};
+    use crate::Error;
+
+    #[test]
+    fn test_exponential_backoff() {
+        let policy = ExponentialBackoff {
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_millis(500),
+            multiplier: 2,
+            max_retries: 5,
+        };
+        let unavailable = Error::Rpc(tonic::Status::unavailable("connection reset").into());
+        let backoffs: Vec<_> = (1..=6)
+            .map(|attempt| policy.next_backoff(attempt, &unavailable))
+            .collect();
+        let expected = [100, 200, 400, 500, 500]
+            .map(|ms| Some(Duration::from_millis(ms)))
+            .into_iter()
+            .chain([None])
+            .collect::<Vec<_>>();
+        assert_eq!(backoffs, expected);
+
+        let connect = Error::Connect {
+            addr: "127.0.0.1:8831".to_string(),
+            source: "connection refused".into(),
+        };
+        assert!(policy.next_backoff(1, &connect).is_some());
+        assert!(policy.next_backoff(100, &connect).is_none());
+
+        let not_retryable = [
+            Error::Rpc(tonic::Status::invalid_argument("bad sql").into()),
+            Error::Client("invalid request".to_string()),
+        ];
+        for error in &not_retryable {
+            assert!(policy.next_backoff(1, error).is_none());
+        }
+    }
+
+    #[test]
+    fn test_retry_after() {
+        let policy = ExponentialBackoff {
+            max_retries: 2,
+            ..Default::default()
+        };
+        let mut metadata = tonic::metadata::MetadataMap::new();
+        metadata.insert("retry-after", "3".parse().unwrap());
+        let rate_limited = Error::Rpc(
+            tonic::Status::with_metadata(tonic::Code::ResourceExhausted, "rate limited", metadata)
+                .into(),
+        );
+
+        // The hint is used instead of the backoff, within the max retries.
+        assert_eq!(
+            policy.next_backoff(1, &rate_limited),
+            Some(Duration::from_secs(3))
+        );
+        assert_eq!(
+            policy.next_backoff(2, &rate_limited),
+            Some(Duration::from_secs(3))
+        );
+        assert_eq!(policy.next_backoff(3, &rate_limited), None);
+
+        // Not retried without the hint.
+        let exhausted = Error::Rpc(tonic::Status::resource_exhausted("rate limited").into());
+        assert_eq!(policy.next_backoff(1, &exhausted), None);
+    }
+}
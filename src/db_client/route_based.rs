@@ -2,62 +2,601 @@
 
 //! Client for route based mode
 
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::{BTreeSet, HashMap},
+    future::Future,
+    sync::{Arc, Mutex, PoisonError},
+    time::Duration,
+};
 
 use async_trait::async_trait;
 use dashmap::DashMap;
-use futures::future::join_all;
+use futures::{
+    channel::mpsc,
+    future::join_all,
+    stream::{self, BoxStream, FuturesUnordered},
+    StreamExt, TryStreamExt,
+};
 use tokio::sync::OnceCell;
 
 use crate::{
-    db_client::{inner::InnerClient, DbClient},
+    clock::{Clock, SystemClock},
+    config::{
+        CircuitBreakerConfig, HealthCheckConfig, LoadBalancePolicy, PassiveHealthConfig,
+        QueryGuardConfig, ReadYourWritesConfig, ReconnectConfig, RoutingBudget,
+    },
+    db_client::{
+        deadline::Deadline,
+        discovery::{DiscoveryProvider, DiscoveryRefresher, PrimaryRpcClient},
+        group_commit::GroupCommitter,
+        health_check::{HealthChecker, HealthProbe, HealthStates},
+        hedge::Hedger,
+        inner::InnerClient,
+        is_database_listed, paged_sql_query,
+        passive_health::PassiveHealth,
+        retry::RetryPolicy,
+        show_databases_request,
+        slow_request::SlowRequestLogger,
+        write_affinity::WriteAffinity,
+        write_stats::WriteStatsRecorder,
+        BreakerState, ChannelStats, ConnectionState, DbClient, GroupCommitStats, HedgeStats,
+        TableWriteStats,
+    },
     errors::RouteBasedWriteError,
+    metrics::ClientMetrics,
     model::{
-        route::Endpoint,
+        route::{Endpoint, Route, TableRoute},
         sql_query::{Request as SqlQueryRequest, Response as SqlQueryResponse},
+        warning::WarningHook,
         write::{Request as WriteRequest, Response as WriteResponse},
     },
-    router::{Router, RouterImpl},
-    rpc_client::{RpcClientFactory, RpcContext},
+    route_cache::RouteCache,
+    router::{
+        DefaultEndpoints, FallbackRouter, HealthFilter, InFlightCounter, OverridingRouter,
+        RelatedTables, RouteChangeHook, RouteGeneration, RouteOverrides, Router, RouterImpl,
+    },
+    rpc_client::{ErrorContextRpcClient, RpcClientFactory, RpcContext, ServerCapabilities},
+    spawner::Spawner,
     util::should_refresh,
-    Error, Result,
+    Error, Result, RpcConfig,
 };
 
+/// The cached routes used by a request, by which only the routes still of the
+/// same generations are evicted if the request fails.
+type UsedRoutes = Vec<(String, RouteGeneration)>;
+
 /// Client implementation for ceresdb while using route based mode.
+///
+/// Cloning is cheap, and the clones share the connections, the cached routes
+/// and the background tasks, which are stopped when the last clone is dropped.
+/// The client is only configured by the `with_*` methods in the
+/// [`Builder`](crate::Builder), before it is cloned.
 pub struct RouteBasedImpl<F: RpcClientFactory> {
     factory: Arc<F>,
     router_endpoint: String,
-    router: OnceCell<Box<dyn Router>>,
+    fallback_router_endpoint: Option<String>,
+    // Only the parsed `router_endpoint` unless they are discovered.
+    default_endpoints: Arc<DefaultEndpoints>,
+    discovery: Option<(Arc<dyn DiscoveryProvider>, Duration)>,
+    // Started along with the router, and stopped when the client is dropped.
+    discovery_refresher: Arc<Mutex<Option<DiscoveryRefresher>>>,
+    // Shared with the health checker, which reads the cached endpoints.
+    router: Arc<OnceCell<Box<dyn Router>>>,
     standalone_pool: DirectClientPool<F>,
     default_database: Option<String>,
+    slow_request_logger: SlowRequestLogger,
+    route_timeout: Duration,
+    route_cache_shard_amount: Option<usize>,
+    // Only used by the primary router.
+    route_cache: Option<Arc<dyn RouteCache>>,
+    routing_budget: RoutingBudget,
+    hedger: Option<Arc<Hedger>>,
+    health_check: Option<(HealthCheckConfig, Arc<dyn HealthProbe>)>,
+    health_states: Arc<HealthStates>,
+    // Started along with the router, and stopped when the client is dropped.
+    health_checker: Arc<Mutex<Option<HealthChecker>>>,
+    related_tables: Option<RelatedTables>,
+    route_change_hook: Option<RouteChangeHook>,
+    load_balance_policy: LoadBalancePolicy,
+    strict_routing: bool,
+    // Shared with the router, and changed at runtime by the clones.
+    route_overrides: Arc<RouteOverrides>,
+    group_committer: Option<Arc<GroupCommitter>>,
+    write_affinity: Option<Arc<WriteAffinity>>,
+    // The runtime of the health checker and the discovery refresher.
+    spawner: Spawner,
+    metrics: Option<ClientMetrics>,
+    clock: Arc<dyn Clock>,
 }
 
 impl<F: RpcClientFactory> RouteBasedImpl<F> {
-    pub fn new(factory: Arc<F>, router_endpoint: String, default_database: Option<String>) -> Self {
+    pub fn new(
+        factory: Arc<F>,
+        router_endpoint: String,
+        fallback_router_endpoint: Option<String>,
+        default_database: Option<String>,
+        slow_request_logger: SlowRequestLogger,
+        route_timeout: Duration,
+        max_consecutive_failures: usize,
+    ) -> Self {
+        let default_endpoints = router_endpoint.parse().into_iter().collect();
         Self {
             factory: factory.clone(),
             router_endpoint,
-            router: OnceCell::new(),
-            standalone_pool: DirectClientPool::new(factory),
+            fallback_router_endpoint,
+            default_endpoints: Arc::new(DefaultEndpoints::new(default_endpoints)),
+            discovery: None,
+            discovery_refresher: Arc::new(Mutex::new(None)),
+            router: Arc::new(OnceCell::new()),
+            standalone_pool: DirectClientPool::new(factory, max_consecutive_failures),
             default_database,
+            slow_request_logger,
+            route_timeout,
+            route_cache_shard_amount: None,
+            route_cache: None,
+            routing_budget: RoutingBudget::default(),
+            hedger: None,
+            health_check: None,
+            health_states: Arc::new(HealthStates::default()),
+            health_checker: Arc::new(Mutex::new(None)),
+            related_tables: None,
+            route_change_hook: None,
+            load_balance_policy: LoadBalancePolicy::default(),
+            strict_routing: false,
+            route_overrides: Arc::new(RouteOverrides::default()),
+            group_committer: None,
+            write_affinity: None,
+            spawner: Spawner::default(),
+            metrics: None,
+            clock: Arc::new(SystemClock),
         }
     }
 
+    /// Hedge the sql_query to another replica after `hedge_delay` with at most
+    /// `max_in_flight` hedged ones in flight, and no hedging if it is none.
+    pub(crate) fn with_hedge(
+        mut self,
+        hedge_delay: Option<Duration>,
+        max_in_flight: usize,
+    ) -> Self {
+        self.hedger = hedge_delay.map(|delay| Arc::new(Hedger::new(delay, max_in_flight)));
+        self
+    }
+
+    /// Spend at most the `routing_budget` of the timeout of a request on
+    /// routing, see [`RpcConfig::routing_budget`].
+    ///
+    /// [`RpcConfig::routing_budget`]: crate::RpcConfig::routing_budget
+    pub(crate) fn with_routing_budget(mut self, routing_budget: RoutingBudget) -> Self {
+        self.routing_budget = routing_budget;
+        self
+    }
+
+    /// Rebuild the channels to the data nodes on the first transport failure
+    /// if their endpoints are hostnames, see
+    /// [`RpcConfig::refresh_dns_on_failure`].
+    ///
+    /// [`RpcConfig::refresh_dns_on_failure`]: crate::RpcConfig::refresh_dns_on_failure
+    pub(crate) fn with_refresh_dns_on_failure(mut self, refresh_dns_on_failure: bool) -> Self {
+        self.standalone_pool.refresh_dns_on_failure = refresh_dns_on_failure;
+        self
+    }
+
+    /// Rebuild the channels to the data nodes once they are older than the
+    /// `max_channel_age`, see [`RpcConfig::max_channel_age`].
+    ///
+    /// [`RpcConfig::max_channel_age`]: crate::RpcConfig::max_channel_age
+    pub(crate) fn with_max_channel_age(mut self, max_channel_age: Option<Duration>) -> Self {
+        self.standalone_pool.max_channel_age = max_channel_age;
+        self
+    }
+
+    /// Close the channels to the data nodes without any request for the
+    /// `idle_timeout`, see [`RpcConfig::idle_timeout`].
+    ///
+    /// [`RpcConfig::idle_timeout`]: crate::RpcConfig::idle_timeout
+    pub(crate) fn with_idle_timeout(mut self, idle_timeout: Option<Duration>) -> Self {
+        self.standalone_pool.idle_timeout = idle_timeout;
+        self
+    }
+
+    /// Retry the failed requests to the data nodes according to the
+    /// `retry_policy`, and no retry if it is none.
+    pub(crate) fn with_retry_policy(mut self, retry_policy: Option<Arc<dyn RetryPolicy>>) -> Self {
+        self.standalone_pool.retry_policy = retry_policy;
+        self
+    }
+
+    /// Short-circuit the requests to the data nodes after consecutive
+    /// transport failures, and send them to the default endpoint instead, see
+    /// [`RpcConfig::circuit_breaker`].
+    ///
+    /// [`RpcConfig::circuit_breaker`]: crate::RpcConfig::circuit_breaker
+    pub(crate) fn with_circuit_breaker(mut self, config: Option<CircuitBreakerConfig>) -> Self {
+        self.standalone_pool.circuit_breaker = config;
+        self
+    }
+
+    /// Reconnect to the endpoints with the backoffs when failing to connect,
+    /// see [`RpcConfig::reconnect`].
+    ///
+    /// [`RpcConfig::reconnect`]: crate::RpcConfig::reconnect
+    pub(crate) fn with_reconnect(mut self, config: Option<ReconnectConfig>) -> Self {
+        self.standalone_pool.reconnect = config;
+        self
+    }
+
+    /// Probe the capabilities of the data nodes on every building of the
+    /// channels to them, see [`RpcConfig::negotiate_capabilities`].
+    ///
+    /// [`RpcConfig::negotiate_capabilities`]: crate::RpcConfig::negotiate_capabilities
+    pub(crate) fn with_negotiate_capabilities(mut self, negotiate_capabilities: bool) -> Self {
+        self.standalone_pool.negotiate_capabilities = negotiate_capabilities;
+        self
+    }
+
+    /// Split the writes to the data nodes with more rows than the
+    /// `max_rows_per_write`, see [`RpcConfig::max_rows_per_write`].
+    ///
+    /// [`RpcConfig::max_rows_per_write`]: crate::RpcConfig::max_rows_per_write
+    pub(crate) fn with_write_split(
+        mut self,
+        max_rows_per_write: Option<usize>,
+        concurrency: usize,
+    ) -> Self {
+        self.standalone_pool.write_split = (max_rows_per_write, concurrency);
+        self
+    }
+
+    /// Record the writes per table into the `write_stats`, and nothing is
+    /// recorded if it is none.
+    pub(crate) fn with_write_stats(mut self, write_stats: Option<Arc<WriteStatsRecorder>>) -> Self {
+        self.standalone_pool.write_stats = write_stats;
+        self
+    }
+
+    /// Call the `warning_hook` on every warning reported by the endpoints.
+    pub(crate) fn with_warning_hook(mut self, warning_hook: Option<WarningHook>) -> Self {
+        self.standalone_pool.warning_hook = warning_hook;
+        self
+    }
+
+    /// Bound the sql queries by the `query_guard`, see
+    /// [`Builder::query_guard`](crate::Builder::query_guard).
+    pub(crate) fn with_query_guard(mut self, query_guard: QueryGuardConfig) -> Self {
+        self.standalone_pool.query_guard = query_guard;
+        self
+    }
+
+    /// Record the requests, the retries, the route cache lookups, the hedged
+    /// queries and the breaker states in the `metrics`, see
+    /// [`Builder::metrics`](crate::Builder::metrics).
+    pub(crate) fn with_metrics(mut self, metrics: Option<ClientMetrics>) -> Self {
+        self.standalone_pool.metrics = metrics.clone();
+        self.metrics = metrics;
+        self
+    }
+
+    /// Probe the endpoints in the background by the `probe` according to the
+    /// `config`, and no health check if it is none, see
+    /// [`RpcConfig::health_check`].
+    ///
+    /// [`RpcConfig::health_check`]: crate::RpcConfig::health_check
+    pub(crate) fn with_health_check(
+        mut self,
+        config: Option<HealthCheckConfig>,
+        probe: Arc<dyn HealthProbe>,
+    ) -> Self {
+        self.health_check = config.map(|config| (config, probe));
+        self
+    }
+
+    /// Track the health of the endpoints by the outcomes of the requests
+    /// according to the `config`, and no tracking if it is none, see
+    /// [`RpcConfig::passive_health`].
+    ///
+    /// [`RpcConfig::passive_health`]: crate::RpcConfig::passive_health
+    pub(crate) fn with_passive_health(mut self, config: Option<PassiveHealthConfig>) -> Self {
+        self.standalone_pool.passive_health =
+            config.map(|config| Arc::new(PassiveHealth::new(config)));
+        self
+    }
+
+    /// Pin the queries on the tables to the endpoints of their latest writes
+    /// within the window, and no pinning if it is none, see
+    /// [`RpcConfig::read_your_writes`].
+    ///
+    /// [`RpcConfig::read_your_writes`]: crate::RpcConfig::read_your_writes
+    pub(crate) fn with_read_your_writes(mut self, config: Option<ReadYourWritesConfig>) -> Self {
+        self.write_affinity = config.map(|config| Arc::new(WriteAffinity::new(config)));
+        self
+    }
+
+    /// Split the route cache into `shard_amount` shards, see
+    /// [`RouterImpl::with_shard_amount`].
+    pub(crate) fn with_route_cache_shard_amount(mut self, shard_amount: Option<usize>) -> Self {
+        self.route_cache_shard_amount = shard_amount;
+        self
+    }
+
+    /// Cache the routes of the primary router in the `cache`, see
+    /// [`RouterImpl::with_route_cache`].
+    pub(crate) fn with_route_cache(mut self, cache: Option<Arc<dyn RouteCache>>) -> Self {
+        self.route_cache = cache;
+        self
+    }
+
+    /// Call the `hook` when the routes of the tables change, see
+    /// [`RouterImpl::with_route_change_hook`].
+    pub(crate) fn with_route_change_hook(mut self, hook: Option<RouteChangeHook>) -> Self {
+        self.route_change_hook = hook;
+        self
+    }
+
+    /// Prefetch the routes of the tables derived by `related_tables` on the
+    /// cache miss, see [`RouterImpl::with_related_tables`].
+    pub(crate) fn with_related_tables(mut self, related_tables: Option<RelatedTables>) -> Self {
+        self.related_tables = related_tables;
+        self
+    }
+
+    /// Discover the default endpoints by the `provider` instead of the
+    /// `router_endpoint`, and refresh them every `refresh_interval`, nothing
+    /// to do if the `provider` is none.
+    pub(crate) fn with_discovery(
+        mut self,
+        provider: Option<Arc<dyn DiscoveryProvider>>,
+        refresh_interval: Duration,
+    ) -> Self {
+        self.discovery = provider.map(|provider| (provider, refresh_interval));
+        self
+    }
+
+    /// Pick the endpoint of a table among its candidates by the `policy`, and
+    /// the requests in flight are counted by the clients to the endpoints.
+    pub(crate) fn with_load_balance_policy(mut self, policy: LoadBalancePolicy) -> Self {
+        self.load_balance_policy = policy;
+        self
+    }
+
+    /// Fail the requests on the tables unknown to the route service with
+    /// [`Error::RouteNotFound`] rather than sending them to the default
+    /// endpoint if `strict_routing` is true.
+    pub(crate) fn with_strict_routing(mut self, strict_routing: bool) -> Self {
+        self.strict_routing = strict_routing;
+        self
+    }
+
+    /// Pin the tables to the endpoints, which are routed without asking the
+    /// route service.
+    pub(crate) fn with_route_overrides(mut self, route_overrides: RouteOverrides) -> Self {
+        self.route_overrides = Arc::new(route_overrides);
+        self
+    }
+
+    /// Merge the concurrent writes to the same endpoint by the
+    /// `group_committer`, and no merging if it is none.
+    pub(crate) fn with_group_committer(
+        mut self,
+        group_committer: Option<Arc<GroupCommitter>>,
+    ) -> Self {
+        self.group_committer = group_committer;
+        self
+    }
+
+    /// Run the health checker and the discovery refresher on the runtime of the
+    /// `spawner`, see [`Builder::runtime`](crate::Builder::runtime).
+    pub(crate) fn with_spawner(mut self, spawner: Spawner) -> Self {
+        self.standalone_pool.spawner = spawner.clone();
+        self.spawner = spawner;
+        self
+    }
+
+    /// Measure the time by the `clock` instead of the system time, including
+    /// the hedge delays, the health check intervals and the discovery
+    /// intervals.
+    pub(crate) fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.standalone_pool.clock = clock.clone();
+        self.clock = clock;
+        self
+    }
+
     async fn init_router(&self) -> Result<Box<dyn Router>> {
-        let router_client = self.factory.build(self.router_endpoint.clone()).await?;
-        let default_endpoint: Endpoint = self.router_endpoint.parse().map_err(|e| {
-            Error::Client(format!(
-                "Failed to parse default endpoint:{}, err:{}",
-                self.router_endpoint, e
-            ))
-        })?;
-        Ok(Box::new(RouterImpl::new(default_endpoint, router_client)))
+        if let Some((provider, _)) = &self.discovery {
+            self.default_endpoints.refresh(provider.as_ref()).await?;
+        }
+
+        // No table is routed to the default endpoint in the strict routing.
+        let with_default_endpoint = !self.strict_routing;
+        let router = match &self.fallback_router_endpoint {
+            Some(fallback_router_endpoint) => {
+                // Only the fallback router routes the unknown tables to its default
+                // endpoint, so that the primary router can leave them to the fallback one.
+                let primary = self.build_primary_router(false).await?;
+                let secondary = self
+                    .build_router(fallback_router_endpoint, with_default_endpoint, None)
+                    .await?;
+                Box::new(FallbackRouter::new(primary, secondary))
+            }
+            None => self.build_primary_router(with_default_endpoint).await?,
+        };
+        let router = Box::new(OverridingRouter::new(self.route_overrides.clone(), router));
+        self.start_health_checker();
+        self.start_discovery_refresher();
+
+        Ok(router)
     }
-}
 
-#[async_trait]
-impl<F: RpcClientFactory> DbClient for RouteBasedImpl<F> {
-    async fn sql_query(&self, ctx: &RpcContext, req: &SqlQueryRequest) -> Result<SqlQueryResponse> {
+    /// Build the router to the `router_endpoint`, or to the primary of the
+    /// discovered default endpoints if the discovery is enabled.
+    async fn build_primary_router(&self, with_default_endpoint: bool) -> Result<Box<dyn Router>> {
+        if self.discovery.is_none() {
+            return self
+                .build_router(
+                    &self.router_endpoint,
+                    with_default_endpoint,
+                    self.route_cache.clone(),
+                )
+                .await;
+        }
+
+        let router_client = Arc::new(PrimaryRpcClient::new(
+            self.factory.clone(),
+            self.default_endpoints.clone(),
+            self.clock.clone(),
+        ));
+        let default_endpoints = with_default_endpoint.then(|| self.default_endpoints.clone());
+        Ok(Box::new(
+            RouterImpl::new(None, router_client, self.route_timeout)
+                .with_shard_amount(self.route_cache_shard_amount)
+                .with_route_cache(self.route_cache.clone())
+                .with_clock(self.clock.clone())
+                .with_default_endpoints(default_endpoints)
+                .with_related_tables(self.related_tables.clone())
+                .with_route_change_hook(self.route_change_hook.clone())
+                .with_metrics(self.metrics.clone()),
+        ))
+    }
+
+    /// Start refreshing the discovered default endpoints, and the clients to
+    /// the removed ones are dropped from the pool, nothing to do if the
+    /// discovery is disabled or the refresher has been started.
+    fn start_discovery_refresher(&self) {
+        let (provider, refresh_interval) = match &self.discovery {
+            Some(discovery) => discovery,
+            None => return,
+        };
+        let mut discovery_refresher = self
+            .discovery_refresher
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if discovery_refresher.is_some() {
+            return;
+        }
+
+        // The clients in use are kept by the requests in flight, and their channels
+        // are closed after the requests complete.
+        let pool = Arc::downgrade(&self.standalone_pool.pool);
+        let refresher = DiscoveryRefresher::start(
+            &self.spawner,
+            provider.clone(),
+            *refresh_interval,
+            self.clock.clone(),
+            self.default_endpoints.clone(),
+            move |removed| {
+                if let Some(pool) = pool.upgrade() {
+                    for endpoint in removed {
+                        pool.remove(endpoint);
+                    }
+                }
+            },
+        );
+        *discovery_refresher = Some(refresher);
+    }
+
+    /// Start the health checker probing the endpoints in the route cache and
+    /// the default endpoint, nothing to do if the health check is disabled or
+    /// the checker has been started.
+    fn start_health_checker(&self) {
+        let (config, probe) = match &self.health_check {
+            Some(health_check) => health_check,
+            None => return,
+        };
+        let mut health_checker = self
+            .health_checker
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if health_checker.is_some() {
+            return;
+        }
+
+        let router = Arc::downgrade(&self.router);
+        let default_endpoints = self.default_endpoints.clone();
+        let checker = HealthChecker::start(
+            &self.spawner,
+            config.clone(),
+            probe.clone(),
+            self.clock.clone(),
+            self.health_states.clone(),
+            move || {
+                let router = router.upgrade()?;
+                let mut endpoints = router
+                    .get()
+                    .map(|router| router.cached_endpoints())
+                    .unwrap_or_default();
+                endpoints.extend(default_endpoints.load().iter().cloned());
+                Some(endpoints)
+            },
+        );
+        *health_checker = Some(checker);
+    }
+
+    /// Fail fast if the `endpoint` is found unhealthy by the health checker.
+    fn check_healthy(&self, endpoint: &Endpoint) -> Result<()> {
+        if self.health_states.is_healthy(endpoint) {
+            Ok(())
+        } else {
+            Err(Error::EndpointUnhealthy {
+                endpoint: endpoint.to_string(),
+            })
+        }
+    }
+
+    async fn build_router(
+        &self,
+        router_endpoint: &str,
+        with_default_endpoint: bool,
+        route_cache: Option<Arc<dyn RouteCache>>,
+    ) -> Result<Box<dyn Router>> {
+        let router_client = self.factory.build(router_endpoint.to_string()).await?;
+        let router_client = Arc::new(ErrorContextRpcClient::new(
+            router_client,
+            router_endpoint.to_string(),
+            self.clock.clone(),
+        ));
+        let default_endpoint = if with_default_endpoint {
+            let endpoint: Endpoint = router_endpoint.parse().map_err(|e| {
+                Error::Client(format!(
+                    "Failed to parse default endpoint:{router_endpoint}, err:{e}"
+                ))
+            })?;
+            Some(endpoint)
+        } else {
+            None
+        };
+        let health = self
+            .standalone_pool
+            .passive_health
+            .clone()
+            .map(|passive_health| {
+                HealthFilter::new(move |endpoint| passive_health.is_healthy(endpoint))
+            });
+        let pool = self.standalone_pool.pool.clone();
+        let in_flight = InFlightCounter::new(move |endpoint| {
+            pool.get(endpoint)
+                .map(|client| client.in_flight())
+                .unwrap_or_default()
+        });
+        Ok(Box::new(
+            RouterImpl::new(default_endpoint, router_client, self.route_timeout)
+                .with_shard_amount(self.route_cache_shard_amount)
+                .with_route_cache(route_cache)
+                .with_clock(self.clock.clone())
+                .with_related_tables(self.related_tables.clone())
+                .with_route_change_hook(self.route_change_hook.clone())
+                .with_metrics(self.metrics.clone())
+                .with_load_balance_policy(self.load_balance_policy)
+                .with_in_flight_counter(Some(in_flight))
+                .with_health_filter(health),
+        ))
+    }
+
+    /// Find the client to handle the query on `req.tables`.
+    async fn route_query(
+        &self,
+        ctx: &RpcContext,
+        req: &SqlQueryRequest,
+    ) -> Result<(RpcContext, Endpoint, Arc<InnerClient<F>>, UsedRoutes)> {
         if req.tables.is_empty() {
             return Err(Error::Unknown(
                 "tables in query request can't be empty in route based mode".to_string(),
@@ -66,113 +605,541 @@ impl<F: RpcClientFactory> DbClient for RouteBasedImpl<F> {
         let ctx = crate::db_client::resolve_database(ctx, &self.default_database)?;
 
         let router_handle = self.router.get_or_try_init(|| self.init_router()).await?;
+        let routes = router_handle
+            .route_tables_with_generations(&req.tables, &ctx, false)
+            .await?;
+        let used_routes = req
+            .tables
+            .iter()
+            .zip(routes.iter())
+            .filter_map(|(table, (_, generation))| generation.map(|g| (table.clone(), g)))
+            .collect();
 
-        let endpoint = match router_handle.route(&req.tables, &ctx).await {
-            Ok(mut eps) => {
-                if let Some(ep) = eps[0].take() {
-                    ep
-                } else {
-                    return Err(Error::Unknown(
-                        "table doesn't have corresponding endpoint".to_string(),
-                    ));
-                }
+        let routed_endpoints: Vec<_> = routes
+            .into_iter()
+            .map(|(route, _)| route.into_endpoint())
+            .collect();
+        let endpoint = match routed_endpoints.first() {
+            Some(Some(ep)) => ep.clone(),
+            _ => {
+                return Err(Error::RouteNotFound {
+                    tables: req.tables[..1].to_vec(),
+                });
+            }
+        };
+        if let Some(preferred) = &ctx.preferred_endpoint {
+            let is_candidate = routed_endpoints.iter().flatten().any(|ep| ep == preferred)
+                || self.default_endpoints.load().contains(preferred);
+            if is_candidate && self.health_states.is_healthy(preferred) {
+                let client = self.standalone_pool.get_or_create(preferred);
+                return Ok((ctx.clone(), preferred.clone(), client, used_routes));
+            }
+        }
+        if let Some(pinned) = self.pinned_endpoint(&ctx, &req.tables) {
+            if self.health_states.is_healthy(&pinned) {
+                let client = self.standalone_pool.get_or_create(&pinned);
+                return Ok((ctx, pinned, client, used_routes));
+            }
+        }
+        // Query from the default endpoint instead if the routed one is unhealthy.
+        let endpoint = match self.default_endpoint_except(&endpoint) {
+            Some(default_endpoint)
+                if !self.health_states.is_healthy(&endpoint)
+                    && self.health_states.is_healthy(&default_endpoint) =>
+            {
+                default_endpoint
+            }
+            _ => endpoint,
+        };
+
+        let client = self.standalone_pool.get_or_create(&endpoint);
+        Ok((ctx, endpoint, client, used_routes))
+    }
+
+    /// Get the endpoint of the latest write of the `tables` within the window
+    /// of the read-your-writes, and none if it is disabled.
+    fn pinned_endpoint(&self, ctx: &RpcContext, tables: &[String]) -> Option<Endpoint> {
+        let write_affinity = self.write_affinity.as_ref()?;
+        let database = ctx.database.as_deref()?;
+        write_affinity.pinned_endpoint(database, tables, self.clock.now())
+    }
+
+    /// Record the tables of the `req` acknowledged by the `endpoint` for the
+    /// read-your-writes, nothing to do if it is disabled.
+    fn record_write<'a>(
+        &self,
+        ctx: &RpcContext,
+        tables: impl IntoIterator<Item = &'a String>,
+        endpoint: &Endpoint,
+    ) {
+        if let (Some(write_affinity), Some(database)) = (&self.write_affinity, &ctx.database) {
+            write_affinity.record(database, tables, endpoint, self.clock.now());
+        }
+    }
+
+    /// Evict the routes used by the failed request unless they have been
+    /// refreshed by others, nothing to do if the router is not initialized.
+    fn evict_stale(&self, used_routes: &[(String, RouteGeneration)], ctx: &RpcContext) {
+        if let Some(router_handle) = self.router.get() {
+            for (table, generation) in used_routes {
+                router_handle.evict_if_stale(table, *generation, ctx);
             }
-            Err(e) => {
-                return Err(e);
+        }
+    }
+
+    /// Evict the routes of all the tables routed to the `endpoint` at once if
+    /// it turns out to be unreachable by the error `e`, rather than evicting
+    /// them one by one as their requests fail.
+    fn evict_unreachable(&self, endpoint: &Endpoint, e: &Error) {
+        if !e.is_endpoint_unreachable() {
+            return;
+        }
+        if let Some(router_handle) = self.router.get() {
+            let _evicted = router_handle.evict_by_endpoint(endpoint);
+            #[cfg(feature = "tracing")]
+            tracing::warn!(%endpoint, evicted = _evicted, error = %e, "evict the routes to the unreachable endpoint");
+        }
+    }
+
+    /// Query from the endpoint of the tables, and the endpoint is recorded in
+    /// `target_endpoints`.
+    async fn sql_query_by_route(
+        &self,
+        ctx: &RpcContext,
+        req: &SqlQueryRequest,
+        target_endpoints: &mut Vec<Endpoint>,
+    ) -> Result<SqlQueryResponse> {
+        let deadline = Deadline::start(self.clock.as_ref(), ctx);
+        let (ctx, endpoint, client, used_routes) = deadline
+            .route(&self.routing_budget, ctx, |ctx| async move {
+                self.route_query(&ctx, req).await
+            })
+            .await?;
+        target_endpoints.push(endpoint.clone());
+        let ctx = deadline.execution_ctx(&ctx)?;
+
+        let primary = self.call_with_fallback(client, &endpoint, |client| {
+            let ctx = &ctx;
+            async move { client.sql_query_internal(ctx, req).await }
+        });
+        // The query pinned to the endpoint of the latest write isn't hedged to
+        // the endpoints that may miss the write.
+        let hedge_target = match self.pinned_endpoint(&ctx, &req.tables) {
+            Some(pinned) if pinned == endpoint => None,
+            _ => self.hedge_target(&ctx, &req.tables, &endpoint).await,
+        };
+        let result = match hedge_target {
+            Some((hedger, hedge_endpoint)) => {
+                let hedge = || async {
+                    let hedge_client = self.standalone_pool.get_or_create(&hedge_endpoint);
+                    hedge_client.sql_query_internal(&ctx, req).await
+                };
+                let metrics = self.metrics.as_ref();
+                hedger
+                    .run(self.clock.as_ref(), metrics, primary, hedge)
+                    .await
             }
+            None => primary.await,
         };
 
-        let client = self.standalone_pool.get_or_create(&endpoint).clone();
+        deadline.tag_execution(result).map_err(|e| {
+            self.evict_unreachable(&endpoint, &e);
+            self.evict_stale(&used_routes, &ctx);
+            self.note_route_override(&ctx, &req.tables, e)
+        })
+    }
+
+    /// Wrap the error of the request on the `tables` in
+    /// [`Error::RouteOverridden`] if any of them is pinned by the route
+    /// overrides, whose route is not refreshed on the failure.
+    fn note_route_override(&self, ctx: &RpcContext, tables: &[String], e: Error) -> Error {
+        let database = ctx.database.as_deref();
+        let overridden = tables.iter().find_map(|table| {
+            let endpoint = self.route_overrides.get(database, table)?;
+            Some((table, endpoint))
+        });
+        match overridden {
+            Some((table, endpoint)) => Error::RouteOverridden {
+                table: table.clone(),
+                endpoint: endpoint.to_string(),
+                source: Box::new(e),
+            },
+            None => e,
+        }
+    }
+
+    /// Get the hedger and the endpoint to hedge the query of the `tables`
+    /// routed to `endpoint`, which is another replica of the first table, and
+    /// the healthy ones are preferred.
+    ///
+    /// None if hedging is disabled or the table has no other replica, e.g. it
+    /// is routed to the default endpoint.
+    async fn hedge_target(
+        &self,
+        ctx: &RpcContext,
+        tables: &[String],
+        endpoint: &Endpoint,
+    ) -> Option<(&Hedger, Endpoint)> {
+        let hedger = self.hedger.as_deref()?;
+        let router_handle = self.router.get()?;
+        let table = tables.first()?;
+        let replicas = router_handle
+            .route_replicas(std::slice::from_ref(table), ctx)
+            .await
+            .ok()?;
+        let route = replicas.into_iter().next().flatten()?;
+        let others: Vec<_> = route
+            .replicas
+            .into_iter()
+            .map(|replica| replica.endpoint)
+            .filter(|other| other != endpoint)
+            .collect();
+        let hedge_endpoint = others
+            .iter()
+            .find(|other| self.health_states.is_healthy(other))
+            .or_else(|| others.first())?
+            .clone();
+
+        Some((hedger, hedge_endpoint))
+    }
+
+    /// Get a default endpoint other than `endpoint` to fail over to, and the
+    /// healthy ones are preferred. None if there is no other default endpoint.
+    fn default_endpoint_except(&self, endpoint: &Endpoint) -> Option<Endpoint> {
+        let default_endpoints = self.default_endpoints.load();
+        let mut others = default_endpoints.iter().filter(|other| *other != endpoint);
+        others
+            .clone()
+            .find(|other| self.health_states.is_healthy(other))
+            .or_else(|| others.next())
+            .cloned()
+    }
+
+    /// Call with the `client` to `endpoint`, and call again with the client to
+    /// the default endpoint if the circuit breaker of `endpoint` is open.
+    async fn call_with_fallback<T, C, Fut>(
+        &self,
+        client: Arc<InnerClient<F>>,
+        endpoint: &Endpoint,
+        call: C,
+    ) -> Result<T>
+    where
+        C: Fn(Arc<InnerClient<F>>) -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        match call(client).await {
+            Err(e @ Error::CircuitOpen { .. }) => match self.default_endpoint_except(endpoint) {
+                Some(fallback_endpoint) => {
+                    call(self.standalone_pool.get_or_create(&fallback_endpoint)).await
+                }
+                None => Err(e),
+            },
+            result => result,
+        }
+    }
 
-        client.sql_query_internal(&ctx, req).await.map_err(|e| {
-            router_handle.evict(&req.tables);
-            e
+    /// Write the `req` to the `endpoint`, and to the default endpoint instead
+    /// if the circuit breaker of the `endpoint` is open.
+    async fn write_to_endpoint(
+        &self,
+        ctx: &RpcContext,
+        endpoint: &Endpoint,
+        req: &WriteRequest,
+    ) -> Result<WriteResponse> {
+        let client = self.standalone_pool.get_or_create(endpoint);
+        self.call_with_fallback(client, endpoint, |client| async move {
+            let resp = client.write_internal(ctx, req).await?;
+            // The write may be acknowledged by the default endpoint instead.
+            if let Ok(acked_endpoint) = client.endpoint().parse::<Endpoint>() {
+                self.record_write(ctx, req.point_groups.keys(), &acked_endpoint);
+            }
+            Ok(resp)
         })
+        .await
     }
 
-    async fn write(&self, ctx: &RpcContext, req: &WriteRequest) -> Result<WriteResponse> {
+    /// Write to the endpoints of the tables, and the endpoints are recorded in
+    /// `target_endpoints`.
+    async fn write_by_route(
+        &self,
+        ctx: &RpcContext,
+        req: &WriteRequest,
+        target_endpoints: &mut Vec<Endpoint>,
+    ) -> Result<WriteResponse> {
         let ctx = crate::db_client::resolve_database(ctx, &self.default_database)?;
+        // Validate and normalize before any rpc, so the invalid request is rejected
+        // as a whole.
+        let (req, dropped) = req.prepare()?;
+        let req = req.as_ref();
 
         // Get tables' related endpoints(some may not exist).
         let should_routes: Vec<_> = req.point_groups.keys().cloned().collect();
-        let router_handle = self.router.get_or_try_init(|| self.init_router()).await?;
-        let endpoints = router_handle.route(&should_routes, &ctx).await?;
+        let deadline = Deadline::start(self.clock.as_ref(), &ctx);
+        let routes = deadline
+            .route(&self.routing_budget, &ctx, |ctx| {
+                let should_routes = &should_routes;
+                async move {
+                    let router_handle = self.router.get_or_try_init(|| self.init_router()).await?;
+                    router_handle
+                        .route_tables_with_generations(should_routes, &ctx, false)
+                        .await
+                }
+            })
+            .await?;
+        let exec_ctx = deadline.execution_ctx(&ctx)?;
 
-        // Partition write entries in request according to related endpoints.
+        // Partition write entries in request according to related endpoints, and
+        // remember the generations of the cached routes used.
         let mut no_corresponding_endpoints = Vec::new();
         let mut partition_by_endpoint = HashMap::new();
-        endpoints
+        let mut generations = HashMap::new();
+        routes
             .into_iter()
             .zip(should_routes.into_iter())
-            .for_each(|(ep, m)| match ep {
-                Some(ep) => {
-                    let write_req = partition_by_endpoint
-                        .entry(ep)
-                        .or_insert_with(WriteRequest::default);
-                    write_req.point_groups.insert(
-                        m.clone(),
-                        req.point_groups.get(m.as_str()).cloned().unwrap(),
-                    );
+            .for_each(|((route, generation), m)| {
+                if let Some(generation) = generation {
+                    generations.insert(m.clone(), generation);
                 }
-                None => {
-                    no_corresponding_endpoints.push(m);
+                let points = match req.point_groups.get(m.as_str()) {
+                    Some(points) => points.clone(),
+                    None => return,
+                };
+                match route.into_endpoint() {
+                    Some(ep) => {
+                        partition_by_endpoint
+                            .entry(ep)
+                            .or_insert_with(|| req.empty_like())
+                            .point_groups
+                            .insert(m.clone(), points);
+                    }
+                    None => {
+                        no_corresponding_endpoints.push(m);
+                    }
                 }
             });
 
-        // Get client and send.
-        let mut write_tables = vec![Vec::new(); partition_by_endpoint.len()];
-        let client_req_paris: Vec<_> = partition_by_endpoint
-            .into_iter()
-            .enumerate()
-            .map(|(idx, (ep, req))| {
-                assert!(idx < write_tables.len());
-                write_tables[idx].extend(req.point_groups.keys().cloned());
-                (self.standalone_pool.get_or_create(&ep), req)
-            })
-            .collect();
-        let mut futures = Vec::with_capacity(client_req_paris.len());
-        for (client, req) in client_req_paris {
-            let ctx_clone = ctx.clone();
-            futures.push(async move { client.write_internal(&ctx_clone, &req).await })
+        // Reject the request as a whole before any rpc in the strict routing.
+        if self.strict_routing && !no_corresponding_endpoints.is_empty() {
+            return Err(Error::RouteNotFound {
+                tables: no_corresponding_endpoints,
+            });
         }
 
-        // Await rpc results and collect results.
-        let mut tables_result_pairs: Vec<_> = join_all(futures)
-            .await
+        let partitions = WritePartition::from_endpoints(partition_by_endpoint);
+        target_endpoints.extend(partitions.iter().map(|p| p.endpoint.clone()));
+
+        // The writes to all the endpoints share the deadline, and the ones still
+        // outstanding at the deadline are cancelled, while the results collected
+        // so far are kept. A partition failed or timed out on its own doesn't
+        // affect the others.
+        let futures = partitions.iter().map(|partition| {
+            let (ctx, endpoint, req) = (&exec_ctx, &partition.endpoint, &partition.req);
+            deadline.execute(async move {
+                self.check_healthy(endpoint)?;
+                match &self.group_committer {
+                    Some(group_committer) => {
+                        let (this, endpoint_to_send) = (self.clone(), endpoint.clone());
+                        let send = move |ctx: RpcContext, req: WriteRequest| async move {
+                            this.write_to_endpoint(&ctx, &endpoint_to_send, &req).await
+                        };
+                        group_committer
+                            .write(endpoint, ctx, req.clone(), send)
+                            .await
+                    }
+                    None => self.write_to_endpoint(ctx, endpoint, req).await,
+                }
+            })
+        });
+
+        // Every partition contributes only the result of its last attempt, in the
+        // order of the partitions.
+        let results = join_all(futures).await;
+        for (partition, result) in partitions.iter().zip(&results) {
+            if let Err(e) = result {
+                self.evict_unreachable(&partition.endpoint, e);
+            }
+        }
+        let mut tables_result_pairs: Vec<_> = partitions
             .into_iter()
-            .zip(write_tables.into_iter())
-            .map(|(results, tables)| (tables, results))
+            .zip(results)
+            .map(|(partition, result)| (partition.tables, result))
             .collect();
 
         if !no_corresponding_endpoints.is_empty() {
             tables_result_pairs.push((
-                no_corresponding_endpoints,
-                Err(Error::Unknown(
-                    "tables don't have corresponding endpoints".to_string(),
-                )),
+                no_corresponding_endpoints.clone(),
+                Err(Error::RouteNotFound {
+                    tables: no_corresponding_endpoints,
+                }),
             ));
         }
 
+        match self.merge_write_results(&ctx, tables_result_pairs, generations) {
+            Ok(mut resp) => {
+                dropped.count_in(&mut resp);
+                Ok(resp)
+            }
+            Err(Error::RouteBasedWriteError(mut e)) => {
+                dropped.count_in(&mut e.ok.1);
+                Err(Error::RouteBasedWriteError(e))
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Dispatch the requests in `reqs` to the write streams to the endpoints of
+    /// their tables, and the stream to an endpoint is opened on the first
+    /// request routed to it.
+    async fn write_stream_by_route(
+        &self,
+        ctx: &RpcContext,
+        reqs: BoxStream<'_, WriteRequest>,
+    ) -> Result<WriteResponse> {
+        let ctx = crate::db_client::resolve_database(ctx, &self.default_database)?;
+        let router_handle = self.router.get_or_try_init(|| self.init_router()).await?;
+
+        let mut senders = HashMap::new();
+        let mut tables_by_endpoint: HashMap<Endpoint, BTreeSet<String>> = HashMap::new();
+        let mut no_corresponding_endpoints = BTreeSet::new();
+        let mut generations = HashMap::new();
+        let mut streams = FuturesUnordered::new();
+        let mut endpoint_results = Vec::new();
+        let mut reqs = reqs.fuse();
+        loop {
+            futures::select! {
+                req = reqs.next() => {
+                    let mut req = match req {
+                        Some(req) => req,
+                        None => break,
+                    };
+                    let should_routes: Vec<_> = req.point_groups.keys().cloned().collect();
+                    let routes = router_handle
+                        .route_tables_with_generations(&should_routes, &ctx, false)
+                        .await?;
+
+                    let mut partition_by_endpoint = HashMap::new();
+                    for ((route, generation), table) in routes.into_iter().zip(should_routes) {
+                        if let Some(generation) = generation {
+                            generations.insert(table.clone(), generation);
+                        }
+                        let points = match req.point_groups.remove(&table) {
+                            Some(points) => points,
+                            None => continue,
+                        };
+                        match route.into_endpoint() {
+                            Some(ep) => {
+                                partition_by_endpoint
+                                    .entry(ep)
+                                    .or_insert_with(|| req.empty_like())
+                                    .point_groups
+                                    .insert(table, points);
+                            }
+                            None => {
+                                no_corresponding_endpoints.insert(table);
+                            }
+                        }
+                    }
+
+                    if self.strict_routing && !no_corresponding_endpoints.is_empty() {
+                        return Err(Error::RouteNotFound {
+                            tables: no_corresponding_endpoints.into_iter().collect(),
+                        });
+                    }
+
+                    for (ep, partition) in partition_by_endpoint {
+                        tables_by_endpoint
+                            .entry(ep.clone())
+                            .or_default()
+                            .extend(partition.point_groups.keys().cloned());
+                        let sender = senders.entry(ep.clone()).or_insert_with(|| {
+                            let (tx, rx) = mpsc::unbounded();
+                            let client = self.standalone_pool.get_or_create(&ep);
+                            let healthy = self.check_healthy(&ep);
+                            let ctx = &ctx;
+                            streams.push(async move {
+                                let result = match healthy {
+                                    Ok(()) => client.write_stream_internal(ctx, rx).await,
+                                    Err(e) => Err(e),
+                                };
+                                (ep, result)
+                            });
+                            tx
+                        });
+                        // The stream is closed if it has failed, and the failure is
+                        // reported by it.
+                        let _ = sender.unbounded_send(partition);
+                    }
+                }
+                endpoint_result = streams.select_next_some() => endpoint_results.push(endpoint_result),
+            }
+        }
+
+        // Close all the streams and wait for them.
+        drop(senders);
+        while let Some(endpoint_result) = streams.next().await {
+            endpoint_results.push(endpoint_result);
+        }
+
+        for (ep, result) in &endpoint_results {
+            match result {
+                Ok(_) => {
+                    let tables = tables_by_endpoint.get(ep).into_iter().flatten();
+                    self.record_write(&ctx, tables, ep);
+                }
+                Err(e) => self.evict_unreachable(ep, e),
+            }
+        }
+        let mut tables_result_pairs: Vec<_> = endpoint_results
+            .into_iter()
+            .map(|(ep, result)| {
+                let tables = tables_by_endpoint.remove(&ep).unwrap_or_default();
+                (tables.into_iter().collect(), result)
+            })
+            .collect();
+        if !no_corresponding_endpoints.is_empty() {
+            let tables: Vec<_> = no_corresponding_endpoints.into_iter().collect();
+            tables_result_pairs.push((tables.clone(), Err(Error::RouteNotFound { tables })));
+        }
+
+        self.merge_write_results(&ctx, tables_result_pairs, generations)
+    }
+
+    /// Merge the results of the writes to the endpoints, and evict the routes
+    /// of the tables failed for the outdated routes or the unhealthy
+    /// endpoints.
+    fn merge_write_results(
+        &self,
+        ctx: &RpcContext,
+        tables_result_pairs: Vec<(Vec<String>, Result<WriteResponse>)>,
+        mut generations: HashMap<String, RouteGeneration>,
+    ) -> Result<WriteResponse> {
         // Process results:
-        //  + Evict outdated endpoints.
+        //  + Evict outdated endpoints, unless they have been refreshed by others.
         //  + Merge results and return.
         let evicts: Vec<_> = tables_result_pairs
             .iter()
-            .filter_map(|(tables, result)| {
-                if let Err(Error::Server(server_error)) = &result {
-                    if should_refresh(server_error.code, &server_error.msg) {
-                        Some(tables.clone())
-                    } else {
-                        None
-                    }
-                } else {
-                    None
+            .filter_map(|(tables, result)| match result {
+                Err(Error::Server(server_error))
+                    if should_refresh(server_error.code, &server_error.msg) =>
+                {
+                    Some(tables.clone())
                 }
+                // The tables may have been moved away from the unhealthy endpoint.
+                Err(Error::EndpointUnhealthy { .. }) => Some(tables.clone()),
+                _ => None,
             })
             .flatten()
+            .filter_map(|table| generations.remove(&table).map(|g| (table, g)))
             .collect();
-        router_handle.evict(&evicts);
+        self.evict_stale(&evicts, ctx);
 
+        let tables_result_pairs: Vec<_> = tables_result_pairs
+            .into_iter()
+            .map(|(tables, result)| {
+                let result = result.map_err(|e| self.note_route_override(ctx, &tables, e));
+                (tables, result)
+            })
+            .collect();
         let route_based_error: RouteBasedWriteError = tables_result_pairs.into();
         if route_based_error.all_ok() {
             Ok(route_based_error.ok.1)
@@ -182,33 +1149,2298 @@ impl<F: RpcClientFactory> DbClient for RouteBasedImpl<F> {
     }
 }
 
-/// DirectClientPool is the pool actually holding connections to data nodes.
-struct DirectClientPool<F: RpcClientFactory> {
-    pool: DashMap<Endpoint, Arc<InnerClient<F>>>,
-    factory: Arc<F>,
+/// Sub-request of a write partitioned by the endpoints of the tables, which is
+/// identified by its index among the partitions sorted by the endpoints.
+struct WritePartition {
+    endpoint: Endpoint,
+    tables: Vec<String>,
+    req: WriteRequest,
 }
 
-impl<F: RpcClientFactory> DirectClientPool<F> {
-    fn new(factory: Arc<F>) -> Self {
+impl WritePartition {
+    fn from_endpoints(partition_by_endpoint: HashMap<Endpoint, WriteRequest>) -> Vec<Self> {
+        let mut partitions: Vec<_> = partition_by_endpoint
+            .into_iter()
+            .map(|(endpoint, req)| {
+                let mut tables: Vec<_> = req.point_groups.keys().cloned().collect();
+                tables.sort();
+                Self {
+                    endpoint,
+                    tables,
+                    req,
+                }
+            })
+            .collect();
+        partitions.sort_by_cached_key(|partition| partition.endpoint.to_string());
+
+        partitions
+    }
+}
+
+impl<F: RpcClientFactory> Clone for RouteBasedImpl<F> {
+    fn clone(&self) -> Self {
         Self {
-            pool: DashMap::new(),
-            factory,
+            factory: self.factory.clone(),
+            router_endpoint: self.router_endpoint.clone(),
+            fallback_router_endpoint: self.fallback_router_endpoint.clone(),
+            default_endpoints: self.default_endpoints.clone(),
+            discovery: self.discovery.clone(),
+            discovery_refresher: self.discovery_refresher.clone(),
+            router: self.router.clone(),
+            standalone_pool: self.standalone_pool.clone(),
+            default_database: self.default_database.clone(),
+            slow_request_logger: self.slow_request_logger.clone(),
+            route_timeout: self.route_timeout,
+            route_cache_shard_amount: self.route_cache_shard_amount,
+            route_cache: self.route_cache.clone(),
+            routing_budget: self.routing_budget.clone(),
+            hedger: self.hedger.clone(),
+            health_check: self.health_check.clone(),
+            health_states: self.health_states.clone(),
+            health_checker: self.health_checker.clone(),
+            related_tables: self.related_tables.clone(),
+            route_change_hook: self.route_change_hook.clone(),
+            load_balance_policy: self.load_balance_policy,
+            strict_routing: self.strict_routing,
+            route_overrides: self.route_overrides.clone(),
+            group_committer: self.group_committer.clone(),
+            write_affinity: self.write_affinity.clone(),
+            spawner: self.spawner.clone(),
+            metrics: self.metrics.clone(),
+            clock: self.clock.clone(),
         }
     }
+}
 
-    fn get_or_create(&self, endpoint: &Endpoint) -> Arc<InnerClient<F>> {
-        if let Some(c) = self.pool.get(endpoint) {
-            // If exist in cache, return.
-            c.value().clone()
-        } else {
-            // If not exist, build --> insert --> return.
-            self.pool
-                .entry(endpoint.clone())
-                .or_insert(Arc::new(InnerClient::new(
-                    self.factory.clone(),
-                    endpoint.to_string(),
-                )))
-                .clone()
-        }
+#[async_trait]
+impl<F: RpcClientFactory> DbClient for RouteBasedImpl<F> {
+    async fn sql_query(&self, ctx: &RpcContext, req: &SqlQueryRequest) -> Result<SqlQueryResponse> {
+        let begin = self.clock.now();
+        let mut endpoints = Vec::new();
+        let result = self.sql_query_by_route(ctx, req, &mut endpoints).await;
+
+        self.slow_request_logger.observe_sql_query(
+            ctx.database.as_ref().or(self.default_database.as_ref()),
+            req,
+            endpoints,
+            self.clock.now() - begin,
+            &result,
+        );
+        result
+    }
+
+    async fn write(&self, ctx: &RpcContext, req: &WriteRequest) -> Result<WriteResponse> {
+        let begin = self.clock.now();
+        let mut endpoints = Vec::new();
+        let result = self.write_by_route(ctx, req, &mut endpoints).await;
+
+        self.slow_request_logger.observe_write(
+            ctx.database.as_ref().or(self.default_database.as_ref()),
+            req,
+            endpoints,
+            self.clock.now() - begin,
+            &result,
+        );
+        result
+    }
+
+    async fn write_stream(
+        &self,
+        ctx: &RpcContext,
+        reqs: BoxStream<'_, WriteRequest>,
+    ) -> Result<WriteResponse> {
+        self.write_stream_by_route(ctx, reqs).await
+    }
+
+    fn sql_query_paged<'a>(
+        &'a self,
+        ctx: &'a RpcContext,
+        req: &'a SqlQueryRequest,
+        page_size: usize,
+    ) -> BoxStream<'a, Result<SqlQueryResponse>> {
+        // Route only once, and all the pages are queried from the same endpoint.
+        let pages = async move {
+            let (ctx, endpoint, client, used_routes) = self.route_query(ctx, req).await?;
+            let evict_ctx = ctx.clone();
+            let cancel = ctx.cancel.clone();
+            let pages = paged_sql_query(req, page_size, cancel, move |page_req| {
+                let ctx = ctx.clone();
+                let client = client.clone();
+                async move { client.sql_query_internal(&ctx, &page_req).await }
+            });
+
+            Ok(pages.inspect_err(move |e| {
+                self.evict_unreachable(&endpoint, e);
+                self.evict_stale(&used_routes, &evict_ctx)
+            }))
+        };
+
+        stream::once(pages).try_flatten().boxed()
+    }
+
+    async fn check_database(&self, ctx: &RpcContext) -> Result<bool> {
+        let ctx = crate::db_client::resolve_database(ctx, &self.default_database)?;
+        // The default endpoints are discovered along with the router.
+        self.router.get_or_try_init(|| self.init_router()).await?;
+        // The databases are listed by any endpoint, as there is no table to route.
+        let endpoint = self.default_endpoints.primary().ok_or_else(|| {
+            Error::Client("no default endpoint to list the databases".to_string())
+        })?;
+        let client = self.standalone_pool.get_or_create(&endpoint);
+        let resp = client
+            .sql_query_internal(&ctx, &show_databases_request())
+            .await?;
+
+        Ok(is_database_listed(
+            &resp,
+            ctx.database.as_deref().unwrap_or_default(),
+        ))
+    }
+
+    fn config(&self) -> RpcConfig {
+        self.factory.config()
+    }
+
+    fn clock(&self) -> Arc<dyn Clock> {
+        self.clock.clone()
+    }
+
+    fn connection_states(&self) -> Vec<(Endpoint, ConnectionState)> {
+        self.standalone_pool
+            .pool
+            .iter()
+            .map(|entry| (entry.key().clone(), entry.value().connection_state()))
+            .collect()
+    }
+
+    fn channel_stats(&self) -> Vec<(Endpoint, ChannelStats)> {
+        self.standalone_pool
+            .pool
+            .iter()
+            .map(|entry| (entry.key().clone(), entry.value().channel_stats()))
+            .collect()
+    }
+
+    fn circuit_breaker_states(&self) -> Vec<(Endpoint, BreakerState)> {
+        self.standalone_pool
+            .pool
+            .iter()
+            .filter_map(|entry| {
+                let state = entry.value().breaker_state()?;
+                Some((entry.key().clone(), state))
+            })
+            .collect()
+    }
+
+    async fn resolve_route_uncached(
+        &self,
+        ctx: &RpcContext,
+        table: &str,
+    ) -> Result<Option<Endpoint>> {
+        let ctx = crate::db_client::resolve_database(ctx, &self.default_database)?;
+        let router_handle = self.router.get_or_try_init(|| self.init_router()).await?;
+
+        router_handle.resolve_uncached(table, &ctx).await
+    }
+
+    async fn route_tables(
+        &self,
+        ctx: &RpcContext,
+        tables: &[String],
+        force_refresh: bool,
+    ) -> Result<HashMap<String, TableRoute>> {
+        let ctx = crate::db_client::resolve_database(ctx, &self.default_database)?;
+        let router_handle = self.router.get_or_try_init(|| self.init_router()).await?;
+        let routes = router_handle
+            .route_tables(tables, &ctx, force_refresh)
+            .await?;
+
+        Ok(tables.iter().cloned().zip(routes).collect())
+    }
+
+    async fn route_replicas(
+        &self,
+        ctx: &RpcContext,
+        tables: &[String],
+    ) -> Result<HashMap<String, Route>> {
+        let ctx = crate::db_client::resolve_database(ctx, &self.default_database)?;
+        let router_handle = self.router.get_or_try_init(|| self.init_router()).await?;
+        let replicas = router_handle.route_replicas(tables, &ctx).await?;
+
+        Ok(replicas
+            .into_iter()
+            .flatten()
+            .map(|route| (route.table.clone(), route))
+            .collect())
+    }
+
+    fn add_route_override(
+        &self,
+        database: Option<&str>,
+        table: &str,
+        endpoint: Endpoint,
+    ) -> Result<Option<Endpoint>> {
+        if table.is_empty() {
+            return Err(Error::Client(
+                "table of route override can't be empty".to_string(),
+            ));
+        }
+
+        Ok(self.route_overrides.insert(database, table, endpoint))
+    }
+
+    fn remove_route_override(
+        &self,
+        database: Option<&str>,
+        table: &str,
+    ) -> Result<Option<Endpoint>> {
+        Ok(self.route_overrides.remove(database, table))
+    }
+
+    fn server_capabilities(&self, endpoint: &Endpoint) -> Option<ServerCapabilities> {
+        self.standalone_pool
+            .pool
+            .get(endpoint)?
+            .value()
+            .server_capabilities()
+    }
+
+    fn is_healthy(&self, endpoint: &Endpoint) -> bool {
+        match &self.standalone_pool.passive_health {
+            Some(passive_health) => passive_health.is_healthy(endpoint),
+            None => true,
+        }
+    }
+
+    fn last_write_endpoint(&self, ctx: &RpcContext, table: &str) -> Option<Endpoint> {
+        let ctx = crate::db_client::resolve_database(ctx, &self.default_database).ok()?;
+        let database = ctx.database.as_deref()?;
+        self.write_affinity
+            .as_ref()?
+            .endpoint(database, table, self.clock.now())
+    }
+
+    fn evict_routes_by_endpoint(&self, endpoint: &Endpoint) -> Result<usize> {
+        Ok(self
+            .router
+            .get()
+            .map_or(0, |router_handle| router_handle.evict_by_endpoint(endpoint)))
+    }
+
+    fn hedge_stats(&self) -> HedgeStats {
+        self.hedger
+            .as_deref()
+            .map(Hedger::stats)
+            .unwrap_or_default()
+    }
+
+    fn group_commit_stats(&self) -> GroupCommitStats {
+        self.group_committer
+            .as_deref()
+            .map(GroupCommitter::stats)
+            .unwrap_or_default()
+    }
+
+    fn write_stats(&self) -> Vec<TableWriteStats> {
+        self.standalone_pool
+            .write_stats
+            .as_deref()
+            .map(WriteStatsRecorder::stats)
+            .unwrap_or_default()
+    }
+
+    fn reset_write_stats(&self) {
+        if let Some(write_stats) = &self.standalone_pool.write_stats {
+            write_stats.reset();
+        }
+    }
+}
+
+/// DirectClientPool is the pool actually holding connections to data nodes.
+struct DirectClientPool<F: RpcClientFactory> {
+    // Shared with the discovery refresher, which removes the clients to the
+    // removed default endpoints.
+    pool: Arc<DashMap<Endpoint, Arc<InnerClient<F>>>>,
+    factory: Arc<F>,
+    max_consecutive_failures: usize,
+    refresh_dns_on_failure: bool,
+    max_channel_age: Option<Duration>,
+    idle_timeout: Option<Duration>,
+    retry_policy: Option<Arc<dyn RetryPolicy>>,
+    circuit_breaker: Option<CircuitBreakerConfig>,
+    passive_health: Option<Arc<PassiveHealth>>,
+    reconnect: Option<ReconnectConfig>,
+    negotiate_capabilities: bool,
+    spawner: Spawner,
+    // The max rows per write and the concurrency of the sub-requests.
+    write_split: (Option<usize>, usize),
+    write_stats: Option<Arc<WriteStatsRecorder>>,
+    warning_hook: Option<WarningHook>,
+    query_guard: QueryGuardConfig,
+    metrics: Option<ClientMetrics>,
+    clock: Arc<dyn Clock>,
+}
+
+impl<F: RpcClientFactory> Clone for DirectClientPool<F> {
+    fn clone(&self) -> Self {
+        Self {
+            pool: self.pool.clone(),
+            factory: self.factory.clone(),
+            max_consecutive_failures: self.max_consecutive_failures,
+            refresh_dns_on_failure: self.refresh_dns_on_failure,
+            max_channel_age: self.max_channel_age,
+            idle_timeout: self.idle_timeout,
+            retry_policy: self.retry_policy.clone(),
+            circuit_breaker: self.circuit_breaker.clone(),
+            passive_health: self.passive_health.clone(),
+            reconnect: self.reconnect.clone(),
+            negotiate_capabilities: self.negotiate_capabilities,
+            spawner: self.spawner.clone(),
+            write_split: self.write_split,
+            write_stats: self.write_stats.clone(),
+            warning_hook: self.warning_hook.clone(),
+            query_guard: self.query_guard.clone(),
+            metrics: self.metrics.clone(),
+            clock: self.clock.clone(),
+        }
+    }
+}
+
+impl<F: RpcClientFactory> DirectClientPool<F> {
+    fn new(factory: Arc<F>, max_consecutive_failures: usize) -> Self {
+        Self {
+            pool: Arc::new(DashMap::new()),
+            factory,
+            max_consecutive_failures,
+            refresh_dns_on_failure: false,
+            max_channel_age: None,
+            idle_timeout: None,
+            retry_policy: None,
+            circuit_breaker: None,
+            passive_health: None,
+            reconnect: None,
+            negotiate_capabilities: false,
+            spawner: Spawner::default(),
+            write_split: (None, 1),
+            write_stats: None,
+            warning_hook: None,
+            query_guard: QueryGuardConfig::default(),
+            metrics: None,
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    fn get_or_create(&self, endpoint: &Endpoint) -> Arc<InnerClient<F>> {
+        if let Some(c) = self.pool.get(endpoint) {
+            // If exist in cache, return.
+            c.value().clone()
+        } else {
+            // If not exist, build --> insert --> return.
+            self.pool
+                .entry(endpoint.clone())
+                .or_insert(Arc::new(
+                    InnerClient::new(
+                        self.factory.clone(),
+                        endpoint.to_string(),
+                        self.max_consecutive_failures,
+                    )
+                    .with_refresh_dns_on_failure(self.refresh_dns_on_failure)
+                    .with_max_channel_age(self.max_channel_age)
+                    .with_idle_timeout(self.idle_timeout)
+                    .with_retry_policy(self.retry_policy.clone())
+                    .with_circuit_breaker(self.circuit_breaker.clone())
+                    .with_passive_health(self.passive_health.clone())
+                    .with_reconnect(self.reconnect.clone())
+                    .with_negotiate_capabilities(self.negotiate_capabilities)
+                    .with_spawner(self.spawner.clone())
+                    .with_write_split(self.write_split.0, self.write_split.1)
+                    .with_write_stats(self.write_stats.clone())
+                    .with_warning_hook(self.warning_hook.clone())
+                    .with_query_guard(self.query_guard.clone())
+                    .with_metrics(self.metrics.clone())
+                    .with_clock(self.clock.clone()),
+                ))
+                .clone()
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        collections::{HashMap, HashSet},
+        sync::{
+            atomic::{AtomicU64, Ordering},
+            Arc, Mutex,
+        },
+        time::{Duration, Instant},
+    };
+
+    use async_trait::async_trait;
+    use ceresdbproto::storage::{
+        sql_query_response::Output, SqlQueryResponse as QueryResponsePb,
+        WriteRequest as WriteRequestPb, WriteResponse as WriteResponsePb,
+    };
+    use dashmap::{DashMap, DashSet};
+    use futures::{
+        future::{self, join_all},
+        stream, StreamExt,
+    };
+    use prost::Message;
+
+    use super::RouteBasedImpl;
+    use crate::{
+        capture::{CapturingRpcClient, RequestCapture},
+        clock::{ManualClock, SystemClock},
+        config::{
+            CircuitBreakerConfig, GroupCommitConfig, HealthCheckConfig, ReadYourWritesConfig,
+            RoutingBudget, WriteStatsConfig,
+        },
+        db_client::{
+            group_commit::GroupCommitter, slow_request::SlowRequestLogger,
+            write_stats::WriteStatsRecorder, BreakerState, ConnectionState, DbClient,
+            DiscoveryProvider, ExponentialBackoff, HealthProbe, Operation,
+        },
+        errors::TimeoutPhase,
+        interceptor::OperationKind,
+        model::{
+            execution_info::ExecutionInfo,
+            route::Endpoint,
+            sql_query::{Request as SqlQueryRequest, Response as SqlQueryResponse},
+            value::Value,
+            warning::{ServerWarning, WarningHook},
+            write::{
+                point::PointBuilder, row_count, Normalization, Request as WriteRequest,
+                RetriedPartition, ValidationMode, WriteTableRequestPbsBuilder,
+            },
+        },
+        rpc_client::{
+            MockRpcClient, MockRpcClientFactory, RpcClient, RpcClientFactory, RpcContext,
+            RpcResponse,
+        },
+        spawner::Spawner,
+        Error, Result,
+    };
+
+    /// Written (endpoint, database, tables), and the writes of the tables
+    /// prefixed by `partial_` are warned as partial.
+    type WriteRecords = Arc<Mutex<Vec<(String, String, Vec<String>)>>>;
+
+    /// Client recording the queries and the writes into the `records`, and the
+    /// writes are successful by tables except the ones prefixed by `failed_`.
+    fn recording_rpc_client(endpoint: String, records: WriteRecords) -> MockRpcClient {
+        let query_records = records.clone();
+        let query_endpoint = endpoint.clone();
+        MockRpcClient::default()
+            .on_sql_query(move |_, req| {
+                let database = req.context.unwrap().database;
+                query_records
+                    .lock()
+                    .unwrap()
+                    .push((query_endpoint.clone(), database, req.tables));
+
+                future::ready(Ok(QueryResponsePb {
+                    header: None,
+                    output: Some(Output::AffectedRows(1)),
+                }
+                .into()))
+            })
+            .on_write(move |_, req| {
+                let database = req.context.unwrap().database;
+                let tables: Vec<_> = req.table_requests.into_iter().map(|r| r.table).collect();
+                let failed = tables
+                    .iter()
+                    .filter(|table| table.starts_with("failed_"))
+                    .count() as u32;
+                let success = tables.len() as u32 - failed;
+                let warnings = tables
+                    .iter()
+                    .filter(|table| table.starts_with("partial_"))
+                    .map(|table| ServerWarning {
+                        code: ServerWarning::PARTIAL_RESULT,
+                        message: "partition is missing".to_string(),
+                        table: Some(table.clone()),
+                        endpoint: None,
+                    })
+                    .collect();
+                records
+                    .lock()
+                    .unwrap()
+                    .push((endpoint.clone(), database, tables));
+
+                future::ready(Ok(RpcResponse {
+                    warnings,
+                    resp: WriteResponsePb {
+                        header: None,
+                        success,
+                        failed,
+                    },
+                }))
+            })
+    }
+
+    /// Factory of the [`recording_rpc_client`]s, where only the client to the
+    /// `router_endpoint` routes by the `route_table`, and the writes to the
+    /// `down_endpoints` fail as unavailable.
+    fn mock_factory(
+        router_endpoint: String,
+        route_table: Arc<DashMap<(String, String), Endpoint>>,
+        records: WriteRecords,
+        down_endpoints: Vec<String>,
+    ) -> MockRpcClientFactory {
+        MockRpcClientFactory::new(move |endpoint| {
+            let mut client = recording_rpc_client(endpoint.clone(), records.clone());
+            if endpoint == router_endpoint {
+                client = client.with_route_table(route_table.clone());
+            }
+            if down_endpoints.contains(&endpoint) {
+                client = client.with_failure(|operation| {
+                    matches!(operation, OperationKind::Write | OperationKind::StreamWrite).then(
+                        || Error::Rpc(tonic::Status::unavailable("connection refused").into()),
+                    )
+                });
+            }
+            client
+        })
+    }
+
+    #[tokio::test]
+    async fn test_write_to_databases() {
+        let table = "table".to_string();
+        let endpoint1 = Endpoint::new("192.168.0.1".to_string(), 11);
+        let endpoint2 = Endpoint::new("192.168.0.2".to_string(), 12);
+        let router_endpoint = "192.168.0.5:15".to_string();
+
+        let route_table = Arc::new(DashMap::default());
+        route_table.insert(("db1".to_string(), table.clone()), endpoint1.clone());
+        route_table.insert(("db2".to_string(), table.clone()), endpoint2.clone());
+        let records = WriteRecords::default();
+        let factory = mock_factory(
+            router_endpoint.clone(),
+            route_table,
+            records.clone(),
+            Vec::new(),
+        );
+        let client: Arc<dyn DbClient> = Arc::new(RouteBasedImpl::new(
+            Arc::new(factory),
+            router_endpoint,
+            None,
+            None,
+            SlowRequestLogger::default(),
+            Duration::from_secs(5),
+            3,
+        ));
+
+        let point = PointBuilder::new(table.clone())
+            .timestamp(1_700_000_000_000)
+            .field("value".to_string(), Value::Int64(42))
+            .build()
+            .unwrap();
+        let mut req = WriteRequest::default();
+        req.add_point(point);
+
+        // Interleave the writes to two databases through the same client.
+        let ctx = RpcContext::default();
+        for _ in 0..2 {
+            for database in ["db1", "db2"] {
+                let resp = client.with_database(database).write(&ctx, &req).await;
+                assert!(resp.is_ok());
+            }
+        }
+
+        let expected_record1 = (
+            endpoint1.to_string(),
+            "db1".to_string(),
+            vec![table.clone()],
+        );
+        let expected_record2 = (endpoint2.to_string(), "db2".to_string(), vec![table]);
+        let records = records.lock().unwrap().clone();
+        assert_eq!(
+            records,
+            vec![
+                expected_record1.clone(),
+                expected_record2.clone(),
+                expected_record1,
+                expected_record2,
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_group_commit() {
+        let endpoint1 = Endpoint::new("192.168.0.1".to_string(), 11);
+        let endpoint2 = Endpoint::new("192.168.0.2".to_string(), 12);
+        let router_endpoint = "192.168.0.5:15".to_string();
+        let route_table = Arc::new(DashMap::default());
+        for (table, endpoint) in [
+            ("table1", &endpoint1),
+            ("table2", &endpoint1),
+            ("table3", &endpoint2),
+            ("failed_table", &endpoint1),
+        ] {
+            route_table.insert(("db".to_string(), table.to_string()), endpoint.clone());
+        }
+        let records = WriteRecords::default();
+        let build_client = |config: GroupCommitConfig| {
+            let factory = mock_factory(
+                router_endpoint.clone(),
+                route_table.clone(),
+                records.clone(),
+                vec![endpoint2.to_string()],
+            );
+            let group_committer = GroupCommitter::new(config, Arc::new(SystemClock));
+            RouteBasedImpl::new(
+                Arc::new(factory),
+                router_endpoint.clone(),
+                None,
+                Some("db".to_string()),
+                SlowRequestLogger::default(),
+                Duration::from_secs(5),
+                3,
+            )
+            .with_group_committer(Some(Arc::new(group_committer)))
+        };
+        let build_req = |table: &str, rows: i64| {
+            let mut req = WriteRequest::default();
+            for i in 0..rows {
+                let point = PointBuilder::new(table.to_string())
+                    .timestamp(1_700_000_000_000 + i)
+                    .field("value".to_string(), Value::Int64(42))
+                    .build()
+                    .unwrap();
+                req.add_point(point);
+            }
+            req
+        };
+        let ctx = RpcContext::default();
+
+        // The group is sent once it is full, and every write gets its own points.
+        let client = build_client(GroupCommitConfig {
+            window: Duration::from_secs(60),
+            max_writes: 3,
+        });
+        let reqs = [
+            build_req("table1", 2),
+            build_req("table2", 1),
+            build_req("table1", 1),
+        ];
+        let results = join_all(reqs.iter().map(|req| client.write(&ctx, req))).await;
+        let successes: Vec<_> = results.into_iter().map(|r| r.unwrap().success).collect();
+        assert_eq!(successes, vec![2, 1, 1]);
+        let mut records = records.lock().unwrap().clone();
+        assert_eq!(records.len(), 1);
+        records[0].2.sort();
+        assert_eq!(
+            records[0],
+            (
+                endpoint1.to_string(),
+                "db".to_string(),
+                vec!["table1".to_string(), "table2".to_string()]
+            )
+        );
+        let stats = client.group_commit_stats();
+        assert_eq!((stats.writes, stats.rpcs), (3, 1));
+
+        // The group is sent after the window, and its failure is returned to all
+        // the writes in it.
+        let window = Duration::from_millis(10);
+        let client = build_client(GroupCommitConfig {
+            window,
+            max_writes: 64,
+        });
+        let reqs = [build_req("table3", 1), build_req("table3", 2)];
+        let results = join_all(reqs.iter().map(|req| client.write(&ctx, req))).await;
+        for result in results {
+            match result {
+                Err(Error::RouteBasedWriteError(e)) => {
+                    assert_eq!(e.errors.len(), 1);
+                    assert_eq!(e.errors[0].0, vec!["table3".to_string()]);
+                    assert!(matches!(&e.errors[0].1, Error::Rpc(e)
+                        if e.code() == tonic::Code::Unavailable));
+                }
+                result => panic!("unexpected result:{result:?}"),
+            }
+        }
+        let stats = client.group_commit_stats();
+        assert_eq!((stats.writes, stats.rpcs), (2, 1));
+        assert!(stats.max_delay >= window);
+        assert!(stats.total_delay >= stats.max_delay);
+
+        // All the writes fail if all the points of the group fail.
+        let client = build_client(GroupCommitConfig {
+            window: Duration::from_secs(60),
+            max_writes: 2,
+        });
+        let reqs = [build_req("failed_table", 1), build_req("failed_table", 2)];
+        let results = join_all(reqs.iter().map(|req| client.write(&ctx, req))).await;
+        let counts: Vec<_> = results
+            .into_iter()
+            .map(|r| {
+                let resp = r.unwrap();
+                (resp.success, resp.failed)
+            })
+            .collect();
+        assert_eq!(counts, vec![(0, 1), (0, 2)]);
+
+        // The failure can't be attributed if only some points of the group fail.
+        let reqs = [build_req("table1", 1), build_req("failed_table", 2)];
+        let results = join_all(reqs.iter().map(|req| client.write(&ctx, req))).await;
+        let mut points = Vec::new();
+        for result in results {
+            match result {
+                Err(Error::RouteBasedWriteError(e)) => {
+                    assert_eq!(e.errors.len(), 1);
+                    match &e.errors[0].1 {
+                        Error::UnattributedWriteFailure {
+                            points: own_points,
+                            merged_success: 1,
+                            merged_failed: 1,
+                        } => points.push(*own_points),
+                        e => panic!("unexpected error:{e:?}"),
+                    }
+                }
+                result => panic!("unexpected result:{result:?}"),
+            }
+        }
+        assert_eq!(points, vec![1, 2]);
+        let stats = client.group_commit_stats();
+        assert_eq!((stats.writes, stats.rpcs), (4, 2));
+    }
+
+    #[tokio::test]
+    async fn test_clone() {
+        let table = "table".to_string();
+        let endpoint = Endpoint::new("192.168.0.1".to_string(), 11);
+        let router_endpoint = "192.168.0.5:15".to_string();
+
+        let route_table = Arc::new(DashMap::default());
+        route_table.insert(("db".to_string(), table.clone()), endpoint.clone());
+        let records = WriteRecords::default();
+        let factory = mock_factory(
+            router_endpoint.clone(),
+            route_table,
+            records.clone(),
+            Vec::new(),
+        );
+        let client = RouteBasedImpl::new(
+            Arc::new(factory),
+            router_endpoint,
+            None,
+            Some("db".to_string()),
+            SlowRequestLogger::default(),
+            Duration::from_secs(5),
+            3,
+        );
+        let cloned = client.clone();
+
+        let point = PointBuilder::new(table.clone())
+            .timestamp(1_700_000_000_000)
+            .field("value".to_string(), Value::Int64(42))
+            .build()
+            .unwrap();
+        let mut req = WriteRequest::default();
+        req.add_point(point);
+        client.write(&RpcContext::default(), &req).await.unwrap();
+
+        // The clone shares the router and the connections of the client.
+        assert!(cloned.router.get().is_some());
+        assert_eq!(
+            cloned.connection_states(),
+            vec![(endpoint.clone(), ConnectionState::Connected)]
+        );
+        drop(client);
+        cloned.write(&RpcContext::default(), &req).await.unwrap();
+        let expected_record = (endpoint.to_string(), "db".to_string(), vec![table]);
+        assert_eq!(
+            *records.lock().unwrap(),
+            vec![expected_record.clone(), expected_record]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_write_validation() {
+        let database = "db".to_string();
+        let endpoint = Endpoint::new("192.168.0.1".to_string(), 11);
+        let router_endpoint = "192.168.0.5:15".to_string();
+
+        let route_table = Arc::new(DashMap::default());
+        for table in ["table1", "table2"] {
+            route_table.insert((database.clone(), table.to_string()), endpoint.clone());
+        }
+        let records = WriteRecords::default();
+        let factory = mock_factory(
+            router_endpoint.clone(),
+            route_table,
+            records.clone(),
+            Vec::new(),
+        );
+        let client = RouteBasedImpl::new(
+            Arc::new(factory),
+            router_endpoint,
+            None,
+            Some(database),
+            SlowRequestLogger::default(),
+            Duration::from_secs(5),
+            3,
+        );
+
+        let point = |table: &str, value: f64| {
+            PointBuilder::new(table.to_string())
+                .timestamp(1_700_000_000_000)
+                .field("value".to_string(), Value::Double(value))
+                .build()
+                .unwrap()
+        };
+        let mut req = WriteRequest {
+            validation: ValidationMode::Strict,
+            ..Default::default()
+        };
+        req.add_points(vec![point("table1", 1.0), point("table2", f64::NAN)]);
+
+        // Nothing is written if any point is invalid.
+        let ctx = RpcContext::default();
+        let err = client.write(&ctx, &req).await.unwrap_err();
+        assert!(
+            matches!(err, Error::InvalidPoint { ref table, index: 0, .. } if table == "table2")
+        );
+        assert!(records.lock().unwrap().is_empty());
+
+        // Only the valid points are written.
+        req.validation = ValidationMode::Warn;
+        let resp = client.write(&ctx, &req).await.unwrap();
+        assert_eq!((resp.success, resp.dropped), (1, 1));
+        let records = records.lock().unwrap().clone();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].2, vec!["table1".to_string()]);
+
+        // The duplicates are dropped by the normalization.
+        req.normalization = Some(Normalization::default());
+        req.add_point(point("table1", 1.0));
+        let resp = client.write(&ctx, &req).await.unwrap();
+        assert_eq!((resp.dropped, resp.deduplicated), (1, 1));
+    }
+
+    #[tokio::test]
+    async fn test_partitioned_write_execution_info() {
+        let database = "db".to_string();
+        let table1 = "table1".to_string();
+        let table2 = "table2".to_string();
+        let endpoint1 = Endpoint::new("192.168.0.1".to_string(), 11);
+        let endpoint2 = Endpoint::new("192.168.0.2".to_string(), 12);
+        let router_endpoint = "192.168.0.5:15".to_string();
+
+        let route_table = Arc::new(DashMap::default());
+        route_table.insert((database.clone(), table1.clone()), endpoint1.clone());
+        route_table.insert((database.clone(), table2.clone()), endpoint2.clone());
+        let factory = mock_factory(
+            router_endpoint.clone(),
+            route_table,
+            WriteRecords::default(),
+            Vec::new(),
+        );
+        let client = RouteBasedImpl::new(
+            Arc::new(factory),
+            router_endpoint,
+            None,
+            Some(database),
+            SlowRequestLogger::default(),
+            Duration::from_secs(5),
+            3,
+        );
+
+        let mut req = WriteRequest::default();
+        for table in [table1, table2] {
+            let point = PointBuilder::new(table)
+                .timestamp(1_700_000_000_000)
+                .field("value".to_string(), Value::Int64(42))
+                .build()
+                .unwrap();
+            req.add_point(point);
+        }
+        let resp = client.write(&RpcContext::default(), &req).await.unwrap();
+        assert_eq!(resp.success, 2);
+
+        let info = &resp.execution_info;
+        assert!(info.endpoint.is_none());
+        assert_eq!(info.partitions.len(), 2);
+        let partition_endpoints: HashSet<_> = info
+            .partitions
+            .iter()
+            .map(|partition| partition.endpoint.clone().unwrap())
+            .collect();
+        let expected_endpoints: HashSet<_> =
+            [endpoint1.clone(), endpoint2.clone()].into_iter().collect();
+        assert_eq!(partition_endpoints, expected_endpoints);
+        for partition in &info.partitions {
+            assert!(partition.request_bytes > 0);
+            assert!(partition.response_bytes > 0);
+            assert!(partition.partitions.is_empty());
+        }
+        let sum = |f: fn(&ExecutionInfo) -> usize| info.partitions.iter().map(f).sum::<usize>();
+        assert_eq!(info.request_bytes, sum(|p| p.request_bytes));
+        assert_eq!(info.response_bytes, sum(|p| p.response_bytes));
+        assert_eq!(info.retries, 0);
+        let max_latency = info.partitions.iter().map(|p| p.latency).max().unwrap();
+        assert_eq!(info.latency, max_latency);
+    }
+
+    #[tokio::test]
+    async fn test_partitioned_write_warnings() {
+        let database = "db".to_string();
+        let endpoint1 = Endpoint::new("192.168.0.1".to_string(), 11);
+        let endpoint2 = Endpoint::new("192.168.0.2".to_string(), 12);
+        let router_endpoint = "192.168.0.5:15".to_string();
+
+        let route_table = Arc::new(DashMap::default());
+        for (table, endpoint) in [
+            ("table1", &endpoint1),
+            ("partial_table2", &endpoint1),
+            ("partial_table3", &endpoint2),
+        ] {
+            route_table.insert((database.clone(), table.to_string()), endpoint.clone());
+        }
+        let factory = mock_factory(
+            router_endpoint.clone(),
+            route_table,
+            WriteRecords::default(),
+            Vec::new(),
+        );
+        let hooked = Arc::new(Mutex::new(Vec::new()));
+        let hooked_clone = hooked.clone();
+        let client = RouteBasedImpl::new(
+            Arc::new(factory),
+            router_endpoint,
+            None,
+            Some(database),
+            SlowRequestLogger::default(),
+            Duration::from_secs(5),
+            3,
+        )
+        .with_warning_hook(Some(WarningHook::new(move |operation, warning| {
+            hooked_clone.lock().unwrap().push((operation, warning));
+        })));
+
+        let write = |tables: &[&str]| {
+            let mut req = WriteRequest::default();
+            for table in tables {
+                let point = PointBuilder::new(table.to_string())
+                    .timestamp(1_700_000_000_000)
+                    .field("value".to_string(), Value::Int64(42))
+                    .build()
+                    .unwrap();
+                req.add_point(point);
+            }
+            let client = &client;
+            async move { client.write(&RpcContext::default(), &req).await.unwrap() }
+        };
+
+        // The warnings of the partitions are merged with their endpoints.
+        let resp = write(&["table1", "partial_table2", "partial_table3"]).await;
+        assert_eq!(resp.success, 3);
+        assert!(resp.is_partial());
+        let mut warned: Vec<_> = resp
+            .warnings
+            .iter()
+            .map(|w| (w.table.clone().unwrap(), w.endpoint.clone().unwrap()))
+            .collect();
+        warned.sort();
+        assert_eq!(
+            warned,
+            vec![
+                ("partial_table2".to_string(), endpoint1.to_string()),
+                ("partial_table3".to_string(), endpoint2.to_string()),
+            ]
+        );
+        let mut hooked_warnings: Vec<_> = hooked.lock().unwrap().drain(..).collect();
+        hooked_warnings.sort_by(|a, b| a.1.table.cmp(&b.1.table));
+        assert_eq!(
+            hooked_warnings
+                .iter()
+                .map(|(operation, w)| (*operation, w.endpoint.clone().unwrap()))
+                .collect::<Vec<_>>(),
+            vec![
+                (Operation::Write, endpoint1.to_string()),
+                (Operation::Write, endpoint2.to_string()),
+            ]
+        );
+
+        let resp = write(&["table1"]).await;
+        assert!(resp.warnings.is_empty());
+        assert!(!resp.is_partial());
+        assert!(hooked.lock().unwrap().is_empty());
+    }
+
+    /// Capture recording the (endpoint, encoded request) in memory.
+    #[derive(Debug, Default)]
+    struct MemoryCapture(Mutex<Vec<(Endpoint, Vec<u8>)>>);
+
+    impl RequestCapture for MemoryCapture {
+        fn on_write(&self, _database: &str, endpoint: &Endpoint, encoded: &[u8]) {
+            self.0
+                .lock()
+                .unwrap()
+                .push((endpoint.clone(), encoded.to_vec()));
+        }
+    }
+
+    /// Factory wrapping the clients of the [`mock_factory`] by the capture, as
+    /// the [`RpcClientImplFactory`](crate::rpc_client::RpcClientImplFactory)
+    /// does.
+    struct CapturingFactory {
+        inner: MockRpcClientFactory,
+        capture: Arc<MemoryCapture>,
+    }
+
+    #[async_trait]
+    impl RpcClientFactory for CapturingFactory {
+        async fn build(&self, endpoint: String) -> Result<Arc<dyn RpcClient>> {
+            let client = self.inner.build(endpoint.clone()).await?;
+            Ok(Arc::new(CapturingRpcClient::new(
+                client,
+                endpoint.parse().unwrap(),
+                self.capture.clone(),
+            )))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_capture_partitioned_write() {
+        let database = "db".to_string();
+        let endpoint1 = Endpoint::new("192.168.0.1".to_string(), 11);
+        let endpoint2 = Endpoint::new("192.168.0.2".to_string(), 12);
+        let router_endpoint = "192.168.0.5:15".to_string();
+
+        let route_table = Arc::new(DashMap::default());
+        route_table.insert((database.clone(), "table1".to_string()), endpoint1.clone());
+        route_table.insert((database.clone(), "table2".to_string()), endpoint2.clone());
+        let capture = Arc::new(MemoryCapture::default());
+        let factory = CapturingFactory {
+            inner: mock_factory(
+                router_endpoint.clone(),
+                route_table,
+                WriteRecords::default(),
+                Vec::new(),
+            ),
+            capture: capture.clone(),
+        };
+        let client = RouteBasedImpl::new(
+            Arc::new(factory),
+            router_endpoint,
+            None,
+            Some(database.clone()),
+            SlowRequestLogger::default(),
+            Duration::from_secs(5),
+            3,
+        );
+
+        let mut req = WriteRequest::default();
+        for table in ["table1", "table2", "table2"] {
+            let point = PointBuilder::new(table.to_string())
+                .timestamp(1_700_000_000_000)
+                .field("value".to_string(), Value::Int64(42))
+                .build()
+                .unwrap();
+            req.add_point(point);
+        }
+        client.write(&RpcContext::default(), &req).await.unwrap();
+
+        // Every partition is captured with its endpoint, and decoded back to the
+        // partition of the original request.
+        let mut captured: Vec<_> = capture
+            .0
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(endpoint, encoded)| {
+                let req_pb = WriteRequestPb::decode(encoded.as_slice()).unwrap();
+                (endpoint.clone(), req_pb)
+            })
+            .collect();
+        captured.sort_by_key(|(endpoint, _)| endpoint.to_string());
+        assert_eq!(captured.len(), 2);
+        for ((endpoint, req_pb), (expected_endpoint, table)) in captured
+            .into_iter()
+            .zip([(endpoint1, "table1"), (endpoint2, "table2")])
+        {
+            assert_eq!(endpoint, expected_endpoint);
+            assert_eq!(req_pb.context.unwrap().database, database);
+            let mut partition = WriteRequest::default();
+            partition.add_points(req.point_groups[table].clone());
+            let expected = WriteTableRequestPbsBuilder(partition).build().unwrap();
+            assert_eq!(req_pb.table_requests, expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_preferred_endpoint() {
+        let database = "db".to_string();
+        let endpoint1 = Endpoint::new("192.168.0.1".to_string(), 11);
+        let endpoint2 = Endpoint::new("192.168.0.2".to_string(), 12);
+        let endpoint3 = Endpoint::new("192.168.0.3".to_string(), 13);
+        let router_endpoint = Endpoint::new("192.168.0.5".to_string(), 15);
+
+        let route_table = Arc::new(DashMap::default());
+        route_table.insert((database.clone(), "table1".to_string()), endpoint1.clone());
+        route_table.insert((database.clone(), "table2".to_string()), endpoint2.clone());
+        let records = WriteRecords::default();
+        let factory = mock_factory(
+            router_endpoint.to_string(),
+            route_table,
+            records.clone(),
+            Vec::new(),
+        );
+        let client = RouteBasedImpl::new(
+            Arc::new(factory),
+            router_endpoint.to_string(),
+            None,
+            Some(database),
+            SlowRequestLogger::default(),
+            Duration::from_secs(5),
+            3,
+        );
+        let req = SqlQueryRequest::new(
+            vec!["table1".to_string(), "table2".to_string()],
+            "SELECT * FROM table1 JOIN table2".to_string(),
+        );
+        let last_endpoint = || records.lock().unwrap().last().unwrap().0.clone();
+
+        // The routed endpoints of all the tables and the default endpoints are
+        // the candidates, and the others are ignored.
+        for (preferred, expected) in [
+            (None, &endpoint1),
+            (Some(&endpoint2), &endpoint2),
+            (Some(&router_endpoint), &router_endpoint),
+            (Some(&endpoint3), &endpoint1),
+        ] {
+            let ctx = RpcContext {
+                preferred_endpoint: preferred.cloned(),
+                ..Default::default()
+            };
+            client.sql_query(&ctx, &req).await.unwrap();
+            assert_eq!(
+                last_endpoint(),
+                expected.to_string(),
+                "preferred:{preferred:?}"
+            );
+        }
+
+        // The writes always go to the routed endpoints.
+        let point = PointBuilder::new("table1".to_string())
+            .timestamp(1_700_000_000_000)
+            .field("value".to_string(), Value::Int64(42))
+            .build()
+            .unwrap();
+        let mut write_req = WriteRequest::default();
+        write_req.add_point(point);
+        let ctx = RpcContext::default().preferred_endpoint(router_endpoint);
+        client.write(&ctx, &write_req).await.unwrap();
+        assert_eq!(last_endpoint(), endpoint1.to_string());
+    }
+
+    #[tokio::test]
+    async fn test_read_your_writes() {
+        let database = "db".to_string();
+        let table = "table".to_string();
+        let endpoint1 = Endpoint::new("192.168.0.1".to_string(), 11);
+        let endpoint2 = Endpoint::new("192.168.0.2".to_string(), 12);
+        let router_endpoint = "192.168.0.5:15".to_string();
+
+        let route_table = Arc::new(DashMap::default());
+        let route_key = (database.clone(), table.clone());
+        route_table.insert(route_key.clone(), endpoint1.clone());
+        let records = WriteRecords::default();
+        let factory = mock_factory(
+            router_endpoint.clone(),
+            route_table.clone(),
+            records.clone(),
+            Vec::new(),
+        );
+        let window = Duration::from_secs(5);
+        let clock = ManualClock::new();
+        let client = RouteBasedImpl::new(
+            Arc::new(factory),
+            router_endpoint,
+            None,
+            Some(database),
+            SlowRequestLogger::default(),
+            Duration::from_secs(5),
+            3,
+        )
+        .with_read_your_writes(Some(ReadYourWritesConfig {
+            window,
+            max_tables: 16,
+        }))
+        .with_clock(Arc::new(clock.clone()));
+        let ctx = RpcContext::default();
+        let req = SqlQueryRequest::new(vec![table.clone()], "SELECT * FROM table".to_string());
+        let last_endpoint = || records.lock().unwrap().last().unwrap().0.clone();
+
+        let point = PointBuilder::new(table.clone())
+            .timestamp(1_700_000_000_000)
+            .field("value".to_string(), Value::Int64(42))
+            .build()
+            .unwrap();
+        let mut write_req = WriteRequest::default();
+        write_req.add_point(point);
+        client.write(&ctx, &write_req).await.unwrap();
+        assert_eq!(last_endpoint(), endpoint1.to_string());
+        assert_eq!(
+            client.last_write_endpoint(&ctx, &table),
+            Some(endpoint1.clone())
+        );
+
+        // The table moves to the endpoint missing the write, and the queries within
+        // the window still go to the endpoint of the write.
+        route_table.insert(route_key, endpoint2.clone());
+        client.route_tables(&ctx, &req.tables, true).await.unwrap();
+        clock.advance(window - Duration::from_millis(1));
+        client.sql_query(&ctx, &req).await.unwrap();
+        assert_eq!(last_endpoint(), endpoint1.to_string());
+
+        // The normal routing resumes after the window.
+        clock.advance(Duration::from_millis(1));
+        assert_eq!(client.last_write_endpoint(&ctx, &table), None);
+        client.sql_query(&ctx, &req).await.unwrap();
+        assert_eq!(last_endpoint(), endpoint2.to_string());
+    }
+
+    #[tokio::test]
+    async fn test_circuit_breaker_fallback() {
+        let database = "db".to_string();
+        let table = "table".to_string();
+        let endpoint = Endpoint::new("192.168.0.1".to_string(), 11);
+        let router_endpoint = "192.168.0.5:15".to_string();
+
+        let route_table = Arc::new(DashMap::default());
+        route_table.insert((database.clone(), table.clone()), endpoint.clone());
+        let records = WriteRecords::default();
+        let factory = mock_factory(
+            router_endpoint.clone(),
+            route_table,
+            records.clone(),
+            vec![endpoint.to_string()],
+        );
+        let client = RouteBasedImpl::new(
+            Arc::new(factory),
+            router_endpoint.clone(),
+            None,
+            Some(database),
+            SlowRequestLogger::default(),
+            Duration::from_secs(5),
+            3,
+        )
+        .with_circuit_breaker(Some(CircuitBreakerConfig {
+            failure_threshold: 2,
+            cooldown: Duration::from_secs(60),
+        }));
+
+        let point = PointBuilder::new(table)
+            .timestamp(1_700_000_000_000)
+            .field("value".to_string(), Value::Int64(42))
+            .build()
+            .unwrap();
+        let mut req = WriteRequest::default();
+        req.add_point(point);
+
+        // Fail until the breaker opens, and then the writes are sent to the default
+        // endpoint.
+        let ctx = RpcContext::default();
+        for _ in 0..2 {
+            assert!(client.write(&ctx, &req).await.is_err());
+        }
+        let states = client.circuit_breaker_states();
+        assert_eq!(states.len(), 1);
+        assert_eq!(states[0].0, endpoint);
+        assert!(matches!(states[0].1, BreakerState::Open { .. }));
+
+        for _ in 0..2 {
+            let resp = client.write(&ctx, &req).await.unwrap();
+            let partition = &resp.execution_info.partitions[0];
+            assert_eq!(partition.endpoint, router_endpoint.parse().ok());
+        }
+        let written_endpoints: Vec<_> = records
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(endpoint, ..)| endpoint.clone())
+            .collect();
+        assert_eq!(written_endpoints, vec![router_endpoint.clone(); 2]);
+    }
+
+    #[tokio::test]
+    async fn test_evict_unreachable_endpoint() {
+        let database = "db".to_string();
+        let endpoint1 = Endpoint::new("192.168.0.1".to_string(), 11);
+        let endpoint2 = Endpoint::new("192.168.0.2".to_string(), 12);
+        let router_endpoint = "192.168.0.5:15".to_string();
+
+        let route_table = Arc::new(DashMap::default());
+        route_table.insert((database.clone(), "table1".to_string()), endpoint1.clone());
+        route_table.insert((database.clone(), "table2".to_string()), endpoint1.clone());
+        route_table.insert((database.clone(), "table3".to_string()), endpoint2.clone());
+        let factory = mock_factory(
+            router_endpoint.clone(),
+            route_table,
+            WriteRecords::default(),
+            vec![endpoint1.to_string()],
+        );
+        let client = RouteBasedImpl::new(
+            Arc::new(factory),
+            router_endpoint,
+            None,
+            Some(database),
+            SlowRequestLogger::default(),
+            Duration::from_secs(5),
+            3,
+        );
+        // Nothing is cached before the router is initialized.
+        assert_eq!(client.evict_routes_by_endpoint(&endpoint1).unwrap(), 0);
+
+        let ctx = RpcContext::default();
+        let tables: Vec<_> = ["table1", "table2", "table3"]
+            .iter()
+            .map(|table| table.to_string())
+            .collect();
+        client.route_tables(&ctx, &tables, false).await.unwrap();
+
+        // The failed write to table1 evicts the route of table2 to the same
+        // unreachable endpoint too.
+        let point = PointBuilder::new("table1".to_string())
+            .timestamp(1_700_000_000_000)
+            .field("value".to_string(), Value::Int64(42))
+            .build()
+            .unwrap();
+        let mut req = WriteRequest::default();
+        req.add_point(point);
+        assert!(client.write(&ctx, &req).await.is_err());
+        assert_eq!(client.evict_routes_by_endpoint(&endpoint1).unwrap(), 0);
+        assert_eq!(client.evict_routes_by_endpoint(&endpoint2).unwrap(), 1);
+
+        client.route_tables(&ctx, &tables, false).await.unwrap();
+        assert_eq!(client.evict_routes_by_endpoint(&endpoint1).unwrap(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_write_stream_by_route() {
+        let database = "db".to_string();
+        let endpoint1 = Endpoint::new("192.168.0.1".to_string(), 11);
+        let endpoint2 = Endpoint::new("192.168.0.2".to_string(), 12);
+        let router_endpoint = "192.168.0.5:15".to_string();
+
+        let route_table = Arc::new(DashMap::default());
+        route_table.insert((database.clone(), "table1".to_string()), endpoint1.clone());
+        route_table.insert((database.clone(), "table2".to_string()), endpoint2.clone());
+        let records = WriteRecords::default();
+        let factory = mock_factory(
+            router_endpoint.clone(),
+            route_table,
+            records.clone(),
+            Vec::new(),
+        );
+        let client = RouteBasedImpl::new(
+            Arc::new(factory),
+            router_endpoint.clone(),
+            None,
+            Some(database.clone()),
+            SlowRequestLogger::default(),
+            Duration::from_secs(5),
+            3,
+        );
+
+        // Every request is split to the streams to all the endpoints, and the table
+        // without route is written to the router endpoint.
+        let reqs = stream::iter(0..3)
+            .map(|i| {
+                let mut req = WriteRequest::default();
+                for table in ["table1", "table2", "table3"] {
+                    let point = PointBuilder::new(table.to_string())
+                        .timestamp(1_700_000_000_000 + i)
+                        .field("value".to_string(), Value::Int64(42))
+                        .build()
+                        .unwrap();
+                    req.add_point(point);
+                }
+                req
+            })
+            .boxed();
+        let resp = client
+            .write_stream(&RpcContext::default(), reqs)
+            .await
+            .unwrap();
+        assert_eq!(resp.success, 9);
+        assert_eq!(resp.execution_info.partitions.len(), 3);
+
+        let records = records.lock().unwrap().clone();
+        for (endpoint, table) in [(endpoint1, "table1"), (endpoint2, "table2")] {
+            let expected = (
+                endpoint.to_string(),
+                database.clone(),
+                vec![table.to_string()],
+            );
+            let endpoint_records: Vec<_> = records
+                .iter()
+                .filter(|(ep, ..)| ep == &endpoint.to_string())
+                .cloned()
+                .collect();
+            assert_eq!(endpoint_records, vec![expected; 3]);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_strict_routing() {
+        let database = "db".to_string();
+        let endpoint1 = Endpoint::new("192.168.0.1".to_string(), 11);
+        let router_endpoint = "192.168.0.5:15".to_string();
+
+        let route_table = Arc::new(DashMap::default());
+        route_table.insert((database.clone(), "table1".to_string()), endpoint1.clone());
+        let records = WriteRecords::default();
+        let build_client = |strict_routing| {
+            let factory = mock_factory(
+                router_endpoint.clone(),
+                route_table.clone(),
+                records.clone(),
+                Vec::new(),
+            );
+            RouteBasedImpl::new(
+                Arc::new(factory),
+                router_endpoint.clone(),
+                None,
+                Some(database.clone()),
+                SlowRequestLogger::default(),
+                Duration::from_secs(5),
+                3,
+            )
+            .with_strict_routing(strict_routing)
+        };
+        let write_req = || {
+            let mut req = WriteRequest::default();
+            for table in ["table1", "table2"] {
+                let point = PointBuilder::new(table.to_string())
+                    .timestamp(1_700_000_000_000)
+                    .field("value".to_string(), Value::Int64(42))
+                    .build()
+                    .unwrap();
+                req.add_point(point);
+            }
+            req
+        };
+        let query_req = SqlQueryRequest::new(vec!["table2".to_string()], "SELECT 1".to_string());
+        let ctx = RpcContext::default();
+
+        // The unknown table is sent to the default endpoint by default.
+        let client = build_client(false);
+        let resp = client.write(&ctx, &write_req()).await.unwrap();
+        assert_eq!(resp.success, 2);
+        client.sql_query(&ctx, &query_req).await.unwrap();
+        let endpoints: HashSet<_> = records
+            .lock()
+            .unwrap()
+            .drain(..)
+            .map(|(endpoint, ..)| endpoint)
+            .collect();
+        assert_eq!(
+            endpoints,
+            HashSet::from([endpoint1.to_string(), router_endpoint.clone()])
+        );
+
+        // The request on the unknown table fails as a whole before any rpc.
+        let client = build_client(true);
+        let expect_not_found = |result: Result<_>| match result {
+            Err(Error::RouteNotFound { tables }) => assert_eq!(tables, vec!["table2"]),
+            other => panic!("unexpected result:{other:?}"),
+        };
+        expect_not_found(client.write(&ctx, &write_req()).await.map(|_| ()));
+        expect_not_found(
+            client
+                .write_stream(&ctx, stream::iter([write_req()]).boxed())
+                .await
+                .map(|_| ()),
+        );
+        expect_not_found(client.sql_query(&ctx, &query_req).await.map(|_| ()));
+        assert!(records.lock().unwrap().is_empty());
+
+        // The known tables are still routed.
+        let mut req = WriteRequest::default();
+        req.add_point(
+            PointBuilder::new("table1".to_string())
+                .timestamp(1_700_000_000_000)
+                .field("value".to_string(), Value::Int64(42))
+                .build()
+                .unwrap(),
+        );
+        assert_eq!(client.write(&ctx, &req).await.unwrap().success, 1);
+    }
+
+    #[tokio::test]
+    async fn test_rpc_error_context() {
+        let database = "db".to_string();
+        let endpoint1 = Endpoint::new("192.168.0.1".to_string(), 11);
+        let endpoint2 = Endpoint::new("192.168.0.2".to_string(), 12);
+        let router_endpoint = "192.168.0.5:15".to_string();
+
+        let route_table = Arc::new(DashMap::default());
+        route_table.insert((database.clone(), "table1".to_string()), endpoint1.clone());
+        route_table.insert((database.clone(), "table2".to_string()), endpoint2.clone());
+        let build_client = |failing: Vec<OperationKind>| {
+            let route_table = route_table.clone();
+            let factory = MockRpcClientFactory::new(move |endpoint| {
+                let failing = failing.clone();
+                recording_rpc_client(endpoint, WriteRecords::default())
+                    .with_route_table(route_table.clone())
+                    .with_failure(move |operation| {
+                        failing.contains(&operation).then(|| {
+                            Error::Rpc(
+                                tonic::Status::invalid_argument(format!("bad {operation}")).into(),
+                            )
+                        })
+                    })
+            });
+            RouteBasedImpl::new(
+                Arc::new(factory),
+                router_endpoint.clone(),
+                None,
+                Some(database.clone()),
+                SlowRequestLogger::default(),
+                Duration::from_secs(5),
+                3,
+            )
+        };
+        let query_req = SqlQueryRequest::new(
+            vec!["table1".to_string()],
+            "SELECT * FROM table1".to_string(),
+        );
+        let mut write_req = WriteRequest::default();
+        for table in ["table1", "table2"] {
+            let point = PointBuilder::new(table.to_string())
+                .timestamp(1_700_000_000_000)
+                .field("value".to_string(), Value::Int64(42))
+                .build()
+                .unwrap();
+            write_req.add_point(point);
+        }
+        let ctx = RpcContext::default();
+
+        // Failed at the route step.
+        let client = build_client(vec![OperationKind::Route]);
+        match client.sql_query(&ctx, &query_req).await {
+            Err(Error::Rpc(e)) => {
+                assert_eq!(e.operation, Some(OperationKind::Route));
+                assert_eq!(e.endpoint, Some(router_endpoint.clone()));
+                assert_eq!(e.tables, vec!["table1".to_string()]);
+                assert_eq!(e.code(), tonic::Code::InvalidArgument);
+                assert_eq!(e.message(), "bad route");
+                assert!(e.elapsed.is_some());
+            }
+            result => panic!("unexpected result:{result:?}"),
+        }
+
+        // Failed on the query.
+        let client = build_client(vec![OperationKind::SqlQuery]);
+        match client.sql_query(&ctx, &query_req).await {
+            Err(Error::Rpc(e)) => {
+                assert_eq!(e.operation, Some(OperationKind::SqlQuery));
+                assert_eq!(e.endpoint, Some(endpoint1.to_string()));
+                assert_eq!(e.tables, vec!["table1".to_string()]);
+                assert_eq!(e.code(), tonic::Code::InvalidArgument);
+                assert!(e.elapsed.is_some());
+            }
+            result => panic!("unexpected result:{result:?}"),
+        }
+
+        // Failed on every write partition.
+        let client = build_client(vec![OperationKind::Write]);
+        match client.write(&ctx, &write_req).await {
+            Err(Error::RouteBasedWriteError(e)) => {
+                let mut errors: Vec<_> = e
+                    .errors
+                    .iter()
+                    .map(|(tables, e)| match e {
+                        Error::Rpc(e) => {
+                            assert_eq!(e.operation, Some(OperationKind::Write));
+                            assert_eq!(&e.tables, tables);
+                            assert_eq!(e.code(), tonic::Code::InvalidArgument);
+                            assert!(e.elapsed.is_some());
+                            (e.endpoint.clone().unwrap(), e.tables.clone())
+                        }
+                        e => panic!("unexpected error:{e:?}"),
+                    })
+                    .collect();
+                errors.sort();
+                assert_eq!(
+                    errors,
+                    vec![
+                        (endpoint1.to_string(), vec!["table1".to_string()]),
+                        (endpoint2.to_string(), vec!["table2".to_string()]),
+                    ]
+                );
+            }
+            result => panic!("unexpected result:{result:?}"),
+        }
+    }
+
+    /// Probe counting the probes, and the endpoints in `down` are unhealthy.
+    #[derive(Debug, Default)]
+    struct MockProbe {
+        down: DashSet<Endpoint>,
+        probes: AtomicU64,
+    }
+
+    #[async_trait]
+    impl HealthProbe for MockProbe {
+        async fn probe(&self, endpoint: &Endpoint) -> bool {
+            self.probes.fetch_add(1, Ordering::Relaxed);
+            !self.down.contains(endpoint)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_health_check() {
+        let database = "db".to_string();
+        let table = "table".to_string();
+        let endpoint = Endpoint::new("192.168.0.1".to_string(), 11);
+        let router_endpoint = "192.168.0.5:15".to_string();
+
+        let route_table = Arc::new(DashMap::default());
+        route_table.insert((database.clone(), table.clone()), endpoint.clone());
+        let records = WriteRecords::default();
+        let factory = mock_factory(
+            router_endpoint.clone(),
+            route_table,
+            records.clone(),
+            Vec::new(),
+        );
+        let interval = Duration::from_millis(10);
+        let probe = Arc::new(MockProbe::default());
+        let client = RouteBasedImpl::new(
+            Arc::new(factory),
+            router_endpoint.clone(),
+            None,
+            Some(database),
+            SlowRequestLogger::default(),
+            Duration::from_secs(5),
+            3,
+        )
+        .with_health_check(
+            Some(HealthCheckConfig {
+                interval,
+                timeout: interval,
+            }),
+            probe.clone(),
+        );
+
+        let point = PointBuilder::new(table.clone())
+            .timestamp(1_700_000_000_000)
+            .field("value".to_string(), Value::Int64(42))
+            .build()
+            .unwrap();
+        let mut write_req = WriteRequest::default();
+        write_req.add_point(point);
+        let query_req = SqlQueryRequest::new(vec![table.clone()], format!("SELECT * FROM {table}"));
+        let last_endpoint = || records.lock().unwrap().last().unwrap().0.clone();
+
+        // Healthy at first.
+        let ctx = RpcContext::default();
+        client.write(&ctx, &write_req).await.unwrap();
+        assert_eq!(last_endpoint(), endpoint.to_string());
+
+        // The queries avoid the unhealthy endpoint, and the writes to it fail fast.
+        probe.down.insert(endpoint.clone());
+        tokio::time::sleep(interval * 10).await;
+        client.sql_query(&ctx, &query_req).await.unwrap();
+        assert_eq!(last_endpoint(), router_endpoint);
+        let record_num = records.lock().unwrap().len();
+        let err = match client.write(&ctx, &write_req).await {
+            Err(Error::RouteBasedWriteError(err)) => err,
+            result => panic!("unexpected result:{result:?}"),
+        };
+        assert!(matches!(err.errors[0].1, Error::EndpointUnhealthy { .. }));
+        assert_eq!(records.lock().unwrap().len(), record_num);
+
+        // Recover.
+        probe.down.remove(&endpoint);
+        tokio::time::sleep(interval * 10).await;
+        client.sql_query(&ctx, &query_req).await.unwrap();
+        assert_eq!(last_endpoint(), endpoint.to_string());
+        client.write(&ctx, &write_req).await.unwrap();
+        assert_eq!(last_endpoint(), endpoint.to_string());
+
+        // Stop probing once the client is dropped.
+        drop(client);
+        tokio::time::sleep(interval).await;
+        let probes = probe.probes.load(Ordering::Relaxed);
+        tokio::time::sleep(interval * 5).await;
+        assert_eq!(probe.probes.load(Ordering::Relaxed), probes);
+    }
+
+    #[test]
+    fn test_background_runtime() {
+        let database = "db".to_string();
+        let table = "table".to_string();
+        let endpoint = Endpoint::new("192.168.0.1".to_string(), 11);
+        let router_endpoint = "192.168.0.5:15".to_string();
+
+        let route_table = Arc::new(DashMap::default());
+        route_table.insert((database.clone(), table.clone()), endpoint);
+        let factory = mock_factory(
+            router_endpoint.clone(),
+            route_table,
+            WriteRecords::default(),
+            Vec::new(),
+        );
+        let background = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .enable_all()
+            .build()
+            .unwrap();
+        let interval = Duration::from_millis(10);
+        let probe = Arc::new(MockProbe::default());
+        let client = RouteBasedImpl::new(
+            Arc::new(factory),
+            router_endpoint,
+            None,
+            Some(database),
+            SlowRequestLogger::default(),
+            Duration::from_secs(5),
+            3,
+        )
+        .with_health_check(
+            Some(HealthCheckConfig {
+                interval,
+                timeout: interval,
+            }),
+            probe.clone(),
+        )
+        .with_spawner(Spawner::new(Some(background.handle().clone())));
+
+        // The health checker is started in a runtime dropped afterwards.
+        let query_req = SqlQueryRequest::new(vec![table.clone()], format!("SELECT * FROM {table}"));
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        runtime
+            .block_on(client.sql_query(&RpcContext::default(), &query_req))
+            .unwrap();
+        drop(runtime);
+
+        let probes = probe.probes.load(Ordering::Relaxed);
+        std::thread::sleep(interval * 5);
+        assert!(probe.probes.load(Ordering::Relaxed) > probes);
+
+        // Stop probing once the client is dropped.
+        drop(client);
+        std::thread::sleep(interval);
+        let probes = probe.probes.load(Ordering::Relaxed);
+        std::thread::sleep(interval * 5);
+        assert_eq!(probe.probes.load(Ordering::Relaxed), probes);
+    }
+
+    /// Provider of the endpoints set by the test.
+    #[derive(Debug, Default)]
+    struct MockDiscovery(Mutex<Vec<Endpoint>>);
+
+    #[async_trait]
+    impl DiscoveryProvider for MockDiscovery {
+        async fn endpoints(&self) -> Result<Vec<Endpoint>> {
+            Ok(self.0.lock().unwrap().clone())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_discovery() {
+        let database = "db".to_string();
+        let endpoint = |n: u32| Endpoint::new(format!("192.168.0.{n}"), 8831);
+        let records = WriteRecords::default();
+        let factory = {
+            let records = records.clone();
+            MockRpcClientFactory::new(move |endpoint| {
+                recording_rpc_client(endpoint, records.clone())
+                    .with_route_table(Arc::new(DashMap::default()))
+            })
+        };
+        let discovery = Arc::new(MockDiscovery::default());
+        *discovery.0.lock().unwrap() = vec![endpoint(1), endpoint(2)];
+        let interval = Duration::from_secs(10);
+        let clock = ManualClock::new();
+        let client = RouteBasedImpl::new(
+            Arc::new(factory),
+            String::new(),
+            None,
+            Some(database),
+            SlowRequestLogger::default(),
+            Duration::from_secs(5),
+            3,
+        )
+        .with_discovery(Some(discovery.clone()), interval)
+        .with_clock(Arc::new(clock.clone()));
+
+        // The unknown table is queried from the primary.
+        let req = SqlQueryRequest::new(
+            vec!["unknown".to_string()],
+            "SELECT * FROM unknown".to_string(),
+        );
+        let ctx = RpcContext::default();
+        let last_endpoint = || records.lock().unwrap().last().unwrap().0.clone();
+        client.sql_query(&ctx, &req).await.unwrap();
+        assert_eq!(last_endpoint(), endpoint(1).to_string());
+        assert_eq!(
+            client.default_endpoint_except(&endpoint(1)),
+            Some(endpoint(2))
+        );
+
+        // Remove the primary and add another one.
+        *discovery.0.lock().unwrap() = vec![endpoint(2), endpoint(3)];
+        while clock.sleepers() == 0 {
+            tokio::task::yield_now().await;
+        }
+        clock.advance(interval);
+        while client.default_endpoints.primary() != Some(endpoint(2)) {
+            tokio::task::yield_now().await;
+        }
+
+        client.sql_query(&ctx, &req).await.unwrap();
+        assert_eq!(last_endpoint(), endpoint(2).to_string());
+        assert_eq!(
+            client.default_endpoint_except(&endpoint(2)),
+            Some(endpoint(3))
+        );
+        assert_eq!(
+            client.default_endpoint_except(&endpoint(1)),
+            Some(endpoint(2))
+        );
+        // The client to the removed endpoint is dropped from the pool.
+        let connected: HashSet<_> = client
+            .connection_states()
+            .into_iter()
+            .map(|(endpoint, _)| endpoint)
+            .collect();
+        assert_eq!(connected, HashSet::from([endpoint(2)]));
+
+        // Only the endpoint itself is left.
+        *discovery.0.lock().unwrap() = vec![endpoint(3)];
+        while clock.sleepers() == 0 {
+            tokio::task::yield_now().await;
+        }
+        clock.advance(interval);
+        while client.default_endpoints.primary() != Some(endpoint(3)) {
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(client.default_endpoint_except(&endpoint(3)), None);
+    }
+
+    /// Query with the `timeout` through the client whose routes and queries
+    /// take the delays, and return the result, the elapsed time and the
+    /// timeouts of the queries.
+    async fn query_with_delays(
+        routing_budget: RoutingBudget,
+        route_delay: Duration,
+        query_delay: Duration,
+        timeout: Duration,
+    ) -> (Result<SqlQueryResponse>, Duration, Vec<Option<Duration>>) {
+        let route_table = Arc::new(DashMap::default());
+        route_table.insert(
+            ("db".to_string(), "table".to_string()),
+            Endpoint::new("192.168.0.1".to_string(), 11),
+        );
+        let query_timeouts = Arc::new(Mutex::new(Vec::new()));
+        // The queries fail for the deadline beyond their timeouts, which are
+        // recorded.
+        let recorded_timeouts = query_timeouts.clone();
+        let factory = MockRpcClientFactory::new(move |_| {
+            let query_timeouts = recorded_timeouts.clone();
+            MockRpcClient::default()
+                .with_route_table(route_table.clone())
+                .with_latency(OperationKind::Route, route_delay)
+                .on_sql_query(move |ctx, _| {
+                    query_timeouts.lock().unwrap().push(ctx.timeout);
+                    async move {
+                        match ctx.timeout {
+                            Some(timeout) if timeout < query_delay => {
+                                tokio::time::sleep(timeout).await;
+                                Err(Error::Rpc(
+                                    tonic::Status::deadline_exceeded("timeout expired").into(),
+                                ))
+                            }
+                            _ => {
+                                tokio::time::sleep(query_delay).await;
+                                Ok(QueryResponsePb {
+                                    header: None,
+                                    output: Some(Output::AffectedRows(1)),
+                                }
+                                .into())
+                            }
+                        }
+                    }
+                })
+        });
+        let client = RouteBasedImpl::new(
+            Arc::new(factory),
+            "192.168.0.5:15".to_string(),
+            None,
+            Some("db".to_string()),
+            SlowRequestLogger::default(),
+            Duration::from_secs(5),
+            3,
+        )
+        .with_routing_budget(routing_budget);
+
+        let req =
+            SqlQueryRequest::new(vec!["table".to_string()], "SELECT * FROM table".to_string());
+        let begin = Instant::now();
+        let result = client
+            .sql_query(&RpcContext::default().timeout(timeout), &req)
+            .await;
+        let elapsed = begin.elapsed();
+
+        let query_timeouts = query_timeouts.lock().unwrap().clone();
+        (result, elapsed, query_timeouts)
+    }
+
+    #[tokio::test]
+    async fn test_routing_budget() {
+        let timeout = Duration::from_millis(400);
+        let budget = RoutingBudget::default();
+
+        // The execution gets the rest of the time after the slow routing.
+        let route_delay = Duration::from_millis(100);
+        let (result, _, query_timeouts) =
+            query_with_delays(budget.clone(), route_delay, Duration::ZERO, timeout).await;
+        assert!(result.is_ok());
+        let query_timeout = query_timeouts[0].unwrap();
+        assert!(query_timeout >= timeout / 2 && query_timeout <= timeout - route_delay);
+
+        // Give up the routing beyond its budget.
+        let (result, elapsed, query_timeouts) = query_with_delays(
+            budget.clone(),
+            Duration::from_secs(5),
+            Duration::ZERO,
+            timeout,
+        )
+        .await;
+        assert!(matches!(
+            result,
+            Err(Error::Timeout { phase: TimeoutPhase::Routing, timeout: t }) if t == timeout
+        ));
+        assert!(elapsed >= timeout / 2 && elapsed < timeout);
+        assert!(query_timeouts.is_empty());
+
+        // The routing budget is capped.
+        let capped = RoutingBudget {
+            fraction: 1.0,
+            cap: Some(Duration::from_millis(50)),
+        };
+        let (result, elapsed, _) =
+            query_with_delays(capped, route_delay, Duration::ZERO, timeout).await;
+        assert!(matches!(
+            result,
+            Err(Error::Timeout {
+                phase: TimeoutPhase::Routing,
+                ..
+            })
+        ));
+        assert!(elapsed < route_delay);
+
+        // The execution runs out of the time.
+        let (result, _, _) =
+            query_with_delays(budget, Duration::ZERO, Duration::from_secs(5), timeout).await;
+        assert!(matches!(
+            result,
+            Err(Error::Timeout {
+                phase: TimeoutPhase::Execution,
+                ..
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_write_deadline() {
+        let endpoint1 = Endpoint::new("192.168.0.1".to_string(), 11);
+        let endpoint2 = Endpoint::new("192.168.0.2".to_string(), 12);
+        let route_table = Arc::new(DashMap::default());
+        route_table.insert(("db".to_string(), "table1".to_string()), endpoint1);
+        route_table.insert(("db".to_string(), "table2".to_string()), endpoint2.clone());
+        // The writes to the endpoint2 never complete, ignoring their timeouts.
+        let hanging = endpoint2.to_string();
+        let factory = MockRpcClientFactory::new(move |endpoint| {
+            let client = MockRpcClient::default().with_route_table(route_table.clone());
+            if endpoint == hanging {
+                client.on_write(|_, _| future::pending())
+            } else {
+                client
+            }
+        });
+        let client = RouteBasedImpl::new(
+            Arc::new(factory),
+            "192.168.0.5:15".to_string(),
+            None,
+            Some("db".to_string()),
+            SlowRequestLogger::default(),
+            Duration::from_secs(5),
+            3,
+        );
+
+        let mut req = WriteRequest::default();
+        for table in ["table1", "table2"] {
+            let point = PointBuilder::new(table.to_string())
+                .timestamp(1_700_000_000_000)
+                .field("value".to_string(), Value::Int64(42))
+                .build()
+                .unwrap();
+            req.add_point(point);
+        }
+        let timeout = Duration::from_millis(100);
+        let begin = Instant::now();
+        let result = client
+            .write(&RpcContext::default().timeout(timeout), &req)
+            .await;
+        assert!(begin.elapsed() < timeout * 5);
+
+        // The write to the hanging endpoint is cancelled at the deadline, and the
+        // other one is kept.
+        let e = match result {
+            Err(Error::RouteBasedWriteError(e)) => e,
+            other => panic!("unexpected result:{other:?}"),
+        };
+        assert_eq!(e.ok.0, vec!["table1".to_string()]);
+        assert_eq!(e.ok.1.success, 1);
+        assert_eq!(e.errors.len(), 1);
+        assert_eq!(e.errors[0].0, vec!["table2".to_string()]);
+        assert!(matches!(
+            e.errors[0].1,
+            Error::Timeout {
+                phase: TimeoutPhase::Execution,
+                timeout: t,
+            } if t == timeout
+        ));
+    }
+
+    /// Behavior of the writes to an endpoint of the [`flaky_factory`].
+    #[derive(Clone, Copy)]
+    enum WriteBehavior {
+        /// The first write is handled by the server but fails for the
+        /// transport, and the rest succeed.
+        FailOnce,
+        /// The writes succeed after the delay.
+        Slow(Duration),
+        /// The writes fail for their own deadlines.
+        TimedOut,
+    }
+
+    /// Factory of the clients writing the points by the behaviors of their
+    /// endpoints, and the rows handled by the server are counted in `handled`.
+    fn flaky_factory(
+        route_table: Arc<DashMap<(String, String), Endpoint>>,
+        behaviors: HashMap<String, WriteBehavior>,
+        handled: Arc<AtomicU64>,
+    ) -> MockRpcClientFactory {
+        MockRpcClientFactory::new(move |endpoint| {
+            let behavior = behaviors.get(&endpoint).copied();
+            let attempts = Arc::new(AtomicU64::new(0));
+            let handled = handled.clone();
+            MockRpcClient::default()
+                .with_route_table(route_table.clone())
+                .on_write(move |_, req| {
+                    let rows = row_count(&req.table_requests) as u64;
+                    let attempt = attempts.fetch_add(1, Ordering::Relaxed);
+                    let handled = handled.clone();
+                    async move {
+                        match behavior {
+                            Some(WriteBehavior::TimedOut) => {
+                                return Err(Error::Rpc(
+                                    tonic::Status::deadline_exceeded("timeout expired").into(),
+                                ))
+                            }
+                            Some(WriteBehavior::Slow(delay)) => tokio::time::sleep(delay).await,
+                            _ => (),
+                        }
+
+                        handled.fetch_add(rows, Ordering::Relaxed);
+                        if matches!(behavior, Some(WriteBehavior::FailOnce)) && attempt == 0 {
+                            return Err(Error::Rpc(
+                                tonic::Status::unavailable("connection reset").into(),
+                            ));
+                        }
+                        Ok(WriteResponsePb {
+                            header: None,
+                            success: rows as u32,
+                            failed: 0,
+                        }
+                        .into())
+                    }
+                })
+        })
+    }
+
+    #[tokio::test]
+    async fn test_retried_partitions() {
+        let endpoint1 = Endpoint::new("192.168.0.1".to_string(), 11);
+        let endpoint2 = Endpoint::new("192.168.0.2".to_string(), 12);
+        let endpoint3 = Endpoint::new("192.168.0.3".to_string(), 13);
+        let route_table = Arc::new(DashMap::default());
+        for (table, endpoint) in [
+            ("table1", &endpoint1),
+            ("table2", &endpoint2),
+            ("table3", &endpoint3),
+        ] {
+            route_table.insert(("db".to_string(), table.to_string()), endpoint.clone());
+        }
+        let handled = Arc::new(AtomicU64::new(0));
+        let delay = Duration::from_millis(100);
+        let factory = flaky_factory(
+            route_table,
+            [
+                (endpoint1.to_string(), WriteBehavior::FailOnce),
+                (endpoint2.to_string(), WriteBehavior::Slow(delay)),
+                (endpoint3.to_string(), WriteBehavior::TimedOut),
+            ]
+            .into_iter()
+            .collect(),
+            handled.clone(),
+        );
+        let client = RouteBasedImpl::new(
+            Arc::new(factory),
+            "192.168.0.5:15".to_string(),
+            None,
+            Some("db".to_string()),
+            SlowRequestLogger::default(),
+            Duration::from_secs(5),
+            3,
+        )
+        .with_retry_policy(Some(Arc::new(ExponentialBackoff {
+            initial_backoff: Duration::from_millis(10),
+            ..Default::default()
+        })));
+
+        let mut req = WriteRequest::default();
+        for (table, rows) in [("table1", 2), ("table2", 3), ("table3", 1)] {
+            for i in 0..rows {
+                let point = PointBuilder::new(table.to_string())
+                    .timestamp(1_700_000_000_000 + i)
+                    .field("value".to_string(), Value::Int64(42))
+                    .build()
+                    .unwrap();
+                req.add_point(point);
+            }
+        }
+        let result = client
+            .write(&RpcContext::default().timeout(Duration::from_secs(5)), &req)
+            .await;
+
+        // The rows of the first attempt of table1 are handled by the server, but
+        // only the retry is counted, and the slow partition isn't cancelled by
+        // the timed out one.
+        assert_eq!(handled.load(Ordering::Relaxed), 2 + 2 + 3);
+        let e = match result {
+            Err(Error::RouteBasedWriteError(e)) => e,
+            other => panic!("unexpected result:{other:?}"),
+        };
+        assert_eq!(e.ok.0, vec!["table1".to_string(), "table2".to_string()]);
+        let resp = &e.ok.1;
+        assert_eq!((resp.success, resp.failed), (2 + 3, 0));
+        assert_eq!(
+            resp.retried_partitions,
+            vec![RetriedPartition {
+                endpoint: endpoint1.to_string(),
+                tables: vec!["table1".to_string()],
+                attempts: 2,
+            }]
+        );
+        assert_eq!(e.errors.len(), 1);
+        assert_eq!(e.errors[0].0, vec!["table3".to_string()]);
+        assert!(matches!(
+            e.errors[0].1,
+            Error::Timeout {
+                phase: TimeoutPhase::Execution,
+                ..
+            }
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_write_stats() {
+        let endpoint1 = Endpoint::new("192.168.0.1".to_string(), 11);
+        let endpoint2 = Endpoint::new("192.168.0.2".to_string(), 12);
+        let endpoint3 = Endpoint::new("192.168.0.3".to_string(), 13);
+        let route_table = Arc::new(DashMap::default());
+        for (table, endpoint) in [
+            ("table1", &endpoint1),
+            ("table2", &endpoint1),
+            ("table3", &endpoint2),
+            ("table4", &endpoint3),
+        ] {
+            route_table.insert(("db".to_string(), table.to_string()), endpoint.clone());
+        }
+        let factory = flaky_factory(
+            route_table,
+            [
+                (endpoint2.to_string(), WriteBehavior::FailOnce),
+                (endpoint3.to_string(), WriteBehavior::TimedOut),
+            ]
+            .into_iter()
+            .collect(),
+            Arc::new(AtomicU64::new(0)),
+        );
+        let write_stats =
+            WriteStatsRecorder::new(WriteStatsConfig::default(), Arc::new(SystemClock));
+        let client = RouteBasedImpl::new(
+            Arc::new(factory),
+            "192.168.0.5:15".to_string(),
+            None,
+            Some("db".to_string()),
+            SlowRequestLogger::default(),
+            Duration::from_secs(5),
+            3,
+        )
+        .with_retry_policy(Some(Arc::new(ExponentialBackoff {
+            initial_backoff: Duration::from_millis(10),
+            ..Default::default()
+        })))
+        .with_write_stats(Some(Arc::new(write_stats)));
+
+        let build_req = |points: &[(&str, i64)]| {
+            let mut req = WriteRequest::default();
+            for (table, rows) in points {
+                for i in 0..*rows {
+                    let point = PointBuilder::new(table.to_string())
+                        .timestamp(1_700_000_000_000 + i)
+                        .field("value".to_string(), Value::Int64(42))
+                        .build()
+                        .unwrap();
+                    req.add_point(point);
+                }
+            }
+            req
+        };
+        let ctx = RpcContext::default();
+        let req = build_req(&[("table1", 2), ("table2", 1), ("table3", 3), ("table4", 1)]);
+        // The partition of table4 fails, while the others succeed, including the
+        // retried one of table3.
+        assert!(client.write(&ctx, &req).await.is_err());
+        client
+            .write(&ctx, &build_req(&[("table1", 1)]))
+            .await
+            .unwrap();
+
+        let stats = client.write_stats();
+        let summary: Vec<_> = stats
+            .iter()
+            .map(|stats| (stats.table.as_str(), stats.points, stats.failures))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("table1", 3, 0),
+                ("table3", 3, 0),
+                ("table2", 1, 0),
+                ("table4", 0, 1)
+            ]
+        );
+        assert!(stats.iter().all(|stats| stats.database == "db"));
+        assert!(stats[0].bytes > stats[2].bytes);
+        assert_eq!(stats[3].bytes, 0);
+
+        client.reset_write_stats();
+        assert!(client.write_stats().is_empty());
     }
 }
@@ -0,0 +1,224 @@
+// Copyright 2022 CeresDB Project Authors. Licensed under Apache-2.0.
+
+//! Slow request logging
+
+use std::{fmt, sync::Arc, time::Duration};
+
+use crate::{
+    config::SlowRequestThreshold,
+    model::{
+        route::Endpoint, sql_query::Request as SqlQueryRequest, write::Request as WriteRequest,
+    },
+    Result,
+};
+
+/// Operation of the request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operation {
+    SqlQuery,
+    Write,
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Operation::SqlQuery => f.write_str("sql_query"),
+            Operation::Write => f.write_str("write"),
+        }
+    }
+}
+
+/// Information about a slow request.
+#[derive(Debug, Clone)]
+pub struct SlowRequestInfo {
+    pub operation: Operation,
+    /// The endpoints the request is sent to, and it is empty if failing before
+    /// sending.
+    pub endpoints: Vec<Endpoint>,
+    pub database: Option<String>,
+    pub tables: Vec<String>,
+    /// Bytes of the sql for query, and number of the points for write.
+    pub request_size: usize,
+    /// Elapsed time of the whole operation, including the routing.
+    pub elapsed: Duration,
+    /// The error message, and it is none if the request succeeds.
+    pub error: Option<String>,
+}
+
+/// Hook called on every slow request.
+#[derive(Clone)]
+pub struct SlowRequestHook(Arc<dyn Fn(SlowRequestInfo) + Send + Sync>);
+
+impl SlowRequestHook {
+    pub fn new(hook: impl Fn(SlowRequestInfo) + Send + Sync + 'static) -> Self {
+        Self(Arc::new(hook))
+    }
+}
+
+impl fmt::Debug for SlowRequestHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SlowRequestHook")
+    }
+}
+
+/// Log the requests slower than the threshold if the `tracing` feature is
+/// enabled, and call the hook if set.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct SlowRequestLogger {
+    threshold: SlowRequestThreshold,
+    hook: Option<SlowRequestHook>,
+}
+
+impl SlowRequestLogger {
+    pub fn new(threshold: SlowRequestThreshold, hook: Option<SlowRequestHook>) -> Self {
+        Self { threshold, hook }
+    }
+
+    pub fn observe_sql_query<T>(
+        &self,
+        database: Option<&String>,
+        req: &SqlQueryRequest,
+        endpoints: Vec<Endpoint>,
+        elapsed: Duration,
+        result: &Result<T>,
+    ) {
+        if !Self::exceeds(self.threshold.sql_query, elapsed) {
+            return;
+        }
+
+        self.report(SlowRequestInfo {
+            operation: Operation::SqlQuery,
+            endpoints,
+            database: database.cloned(),
+            tables: req.tables.clone(),
+            request_size: req.sql.len(),
+            elapsed,
+            error: result.as_ref().err().map(|e| e.to_string()),
+        });
+    }
+
+    pub fn observe_write<T>(
+        &self,
+        database: Option<&String>,
+        req: &WriteRequest,
+        endpoints: Vec<Endpoint>,
+        elapsed: Duration,
+        result: &Result<T>,
+    ) {
+        if !Self::exceeds(self.threshold.write, elapsed) {
+            return;
+        }
+
+        self.report(SlowRequestInfo {
+            operation: Operation::Write,
+            endpoints,
+            database: database.cloned(),
+            tables: req.point_groups.keys().cloned().collect(),
+            request_size: req.point_groups.values().map(|points| points.len()).sum(),
+            elapsed,
+            error: result.as_ref().err().map(|e| e.to_string()),
+        });
+    }
+
+    #[inline]
+    fn exceeds(threshold: Option<Duration>, elapsed: Duration) -> bool {
+        threshold.map(|t| elapsed >= t).unwrap_or(false)
+    }
+
+    fn report(&self, info: SlowRequestInfo) {
+        #[cfg(feature = "tracing")]
+        tracing::warn!(
+            operation = %info.operation,
+            endpoints = ?info.endpoints,
+            database = ?info.database,
+            tables = ?info.tables,
+            request_size = info.request_size,
+            elapsed_ms = info.elapsed.as_millis() as u64,
+            error = ?info.error,
+            "slow request"
+        );
+
+        if let Some(hook) = &self.hook {
+            (hook.0)(info);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use super::{Operation, SlowRequestHook, SlowRequestInfo, SlowRequestLogger};
+    use crate::{
+        config::SlowRequestThreshold,
+        db_client::{raw::RawImpl, DbClient},
+        interceptor::OperationKind,
+        model::{
+            value::Value,
+            write::{point::PointBuilder, Request as WriteRequest},
+        },
+        rpc_client::{MockRpcClient, MockRpcClientFactory, RpcContext},
+    };
+
+    async fn write_with_delay(delay: Duration, threshold: Duration) -> Vec<SlowRequestInfo> {
+        let reported = Arc::new(Mutex::new(Vec::new()));
+        let reported_clone = reported.clone();
+        let logger = SlowRequestLogger::new(
+            SlowRequestThreshold {
+                write: Some(threshold),
+                sql_query: None,
+            },
+            Some(SlowRequestHook::new(move |info| {
+                reported_clone.lock().unwrap().push(info)
+            })),
+        );
+        // The writes take the `delay` to finish.
+        let factory = MockRpcClientFactory::new(move |_| {
+            MockRpcClient::default().with_latency(OperationKind::Write, delay)
+        });
+        let client = RawImpl::new(
+            Arc::new(factory),
+            "127.0.0.1:8831".to_string(),
+            Some("public".to_string()),
+            logger,
+            3,
+        );
+
+        let point = PointBuilder::new("test_table".to_string())
+            .timestamp(1_700_000_000_000)
+            .field("value".to_string(), Value::Int64(42))
+            .build()
+            .unwrap();
+        let mut req = WriteRequest::default();
+        req.add_point(point);
+        client.write(&RpcContext::default(), &req).await.unwrap();
+
+        let reported = reported.lock().unwrap().clone();
+        reported
+    }
+
+    #[tokio::test]
+    async fn test_slow_write() {
+        let delay = Duration::from_millis(50);
+        let reported = write_with_delay(delay, Duration::from_millis(20)).await;
+        assert_eq!(reported.len(), 1);
+
+        let info = &reported[0];
+        assert_eq!(info.operation, Operation::Write);
+        assert_eq!(info.endpoints[0].to_string(), "127.0.0.1:8831");
+        assert_eq!(info.database.as_deref(), Some("public"));
+        assert_eq!(info.tables, vec!["test_table".to_string()]);
+        assert_eq!(info.request_size, 1);
+        assert!(info.elapsed >= delay && info.elapsed < delay * 10);
+        assert!(info.error.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_fast_write() {
+        let reported = write_with_delay(Duration::ZERO, Duration::from_secs(10)).await;
+        assert!(reported.is_empty());
+    }
+}
@@ -0,0 +1,142 @@
+// Copyright 2022 CeresDB Project Authors. Licensed under Apache-2.0.
+
+//! Endpoints that acknowledged the latest writes of the tables
+
+use std::{
+    collections::HashMap,
+    sync::{Mutex, PoisonError},
+    time::Instant,
+};
+
+use crate::{config::ReadYourWritesConfig, model::route::Endpoint, route_cache::RouteKey};
+
+/// The endpoints acknowledged the latest writes of the tables by the client,
+/// to which the queries on the tables are pinned within the window after the
+/// writes, see [`RpcConfig::read_your_writes`].
+///
+/// At most `max_tables` tables are tracked, and the ones written earliest are
+/// forgotten first.
+///
+/// [`RpcConfig::read_your_writes`]: crate::RpcConfig::read_your_writes
+#[derive(Debug)]
+pub(crate) struct WriteAffinity {
+    config: ReadYourWritesConfig,
+    // The endpoint and the time of the latest write of every table.
+    tables: Mutex<HashMap<RouteKey, (Endpoint, Instant)>>,
+}
+
+impl WriteAffinity {
+    pub fn new(config: ReadYourWritesConfig) -> Self {
+        Self {
+            config,
+            tables: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Record the write of the `tables` in the `database` acknowledged by the
+    /// `endpoint` at `now`.
+    pub fn record<'a>(
+        &self,
+        database: &str,
+        tables: impl IntoIterator<Item = &'a String>,
+        endpoint: &Endpoint,
+        now: Instant,
+    ) {
+        let max_tables = self.config.max_tables.max(1);
+        let mut written = self.tables.lock().unwrap_or_else(PoisonError::into_inner);
+        for table in tables {
+            written.insert(
+                (database.to_string(), table.clone()),
+                (endpoint.clone(), now),
+            );
+        }
+        if written.len() <= max_tables {
+            return;
+        }
+
+        written.retain(|_, (_, written_at)| now < *written_at + self.config.window);
+        while written.len() > max_tables {
+            let earliest = written
+                .iter()
+                .min_by_key(|(_, (_, written_at))| *written_at)
+                .map(|(key, _)| key.clone());
+            match earliest {
+                Some(key) => written.remove(&key),
+                None => break,
+            };
+        }
+    }
+
+    /// Get the endpoint the queries on the `table` in the `database` are
+    /// pinned to at `now`, and none if it isn't written within the window.
+    pub fn endpoint(&self, database: &str, table: &str, now: Instant) -> Option<Endpoint> {
+        self.pinned_endpoint(database, &[table.to_string()], now)
+    }
+
+    /// Get the endpoint of the latest write among the `tables` within the
+    /// window, to which the query on them is pinned.
+    pub fn pinned_endpoint(
+        &self,
+        database: &str,
+        tables: &[String],
+        now: Instant,
+    ) -> Option<Endpoint> {
+        let written = self.tables.lock().unwrap_or_else(PoisonError::into_inner);
+        tables
+            .iter()
+            .filter_map(|table| written.get(&(database.to_string(), table.clone())))
+            .filter(|(_, written_at)| now < *written_at + self.config.window)
+            .max_by_key(|(_, written_at)| *written_at)
+            .map(|(endpoint, _)| endpoint.clone())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use super::WriteAffinity;
+    use crate::{config::ReadYourWritesConfig, model::route::Endpoint};
+
+    #[test]
+    fn test_write_affinity() {
+        let affinity = WriteAffinity::new(ReadYourWritesConfig {
+            window: Duration::from_secs(5),
+            max_tables: 2,
+        });
+        let endpoint1 = Endpoint::new("192.168.0.1".to_string(), 11);
+        let endpoint2 = Endpoint::new("192.168.0.2".to_string(), 12);
+        let tables: Vec<_> = (1..=3).map(|i| format!("table{i}")).collect();
+        let begin = Instant::now();
+
+        affinity.record("db", &tables[..1], &endpoint1, begin);
+        let now = begin + Duration::from_secs(1);
+        affinity.record("db", &tables[1..2], &endpoint2, now);
+        assert_eq!(affinity.endpoint("db", &tables[0], now), Some(endpoint1));
+        assert_eq!(affinity.endpoint("other_db", &tables[0], now), None);
+        // The latest write among the tables wins.
+        assert_eq!(
+            affinity.pinned_endpoint("db", &tables, now),
+            Some(endpoint2.clone())
+        );
+
+        // The window passes.
+        let now = begin + Duration::from_secs(5);
+        assert_eq!(affinity.endpoint("db", &tables[0], now), None);
+        assert_eq!(
+            affinity.endpoint("db", &tables[1], now),
+            Some(endpoint2.clone())
+        );
+
+        // The table written earliest is forgotten beyond the max tables, even if
+        // it is still within the window.
+        affinity.record("db", &tables[..1], &endpoint2, now);
+        affinity.record("db", &tables[2..], &endpoint2, now);
+        assert_eq!(affinity.endpoint("db", &tables[1], now), None);
+        assert_eq!(
+            affinity.endpoint("db", &tables[0], now),
+            Some(endpoint2.clone())
+        );
+        assert_eq!(affinity.endpoint("db", &tables[2], now), Some(endpoint2));
+    }
+}
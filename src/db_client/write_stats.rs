@@ -0,0 +1,291 @@
+// Copyright 2022 CeresDB Project Authors. Licensed under Apache-2.0.
+
+//! Statistics of the writes per table
+
+use std::{
+    cmp::Reverse,
+    fmt,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use ceresdbproto::storage::WriteTableRequest as WriteTableRequestPb;
+use dashmap::DashMap;
+use prost::Message;
+
+use crate::{clock::Clock, config::WriteStatsConfig};
+
+/// Statistics of the writes to a table since the client is built or the
+/// statistics are reset.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TableWriteStats {
+    pub database: String,
+    pub table: String,
+    /// The number of the points written successfully.
+    pub points: u64,
+    /// The size of the points written successfully, measured by the encoded
+    /// requests.
+    pub bytes: u64,
+    /// The number of the failed writes of the table, and a retried write is
+    /// counted once.
+    pub failures: u64,
+    /// When the table is written the first time since it is tracked, whether
+    /// it succeeds or not.
+    pub first_write: Instant,
+    /// When the table is written the last time, whether it succeeds or not.
+    pub last_write: Instant,
+}
+
+impl TableWriteStats {
+    /// The average number of the points written per second from the first
+    /// write to `now`, e.g. for the capacity planning of the hot tables, and
+    /// it is 0 if no time elapses.
+    pub fn points_per_sec(&self, now: Instant) -> f64 {
+        self.per_sec(self.points, now)
+    }
+
+    /// The average size of the points written per second from the first
+    /// write to `now`, see [`points_per_sec`](Self::points_per_sec).
+    pub fn bytes_per_sec(&self, now: Instant) -> f64 {
+        self.per_sec(self.bytes, now)
+    }
+
+    fn per_sec(&self, count: u64, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(self.first_write);
+        if elapsed.is_zero() {
+            return 0.0;
+        }
+        count as f64 / elapsed.as_secs_f64()
+    }
+}
+
+/// Key of the statistics: (database, table).
+type TableKey = (String, String);
+
+#[derive(Debug, Default)]
+struct TableCounters {
+    points: AtomicU64,
+    bytes: AtomicU64,
+    failures: AtomicU64,
+    /// Nanoseconds since the base of the recorder.
+    first_write: AtomicU64,
+    /// Nanoseconds since the base of the recorder.
+    last_write: AtomicU64,
+    /// Sequence of the last write, by which the least recently written table
+    /// is evicted.
+    last_seq: AtomicU64,
+}
+
+/// Recorder of the [`TableWriteStats`], shared by the clients to all the
+/// endpoints.
+///
+/// The counters of the known tables are updated by the atomics, and the least
+/// recently written table is evicted when a new one exceeds the
+/// [`max_tables`](WriteStatsConfig::max_tables).
+pub(crate) struct WriteStatsRecorder {
+    max_tables: usize,
+    tables: DashMap<TableKey, TableCounters>,
+    next_seq: AtomicU64,
+    clock: Arc<dyn Clock>,
+    base: Instant,
+}
+
+impl fmt::Debug for WriteStatsRecorder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WriteStatsRecorder")
+            .field("max_tables", &self.max_tables)
+            .field("tables", &self.tables.len())
+            .finish()
+    }
+}
+
+impl WriteStatsRecorder {
+    pub fn new(config: WriteStatsConfig, clock: Arc<dyn Clock>) -> Self {
+        let base = clock.now();
+        Self {
+            max_tables: config.max_tables.max(1),
+            tables: DashMap::new(),
+            next_seq: AtomicU64::new(0),
+            clock,
+            base,
+        }
+    }
+
+    /// Record the write of the `table_requests` in the `database`.
+    pub fn record(&self, database: &str, table_requests: &[WriteTableRequestPb], succeeded: bool) {
+        let now = self
+            .clock
+            .now()
+            .saturating_duration_since(self.base)
+            .as_nanos() as u64;
+        for table_request in table_requests {
+            let seq = self.next_seq.fetch_add(1, Ordering::Relaxed);
+            let key = (database.to_string(), table_request.table.clone());
+            let update = |counters: &TableCounters| {
+                if succeeded {
+                    let points: usize = table_request
+                        .entries
+                        .iter()
+                        .map(|entry| entry.field_groups.len())
+                        .sum();
+                    counters.points.fetch_add(points as u64, Ordering::Relaxed);
+                    counters
+                        .bytes
+                        .fetch_add(table_request.encoded_len() as u64, Ordering::Relaxed);
+                } else {
+                    counters.failures.fetch_add(1, Ordering::Relaxed);
+                }
+                counters.last_write.fetch_max(now, Ordering::Relaxed);
+                counters.last_seq.fetch_max(seq, Ordering::Relaxed);
+            };
+
+            match self.tables.get(&key) {
+                Some(counters) => update(&counters),
+                None => {
+                    update(&self.tables.entry(key).or_insert_with(|| TableCounters {
+                        first_write: AtomicU64::new(now),
+                        ..Default::default()
+                    }));
+                    self.evict_lru();
+                }
+            }
+        }
+    }
+
+    /// Get the statistics of all the tables, sorted by the points written in
+    /// descending order.
+    pub fn stats(&self) -> Vec<TableWriteStats> {
+        let mut stats: Vec<_> = self
+            .tables
+            .iter()
+            .map(|entry| {
+                let ((database, table), counters) = entry.pair();
+                let instant = |nanos: &AtomicU64| {
+                    self.base + Duration::from_nanos(nanos.load(Ordering::Relaxed))
+                };
+                TableWriteStats {
+                    database: database.clone(),
+                    table: table.clone(),
+                    points: counters.points.load(Ordering::Relaxed),
+                    bytes: counters.bytes.load(Ordering::Relaxed),
+                    failures: counters.failures.load(Ordering::Relaxed),
+                    first_write: instant(&counters.first_write),
+                    last_write: instant(&counters.last_write),
+                }
+            })
+            .collect();
+        stats.sort_unstable_by(|a, b| {
+            (Reverse(a.points), &a.database, &a.table).cmp(&(
+                Reverse(b.points),
+                &b.database,
+                &b.table,
+            ))
+        });
+
+        stats
+    }
+
+    pub fn reset(&self) {
+        self.tables.clear();
+    }
+
+    fn evict_lru(&self) {
+        while self.tables.len() > self.max_tables {
+            let lru = self
+                .tables
+                .iter()
+                .min_by_key(|entry| entry.last_seq.load(Ordering::Relaxed))
+                .map(|entry| entry.key().clone());
+            match lru {
+                Some(key) => self.tables.remove(&key),
+                None => return,
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{sync::Arc, time::Duration};
+
+    use ceresdbproto::storage::{
+        FieldGroup as FieldGroupPb, WriteSeriesEntry as WriteSeriesEntryPb,
+        WriteTableRequest as WriteTableRequestPb,
+    };
+
+    use super::WriteStatsRecorder;
+    use crate::{
+        clock::{Clock, ManualClock},
+        config::WriteStatsConfig,
+    };
+
+    fn table_request(table: &str, points: usize) -> WriteTableRequestPb {
+        WriteTableRequestPb {
+            table: table.to_string(),
+            tag_names: Vec::new(),
+            field_names: Vec::new(),
+            entries: vec![WriteSeriesEntryPb {
+                tags: Vec::new(),
+                field_groups: (0..points)
+                    .map(|i| FieldGroupPb {
+                        timestamp: i as i64,
+                        fields: Vec::new(),
+                    })
+                    .collect(),
+            }],
+        }
+    }
+
+    #[test]
+    fn test_evict_least_recently_written() {
+        let clock = ManualClock::new();
+        let recorder =
+            WriteStatsRecorder::new(WriteStatsConfig { max_tables: 2 }, Arc::new(clock.clone()));
+        recorder.record("db", &[table_request("table1", 1)], true);
+        clock.advance(Duration::from_secs(1));
+        recorder.record("db", &[table_request("table2", 2)], true);
+        recorder.record("db", &[table_request("table1", 1)], false);
+        recorder.record("db", &[table_request("table3", 3)], true);
+
+        // The table2 is evicted as the least recently written one.
+        let stats = recorder.stats();
+        let tables: Vec<_> = stats.iter().map(|stats| stats.table.as_str()).collect();
+        assert_eq!(tables, vec!["table3", "table1"]);
+        assert_eq!((stats[1].points, stats[1].failures), (1, 1));
+        assert_eq!(stats[1].last_write, clock.now());
+
+        recorder.reset();
+        assert!(recorder.stats().is_empty());
+    }
+
+    #[test]
+    fn test_write_rate() {
+        let clock = ManualClock::new();
+        let recorder =
+            WriteStatsRecorder::new(WriteStatsConfig::default(), Arc::new(clock.clone()));
+        let first_write = clock.now();
+        recorder.record("db", &[table_request("table1", 10)], true);
+        let stats = recorder.stats();
+        assert_eq!(stats[0].points_per_sec(clock.now()), 0.0);
+
+        clock.advance(Duration::from_secs(2));
+        recorder.record("db", &[table_request("table1", 30)], true);
+        recorder.record("db", &[table_request("table2", 5)], true);
+        clock.advance(Duration::from_secs(2));
+
+        let stats = recorder.stats();
+        assert_eq!(stats[0].table, "table1");
+        assert_eq!(stats[0].first_write, first_write);
+        assert_eq!(stats[0].points_per_sec(clock.now()), 10.0);
+        assert_eq!(
+            stats[0].bytes_per_sec(clock.now()),
+            stats[0].bytes as f64 / 4.0
+        );
+        // The rate of a table is measured since it is written the first time.
+        assert_eq!(stats[1].table, "table2");
+        assert_eq!(stats[1].points_per_sec(clock.now()), 2.5);
+    }
+}
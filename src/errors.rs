@@ -2,11 +2,31 @@
 
 //! Error in client
 
-use std::fmt::Display;
+use std::{fmt::Display, time::Duration};
 
 use thiserror::Error as ThisError;
 
-use crate::model::write::Response;
+use crate::{
+    interceptor::OperationKind,
+    model::{
+        route::Endpoint,
+        sql_query::Response as SqlQueryResponse,
+        write::{Response, RetriedPartition},
+    },
+};
+
+/// Metadata key of the delay the server asks to wait before retrying, which
+/// comes along with the `ResourceExhausted` status.
+const RETRY_AFTER_KEY: &str = "retry-after";
+
+/// Phase of the request in `Direct` mode, in which the time runs out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeoutPhase {
+    /// Routing the tables, including building the router.
+    Routing,
+    /// Executing the request on the routed endpoints, including the retries.
+    Execution,
+}
 
 /// An error generated by the client.
 #[derive(Debug, ThisError)]
@@ -18,8 +38,8 @@ pub enum Error {
     /// Error from the rpc
     /// Note that any error caused by a running server wont be wrapped in the
     /// grpc errors.
-    #[error("failed in grpc, err:{0}")]
-    Rpc(tonic::Status),
+    #[error("failed in grpc, {0}")]
+    Rpc(RpcError),
 
     /// Error about rpc.
     /// It will be throw while connection between client and server is broken
@@ -35,10 +55,105 @@ pub enum Error {
     #[error("failed in client, msg:{0}")]
     Client(String),
 
+    /// The request exceeds the max length of the message sent to server, and
+    /// it should be split into smaller ones.
+    #[error("request is too large, estimated_size:{estimated_size}, limit:{limit}, try to split it into smaller requests")]
+    RequestTooLarge { limit: usize, estimated_size: usize },
+
+    /// The response exceeds the max length of the message received from
+    /// server, or the max size of the decoded response set by the
+    /// [`QueryGuardConfig`](crate::QueryGuardConfig), and the query should
+    /// fetch fewer rows at a time.
+    ///
+    /// The `partial` is the response of the rows decoded before the decoding
+    /// is aborted by the latter, see [`Error::partial_response`].
+    #[error("response is too large, endpoint:{endpoint}, limit:{limit}, try to query by pages with `sql_query_paged`")]
+    ResponseTooLarge {
+        limit: usize,
+        endpoint: String,
+        partial: Option<Box<SqlQueryResponse>>,
+    },
+
+    /// The circuit breaker of the endpoint is open after consecutive
+    /// failures, so the request is not sent.
+    #[error("circuit breaker is open, endpoint:{endpoint}")]
+    CircuitOpen { endpoint: String },
+
+    /// The endpoint is found unhealthy by the health checker, so the request
+    /// is not sent, and the routes to it are evicted to be refreshed.
+    #[error(
+        "endpoint is unhealthy, and the routes to it should be refreshed, endpoint:{endpoint}"
+    )]
+    EndpointUnhealthy { endpoint: String },
+
+    /// The point is rejected by the validation of the write request, and
+    /// the column is none if the point is invalid as a whole.
+    #[error("invalid point, table:{table}, index:{index}, column:{column:?}, reason:{reason}")]
+    InvalidPoint {
+        table: String,
+        index: usize,
+        column: Option<String>,
+        reason: String,
+    },
+
+    /// The request doesn't complete within the timeout of its context, and
+    /// the `phase` tells in which phase the time runs out.
+    #[error("request timed out, phase:{phase:?}, timeout:{timeout:?}")]
+    Timeout {
+        phase: TimeoutPhase,
+        timeout: Duration,
+    },
+
+    /// The request is cancelled by the
+    /// [`cancel`](crate::RpcContext::cancel) token of its context.
+    #[error("request is cancelled")]
+    Cancelled,
+
+    /// The text of the line protocol is malformed, and the `line` starts
+    /// from 1.
+    #[error("invalid line protocol, line:{line}, reason:{reason}")]
+    InvalidLineProtocol { line: usize, reason: String },
+
     /// Error about authentication
     #[error("failed to check auth, err:{0}")]
     AuthFail(AuthFailStatus),
 
+    /// The request to the endpoint the `table` is pinned to by the route
+    /// overrides fails, and the route is not refreshed on the failure.
+    #[error("failed on overridden route, table:{table}, endpoint:{endpoint}, err:{source}")]
+    RouteOverridden {
+        table: String,
+        endpoint: String,
+        source: Box<Error>,
+    },
+
+    /// The `tables` are unknown to the route service, and they are not sent
+    /// to the default endpoint because of the
+    /// [`strict_routing`](crate::RpcConfig::strict_routing).
+    #[error("failed to find the routes of tables:{tables:?}")]
+    RouteNotFound { tables: Vec<String> },
+
+    /// The batch can't be spilled by the
+    /// [`ResilientWriter`](crate::ResilientWriter) because the spill is full,
+    /// or the batch is larger than the `limit` itself.
+    #[error("spill is full, size:{size}, batch_size:{batch_size}, limit:{limit}")]
+    SpillFull {
+        size: u64,
+        batch_size: u64,
+        limit: u64,
+    },
+
+    /// Some points of the write merged with the concurrent ones by the
+    /// [`GroupCommitConfig`](crate::GroupCommitConfig) fail, and the server
+    /// doesn't tell which ones, so the `points` of this write may be partly
+    /// written and retrying it may duplicate the written ones.
+    #[error("failed partly in merged write, and the failed points can't be attributed, points:{points}, merged_success:{merged_success}, merged_failed:{merged_failed}")]
+    UnattributedWriteFailure {
+        points: u32,
+        merged_success: u32,
+        merged_failed: u32,
+    },
+
     /// Error from write in route based mode, some of rows may be written
     /// successfully, and others may fail.
     #[error("failed to write with route based client, err:{0}")]
@@ -56,6 +171,58 @@ pub enum Error {
 
     #[error("failed to find a database")]
     NoDatabase,
+
+    /// The response from the server is not in the expected shape, e.g. the
+    /// routes of the tables not requested.
+    #[error("malformed response from server, detail:{detail}")]
+    MalformedResponse { detail: String },
+}
+
+impl Error {
+    /// Get the delay the server asks to wait before retrying, e.g. when it is
+    /// rate limiting, which is carried in the `retry-after` metadata of the
+    /// `ResourceExhausted` status in seconds. None if it is absent or
+    /// malformed.
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            Error::Rpc(e) if e.code() == tonic::Code::ResourceExhausted => {
+                let value = e.status.metadata().get(RETRY_AFTER_KEY)?.to_str().ok()?;
+                let secs: f64 = value.trim().parse().ok()?;
+                if !secs.is_finite() || secs < 0.0 {
+                    return None;
+                }
+                Some(Duration::from_secs_f64(secs))
+            }
+            Error::RouteOverridden { source, .. } => source.retry_after(),
+            _ => None,
+        }
+    }
+
+    /// Whether the endpoint is unreachable as a whole rather than failing the
+    /// request, e.g. the connection is refused or the dns lookup fails, so
+    /// all the tables routed to it are affected.
+    pub fn is_endpoint_unreachable(&self) -> bool {
+        match self {
+            Error::Connect { .. } => true,
+            Error::Rpc(e) if e.code() == tonic::Code::Unavailable => {
+                let msg = e.message().to_lowercase();
+                msg.contains("connection refused") || msg.contains("dns error")
+            }
+            Error::RouteOverridden { source, .. } => source.is_endpoint_unreachable(),
+            _ => false,
+        }
+    }
+
+    /// Get the response of the rows decoded before the decoding is aborted
+    /// for the [`ResponseTooLarge`](Error::ResponseTooLarge), and it is none
+    /// for the other errors.
+    pub fn partial_response(&self) -> Option<&SqlQueryResponse> {
+        match self {
+            Error::ResponseTooLarge { partial, .. } => partial.as_deref(),
+            Error::RouteOverridden { source, .. } => source.partial_response(),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -66,16 +233,27 @@ pub struct RouteBasedWriteError {
 
 impl From<Vec<(Vec<String>, Result<Response>)>> for RouteBasedWriteError {
     fn from(write_results: Vec<(Vec<String>, Result<Response>)>) -> Self {
-        let mut success_total = 0;
-        let mut failed_total = 0;
         let mut ok_tables = Vec::new();
+        let mut ok_resps = Vec::new();
         let mut errors = Vec::new();
         for (tables, write_result) in write_results {
             match write_result {
-                Ok(write_resp) => {
-                    success_total += write_resp.success;
-                    failed_total += write_resp.failed;
+                Ok(mut write_resp) => {
+                    let info = &write_resp.execution_info;
+                    if info.retries > 0 {
+                        let retried = RetriedPartition {
+                            endpoint: info
+                                .endpoint
+                                .as_ref()
+                                .map(Endpoint::to_string)
+                                .unwrap_or_default(),
+                            tables: tables.clone(),
+                            attempts: info.retries + 1,
+                        };
+                        write_resp.retried_partitions.insert(0, retried);
+                    }
                     ok_tables.extend(tables);
+                    ok_resps.push(write_resp);
                 }
                 Err(e) => {
                     errors.push((tables, e));
@@ -84,7 +262,7 @@ impl From<Vec<(Vec<String>, Result<Response>)>> for RouteBasedWriteError {
         }
 
         Self {
-            ok: (ok_tables, Response::new(success_total, failed_total)),
+            ok: (ok_tables, Response::merge(ok_resps)),
             errors,
         }
     }
@@ -105,6 +283,80 @@ impl Display for RouteBasedWriteError {
     }
 }
 
+/// The grpc status of the failed rpc, along with where and when it fails.
+///
+/// The context is filled by the client sending the rpc, and it is none or
+/// empty if unknown, e.g. the tables of the stream writes.
+#[derive(Debug, Clone)]
+pub struct RpcError {
+    pub status: tonic::Status,
+    pub operation: Option<OperationKind>,
+    /// The endpoint in the form of `addr:port`.
+    pub endpoint: Option<String>,
+    /// The tables in the request sent to the endpoint.
+    pub tables: Vec<String>,
+    /// The time spent on the rpc before it fails.
+    pub elapsed: Option<Duration>,
+}
+
+impl RpcError {
+    pub fn code(&self) -> tonic::Code {
+        self.status.code()
+    }
+
+    pub fn message(&self) -> &str {
+        self.status.message()
+    }
+
+    /// Fill the context not set yet, so the one closest to the rpc wins.
+    pub(crate) fn fill_context(
+        &mut self,
+        operation: OperationKind,
+        endpoint: &str,
+        tables: Vec<String>,
+        elapsed: Duration,
+    ) {
+        self.operation.get_or_insert(operation);
+        self.endpoint.get_or_insert_with(|| endpoint.to_string());
+        if self.tables.is_empty() {
+            self.tables = tables;
+        }
+        self.elapsed.get_or_insert(elapsed);
+    }
+}
+
+impl From<tonic::Status> for RpcError {
+    fn from(status: tonic::Status) -> Self {
+        Self {
+            status,
+            operation: None,
+            endpoint: None,
+            tables: Vec::new(),
+            elapsed: None,
+        }
+    }
+}
+
+impl Display for RpcError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(operation) = self.operation {
+            write!(f, "operation:{operation}, ")?;
+        }
+        if let Some(endpoint) = &self.endpoint {
+            write!(f, "endpoint:{endpoint}, ")?;
+        }
+        if !self.tables.is_empty() {
+            write!(f, "tables:{:?}, ", self.tables)?;
+        }
+        write!(f, "code:{:?}, message:{}", self.code(), self.message())?;
+        if let Some(elapsed) = self.elapsed {
+            write!(f, ", elapsed:{elapsed:?}")?;
+        }
+
+        Ok(())
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct ServerError {
     pub code: u32,
@@ -163,4 +415,100 @@ mod test {
             r#"failed to connect, addr:"1.1.1.1:1111", err:Unknown("unknown error")"#
         );
     }
+
+    #[test]
+    fn test_rpc_error_display() {
+        let mut rpc_error = RpcError::from(tonic::Status::unavailable("connection refused"));
+        assert_eq!(
+            Error::Rpc(rpc_error.clone()).to_string(),
+            "failed in grpc, code:Unavailable, message:connection refused"
+        );
+
+        rpc_error.fill_context(
+            OperationKind::Write,
+            "127.0.0.1:8831",
+            vec!["t1".to_string(), "t2".to_string()],
+            Duration::from_millis(12),
+        );
+        // The context filled first wins.
+        rpc_error.fill_context(
+            OperationKind::Route,
+            "127.0.0.1:8832",
+            Vec::new(),
+            Duration::from_millis(20),
+        );
+        assert_eq!(
+            Error::Rpc(rpc_error).to_string(),
+            r#"failed in grpc, operation:write, endpoint:127.0.0.1:8831, tables:["t1", "t2"], code:Unavailable, message:connection refused, elapsed:12ms"#
+        );
+    }
+
+    #[test]
+    fn test_retry_after() {
+        let status = |code, retry_after: Option<&str>| {
+            let mut metadata = tonic::metadata::MetadataMap::new();
+            if let Some(retry_after) = retry_after {
+                metadata.insert(RETRY_AFTER_KEY, retry_after.parse().unwrap());
+            }
+            Error::Rpc(tonic::Status::with_metadata(code, "rate limited", metadata).into())
+        };
+
+        let exhausted = tonic::Code::ResourceExhausted;
+        assert_eq!(
+            status(exhausted, Some("2")).retry_after(),
+            Some(Duration::from_secs(2))
+        );
+        assert_eq!(
+            status(exhausted, Some(" 0.25 ")).retry_after(),
+            Some(Duration::from_millis(250))
+        );
+        let overridden = Error::RouteOverridden {
+            table: "t".to_string(),
+            endpoint: "127.0.0.1:8831".to_string(),
+            source: Box::new(status(exhausted, Some("1"))),
+        };
+        assert_eq!(overridden.retry_after(), Some(Duration::from_secs(1)));
+
+        for e in [
+            status(exhausted, None),
+            status(exhausted, Some("-1")),
+            status(exhausted, Some("soon")),
+            status(tonic::Code::Unavailable, Some("1")),
+        ] {
+            assert_eq!(e.retry_after(), None, "e:{e:?}");
+        }
+    }
+
+    #[test]
+    fn test_is_endpoint_unreachable() {
+        let rpc_error = |code, msg: &str| Error::Rpc(tonic::Status::new(code, msg).into());
+        let unavailable = tonic::Code::Unavailable;
+        let connect_error = Error::Connect {
+            addr: "127.0.0.1:8831".to_string(),
+            source: "invalid endpoint".into(),
+        };
+        for e in [
+            connect_error,
+            rpc_error(
+                unavailable,
+                "error trying to connect: tcp connect error: Connection refused (os error 111)",
+            ),
+            rpc_error(
+                unavailable,
+                "error trying to connect: dns error: failed to lookup address information",
+            ),
+        ] {
+            assert!(e.is_endpoint_unreachable(), "e:{e:?}");
+        }
+
+        for e in [
+            rpc_error(unavailable, "server is shutting down"),
+            rpc_error(tonic::Code::Internal, "connection refused"),
+            Error::EndpointUnhealthy {
+                endpoint: "127.0.0.1:8831".to_string(),
+            },
+        ] {
+            assert!(!e.is_endpoint_unreachable(), "e:{e:?}");
+        }
+    }
 }
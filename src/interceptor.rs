@@ -0,0 +1,451 @@
+// Copyright 2022 CeresDB Project Authors. Licensed under Apache-2.0.
+
+//! Interceptors around every rpc
+
+use std::{fmt, future::Future, sync::Arc, time::Duration};
+
+use async_trait::async_trait;
+use tonic::metadata::MetadataMap;
+
+use crate::{
+    clock::{Clock, SystemClock},
+    rpc_client::RpcContext,
+    Error, Result,
+};
+
+/// Kind of the rpc intercepted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OperationKind {
+    Route,
+    Write,
+    StreamWrite,
+    SqlQuery,
+}
+
+impl OperationKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            OperationKind::Route => "route",
+            OperationKind::Write => "write",
+            OperationKind::StreamWrite => "stream_write",
+            OperationKind::SqlQuery => "sql_query",
+        }
+    }
+}
+
+impl fmt::Display for OperationKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Interceptor called around every rpc, including the ones to the route
+/// service.
+///
+/// The `before` hooks are called in the order of registering, and the `after`
+/// hooks in the reverse order.
+#[async_trait]
+pub trait RequestInterceptor: Send + Sync {
+    /// Called before the rpc is sent, and the changes to the `ctx` and the
+    /// `metadata` are seen by the following interceptors and the rpc.
+    ///
+    /// The rpc is aborted with the returned error, and the following
+    /// interceptors are skipped then.
+    async fn before(
+        &self,
+        _ctx: &mut RpcContext,
+        _metadata: &mut MetadataMap,
+        _op: OperationKind,
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called after the rpc completes or is aborted, and only the interceptors
+    /// whose `before` succeeds are called.
+    async fn after(
+        &self,
+        _ctx: &RpcContext,
+        _op: OperationKind,
+        _result: std::result::Result<(), &Error>,
+        _elapsed: Duration,
+    ) {
+    }
+}
+
+/// Log every rpc if the `tracing` feature is enabled, and the failed ones are
+/// logged at the warn level.
+#[derive(Debug, Clone, Default)]
+pub struct LoggingInterceptor;
+
+#[async_trait]
+impl RequestInterceptor for LoggingInterceptor {
+    async fn after(
+        &self,
+        ctx: &RpcContext,
+        op: OperationKind,
+        result: std::result::Result<(), &Error>,
+        elapsed: Duration,
+    ) {
+        #[cfg(feature = "tracing")]
+        match result {
+            Ok(()) => tracing::debug!(
+                operation = %op,
+                database = ?ctx.database,
+                elapsed_ms = elapsed.as_millis() as u64,
+                "rpc succeeded"
+            ),
+            Err(e) => tracing::warn!(
+                operation = %op,
+                database = ?ctx.database,
+                elapsed_ms = elapsed.as_millis() as u64,
+                error = %e,
+                "rpc failed"
+            ),
+        }
+
+        #[cfg(not(feature = "tracing"))]
+        let _ = (ctx, op, result, elapsed);
+    }
+}
+
+/// The interceptors chained in order, which is an interceptor itself, e.g. to
+/// register the interceptors of the signing, the tracing and the headers as
+/// one by [`Builder::interceptor`](crate::Builder::interceptor).
+///
+/// The chained interceptors are called like the registered ones, and the
+/// `after` hooks of the ones entered are called if a `before` hook aborts.
+#[derive(Clone)]
+pub struct Interceptors {
+    interceptors: Vec<Arc<dyn RequestInterceptor>>,
+    // Measure the elapsed time passed to the `after` hooks.
+    clock: Arc<dyn Clock>,
+}
+
+impl Default for Interceptors {
+    fn default() -> Self {
+        Self {
+            interceptors: Vec::new(),
+            clock: Arc::new(SystemClock),
+        }
+    }
+}
+
+impl Interceptors {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Measure the elapsed time of the rpcs by the `clock` instead of the
+    /// system time.
+    pub(crate) fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub(crate) fn clock(&self) -> Arc<dyn Clock> {
+        self.clock.clone()
+    }
+
+    /// Chain the `interceptor` after the ones chained.
+    pub fn with(mut self, interceptor: Arc<dyn RequestInterceptor>) -> Self {
+        self.push(interceptor);
+        self
+    }
+
+    pub fn push(&mut self, interceptor: Arc<dyn RequestInterceptor>) {
+        self.interceptors.push(interceptor);
+    }
+
+    pub fn len(&self) -> usize {
+        self.interceptors.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.interceptors.is_empty()
+    }
+
+    /// Call the rpc with the context and the metadata passed through the
+    /// interceptors.
+    pub(crate) async fn run<O, C, Fut>(
+        &self,
+        ctx: &RpcContext,
+        op: OperationKind,
+        call: C,
+    ) -> Result<O>
+    where
+        C: FnOnce(RpcContext, MetadataMap) -> Fut,
+        Fut: Future<Output = Result<O>>,
+    {
+        if self.interceptors.is_empty() {
+            return call(ctx.clone(), MetadataMap::new()).await;
+        }
+
+        let begin = self.clock.now();
+        let mut ctx = ctx.clone();
+        let mut metadata = MetadataMap::new();
+        let mut entered = 0;
+        let mut aborted = None;
+        for interceptor in &self.interceptors {
+            if let Err(e) = interceptor.before(&mut ctx, &mut metadata, op).await {
+                aborted = Some(e);
+                break;
+            }
+            entered += 1;
+        }
+
+        let result = match aborted {
+            Some(e) => Err(e),
+            None => call(ctx.clone(), metadata).await,
+        };
+        let elapsed = self.clock.now() - begin;
+        for interceptor in self.interceptors[..entered].iter().rev() {
+            interceptor
+                .after(&ctx, op, result.as_ref().map(|_| ()), elapsed)
+                .await;
+        }
+
+        result
+    }
+}
+
+#[async_trait]
+impl RequestInterceptor for Interceptors {
+    async fn before(
+        &self,
+        ctx: &mut RpcContext,
+        metadata: &mut MetadataMap,
+        op: OperationKind,
+    ) -> Result<()> {
+        let begin = self.clock.now();
+        for (entered, interceptor) in self.interceptors.iter().enumerate() {
+            if let Err(e) = interceptor.before(ctx, metadata, op).await {
+                // The chain isn't entered, so the entered ones are exited here.
+                for interceptor in self.interceptors[..entered].iter().rev() {
+                    let elapsed = self.clock.now() - begin;
+                    interceptor.after(ctx, op, Err(&e), elapsed).await;
+                }
+                return Err(e);
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn after(
+        &self,
+        ctx: &RpcContext,
+        op: OperationKind,
+        result: std::result::Result<(), &Error>,
+        elapsed: Duration,
+    ) {
+        for interceptor in self.interceptors.iter().rev() {
+            interceptor.after(ctx, op, result, elapsed).await;
+        }
+    }
+}
+
+impl fmt::Debug for Interceptors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Interceptors({})", self.interceptors.len())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use async_trait::async_trait;
+    use tonic::metadata::MetadataMap;
+
+    use super::{Interceptors, LoggingInterceptor, OperationKind, RequestInterceptor};
+    use crate::{rpc_client::RpcContext, Error, Result};
+
+    type Events = Arc<Mutex<Vec<String>>>;
+
+    /// Interceptor recording the calls, which adds its name to the `trace`
+    /// metadata and aborts if `abort` is set.
+    struct RecordingInterceptor {
+        name: &'static str,
+        abort: bool,
+        events: Events,
+    }
+
+    #[async_trait]
+    impl RequestInterceptor for RecordingInterceptor {
+        async fn before(
+            &self,
+            ctx: &mut RpcContext,
+            metadata: &mut MetadataMap,
+            op: OperationKind,
+        ) -> Result<()> {
+            self.events
+                .lock()
+                .unwrap()
+                .push(format!("before {} {op}", self.name));
+            if self.abort {
+                return Err(Error::Client(format!("aborted by {}", self.name)));
+            }
+
+            let trace = match metadata.get("trace") {
+                Some(trace) => format!("{},{}", trace.to_str().unwrap(), self.name),
+                None => self.name.to_string(),
+            };
+            metadata.insert("trace", trace.parse().unwrap());
+            ctx.timeout = Some(Duration::from_secs(1));
+            Ok(())
+        }
+
+        async fn after(
+            &self,
+            _ctx: &RpcContext,
+            op: OperationKind,
+            result: std::result::Result<(), &Error>,
+            _elapsed: Duration,
+        ) {
+            let outcome = if result.is_ok() { "ok" } else { "err" };
+            self.events
+                .lock()
+                .unwrap()
+                .push(format!("after {} {op} {outcome}", self.name));
+        }
+    }
+
+    fn interceptors(abort: &[bool], events: &Events) -> Interceptors {
+        let mut interceptors = Interceptors::default();
+        interceptors.push(Arc::new(LoggingInterceptor));
+        for (name, abort) in ["a", "b", "c"].into_iter().zip(abort) {
+            interceptors.push(Arc::new(RecordingInterceptor {
+                name,
+                abort: *abort,
+                events: events.clone(),
+            }));
+        }
+        interceptors
+    }
+
+    #[tokio::test]
+    async fn test_interceptors_order() {
+        let events = Events::default();
+        let interceptors = interceptors(&[false, false, false], &events);
+
+        let result = interceptors
+            .run(
+                &RpcContext::default(),
+                OperationKind::Write,
+                |ctx, metadata| {
+                    events.lock().unwrap().push("call".to_string());
+                    async move {
+                        assert_eq!(ctx.timeout, Some(Duration::from_secs(1)));
+                        Ok(metadata.get("trace").unwrap().to_str().unwrap().to_string())
+                    }
+                },
+            )
+            .await;
+        assert_eq!(result.unwrap(), "a,b,c");
+        assert_eq!(
+            *events.lock().unwrap(),
+            [
+                "before a write",
+                "before b write",
+                "before c write",
+                "call",
+                "after c write ok",
+                "after b write ok",
+                "after a write ok",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_chained_interceptors() {
+        let events = Events::default();
+        let recording = |name, abort| {
+            Arc::new(RecordingInterceptor {
+                name,
+                abort,
+                events: events.clone(),
+            })
+        };
+        let mut interceptors = Interceptors::default();
+        interceptors.push(recording("a", false));
+        interceptors.push(Arc::new(
+            Interceptors::new()
+                .with(recording("b", false))
+                .with(recording("c", false)),
+        ));
+        interceptors.push(recording("d", false));
+
+        let result = interceptors
+            .run(
+                &RpcContext::default(),
+                OperationKind::SqlQuery,
+                |_, metadata| {
+                    events.lock().unwrap().push("call".to_string());
+                    async move { Ok(metadata.get("trace").unwrap().to_str().unwrap().to_string()) }
+                },
+            )
+            .await;
+        assert_eq!(result.unwrap(), "a,b,c,d");
+        assert_eq!(
+            *events.lock().unwrap(),
+            [
+                "before a sql_query",
+                "before b sql_query",
+                "before c sql_query",
+                "before d sql_query",
+                "call",
+                "after d sql_query ok",
+                "after c sql_query ok",
+                "after b sql_query ok",
+                "after a sql_query ok",
+            ]
+        );
+
+        // The entered ones of the chain are exited when the chain aborts.
+        events.lock().unwrap().clear();
+        let mut interceptors = Interceptors::default();
+        interceptors.push(recording("a", false));
+        interceptors.push(Arc::new(
+            Interceptors::new()
+                .with(recording("b", false))
+                .with(recording("c", true)),
+        ));
+        let result = interceptors
+            .run(&RpcContext::default(), OperationKind::Write, |_, _| async {
+                Ok(())
+            })
+            .await;
+        assert!(matches!(result, Err(Error::Client(msg)) if msg == "aborted by c"));
+        assert_eq!(
+            *events.lock().unwrap(),
+            [
+                "before a write",
+                "before b write",
+                "before c write",
+                "after b write err",
+                "after a write err",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_interceptors_abort() {
+        let events = Events::default();
+        let interceptors = interceptors(&[false, true, false], &events);
+
+        let result = interceptors
+            .run(&RpcContext::default(), OperationKind::Route, |_, _| {
+                events.lock().unwrap().push("call".to_string());
+                async { Ok(()) }
+            })
+            .await;
+        assert!(matches!(result, Err(Error::Client(msg)) if msg == "aborted by b"));
+        assert_eq!(
+            *events.lock().unwrap(),
+            ["before a route", "before b route", "after a route err"]
+        );
+    }
+}
@@ -46,10 +46,7 @@
 //!     TIMESTAMP KEY(t)) ENGINE=Analytic with
 //!     (enable_ttl='false')"#;
 //!
-//! let req = SqlQueryRequest {
-//!     tables: vec!["ceresdb".to_string()],
-//!     sql: create_table_sql.to_string(),
-//! };
+//! let req = SqlQueryRequest::new(vec!["ceresdb".to_string()], create_table_sql.to_string());
 //! let resp = client
 //!     .sql_query(&rpc_ctx, &req)
 //!     .await
@@ -60,24 +57,68 @@
 //! # }
 //! ```
 
+mod auth;
+mod capture;
+mod clock;
 mod config;
 #[doc(hidden)]
 pub mod db_client;
 mod errors;
+mod interceptor;
+mod metrics;
 #[doc(hidden)]
 pub mod model;
-mod router;
+mod route_cache;
+#[doc(hidden)]
+pub mod router;
 mod rpc_client;
+mod spawner;
 mod util;
 
+#[doc(no_inline)]
+pub use tokio_util::sync::CancellationToken;
+
+#[cfg(feature = "testing")]
+#[doc(inline)]
+pub use crate::clock::{Clock, ManualClock, SystemClock};
+#[cfg(feature = "blocking")]
+#[doc(inline)]
+pub use crate::db_client::{BlockingDbClient, BlockingRuntime};
 #[doc(inline)]
 pub use crate::{
-    config::RpcConfig,
-    db_client::{Builder, DbClient, Mode},
-    errors::{Error, Result},
+    auth::{AuthProvider, CachingProvider, StaticTokenProvider},
+    capture::{CapturedRequest, FileCapture, RequestCapture},
+    config::{
+        AutoCreateTableConfig, CircuitBreakerConfig, FileRouteCacheConfig, GroupCommitConfig,
+        HealthCheckConfig, LoadBalancePolicy, MetricsConfig, PassiveHealthConfig, ProxyConfig,
+        QueryCacheConfig, QueryGuardConfig, ReadYourWritesConfig, ReconnectConfig, RoutingBudget,
+        RpcConfig, SettingsTransport, SlowRequestThreshold, SpillConfig, SpillFullPolicy,
+        WriteStatsConfig,
+    },
+    db_client::{
+        BreakerState, Builder, ChannelStats, ConnectionState, DatabaseScopedClient, DbClient,
+        DiscoveryProvider, DnsDiscovery, ExponentialBackoff, GroupCommitStats, HealthProbe,
+        HedgeStats, Mode, Operation, PaginatedQuery, QueryCacheStats, ResilientWriter, RetryPolicy,
+        SlowRequestInfo, StaticList, TableWriteStats, TcpProbe, WriteOutcome,
+    },
+    errors::{Error, Result, RpcError, TimeoutPhase},
+    interceptor::{Interceptors, LoggingInterceptor, OperationKind, RequestInterceptor},
+    metrics::ClientMetrics,
     model::{
-        sql_query::{Request as SqlQueryRequest, Response as SqlQueryResponse},
-        write::{Request as WriteRequest, Response as WriteResponse},
+        ddl::{TableDefinition, TableDefinitionBuilder},
+        execution_info::ExecutionInfo,
+        explain::{PlanLine, PlanSection, QueryPlan},
+        schema::{ColumnRole, ColumnSchema, TableSchema},
+        sql_query::{
+            MultiStatementRequest, Output as SqlQueryOutput, Projection,
+            Request as SqlQueryRequest, Response as SqlQueryResponse, StatementResult,
+        },
+        warning::ServerWarning,
+        write::{
+            point::TimestampPrecision, FieldCompression, Normalization, Request as WriteRequest,
+            Response as WriteResponse, RetriedPartition, ValidationMode,
+        },
     },
-    rpc_client::RpcContext,
+    route_cache::{FileRouteCache, MemoryRouteCache, RouteCache, RouteEntry, RouteKey},
+    rpc_client::{Consistency, Priority, RpcContext, ServerCapabilities, SessionSettings},
 };
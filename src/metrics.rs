@@ -0,0 +1,645 @@
+// Copyright 2022 CeresDB Project Authors. Licensed under Apache-2.0.
+
+//! Metrics of the client collected in the memory
+
+#[cfg(feature = "prometheus")]
+use std::fmt::Write;
+use std::{
+    collections::{BTreeMap, HashSet},
+    fmt,
+    sync::{Arc, Mutex, PoisonError},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+
+use crate::{
+    config::MetricsConfig,
+    db_client::{BreakerState, HedgeStats},
+    interceptor::{OperationKind, RequestInterceptor},
+    rpc_client::RpcContext,
+    Error,
+};
+
+/// The label of the endpoints beyond the
+/// [`max_endpoints`](MetricsConfig::max_endpoints), and the databases beyond
+/// the [`max_databases`](MetricsConfig::max_databases).
+const OTHER: &str = "other";
+
+#[derive(Debug, Clone)]
+struct Histogram {
+    // The count of the observations in every bucket, not cumulative.
+    buckets: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn new(bucket_amount: usize) -> Self {
+        Self {
+            buckets: vec![0; bucket_amount],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    fn observe(&mut self, bounds: &[f64], value: f64) {
+        if let Some(idx) = bounds.iter().position(|bound| value <= *bound) {
+            self.buckets[idx] += 1;
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+#[derive(Debug, Default)]
+struct MetricsState {
+    // The endpoints and the databases having their own labels.
+    endpoints: HashSet<String>,
+    databases: HashSet<String>,
+    // Keyed by (operation, database, endpoint, outcome).
+    requests: BTreeMap<(&'static str, String, String, &'static str), u64>,
+    // Keyed by (operation, endpoint).
+    latencies: BTreeMap<(&'static str, String), Histogram>,
+    retries: BTreeMap<(&'static str, String), u64>,
+    route_cache_hits: u64,
+    route_cache_misses: u64,
+    hedges: HedgeStats,
+    // Keyed by the endpoint, and 0 for closed, 1 for half open and 2 for open.
+    breaker_states: BTreeMap<String, u8>,
+}
+
+impl MetricsState {
+    /// The label of the `endpoint`, which is [`OTHER`] once there are
+    /// `max_endpoints` labels already.
+    fn endpoint_label(&mut self, endpoint: &str, max_endpoints: usize) -> String {
+        capped_label(&mut self.endpoints, endpoint, max_endpoints)
+    }
+
+    /// The label of the `database`, which is [`OTHER`] once there are
+    /// `max_databases` labels already.
+    fn database_label(&mut self, database: &str, max_databases: usize) -> String {
+        capped_label(&mut self.databases, database, max_databases)
+    }
+}
+
+fn capped_label(labels: &mut HashSet<String>, value: &str, max_labels: usize) -> String {
+    if labels.contains(value) {
+        return value.to_string();
+    }
+    if labels.len() < max_labels {
+        labels.insert(value.to_string());
+        return value.to_string();
+    }
+
+    OTHER.to_string()
+}
+
+/// Metrics of the client collected in the memory, including the rpcs by the
+/// operation, the database, the endpoint and the outcome, their latencies, the
+/// retries, the route cache lookups, the hedged queries and the circuit breaker
+/// states.
+///
+/// It is registered by [`Builder::metrics`](crate::Builder::metrics), and a
+/// clone can be kept to read the metrics, which are rendered in the Prometheus
+/// text format by `render_prometheus` with the `prometheus` feature enabled.
+/// Cloning is cheap, and the clones share the metrics.
+#[derive(Clone)]
+pub struct ClientMetrics {
+    config: Arc<MetricsConfig>,
+    state: Arc<Mutex<MetricsState>>,
+}
+
+impl fmt::Debug for ClientMetrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ClientMetrics")
+            .field("config", &self.config)
+            .finish()
+    }
+}
+
+impl Default for ClientMetrics {
+    fn default() -> Self {
+        Self::new(MetricsConfig::default())
+    }
+}
+
+impl ClientMetrics {
+    pub fn new(mut config: MetricsConfig) -> Self {
+        config
+            .latency_buckets
+            .retain(|bound| bound.is_finite() && *bound >= 0.0);
+        config
+            .latency_buckets
+            .sort_unstable_by(|a, b| a.total_cmp(b));
+        config.latency_buckets.dedup();
+
+        Self {
+            config: Arc::new(config),
+            state: Arc::new(Mutex::new(MetricsState::default())),
+        }
+    }
+
+    /// The number of the rpcs of the `op` to the `endpoint` succeeded or not
+    /// in all the databases, and the `endpoint` beyond the
+    /// [`max_endpoints`](MetricsConfig::max_endpoints) is counted as `other`.
+    pub fn request_count(&self, op: OperationKind, endpoint: &str, success: bool) -> u64 {
+        let state = self.state();
+        state
+            .requests
+            .iter()
+            .filter(|((request_op, _, request_endpoint, request_outcome), _)| {
+                *request_op == op.as_str()
+                    && request_endpoint == endpoint
+                    && *request_outcome == outcome(success)
+            })
+            .map(|(_, count)| count)
+            .sum()
+    }
+
+    /// The number of the rpcs of the `op` to the `endpoint` succeeded or not
+    /// in the `database`, and the `database` beyond the
+    /// [`max_databases`](MetricsConfig::max_databases) is counted as `other`.
+    pub fn database_request_count(
+        &self,
+        op: OperationKind,
+        database: &str,
+        endpoint: &str,
+        success: bool,
+    ) -> u64 {
+        let state = self.state();
+        state
+            .requests
+            .get(&(
+                op.as_str(),
+                database.to_string(),
+                endpoint.to_string(),
+                outcome(success),
+            ))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// The numbers of the hits and the misses of the route cache lookups.
+    pub fn route_cache_lookups(&self) -> (u64, u64) {
+        let state = self.state();
+        (state.route_cache_hits, state.route_cache_misses)
+    }
+
+    /// The numbers of the hedged queries issued, won and throttled.
+    pub fn hedges(&self) -> HedgeStats {
+        self.state().hedges
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, MetricsState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub(crate) fn record_request(
+        &self,
+        op: OperationKind,
+        database: &str,
+        endpoint: &str,
+        success: bool,
+        elapsed: Duration,
+    ) {
+        let bounds = &self.config.latency_buckets;
+        let mut state = self.state();
+        let database = state.database_label(database, self.config.max_databases);
+        let endpoint = state.endpoint_label(endpoint, self.config.max_endpoints);
+        *state
+            .requests
+            .entry((op.as_str(), database, endpoint.clone(), outcome(success)))
+            .or_default() += 1;
+        state
+            .latencies
+            .entry((op.as_str(), endpoint))
+            .or_insert_with(|| Histogram::new(bounds.len()))
+            .observe(bounds, elapsed.as_secs_f64());
+    }
+
+    pub(crate) fn record_retries(&self, op: OperationKind, endpoint: &str, retries: usize) {
+        if retries == 0 {
+            return;
+        }
+
+        let mut state = self.state();
+        let endpoint = state.endpoint_label(endpoint, self.config.max_endpoints);
+        *state.retries.entry((op.as_str(), endpoint)).or_default() += retries as u64;
+    }
+
+    pub(crate) fn record_route_cache(&self, hits: usize, misses: usize) {
+        let mut state = self.state();
+        state.route_cache_hits += hits as u64;
+        state.route_cache_misses += misses as u64;
+    }
+
+    /// Add the numbers of the hedged queries in the `hedges`.
+    pub(crate) fn record_hedges(&self, hedges: HedgeStats) {
+        let mut state = self.state();
+        state.hedges.issued += hedges.issued;
+        state.hedges.won += hedges.won;
+        state.hedges.throttled += hedges.throttled;
+    }
+
+    pub(crate) fn record_breaker_state(&self, endpoint: &str, breaker_state: &BreakerState) {
+        let value = match breaker_state {
+            BreakerState::Closed => 0,
+            BreakerState::HalfOpen => 1,
+            BreakerState::Open { .. } => 2,
+        };
+        let mut state = self.state();
+        let endpoint = state.endpoint_label(endpoint, self.config.max_endpoints);
+        // The states of the endpoints sharing the label can't be told apart.
+        if endpoint != OTHER {
+            state.breaker_states.insert(endpoint, value);
+        }
+    }
+
+    /// Interceptor recording the rpcs to the `endpoint`.
+    pub(crate) fn interceptor(&self, endpoint: String) -> MetricsInterceptor {
+        MetricsInterceptor {
+            metrics: self.clone(),
+            endpoint,
+        }
+    }
+
+    /// Render the metrics in the Prometheus text exposition format, e.g. to be
+    /// served from the `/metrics` endpoint of the application.
+    #[cfg(feature = "prometheus")]
+    pub fn render_prometheus(&self) -> String {
+        let state = self.state();
+        let mut out = String::new();
+
+        write_header(
+            &mut out,
+            "ceresdb_client_requests_total",
+            "The rpcs sent by the client.",
+            "counter",
+        );
+        for ((op, database, endpoint, outcome), count) in &state.requests {
+            let labels = labels(&[
+                ("operation", op),
+                ("database", database),
+                ("endpoint", endpoint),
+                ("outcome", outcome),
+            ]);
+            let _ = writeln!(out, "ceresdb_client_requests_total{{{labels}}} {count}");
+        }
+
+        write_header(
+            &mut out,
+            "ceresdb_client_request_duration_seconds",
+            "Latency of the rpcs sent by the client.",
+            "histogram",
+        );
+        for ((op, endpoint), histogram) in &state.latencies {
+            let labels = labels(&[("operation", op), ("endpoint", endpoint)]);
+            let mut cumulative = 0;
+            for (bound, count) in self.config.latency_buckets.iter().zip(&histogram.buckets) {
+                cumulative += count;
+                let _ = writeln!(
+                    out,
+                    "ceresdb_client_request_duration_seconds_bucket{{{labels},le=\"{bound}\"}} \
+                     {cumulative}"
+                );
+            }
+            let _ = writeln!(
+                out,
+                "ceresdb_client_request_duration_seconds_bucket{{{labels},le=\"+Inf\"}} {}",
+                histogram.count
+            );
+            let _ = writeln!(
+                out,
+                "ceresdb_client_request_duration_seconds_sum{{{labels}}} {}",
+                histogram.sum
+            );
+            let _ = writeln!(
+                out,
+                "ceresdb_client_request_duration_seconds_count{{{labels}}} {}",
+                histogram.count
+            );
+        }
+
+        write_header(
+            &mut out,
+            "ceresdb_client_retries_total",
+            "The retries of the failed rpcs.",
+            "counter",
+        );
+        for ((op, endpoint), count) in &state.retries {
+            let labels = labels(&[("operation", op), ("endpoint", endpoint)]);
+            let _ = writeln!(out, "ceresdb_client_retries_total{{{labels}}} {count}");
+        }
+
+        write_header(
+            &mut out,
+            "ceresdb_client_route_cache_lookups_total",
+            "The lookups of the tables in the route cache.",
+            "counter",
+        );
+        for (result, count) in [
+            ("hit", state.route_cache_hits),
+            ("miss", state.route_cache_misses),
+        ] {
+            let labels = labels(&[("result", result)]);
+            let _ = writeln!(
+                out,
+                "ceresdb_client_route_cache_lookups_total{{{labels}}} {count}"
+            );
+        }
+
+        write_header(
+            &mut out,
+            "ceresdb_client_hedges_total",
+            "The hedged sql queries issued, won or throttled by the in-flight limit.",
+            "counter",
+        );
+        for (result, count) in [
+            ("issued", state.hedges.issued),
+            ("won", state.hedges.won),
+            ("throttled", state.hedges.throttled),
+        ] {
+            let labels = labels(&[("result", result)]);
+            let _ = writeln!(out, "ceresdb_client_hedges_total{{{labels}}} {count}");
+        }
+
+        write_header(
+            &mut out,
+            "ceresdb_client_circuit_breaker_state",
+            "State of the circuit breaker of the endpoint, 0 for closed, 1 for half open and \
+             2 for open.",
+            "gauge",
+        );
+        for (endpoint, value) in &state.breaker_states {
+            let labels = labels(&[("endpoint", endpoint)]);
+            let _ = writeln!(
+                out,
+                "ceresdb_client_circuit_breaker_state{{{labels}}} {value}"
+            );
+        }
+
+        out
+    }
+}
+
+fn outcome(success: bool) -> &'static str {
+    if success {
+        "ok"
+    } else {
+        "error"
+    }
+}
+
+#[cfg(feature = "prometheus")]
+fn write_header(out: &mut String, name: &str, help: &str, metric_type: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {metric_type}");
+}
+
+/// Format the labels with the values escaped.
+#[cfg(feature = "prometheus")]
+fn labels(labels: &[(&str, &str)]) -> String {
+    labels
+        .iter()
+        .map(|(name, value)| {
+            let value = value
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n");
+            format!("{name}=\"{value}\"")
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Interceptor recording the rpcs to an endpoint in the [`ClientMetrics`].
+pub(crate) struct MetricsInterceptor {
+    metrics: ClientMetrics,
+    endpoint: String,
+}
+
+#[async_trait]
+impl RequestInterceptor for MetricsInterceptor {
+    async fn after(
+        &self,
+        ctx: &RpcContext,
+        op: OperationKind,
+        result: std::result::Result<(), &Error>,
+        elapsed: Duration,
+    ) {
+        let database = ctx.database.as_deref().unwrap_or_default();
+        self.metrics
+            .record_request(op, database, &self.endpoint, result.is_ok(), elapsed);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::ClientMetrics;
+    use crate::{config::MetricsConfig, interceptor::OperationKind};
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_concurrent_updates() {
+        let metrics = ClientMetrics::new(MetricsConfig {
+            max_endpoints: 2,
+            latency_buckets: vec![0.1, 0.01, 1.0],
+            ..Default::default()
+        });
+        let handles: Vec<_> = (0..8)
+            .map(|i| {
+                let metrics = metrics.clone();
+                tokio::spawn(async move {
+                    let endpoint = format!("192.168.0.{}:8831", i % 4);
+                    for n in 0..100 {
+                        let elapsed = Duration::from_millis(n % 3 * 50);
+                        metrics.record_request(
+                            OperationKind::Write,
+                            "public",
+                            &endpoint,
+                            n % 10 != 0,
+                            elapsed,
+                        );
+                        metrics.record_route_cache(1, 1);
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.await.unwrap();
+        }
+
+        // Only two endpoints have their own labels.
+        let mut counts: Vec<_> = (0..4)
+            .map(|i| format!("192.168.0.{i}:8831"))
+            .chain(Some("other".to_string()))
+            .map(|endpoint| {
+                metrics.request_count(OperationKind::Write, &endpoint, true)
+                    + metrics.request_count(OperationKind::Write, &endpoint, false)
+            })
+            .collect();
+        let other = counts.pop().unwrap();
+        counts.retain(|count| *count > 0);
+        assert_eq!(counts, vec![200, 200]);
+        assert_eq!(other, 400);
+        assert_eq!(metrics.route_cache_lookups(), (800, 800));
+    }
+
+    #[test]
+    fn test_database_labels() {
+        let metrics = ClientMetrics::new(MetricsConfig {
+            max_databases: 2,
+            ..Default::default()
+        });
+        let endpoint = "192.168.0.1:8831";
+        for database in ["db0", "db1", "db2", "db3", "db0"] {
+            metrics.record_request(
+                OperationKind::Write,
+                database,
+                endpoint,
+                true,
+                Duration::from_millis(10),
+            );
+        }
+
+        // Only two databases have their own labels.
+        let count = |database| {
+            metrics.database_request_count(OperationKind::Write, database, endpoint, true)
+        };
+        assert_eq!(count("db0"), 2);
+        assert_eq!(count("db1"), 1);
+        assert_eq!(count("db2"), 0);
+        assert_eq!(count("other"), 2);
+        assert_eq!(
+            metrics.request_count(OperationKind::Write, endpoint, true),
+            5
+        );
+    }
+
+    #[cfg(feature = "prometheus")]
+    #[test]
+    fn test_render_prometheus() {
+        use crate::db_client::{BreakerState, HedgeStats};
+
+        let metrics = ClientMetrics::new(MetricsConfig {
+            max_endpoints: 1,
+            latency_buckets: vec![0.05, 0.5],
+            ..Default::default()
+        });
+        let endpoint = "192.168.0.1:8831";
+        metrics.record_request(
+            OperationKind::SqlQuery,
+            "public",
+            endpoint,
+            true,
+            Duration::from_millis(10),
+        );
+        metrics.record_request(
+            OperationKind::SqlQuery,
+            "public",
+            endpoint,
+            false,
+            Duration::from_millis(100),
+        );
+        metrics.record_request(
+            OperationKind::Route,
+            "public",
+            "192.168.0.2:8831",
+            true,
+            Duration::from_secs(1),
+        );
+        metrics.record_retries(OperationKind::SqlQuery, endpoint, 2);
+        metrics.record_route_cache(3, 1);
+        metrics.record_hedges(HedgeStats {
+            issued: 2,
+            won: 1,
+            throttled: 0,
+        });
+        metrics.record_breaker_state(endpoint, &BreakerState::HalfOpen);
+
+        let rendered = metrics.render_prometheus();
+        let lines = rendered.lines().map(|line| Ok(line.to_string()));
+        let scrape = prometheus_parse::Scrape::parse(lines).unwrap();
+        let value = |name: &str, labels: &[(&str, &str)]| {
+            scrape
+                .samples
+                .iter()
+                .find(|sample| {
+                    sample.metric == name
+                        && labels
+                            .iter()
+                            .all(|(label, value)| sample.labels.get(label) == Some(*value))
+                })
+                .map(|sample| sample.value.clone())
+        };
+
+        assert_eq!(
+            value(
+                "ceresdb_client_requests_total",
+                &[
+                    ("operation", "sql_query"),
+                    ("database", "public"),
+                    ("endpoint", endpoint),
+                    ("outcome", "error")
+                ]
+            ),
+            Some(prometheus_parse::Value::Counter(1.0))
+        );
+        // The second endpoint is beyond the cap.
+        assert_eq!(
+            value(
+                "ceresdb_client_requests_total",
+                &[("operation", "route"), ("endpoint", "other")]
+            ),
+            Some(prometheus_parse::Value::Counter(1.0))
+        );
+        assert!(!rendered.contains("192.168.0.2"));
+        match value(
+            "ceresdb_client_request_duration_seconds",
+            &[("operation", "sql_query"), ("endpoint", endpoint)],
+        ) {
+            Some(prometheus_parse::Value::Histogram(buckets)) => {
+                let counts: Vec<_> = buckets.iter().map(|bucket| bucket.count).collect();
+                assert_eq!(counts, vec![1.0, 2.0, 2.0]);
+            }
+            value => panic!("unexpected value:{value:?}"),
+        }
+        assert_eq!(
+            value(
+                "ceresdb_client_retries_total",
+                &[("operation", "sql_query")]
+            ),
+            Some(prometheus_parse::Value::Counter(2.0))
+        );
+        assert_eq!(
+            value(
+                "ceresdb_client_route_cache_lookups_total",
+                &[("result", "hit")]
+            ),
+            Some(prometheus_parse::Value::Counter(3.0))
+        );
+        assert_eq!(
+            value("ceresdb_client_hedges_total", &[("result", "won")]),
+            Some(prometheus_parse::Value::Counter(1.0))
+        );
+        assert_eq!(
+            value(
+                "ceresdb_client_circuit_breaker_state",
+                &[("endpoint", endpoint)]
+            ),
+            Some(prometheus_parse::Value::Gauge(1.0))
+        );
+    }
+
+    #[test]
+    fn test_latency_buckets() {
+        let metrics = ClientMetrics::new(MetricsConfig {
+            max_endpoints: 1,
+            latency_buckets: vec![1.0, f64::NAN, 0.5, 1.0, -1.0],
+            ..Default::default()
+        });
+        assert_eq!(metrics.config.latency_buckets, vec![0.5, 1.0]);
+    }
+}
@@ -0,0 +1,406 @@
+// Copyright 2022 CeresDB Project Authors. Licensed under Apache-2.0.
+
+//! [TableDefinition] and its builder for generating the DDL
+
+use std::{collections::BTreeMap, time::Duration};
+
+use crate::{
+    model::{
+        value::{DataType, Value},
+        write::point::{is_reserved_column_name, Point},
+    },
+    Error, Result,
+};
+
+const TTL_OPTION: &str = "ttl";
+/// Name of the timestamp column of the tables inferred from the points.
+const INFERRED_TIMESTAMP_COLUMN: &str = "timestamp";
+const TABLE_PLACEHOLDER: &str = "{table}";
+const COLUMNS_PLACEHOLDER: &str = "{columns}";
+
+/// Definition of a table to create, which is built and validated by the
+/// [`TableDefinitionBuilder`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TableDefinition {
+    table: String,
+    timestamp_column: String,
+    tags: Vec<(String, DataType)>,
+    fields: Vec<(String, DataType)>,
+    options: BTreeMap<String, String>,
+    if_not_exists: bool,
+}
+
+impl TableDefinition {
+    pub fn table(&self) -> &str {
+        &self.table
+    }
+
+    pub fn timestamp_column(&self) -> &str {
+        &self.timestamp_column
+    }
+
+    /// The tag columns in the order of adding.
+    pub fn tags(&self) -> &[(String, DataType)] {
+        &self.tags
+    }
+
+    /// The field columns in the order of adding.
+    pub fn fields(&self) -> &[(String, DataType)] {
+        &self.fields
+    }
+
+    pub fn options(&self) -> &BTreeMap<String, String> {
+        &self.options
+    }
+
+    pub fn if_not_exists(&self) -> bool {
+        self.if_not_exists
+    }
+
+    /// Generate the `CREATE TABLE` sql, and the identifiers are quoted by
+    /// backquotes.
+    pub(crate) fn create_table_sql(&self) -> String {
+        let if_not_exists = if self.if_not_exists {
+            "IF NOT EXISTS "
+        } else {
+            ""
+        };
+        let mut sql = format!(
+            "CREATE TABLE {if_not_exists}{} ({}) ENGINE=Analytic",
+            quote_ident(&self.table),
+            self.column_defs()
+        );
+        if !self.options.is_empty() {
+            let options: Vec<_> = self
+                .options
+                .iter()
+                .map(|(key, value)| format!("{key}={}", quote_literal(value)))
+                .collect();
+            sql.push_str(&format!(" WITH ({})", options.join(", ")));
+        }
+
+        sql
+    }
+
+    /// Generate the `CREATE TABLE` sql by the `template`, whose `{table}` is
+    /// replaced with the quoted table name and `{columns}` with the column
+    /// definitions including the timestamp key.
+    pub(crate) fn create_table_sql_by_template(&self, template: &str) -> Result<String> {
+        if !template.contains(TABLE_PLACEHOLDER) || !template.contains(COLUMNS_PLACEHOLDER) {
+            return Err(Error::Client(format!(
+                "template of create table sql must contain {TABLE_PLACEHOLDER} and \
+                 {COLUMNS_PLACEHOLDER}, template:{template}"
+            )));
+        }
+
+        Ok(template
+            .replace(TABLE_PLACEHOLDER, &quote_ident(&self.table))
+            .replace(COLUMNS_PLACEHOLDER, &self.column_defs()))
+    }
+
+    /// Infer the definition of the `table` from the `points` written to it,
+    /// and the type of every column is the one of its first non-null value.
+    ///
+    /// The columns only with null values are left out, and the timestamp
+    /// column is named `timestamp`.
+    pub(crate) fn infer(table: &str, points: &[Point]) -> Result<Self> {
+        let infer_types = |columns: fn(&Point) -> &BTreeMap<String, Value>| {
+            let mut types = BTreeMap::new();
+            for (name, value) in points.iter().flat_map(columns) {
+                if value.data_type() != DataType::Null {
+                    types.entry(name).or_insert_with(|| value.data_type());
+                }
+            }
+            types
+        };
+        let tags = infer_types(|point| &point.tags);
+        let fields = infer_types(|point| &point.fields);
+
+        let builder = TableDefinitionBuilder::new(table.to_string())
+            .timestamp_column(INFERRED_TIMESTAMP_COLUMN.to_string())
+            .if_not_exists(true);
+        let builder = tags
+            .into_iter()
+            .fold(builder, |builder, (name, data_type)| {
+                builder.tag(name.clone(), data_type)
+            });
+        let builder = fields
+            .into_iter()
+            .fold(builder, |builder, (name, data_type)| {
+                builder.field(name.clone(), data_type)
+            });
+        builder.build()
+    }
+
+    /// Generate the column definitions, and the timestamp key is the last one.
+    fn column_defs(&self) -> String {
+        let mut columns: Vec<_> = self
+            .tags
+            .iter()
+            .map(|(name, data_type)| format!("{} {} TAG", quote_ident(name), data_type.name()))
+            .chain(
+                self.fields
+                    .iter()
+                    .map(|(name, data_type)| format!("{} {}", quote_ident(name), data_type.name())),
+            )
+            .collect();
+        let timestamp_column = quote_ident(&self.timestamp_column);
+        columns.push(format!("{timestamp_column} timestamp NOT NULL"));
+        columns.push(format!("TIMESTAMP KEY({timestamp_column})"));
+
+        columns.join(", ")
+    }
+}
+
+/// Generate the `DROP TABLE` sql.
+pub(crate) fn drop_table_sql(table: &str, if_exists: bool) -> Result<String> {
+    check_ident("table", table)?;
+
+    let if_exists = if if_exists { "IF EXISTS " } else { "" };
+    Ok(format!("DROP TABLE {if_exists}{}", quote_ident(table)))
+}
+
+/// Builder for building a table definition.
+#[derive(Debug)]
+pub struct TableDefinitionBuilder {
+    table: String,
+    timestamp_column: Option<String>,
+    tags: Vec<(String, DataType)>,
+    fields: Vec<(String, DataType)>,
+    options: BTreeMap<String, String>,
+    if_not_exists: bool,
+}
+
+impl TableDefinitionBuilder {
+    pub fn new(table: String) -> Self {
+        Self {
+            table,
+            timestamp_column: None,
+            tags: Vec::new(),
+            fields: Vec::new(),
+            options: BTreeMap::new(),
+            if_not_exists: false,
+        }
+    }
+
+    /// Set the name of the timestamp column, which is the timestamp key of the
+    /// table.
+    pub fn timestamp_column(mut self, name: String) -> Self {
+        self.timestamp_column = Some(name);
+        self
+    }
+
+    /// Add a tag column, and the columns are defined in the order of adding.
+    pub fn tag(mut self, name: String, data_type: DataType) -> Self {
+        self.tags.push((name, data_type));
+        self
+    }
+
+    /// Add a field column, and the columns are defined in the order of adding.
+    pub fn field(mut self, name: String, data_type: DataType) -> Self {
+        self.fields.push((name, data_type));
+        self
+    }
+
+    /// Set the ttl of the data in the table, whose precision is millisecond.
+    pub fn ttl(self, ttl: Duration) -> Self {
+        self.option(TTL_OPTION.to_string(), format_duration(ttl))
+    }
+
+    /// Set the table option, e.g. `enable_ttl` or `segment_duration`.
+    pub fn option(mut self, key: String, value: String) -> Self {
+        self.options.insert(key, value);
+        self
+    }
+
+    /// Don't fail if the table exists already.
+    pub fn if_not_exists(mut self, if_not_exists: bool) -> Self {
+        self.if_not_exists = if_not_exists;
+        self
+    }
+
+    /// Build the final table definition.
+    pub fn build(self) -> Result<TableDefinition> {
+        check_ident("table", &self.table)?;
+        let timestamp_column = self
+            .timestamp_column
+            .ok_or_else(|| Error::Client("timestamp column must be set".to_string()))?;
+        check_ident("timestamp column", &timestamp_column)?;
+
+        let mut columns = vec![timestamp_column.as_str()];
+        for (name, data_type) in self.tags.iter().chain(self.fields.iter()) {
+            check_ident("column", name)?;
+            if is_reserved_column_name(name) {
+                return Err(Error::Client(format!(
+                    "column name is reserved in ceresdb, name:{name}"
+                )));
+            }
+            if *data_type == DataType::Null {
+                return Err(Error::Client(format!(
+                    "data type of column can't be null, name:{name}"
+                )));
+            }
+            if columns.iter().any(|c| c.eq_ignore_ascii_case(name)) {
+                return Err(Error::Client(format!("duplicate column, name:{name}")));
+            }
+            columns.push(name);
+        }
+
+        for key in self.options.keys() {
+            let valid =
+                !key.is_empty() && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+            if !valid {
+                return Err(Error::Client(format!("invalid table option, key:{key}")));
+            }
+        }
+        if self.options.get(TTL_OPTION).map(String::as_str) == Some("0ms") {
+            return Err(Error::Client("ttl must be positive".to_string()));
+        }
+
+        Ok(TableDefinition {
+            table: self.table,
+            timestamp_column,
+            tags: self.tags,
+            fields: self.fields,
+            options: self.options,
+            if_not_exists: self.if_not_exists,
+        })
+    }
+}
+
+/// Check the identifier can be quoted by backquotes.
+pub(crate) fn check_ident(kind: &str, ident: &str) -> Result<()> {
+    if ident.is_empty() {
+        return Err(Error::Client(format!("{kind} name can't be empty")));
+    }
+    if ident.contains('`') {
+        return Err(Error::Client(format!(
+            "{kind} name can't contain backquote, name:{ident}"
+        )));
+    }
+
+    Ok(())
+}
+
+#[inline]
+pub(crate) fn quote_ident(ident: &str) -> String {
+    format!("`{ident}`")
+}
+
+#[inline]
+fn quote_literal(literal: &str) -> String {
+    format!("'{}'", literal.replace('\'', "''"))
+}
+
+/// Format the duration in the largest unit dividing it exactly, e.g. `7d`.
+fn format_duration(duration: Duration) -> String {
+    const UNITS: [(u128, &str); 4] = [
+        (24 * 3600 * 1000, "d"),
+        (3600 * 1000, "h"),
+        (60 * 1000, "m"),
+        (1000, "s"),
+    ];
+
+    let millis = duration.as_millis();
+    for (unit_millis, unit) in UNITS {
+        if millis > 0 && millis % unit_millis == 0 {
+            return format!("{}{unit}", millis / unit_millis);
+        }
+    }
+
+    format!("{millis}ms")
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::{drop_table_sql, TableDefinitionBuilder};
+    use crate::model::value::DataType;
+
+    #[test]
+    fn test_create_table_sql() {
+        let def = TableDefinitionBuilder::new("demo".to_string())
+            .timestamp_column("t".to_string())
+            .tag("host".to_string(), DataType::String)
+            .tag("region".to_string(), DataType::String)
+            .field("cpu".to_string(), DataType::Double)
+            .field("mem".to_string(), DataType::UInt64)
+            .ttl(Duration::from_secs(7 * 24 * 3600))
+            .option("enable_ttl".to_string(), "true".to_string())
+            .if_not_exists(true)
+            .build()
+            .unwrap();
+        assert_eq!(
+            def.create_table_sql(),
+            "CREATE TABLE IF NOT EXISTS `demo` (`host` string TAG, `region` string TAG, \
+             `cpu` double, `mem` uint64, `t` timestamp NOT NULL, TIMESTAMP KEY(`t`)) \
+             ENGINE=Analytic WITH (enable_ttl='true', ttl='7d')"
+        );
+
+        let def = TableDefinitionBuilder::new("my table".to_string())
+            .timestamp_column("ts".to_string())
+            .field("value".to_string(), DataType::Int32)
+            .option("storage_format".to_string(), "it's".to_string())
+            .build()
+            .unwrap();
+        assert_eq!(
+            def.create_table_sql(),
+            "CREATE TABLE `my table` (`value` int32, `ts` timestamp NOT NULL, \
+             TIMESTAMP KEY(`ts`)) ENGINE=Analytic WITH (storage_format='it''s')"
+        );
+    }
+
+    #[test]
+    fn test_drop_table_sql() {
+        assert_eq!(
+            drop_table_sql("demo", true).unwrap(),
+            "DROP TABLE IF EXISTS `demo`"
+        );
+        assert_eq!(drop_table_sql("demo", false).unwrap(), "DROP TABLE `demo`");
+        assert!(drop_table_sql("", true).is_err());
+        assert!(drop_table_sql("de`mo", true).is_err());
+    }
+
+    #[test]
+    fn test_format_ttl() {
+        let cases = [
+            (Duration::from_secs(3600 * 36), "36h"),
+            (Duration::from_secs(90), "90s"),
+            (Duration::from_secs(120), "2m"),
+            (Duration::from_millis(1500), "1500ms"),
+        ];
+        for (ttl, expected) in cases {
+            let def = TableDefinitionBuilder::new("demo".to_string())
+                .timestamp_column("t".to_string())
+                .ttl(ttl)
+                .build()
+                .unwrap();
+            assert_eq!(def.options["ttl"], expected);
+        }
+    }
+
+    #[test]
+    fn test_invalid_definition() {
+        let builder =
+            || TableDefinitionBuilder::new("demo".to_string()).timestamp_column("t".to_string());
+        let invalid_builders = [
+            TableDefinitionBuilder::new("demo".to_string()),
+            TableDefinitionBuilder::new("".to_string()).timestamp_column("t".to_string()),
+            TableDefinitionBuilder::new("de`mo".to_string()).timestamp_column("t".to_string()),
+            builder().tag("".to_string(), DataType::String),
+            builder().field("va`lue".to_string(), DataType::Double),
+            builder().field("tsid".to_string(), DataType::UInt64),
+            builder().field("value".to_string(), DataType::Null),
+            builder()
+                .tag("host".to_string(), DataType::String)
+                .field("HOST".to_string(), DataType::Double),
+            builder().field("T".to_string(), DataType::Double),
+            builder().option("ttl'='1d".to_string(), "1d".to_string()),
+            builder().ttl(Duration::ZERO),
+        ];
+        for builder in invalid_builders {
+            assert!(builder.build().is_err());
+        }
+    }
+}
@@ -0,0 +1,74 @@
+// Copyright 2022 CeresDB Project Authors. Licensed under Apache-2.0.
+
+//! Execution info of the request
+
+use std::time::Duration;
+
+use crate::model::route::Endpoint;
+
+/// Execution info of a request measured by the client.
+///
+/// The server reports no cost in the response header so far, and the affected
+/// rows can be found in the response itself.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ExecutionInfo {
+    /// The endpoint the request is sent to, and it is none if the request is
+    /// partitioned across multiple endpoints.
+    pub endpoint: Option<Endpoint>,
+    /// Bytes of the encoded request.
+    pub request_bytes: usize,
+    /// Bytes of the encoded response.
+    pub response_bytes: usize,
+    /// Latency of the whole call measured by the client, including the
+    /// connecting, the retries and the backoffs between them.
+    pub latency: Duration,
+    /// The number of the retries performed.
+    pub retries: usize,
+    /// Execution info on every endpoint, and it is empty if the request is
+    /// not partitioned.
+    pub partitions: Vec<ExecutionInfo>,
+}
+
+impl ExecutionInfo {
+    /// Merge the execution info of the partitions sent to different endpoints
+    /// concurrently.
+    ///
+    /// The sizes and retries are summed, and the latency is the max one of the
+    /// partitions.
+    pub(crate) fn merge(partitions: Vec<ExecutionInfo>) -> Self {
+        Self::combine(partitions, Duration::max)
+    }
+
+    /// Concatenate the execution info of the requests sent one after another,
+    /// which are kept as the partitions.
+    ///
+    /// The sizes, retries and latencies are summed.
+    pub(crate) fn concat(partitions: Vec<ExecutionInfo>) -> Self {
+        Self::combine(partitions, Duration::saturating_add)
+    }
+
+    fn combine(
+        partitions: Vec<ExecutionInfo>,
+        combine_latency: fn(Duration, Duration) -> Duration,
+    ) -> Self {
+        let mut combined = ExecutionInfo::default();
+        for partition in &partitions {
+            // Destructured to combine the new fields as well.
+            let ExecutionInfo {
+                endpoint: _,
+                request_bytes,
+                response_bytes,
+                latency,
+                retries,
+                partitions: _,
+            } = partition;
+            combined.request_bytes += request_bytes;
+            combined.response_bytes += response_bytes;
+            combined.latency = combine_latency(combined.latency, *latency);
+            combined.retries += retries;
+        }
+        combined.partitions = partitions;
+
+        combined
+    }
+}
@@ -0,0 +1,466 @@
+// Copyright 2022 CeresDB Project Authors. Licensed under Apache-2.0.
+
+//! [QueryPlan] parsed from the output of `EXPLAIN`
+
+use std::{fmt, time::Duration};
+
+use crate::{
+    model::sql_query::{row::Row, Request as SqlQueryRequest, Response as SqlQueryResponse},
+    Error, Result,
+};
+
+const PLAN_COLUMN: &str = "plan";
+const PLAN_TYPE_COLUMN: &str = "plan_type";
+
+/// A line of the plan, which is kept verbatim in `text`, and the parts known
+/// by the client are broken out of it.
+///
+/// The lines not understood by the client, e.g. the metrics of a new version
+/// of the server, have none of the parts.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PlanLine {
+    pub text: String,
+    /// The depth of the line in the plan tree by its indent.
+    pub depth: usize,
+    /// The operator of the line, e.g. `Projection` and `ScanTable`.
+    pub operator: Option<String>,
+    /// The table scanned by the line.
+    pub table: Option<String>,
+    /// The number of the rows estimated by the planner.
+    pub estimated_rows: Option<u64>,
+    /// The cost estimated by the planner.
+    pub estimated_cost: Option<f64>,
+    /// The number of the rows output actually, only by `EXPLAIN ANALYZE`.
+    pub actual_rows: Option<u64>,
+    /// The elapsed time of the phases, only by `EXPLAIN ANALYZE`.
+    pub elapsed: Vec<(String, Duration)>,
+}
+
+impl PlanLine {
+    fn parse(text: &str) -> Self {
+        // The operator leads the line, e.g. `Filter: ..` and `ScanTable, ..`.
+        let trimmed = text.trim_start();
+        let head_end = trimmed
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+            .unwrap_or(trimmed.len());
+        let (head, rest) = trimmed.split_at(head_end);
+        let is_operator = head.starts_with(|c: char| c.is_ascii_uppercase())
+            && (rest.is_empty() || rest.starts_with([':', ',']));
+        let operator = is_operator.then(|| head.to_string());
+
+        let mut line = Self {
+            text: text.to_string(),
+            depth: (text.len() - trimmed.len()) / 2,
+            operator,
+            table: None,
+            estimated_rows: None,
+            estimated_cost: None,
+            actual_rows: None,
+            elapsed: Vec::new(),
+        };
+        // The logical scan is in the form of `TableScan: demo projection=[..]`.
+        if line.operator.as_deref() == Some("TableScan") {
+            line.table = trimmed
+                .split_once(':')
+                .and_then(|(_, rest)| rest.split_whitespace().next())
+                .map(unquote);
+        }
+
+        let tokens = trimmed
+            .split(|c: char| c.is_whitespace() || ",()[]{}".contains(c))
+            .filter_map(|token| token.split_once('='));
+        for (key, value) in tokens {
+            match key {
+                "table" => {
+                    line.table.get_or_insert_with(|| unquote(value));
+                }
+                "rows" | "estimated_rows" => line.estimated_rows = value.parse().ok(),
+                // The cost may be a range of the startup and the total cost.
+                "cost" | "estimated_cost" => {
+                    line.estimated_cost = value.rsplit("..").next().and_then(|v| v.parse().ok())
+                }
+                "output_rows" | "actual_rows" => line.actual_rows = value.parse().ok(),
+                _ if key.contains("elapsed")
+                    || key.contains("duration")
+                    || key.ends_with("_time") =>
+                {
+                    if let Some(elapsed) = parse_duration(value) {
+                        line.elapsed.push((key.to_string(), elapsed));
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        line
+    }
+}
+
+/// A section of the plan, e.g. the logical or the physical plan.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PlanSection {
+    /// The type of the plan if the server tells, e.g. `logical_plan`.
+    pub plan_type: Option<String>,
+    pub lines: Vec<PlanLine>,
+}
+
+/// The plan of a query returned by
+/// [`DbClient::explain`](crate::DbClient::explain) and
+/// [`DbClient::explain_analyze`](crate::DbClient::explain_analyze).
+///
+/// It is displayed as the plan text returned by the server.
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueryPlan {
+    pub sections: Vec<PlanSection>,
+    /// The tables scanned by the plan in the order they appear.
+    pub tables: Vec<String>,
+}
+
+impl QueryPlan {
+    /// Get the lines of all the sections.
+    pub fn lines(&self) -> impl Iterator<Item = &PlanLine> {
+        self.sections
+            .iter()
+            .flat_map(|section| section.lines.iter())
+    }
+
+    /// Parse the plan text of the sections, and every section may be of a
+    /// type, e.g. `logical_plan`.
+    pub fn parse<'a>(sections: impl IntoIterator<Item = (Option<String>, &'a str)>) -> Self {
+        let sections: Vec<_> = sections
+            .into_iter()
+            .map(|(plan_type, text)| PlanSection {
+                plan_type,
+                lines: text.lines().map(PlanLine::parse).collect(),
+            })
+            .collect();
+        let mut tables = Vec::new();
+        for table in sections
+            .iter()
+            .flat_map(|section| section.lines.iter())
+            .filter_map(|line| line.table.as_ref())
+        {
+            if !tables.contains(table) {
+                tables.push(table.clone());
+            }
+        }
+
+        Self { sections, tables }
+    }
+
+    /// Parse the plan from the response of the [`explain_request`], and there
+    /// is a row per section, whose plan is in the `plan` column or the last
+    /// one.
+    pub(crate) fn from_explain_response(resp: &SqlQueryResponse) -> Result<Self> {
+        let sections = resp
+            .rows()
+            .iter()
+            .map(parse_section)
+            .collect::<Result<Vec<_>>>()?;
+        if sections.is_empty() {
+            return Err(Error::MalformedResponse {
+                detail: "no plan is explained".to_string(),
+            });
+        }
+
+        Ok(Self::parse(sections.iter().map(|(plan_type, text)| {
+            (plan_type.clone(), text.as_str())
+        })))
+    }
+}
+
+impl fmt::Display for QueryPlan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let texts = self.sections.iter().flat_map(|section| {
+            section
+                .plan_type
+                .iter()
+                .chain(section.lines.iter().map(|line| &line.text))
+        });
+        for (i, text) in texts.enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{text}")?;
+        }
+
+        Ok(())
+    }
+}
+
+fn parse_section(row: &Row) -> Result<(Option<String>, String)> {
+    let plan = row
+        .column(PLAN_COLUMN)
+        .or_else(|| row.columns().last())
+        .and_then(|column| column.value().as_str())
+        .ok_or_else(|| Error::MalformedResponse {
+            detail: format!("plan is missing in explain, row:{row:?}"),
+        })?;
+    let plan_type = row
+        .column(PLAN_TYPE_COLUMN)
+        .and_then(|column| column.value().as_str());
+
+    Ok((plan_type, plan))
+}
+
+fn unquote(name: &str) -> String {
+    name.trim_matches(|c| c == '`' || c == '"' || c == '\'')
+        .to_string()
+}
+
+/// Parse the duration in the form of `1.5ms`, and the units are `ns`, `µs`,
+/// `us`, `ms` and `s`.
+fn parse_duration(value: &str) -> Option<Duration> {
+    let unit_start = value.find(|c: char| !(c.is_ascii_digit() || c == '.'))?;
+    let (number, unit) = value.split_at(unit_start);
+    let number: f64 = number.parse().ok()?;
+    let secs = match unit {
+        "ns" => number / 1e9,
+        "µs" | "us" => number / 1e6,
+        "ms" => number / 1e3,
+        "s" => number,
+        _ => return None,
+    };
+
+    Some(Duration::from_secs_f64(secs))
+}
+
+/// Strip the leading `keyword` of the `sql` case-insensitively.
+fn strip_keyword<'a>(sql: &'a str, keyword: &str) -> Option<&'a str> {
+    let head = sql.get(..keyword.len())?;
+    let rest = &sql[keyword.len()..];
+    (head.eq_ignore_ascii_case(keyword) && rest.starts_with(char::is_whitespace))
+        .then(|| rest.trim_start())
+}
+
+/// Request explaining the query of the `req`, or running it to explain with
+/// the actual metrics if `analyze` is set.
+///
+/// The `EXPLAIN` already in the sql is replaced rather than doubled.
+pub(crate) fn explain_request(req: &SqlQueryRequest, analyze: bool) -> SqlQueryRequest {
+    let sql = req.sql.trim_start();
+    let sql = match strip_keyword(sql, "EXPLAIN") {
+        Some(rest) => strip_keyword(rest, "ANALYZE").unwrap_or(rest),
+        None => sql,
+    };
+    let prefix = if analyze {
+        "EXPLAIN ANALYZE"
+    } else {
+        "EXPLAIN"
+    };
+
+    SqlQueryRequest::new(req.tables.clone(), format!("{prefix} {sql}"))
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::{explain_request, parse_duration, QueryPlan};
+    use crate::{
+        model::{
+            sql_query::{
+                row::RowBuilder, Output, Request as SqlQueryRequest, Response as SqlQueryResponse,
+            },
+            value::Value,
+        },
+        Error,
+    };
+
+    const LOGICAL_PLAN: &str = "Limit: skip=0, fetch=10
+  Sort: demo.t DESC NULLS FIRST, fetch=10
+    Projection: demo.t, demo.name, demo.value
+      Filter: demo.name = Utf8(\"host1\")
+        TableScan: demo projection=[t, name, value], partial_filters=[demo.name = Utf8(\"host1\")]";
+
+    const PHYSICAL_PLAN: &str = "GlobalLimitExec: skip=0, fetch=10
+  SortPreservingMergeExec: [t@0 DESC], fetch=10, statistics=[rows=10, cost=0.00..35.50]
+    ScanTable: table=demo, parallelism=8, priority=Low, rows=1000";
+
+    const ANALYZED_PLAN: &str =
+        "CoalescePartitionsExec, metrics=[output_rows=2, elapsed_compute=12.5µs]
+  ScanTable: table=`cpu`, parallelism=8, metrics=[output_rows=2, elapsed_compute=1.234ms]
+    Predicate { exprs:[], time_range:TimeRange { inclusive_start: Timestamp(0) } }
+    scan_table:
+        do_merge_sort=true
+        merge_iter_0:
+            init_duration=3.856µs
+            scan_duration=94.473us
+            scan_count=2";
+
+    fn explain_response(sections: Vec<(Option<&str>, &str)>) -> SqlQueryResponse {
+        let with_plan_type = sections.iter().any(|(plan_type, _)| plan_type.is_some());
+        let col_idx_to_name = if with_plan_type {
+            vec!["plan_type".to_string(), "plan".to_string()]
+        } else {
+            vec!["plan".to_string()]
+        };
+        let row_values = sections
+            .into_iter()
+            .map(|(plan_type, plan)| {
+                let mut values: Vec<_> = plan_type
+                    .map(|plan_type| Value::String(plan_type.to_string()))
+                    .into_iter()
+                    .collect();
+                values.push(Value::String(plan.to_string()));
+                values
+            })
+            .collect();
+        let rows = RowBuilder {
+            col_idx_to_name,
+            row_values,
+        }
+        .build();
+
+        SqlQueryResponse {
+            output: Output::ResultSet { rows, schema: None },
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_parse_plan() {
+        let resp = explain_response(vec![
+            (Some("logical_plan"), LOGICAL_PLAN),
+            (Some("physical_plan"), PHYSICAL_PLAN),
+        ]);
+        let plan = QueryPlan::from_explain_response(&resp).unwrap();
+        assert_eq!(plan.sections.len(), 2);
+        assert_eq!(plan.sections[0].plan_type.as_deref(), Some("logical_plan"));
+        assert_eq!(plan.tables, vec!["demo".to_string()]);
+
+        let logical = &plan.sections[0].lines;
+        let operators: Vec<_> = logical
+            .iter()
+            .map(|line| (line.depth, line.operator.as_deref().unwrap()))
+            .collect();
+        assert_eq!(
+            operators,
+            vec![
+                (0, "Limit"),
+                (1, "Sort"),
+                (2, "Projection"),
+                (3, "Filter"),
+                (4, "TableScan")
+            ]
+        );
+        assert_eq!(logical[4].table.as_deref(), Some("demo"));
+
+        let physical = &plan.sections[1].lines;
+        assert_eq!(physical[1].estimated_rows, Some(10));
+        assert_eq!(physical[1].estimated_cost, Some(35.5));
+        assert_eq!(physical[2].operator.as_deref(), Some("ScanTable"));
+        assert_eq!(physical[2].table.as_deref(), Some("demo"));
+        assert_eq!(physical[2].estimated_rows, Some(1000));
+        assert!(plan.lines().all(|line| line.actual_rows.is_none()));
+
+        assert_eq!(
+            plan.to_string(),
+            format!("logical_plan\n{LOGICAL_PLAN}\nphysical_plan\n{PHYSICAL_PLAN}")
+        );
+    }
+
+    #[test]
+    fn test_parse_analyzed_plan() {
+        let resp = explain_response(vec![(None, ANALYZED_PLAN)]);
+        let plan = QueryPlan::from_explain_response(&resp).unwrap();
+        assert_eq!(plan.tables, vec!["cpu".to_string()]);
+
+        let lines: Vec<_> = plan.lines().collect();
+        assert_eq!(lines[0].operator.as_deref(), Some("CoalescePartitionsExec"));
+        assert_eq!(lines[0].actual_rows, Some(2));
+        assert_eq!(
+            lines[0].elapsed,
+            vec![("elapsed_compute".to_string(), Duration::from_nanos(12_500))]
+        );
+        assert_eq!(lines[1].actual_rows, Some(2));
+        assert_eq!(
+            lines[1].elapsed,
+            vec![("elapsed_compute".to_string(), Duration::from_micros(1234))]
+        );
+
+        // The unknown lines are kept verbatim.
+        for line in &lines[2..] {
+            assert!(line.operator.is_none(), "line:{line:?}");
+        }
+        let durations: Vec<_> = lines
+            .iter()
+            .flat_map(|line| line.elapsed.iter())
+            .skip(2)
+            .cloned()
+            .collect();
+        assert_eq!(
+            durations,
+            vec![
+                ("init_duration".to_string(), Duration::from_nanos(3_856)),
+                ("scan_duration".to_string(), Duration::from_nanos(94_473)),
+            ]
+        );
+        assert_eq!(plan.to_string(), ANALYZED_PLAN);
+    }
+
+    #[test]
+    fn test_parse_malformed_plan() {
+        let resp = explain_response(Vec::new());
+        assert!(matches!(
+            QueryPlan::from_explain_response(&resp),
+            Err(Error::MalformedResponse { .. })
+        ));
+
+        let resp = SqlQueryResponse {
+            output: Output::AffectedRows(0),
+            ..Default::default()
+        };
+        assert!(QueryPlan::from_explain_response(&resp).is_err());
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        for (value, expected) in [
+            ("100ns", Duration::from_nanos(100)),
+            ("1.5µs", Duration::from_nanos(1500)),
+            ("2us", Duration::from_micros(2)),
+            ("3.25ms", Duration::from_micros(3250)),
+            ("2s", Duration::from_secs(2)),
+        ] {
+            assert_eq!(parse_duration(value), Some(expected), "value:{value}");
+        }
+        for value in ["", "ms", "12", "1.2.3ms", "5m"] {
+            assert_eq!(parse_duration(value), None, "value:{value}");
+        }
+    }
+
+    #[test]
+    fn test_explain_request() {
+        let req = |sql: &str| {
+            SqlQueryRequest::new(vec!["demo".to_string()], sql.to_string())
+                .with_cache_ttl(Some(Duration::from_secs(1)))
+        };
+        let sql = "SELECT * FROM demo";
+        for (sql, analyze, expected) in [
+            (sql, false, "EXPLAIN SELECT * FROM demo"),
+            (sql, true, "EXPLAIN ANALYZE SELECT * FROM demo"),
+            (
+                "  explain SELECT * FROM demo",
+                false,
+                "EXPLAIN SELECT * FROM demo",
+            ),
+            (
+                "EXPLAIN SELECT * FROM demo",
+                true,
+                "EXPLAIN ANALYZE SELECT * FROM demo",
+            ),
+            (
+                "Explain Analyze SELECT * FROM demo",
+                false,
+                "EXPLAIN SELECT * FROM demo",
+            ),
+            ("EXPLAINED", false, "EXPLAIN EXPLAINED"),
+        ] {
+            let explain_req = explain_request(&req(sql), analyze);
+            assert_eq!(explain_req.sql, expected);
+            assert_eq!(explain_req.tables, vec!["demo".to_string()]);
+            assert!(explain_req.cache_ttl.is_none());
+        }
+    }
+}
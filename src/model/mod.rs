@@ -2,7 +2,14 @@
 
 //! Data model
 
+#![cfg_attr(not(test), deny(clippy::unwrap_used))]
+
+pub mod ddl;
+pub mod execution_info;
+pub mod explain;
 pub mod route;
+pub mod schema;
 pub mod sql_query;
 pub mod value;
+pub mod warning;
 pub mod write;
@@ -5,6 +5,7 @@
 use std::{fmt::Display, str::FromStr};
 
 use ceresdbproto::storage::Endpoint as EndPointPb;
+use tonic::{codegen::http::uri::InvalidUri, transport::Uri};
 
 #[derive(Debug, Clone, Hash, Eq, PartialEq)]
 pub struct Endpoint {
@@ -22,6 +23,113 @@ impl Endpoint {
     pub fn new(ip: String, port: u32) -> Self {
         Self { addr: ip, port }
     }
+
+    /// Build the uri of the endpoint, whose scheme is `https` if `secure` is
+    /// set and `http` otherwise.
+    ///
+    /// The ipv6 address is bracketed in the uri, e.g. `http://[::1]:8831`.
+    pub fn to_uri(&self, secure: bool) -> std::result::Result<Uri, InvalidUri> {
+        let scheme = if secure { "https" } else { "http" };
+        let uri = if self.addr.contains(':') && !self.addr.starts_with('[') {
+            format!("{scheme}://[{}]:{}", self.addr, self.port)
+        } else {
+            format!("{scheme}://{}:{}", self.addr, self.port)
+        };
+
+        Uri::try_from(uri)
+    }
+}
+
+/// Route of a table.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum TableRoute {
+    /// Routed to the endpoint by the route service.
+    Routed(Endpoint),
+    /// Unknown to the route service, and routed to the default endpoint.
+    Default(Endpoint),
+    /// Unknown to the route service, and no default endpoint.
+    NoRoute,
+}
+
+impl TableRoute {
+    /// Get the endpoint the table is routed to, no matter whether it is the
+    /// default one.
+    pub fn endpoint(&self) -> Option<&Endpoint> {
+        match self {
+            TableRoute::Routed(endpoint) | TableRoute::Default(endpoint) => Some(endpoint),
+            TableRoute::NoRoute => None,
+        }
+    }
+
+    pub fn into_endpoint(self) -> Option<Endpoint> {
+        match self {
+            TableRoute::Routed(endpoint) | TableRoute::Default(endpoint) => Some(endpoint),
+            TableRoute::NoRoute => None,
+        }
+    }
+}
+
+/// Role of a replica of a table.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ReplicaRole {
+    Leader,
+    Follower,
+}
+
+/// Replica of a table served by the endpoint.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Replica {
+    pub endpoint: Endpoint,
+    pub role: ReplicaRole,
+}
+
+/// All the replicas of a table returned by the route service.
+///
+/// The route response carries no roles, so the first endpoint returned for
+/// the table is regarded as the leader and the rest as the followers, which
+/// is also the one picked by [`LoadBalancePolicy::First`].
+///
+/// [`LoadBalancePolicy::First`]: crate::LoadBalancePolicy::First
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Route {
+    pub table: String,
+    /// Never empty, and the leader comes first.
+    pub replicas: Vec<Replica>,
+}
+
+impl Route {
+    /// Build the route from the candidate endpoints in the order returned by
+    /// the route service, which must not be empty.
+    pub(crate) fn from_candidates(table: String, endpoints: Vec<Endpoint>) -> Self {
+        let replicas = endpoints
+            .into_iter()
+            .enumerate()
+            .map(|(idx, endpoint)| Replica {
+                endpoint,
+                role: if idx == 0 {
+                    ReplicaRole::Leader
+                } else {
+                    ReplicaRole::Follower
+                },
+            })
+            .collect();
+
+        Self { table, replicas }
+    }
+
+    pub fn leader(&self) -> Option<&Endpoint> {
+        self.replicas
+            .iter()
+            .find(|replica| replica.role == ReplicaRole::Leader)
+            .map(|replica| &replica.endpoint)
+    }
+
+    pub fn followers(&self) -> impl Iterator<Item = &Endpoint> {
+        self.replicas
+            .iter()
+            .filter(|replica| replica.role == ReplicaRole::Follower)
+            .map(|replica| &replica.endpoint)
+    }
 }
 
 impl FromStr for Endpoint {
@@ -57,6 +165,27 @@ impl FromStr for Endpoint {
     }
 }
 
+/// The endpoint is serialized as the string `addr:port`.
+#[cfg(feature = "serde")]
+impl serde::Serialize for Endpoint {
+    fn serialize<S: serde::Serializer>(
+        &self,
+        serializer: S,
+    ) -> std::result::Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for Endpoint {
+    fn deserialize<D: serde::Deserializer<'de>>(
+        deserializer: D,
+    ) -> std::result::Result<Self, D::Error> {
+        let raw = String::deserialize(deserializer)?;
+        raw.parse().map_err(serde::de::Error::custom)
+    }
+}
+
 impl From<EndPointPb> for Endpoint {
     fn from(endpoint_pb: EndPointPb) -> Self {
         Self {
@@ -90,4 +219,50 @@ mod tests {
             assert!(parse_res.is_err());
         }
     }
+
+    #[test]
+    fn test_endpoint_to_uri() {
+        let cases = [
+            ("127.0.0.1:8831", false, "http://127.0.0.1:8831/"),
+            ("ceresdb.io:8831", true, "https://ceresdb.io:8831/"),
+            ("::1:8831", false, "http://[::1]:8831/"),
+            ("[fe80::1]:8831", true, "https://[fe80::1]:8831/"),
+        ];
+        for (raw_endpoint, secure, expected) in cases {
+            let endpoint: Endpoint = raw_endpoint.parse().unwrap();
+            let uri = endpoint.to_uri(secure).unwrap();
+            assert_eq!(uri.to_string(), expected);
+        }
+
+        let uri = Endpoint::new("::1".to_string(), 8831)
+            .to_uri(false)
+            .unwrap();
+        assert_eq!(uri.host(), Some("[::1]"));
+        assert_eq!(uri.port_u16(), Some(8831));
+
+        assert!(Endpoint::new("in valid".to_string(), 8831)
+            .to_uri(false)
+            .is_err());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde_endpoint() {
+        let entries = vec![
+            (
+                "table1".to_string(),
+                Endpoint::new("127.0.0.1".to_string(), 8831),
+            ),
+            ("table2".to_string(), Endpoint::new("::1".to_string(), 8832)),
+        ];
+        let json = serde_json::to_string(&entries).unwrap();
+        assert_eq!(
+            json,
+            r#"[["table1","127.0.0.1:8831"],["table2","::1:8832"]]"#
+        );
+        let decoded: Vec<(String, Endpoint)> = serde_json::from_str(&json).unwrap();
+        assert_eq!(decoded, entries);
+
+        assert!(serde_json::from_str::<Endpoint>(r#""127.0.0.1""#).is_err());
+    }
 }
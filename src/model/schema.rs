@@ -0,0 +1,269 @@
+// Copyright 2022 CeresDB Project Authors. Licensed under Apache-2.0.
+
+//! [TableSchema] read from the server by `DESCRIBE`
+
+use crate::{
+    model::{
+        ddl::{check_ident, quote_ident},
+        sql_query::{row::Row, Request as SqlQueryRequest, Response as SqlQueryResponse},
+        value::DataType,
+    },
+    Error, Result,
+};
+
+const NAME_COLUMN: &str = "name";
+const TYPE_COLUMN: &str = "type";
+const IS_PRIMARY_COLUMN: &str = "is_primary";
+const IS_NULLABLE_COLUMN: &str = "is_nullable";
+const IS_TAG_COLUMN: &str = "is_tag";
+
+/// Role of a column in the table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColumnRole {
+    /// The timestamp key of the table.
+    Timestamp,
+    Tag,
+    Field,
+}
+
+/// Schema of a column in the table.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ColumnSchema {
+    pub name: String,
+    pub data_type: DataType,
+    pub role: ColumnRole,
+    /// Whether the column is a part of the primary key, e.g. the timestamp
+    /// key and the `tsid` generated by the server.
+    pub is_primary: bool,
+    pub is_nullable: bool,
+}
+
+/// Schema of a table, and the columns are in the order of the table.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TableSchema {
+    pub table: String,
+    pub columns: Vec<ColumnSchema>,
+}
+
+impl TableSchema {
+    /// Find the column by its name.
+    pub fn column(&self, name: &str) -> Option<&ColumnSchema> {
+        self.columns.iter().find(|column| column.name == name)
+    }
+
+    /// Get the timestamp key of the table.
+    pub fn timestamp_column(&self) -> Option<&ColumnSchema> {
+        self.columns_of(ColumnRole::Timestamp).next()
+    }
+
+    pub fn tags(&self) -> impl Iterator<Item = &ColumnSchema> {
+        self.columns_of(ColumnRole::Tag)
+    }
+
+    pub fn fields(&self) -> impl Iterator<Item = &ColumnSchema> {
+        self.columns_of(ColumnRole::Field)
+    }
+
+    fn columns_of(&self, role: ColumnRole) -> impl Iterator<Item = &ColumnSchema> {
+        self.columns
+            .iter()
+            .filter(move |column| column.role == role)
+    }
+
+    /// Parse the schema of the `table` from the response of the
+    /// [`describe_table_request`], and there is a row per column.
+    ///
+    /// The timestamp key is the first primary column of the timestamp type,
+    /// which is not a tag.
+    pub(crate) fn from_describe_response(table: &str, resp: &SqlQueryResponse) -> Result<Self> {
+        let mut columns = resp
+            .rows()
+            .iter()
+            .map(parse_column)
+            .collect::<Result<Vec<_>>>()?;
+        if columns.is_empty() {
+            return Err(Error::MalformedResponse {
+                detail: format!("no column is described, table:{table}"),
+            });
+        }
+
+        if let Some(timestamp_column) = columns.iter_mut().find(|column| {
+            column.is_primary
+                && column.data_type == DataType::Timestamp
+                && column.role == ColumnRole::Field
+        }) {
+            timestamp_column.role = ColumnRole::Timestamp;
+        }
+
+        Ok(Self {
+            table: table.to_string(),
+            columns,
+        })
+    }
+}
+
+/// Parse a row of the `DESCRIBE` result, and the missing flags are regarded
+/// as false.
+fn parse_column(row: &Row) -> Result<ColumnSchema> {
+    let malformed = |e: Error| Error::MalformedResponse {
+        detail: format!("invalid row of describe, row:{row:?}, err:{e}"),
+    };
+    let required = |name: &str| {
+        row.column(name)
+            .and_then(|column| column.value().as_str())
+            .ok_or_else(|| Error::MalformedResponse {
+                detail: format!("column {name} is missing in describe, row:{row:?}"),
+            })
+    };
+    let flag = |name: &str| -> Result<bool> {
+        match row.column(name) {
+            Some(column) => Ok(column.get::<bool>().map_err(malformed)?.unwrap_or(false)),
+            None => Ok(false),
+        }
+    };
+
+    let name = required(NAME_COLUMN)?;
+    let data_type = required(TYPE_COLUMN)?.parse().map_err(malformed)?;
+    let role = if flag(IS_TAG_COLUMN)? {
+        ColumnRole::Tag
+    } else {
+        ColumnRole::Field
+    };
+
+    Ok(ColumnSchema {
+        name,
+        data_type,
+        role,
+        is_primary: flag(IS_PRIMARY_COLUMN)?,
+        is_nullable: flag(IS_NULLABLE_COLUMN)?,
+    })
+}
+
+/// Request describing the columns of the `table`.
+pub(crate) fn describe_table_request(table: &str) -> Result<SqlQueryRequest> {
+    check_ident("table", table)?;
+
+    Ok(SqlQueryRequest::new(
+        vec![table.to_string()],
+        format!("DESCRIBE {}", quote_ident(table)),
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{describe_table_request, ColumnRole, ColumnSchema, TableSchema};
+    use crate::{
+        model::{
+            sql_query::{row::RowBuilder, Output, Response as SqlQueryResponse},
+            value::{DataType, Value},
+        },
+        Error,
+    };
+
+    fn describe_response(rows: Vec<(&str, &str, bool, bool, bool)>) -> SqlQueryResponse {
+        let rows = RowBuilder {
+            col_idx_to_name: ["name", "type", "is_primary", "is_nullable", "is_tag"]
+                .iter()
+                .map(|name| name.to_string())
+                .collect(),
+            row_values: rows
+                .into_iter()
+                .map(|(name, data_type, is_primary, is_nullable, is_tag)| {
+                    vec![
+                        Value::String(name.to_string()),
+                        Value::String(data_type.to_string()),
+                        Value::Boolean(is_primary),
+                        Value::Boolean(is_nullable),
+                        Value::Boolean(is_tag),
+                    ]
+                })
+                .collect(),
+        }
+        .build();
+
+        SqlQueryResponse {
+            output: Output::ResultSet { rows, schema: None },
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_parse_describe_response() {
+        let resp = describe_response(vec![
+            ("tsid", "uint64", true, false, false),
+            ("t", "timestamp", true, false, false),
+            ("host", "string", false, true, true),
+            ("cpu", "double", false, true, false),
+            ("count", "UINT32", false, true, false),
+        ]);
+        let schema = TableSchema::from_describe_response("demo", &resp).unwrap();
+
+        let column = |name: &str, data_type, role, is_primary, is_nullable| ColumnSchema {
+            name: name.to_string(),
+            data_type,
+            role,
+            is_primary,
+            is_nullable,
+        };
+        let expected = TableSchema {
+            table: "demo".to_string(),
+            columns: vec![
+                column("tsid", DataType::UInt64, ColumnRole::Field, true, false),
+                column("t", DataType::Timestamp, ColumnRole::Timestamp, true, false),
+                column("host", DataType::String, ColumnRole::Tag, false, true),
+                column("cpu", DataType::Double, ColumnRole::Field, false, true),
+                column("count", DataType::UInt32, ColumnRole::Field, false, true),
+            ],
+        };
+        assert_eq!(schema, expected);
+        assert_eq!(schema.timestamp_column().unwrap().name, "t");
+        assert_eq!(
+            schema.tags().map(|c| c.name.as_str()).collect::<Vec<_>>(),
+            vec!["host"]
+        );
+        assert_eq!(
+            schema.fields().map(|c| c.name.as_str()).collect::<Vec<_>>(),
+            vec!["tsid", "cpu", "count"]
+        );
+        assert_eq!(schema.column("cpu").unwrap().data_type, DataType::Double);
+        assert!(schema.column("mem").is_none());
+    }
+
+    #[test]
+    fn test_parse_malformed_describe_response() {
+        let cases = vec![
+            describe_response(Vec::new()),
+            describe_response(vec![("t", "decimal", true, false, false)]),
+        ];
+        for resp in cases {
+            assert!(matches!(
+                TableSchema::from_describe_response("demo", &resp),
+                Err(Error::MalformedResponse { .. })
+            ));
+        }
+
+        // The type column is missing.
+        let rows = RowBuilder {
+            col_idx_to_name: vec!["name".to_string()],
+            row_values: vec![vec![Value::String("t".to_string())]],
+        }
+        .build();
+        let resp = SqlQueryResponse {
+            output: Output::ResultSet { rows, schema: None },
+            ..Default::default()
+        };
+        assert!(matches!(
+            TableSchema::from_describe_response("demo", &resp),
+            Err(Error::MalformedResponse { .. })
+        ));
+    }
+
+    #[test]
+    fn test_describe_table_request() {
+        let req = describe_table_request("demo").unwrap();
+        assert_eq!(req.sql, "DESCRIBE `demo`");
+        assert_eq!(req.tables, vec!["demo".to_string()]);
+        assert!(describe_table_request("de`mo").is_err());
+        assert!(describe_table_request("").is_err());
+    }
+}
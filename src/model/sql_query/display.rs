@@ -14,9 +14,8 @@ pub struct CsvFormatter {
 impl Display for CsvFormatter {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         // Just print while returned `rows` in not empty.
-        if !self.resp.rows.is_empty() {
-            // Get and output column names, unwrap is safe here.
-            let first_row = self.resp.rows.first().unwrap();
+        if let Some(first_row) = self.resp.rows().first() {
+            // Get and output column names.
             let col_names = first_row
                 .columns()
                 .iter()
@@ -28,7 +27,7 @@ impl Display for CsvFormatter {
             f.write_str("\n")?;
 
             // Get and output rows.
-            for row in &self.resp.rows {
+            for row in self.resp.rows() {
                 for column in row.columns() {
                     f.write_fmt(format_args!("{:?},", column.value()))?;
                 }
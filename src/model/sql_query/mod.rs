@@ -7,5 +7,5 @@ pub(crate) mod request;
 pub(crate) mod response;
 pub mod row;
 
-pub use request::Request;
-pub use response::Response;
+pub use request::{MultiStatementRequest, Projection, Request};
+pub use response::{Output, Response, StatementResult};
@@ -1,7 +1,14 @@
 // Copyright 2022 CeresDB Project Authors. Licensed under Apache-2.0.
 
+use std::{borrow::Cow, ops::Range, time::Duration};
+
+use crate::{model::ddl::quote_ident, Error, Result};
+
 /// Sql query request.
-#[derive(Debug, Clone)]
+///
+/// Build it by [`Request::new`] and the `with_*` methods, since more optional
+/// fields may be added.
+#[derive(Debug, Clone, Default)]
 pub struct Request {
     /// The tables involved in the sql.
     ///
@@ -10,4 +17,639 @@ pub struct Request {
     pub tables: Vec<String>,
     /// The sql for query.
     pub sql: String,
+    /// How long the response can be cached by the client, and none means the
+    /// query is not cacheable.
+    ///
+    /// It only works if the [`query_cache`](crate::Builder::query_cache) is
+    /// set, and the paged queries are never cached, nor the ones keeping the
+    /// arrow data.
+    pub cache_ttl: Option<Duration>,
+    /// The columns needed, see [`Request::with_columns`].
+    pub projection: Option<Projection>,
+    /// Whether to keep the arrow record batches in the response, see
+    /// [`Request::with_record_batches`].
+    pub keep_record_batches: bool,
+    /// Whether to keep the raw arrow ipc bytes in the response, see
+    /// [`Request::with_arrow_ipc_bytes`].
+    pub keep_arrow_ipc_bytes: bool,
+}
+
+/// The statements of a multi-statement sql, see [`Request::multi`].
+#[derive(Debug, Clone)]
+pub struct MultiStatementRequest {
+    /// The tables involved in the statements, by which all the statements are
+    /// routed to the same endpoint.
+    pub tables: Vec<String>,
+    /// The statements without the separating semicolons, in their order in
+    /// the sql.
+    pub statements: Vec<String>,
+    /// Whether to stop at the first failed statement, and the statements after
+    /// it are not run. Default is true.
+    pub stop_on_error: bool,
+}
+
+impl MultiStatementRequest {
+    /// Route all the statements by the `tables`, and they are sent to the
+    /// default endpoint if there is no table.
+    pub fn with_tables(mut self, tables: Vec<String>) -> Self {
+        self.tables = tables;
+        self
+    }
+
+    /// Whether to stop at the first failed statement or run all of them.
+    pub fn stop_on_error(mut self, stop_on_error: bool) -> Self {
+        self.stop_on_error = stop_on_error;
+        self
+    }
+
+    /// The request of the `i`-th statement.
+    pub(crate) fn statement_request(&self, i: usize) -> Request {
+        Request::new(self.tables.clone(), self.statements[i].clone())
+    }
+}
+
+/// The columns hinted by [`Request::with_columns`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Projection {
+    /// The hinted columns, and only them are decoded from the response.
+    pub columns: Vec<String>,
+    /// Whether the sql is rewritten to select only the hinted columns.
+    pub rewritten: bool,
+}
+
+impl Request {
+    /// Query by the `sql` routed by the `tables`, without any other option.
+    pub fn new(tables: Vec<String>, sql: String) -> Self {
+        Self {
+            tables,
+            sql,
+            cache_ttl: None,
+            projection: None,
+            keep_record_batches: false,
+            keep_arrow_ipc_bytes: false,
+        }
+    }
+
+    /// Cache the response for the `cache_ttl`, see [`Request::cache_ttl`].
+    pub fn with_cache_ttl(mut self, cache_ttl: Option<Duration>) -> Self {
+        self.cache_ttl = cache_ttl;
+        self
+    }
+
+    /// Keep the arrow record batches which the rows are decoded from in the
+    /// response, see
+    /// [`Response::record_batches`](crate::model::sql_query::Response::record_batches).
+    ///
+    /// They are dropped after decoding by default.
+    pub fn with_record_batches(mut self, keep: bool) -> Self {
+        self.keep_record_batches = keep;
+        self
+    }
+
+    /// Keep the raw arrow ipc bytes returned by the server in the response,
+    /// see
+    /// [`Response::arrow_ipc_bytes`](crate::model::sql_query::Response::arrow_ipc_bytes).
+    ///
+    /// They are dropped after decoding by default.
+    pub fn with_arrow_ipc_bytes(mut self, keep: bool) -> Self {
+        self.keep_arrow_ipc_bytes = keep;
+        self
+    }
+
+    /// Split the multi-statement `sql`, e.g. a migration script, into the
+    /// statements run one by one by
+    /// [`DbClient::sql_query_multi`](crate::DbClient::sql_query_multi).
+    ///
+    /// The sql is split by the semicolons outside the quoted text, the
+    /// parentheses and the comments, and the empty statements, e.g. of the
+    /// trailing semicolons, are skipped. It fails if the quotes, parentheses
+    /// or comments are unbalanced, or there is no statement.
+    pub fn multi(sql: &str) -> Result<MultiStatementRequest> {
+        let statements = split_statements(sql).ok_or_else(|| {
+            Error::Client(format!(
+                "unbalanced quotes, parentheses or comments in the sql, sql:{sql}"
+            ))
+        })?;
+        if statements.is_empty() {
+            return Err(Error::Client(format!("no statement in the sql, sql:{sql}")));
+        }
+
+        Ok(MultiStatementRequest {
+            tables: Vec::new(),
+            statements: statements.into_iter().map(str::to_string).collect(),
+            stop_on_error: true,
+        })
+    }
+
+    /// Hint that only the `columns` are needed.
+    ///
+    /// The sql of a simple single-table query like `SELECT * FROM t ...` is
+    /// rewritten to select only the `columns`, and the other sql is left
+    /// unchanged with the hint ignored, see
+    /// [`is_projection_ignored`](Self::is_projection_ignored). Either way,
+    /// the other columns returned by the server are skipped when decoding the
+    /// rows.
+    pub fn with_columns(mut self, columns: &[&str]) -> Self {
+        let columns: Vec<_> = columns.iter().map(|column| column.to_string()).collect();
+        let rewritten = match rewrite_projection(&self.sql, &columns) {
+            Some(sql) => {
+                self.sql = sql;
+                true
+            }
+            None => false,
+        };
+        self.projection = Some(Projection { columns, rewritten });
+        self
+    }
+
+    /// Whether the projection hinted by [`with_columns`](Self::with_columns)
+    /// can't be rewritten into the sql.
+    pub fn is_projection_ignored(&self) -> bool {
+        matches!(&self.projection, Some(projection) if !projection.rewritten)
+    }
+
+    /// Get the sql which can be paged by appending `LIMIT` and `OFFSET`.
+    ///
+    /// The trailing semicolons and comments are removed, and the sql already
+    /// containing `LIMIT` or `OFFSET` at the top level is rejected, while the
+    /// ones in the quoted text or the subqueries are not. The sql of multiple
+    /// statements is rejected too.
+    pub(crate) fn pageable_sql(&self, page_size: usize) -> Result<String> {
+        if page_size == 0 {
+            return Err(Error::Client(
+                "page size of the paged query must be positive".to_string(),
+            ));
+        }
+
+        let sql = self.sql.as_str();
+        let unpageable =
+            |reason: &str| Error::Client(format!("sql can't be paged, reason:{reason}, sql:{sql}"));
+        let text = |token: &Range<usize>| &sql[token.clone()];
+
+        let mut tokens = top_level_tokens(sql)
+            .ok_or_else(|| unpageable("unbalanced quotes, parentheses or comments"))?;
+        while matches!(tokens.last(), Some(token) if text(token) == ";") {
+            tokens.pop();
+        }
+        if tokens.iter().any(|token| text(token) == ";") {
+            return Err(unpageable("multiple statements"));
+        }
+        let has_limit = tokens.iter().any(|token| {
+            text(token).eq_ignore_ascii_case("limit") || text(token).eq_ignore_ascii_case("offset")
+        });
+        if has_limit {
+            return Err(unpageable("LIMIT or OFFSET clause"));
+        }
+
+        let end = tokens.last().map_or(0, |last| last.end);
+        Ok(sql[..end].trim_start().to_string())
+    }
+
+    /// Get the sql bounded to return at most `max_rows` rows.
+    ///
+    /// `LIMIT max_rows` is added to the `SELECT` without `LIMIT`, before its
+    /// `OFFSET` if any, and the `SELECT` with a `LIMIT` not above `max_rows` is
+    /// left unchanged, as well as the other statements. The sql of multiple
+    /// statements or the `SELECT` which can't be bounded safely, e.g. with a
+    /// higher `LIMIT`, is rejected.
+    pub(crate) fn bounded_sql(&self, max_rows: usize) -> Result<Cow<'_, str>> {
+        let sql = self.sql.as_str();
+        let unbounded = |reason: &str| {
+            Error::Client(format!(
+                "sql can't be bounded to {max_rows} rows, reason:{reason}, sql:{sql}"
+            ))
+        };
+        let text = |token: &Range<usize>| &sql[token.clone()];
+
+        let mut tokens = top_level_tokens(sql)
+            .ok_or_else(|| unbounded("unbalanced quotes, parentheses or comments"))?;
+        while matches!(tokens.last(), Some(token) if text(token) == ";") {
+            tokens.pop();
+        }
+        if tokens.iter().any(|token| text(token) == ";") {
+            return Err(unbounded("multiple statements"));
+        }
+        let is_select = match tokens.first() {
+            Some(token) => {
+                let first = text(token);
+                first.eq_ignore_ascii_case("select")
+                    || first.eq_ignore_ascii_case("with")
+                    || first.starts_with('(')
+            }
+            None => false,
+        };
+        if !is_select {
+            return Ok(Cow::Borrowed(sql));
+        }
+
+        let keyword_pos = |keyword: &str| {
+            tokens
+                .iter()
+                .rposition(|token| text(token).eq_ignore_ascii_case(keyword))
+        };
+        if keyword_pos("fetch").is_some() {
+            return Err(unbounded("FETCH clause"));
+        }
+        if let Some(pos) = keyword_pos("limit") {
+            // The row count follows the offset in `LIMIT <offset>, <count>`.
+            let count = match tokens.get(pos + 2) {
+                Some(token) if text(token) == "," => tokens.get(pos + 3),
+                _ => tokens.get(pos + 1),
+            };
+            let count: usize = count
+                .and_then(|token| text(token).parse().ok())
+                .ok_or_else(|| unbounded("LIMIT is not a number"))?;
+            if count > max_rows {
+                return Err(unbounded(&format!("LIMIT {count} is higher")));
+            }
+            return Ok(Cow::Borrowed(sql));
+        }
+
+        let bounded = match keyword_pos("offset") {
+            Some(pos) => {
+                let (head, tail) = sql.split_at(tokens[pos].start);
+                format!("{} LIMIT {max_rows} {tail}", head.trim_end())
+            }
+            None => {
+                let end = tokens.last().map_or(sql.len(), |last| last.end);
+                let (head, tail) = sql.split_at(end);
+                format!("{head} LIMIT {max_rows}{tail}")
+            }
+        };
+        Ok(Cow::Owned(bounded))
+    }
+}
+
+/// Split the sql into the tokens at the top level, i.e. the words and the
+/// punctuations outside the parentheses, and the quoted text or the text in
+/// the parentheses is a token as a whole while the comments are skipped.
+///
+/// None if the quotes, parentheses or comments are unbalanced.
+pub(crate) fn top_level_tokens(sql: &str) -> Option<Vec<Range<usize>>> {
+    let bytes = sql.as_bytes();
+    let is_word = |b: u8| b.is_ascii_alphanumeric() || b == b'_' || !b.is_ascii();
+
+    let mut tokens = Vec::new();
+    let mut depth = 0usize;
+    let mut group_start = 0;
+    let mut i = 0;
+    while i < bytes.len() {
+        let start = i;
+        match bytes[i] {
+            quote @ (b'\'' | b'"' | b'`') => {
+                // The quote is escaped by doubling it.
+                i += 1;
+                loop {
+                    match bytes.get(i) {
+                        None => return None,
+                        Some(&b) if b == quote && bytes.get(i + 1) == Some(&quote) => i += 2,
+                        Some(&b) if b == quote => break,
+                        Some(_) => i += 1,
+                    }
+                }
+                i += 1;
+            }
+            b'-' if bytes.get(i + 1) == Some(&b'-') => {
+                i = sql[i..].find('\n').map_or(bytes.len(), |end| i + end);
+                continue;
+            }
+            b'/' if bytes.get(i + 1) == Some(&b'*') => {
+                i += sql[i + 2..].find("*/")? + 4;
+                continue;
+            }
+            b'(' => {
+                if depth == 0 {
+                    group_start = i;
+                }
+                depth += 1;
+                i += 1;
+                continue;
+            }
+            b')' => {
+                depth = depth.checked_sub(1)?;
+                i += 1;
+                if depth == 0 {
+                    tokens.push(group_start..i);
+                }
+                continue;
+            }
+            b if b.is_ascii_whitespace() => {
+                i += 1;
+                continue;
+            }
+            b if is_word(b) => {
+                while i < bytes.len() && is_word(bytes[i]) {
+                    i += 1;
+                }
+            }
+            _ => i += 1,
+        }
+        if depth == 0 {
+            tokens.push(start..i);
+        }
+    }
+
+    (depth == 0).then_some(tokens)
+}
+
+/// Split the sql into the statements by the top level semicolons, and the
+/// statements are trimmed while the ones of only the comments are skipped.
+///
+/// None if the quotes, parentheses or comments are unbalanced.
+fn split_statements(sql: &str) -> Option<Vec<&str>> {
+    let mut statements = Vec::new();
+    let mut start = 0;
+    let mut is_empty = true;
+    for token in top_level_tokens(sql)? {
+        if &sql[token.clone()] != ";" {
+            is_empty = false;
+            continue;
+        }
+        if !is_empty {
+            statements.push(sql[start..token.start].trim());
+        }
+        start = token.end;
+        is_empty = true;
+    }
+    if !is_empty {
+        statements.push(sql[start..].trim());
+    }
+
+    Some(statements)
+}
+
+/// Rewrite the sql like `SELECT * FROM <table> ...` to select the `columns`,
+/// and none if it is not such a single-table query.
+///
+/// The sql containing subqueries, joins or set operations is never rewritten,
+/// even if the keywords are only in the literals.
+fn rewrite_projection(sql: &str, columns: &[String]) -> Option<String> {
+    let valid_columns = !columns.is_empty()
+        && columns
+            .iter()
+            .all(|column| !column.is_empty() && !column.contains('`'));
+    if !valid_columns {
+        return None;
+    }
+
+    let mut select_count = 0;
+    for word in sql.split(|c: char| !c.is_ascii_alphanumeric() && c != '_') {
+        let word = word.to_ascii_lowercase();
+        match word.as_str() {
+            "select" => select_count += 1,
+            "join" | "union" | "intersect" | "except" | "with" => return None,
+            _ => (),
+        }
+    }
+    if select_count != 1 {
+        return None;
+    }
+
+    let rest = strip_keyword(sql.trim_start(), "select")?;
+    let from_clause = rest.trim_start().strip_prefix('*')?.trim_start();
+    let after_from = strip_keyword(from_clause, "from")?.trim_start();
+    let table_end = after_from
+        .find(|c: char| c.is_whitespace() || c == ';')
+        .unwrap_or(after_from.len());
+    let (table, after_table) = after_from.split_at(table_end);
+    if table.is_empty() || table.contains(',') || after_table.trim_start().starts_with(',') {
+        return None;
+    }
+
+    let projection = columns
+        .iter()
+        .map(|column| quote_ident(column))
+        .collect::<Vec<_>>()
+        .join(", ");
+    Some(format!("SELECT {projection} {from_clause}"))
+}
+
+/// Strip the case-insensitive `keyword` followed by a whitespace.
+fn strip_keyword<'a>(sql: &'a str, keyword: &str) -> Option<&'a str> {
+    let head = sql.get(..keyword.len())?;
+    let rest = &sql[keyword.len()..];
+    (head.eq_ignore_ascii_case(keyword) && rest.starts_with(char::is_whitespace)).then_some(rest)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{split_statements, Request};
+    use crate::Error;
+
+    fn request(sql: &str) -> Request {
+        Request::new(vec!["t".to_string()], sql.to_string())
+    }
+
+    #[test]
+    fn test_rewrite_projection() {
+        let columns = ["ts", "host", "usage"];
+        let cases = [
+            ("SELECT * FROM t", "SELECT `ts`, `host`, `usage` FROM t"),
+            (
+                "  select *\n  from `cpu` where host = 'a' order by ts limit 10;",
+                "SELECT `ts`, `host`, `usage` from `cpu` where host = 'a' order by ts limit 10;",
+            ),
+            (
+                "SELECT *FROM t WHERE ts > 0",
+                "SELECT `ts`, `host`, `usage` FROM t WHERE ts > 0",
+            ),
+        ];
+        for (sql, expected) in cases {
+            let req = request(sql).with_columns(&columns);
+            assert_eq!(req.sql, expected);
+            assert!(!req.is_projection_ignored());
+            assert_eq!(req.projection.unwrap().columns, columns);
+        }
+    }
+
+    #[test]
+    fn test_rewrite_projection_fallback() {
+        let sqls = [
+            "SELECT ts, host FROM t",
+            "SELECT *, ts FROM t",
+            "SELECT COUNT(*) FROM t",
+            "SELECT * FROM t1, t2",
+            "SELECT * FROM t1 , t2",
+            "SELECT * FROM t1 JOIN t2 ON t1.ts = t2.ts",
+            "SELECT * FROM (SELECT * FROM t)",
+            "SELECT * FROM t WHERE ts IN (SELECT ts FROM t2)",
+            "SELECT * FROM t UNION ALL SELECT * FROM t2",
+            "WITH a AS (SELECT 1) SELECT * FROM a",
+            "SELECT * FROM t WHERE name = 'join'",
+            "SHOW CREATE TABLE t",
+            "SELECT * FROM",
+        ];
+        for sql in sqls {
+            let req = request(sql).with_columns(&["ts"]);
+            assert_eq!(req.sql, sql);
+            assert!(req.is_projection_ignored(), "sql:{sql}");
+        }
+
+        // Invalid columns.
+        for columns in [&[][..], &[""], &["a`b"]] {
+            let req = request("SELECT * FROM t").with_columns(columns);
+            assert_eq!(req.sql, "SELECT * FROM t");
+            assert!(req.is_projection_ignored());
+        }
+        assert!(!request("SELECT * FROM t").is_projection_ignored());
+    }
+
+    #[test]
+    fn test_bounded_sql() {
+        let cases = [
+            ("SELECT * FROM t", "SELECT * FROM t LIMIT 100"),
+            (
+                "select * from t where host = 'a';  ",
+                "select * from t where host = 'a' LIMIT 100;  ",
+            ),
+            (
+                "SELECT * FROM t -- no limit here\n",
+                "SELECT * FROM t LIMIT 100 -- no limit here\n",
+            ),
+            (
+                "SELECT * FROM t ORDER BY ts OFFSET 10",
+                "SELECT * FROM t ORDER BY ts LIMIT 100 OFFSET 10",
+            ),
+            (
+                "SELECT * FROM t WHERE ts IN (SELECT ts FROM t2 LIMIT 1000)",
+                "SELECT * FROM t WHERE ts IN (SELECT ts FROM t2 LIMIT 1000) LIMIT 100",
+            ),
+            (
+                "WITH a AS (SELECT * FROM t) SELECT * FROM a",
+                "WITH a AS (SELECT * FROM t) SELECT * FROM a LIMIT 100",
+            ),
+            (
+                "SELECT * FROM t1 UNION ALL SELECT * FROM t2",
+                "SELECT * FROM t1 UNION ALL SELECT * FROM t2 LIMIT 100",
+            ),
+            (
+                "SELECT * FROM t WHERE name = 'limit 1000; offset'",
+                "SELECT * FROM t WHERE name = 'limit 1000; offset' LIMIT 100",
+            ),
+            ("SELECT `limit` FROM t", "SELECT `limit` FROM t LIMIT 100"),
+            // Bounded already.
+            ("SELECT * FROM t LIMIT 10", "SELECT * FROM t LIMIT 10"),
+            (
+                "SELECT * FROM t limit 100 offset 5;",
+                "SELECT * FROM t limit 100 offset 5;",
+            ),
+            (
+                "SELECT * FROM t LIMIT 1000, 10",
+                "SELECT * FROM t LIMIT 1000, 10",
+            ),
+            // Not a query.
+            ("SHOW CREATE TABLE t", "SHOW CREATE TABLE t"),
+            (
+                "INSERT INTO t (ts) VALUES (1)",
+                "INSERT INTO t (ts) VALUES (1)",
+            ),
+        ];
+        for (sql, expected) in cases {
+            assert_eq!(
+                request(sql).bounded_sql(100).unwrap(),
+                expected,
+                "sql:{sql}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_split_statements() {
+        let cases: [(&str, &[&str]); 10] = [
+            ("SELECT 1", &["SELECT 1"]),
+            ("SELECT 1;", &["SELECT 1"]),
+            ("  SELECT 1 ;; SELECT 2;\n", &["SELECT 1", "SELECT 2"]),
+            ("", &[]),
+            (";  ;", &[]),
+            (
+                "INSERT INTO t VALUES ('a;b', \"c;d\"); SELECT `e;f` FROM t",
+                &[
+                    "INSERT INTO t VALUES ('a;b', \"c;d\")",
+                    "SELECT `e;f` FROM t",
+                ],
+            ),
+            // The quotes escaped by doubling them.
+            (
+                "INSERT INTO t VALUES ('it''s;'); SELECT 1",
+                &["INSERT INTO t VALUES ('it''s;')", "SELECT 1"],
+            ),
+            (
+                "-- create the table;\nCREATE TABLE t (ts timestamp);\n-- done;",
+                &["-- create the table;\nCREATE TABLE t (ts timestamp)"],
+            ),
+            (
+                "SELECT 1 /* a; b */; SELECT 2 -- c;",
+                &["SELECT 1 /* a; b */", "SELECT 2 -- c;"],
+            ),
+            (
+                "SELECT * FROM (SELECT 1; SELECT 2); DROP TABLE t",
+                &["SELECT * FROM (SELECT 1; SELECT 2)", "DROP TABLE t"],
+            ),
+        ];
+        for (sql, expected) in cases {
+            assert_eq!(split_statements(sql).unwrap(), expected, "sql:{sql}");
+        }
+
+        for sql in ["SELECT 'a; SELECT 2", "SELECT 1 /* a;", "SELECT (1;"] {
+            assert!(split_statements(sql).is_none(), "sql:{sql}");
+            assert!(Request::multi(sql).is_err());
+        }
+
+        let req =
+            Request::multi("CREATE TABLE t (ts timestamp); INSERT INTO t VALUES (1);").unwrap();
+        assert_eq!(req.statements.len(), 2);
+        assert!(req.stop_on_error);
+        assert!(Request::multi("-- nothing").is_err());
+    }
+
+    #[test]
+    fn test_pageable_sql() {
+        let cases = [
+            ("SELECT * FROM t ;  ; ", "SELECT * FROM t"),
+            ("  SELECT * FROM t -- no limit here\n", "SELECT * FROM t"),
+            (
+                "SELECT * FROM t WHERE name = 'limit 10 offset 20'",
+                "SELECT * FROM t WHERE name = 'limit 10 offset 20'",
+            ),
+            (
+                "SELECT `offset` FROM t WHERE ts IN (SELECT ts FROM t2 LIMIT 10)",
+                "SELECT `offset` FROM t WHERE ts IN (SELECT ts FROM t2 LIMIT 10)",
+            ),
+        ];
+        for (sql, expected) in cases {
+            assert_eq!(request(sql).pageable_sql(2).unwrap(), expected);
+        }
+
+        let sqls = [
+            "SELECT * FROM t limit 10",
+            "SELECT * FROM t ORDER BY ts OFFSET 10",
+            "SELECT * FROM t; SELECT * FROM t2",
+            "SELECT * FROM t WHERE name = 'a",
+        ];
+        for sql in sqls {
+            let err = request(sql).pageable_sql(2).unwrap_err();
+            assert!(matches!(err, Error::Client(_)), "sql:{sql}, err:{err:?}");
+        }
+        assert!(request("SELECT * FROM t").pageable_sql(0).is_err());
+    }
+
+    #[test]
+    fn test_bounded_sql_refusal() {
+        let sqls = [
+            "SELECT * FROM t LIMIT 1000",
+            "SELECT * FROM t LIMIT 10, 1000",
+            "SELECT * FROM t LIMIT ALL",
+            "SELECT * FROM t LIMIT ?",
+            "SELECT * FROM t FETCH FIRST 10 ROWS ONLY",
+            "SELECT * FROM t; SELECT * FROM t2",
+            "SHOW TABLES; SELECT * FROM t",
+            "SELECT * FROM t WHERE name = 'a",
+            "SELECT * FROM (SELECT * FROM t",
+            "SELECT * FROM t /* unterminated",
+        ];
+        for sql in sqls {
+            let err = request(sql).bounded_sql(100).unwrap_err();
+            assert!(matches!(err, Error::Client(_)), "sql:{sql}, err:{err:?}");
+        }
+    }
 }
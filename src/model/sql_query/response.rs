@@ -2,79 +2,311 @@
 
 //! Sql query response
 
-use std::io::Cursor;
+use std::{
+    any::Any,
+    io::Cursor,
+    panic::{self, AssertUnwindSafe},
+    time::Duration,
+};
 
-use arrow::{ipc::reader::StreamReader, record_batch::RecordBatch};
+use arrow::{
+    datatypes::SchemaRef,
+    ipc::{reader::StreamReader, root_as_message},
+    record_batch::RecordBatch,
+};
 use ceresdbproto::storage::{
     arrow_payload::Compression, sql_query_response::Output as OutputPb, ArrowPayload,
     SqlQueryResponse,
 };
+use futures::{future, stream, Stream, StreamExt};
 
 use crate::{
     errors::{Error, Result},
-    model::sql_query::row::{Row, RowBuilder},
+    model::{
+        execution_info::ExecutionInfo,
+        sql_query::{
+            request::Request,
+            row::{Row, RowBuilder},
+        },
+        warning::ServerWarning,
+    },
 };
 
+/// The output of the sql, which is a result set for the queries, e.g.
+/// `SELECT`, and the number of the affected rows for the others, e.g.
+/// `INSERT`, `ALTER` and `CREATE`.
+#[derive(Clone, Debug)]
+pub enum Output {
+    /// The rows and their schema, and the schema is none if the server returns
+    /// no record batch.
+    ResultSet {
+        rows: Vec<Row>,
+        schema: Option<SchemaRef>,
+    },
+    AffectedRows(u64),
+}
+
+impl Default for Output {
+    fn default() -> Self {
+        Output::ResultSet {
+            rows: Vec::new(),
+            schema: None,
+        }
+    }
+}
+
 /// The response for [`SqlQueryRequest`](crate::model::sql_query::Request).
-#[derive(Debug, Default)]
+#[derive(Clone, Debug, Default)]
 pub struct Response {
-    /// The affected rows by the query sql.
-    pub affected_rows: u32,
-    /// The rows of the sql result.
-    pub rows: Vec<Row>,
+    /// The output of the sql.
+    pub output: Output,
+    /// The execution info of the query measured by the client.
+    pub execution_info: ExecutionInfo,
+    /// The warnings reported by the server.
+    pub warnings: Vec<ServerWarning>,
+    /// The arrow record batches which the rows are decoded from, kept only if
+    /// the request asks for them.
+    pub(crate) record_batches: Vec<RecordBatch>,
+    /// The arrow ipc streams which the record batches are decoded from, kept
+    /// only if the request asks for them.
+    pub(crate) ipc_streams: Vec<Vec<u8>>,
 }
 
+impl Response {
+    /// The rows of the result set, and it is empty for the
+    /// [`AffectedRows`](Output::AffectedRows).
+    ///
+    /// Use [`try_rows`](Self::try_rows) to tell the empty result set from
+    /// the affected rows.
+    pub fn rows(&self) -> &[Row] {
+        match &self.output {
+            Output::ResultSet { rows, .. } => rows,
+            Output::AffectedRows(_) => &[],
+        }
+    }
+
+    /// The rows of the result set, and it fails for the
+    /// [`AffectedRows`](Output::AffectedRows).
+    pub fn try_rows(&self) -> Result<&[Row]> {
+        match &self.output {
+            Output::ResultSet { rows, .. } => Ok(rows),
+            Output::AffectedRows(affected_rows) => Err(not_result_set_error(*affected_rows)),
+        }
+    }
+
+    /// The schema of the result set, and it is none for the
+    /// [`AffectedRows`](Output::AffectedRows) or the result set without any
+    /// record batch.
+    pub fn schema(&self) -> Option<&SchemaRef> {
+        match &self.output {
+            Output::ResultSet { schema, .. } => schema.as_ref(),
+            Output::AffectedRows(_) => None,
+        }
+    }
+
+    /// Whether the server warns that the result is partial, e.g. some
+    /// partitions of the table are missing.
+    pub fn is_partial(&self) -> bool {
+        self.warnings.iter().any(ServerWarning::is_partial)
+    }
+
+    /// The number of the affected rows, and it is none for the
+    /// [`ResultSet`](Output::ResultSet).
+    pub fn affected_rows(&self) -> Option<u64> {
+        match &self.output {
+            Output::ResultSet { .. } => None,
+            Output::AffectedRows(affected_rows) => Some(*affected_rows),
+        }
+    }
+
+    /// Consume the response into a stream of its rows, by which the fetched
+    /// results are iterated the same way as the streamed ones, e.g. the pages
+    /// of [`sql_query_paged`](crate::DbClient::sql_query_paged).
+    ///
+    /// The rows are decoded already, and the stream yields a single error
+    /// like [`try_rows`](Self::try_rows) for the
+    /// [`AffectedRows`](Output::AffectedRows).
+    pub fn rows_stream(self) -> impl Stream<Item = Result<Row>> {
+        match self.output {
+            Output::ResultSet { rows, .. } => stream::iter(rows.into_iter().map(Ok)).left_stream(),
+            Output::AffectedRows(affected_rows) => {
+                stream::once(future::ready(Err(not_result_set_error(affected_rows)))).right_stream()
+            }
+        }
+    }
+
+    /// The arrow record batches of the sql result, for the columnar access.
+    ///
+    /// They are kept only if the request is built
+    /// [`with_record_batches`](Request::with_record_batches), and it is empty
+    /// otherwise or if the result is just the affected rows. Only the columns
+    /// hinted by the [`Projection`](super::Projection) are kept if the request
+    /// has one.
+    pub fn record_batches(&self) -> &[RecordBatch] {
+        &self.record_batches
+    }
+
+    /// The raw bytes of the sql result as returned by the server, e.g. to
+    /// forward the result to another system without encoding it again.
+    ///
+    /// Every element is a complete stream in the
+    /// [Arrow IPC streaming format](https://arrow.apache.org/docs/format/Columnar.html#ipc-streaming-format)
+    /// of the metadata version `V5` (Arrow 1.0 and later), which starts with
+    /// its schema message and may contain several record batches, so the
+    /// elements can't be concatenated into one stream. They are decompressed
+    /// already if the server compresses them.
+    ///
+    /// The bytes are kept only if the request is built
+    /// [`with_arrow_ipc_bytes`](Request::with_arrow_ipc_bytes), and it is
+    /// empty otherwise or if the result is just the affected rows. They are
+    /// kept as is, so all the columns are included even if the request has a
+    /// [`Projection`](super::Projection).
+    pub fn arrow_ipc_bytes(&self) -> &[Vec<u8>] {
+        &self.ipc_streams
+    }
+}
+
+/// The result of a statement of the
+/// [`MultiStatementRequest`](crate::model::sql_query::MultiStatementRequest).
 #[derive(Debug)]
-enum Output {
+pub struct StatementResult {
+    /// The sql of the statement.
+    pub sql: String,
+    /// The result set or the affected rows of the statement, or its error.
+    pub result: Result<Response>,
+    /// Time taken by the statement measured by the client.
+    pub elapsed: Duration,
+}
+
+fn not_result_set_error(affected_rows: u64) -> Error {
+    Error::Client(format!(
+        "sql returns the affected rows rather than a result set, \
+         affected_rows:{affected_rows}"
+    ))
+}
+
+/// The output decoded from the pb.
+#[derive(Debug)]
+enum DecodedOutput {
     AffectedRows(u32),
-    Rows(Vec<Row>),
+    /// The record batches, the ipc streams they are decoded from, and whether
+    /// the decoding is aborted for the size.
+    Arrow(Vec<RecordBatch>, Vec<Vec<u8>>, bool),
 }
 
 impl TryFrom<SqlQueryResponse> for Response {
     type Error = Error;
 
+    /// Decode the rows only, as if by the request without any option.
     fn try_from(sql_resp_pb: SqlQueryResponse) -> std::result::Result<Self, Self::Error> {
-        let output_pb = sql_resp_pb
-            .output
-            .ok_or_else(|| Error::Unknown("output is empty in sql query response".to_string()))?;
-        let output = Output::try_from(output_pb)?;
+        Response::decode(sql_resp_pb, &Request::default(), None)
+    }
+}
+
+impl Response {
+    /// Decode the response of the `req`, and the columns not in its
+    /// projection are skipped if it has one. The record batches and the ipc
+    /// streams are kept only if the `req` asks for them.
+    ///
+    /// The decoding is aborted with [`Error::ResponseTooLarge`] once the
+    /// record batches exceed the `max_bytes`, which carries the response of
+    /// the rows decoded so far, and its endpoint is left empty.
+    pub(crate) fn decode(
+        sql_resp_pb: SqlQueryResponse,
+        req: &Request,
+        max_bytes: Option<usize>,
+    ) -> Result<Self> {
+        let output_pb = sql_resp_pb.output.ok_or_else(|| Error::MalformedResponse {
+            detail: "output is empty in sql query response".to_string(),
+        })?;
+        let decoded = DecodedOutput::decode(output_pb, max_bytes)?;
+        let output = match (decoded, &req.projection) {
+            (DecodedOutput::Arrow(record_batches, ipc_streams, aborted), Some(projection)) => {
+                let record_batches = record_batches
+                    .into_iter()
+                    .map(|record_batch| project_record_batch(record_batch, &projection.columns))
+                    .collect::<Result<Vec<_>>>()?;
+                DecodedOutput::Arrow(record_batches, ipc_streams, aborted)
+            }
+            (output, _) => output,
+        };
 
         let resp = match output {
-            Output::AffectedRows(affected) => Response {
-                affected_rows: affected,
-                ..Default::default()
-            },
-            Output::Rows(rows) => Response {
-                rows,
+            DecodedOutput::AffectedRows(affected) => Response {
+                output: Output::AffectedRows(affected as u64),
                 ..Default::default()
             },
+            DecodedOutput::Arrow(mut record_batches, mut ipc_streams, aborted) => {
+                let rows_group = record_batches
+                    .iter()
+                    .map(|record_batch| {
+                        let row_builder =
+                            match RowBuilder::with_arrow_record_batch(record_batch.clone()) {
+                                Ok(builder) => builder,
+                                Err(e) => return Err(e),
+                            };
+                        Ok(row_builder.build())
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+                let rows = rows_group.into_iter().flatten().collect::<Vec<_>>();
+                let schema = record_batches
+                    .first()
+                    .map(|record_batch| record_batch.schema());
+                if !req.keep_record_batches {
+                    record_batches.clear();
+                }
+                if !req.keep_arrow_ipc_bytes {
+                    ipc_streams.clear();
+                }
+
+                let resp = Response {
+                    output: Output::ResultSet { rows, schema },
+                    record_batches,
+                    ipc_streams,
+                    ..Default::default()
+                };
+                if aborted {
+                    return Err(Error::ResponseTooLarge {
+                        limit: max_bytes.unwrap_or_default(),
+                        endpoint: String::new(),
+                        partial: Some(Box::new(resp)),
+                    });
+                }
+                resp
+            }
         };
 
         Ok(resp)
     }
 }
 
-impl TryFrom<OutputPb> for Output {
-    type Error = Error;
+/// Keep only the `columns` in the `record_batch`, and the missing ones are
+/// ignored.
+fn project_record_batch(record_batch: RecordBatch, columns: &[String]) -> Result<RecordBatch> {
+    let schema = record_batch.schema();
+    let indices: Vec<_> = columns
+        .iter()
+        .filter_map(|column| schema.index_of(column).ok())
+        .collect();
+    if indices.iter().copied().eq(0..record_batch.num_columns()) {
+        return Ok(record_batch);
+    }
 
-    fn try_from(output_pb: OutputPb) -> std::result::Result<Self, Self::Error> {
+    record_batch
+        .project(&indices)
+        .map_err(|e| Error::DecodeArrowPayload(Box::new(e)))
+}
+
+impl DecodedOutput {
+    /// Decode the output, and the record batches beyond the `max_bytes` are
+    /// not decoded.
+    fn decode(output_pb: OutputPb, max_bytes: Option<usize>) -> Result<Self> {
         let output = match output_pb {
-            OutputPb::AffectedRows(affected) => Output::AffectedRows(affected),
+            OutputPb::AffectedRows(affected) => DecodedOutput::AffectedRows(affected),
             OutputPb::Arrow(arrow_payload) => {
-                let arrow_record_batches = decode_arrow_payload(arrow_payload)?;
-                let rows_group = arrow_record_batches
-                    .into_iter()
-                    .map(|record_batch| {
-                        let row_builder = match RowBuilder::with_arrow_record_batch(record_batch) {
-                            Ok(builder) => builder,
-                            Err(e) => return Err(e),
-                        };
-                        Ok(row_builder.build())
-                    })
-                    .collect::<Result<Vec<_>>>()?;
-                let rows = rows_group.into_iter().flatten().collect::<Vec<_>>();
-
-                Output::Rows(rows)
+                let (record_batches, ipc_streams, aborted) =
+                    decode_arrow_payload(arrow_payload, max_bytes)?;
+                DecodedOutput::Arrow(record_batches, ipc_streams, aborted)
             }
         };
 
@@ -82,46 +314,671 @@ impl TryFrom<OutputPb> for Output {
     }
 }
 
-pub fn decode_arrow_payload(arrow_payload: ArrowPayload) -> Result<Vec<RecordBatch>> {
+/// Decode the record batches until their total size exceeds the `max_bytes`,
+/// and the decompressed ipc streams decoded so far and whether the decoding
+/// is aborted for it are returned too.
+///
+/// The size is measured by the memory of the decoded arrays.
+fn decode_arrow_payload(
+    arrow_payload: ArrowPayload,
+    max_bytes: Option<usize>,
+) -> Result<(Vec<RecordBatch>, Vec<Vec<u8>>, bool)> {
     let compression = arrow_payload.compression();
-    let byte_batches = arrow_payload.record_batches;
+    let mut record_batches = Vec::new();
+    let mut ipc_streams = Vec::with_capacity(arrow_payload.record_batches.len());
+    let mut total_bytes = 0usize;
+    for bytes_batch in arrow_payload.record_batches {
+        // Maybe unzip payload bytes firstly.
+        let byte_batch = match compression {
+            Compression::None => bytes_batch,
+            Compression::Zstd => zstd::stream::decode_all(Cursor::new(bytes_batch))
+                .map_err(|e| Error::DecodeArrowPayload(Box::new(e)))?,
+        };
+
+        // Multiple record batches may be included in one byte batch. The arrow
+        // reader may panic on the malformed bytes instead of returning an
+        // error, so the panic is caught and returned as error.
+        let decoded = panic::catch_unwind(AssertUnwindSafe(|| decode_byte_batch(&byte_batch)))
+            .unwrap_or_else(|payload| Err(decode_panic_error(payload)))?;
+        ipc_streams.push(byte_batch);
+        for record_batch in decoded {
+            total_bytes = total_bytes.saturating_add(record_batch_bytes(&record_batch));
+            if matches!(max_bytes, Some(max_bytes) if total_bytes > max_bytes) {
+                return Ok((record_batches, ipc_streams, true));
+            }
+            record_batches.push(record_batch);
+        }
+    }
+
+    Ok((record_batches, ipc_streams, false))
+}
+
+/// The memory size of the arrays in the `record_batch`.
+fn record_batch_bytes(record_batch: &RecordBatch) -> usize {
+    record_batch
+        .columns()
+        .iter()
+        .map(|column| column.get_array_memory_size())
+        .sum()
+}
+
+/// The marker preceding the metadata length of an arrow ipc message.
+const IPC_CONTINUATION_MARKER: [u8; 4] = [0xff; 4];
+
+/// Check the lengths of the messages in the arrow ipc stream don't exceed the
+/// bytes, otherwise the arrow reader allocates the buffers by the corrupted
+/// lengths and the process may be aborted.
+fn check_ipc_stream(bytes: &[u8]) -> Result<()> {
+    let malformed = |detail: &str| {
+        Error::DecodeArrowPayload(format!("malformed arrow ipc stream, detail:{detail}").into())
+    };
+    let read_len = |bytes: &[u8]| -> Option<[u8; 4]> { bytes.get(..4)?.try_into().ok() };
+
+    let mut remaining = bytes;
+    // The stream ended without the end-of-stream marker is valid too.
+    while let Some(mut len_bytes) = read_len(remaining) {
+        remaining = &remaining[4..];
+        if len_bytes == IPC_CONTINUATION_MARKER {
+            len_bytes =
+                read_len(remaining).ok_or_else(|| malformed("metadata length is missing"))?;
+            remaining = &remaining[4..];
+        }
+
+        let meta_len = i32::from_le_bytes(len_bytes);
+        if meta_len == 0 {
+            return Ok(());
+        }
+        let meta_len = usize::try_from(meta_len)
+            .ok()
+            .filter(|len| *len <= remaining.len())
+            .ok_or_else(|| malformed(&format!("invalid metadata length:{meta_len}")))?;
+        let message = root_as_message(&remaining[..meta_len])
+            .map_err(|e| malformed(&format!("invalid metadata, err:{e}")))?;
+        remaining = &remaining[meta_len..];
 
-    // Maybe unzip payload bytes firstly.
-    let unzip_byte_batches = byte_batches
+        let body_len = message.bodyLength();
+        let body_len = usize::try_from(body_len)
+            .ok()
+            .filter(|len| *len <= remaining.len())
+            .ok_or_else(|| malformed(&format!("invalid body length:{body_len}")))?;
+        remaining = &remaining[body_len..];
+    }
+
+    Ok(())
+}
+
+fn decode_byte_batch(byte_batch: &[u8]) -> Result<Vec<RecordBatch>> {
+    check_ipc_stream(byte_batch)?;
+
+    // Decode bytes to `RecordBatch`.
+    let stream_reader = StreamReader::try_new(Cursor::new(byte_batch), None)
+        .map_err(|e| Error::DecodeArrowPayload(Box::new(e)))?;
+
+    stream_reader
         .into_iter()
-        .map(|bytes_batch| match compression {
-            Compression::None => Ok(bytes_batch),
-            Compression::Zstd => zstd::stream::decode_all(Cursor::new(bytes_batch))
-                .map_err(|e| Error::DecodeArrowPayload(Box::new(e))),
+        .map(|decode_result| decode_result.map_err(|e| Error::DecodeArrowPayload(Box::new(e))))
+        .collect()
+}
+
+fn decode_panic_error(payload: Box<dyn Any + Send>) -> Error {
+    let msg = if let Some(msg) = payload.downcast_ref::<&str>() {
+        msg.to_string()
+    } else if let Some(msg) = payload.downcast_ref::<String>() {
+        msg.clone()
+    } else {
+        "unknown panic".to_string()
+    };
+
+    Error::DecodeArrowPayload(format!("malformed arrow payload, panic:{msg}").into())
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use arrow::{
+        array::{
+            ArrayRef, BinaryArray, BooleanArray, Date32Array, Date64Array, Float16Array,
+            Float32Array, Float64Array, Int16Array, Int32Array, Int64Array, Int8Array,
+            LargeBinaryArray, LargeStringArray, NullArray, StringArray, Time32MillisecondArray,
+            Time32SecondArray, Time64MicrosecondArray, Time64NanosecondArray,
+            TimestampMicrosecondArray, TimestampMillisecondArray, TimestampNanosecondArray,
+            TimestampSecondArray, UInt16Array, UInt32Array, UInt64Array, UInt8Array,
+        },
+        ipc::{reader::StreamReader, writer::StreamWriter},
+        record_batch::RecordBatch,
+    };
+    use ceresdbproto::storage::{
+        arrow_payload::Compression, sql_query_response::Output as OutputPb, ArrowPayload,
+        SqlQueryResponse,
+    };
+    use futures::{executor::block_on, StreamExt};
+    use half::f16;
+
+    use super::{record_batch_bytes, Output, Response};
+    use crate::{
+        errors::Error,
+        model::{
+            sql_query::request::{Projection, Request},
+            value::Value,
+        },
+    };
+
+    fn encode_response(record_batch: &RecordBatch, compression: Compression) -> SqlQueryResponse {
+        let mut bytes = Vec::new();
+        let mut writer = StreamWriter::try_new(&mut bytes, &record_batch.schema()).unwrap();
+        writer.write(record_batch).unwrap();
+        writer.finish().unwrap();
+        drop(writer);
+
+        let bytes = match compression {
+            Compression::None => bytes,
+            Compression::Zstd => zstd::stream::encode_all(bytes.as_slice(), 0).unwrap(),
+        };
+        SqlQueryResponse {
+            header: None,
+            output: Some(OutputPb::Arrow(ArrowPayload {
+                record_batches: vec![bytes],
+                compression: compression as i32,
+            })),
+        }
+    }
+
+    fn decode_response(record_batch: &RecordBatch, compression: Compression) -> Response {
+        let resp_pb = encode_response(record_batch, compression);
+        Response::decode(resp_pb, &keeping_batches(None), None).unwrap()
+    }
+
+    /// Request keeping the record batches, with the `projection` if any.
+    fn keeping_batches(projection: Option<Projection>) -> Request {
+        let req = Request {
+            projection,
+            ..Default::default()
+        };
+        req.with_record_batches(true)
+    }
+
+    fn column_values(resp: &Response, name: &str) -> Vec<Value> {
+        resp.rows()
+            .iter()
+            .map(|row| row.column(name).unwrap().value().clone())
+            .collect()
+    }
+
+    #[test]
+    fn test_decode_all_types() {
+        // (column name, arrow array with a single value, expected value)
+        let columns: Vec<(&str, ArrayRef, Value)> = vec![
+            ("null", Arc::new(NullArray::new(1)), Value::Null),
+            (
+                "boolean",
+                Arc::new(BooleanArray::from(vec![true])),
+                Value::Boolean(true),
+            ),
+            ("int8", Arc::new(Int8Array::from(vec![-8])), Value::Int8(-8)),
+            (
+                "int16",
+                Arc::new(Int16Array::from(vec![-16])),
+                Value::Int16(-16),
+            ),
+            (
+                "int32",
+                Arc::new(Int32Array::from(vec![-32])),
+                Value::Int32(-32),
+            ),
+            (
+                "int64",
+                Arc::new(Int64Array::from(vec![-64])),
+                Value::Int64(-64),
+            ),
+            (
+                "uint8",
+                Arc::new(UInt8Array::from(vec![8])),
+                Value::UInt8(8),
+            ),
+            (
+                "uint16",
+                Arc::new(UInt16Array::from(vec![16])),
+                Value::UInt16(16),
+            ),
+            (
+                "uint32",
+                Arc::new(UInt32Array::from(vec![32])),
+                Value::UInt32(32),
+            ),
+            (
+                "uint64",
+                Arc::new(UInt64Array::from(vec![64])),
+                Value::UInt64(64),
+            ),
+            (
+                "float16",
+                Arc::new(Float16Array::from(vec![f16::from_f32(1.5)])),
+                Value::Float(1.5),
+            ),
+            (
+                "float32",
+                Arc::new(Float32Array::from(vec![2.5])),
+                Value::Float(2.5),
+            ),
+            (
+                "float64",
+                Arc::new(Float64Array::from(vec![3.5])),
+                Value::Double(3.5),
+            ),
+            (
+                "utf8",
+                Arc::new(StringArray::from(vec!["utf8"])),
+                Value::String("utf8".to_string()),
+            ),
+            (
+                "large_utf8",
+                Arc::new(LargeStringArray::from(vec!["large_utf8"])),
+                Value::String("large_utf8".to_string()),
+            ),
+            (
+                "binary",
+                Arc::new(BinaryArray::from(vec![b"binary".as_slice()])),
+                Value::Varbinary(b"binary".to_vec()),
+            ),
+            (
+                "large_binary",
+                Arc::new(LargeBinaryArray::from(vec![b"large_binary".as_slice()])),
+                Value::Varbinary(b"large_binary".to_vec()),
+            ),
+            (
+                "timestamp_s",
+                Arc::new(TimestampSecondArray::from(vec![1_000])),
+                Value::Timestamp(1_000_000),
+            ),
+            (
+                "timestamp_ms",
+                Arc::new(TimestampMillisecondArray::from(vec![1_000_001])),
+                Value::Timestamp(1_000_001),
+            ),
+            (
+                "timestamp_us",
+                Arc::new(TimestampMicrosecondArray::from(vec![1_000_002_000])),
+                Value::Timestamp(1_000_002),
+            ),
+            (
+                "timestamp_ns",
+                Arc::new(TimestampNanosecondArray::from(vec![1_000_003_000_000])),
+                Value::Timestamp(1_000_003),
+            ),
+            // The timestamps before the epoch are rounded down.
+            (
+                "timestamp_us_before_epoch",
+                Arc::new(TimestampMicrosecondArray::from(vec![-1])),
+                Value::Timestamp(-1),
+            ),
+            (
+                "timestamp_ns_before_epoch",
+                Arc::new(TimestampNanosecondArray::from(vec![-1_500_000])),
+                Value::Timestamp(-2),
+            ),
+            (
+                "time32_s",
+                Arc::new(Time32SecondArray::from(vec![10])),
+                Value::Int32(10),
+            ),
+            (
+                "time32_ms",
+                Arc::new(Time32MillisecondArray::from(vec![10_001])),
+                Value::Int32(10_001),
+            ),
+            (
+                "time64_us",
+                Arc::new(Time64MicrosecondArray::from(vec![10_002_000])),
+                Value::Int64(10_002_000),
+            ),
+            (
+                "time64_ns",
+                Arc::new(Time64NanosecondArray::from(vec![10_003_000_000])),
+                Value::Int64(10_003_000_000),
+            ),
+            (
+                "date32",
+                Arc::new(Date32Array::from(vec![1])),
+                Value::Timestamp(86_400_000),
+            ),
+            (
+                "date64",
+                Arc::new(Date64Array::from(vec![86_400_001])),
+                Value::Timestamp(86_400_001),
+            ),
+        ];
+        let record_batch = RecordBatch::try_from_iter(
+            columns
+                .iter()
+                .map(|(name, array, _)| (name.to_string(), array.clone())),
+        )
+        .unwrap();
+
+        let resp = decode_response(&record_batch, Compression::None);
+        assert_eq!(resp.rows().len(), 1);
+        for (name, _, expected) in columns {
+            assert_eq!(column_values(&resp, name), vec![expected], "column:{name}");
+        }
+        assert_eq!(resp.record_batches(), &[record_batch]);
+    }
+
+    #[test]
+    fn test_decode_mixed_nulls() {
+        let int_array = Int64Array::from(vec![Some(1), None, Some(3)]);
+        let string_array = StringArray::from(vec![None, Some("b"), None]);
+        let timestamp_array = TimestampMillisecondArray::from(vec![Some(1000), None, Some(3000)]);
+        let record_batch = RecordBatch::try_from_iter(vec![
+            ("int", Arc::new(int_array) as ArrayRef),
+            ("string", Arc::new(string_array) as ArrayRef),
+            ("timestamp", Arc::new(timestamp_array) as ArrayRef),
+        ])
+        .unwrap();
+
+        let resp = decode_response(&record_batch, Compression::Zstd);
+        assert_eq!(
+            column_values(&resp, "int"),
+            vec![Value::Int64(1), Value::Null, Value::Int64(3)]
+        );
+        assert_eq!(
+            column_values(&resp, "string"),
+            vec![Value::Null, Value::String("b".to_string()), Value::Null]
+        );
+        assert_eq!(
+            column_values(&resp, "timestamp"),
+            vec![Value::Timestamp(1000), Value::Null, Value::Timestamp(3000)]
+        );
+        assert_eq!(resp.record_batches(), &[record_batch]);
+    }
+
+    #[test]
+    fn test_decode_outputs() {
+        let affected_pb = |affected| SqlQueryResponse {
+            header: None,
+            output: Some(OutputPb::AffectedRows(affected)),
+        };
+        let resp = Response::try_from(affected_pb(3)).unwrap();
+        assert!(matches!(resp.output, Output::AffectedRows(3)));
+        assert_eq!(resp.affected_rows(), Some(3));
+        assert!(resp.rows().is_empty());
+        assert!(resp.schema().is_none());
+        assert!(matches!(resp.try_rows(), Err(Error::Client(_))));
+
+        let record_batch = RecordBatch::try_from_iter(vec![(
+            "ts",
+            Arc::new(Int64Array::from(vec![1, 2])) as ArrayRef,
+        )])
+        .unwrap();
+        let resp = decode_response(&record_batch, Compression::None);
+        assert_eq!(resp.affected_rows(), None);
+        assert_eq!(resp.try_rows().unwrap().len(), 2);
+        assert_eq!(resp.schema(), Some(&record_batch.schema()));
+
+        // The empty result set is told from the zero affected rows.
+        let resp = Response::try_from(affected_pb(0)).unwrap();
+        assert_eq!(resp.affected_rows(), Some(0));
+        assert!(resp.try_rows().is_err());
+
+        let empty_pb = SqlQueryResponse {
+            header: None,
+            output: Some(OutputPb::Arrow(ArrowPayload {
+                record_batches: Vec::new(),
+                compression: Compression::None as i32,
+            })),
+        };
+        let resp = Response::try_from(empty_pb).unwrap();
+        assert_eq!(resp.affected_rows(), None);
+        assert!(resp.try_rows().unwrap().is_empty());
+        assert!(resp.schema().is_none());
+
+        let resp = decode_response(&record_batch.slice(0, 0), Compression::None);
+        assert_eq!(resp.affected_rows(), None);
+        assert!(resp.try_rows().unwrap().is_empty());
+        assert_eq!(resp.schema(), Some(&record_batch.schema()));
+    }
+
+    #[test]
+    fn test_rows_stream() {
+        let record_batch = RecordBatch::try_from_iter(vec![(
+            "ts",
+            Arc::new(Int64Array::from(vec![1, 2])) as ArrayRef,
+        )])
+        .unwrap();
+        let resp = decode_response(&record_batch, Compression::None);
+        let expected = resp.rows().to_vec();
+        let rows = block_on(resp.rows_stream().collect::<Vec<_>>());
+        let rows = rows.into_iter().collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(rows, expected);
+
+        let resp = decode_response(&record_batch.slice(0, 0), Compression::None);
+        assert_eq!(block_on(resp.rows_stream().count()), 0);
+
+        let resp = Response::try_from(SqlQueryResponse {
+            header: None,
+            output: Some(OutputPb::AffectedRows(3)),
         })
-        .collect::<Result<Vec<Vec<u8>>>>()?;
+        .unwrap();
+        let items = block_on(resp.rows_stream().collect::<Vec<_>>());
+        assert_eq!(items.len(), 1);
+        assert!(matches!(items[0], Err(Error::Client(_))));
+    }
 
-    // Decode the byte batches to record batches, multiple record batches may be
-    // included in one byte batch.
-    let record_batches_group = unzip_byte_batches
-        .into_iter()
-        .map(|byte_batch| {
-            // Decode bytes to `RecordBatch`.
-            let stream_reader = match StreamReader::try_new(Cursor::new(byte_batch), None)
-                .map_err(|e| Error::DecodeArrowPayload(Box::new(e)))
-            {
-                Ok(reader) => reader,
-                Err(e) => return Err(e),
+    #[test]
+    fn test_arrow_ipc_bytes() {
+        let record_batch = RecordBatch::try_from_iter(vec![
+            ("ts", Arc::new(Int64Array::from(vec![1, 2])) as ArrayRef),
+            (
+                "host",
+                Arc::new(StringArray::from(vec!["a", "b"])) as ArrayRef,
+            ),
+        ])
+        .unwrap();
+
+        for compression in [Compression::None, Compression::Zstd] {
+            let resp_pb = encode_response(&record_batch, compression);
+            let projection = Projection {
+                columns: vec!["host".to_string()],
+                rewritten: false,
             };
+            let req = keeping_batches(Some(projection)).with_arrow_ipc_bytes(true);
+            let resp = Response::decode(resp_pb.clone(), &req, None).unwrap();
+            assert_eq!(resp.record_batches()[0].num_columns(), 1);
+
+            // The raw bytes are decompressed and keep all the columns.
+            let streams = resp.arrow_ipc_bytes();
+            assert_eq!(streams.len(), 1);
+            let decoded = StreamReader::try_new(streams[0].as_slice(), None)
+                .unwrap()
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap();
+            assert_eq!(decoded, vec![record_batch.clone()]);
 
-            stream_reader
-                .into_iter()
-                .map(|decode_result| {
-                    decode_result.map_err(|e| Error::DecodeArrowPayload(Box::new(e)))
-                })
-                .collect::<Result<Vec<_>>>()
+            // Neither is kept unless asked for.
+            let resp = Response::try_from(resp_pb).unwrap();
+            assert_eq!(resp.rows().len(), 2);
+            assert!(resp.record_batches().is_empty());
+            assert!(resp.arrow_ipc_bytes().is_empty());
+        }
+
+        let resp = Response::try_from(SqlQueryResponse {
+            header: None,
+            output: Some(OutputPb::AffectedRows(3)),
         })
-        .collect::<Result<Vec<Vec<_>>>>()?;
+        .unwrap();
+        assert!(resp.arrow_ipc_bytes().is_empty());
+    }
 
-    let record_batches = record_batches_group
-        .into_iter()
-        .flatten()
-        .collect::<Vec<_>>();
+    #[test]
+    fn test_decode_with_projection() {
+        let record_batch = RecordBatch::try_from_iter(vec![
+            ("ts", Arc::new(Int64Array::from(vec![1, 2])) as ArrayRef),
+            (
+                "host",
+                Arc::new(StringArray::from(vec!["a", "b"])) as ArrayRef,
+            ),
+            (
+                "usage",
+                Arc::new(Float64Array::from(vec![0.5, 1.5])) as ArrayRef,
+            ),
+            ("extra", Arc::new(Int32Array::from(vec![3, 4])) as ArrayRef),
+        ])
+        .unwrap();
+        let resp_pb = encode_response(&record_batch, Compression::None);
+
+        // Only the hinted columns are decoded in the hinted order, and the missing one
+        // is ignored.
+        let projection = Projection {
+            columns: vec!["usage".to_string(), "ts".to_string(), "missing".to_string()],
+            rewritten: false,
+        };
+        let resp =
+            Response::decode(resp_pb.clone(), &keeping_batches(Some(projection)), None).unwrap();
+        for row in resp.rows() {
+            let names: Vec<_> = row.columns().iter().map(|column| column.name()).collect();
+            assert_eq!(names, ["usage", "ts"]);
+        }
+        assert_eq!(
+            column_values(&resp, "usage"),
+            vec![Value::Double(0.5), Value::Double(1.5)]
+        );
+        assert_eq!(
+            column_values(&resp, "ts"),
+            vec![Value::Int64(1), Value::Int64(2)]
+        );
+        assert_eq!(
+            resp.record_batches(),
+            &[record_batch.project(&[2, 0]).unwrap()]
+        );
+
+        // Nothing is skipped if all the columns are hinted.
+        let projection = Projection {
+            columns: vec![
+                "ts".to_string(),
+                "host".to_string(),
+                "usage".to_string(),
+                "extra".to_string(),
+            ],
+            rewritten: true,
+        };
+        let resp = Response::decode(resp_pb, &keeping_batches(Some(projection)), None).unwrap();
+        assert_eq!(resp.rows()[0].columns().len(), 4);
+        assert_eq!(resp.record_batches(), &[record_batch]);
+    }
 
-    Ok(record_batches)
+    #[test]
+    fn test_decode_malformed_responses() {
+        let empty_pb = SqlQueryResponse {
+            header: None,
+            output: None,
+        };
+        assert!(matches!(
+            Response::try_from(empty_pb),
+            Err(Error::MalformedResponse { .. })
+        ));
+
+        let record_batch = RecordBatch::try_from_iter(vec![
+            ("ts", Arc::new(Int64Array::from(vec![1, 2])) as ArrayRef),
+            (
+                "name",
+                Arc::new(StringArray::from(vec!["a", "b"])) as ArrayRef,
+            ),
+        ])
+        .unwrap();
+        for compression in [Compression::None, Compression::Zstd] {
+            let resp_pb = encode_response(&record_batch, compression);
+            let bytes = match &resp_pb.output {
+                Some(OutputPb::Arrow(payload)) => payload.record_batches[0].clone(),
+                _ => unreachable!(),
+            };
+            let with_bytes = |bytes: Vec<u8>| SqlQueryResponse {
+                header: None,
+                output: Some(OutputPb::Arrow(ArrowPayload {
+                    record_batches: vec![bytes],
+                    compression: compression as i32,
+                })),
+            };
+
+            // The truncated and corrupted payloads are decoded without
+            // panicking, and the result is not checked as some of them are
+            // still valid.
+            for len in 0..bytes.len() {
+                let _ = Response::try_from(with_bytes(bytes[..len].to_vec()));
+            }
+            for idx in 0..bytes.len() {
+                let mut corrupted = bytes.clone();
+                corrupted[idx] ^= 0xff;
+                let _ = Response::try_from(with_bytes(corrupted));
+            }
+            assert!(Response::try_from(with_bytes(vec![0xff; 16])).is_err());
+        }
+    }
+
+    #[test]
+    fn test_decode_within_max_bytes() {
+        let batches: Vec<_> = (0..3)
+            .map(|i| {
+                RecordBatch::try_from_iter(vec![(
+                    "ts",
+                    Arc::new(Int64Array::from(vec![i * 2, i * 2 + 1])) as ArrayRef,
+                )])
+                .unwrap()
+            })
+            .collect();
+        let byte_batches = batches
+            .iter()
+            .map(
+                |batch| match encode_response(batch, Compression::Zstd).output {
+                    Some(OutputPb::Arrow(mut payload)) => payload.record_batches.remove(0),
+                    _ => unreachable!(),
+                },
+            )
+            .collect();
+        let resp_pb = SqlQueryResponse {
+            header: None,
+            output: Some(OutputPb::Arrow(ArrowPayload {
+                record_batches: byte_batches,
+                compression: Compression::Zstd as i32,
+            })),
+        };
+        let resp = Response::decode(resp_pb.clone(), &keeping_batches(None), None).unwrap();
+        let sizes: Vec<_> = resp
+            .record_batches()
+            .iter()
+            .map(record_batch_bytes)
+            .collect();
+        let total: usize = sizes.iter().sum();
+
+        let resp = Response::decode(resp_pb.clone(), &Request::default(), Some(total)).unwrap();
+        assert_eq!(resp.rows().len(), 6);
+
+        // The decoding is aborted at the batch exceeding the max bytes.
+        for (max_bytes, expected_rows) in [
+            (total - 1, 4),
+            (sizes[0] + sizes[1], 4),
+            (sizes[0], 2),
+            (sizes[0] - 1, 0),
+            (0, 0),
+        ] {
+            let err = Response::decode(resp_pb.clone(), &keeping_batches(None), Some(max_bytes))
+                .unwrap_err();
+            assert!(
+                matches!(&err, Error::ResponseTooLarge { limit, .. } if *limit == max_bytes),
+                "err:{err:?}"
+            );
+            let partial = err.partial_response().unwrap();
+            assert_eq!(partial.rows().len(), expected_rows);
+            assert_eq!(partial.record_batches(), &batches[..expected_rows / 2]);
+            let ts: Vec<_> = column_values(partial, "ts");
+            let expected: Vec<_> = (0..expected_rows as i64).map(Value::Int64).collect();
+            assert_eq!(ts, expected);
+        }
+
+        // The affected rows are never aborted.
+        let affected_pb = SqlQueryResponse {
+            header: None,
+            output: Some(OutputPb::AffectedRows(3)),
+        };
+        let resp = Response::decode(affected_pb, &Request::default(), Some(0)).unwrap();
+        assert_eq!(resp.affected_rows(), Some(3));
+    }
 }
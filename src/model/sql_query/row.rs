@@ -4,9 +4,12 @@
 
 use arrow::{
     array::{
-        ArrayRef, BinaryArray, BooleanArray, Float32Array, Float64Array, Int16Array, Int32Array,
-        Int64Array, Int8Array, StringArray, Time32MillisecondArray, TimestampMillisecondArray,
-        UInt16Array, UInt32Array, UInt64Array, UInt8Array,
+        Array, ArrayRef, BinaryArray, BooleanArray, Date32Array, Date64Array, Float16Array,
+        Float32Array, Float64Array, Int16Array, Int32Array, Int64Array, Int8Array,
+        LargeBinaryArray, LargeStringArray, StringArray, Time32MillisecondArray, Time32SecondArray,
+        Time64MicrosecondArray, Time64NanosecondArray, TimestampMicrosecondArray,
+        TimestampMillisecondArray, TimestampNanosecondArray, TimestampSecondArray, UInt16Array,
+        UInt32Array, UInt64Array, UInt8Array,
     },
     datatypes::{DataType, TimeUnit},
     record_batch::RecordBatch,
@@ -33,6 +36,20 @@ impl Row {
     pub fn columns(&self) -> &[Column] {
         &self.columns
     }
+
+    /// Get the value of the column converted to `T`, and none is returned if
+    /// the value is null.
+    ///
+    /// It fails if the column is not found or the value can't be converted to
+    /// `T` without loss, e.g. a negative [`Value::Int64`] to `u64`.
+    pub fn get<'a, T>(&'a self, name: &str) -> Result<Option<T>>
+    where
+        T: TryFrom<&'a Value, Error = Error>,
+    {
+        self.column(name)
+            .ok_or_else(|| Error::Client(format!("column not found, name:{name}")))?
+            .get()
+    }
 }
 
 /// A column in the [`Row`].
@@ -56,25 +73,60 @@ impl Column {
     pub fn value(&self) -> &Value {
         &self.value
     }
+
+    /// Return the value converted to `T`, see [`Row::get`].
+    pub fn get<'a, T>(&'a self) -> Result<Option<T>>
+    where
+        T: TryFrom<&'a Value, Error = Error>,
+    {
+        if self.value.is_null() {
+            return Ok(None);
+        }
+
+        T::try_from(&self.value).map(Some)
+    }
 }
 
 macro_rules! fill_column {
-    ($arrow_column:expr, $arrow_array_type:ty, $value_type:ty, $rows:expr, $col_idx:expr) => {
+    ($arrow_column:expr, $arrow_array_type:ty, $to_value:expr, $rows:expr, $col_idx:expr) => {
         paste! {
-            let row_count = $rows.len();
             let cast_arrow_column = $arrow_column
                 .as_any()
-                .downcast_ref::<$arrow_array_type>().unwrap();
-            for row_idx in 0..row_count {
+                .downcast_ref::<$arrow_array_type>()
+                .ok_or_else(|| {
+                    Error::BuildRows(format!(
+                        "Column doesn't match its type, col_idx:{}, type:{}",
+                        $col_idx,
+                        $arrow_column.data_type()
+                    ))
+                })?;
+            if cast_arrow_column.len() != $rows.len() {
+                return Err(Error::BuildRows(format!(
+                    "Column length doesn't match the rows, col_idx:{}, len:{}, rows:{}",
+                    $col_idx,
+                    cast_arrow_column.len(),
+                    $rows.len()
+                )));
+            }
+            for (row_idx, row) in $rows.iter_mut().enumerate() {
+                // Keep the initialized `Value::Null` for null.
+                if cast_arrow_column.is_null(row_idx) {
+                    continue;
+                }
                 let value = cast_arrow_column.value(row_idx).to_owned();
-                let row = $rows.get_mut(row_idx).unwrap();
-                let col = row.get_mut($col_idx).unwrap();
-                *col = $value_type(value)
+                if let Some(col) = row.get_mut($col_idx) {
+                    *col = ($to_value)(value);
+                }
             }
         }
     };
 }
 
+const MILLIS_PER_SECOND: i64 = 1000;
+const MICROS_PER_MILLI: i64 = 1000;
+const NANOS_PER_MILLI: i64 = 1_000_000;
+const MILLIS_PER_DAY: i64 = 24 * 3600 * MILLIS_PER_SECOND;
+
 #[derive(Clone, Debug, Default)]
 pub struct RowBuilder {
     pub col_idx_to_name: Vec<String>,
@@ -90,8 +142,12 @@ impl RowBuilder {
                     .into_iter()
                     .enumerate()
                     .map(|(col_idx, value)| {
-                        // Find its name.
-                        let col_name = self.col_idx_to_name[col_idx].clone();
+                        // Find its name, which is empty if missing.
+                        let col_name = self
+                            .col_idx_to_name
+                            .get(col_idx)
+                            .cloned()
+                            .unwrap_or_default();
 
                         Column::new(col_name, value)
                     })
@@ -134,7 +190,6 @@ impl RowBuilder {
         col_idx: usize,
         arrow_column: &ArrayRef,
     ) -> Result<()> {
-        let row_count = rows.len();
         let arrow_type = arrow_column.data_type();
         // TODO: may we can make it simpler with macro.
         match arrow_type {
@@ -168,18 +223,49 @@ impl RowBuilder {
             DataType::UInt64 => {
                 fill_column!(arrow_column, UInt64Array, Value::UInt64, rows, col_idx);
             }
+            DataType::Float16 => {
+                fill_column!(
+                    arrow_column,
+                    Float16Array,
+                    |v| Value::Float(f32::from(v)),
+                    rows,
+                    col_idx
+                );
+            }
             DataType::Float32 => {
                 fill_column!(arrow_column, Float32Array, Value::Float, rows, col_idx);
             }
             DataType::Float64 => {
                 fill_column!(arrow_column, Float64Array, Value::Double, rows, col_idx);
             }
-            DataType::Utf8 | DataType::LargeUtf8 => {
+            DataType::Utf8 => {
                 fill_column!(arrow_column, StringArray, Value::String, rows, col_idx);
             }
-            DataType::Binary | DataType::LargeBinary => {
+            DataType::LargeUtf8 => {
+                fill_column!(arrow_column, LargeStringArray, Value::String, rows, col_idx);
+            }
+            DataType::Binary => {
                 fill_column!(arrow_column, BinaryArray, Value::Varbinary, rows, col_idx);
             }
+            DataType::LargeBinary => {
+                fill_column!(
+                    arrow_column,
+                    LargeBinaryArray,
+                    Value::Varbinary,
+                    rows,
+                    col_idx
+                );
+            }
+            // All the timestamps are converted to the ones in milliseconds.
+            DataType::Timestamp(TimeUnit::Second, _) => {
+                fill_column!(
+                    arrow_column,
+                    TimestampSecondArray,
+                    |v: i64| Value::Timestamp(v.saturating_mul(MILLIS_PER_SECOND)),
+                    rows,
+                    col_idx
+                );
+            }
             DataType::Timestamp(TimeUnit::Millisecond, _) => {
                 fill_column!(
                     arrow_column,
@@ -189,17 +275,67 @@ impl RowBuilder {
                     col_idx
                 );
             }
+            DataType::Timestamp(TimeUnit::Microsecond, _) => {
+                fill_column!(
+                    arrow_column,
+                    TimestampMicrosecondArray,
+                    |v: i64| Value::Timestamp(v.div_euclid(MICROS_PER_MILLI)),
+                    rows,
+                    col_idx
+                );
+            }
+            DataType::Timestamp(TimeUnit::Nanosecond, _) => {
+                fill_column!(
+                    arrow_column,
+                    TimestampNanosecondArray,
+                    |v: i64| Value::Timestamp(v.div_euclid(NANOS_PER_MILLI)),
+                    rows,
+                    col_idx
+                );
+            }
+            // The times of day are not timestamps, so they are kept as the
+            // integers in their units, which are found in the schema.
+            DataType::Time32(TimeUnit::Second) => {
+                fill_column!(arrow_column, Time32SecondArray, Value::Int32, rows, col_idx);
+            }
             DataType::Time32(TimeUnit::Millisecond) => {
-                let cast_arrow_column = arrow_column
-                    .as_any()
-                    .downcast_ref::<Time32MillisecondArray>()
-                    .unwrap();
-                for row_idx in 0..row_count {
-                    let value = cast_arrow_column.value(row_idx);
-                    let row = rows.get_mut(row_idx).unwrap();
-                    let col = row.get_mut(col_idx).unwrap();
-                    *col = Value::Timestamp(value as i64)
-                }
+                fill_column!(
+                    arrow_column,
+                    Time32MillisecondArray,
+                    Value::Int32,
+                    rows,
+                    col_idx
+                );
+            }
+            DataType::Time64(TimeUnit::Microsecond) => {
+                fill_column!(
+                    arrow_column,
+                    Time64MicrosecondArray,
+                    Value::Int64,
+                    rows,
+                    col_idx
+                );
+            }
+            DataType::Time64(TimeUnit::Nanosecond) => {
+                fill_column!(
+                    arrow_column,
+                    Time64NanosecondArray,
+                    Value::Int64,
+                    rows,
+                    col_idx
+                );
+            }
+            DataType::Date32 => {
+                fill_column!(
+                    arrow_column,
+                    Date32Array,
+                    |v: i32| Value::Timestamp(v as i64 * MILLIS_PER_DAY),
+                    rows,
+                    col_idx
+                );
+            }
+            DataType::Date64 => {
+                fill_column!(arrow_column, Date64Array, Value::Timestamp, rows, col_idx);
             }
             // Encounter unsupported type.
             _ => {
@@ -218,11 +354,14 @@ mod test {
 
     use arrow::{
         array::{
-            BinaryArray, Int32Array, StringArray, Time32MillisecondArray, TimestampMillisecondArray,
+            ArrayRef, BinaryArray, Float32Array, Float64Array, Int16Array, Int32Array, Int64Array,
+            Int8Array, StringArray, Time32MillisecondArray, TimestampMillisecondArray, UInt16Array,
+            UInt32Array, UInt64Array, UInt8Array,
         },
         datatypes::{DataType, Field, Schema},
         record_batch::RecordBatch,
     };
+    use ceresdbproto::storage::Value as ValuePb;
 
     use super::{Row, RowBuilder};
     use crate::model::{sql_query::row::Column, value::Value};
@@ -291,7 +430,7 @@ mod test {
             .collect::<Vec<_>>();
         let timestamp32_col_values = timestamp32_values
             .into_iter()
-            .map(|v| Value::Timestamp(v as i64))
+            .map(Value::Int32)
             .collect::<Vec<_>>();
         let row1 = Row {
             columns: vec![
@@ -324,4 +463,131 @@ mod test {
 
         assert_eq!(built_rows, expected_rows);
     }
+
+    #[test]
+    fn test_boundary_values_round_trip() {
+        let f32_subnormal = f32::from_bits(1);
+        let columns: Vec<(&str, ArrayRef)> = vec![
+            (
+                "u64",
+                Arc::new(UInt64Array::from(vec![Some(0), Some(u64::MAX), None])),
+            ),
+            (
+                "u32",
+                Arc::new(UInt32Array::from(vec![Some(0), Some(u32::MAX), None])),
+            ),
+            (
+                "u16",
+                Arc::new(UInt16Array::from(vec![Some(0), Some(u16::MAX), None])),
+            ),
+            (
+                "u8",
+                Arc::new(UInt8Array::from(vec![Some(0), Some(u8::MAX), None])),
+            ),
+            (
+                "i64",
+                Arc::new(Int64Array::from(vec![Some(i64::MIN), Some(i64::MAX), None])),
+            ),
+            (
+                "i32",
+                Arc::new(Int32Array::from(vec![Some(i32::MIN), Some(i32::MAX), None])),
+            ),
+            (
+                "i16",
+                Arc::new(Int16Array::from(vec![Some(i16::MIN), Some(i16::MAX), None])),
+            ),
+            (
+                "i8",
+                Arc::new(Int8Array::from(vec![Some(i8::MIN), Some(i8::MAX), None])),
+            ),
+            (
+                "f32",
+                Arc::new(Float32Array::from(vec![
+                    Some(f32_subnormal),
+                    Some(f32::MAX),
+                    None,
+                ])),
+            ),
+            (
+                "f64",
+                Arc::new(Float64Array::from(vec![
+                    Some(f64::MIN_POSITIVE),
+                    Some(f64::MIN),
+                    None,
+                ])),
+            ),
+        ];
+        let schema = Schema::new(
+            columns
+                .iter()
+                .map(|(name, array)| Field::new(*name, array.data_type().clone(), true))
+                .collect::<Vec<_>>(),
+        );
+        let arrow_batch = RecordBatch::try_new(
+            Arc::new(schema),
+            columns.into_iter().map(|(_, array)| array).collect(),
+        )
+        .unwrap();
+        let rows = RowBuilder::with_arrow_record_batch(arrow_batch)
+            .unwrap()
+            .build();
+
+        let expected_rows = vec![
+            vec![
+                Value::UInt64(0),
+                Value::UInt32(0),
+                Value::UInt16(0),
+                Value::UInt8(0),
+                Value::Int64(i64::MIN),
+                Value::Int32(i32::MIN),
+                Value::Int16(i16::MIN),
+                Value::Int8(i8::MIN),
+                Value::Float(f32_subnormal),
+                Value::Double(f64::MIN_POSITIVE),
+            ],
+            vec![
+                Value::UInt64(u64::MAX),
+                Value::UInt32(u32::MAX),
+                Value::UInt16(u16::MAX),
+                Value::UInt8(u8::MAX),
+                Value::Int64(i64::MAX),
+                Value::Int32(i32::MAX),
+                Value::Int16(i16::MAX),
+                Value::Int8(i8::MAX),
+                Value::Float(f32::MAX),
+                Value::Double(f64::MIN),
+            ],
+            vec![Value::Null; 10],
+        ];
+        for (row, expected) in rows.iter().zip(expected_rows) {
+            let values = row
+                .columns()
+                .iter()
+                .map(|column| column.value().clone())
+                .collect::<Vec<_>>();
+            assert_eq!(values, expected);
+
+            // The decoded values are written back unchanged.
+            for value in values {
+                assert_eq!(Value::from(ValuePb::from(value.clone())), value);
+            }
+        }
+
+        // Typed accessors.
+        assert_eq!(rows[1].get::<u64>("u64").unwrap(), Some(u64::MAX));
+        assert_eq!(rows[0].get::<i8>("i8").unwrap(), Some(i8::MIN));
+        assert_eq!(rows[0].get::<i64>("i8").unwrap(), Some(i8::MIN as i64));
+        assert_eq!(rows[0].get::<f32>("f32").unwrap(), Some(f32_subnormal));
+        assert_eq!(
+            rows[0].get::<f64>("f32").unwrap(),
+            Some(f32_subnormal as f64)
+        );
+        assert_eq!(rows[2].get::<u8>("u8").unwrap(), None);
+        assert!(rows[1].get::<u32>("u64").is_err());
+        assert!(rows[0].get::<u64>("i64").is_err());
+        assert!(rows[1].get::<f32>("f64").is_err());
+        assert!(rows[0].get::<f32>("f64").is_ok());
+        assert!(rows[0].get::<i32>("f32").is_err());
+        assert!(rows[0].get::<i32>("not_exist").is_err());
+    }
 }
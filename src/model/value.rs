@@ -1,9 +1,11 @@
 // Copyright 2022 CeresDB Project Authors. Licensed under Apache-2.0.
 
-use std::any::Any;
+use std::{any::Any, convert::TryFrom, str::FromStr};
 
 use ceresdbproto::storage::{value, Value as ValuePb};
 
+use crate::{Error, Result};
+
 pub type TimestampMs = i64;
 
 /// The value enum to express the data in CeresDB.
@@ -229,6 +231,170 @@ impl Default for Value {
     }
 }
 
+macro_rules! impl_from_primitive {
+    ($($ty:ty => $variant:ident),* $(,)?) => {
+        $(
+            impl From<$ty> for Value {
+                fn from(v: $ty) -> Self {
+                    Value::$variant(v)
+                }
+            }
+        )*
+    };
+}
+
+// The integers are converted to the variants of the same width, so the
+// timestamps in milliseconds should be given by [`Value::Timestamp`]
+// explicitly.
+impl_from_primitive!(
+    f64 => Double,
+    f32 => Float,
+    Vec<u8> => Varbinary,
+    String => String,
+    u64 => UInt64,
+    u32 => UInt32,
+    u16 => UInt16,
+    u8 => UInt8,
+    i64 => Int64,
+    i32 => Int32,
+    i16 => Int16,
+    i8 => Int8,
+    bool => Boolean,
+);
+
+impl From<&str> for Value {
+    fn from(v: &str) -> Self {
+        Value::String(v.to_string())
+    }
+}
+
+impl From<&[u8]> for Value {
+    fn from(v: &[u8]) -> Self {
+        Value::Varbinary(v.to_vec())
+    }
+}
+
+macro_rules! impl_try_from_value_for_int {
+    ($($ty:ty),* $(,)?) => {
+        $(
+            /// Only the integers are converted, and it fails if the value is out of
+            /// the range of the target type rather than wrapping around.
+            impl TryFrom<&Value> for $ty {
+                type Error = Error;
+
+                fn try_from(value: &Value) -> Result<Self> {
+                    let converted = match value {
+                        Value::UInt64(v) => <$ty>::try_from(*v).ok(),
+                        Value::UInt32(v) => <$ty>::try_from(*v).ok(),
+                        Value::UInt16(v) => <$ty>::try_from(*v).ok(),
+                        Value::UInt8(v) => <$ty>::try_from(*v).ok(),
+                        Value::Int64(v) => <$ty>::try_from(*v).ok(),
+                        Value::Int32(v) => <$ty>::try_from(*v).ok(),
+                        Value::Int16(v) => <$ty>::try_from(*v).ok(),
+                        Value::Int8(v) => <$ty>::try_from(*v).ok(),
+                        _ => return Err(mismatched_type(value, stringify!($ty))),
+                    };
+
+                    converted.ok_or_else(|| {
+                        Error::Client(format!(
+                            "value is out of range, value:{value:?}, target:{}",
+                            stringify!($ty)
+                        ))
+                    })
+                }
+            }
+
+            impl TryFrom<Value> for $ty {
+                type Error = Error;
+
+                fn try_from(value: Value) -> Result<Self> {
+                    <$ty>::try_from(&value)
+                }
+            }
+        )*
+    };
+}
+
+impl_try_from_value_for_int!(u64, u32, u16, u8, i64, i32, i16, i8);
+
+/// The [`Value::Double`] is converted only if it is in the range of `f32`, and
+/// the precision may be lost.
+impl TryFrom<&Value> for f32 {
+    type Error = Error;
+
+    fn try_from(value: &Value) -> Result<Self> {
+        match value {
+            Value::Float(v) => Ok(*v),
+            Value::Double(v) if !v.is_finite() || v.abs() <= f32::MAX as f64 => Ok(*v as f32),
+            Value::Double(_) => Err(Error::Client(format!(
+                "value is out of range, value:{value:?}, target:f32"
+            ))),
+            _ => Err(mismatched_type(value, "f32")),
+        }
+    }
+}
+
+impl TryFrom<Value> for f32 {
+    type Error = Error;
+
+    fn try_from(value: Value) -> Result<Self> {
+        f32::try_from(&value)
+    }
+}
+
+impl TryFrom<&Value> for f64 {
+    type Error = Error;
+
+    fn try_from(value: &Value) -> Result<Self> {
+        match value {
+            Value::Double(v) => Ok(*v),
+            Value::Float(v) => Ok(*v as f64),
+            _ => Err(mismatched_type(value, "f64")),
+        }
+    }
+}
+
+impl TryFrom<Value> for f64 {
+    type Error = Error;
+
+    fn try_from(value: Value) -> Result<Self> {
+        f64::try_from(&value)
+    }
+}
+
+impl TryFrom<&Value> for bool {
+    type Error = Error;
+
+    fn try_from(value: &Value) -> Result<Self> {
+        match value {
+            Value::Boolean(v) => Ok(*v),
+            _ => Err(mismatched_type(value, "bool")),
+        }
+    }
+}
+
+impl TryFrom<Value> for bool {
+    type Error = Error;
+
+    fn try_from(value: Value) -> Result<Self> {
+        bool::try_from(&value)
+    }
+}
+
+fn mismatched_type(value: &Value, target: &str) -> Error {
+    Error::Client(format!(
+        "value can't be converted, data_type:{:?}, target:{target}",
+        value.data_type()
+    ))
+}
+
+/// The none is converted to [`Value::Null`].
+impl<T: Into<Value>> From<Option<T>> for Value {
+    fn from(v: Option<T>) -> Self {
+        v.map_or(Value::Null, Into::into)
+    }
+}
+
 impl From<Value> for ValuePb {
     fn from(val: Value) -> Self {
         let value = match val {
@@ -255,17 +421,18 @@ impl From<Value> for ValuePb {
 
 impl From<ValuePb> for Value {
     fn from(value_pb: ValuePb) -> Self {
-        if value_pb.value.is_none() {
-            return Value::Null;
-        }
-
-        let value = value_pb.value.unwrap();
+        let value = match value_pb.value {
+            Some(value) => value,
+            None => return Value::Null,
+        };
         match value {
             value::Value::Float64Value(v) => Value::Double(v),
             value::Value::StringValue(v) => Value::String(v),
             value::Value::Int64Value(v) => Value::Int64(v),
             value::Value::Float32Value(v) => Value::Float(v),
             value::Value::Int32Value(v) => Value::Int32(v),
+            // The narrow integers are carried by the wider ones in the proto, and
+            // the valid ones are always in range.
             value::Value::Int16Value(v) => Value::Int16(v as i16),
             value::Value::Int8Value(v) => Value::Int8(v as i8),
             value::Value::BoolValue(v) => Value::Boolean(v),
@@ -298,3 +465,234 @@ pub enum DataType {
     Int8,
     Boolean,
 }
+
+impl DataType {
+    /// The name of the type used by the server, e.g. in the `CREATE TABLE`
+    /// statements and the results of `DESCRIBE`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            DataType::Null => "null",
+            DataType::Timestamp => "timestamp",
+            DataType::Double => "double",
+            DataType::Float => "float",
+            DataType::Varbinary => "varbinary",
+            DataType::String => "string",
+            DataType::UInt64 => "uint64",
+            DataType::UInt32 => "uint32",
+            DataType::UInt16 => "uint16",
+            DataType::UInt8 => "uint8",
+            DataType::Int64 => "int64",
+            DataType::Int32 => "int32",
+            DataType::Int16 => "int16",
+            DataType::Int8 => "int8",
+            DataType::Boolean => "boolean",
+        }
+    }
+}
+
+/// Parse the type name returned by the server, e.g. the `type` column of the
+/// results of `DESCRIBE`, and the name is case insensitive.
+impl FromStr for DataType {
+    type Err = Error;
+
+    fn from_str(name: &str) -> Result<Self> {
+        let data_type = match name.trim().to_ascii_lowercase().as_str() {
+            "null" => DataType::Null,
+            "timestamp" => DataType::Timestamp,
+            "double" | "float64" => DataType::Double,
+            "float" | "float32" => DataType::Float,
+            "varbinary" => DataType::Varbinary,
+            "string" | "varchar" => DataType::String,
+            "uint64" => DataType::UInt64,
+            "uint32" => DataType::UInt32,
+            "uint16" => DataType::UInt16,
+            "uint8" => DataType::UInt8,
+            "int64" | "bigint" => DataType::Int64,
+            "int32" | "int" => DataType::Int32,
+            "int16" | "smallint" => DataType::Int16,
+            "int8" | "tinyint" => DataType::Int8,
+            "boolean" | "bool" => DataType::Boolean,
+            _ => return Err(Error::Client(format!("unknown data type, name:{name}"))),
+        };
+
+        Ok(data_type)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::convert::TryFrom;
+
+    use ceresdbproto::storage::{value, Value as ValuePb};
+
+    use super::{DataType, Value};
+
+    #[test]
+    fn test_from_rust_types() {
+        let cases: Vec<(Value, Value, Option<value::Value>)> = vec![
+            (
+                42i64.into(),
+                Value::Int64(42),
+                Some(value::Value::Int64Value(42)),
+            ),
+            (
+                23.5.into(),
+                Value::Double(23.5),
+                Some(value::Value::Float64Value(23.5)),
+            ),
+            (
+                "sensor".into(),
+                Value::String("sensor".to_string()),
+                Some(value::Value::StringValue("sensor".to_string())),
+            ),
+            (
+                "sensor".to_string().into(),
+                Value::String("sensor".to_string()),
+                Some(value::Value::StringValue("sensor".to_string())),
+            ),
+            (
+                true.into(),
+                Value::Boolean(true),
+                Some(value::Value::BoolValue(true)),
+            ),
+            (
+                Value::Timestamp(1_700_000_000_000),
+                Value::Timestamp(1_700_000_000_000),
+                Some(value::Value::TimestampValue(1_700_000_000_000)),
+            ),
+            (
+                b"bytes".as_slice().into(),
+                Value::Varbinary(b"bytes".to_vec()),
+                Some(value::Value::VarbinaryValue(b"bytes".to_vec())),
+            ),
+            (
+                vec![1u8, 2].into(),
+                Value::Varbinary(vec![1, 2]),
+                Some(value::Value::VarbinaryValue(vec![1, 2])),
+            ),
+            (
+                7u8.into(),
+                Value::UInt8(7),
+                Some(value::Value::Uint8Value(7)),
+            ),
+            (
+                7i16.into(),
+                Value::Int16(7),
+                Some(value::Value::Int16Value(7)),
+            ),
+            (
+                0.5f32.into(),
+                Value::Float(0.5),
+                Some(value::Value::Float32Value(0.5)),
+            ),
+            (
+                Some(42i32).into(),
+                Value::Int32(42),
+                Some(value::Value::Int32Value(42)),
+            ),
+            (None::<i32>.into(), Value::Null, None),
+        ];
+
+        for (value, expected, expected_pb) in cases {
+            assert_eq!(value, expected);
+            let value_pb = ValuePb::from(value);
+            assert_eq!(value_pb.value, expected_pb);
+            assert_eq!(Value::from(value_pb), expected);
+        }
+    }
+
+    #[test]
+    fn test_try_from_value() {
+        assert_eq!(u64::try_from(Value::UInt64(u64::MAX)).unwrap(), u64::MAX);
+        assert_eq!(
+            i64::try_from(Value::UInt32(u32::MAX)).unwrap(),
+            u32::MAX as i64
+        );
+        assert_eq!(u8::try_from(Value::Int64(255)).unwrap(), 255);
+        assert_eq!(i8::try_from(Value::Int16(-128)).unwrap(), i8::MIN);
+        assert_eq!(i16::try_from(&Value::UInt8(u8::MAX)).unwrap(), 255);
+        assert_eq!(f64::try_from(Value::Float(0.5)).unwrap(), 0.5);
+        assert_eq!(f32::try_from(Value::Double(0.5)).unwrap(), 0.5);
+        assert!(f32::try_from(Value::Double(f64::NAN)).unwrap().is_nan());
+        assert!(bool::try_from(Value::Boolean(true)).unwrap());
+
+        // Out of range.
+        assert!(u64::try_from(Value::Int64(-1)).is_err());
+        assert!(u8::try_from(Value::Int8(-1)).is_err());
+        assert!(i8::try_from(Value::UInt8(128)).is_err());
+        assert!(i64::try_from(Value::UInt64(u64::MAX)).is_err());
+        assert!(u32::try_from(Value::UInt64(u32::MAX as u64 + 1)).is_err());
+        assert!(f32::try_from(Value::Double(f64::MAX)).is_err());
+
+        // Mismatched types.
+        assert!(i32::try_from(Value::Float(1.0)).is_err());
+        assert!(i32::try_from(Value::Boolean(true)).is_err());
+        assert!(f64::try_from(Value::Int64(1)).is_err());
+        assert!(bool::try_from(Value::Int8(1)).is_err());
+        assert!(i64::try_from(Value::Null).is_err());
+    }
+
+    #[test]
+    fn test_boundary_values_encoding() {
+        let values = vec![
+            Value::UInt64(u64::MAX),
+            Value::UInt32(u32::MAX),
+            Value::UInt16(u16::MAX),
+            Value::UInt8(u8::MAX),
+            Value::Int64(i64::MIN),
+            Value::Int32(i32::MIN),
+            Value::Int16(i16::MIN),
+            Value::Int8(i8::MIN),
+            Value::Int8(i8::MAX),
+            Value::Float(f32::from_bits(1)),
+            Value::Float(f32::MAX),
+            Value::Float(f32::MIN),
+            Value::Double(f64::MIN_POSITIVE),
+        ];
+        for value in values {
+            let value_pb = ValuePb::from(value.clone());
+            assert_eq!(Value::from(value_pb), value);
+        }
+
+        // The narrow ones are carried by the wider proto types without wrapping.
+        assert_eq!(
+            ValuePb::from(Value::Int8(i8::MIN)).value,
+            Some(value::Value::Int8Value(-128))
+        );
+        assert_eq!(
+            ValuePb::from(Value::UInt16(u16::MAX)).value,
+            Some(value::Value::Uint16Value(65535))
+        );
+    }
+
+    #[test]
+    fn test_parse_data_type() {
+        let data_types = [
+            DataType::Null,
+            DataType::Timestamp,
+            DataType::Double,
+            DataType::Float,
+            DataType::Varbinary,
+            DataType::String,
+            DataType::UInt64,
+            DataType::UInt32,
+            DataType::UInt16,
+            DataType::UInt8,
+            DataType::Int64,
+            DataType::Int32,
+            DataType::Int16,
+            DataType::Int8,
+            DataType::Boolean,
+        ];
+        for data_type in data_types {
+            assert_eq!(data_type.name().parse::<DataType>().unwrap(), data_type);
+            assert_eq!(
+                data_type.name().to_uppercase().parse::<DataType>().unwrap(),
+                data_type
+            );
+        }
+        assert_eq!("bigint".parse::<DataType>().unwrap(), DataType::Int64);
+        assert_eq!(" bool ".parse::<DataType>().unwrap(), DataType::Boolean);
+        assert!("decimal".parse::<DataType>().is_err());
+    }
+}
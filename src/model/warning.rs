@@ -0,0 +1,112 @@
+// Copyright 2022 CeresDB Project Authors. Licensed under Apache-2.0.
+
+//! Warnings reported by the server
+
+use std::{fmt, sync::Arc};
+
+use crate::db_client::Operation;
+
+/// Non-fatal notice reported by the server along with a successful response,
+/// e.g. the deprecated syntax, or the partial result for the missing
+/// partitions.
+///
+/// The warnings are carried in the repeated `x-ceresdb-warning-bin` metadata
+/// of the response, because there is no such field in the response header,
+/// and every value is `{code}|{table}|{message}` with the empty table if the
+/// warning is not about a table.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ServerWarning {
+    pub code: u32,
+    pub message: String,
+    /// The table the warning is about.
+    pub table: Option<String>,
+    /// The endpoint reporting the warning, which tells the partitions of a
+    /// write apart in the cluster mode.
+    pub endpoint: Option<String>,
+}
+
+impl ServerWarning {
+    /// Code of the warning that the result is partial, e.g. some partitions
+    /// of the table are missing.
+    pub const PARTIAL_RESULT: u32 = 1001;
+
+    /// Whether the warning tells the result is partial.
+    #[inline]
+    pub fn is_partial(&self) -> bool {
+        self.code == Self::PARTIAL_RESULT
+    }
+
+    /// Decode the warning from the metadata value, and none if it is
+    /// malformed.
+    pub(crate) fn decode(value: &[u8]) -> Option<Self> {
+        let value = std::str::from_utf8(value).ok()?;
+        let mut parts = value.splitn(3, '|');
+        let code = parts.next()?.parse().ok()?;
+        let table = parts.next()?;
+        let message = parts.next()?;
+
+        Some(Self {
+            code,
+            message: message.to_string(),
+            table: (!table.is_empty()).then(|| table.to_string()),
+            endpoint: None,
+        })
+    }
+
+    /// Encode the warning as the metadata value, and the endpoint is not
+    /// encoded.
+    #[cfg(test)]
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let table = self.table.as_deref().unwrap_or_default();
+        format!("{}|{table}|{}", self.code, self.message).into_bytes()
+    }
+}
+
+/// Hook called on every warning reported by the server, along with the
+/// operation of the request.
+#[derive(Clone)]
+pub(crate) struct WarningHook(Arc<dyn Fn(Operation, ServerWarning) + Send + Sync>);
+
+impl WarningHook {
+    pub fn new(hook: impl Fn(Operation, ServerWarning) + Send + Sync + 'static) -> Self {
+        Self(Arc::new(hook))
+    }
+
+    pub fn call(&self, operation: Operation, warning: ServerWarning) {
+        (self.0)(operation, warning)
+    }
+}
+
+impl fmt::Debug for WarningHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("WarningHook")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::ServerWarning;
+
+    #[test]
+    fn test_encode_decode() {
+        let warning = ServerWarning {
+            code: ServerWarning::PARTIAL_RESULT,
+            message: "partition 3 is missing | skipped".to_string(),
+            table: Some("table1".to_string()),
+            endpoint: None,
+        };
+        assert_eq!(ServerWarning::decode(&warning.encode()), Some(warning));
+        assert_eq!(
+            ServerWarning::decode(b"7||syntax is deprecated"),
+            Some(ServerWarning {
+                code: 7,
+                message: "syntax is deprecated".to_string(),
+                table: None,
+                endpoint: None,
+            })
+        );
+
+        assert_eq!(ServerWarning::decode(b"not a code||msg"), None);
+        assert_eq!(ServerWarning::decode(b"7|table1"), None);
+    }
+}
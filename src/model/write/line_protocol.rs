@@ -0,0 +1,531 @@
+// Copyright 2022 CeresDB Project Authors. Licensed under Apache-2.0.
+
+//! Line protocol of the write request
+
+use std::{
+    collections::BTreeMap,
+    fmt::{Display, Write},
+    iter::Peekable,
+    str::{Chars, FromStr},
+};
+
+use crate::{
+    model::{
+        value::Value,
+        write::{point::Point, Request},
+    },
+    Error, Result,
+};
+
+impl Request {
+    /// Encode the points to the line protocol, which is a text format like the
+    /// InfluxDB line protocol, one point per line:
+    ///
+    /// ```text
+    /// <table>[,<tag>=<value>...] <field>=<value>[,<field>=<value>...] [<timestamp>]
+    /// ```
+    ///
+    /// - The table, tag and field names escape the `\`, `,`, `=`, space and
+    ///   line breaks by `\`, and the table escapes its leading `#` too.
+    /// - The strings are quoted by `"`, and escape the `\`, `"` and line breaks
+    ///   by `\`.
+    /// - The integers are suffixed by their types, i.e. `i` for `Int64`, `u`
+    ///   for `UInt64`, `i32`, `u32`, `i16`, `u16`, `i8` and `u8`, and the
+    ///   timestamp values by `t`.
+    /// - The doubles are not suffixed, and the floats are suffixed by `f32`.
+    /// - The booleans are `true` or `false`, the nulls are `null`, and the
+    ///   varbinaries are hex encoded with the prefix `0x`.
+    /// - The timestamp of the point is in milliseconds, and omitted if unset.
+    ///
+    /// The tables are sorted by name, and only the points are encoded while the
+    /// options of the request are not. The points without fields can't be
+    /// parsed back.
+    pub fn to_line_protocol(&self) -> String {
+        let mut tables: Vec<_> = self.point_groups.iter().collect();
+        tables.sort_by_key(|(table, _)| table.as_str());
+
+        let mut text = String::new();
+        for (table, points) in tables {
+            for point in points {
+                write_point(&mut text, table, point);
+                text.push('\n');
+            }
+        }
+
+        text
+    }
+
+    /// Parse the points from the line protocol encoded by
+    /// [`to_line_protocol`](Self::to_line_protocol) into a request with the
+    /// default options, and the error reports the line of the malformed
+    /// point.
+    ///
+    /// The empty lines and the lines starting with `#` are ignored.
+    pub fn from_line_protocol(text: &str) -> Result<Request> {
+        let mut req = Request::default();
+        for (index, line) in text.lines().enumerate() {
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let point = LineParser::new(line).parse_point().map_err(|reason| {
+                Error::InvalidLineProtocol {
+                    line: index + 1,
+                    reason,
+                }
+            })?;
+            req.add_point(point);
+        }
+
+        Ok(req)
+    }
+}
+
+fn write_point(text: &mut String, table: &str, point: &Point) {
+    // Otherwise the line is taken as a comment.
+    if table.starts_with('#') {
+        text.push('\\');
+    }
+    write_name(text, table);
+    for (name, value) in &point.tags {
+        text.push(',');
+        write_name(text, name);
+        text.push('=');
+        write_value(text, value);
+    }
+
+    text.push(' ');
+    for (index, (name, value)) in point.fields.iter().enumerate() {
+        if index > 0 {
+            text.push(',');
+        }
+        write_name(text, name);
+        text.push('=');
+        write_value(text, value);
+    }
+
+    if let Some(timestamp) = point.timestamp {
+        let _ = write!(text, " {timestamp}");
+    }
+}
+
+fn write_name(text: &mut String, name: &str) {
+    for c in name.chars() {
+        match c {
+            '\\' | ',' | '=' | ' ' => {
+                text.push('\\');
+                text.push(c);
+            }
+            '\n' => text.push_str("\\n"),
+            '\r' => text.push_str("\\r"),
+            _ => text.push(c),
+        }
+    }
+}
+
+fn write_value(text: &mut String, value: &Value) {
+    // Writing to a string never fails.
+    let _ = match value {
+        Value::Null => write!(text, "null"),
+        Value::Timestamp(v) => write!(text, "{v}t"),
+        Value::Double(v) => write!(text, "{v:?}"),
+        Value::Float(v) => write!(text, "{v:?}f32"),
+        Value::Varbinary(v) => {
+            text.push_str("0x");
+            v.iter().try_for_each(|b| write!(text, "{b:02x}"))
+        }
+        Value::String(v) => {
+            text.push('"');
+            for c in v.chars() {
+                match c {
+                    '\\' | '"' => {
+                        text.push('\\');
+                        text.push(c);
+                    }
+                    '\n' => text.push_str("\\n"),
+                    '\r' => text.push_str("\\r"),
+                    _ => text.push(c),
+                }
+            }
+            text.push('"');
+            Ok(())
+        }
+        Value::UInt64(v) => write!(text, "{v}u"),
+        Value::UInt32(v) => write!(text, "{v}u32"),
+        Value::UInt16(v) => write!(text, "{v}u16"),
+        Value::UInt8(v) => write!(text, "{v}u8"),
+        Value::Int64(v) => write!(text, "{v}i"),
+        Value::Int32(v) => write!(text, "{v}i32"),
+        Value::Int16(v) => write!(text, "{v}i16"),
+        Value::Int8(v) => write!(text, "{v}i8"),
+        Value::Boolean(v) => write!(text, "{v}"),
+    };
+}
+
+/// Parser of one line, and the errors are the reasons only, without the line.
+struct LineParser<'a> {
+    chars: Peekable<Chars<'a>>,
+}
+
+impl<'a> LineParser<'a> {
+    fn new(line: &'a str) -> Self {
+        Self {
+            chars: line.chars().peekable(),
+        }
+    }
+
+    fn parse_point(mut self) -> std::result::Result<Point, String> {
+        let table = self.parse_name()?;
+        if table.is_empty() {
+            return Err("table name is empty".to_string());
+        }
+
+        let mut tags = BTreeMap::new();
+        while self.chars.next_if_eq(&',').is_some() {
+            let (name, value) = self.parse_column()?;
+            if tags.insert(name, value).is_some() {
+                return Err("tag is duplicated".to_string());
+            }
+        }
+
+        if self.chars.next_if_eq(&' ').is_none() {
+            return Err("fields are missing".to_string());
+        }
+        let mut fields = BTreeMap::new();
+        loop {
+            let (name, value) = self.parse_column()?;
+            if fields.insert(name, value).is_some() {
+                return Err("field is duplicated".to_string());
+            }
+            if self.chars.next_if_eq(&',').is_none() {
+                break;
+            }
+        }
+
+        let timestamp = match self.chars.next_if_eq(&' ') {
+            Some(_) => Some(parse_number(&self.parse_token())?),
+            None => None,
+        };
+        if let Some(c) = self.chars.next() {
+            return Err(format!("unexpected character:{c:?} after the point"));
+        }
+
+        Ok(Point {
+            table,
+            timestamp,
+            tags,
+            fields,
+        })
+    }
+
+    /// Parse the name and the value of a tag or field.
+    fn parse_column(&mut self) -> std::result::Result<(String, Value), String> {
+        let name = self.parse_name()?;
+        if name.is_empty() {
+            return Err("column name is empty".to_string());
+        }
+        if self.chars.next_if_eq(&'=').is_none() {
+            return Err(format!("value of column:{name} is missing"));
+        }
+
+        let value = if self.chars.next_if_eq(&'"').is_some() {
+            self.parse_string()
+                .map_err(|e| format!("invalid value of column:{name}, err:{e}"))?
+        } else {
+            parse_scalar(&self.parse_token())
+                .map_err(|e| format!("invalid value of column:{name}, err:{e}"))?
+        };
+
+        Ok((name, value))
+    }
+
+    /// Parse the escaped name until the unescaped `,`, `=` or space.
+    fn parse_name(&mut self) -> std::result::Result<String, String> {
+        let mut name = String::new();
+        while let Some(c) = self.chars.next_if(|c| !matches!(c, ',' | '=' | ' ')) {
+            match c {
+                '\\' => name.push(self.parse_escape()?),
+                _ => name.push(c),
+            }
+        }
+
+        Ok(name)
+    }
+
+    /// Parse the rest of the string after the opening quote.
+    fn parse_string(&mut self) -> std::result::Result<Value, String> {
+        let mut s = String::new();
+        loop {
+            match self.chars.next() {
+                Some('"') => return Ok(Value::String(s)),
+                Some('\\') => s.push(self.parse_escape()?),
+                Some(c) => s.push(c),
+                None => return Err("string is not closed".to_string()),
+            }
+        }
+    }
+
+    /// Parse the character after the `\`.
+    fn parse_escape(&mut self) -> std::result::Result<char, String> {
+        match self.chars.next() {
+            Some('n') => Ok('\n'),
+            Some('r') => Ok('\r'),
+            Some(c @ ('\\' | ',' | '=' | ' ' | '"' | '#')) => Ok(c),
+            Some(c) => Err(format!("invalid escape:\\{c}")),
+            None => Err("escape is not completed".to_string()),
+        }
+    }
+
+    /// Parse the unescaped token until the `,` or space.
+    fn parse_token(&mut self) -> String {
+        let mut token = String::new();
+        while let Some(c) = self.chars.next_if(|c| !matches!(c, ',' | ' ')) {
+            token.push(c);
+        }
+
+        token
+    }
+}
+
+fn parse_scalar(token: &str) -> std::result::Result<Value, String> {
+    match token {
+        "" => return Err("value is empty".to_string()),
+        "null" => return Ok(Value::Null),
+        "true" => return Ok(Value::Boolean(true)),
+        "false" => return Ok(Value::Boolean(false)),
+        _ => (),
+    }
+
+    if let Some(hex) = token.strip_prefix("0x") {
+        return parse_hex(hex).map(Value::Varbinary);
+    }
+
+    // The longer suffixes go first, e.g. `i32` before `i`.
+    let value = if let Some(v) = token.strip_suffix("i32") {
+        Value::Int32(parse_number(v)?)
+    } else if let Some(v) = token.strip_suffix("i16") {
+        Value::Int16(parse_number(v)?)
+    } else if let Some(v) = token.strip_suffix("i8") {
+        Value::Int8(parse_number(v)?)
+    } else if let Some(v) = token.strip_suffix('i') {
+        Value::Int64(parse_number(v)?)
+    } else if let Some(v) = token.strip_suffix("u32") {
+        Value::UInt32(parse_number(v)?)
+    } else if let Some(v) = token.strip_suffix("u16") {
+        Value::UInt16(parse_number(v)?)
+    } else if let Some(v) = token.strip_suffix("u8") {
+        Value::UInt8(parse_number(v)?)
+    } else if let Some(v) = token.strip_suffix('u') {
+        Value::UInt64(parse_number(v)?)
+    } else if let Some(v) = token.strip_suffix("f32") {
+        Value::Float(parse_number(v)?)
+    } else if let Some(v) = token.strip_suffix('t') {
+        Value::Timestamp(parse_number(v)?)
+    } else {
+        Value::Double(parse_number(token)?)
+    };
+
+    Ok(value)
+}
+
+fn parse_number<T>(token: &str) -> std::result::Result<T, String>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    token
+        .parse()
+        .map_err(|e| format!("invalid number:{token}, err:{e}"))
+}
+
+fn parse_hex(hex: &str) -> std::result::Result<Vec<u8>, String> {
+    hex.as_bytes()
+        .chunks(2)
+        .map(|digits| {
+            std::str::from_utf8(digits)
+                .ok()
+                .filter(|digits| digits.len() == 2)
+                .and_then(|digits| u8::from_str_radix(digits, 16).ok())
+                .ok_or_else(|| format!("invalid hex:{hex}"))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+
+    use crate::{
+        model::{
+            value::Value,
+            write::{point::Point, Request},
+        },
+        Error,
+    };
+
+    fn point(
+        table: &str,
+        timestamp: Option<i64>,
+        tags: Vec<(&str, Value)>,
+        fields: Vec<(&str, Value)>,
+    ) -> Point {
+        let columns = |columns: Vec<(&str, Value)>| -> BTreeMap<_, _> {
+            columns
+                .into_iter()
+                .map(|(name, value)| (name.to_string(), value))
+                .collect()
+        };
+        Point {
+            table: table.to_string(),
+            timestamp,
+            tags: columns(tags),
+            fields: columns(fields),
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let points = vec![
+            point(
+                "cpu",
+                Some(1_700_000_000_000),
+                vec![("host", Value::String("host1".to_string()))],
+                vec![("usage", Value::Double(0.5))],
+            ),
+            point(
+                "all types",
+                Some(-1),
+                vec![
+                    ("region", Value::String("us west, \"1\"=\\".to_string())),
+                    ("id", Value::UInt32(7)),
+                ],
+                vec![
+                    ("null", Value::Null),
+                    ("timestamp value", Value::Timestamp(1_700_000_000_001)),
+                    ("double", Value::Double(-1.5e-300)),
+                    ("integral double", Value::Double(3.0)),
+                    ("infinite double", Value::Double(f64::NEG_INFINITY)),
+                    ("float", Value::Float(0.1)),
+                    ("varbinary", Value::Varbinary(vec![0, 1, 0xab, 0xff])),
+                    ("empty varbinary", Value::Varbinary(Vec::new())),
+                    (
+                        "string",
+                        Value::String("line1\nline2\r\t\"quoted\"".to_string()),
+                    ),
+                    ("empty string", Value::String(String::new())),
+                    ("u64", Value::UInt64(u64::MAX)),
+                    ("u32", Value::UInt32(u32::MAX)),
+                    ("u16", Value::UInt16(u16::MAX)),
+                    ("u8", Value::UInt8(u8::MAX)),
+                    ("i64", Value::Int64(i64::MIN)),
+                    ("i32", Value::Int32(i32::MIN)),
+                    ("i16", Value::Int16(i16::MIN)),
+                    ("i8", Value::Int8(i8::MIN)),
+                    ("true", Value::Boolean(true)),
+                    ("false", Value::Boolean(false)),
+                ],
+            ),
+            point(
+                "all types",
+                None,
+                vec![],
+                vec![("unicode", Value::String("温度 🌡".to_string()))],
+            ),
+            point(
+                "#not a comment,a=b\\",
+                None,
+                vec![("tag,=\\ \n", Value::Boolean(false))],
+                vec![("field,=\\ \r", Value::Int8(0))],
+            ),
+        ];
+        let mut req = Request::default();
+        req.add_points(points);
+
+        let text = req.to_line_protocol();
+        assert_eq!(text.lines().count(), 4);
+        let parsed = Request::from_line_protocol(&text).unwrap();
+        assert_eq!(parsed.point_groups, req.point_groups);
+        assert_eq!(parsed.to_line_protocol(), text);
+    }
+
+    #[test]
+    fn test_encode() {
+        let mut req = Request::default();
+        req.add_point(point(
+            "b",
+            None,
+            vec![],
+            vec![("value", Value::Double(1.0))],
+        ));
+        req.add_point(point(
+            "a table",
+            Some(1_700_000_000_000),
+            vec![("host", Value::String("h \"1\"".to_string()))],
+            vec![
+                ("count", Value::Int64(3)),
+                ("bytes", Value::Varbinary(vec![0x0f, 0xa0])),
+            ],
+        ));
+
+        assert_eq!(
+            req.to_line_protocol(),
+            "a\\ table,host=\"h \\\"1\\\"\" bytes=0x0fa0,count=3i 1700000000000\n\
+             b value=1.0\n"
+        );
+    }
+
+    #[test]
+    fn test_decode_nan() {
+        let req = Request::from_line_protocol("t a=NaN,b=NaNf32").unwrap();
+        let fields = &req.point_groups["t"][0].fields;
+        assert!(matches!(fields["a"], Value::Double(v) if v.is_nan()));
+        assert!(matches!(fields["b"], Value::Float(v) if v.is_nan()));
+    }
+
+    #[test]
+    fn test_decode_comments() {
+        let text = "# comment\n\n  \nt v=1i 1\r\n# t v=2i 2\nt v=3i 3\n";
+        let req = Request::from_line_protocol(text).unwrap();
+        let timestamps: Vec<_> = req.point_groups["t"]
+            .iter()
+            .map(|point| point.timestamp)
+            .collect();
+        assert_eq!(timestamps, vec![Some(1), Some(3)]);
+    }
+
+    #[test]
+    fn test_decode_malformed() {
+        let cases = [
+            (" v=1", "table name is empty"),
+            ("t", "fields are missing"),
+            ("t ", "column name is empty"),
+            ("t,host v=1", "value of column:host is missing"),
+            ("t,=a v=1", "column name is empty"),
+            ("t,a=1i,a=2i v=1", "tag is duplicated"),
+            ("t v=1,v=2", "field is duplicated"),
+            ("t v=", "value is empty"),
+            ("t v=abc", "invalid number:abc"),
+            ("t v=1.5i", "invalid number:1.5"),
+            ("t v=256u8", "invalid number:256"),
+            ("t v=0xabc", "invalid hex:abc"),
+            ("t v=0xzz", "invalid hex:zz"),
+            ("t v=\"abc", "string is not closed"),
+            ("t v=\"a\\tb\"", "invalid escape:\\t"),
+            ("t\\", "escape is not completed"),
+            ("t v=1 1.5", "invalid number:1.5"),
+            ("t v=1 1 1", "unexpected character:' ' after the point"),
+            ("t v=\"a\"b", "unexpected character:'b' after the point"),
+        ];
+
+        for (case, expected) in cases {
+            let text = format!("t v=1\n# comment\n{case}\nt v=2");
+            match Request::from_line_protocol(&text) {
+                Err(Error::InvalidLineProtocol { line, reason }) => {
+                    assert_eq!(line, 3, "case:{case}");
+                    assert!(reason.contains(expected), "case:{case}, reason:{reason}");
+                }
+                other => panic!("case:{case}, unexpected result:{other:?}"),
+            }
+        }
+    }
+}
@@ -2,9 +2,17 @@
 
 //! Model for write
 
+mod line_protocol;
 pub mod point;
 mod request;
 mod response;
 
-pub use request::{pb_builder::WriteTableRequestPbsBuilder, Request};
-pub use response::Response;
+pub use request::{
+    pb_builder::WriteTableRequestPbsBuilder, FieldCompression, Normalization, Request,
+    ValidationMode,
+};
+pub(crate) use request::{
+    pb_builder::{points_from_pb, row_count, split_table_requests},
+    DroppedPoints,
+};
+pub use response::{Response, RetriedPartition};
@@ -4,7 +4,7 @@
 
 use std::collections::BTreeMap;
 
-use crate::model::value::Value;
+use crate::model::value::{TimestampMs, Value};
 
 const TSID: &str = "tsid";
 const TIMESTAMP: &str = "timestamp";
@@ -14,11 +14,42 @@ pub fn is_reserved_column_name(name: &str) -> bool {
     name.eq_ignore_ascii_case(TSID) || name.eq_ignore_ascii_case(TIMESTAMP)
 }
 
+/// Precision of the timestamps given to the [`PointBuilder`].
+///
+/// The timestamps are always written in milliseconds, so the ones in other
+/// precisions are converted, and the sub-millisecond part is truncated towards
+/// negative infinity.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TimestampPrecision {
+    Seconds,
+    /// The default precision.
+    #[default]
+    Milliseconds,
+    Microseconds,
+    Nanoseconds,
+}
+
+impl TimestampPrecision {
+    /// Convert the `timestamp` in this precision to milliseconds, and none if
+    /// it overflows.
+    pub fn to_millis(self, timestamp: i64) -> Option<TimestampMs> {
+        match self {
+            TimestampPrecision::Seconds => timestamp.checked_mul(1000),
+            TimestampPrecision::Milliseconds => Some(timestamp),
+            TimestampPrecision::Microseconds => Some(timestamp.div_euclid(1000)),
+            TimestampPrecision::Nanoseconds => Some(timestamp.div_euclid(1_000_000)),
+        }
+    }
+}
+
 /// One point in the [`WriteRequest`](crate::WriteRequest).
 #[derive(Clone, Debug, Default, PartialEq)]
 pub struct Point {
     pub table: String,
-    pub timestamp: i64,
+    /// The timestamp in milliseconds, and the point without timestamp is
+    /// handled according to the options of the
+    /// [`WriteRequest`](crate::WriteRequest).
+    pub timestamp: Option<TimestampMs>,
     pub tags: BTreeMap<String, Value>,
     pub fields: BTreeMap<String, Value>,
 }
@@ -27,7 +58,7 @@ pub struct Point {
 #[derive(Debug)]
 pub struct PointBuilder {
     table: String,
-    timestamp: Option<i64>,
+    timestamp: Option<(i64, TimestampPrecision)>,
     // tags' traversing should have definite order
     tags: BTreeMap<String, Value>,
     fields: BTreeMap<String, Value>,
@@ -51,9 +82,19 @@ impl PointBuilder {
         self
     }
 
-    /// Set the timestamp for the point.
-    pub fn timestamp(mut self, timestamp: i64) -> Self {
-        self.timestamp = Some(timestamp);
+    /// Set the timestamp in milliseconds for the point.
+    pub fn timestamp(self, timestamp: TimestampMs) -> Self {
+        self.timestamp_with_precision(timestamp, TimestampPrecision::Milliseconds)
+    }
+
+    /// Set the timestamp in the `precision` for the point, and it is converted
+    /// to milliseconds when building.
+    pub fn timestamp_with_precision(
+        mut self,
+        timestamp: i64,
+        precision: TimestampPrecision,
+    ) -> Self {
+        self.timestamp = Some((timestamp, precision));
         self
     }
 
@@ -61,26 +102,34 @@ impl PointBuilder {
     ///
     /// You cannot set tag with name like 'timestamp' or 'tsid',
     /// because they are keywords in ceresdb.
-    pub fn tag(mut self, name: String, value: Value) -> Self {
+    pub fn tag(mut self, name: impl Into<String>, value: impl Into<Value>) -> Self {
+        let name = name.into();
         if is_reserved_column_name(&name) {
             self.contains_reserved_column_name = true;
         }
 
-        let _ = self.tags.insert(name, value);
+        let _ = self.tags.insert(name, value.into());
         self
     }
 
     /// Set the name and value of a field specified by its `name`.
-    pub fn field(mut self, name: String, value: Value) -> Self {
+    ///
+    /// The value can be given as the Rust types convertible into [`Value`],
+    /// e.g. `field("temp", 23.5)` or `field("name", "sensor")`.
+    pub fn field(mut self, name: impl Into<String>, value: impl Into<Value>) -> Self {
+        let name = name.into();
         if is_reserved_column_name(&name) {
             self.contains_reserved_column_name = true;
         }
 
-        let _ = self.fields.insert(name, value);
+        let _ = self.fields.insert(name, value.into());
         self
     }
 
     /// Build the final point.
+    ///
+    /// The timestamp may be unset, and such point is handled according to the
+    /// options of the [`WriteRequest`](crate::WriteRequest) it is written by.
     pub fn build(self) -> Result<Point, String> {
         if self.contains_reserved_column_name {
             return Err("Tag or field name reserved column name in ceresdb".to_string());
@@ -90,9 +139,18 @@ impl PointBuilder {
             return Err("Fields should not be empty".to_string());
         }
 
-        let timestamp = self
-            .timestamp
-            .ok_or_else(|| "Timestamp must be set".to_string())?;
+        let timestamp = match self.timestamp {
+            Some((timestamp, precision)) => {
+                let millis = precision.to_millis(timestamp).ok_or_else(|| {
+                    format!(
+                        "Timestamp overflows in milliseconds, timestamp:{timestamp}, \
+                         precision:{precision:?}"
+                    )
+                })?;
+                Some(millis)
+            }
+            None => None,
+        };
 
         Ok(Point {
             table: self.table,
@@ -102,3 +160,70 @@ impl PointBuilder {
         })
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::{PointBuilder, TimestampPrecision};
+    use crate::model::value::Value;
+
+    fn build_timestamp(timestamp: i64, precision: TimestampPrecision) -> Result<i64, String> {
+        PointBuilder::new("test_table".to_string())
+            .timestamp_with_precision(timestamp, precision)
+            .field("value".to_string(), Value::Int64(42))
+            .build()
+            .map(|point| point.timestamp.unwrap())
+    }
+
+    #[test]
+    fn test_timestamp_precision() {
+        let millis = 1_700_000_000_123;
+        let cases = [
+            (
+                1_700_000_000,
+                TimestampPrecision::Seconds,
+                1_700_000_000_000,
+            ),
+            (millis, TimestampPrecision::Milliseconds, millis),
+            (
+                millis * 1000 + 999,
+                TimestampPrecision::Microseconds,
+                millis,
+            ),
+            (
+                millis * 1_000_000 + 1,
+                TimestampPrecision::Nanoseconds,
+                millis,
+            ),
+            (-1, TimestampPrecision::Microseconds, -1),
+        ];
+        for (timestamp, precision, expected) in cases {
+            assert_eq!(build_timestamp(timestamp, precision).unwrap(), expected);
+        }
+
+        assert!(build_timestamp(i64::MAX, TimestampPrecision::Seconds).is_err());
+    }
+
+    #[test]
+    fn test_rust_type_values() {
+        let point = PointBuilder::new("test_table".to_string())
+            .tag("name", "sensor")
+            .tag("id", 7i32)
+            .field("temp", 23.5)
+            .field("count", 42i64)
+            .field("ok", true)
+            .field("raw", vec![1u8, 2])
+            .field("missing", None::<f64>)
+            .field("at", Value::Timestamp(1_700_000_000_000))
+            .build()
+            .unwrap();
+
+        assert_eq!(point.tags["name"], Value::String("sensor".to_string()));
+        assert_eq!(point.tags["id"], Value::Int32(7));
+        assert_eq!(point.fields["temp"], Value::Double(23.5));
+        assert_eq!(point.fields["count"], Value::Int64(42));
+        assert_eq!(point.fields["ok"], Value::Boolean(true));
+        assert_eq!(point.fields["raw"], Value::Varbinary(vec![1, 2]));
+        assert_eq!(point.fields["missing"], Value::Null);
+        assert_eq!(point.fields["at"], Value::Timestamp(1_700_000_000_000));
+    }
+}
@@ -2,15 +2,355 @@
 
 //! Write request and some useful tools for it.
 
-use std::collections::HashMap;
+use std::{
+    borrow::Cow,
+    collections::{hash_map::DefaultHasher, HashMap, HashSet},
+    hash::{Hash, Hasher},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
-use crate::model::write::point::Point;
+use crate::{
+    model::{
+        value::{DataType, TimestampMs, Value},
+        write::{
+            point::{is_reserved_column_name, Point},
+            Response,
+        },
+    },
+    Error, Result,
+};
+
+/// The default min timestamp, below which the timestamp looks like in seconds
+/// rather than milliseconds, and it is 2001-09-09T01:46:40Z in milliseconds.
+const DEFAULT_MIN_TIMESTAMP: TimestampMs = 1_000_000_000_000;
+
+/// The default max length of the string values in bytes.
+const DEFAULT_MAX_VALUE_BYTES: usize = 1024 * 1024;
+
+/// How the points of a [`Request`] are validated before written, and the
+/// points are rejected for:
+/// - the empty table name;
+/// - the NaN or infinite float field values;
+/// - the string or varbinary values longer than
+///   [`max_value_bytes`](Request::max_value_bytes);
+/// - the string tag values containing NUL bytes.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ValidationMode {
+    /// No validation.
+    #[default]
+    Off,
+    /// Drop the invalid points and write the others, and the number of the
+    /// dropped points is reported in the
+    /// [`WriteResponse`](crate::model::write::Response). The dropped points
+    /// are logged if the `tracing` feature is enabled.
+    Warn,
+    /// Reject the whole request if any point is invalid, and nothing is
+    /// sent.
+    Strict,
+}
+
+/// How the points of a [`Request`] are normalized before written, which is
+/// done table by table:
+/// - the timestamps later than the current time by more than the
+///   [`max_future_skew`](Self::max_future_skew) are clamped to the current
+///   time;
+/// - the points are stable-sorted by their timestamps, where the points without
+///   timestamp are regarded as written at the current time;
+/// - the exact duplicates of the earlier points, i.e. with the same timestamp,
+///   tags and field values, are dropped, and the number of them is reported in
+///   the [`WriteResponse`](crate::model::write::Response).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Normalization {
+    /// The max skew of the timestamps in the future, beyond which they are
+    /// clamped to the current time, and none disables the clamp.
+    ///
+    /// It is not set by default.
+    pub max_future_skew: Option<Duration>,
+}
+
+impl Normalization {
+    /// Normalize the `kept` points of a table, which are the indexes into the
+    /// `points` with the timestamps to write, and return the number of the
+    /// duplicates dropped.
+    fn normalize(
+        &self,
+        points: &[Point],
+        kept: &mut Vec<(usize, Option<TimestampMs>)>,
+        default_timestamp: Option<TimestampMs>,
+        now: TimestampMs,
+    ) -> u32 {
+        if let Some(max_future_skew) = self.max_future_skew {
+            let max_timestamp = now.saturating_add(max_future_skew.as_millis() as TimestampMs);
+            for (_, timestamp) in kept.iter_mut() {
+                if matches!(timestamp.or(default_timestamp), Some(t) if t > max_timestamp) {
+                    *timestamp = Some(now);
+                }
+            }
+        }
+
+        let sort_key =
+            |timestamp: Option<TimestampMs>| timestamp.or(default_timestamp).unwrap_or(now);
+        kept.sort_by_key(|(_, timestamp)| sort_key(*timestamp));
+
+        // The kept points by their timestamps and the hashes of their columns.
+        let mut kept_by_key: HashMap<(TimestampMs, u64), Vec<usize>> = HashMap::new();
+        let len = kept.len();
+        kept.retain(|(index, timestamp)| {
+            let point = &points[*index];
+            let same_key = kept_by_key
+                .entry((sort_key(*timestamp), hash_columns(point)))
+                .or_default();
+            let duplicated = same_key.iter().any(|other| {
+                let other = &points[*other];
+                other.tags == point.tags && other.fields == point.fields
+            });
+            if !duplicated {
+                same_key.push(*index);
+            }
+            !duplicated
+        });
+
+        (len - kept.len()) as u32
+    }
+}
+
+/// How the large varbinary field values of a [`Request`] are compressed by the
+/// client, for the payloads dominated by the long texts, independent of the
+/// compression of the grpc messages.
+///
+/// The value of the field in the [`fields`](Self::fields) of at least
+/// [`min_bytes`](Self::min_bytes) is written as the varbinary value of the
+/// [`MARKER`](Self::MARKER) followed by the zstd frame of its bytes, and it is
+/// kept uncompressed unless the compression shrinks it. The value starting
+/// with the marker is always compressed, so that it is restored as is.
+///
+/// The server stores the compressed values as they are, so the columns of the
+/// fields must be varbinary, and the string values of the fields are rejected
+/// by [`Error::InvalidPoint`] before sending rather than written as varbinary.
+/// The values read back are restored by [`decompress`](Self::decompress).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FieldCompression {
+    /// The names of the fields to compress, which must be varbinary columns,
+    /// and the tags are never compressed.
+    ///
+    /// No field is compressed by default.
+    pub fields: HashSet<String>,
+    /// The min length in bytes of the values to compress.
+    ///
+    /// Default value is 1KB.
+    pub min_bytes: usize,
+    /// The zstd compression level.
+    ///
+    /// Default value is 3.
+    pub level: i32,
+}
+
+impl Default for FieldCompression {
+    fn default() -> Self {
+        Self {
+            fields: HashSet::new(),
+            min_bytes: 1024,
+            level: 3,
+        }
+    }
+}
+
+impl FieldCompression {
+    /// The marker prefixed to the compressed values, which is never valid
+    /// utf-8, so no string value starts with it.
+    pub const MARKER: &'static [u8] = b"\xC3ZS\x01";
+
+    /// Restore the value compressed by the client, e.g. the one read back by
+    /// the sql query, and none if the `bytes` don't start with the
+    /// [`MARKER`](Self::MARKER), i.e. not compressed. The string values are
+    /// restored as their utf-8 bytes.
+    pub fn decompress(bytes: &[u8]) -> Result<Option<Vec<u8>>> {
+        let frame = match bytes.strip_prefix(Self::MARKER) {
+            Some(frame) => frame,
+            None => return Ok(None),
+        };
+
+        zstd::stream::decode_all(frame)
+            .map(Some)
+            .map_err(|e| Error::Client(format!("failed to decompress field value, err:{e}")))
+    }
+
+    /// Check that the values of the fields to compress in the `req` are not
+    /// strings, since the columns of them must be varbinary.
+    fn check_fields(&self, req: &Request) -> Result<()> {
+        for (table, points) in &req.point_groups {
+            for (index, point) in points.iter().enumerate() {
+                for (name, value) in &point.fields {
+                    if self.fields.contains(name) && matches!(value, Value::String(_)) {
+                        return Err(Error::InvalidPoint {
+                            table: table.clone(),
+                            index,
+                            column: Some(name.clone()),
+                            reason: "field compressed by the client must be varbinary".to_string(),
+                        });
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Compress the value of the field `name`, and none if it is kept as is.
+    fn compress_value(&self, name: &str, value: &Value) -> Result<Option<Value>> {
+        let bytes = match value {
+            Value::Varbinary(v) if self.fields.contains(name) => v,
+            _ => return Ok(None),
+        };
+
+        // The raw value starting with the marker would be taken as a compressed
+        // one when read back, so it is escaped by the compression.
+        let escaped = bytes.starts_with(Self::MARKER);
+        if !escaped && bytes.len() < self.min_bytes {
+            return Ok(None);
+        }
+        let frame = zstd::stream::encode_all(bytes.as_slice(), self.level)
+            .map_err(|e| Error::Client(format!("failed to compress field value, err:{e}")))?;
+        if !escaped && Self::MARKER.len() + frame.len() >= bytes.len() {
+            return Ok(None);
+        }
+
+        let mut compressed = Vec::with_capacity(Self::MARKER.len() + frame.len());
+        compressed.extend_from_slice(Self::MARKER);
+        compressed.extend_from_slice(&frame);
+        Ok(Some(Value::Varbinary(compressed)))
+    }
+
+    /// Compress the field values of the points in the `req`, which is cloned
+    /// only if any value is compressed.
+    fn compress<'a>(&self, mut req: Cow<'a, Request>) -> Result<Cow<'a, Request>> {
+        let mut compressed = Vec::new();
+        for (table, points) in &req.point_groups {
+            for (index, point) in points.iter().enumerate() {
+                for (name, value) in &point.fields {
+                    if let Some(value) = self.compress_value(name, value)? {
+                        compressed.push((table.clone(), index, name.clone(), value));
+                    }
+                }
+            }
+        }
+        if compressed.is_empty() {
+            return Ok(req);
+        }
+
+        let point_groups = &mut req.to_mut().point_groups;
+        for (table, index, name, value) in compressed {
+            if let Some(point) = point_groups
+                .get_mut(&table)
+                .and_then(|points| points.get_mut(index))
+            {
+                point.fields.insert(name, value);
+            }
+        }
+
+        Ok(req)
+    }
+}
+
+/// Hash the tags and the fields of the `point`, and the floats are hashed by
+/// their bits, so the `0.0` and `-0.0` are never regarded as duplicates.
+fn hash_columns(point: &Point) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    for (name, value) in point.tags.iter().chain(&point.fields) {
+        name.hash(&mut hasher);
+        std::mem::discriminant(value).hash(&mut hasher);
+        match value {
+            Value::Null => (),
+            Value::Timestamp(v) | Value::Int64(v) => v.hash(&mut hasher),
+            Value::Double(v) => v.to_bits().hash(&mut hasher),
+            Value::Float(v) => v.to_bits().hash(&mut hasher),
+            Value::Varbinary(v) => v.hash(&mut hasher),
+            Value::String(v) => v.hash(&mut hasher),
+            Value::UInt64(v) => v.hash(&mut hasher),
+            Value::UInt32(v) => v.hash(&mut hasher),
+            Value::UInt16(v) => v.hash(&mut hasher),
+            Value::UInt8(v) => v.hash(&mut hasher),
+            Value::Int32(v) => v.hash(&mut hasher),
+            Value::Int16(v) => v.hash(&mut hasher),
+            Value::Int8(v) => v.hash(&mut hasher),
+            Value::Boolean(v) => v.hash(&mut hasher),
+        }
+    }
+    hasher.finish()
+}
+
+fn now_millis() -> TimestampMs {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_millis() as TimestampMs)
+        .unwrap_or_default()
+}
+
+/// The numbers of the points dropped before written.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) struct DroppedPoints {
+    /// Dropped by the validation.
+    pub invalid: u32,
+    /// Dropped as the duplicates by the normalization.
+    pub duplicated: u32,
+}
+
+impl DroppedPoints {
+    pub fn count_in(self, resp: &mut Response) {
+        resp.dropped += self.invalid;
+        resp.deduplicated += self.duplicated;
+    }
+}
 
 /// Write request.
-#[derive(Clone, Debug, Default)]
+#[derive(Clone, Debug)]
 pub struct Request {
     /// The points of different tables.
     pub point_groups: HashMap<String, Vec<Point>>,
+    /// The timestamp of the points without one.
+    pub default_timestamp: Option<TimestampMs>,
+    /// Reject the request if any point has no timestamp and no
+    /// [`default_timestamp`](Self::default_timestamp) is set, otherwise the
+    /// current time is used.
+    ///
+    /// It is enabled by default.
+    pub timestamp_required: bool,
+    /// Reject the request if any timestamp is below it, which is likely to be
+    /// in seconds rather than milliseconds, and none disables the check.
+    ///
+    /// Default value is 10^12, i.e. 2001-09-09T01:46:40Z.
+    pub min_timestamp: Option<TimestampMs>,
+    /// How the points are validated, and it is off by default.
+    pub validation: ValidationMode,
+    /// Max length in bytes of the string or varbinary values checked by the
+    /// [`validation`](Self::validation), and none disables the check.
+    ///
+    /// Default value is 1MB.
+    pub max_value_bytes: Option<usize>,
+    /// How the points are normalized, and none disables the normalization.
+    ///
+    /// It is disabled by default.
+    pub normalization: Option<Normalization>,
+    /// How the large field values are compressed by the client, and none
+    /// disables the compression.
+    ///
+    /// It is disabled by default.
+    pub field_compression: Option<FieldCompression>,
+}
+
+impl Default for Request {
+    fn default() -> Self {
+        Self {
+            point_groups: HashMap::new(),
+            default_timestamp: None,
+            timestamp_required: true,
+            min_timestamp: Some(DEFAULT_MIN_TIMESTAMP),
+            validation: ValidationMode::Off,
+            max_value_bytes: Some(DEFAULT_MAX_VALUE_BYTES),
+            normalization: None,
+            field_compression: None,
+        }
+    }
 }
 
 impl Request {
@@ -33,41 +373,488 @@ impl Request {
 
         self
     }
+
+    /// Build an empty request with the same options.
+    pub(crate) fn empty_like(&self) -> Self {
+        Self {
+            point_groups: HashMap::new(),
+            default_timestamp: self.default_timestamp,
+            timestamp_required: self.timestamp_required,
+            min_timestamp: self.min_timestamp,
+            validation: self.validation,
+            max_value_bytes: self.max_value_bytes,
+            normalization: self.normalization,
+            field_compression: self.field_compression.clone(),
+        }
+    }
+
+    /// Validate the points according to the [`validation`](Self::validation)
+    /// mode, normalize them according to the
+    /// [`normalization`](Self::normalization) and compress their fields
+    /// according to the [`field_compression`](Self::field_compression), and
+    /// return the request to write with the numbers of the points dropped.
+    ///
+    /// The request is borrowed unless some points are dropped or changed, and
+    /// the points kept are cloned once otherwise.
+    pub(crate) fn prepare(&self) -> Result<(Cow<'_, Self>, DroppedPoints)> {
+        self.prepare_at(now_millis())
+    }
+
+    fn prepare_at(&self, now: TimestampMs) -> Result<(Cow<'_, Self>, DroppedPoints)> {
+        if let Some(field_compression) = &self.field_compression {
+            field_compression.check_fields(self)?;
+        }
+        let (req, dropped) = self.validate_and_normalize(now)?;
+        let req = match &self.field_compression {
+            Some(field_compression) => field_compression.compress(req)?,
+            None => req,
+        };
+
+        Ok((req, dropped))
+    }
+
+    fn validate_and_normalize(&self, now: TimestampMs) -> Result<(Cow<'_, Self>, DroppedPoints)> {
+        let invalid_points = self.invalid_points()?;
+        if invalid_points.is_empty() && self.normalization.is_none() {
+            return Ok((Cow::Borrowed(self), DroppedPoints::default()));
+        }
+
+        let mut dropped = DroppedPoints::default();
+        let mut changed = false;
+        let mut tables = Vec::with_capacity(self.point_groups.len());
+        for (table, points) in &self.point_groups {
+            let invalid = invalid_points.get(table.as_str());
+            // The indexes of the points to write with their timestamps.
+            let mut kept: Vec<_> = points
+                .iter()
+                .enumerate()
+                .filter(|(index, _)| !matches!(invalid, Some(invalid) if invalid.contains(index)))
+                .map(|(index, point)| (index, point.timestamp))
+                .collect();
+            dropped.invalid += (points.len() - kept.len()) as u32;
+            if let Some(normalization) = &self.normalization {
+                dropped.duplicated +=
+                    normalization.normalize(points, &mut kept, self.default_timestamp, now);
+            }
+
+            changed |= kept.len() != points.len()
+                || kept.iter().enumerate().any(|(i, (index, timestamp))| {
+                    i != *index || *timestamp != points[*index].timestamp
+                });
+            tables.push((table, points, kept));
+        }
+        if !changed {
+            return Ok((Cow::Borrowed(self), dropped));
+        }
+
+        let mut req = self.empty_like();
+        for (table, points, kept) in tables {
+            if kept.is_empty() {
+                continue;
+            }
+            let points = kept
+                .into_iter()
+                .map(|(index, timestamp)| Point {
+                    timestamp,
+                    ..points[index].clone()
+                })
+                .collect();
+            req.point_groups.insert(table.clone(), points);
+        }
+
+        Ok((Cow::Owned(req), dropped))
+    }
+
+    /// Get the indexes of the invalid points by their tables according to the
+    /// [`validation`](Self::validation) mode, and fail on the first one in the
+    /// [`ValidationMode::Strict`].
+    fn invalid_points(&self) -> Result<HashMap<&str, Vec<usize>>> {
+        let mut invalid_points = HashMap::new();
+        if self.validation == ValidationMode::Off {
+            return Ok(invalid_points);
+        }
+
+        for (table, points) in &self.point_groups {
+            for (index, point) in points.iter().enumerate() {
+                let e = match self.validate_point(table, index, point) {
+                    Ok(()) => continue,
+                    Err(e) => e,
+                };
+                if self.validation == ValidationMode::Strict {
+                    return Err(e);
+                }
+
+                #[cfg(feature = "tracing")]
+                tracing::warn!(error = %e, "invalid point is dropped");
+                invalid_points
+                    .entry(table.as_str())
+                    .or_insert_with(Vec::new)
+                    .push(index);
+            }
+        }
+
+        Ok(invalid_points)
+    }
+
+    /// Run all the client-side checks of the request without sending it, and
+    /// return the encoded length of the request, see
+    /// [`DbClient::validate_write`](crate::DbClient::validate_write).
+    pub(crate) fn check(&self) -> Result<usize> {
+        if let Some(field_compression) = &self.field_compression {
+            field_compression.check_fields(self)?;
+        }
+        for (table, points) in &self.point_groups {
+            if points.is_empty() {
+                return Err(Error::Client(format!("no point to write, table:{table}")));
+            }
+
+            // The kind, i.e. whether it is a tag, and the data type of every column.
+            let mut columns: HashMap<&str, (bool, DataType)> = HashMap::new();
+            for (index, point) in points.iter().enumerate() {
+                let invalid = |column: Option<&str>, reason: String| Error::InvalidPoint {
+                    table: table.to_string(),
+                    index,
+                    column: column.map(str::to_string),
+                    reason,
+                };
+
+                self.validate_point(table, index, point)?;
+                if &point.table != table {
+                    return Err(invalid(
+                        None,
+                        format!("point belongs to table:{}", point.table),
+                    ));
+                }
+                if point.fields.is_empty() {
+                    return Err(invalid(None, "fields are empty".to_string()));
+                }
+                self.resolve_timestamp(table, index, point.timestamp)?;
+
+                let tags = point.tags.iter().map(|(name, value)| (true, name, value));
+                let fields = point
+                    .fields
+                    .iter()
+                    .map(|(name, value)| (false, name, value));
+                for (is_tag, name, value) in tags.chain(fields) {
+                    if is_reserved_column_name(name) {
+                        return Err(invalid(Some(name), "column name is reserved".to_string()));
+                    }
+                    // The null is compatible with any data type.
+                    if value.is_null() {
+                        continue;
+                    }
+
+                    let column = (is_tag, value.data_type());
+                    match columns.get(name.as_str()) {
+                        Some(previous) if *previous != column => {
+                            let kind = |is_tag| if is_tag { "tag" } else { "field" };
+                            return Err(invalid(
+                                Some(name),
+                                format!(
+                                    "column is {} of {:?}, but {} of {:?} in the previous points",
+                                    kind(column.0),
+                                    column.1,
+                                    kind(previous.0),
+                                    previous.1
+                                ),
+                            ));
+                        }
+                        Some(_) => (),
+                        None => {
+                            columns.insert(name, column);
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(self.encoded_size_estimate())
+    }
+
+    /// Estimate the size of the request encoded as the
+    /// [`WriteRequestPb`](ceresdbproto::storage::WriteRequest) sent to the
+    /// server, without building it.
+    ///
+    /// The tag and field names are encoded once per table, and every tag and
+    /// field refers to its name by the index, so the points with many fields
+    /// are encoded compactly. The estimate is exact unless some timestamps
+    /// are missing or invalid, and the request context, i.e. the database,
+    /// is not counted.
+    pub fn encoded_size_estimate(&self) -> usize {
+        self.point_groups
+            .iter()
+            .map(|(table, points)| {
+                let table_len = pb_builder::table_encoded_len(self, table, points);
+                pb_builder::len_delimited(2, table_len)
+            })
+            .sum()
+    }
+
+    /// Check the point at `index` of the `table`.
+    fn validate_point(&self, table: &str, index: usize, point: &Point) -> Result<()> {
+        let invalid = |column: Option<&str>, reason: &str| Error::InvalidPoint {
+            table: table.to_string(),
+            index,
+            column: column.map(str::to_string),
+            reason: reason.to_string(),
+        };
+
+        if table.is_empty() {
+            return Err(invalid(None, "table name is empty"));
+        }
+        for (name, value) in &point.tags {
+            if matches!(value, Value::String(s) if s.contains('\0')) {
+                return Err(invalid(Some(name), "tag value contains NUL bytes"));
+            }
+            if self.is_too_long(value) {
+                return Err(invalid(Some(name), "tag value is too long"));
+            }
+        }
+        for (name, value) in &point.fields {
+            let finite = match value {
+                Value::Double(v) => v.is_finite(),
+                Value::Float(v) => v.is_finite(),
+                _ => true,
+            };
+            if !finite {
+                return Err(invalid(Some(name), "field value is NaN or infinite"));
+            }
+            if self.is_too_long(value) {
+                return Err(invalid(Some(name), "field value is too long"));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn is_too_long(&self, value: &Value) -> bool {
+        let len = match value {
+            Value::String(s) => s.len(),
+            Value::Varbinary(v) => v.len(),
+            _ => return false,
+        };
+        matches!(self.max_value_bytes, Some(max) if len > max)
+    }
+
+    /// Get the timestamp to write for the point at `index` of the `table`.
+    fn resolve_timestamp(
+        &self,
+        table: &str,
+        index: usize,
+        timestamp: Option<TimestampMs>,
+    ) -> Result<TimestampMs> {
+        let timestamp = match timestamp.or(self.default_timestamp) {
+            Some(timestamp) => timestamp,
+            None if self.timestamp_required => {
+                return Err(Error::Client(format!(
+                    "timestamp of point is missing, table:{table}, index:{index}"
+                )))
+            }
+            None => now_millis(),
+        };
+
+        match self.min_timestamp {
+            Some(min_timestamp) if timestamp < min_timestamp => Err(Error::Client(format!(
+                "timestamp of point is too small and may be in seconds rather than \
+                 milliseconds, table:{table}, index:{index}, timestamp:{timestamp}, \
+                 min_timestamp:{min_timestamp}"
+            ))),
+            _ => Ok(timestamp),
+        }
+    }
 }
 
 pub mod pb_builder {
     use std::collections::{BTreeMap, HashMap};
 
     use ceresdbproto::storage::{
-        Field, FieldGroup as FieldGroupPb, Tag as TagPb, WriteSeriesEntry as WriteSeriesEntryPb,
-        WriteTableRequest as WriteTableRequestPb,
+        Field, FieldGroup as FieldGroupPb, Tag as TagPb, Value as ValuePb,
+        WriteSeriesEntry as WriteSeriesEntryPb, WriteTableRequest as WriteTableRequestPb,
+    };
+    use prost::{
+        encoding::{encoded_len_varint, key_len},
+        Message,
     };
 
-    use crate::model::{
-        value::{TimestampMs, Value},
-        write::{point::Point, Request},
+    use crate::{
+        model::{
+            value::{TimestampMs, Value},
+            write::{point::Point, Request},
+        },
+        Error, Result,
     };
 
     type TagsKey = Vec<u8>;
+    /// The tags and the fields by the timestamps of a series.
+    type SeriesRef<'a> = (
+        &'a BTreeMap<String, Value>,
+        BTreeMap<TimestampMs, &'a Fields>,
+    );
 
     /// Used to build [`WriteRequestPb`](WriteTableRequestPb) from [Request].
     pub struct WriteTableRequestPbsBuilder(pub Request);
 
     impl WriteTableRequestPbsBuilder {
-        pub fn build(self) -> Vec<WriteTableRequestPb> {
+        /// Build the pbs, and the timestamps of the points are resolved and
+        /// checked according to the options of the request meanwhile.
+        pub fn build(self) -> Result<Vec<WriteTableRequestPb>> {
             // Partition points by table.
-            let point_group = self.0.point_groups;
+            let mut req = self.0;
+            let point_group = std::mem::take(&mut req.point_groups);
 
             // Build pb.
             let mut table_request_pbs = Vec::with_capacity(point_group.len());
             for (table, points) in point_group {
-                let write_table_request_pb_builder = TableRequestPbBuilder::new(table, points);
+                let write_table_request_pb_builder =
+                    TableRequestPbBuilder::new(table, points, &req)?;
                 let write_table_request_pb = write_table_request_pb_builder.build();
                 table_request_pbs.push(write_table_request_pb);
             }
 
-            table_request_pbs
+            Ok(table_request_pbs)
+        }
+    }
+
+    /// Recover the points from the pb built by the
+    /// [`WriteTableRequestPbsBuilder`], and a point is recovered from every
+    /// field group.
+    pub(crate) fn points_from_pb(table_request: WriteTableRequestPb) -> Result<Vec<Point>> {
+        let name = |names: &[String], index: u32| {
+            names.get(index as usize).cloned().ok_or_else(|| {
+                Error::Client(format!(
+                    "name index out of range, table:{}, index:{index}",
+                    table_request.table
+                ))
+            })
+        };
+
+        let mut points = Vec::new();
+        for entry in &table_request.entries {
+            let tags = entry
+                .tags
+                .iter()
+                .map(|tag| {
+                    let value = tag.value.clone().map(Value::from).unwrap_or_default();
+                    Ok((name(&table_request.tag_names, tag.name_index)?, value))
+                })
+                .collect::<Result<BTreeMap<_, _>>>()?;
+            for field_group in &entry.field_groups {
+                let fields = field_group
+                    .fields
+                    .iter()
+                    .map(|field| {
+                        let value = field.value.clone().map(Value::from).unwrap_or_default();
+                        Ok((name(&table_request.field_names, field.name_index)?, value))
+                    })
+                    .collect::<Result<BTreeMap<_, _>>>()?;
+                points.push(Point {
+                    table: table_request.table.clone(),
+                    timestamp: Some(field_group.timestamp),
+                    tags: tags.clone(),
+                    fields,
+                });
+            }
+        }
+
+        Ok(points)
+    }
+
+    /// Count the rows of the table requests, i.e. their field groups.
+    pub(crate) fn row_count(table_requests: &[WriteTableRequestPb]) -> usize {
+        table_requests
+            .iter()
+            .flat_map(|table_request| &table_request.entries)
+            .map(|entry| entry.field_groups.len())
+            .sum()
+    }
+
+    /// Split the table requests into the sub-requests with at most
+    /// `max_rows` rows, and the tables are kept whole in a sub-request unless
+    /// they have more rows than `max_rows` themselves, whose first parts fill
+    /// the room left in the current sub-request.
+    ///
+    /// The split table keeps its tag and field names in all the parts, and
+    /// the rows are in the original order across the sub-requests.
+    pub(crate) fn split_table_requests(
+        table_requests: Vec<WriteTableRequestPb>,
+        max_rows: usize,
+    ) -> Vec<Vec<WriteTableRequestPb>> {
+        let max_rows = max_rows.max(1);
+        let mut sub_requests = Vec::new();
+        let mut sub_request = Vec::new();
+        let mut rows = 0;
+        for table_request in table_requests {
+            let table_rows = row_count(std::slice::from_ref(&table_request));
+            let room = if table_rows <= max_rows {
+                table_rows
+            } else {
+                1
+            };
+            if rows + room > max_rows && !sub_request.is_empty() {
+                sub_requests.push(std::mem::take(&mut sub_request));
+                rows = 0;
+            }
+            if table_rows <= max_rows {
+                sub_request.push(table_request);
+                rows += table_rows;
+                continue;
+            }
+
+            for part in split_table_request(table_request, rows, max_rows) {
+                rows += row_count(std::slice::from_ref(&part));
+                sub_request.push(part);
+                if rows == max_rows {
+                    sub_requests.push(std::mem::take(&mut sub_request));
+                    rows = 0;
+                }
+            }
+        }
+        if !sub_request.is_empty() {
+            sub_requests.push(sub_request);
+        }
+
+        sub_requests
+    }
+
+    /// Split the table request into the parts with at most `max_rows` rows,
+    /// and the first part only has the room left by the `filled` rows.
+    fn split_table_request(
+        table_request: WriteTableRequestPb,
+        filled: usize,
+        max_rows: usize,
+    ) -> Vec<WriteTableRequestPb> {
+        let part = |entries| WriteTableRequestPb {
+            table: table_request.table.clone(),
+            tag_names: table_request.tag_names.clone(),
+            field_names: table_request.field_names.clone(),
+            entries,
+        };
+
+        let mut parts = Vec::new();
+        let mut entries = Vec::new();
+        let mut rows = filled;
+        for entry in &table_request.entries {
+            let mut field_groups = entry.field_groups.as_slice();
+            while !field_groups.is_empty() {
+                let len = field_groups.len().min(max_rows - rows);
+                let (head, rest) = field_groups.split_at(len);
+                entries.push(WriteSeriesEntryPb {
+                    tags: entry.tags.clone(),
+                    field_groups: head.to_vec(),
+                });
+                field_groups = rest;
+                rows += len;
+                if rows == max_rows {
+                    parts.push(part(std::mem::take(&mut entries)));
+                    rows = 0;
+                }
+            }
+        }
+        if !entries.is_empty() {
+            parts.push(part(entries));
         }
+
+        parts
     }
 
     struct TableRequestPbBuilder {
@@ -76,11 +863,20 @@ pub mod pb_builder {
     }
 
     impl TableRequestPbBuilder {
-        pub fn new(table: String, points: Vec<Point>) -> Self {
-            // Partition points according to tags and build [WriteSeriesEntry].
-            let mut series_entries_by_tags = HashMap::new();
-            for point in points {
-                assert_eq!(point.table, table);
+        pub fn new(table: String, points: Vec<Point>, req: &Request) -> Result<Self> {
+            // Partition points according to tags and build [WriteSeriesEntry], ordered
+            // by the tags to keep the encoding deterministic.
+            let mut series_entries_by_tags = BTreeMap::new();
+            for (index, point) in points.into_iter().enumerate() {
+                if point.table != table {
+                    return Err(Error::InvalidPoint {
+                        table,
+                        index,
+                        column: None,
+                        reason: format!("point belongs to table:{}", point.table),
+                    });
+                }
+                let timestamp = req.resolve_timestamp(&table, index, point.timestamp)?;
                 let tags_key = make_tags_key(&point.tags);
                 let series_entry =
                     series_entries_by_tags
@@ -89,21 +885,29 @@ pub mod pb_builder {
                             tags: point.tags,
                             ts_fields: BTreeMap::new(),
                         });
-                series_entry.ts_fields.insert(point.timestamp, point.fields);
+                series_entry.ts_fields.insert(timestamp, point.fields);
             }
 
             // Flatten the write series entires.
             let series_entires = series_entries_by_tags.into_values().collect();
 
-            Self {
+            Ok(Self {
                 table,
                 series_entires,
-            }
+            })
         }
 
         pub fn build(self) -> WriteTableRequestPb {
-            let mut tags_dict = NameDict::new();
-            let mut fields_dict = NameDict::new();
+            let tag_names = self
+                .series_entires
+                .iter()
+                .flat_map(|entry| entry.tags.keys());
+            let field_names = self
+                .series_entires
+                .iter()
+                .flat_map(|entry| entry.ts_fields.values().flat_map(|fields| fields.keys()));
+            let mut tags_dict = NameDict::with_names(ordered_names(tag_names));
+            let mut fields_dict = NameDict::with_names(ordered_names(field_names));
             let mut wirte_entries_pb = Vec::with_capacity(self.series_entires.len());
             for entry in self.series_entires {
                 wirte_entries_pb.push(Self::build_series_entry(
@@ -203,6 +1007,15 @@ pub mod pb_builder {
             }
         }
 
+        /// Build the dict with the `names` indexed in order.
+        fn with_names(names: Vec<&str>) -> Self {
+            let mut dict = Self::new();
+            for name in names {
+                dict.insert(name.to_string());
+            }
+            dict
+        }
+
         fn insert(&mut self, name: String) -> u32 {
             *self.dict.entry(name).or_insert_with(|| {
                 let old_name_idx = self.name_idx;
@@ -220,6 +1033,109 @@ pub mod pb_builder {
         }
     }
 
+    /// Order the distinct `names` by their occurrences in descending order,
+    /// then by the names, so the indices are deterministic and the most
+    /// frequent name gets the index 0, which is omitted in the encoding.
+    fn ordered_names<'a>(names: impl Iterator<Item = &'a String>) -> Vec<&'a str> {
+        let mut occurrences: HashMap<&str, usize> = HashMap::new();
+        for name in names {
+            *occurrences.entry(name.as_str()).or_default() += 1;
+        }
+
+        let mut ordered: Vec<_> = occurrences.into_iter().collect();
+        ordered.sort_unstable_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+        ordered.into_iter().map(|(name, _)| name).collect()
+    }
+
+    fn index_names(names: Vec<&str>) -> HashMap<&str, u32> {
+        names
+            .into_iter()
+            .enumerate()
+            .map(|(index, name)| (name, index as u32))
+            .collect()
+    }
+
+    /// Encoded length of the length-delimited field with the `tag`, whose
+    /// payload is `len` bytes.
+    pub(crate) fn len_delimited(tag: u32, len: usize) -> usize {
+        key_len(tag) + encoded_len_varint(len as u64) + len
+    }
+
+    /// Encoded length of the name index with the `tag`, which is omitted if
+    /// it is 0.
+    fn name_index_len(tag: u32, index: u32) -> usize {
+        if index == 0 {
+            0
+        } else {
+            key_len(tag) + encoded_len_varint(index as u64)
+        }
+    }
+
+    fn value_len(value: &Value) -> usize {
+        ValuePb::from(value.clone()).encoded_len()
+    }
+
+    /// Encoded length of the [`WriteTableRequestPb`] of the `points` of the
+    /// `table` in the `req`, which is built the same way as the
+    /// [`WriteTableRequestPbsBuilder`] but without the values copied.
+    pub(crate) fn table_encoded_len(req: &Request, table: &str, points: &[Point]) -> usize {
+        // The fields of the series by the timestamps, and the later point
+        // overwrites the earlier one with the same timestamp.
+        let mut series: BTreeMap<TagsKey, SeriesRef> = BTreeMap::new();
+        for (index, point) in points.iter().enumerate() {
+            let timestamp = req
+                .resolve_timestamp(table, index, point.timestamp)
+                .unwrap_or_else(|_| point.timestamp.unwrap_or_default());
+            series
+                .entry(make_tags_key(&point.tags))
+                .or_insert_with(|| (&point.tags, BTreeMap::new()))
+                .1
+                .insert(timestamp, &point.fields);
+        }
+
+        let tag_names = ordered_names(series.values().flat_map(|(tags, _)| tags.keys()));
+        let field_names = ordered_names(
+            series
+                .values()
+                .flat_map(|(_, ts_fields)| ts_fields.values().flat_map(|fields| fields.keys())),
+        );
+        let names_len = |tag, names: &[&str]| -> usize {
+            names
+                .iter()
+                .map(|name| len_delimited(tag, name.len()))
+                .sum()
+        };
+        let mut len =
+            len_delimited(1, table.len()) + names_len(2, &tag_names) + names_len(3, &field_names);
+
+        let tag_indices = index_names(tag_names);
+        let field_indices = index_names(field_names);
+        for (tags, ts_fields) in series.values() {
+            let mut entry_len = 0;
+            for (name, value) in tags.iter() {
+                let tag_len = name_index_len(1, tag_indices[name.as_str()])
+                    + len_delimited(2, value_len(value));
+                entry_len += len_delimited(1, tag_len);
+            }
+            for (timestamp, fields) in ts_fields {
+                let mut field_group_len = if *timestamp == 0 {
+                    0
+                } else {
+                    key_len(1) + encoded_len_varint(*timestamp as u64)
+                };
+                for (name, value) in fields.iter() {
+                    let field_len = name_index_len(1, field_indices[name.as_str()])
+                        + len_delimited(2, value_len(value));
+                    field_group_len += len_delimited(2, field_len);
+                }
+                entry_len += len_delimited(2, field_group_len);
+            }
+            len += len_delimited(4, entry_len);
+        }
+
+        len
+    }
+
     pub fn make_tags_key(tags: &BTreeMap<String, Value>) -> TagsKey {
         let mut series_key = Vec::default();
         for (name, val) in tags {
@@ -233,18 +1149,29 @@ pub mod pb_builder {
 
 #[cfg(test)]
 mod test {
-    use std::collections::BTreeMap;
+    use std::{
+        borrow::Cow,
+        collections::{BTreeMap, HashSet},
+        time::Duration,
+    };
 
+    use ceresdbproto::storage::WriteRequest as WriteRequestPb;
     use chrono::Local;
+    use prost::Message;
 
-    use super::pb_builder::make_tags_key;
-    use crate::model::{
-        value::Value,
-        write::{
-            point::{Point, PointBuilder},
-            request::pb_builder::WriteTableRequestPbsBuilder,
-            Request,
+    use super::{pb_builder::make_tags_key, DroppedPoints};
+    use crate::{
+        model::{
+            value::Value,
+            write::{
+                point::{Point, PointBuilder},
+                request::pb_builder::{
+                    points_from_pb, row_count, split_table_requests, WriteTableRequestPbsBuilder,
+                },
+                FieldCompression, Normalization, Request, ValidationMode,
+            },
         },
+        Error,
     };
 
     #[test]
@@ -330,7 +1257,9 @@ mod test {
         write_req.add_points(points).add_points(points2);
 
         // Build pb.
-        let table_requests = WriteTableRequestPbsBuilder(write_req.clone()).build();
+        let table_requests = WriteTableRequestPbsBuilder(write_req.clone())
+            .build()
+            .unwrap();
         // Recover points from pb and compare.
         let mut points = Vec::new();
         for table_request in table_requests {
@@ -361,7 +1290,7 @@ mod test {
 
                     let point = Point {
                         table: table_request.table.clone(),
-                        timestamp,
+                        timestamp: Some(timestamp),
                         tags: tags.clone(),
                         fields,
                     };
@@ -387,7 +1316,369 @@ mod test {
         assert_eq!(points, expected_points);
     }
 
-    fn make_cmp_key(point: &Point) -> (Vec<u8>, i64) {
+    fn timestamps(req: Request) -> crate::Result<Vec<i64>> {
+        let table_requests = WriteTableRequestPbsBuilder(req).build()?;
+        Ok(table_requests
+            .into_iter()
+            .flat_map(|table_request| table_request.entries)
+            .flat_map(|entry| entry.field_groups)
+            .map(|field_group| field_group.timestamp)
+            .collect())
+    }
+
+    fn point(timestamp: Option<i64>) -> Point {
+        let builder = PointBuilder::new("test_table".to_string())
+            .field("value".to_string(), Value::Int64(42));
+        match timestamp {
+            Some(timestamp) => builder.timestamp(timestamp),
+            None => builder,
+        }
+        .build()
+        .unwrap()
+    }
+
+    #[test]
+    fn test_build_mismatched_table() {
+        // The points grouped to another table by hand are rejected instead of
+        // being written to the wrong table.
+        let mut req = Request::default();
+        let point = PointBuilder::new("t2".to_string())
+            .timestamp(Local::now().timestamp_millis())
+            .field("value".to_string(), Value::Double(0.42))
+            .build()
+            .unwrap();
+        req.point_groups.insert("t1".to_string(), vec![point]);
+
+        let err = WriteTableRequestPbsBuilder(req).build().unwrap_err();
+        assert!(
+            matches!(&err, Error::InvalidPoint { table, index: 0, .. } if table == "t1"),
+            "err:{err:?}"
+        );
+    }
+
+    #[test]
+    fn test_default_timestamp() {
+        let ts = Local::now().timestamp_millis();
+        let mut req = Request::default();
+        req.add_points(vec![point(None), point(Some(ts - 1))]);
+
+        // Missing timestamp is rejected by default.
+        let err = timestamps(req.clone()).unwrap_err();
+        assert!(err.to_string().contains("table:test_table, index:0"));
+
+        req.default_timestamp = Some(ts);
+        assert_eq!(timestamps(req.clone()).unwrap(), vec![ts - 1, ts]);
+
+        // The current time is used if not required.
+        req.default_timestamp = None;
+        req.timestamp_required = false;
+        let resolved = timestamps(req).unwrap();
+        assert_eq!(resolved.len(), 2);
+        assert_eq!(resolved[0], ts - 1);
+        assert!(resolved[1] >= ts);
+    }
+
+    #[test]
+    fn test_timestamp_unit_guard() {
+        let ts_secs = Local::now().timestamp();
+        let mut req = Request::default();
+        req.add_points(vec![point(Some(ts_secs * 1000)), point(Some(ts_secs))]);
+
+        let err = timestamps(req.clone()).unwrap_err();
+        assert!(err.to_string().contains("index:1"));
+
+        // The default timestamp is checked too.
+        let mut default_req = Request::default();
+        default_req.add_point(point(None));
+        default_req.default_timestamp = Some(ts_secs);
+        assert!(timestamps(default_req).is_err());
+
+        req.min_timestamp = None;
+        let mut resolved = timestamps(req).unwrap();
+        resolved.sort();
+        assert_eq!(resolved, vec![ts_secs, ts_secs * 1000]);
+    }
+
+    #[test]
+    fn test_check() {
+        let point = |table: &str| {
+            PointBuilder::new(table.to_string())
+                .timestamp(Local::now().timestamp_millis())
+                .tag("host".to_string(), Value::String("host1".to_string()))
+                .field("value".to_string(), Value::Double(0.42))
+        };
+        let check = |points: Vec<Point>| {
+            let mut req = Request::default();
+            req.add_points(points);
+            req.check()
+        };
+
+        let valid = vec![
+            point("t1").build().unwrap(),
+            point("t1")
+                .field("value".to_string(), Value::Null)
+                .build()
+                .unwrap(),
+            point("t2")
+                .field("value".to_string(), Value::Int64(42))
+                .build()
+                .unwrap(),
+        ];
+        assert!(check(valid).unwrap() > 0);
+
+        let cases = vec![
+            (
+                point("t").field("value".to_string(), Value::Int64(42)),
+                Some("value"),
+            ),
+            (
+                point("t").tag("value".to_string(), Value::Double(0.42)),
+                Some("value"),
+            ),
+            (
+                point("t").field("value".to_string(), Value::Double(f64::NAN)),
+                Some("value"),
+            ),
+        ];
+        for (builder, expected_column) in cases {
+            match check(vec![point("t").build().unwrap(), builder.build().unwrap()]) {
+                Err(Error::InvalidPoint { index, column, .. }) => {
+                    assert_eq!(index, 1);
+                    assert_eq!(column.as_deref(), expected_column);
+                }
+                other => panic!("unexpected result:{other:?}"),
+            }
+        }
+
+        // The fields of the point are public, so the reserved column may bypass
+        // the builder.
+        let mut reserved = point("t").build().unwrap();
+        reserved
+            .fields
+            .insert("Timestamp".to_string(), Value::Int64(42));
+        assert!(matches!(
+            check(vec![reserved]),
+            Err(Error::InvalidPoint { column: Some(column), .. }) if column == "Timestamp"
+        ));
+
+        // The point put into the group of another table.
+        let mut req = Request::default();
+        req.point_groups
+            .insert("t1".to_string(), vec![point("t2").build().unwrap()]);
+        assert!(matches!(req.check(), Err(Error::InvalidPoint { .. })));
+        req.point_groups.insert("t1".to_string(), Vec::new());
+        assert!(matches!(req.check(), Err(Error::Client(_))));
+    }
+
+    #[test]
+    fn test_strict_validation() {
+        let valid = |table: &str| {
+            PointBuilder::new(table.to_string())
+                .timestamp(Local::now().timestamp_millis())
+                .tag("host".to_string(), Value::String("host1".to_string()))
+                .field("value".to_string(), Value::Double(0.42))
+        };
+        let cases = vec![
+            (valid(""), None),
+            (
+                valid("t").field("value".to_string(), Value::Double(f64::NAN)),
+                Some("value"),
+            ),
+            (
+                valid("t").field("value".to_string(), Value::Float(f32::INFINITY)),
+                Some("value"),
+            ),
+            (
+                valid("t").tag("host".to_string(), Value::String("a".repeat(9))),
+                Some("host"),
+            ),
+            (
+                valid("t").field("name".to_string(), Value::String("a".repeat(9))),
+                Some("name"),
+            ),
+            (
+                valid("t").tag("host".to_string(), Value::String("a\0b".to_string())),
+                Some("host"),
+            ),
+        ];
+
+        for (builder, expected_column) in cases {
+            let mut req = Request {
+                validation: ValidationMode::Strict,
+                max_value_bytes: Some(8),
+                ..Default::default()
+            };
+            let invalid = builder.build().unwrap();
+            let invalid_table = invalid.table.clone();
+            req.add_points(vec![valid("t").build().unwrap(), invalid]);
+            // The point with the empty table name is the first of its table.
+            let index = if invalid_table.is_empty() { 0 } else { 1 };
+
+            match req.prepare() {
+                Err(Error::InvalidPoint {
+                    table,
+                    index: i,
+                    column,
+                    ..
+                }) => {
+                    assert_eq!(table, invalid_table);
+                    assert_eq!(i, index);
+                    assert_eq!(column.as_deref(), expected_column);
+                }
+                other => panic!("unexpected result:{other:?}"),
+            }
+
+            // Nothing is checked if the validation is off.
+            req.validation = ValidationMode::Off;
+            assert_eq!(req.prepare().unwrap().1.invalid, 0);
+        }
+    }
+
+    #[test]
+    fn test_warn_validation() {
+        let point = |table: &str, value: f64| {
+            PointBuilder::new(table.to_string())
+                .timestamp(Local::now().timestamp_millis())
+                .field("value".to_string(), Value::Double(value))
+                .build()
+                .unwrap()
+        };
+        let mut req = Request {
+            validation: ValidationMode::Warn,
+            ..Default::default()
+        };
+        req.add_points(vec![
+            point("t1", 1.0),
+            point("t1", f64::NAN),
+            point("t1", 2.0),
+            point("t2", f64::NEG_INFINITY),
+        ]);
+
+        let (validated, dropped) = req.prepare().unwrap();
+        assert_eq!(dropped.invalid, 2);
+        // The table without valid points is removed.
+        assert_eq!(validated.point_groups.len(), 1);
+        let values: Vec<_> = validated.point_groups["t1"]
+            .iter()
+            .map(|point| point.fields["value"].clone())
+            .collect();
+        assert_eq!(values, vec![Value::Double(1.0), Value::Double(2.0)]);
+        assert_eq!(validated.validation, ValidationMode::Warn);
+
+        // The request is not copied if all the points are valid.
+        let mut valid_req = req.empty_like();
+        valid_req.add_point(point("t1", 1.0));
+        let (validated, dropped) = valid_req.prepare().unwrap();
+        assert_eq!(dropped.invalid, 0);
+        assert!(matches!(validated, std::borrow::Cow::Borrowed(_)));
+    }
+
+    #[test]
+    fn test_normalization() {
+        let now = 1_700_000_000_000;
+        let point = |table: &str, timestamp: i64, host: &str, value: i64| {
+            PointBuilder::new(table.to_string())
+                .timestamp(timestamp)
+                .tag("host", host)
+                .field("value", value)
+                .build()
+                .unwrap()
+        };
+        let values = |req: &Request, table: &str| -> Vec<(i64, i64)> {
+            req.point_groups[table]
+                .iter()
+                .map(|point| {
+                    let value = point.fields["value"].as_i64().unwrap();
+                    (point.timestamp.unwrap(), value)
+                })
+                .collect()
+        };
+
+        let mut req = Request::default();
+        req.add_points(vec![
+            point("t1", now + 3, "a", 1),
+            point("t1", now + 1, "a", 2),
+            point("t2", now + 2, "a", 3),
+            point("t1", now + 2, "a", 4),
+            point("t1", now + 1, "a", 5),
+            point("t2", now + 1, "a", 6),
+        ]);
+        // Nothing is changed if the normalization is disabled.
+        let (prepared, dropped) = req.prepare_at(now).unwrap();
+        assert!(matches!(prepared, Cow::Borrowed(_)));
+        assert_eq!(dropped, DroppedPoints::default());
+
+        // The points are stable-sorted within their tables.
+        req.normalization = Some(Normalization::default());
+        let (prepared, dropped) = req.prepare_at(now).unwrap();
+        assert_eq!(dropped.duplicated, 0);
+        assert_eq!(
+            values(&prepared, "t1"),
+            vec![(now + 1, 2), (now + 1, 5), (now + 2, 4), (now + 3, 1)]
+        );
+        assert_eq!(values(&prepared, "t2"), vec![(now + 1, 6), (now + 2, 3)]);
+
+        // The request is borrowed if it is normalized already.
+        let sorted = prepared.into_owned();
+        let (prepared, _) = sorted.prepare_at(now).unwrap();
+        assert!(matches!(prepared, Cow::Borrowed(_)));
+
+        // Only the exact duplicates are dropped.
+        let mut req = Request {
+            normalization: Some(Normalization::default()),
+            ..Default::default()
+        };
+        req.add_points(vec![
+            point("t1", now, "a", 1),
+            point("t1", now, "b", 1),
+            point("t1", now, "a", 2),
+            point("t1", now + 1, "a", 1),
+            point("t1", now, "a", 1),
+            point("t2", now, "a", 1),
+            point("t1", now, "b", 1),
+        ]);
+        let (prepared, dropped) = req.prepare_at(now).unwrap();
+        assert_eq!(dropped.duplicated, 2);
+        let kept: Vec<_> = prepared.point_groups["t1"]
+            .iter()
+            .map(|point| (point.tags["host"].clone(), point.fields["value"].clone()))
+            .collect();
+        assert_eq!(
+            kept,
+            vec![
+                (Value::from("a"), Value::Int64(1)),
+                (Value::from("b"), Value::Int64(1)),
+                (Value::from("a"), Value::Int64(2)),
+                (Value::from("a"), Value::Int64(1)),
+            ]
+        );
+        assert_eq!(prepared.point_groups["t2"].len(), 1);
+
+        // The timestamps beyond the skew are clamped.
+        let skew = 60_000;
+        let mut req = Request::default();
+        req.add_points(vec![
+            point("t1", now + skew + 1, "a", 1),
+            point("t1", now + skew, "a", 2),
+            point("t1", now + 10 * skew, "a", 3),
+        ]);
+        req.normalization = Some(Normalization::default());
+        let (prepared, _) = req.prepare_at(now).unwrap();
+        assert_eq!(
+            values(&prepared, "t1"),
+            vec![(now + skew, 2), (now + skew + 1, 1), (now + 10 * skew, 3)]
+        );
+        req.normalization = Some(Normalization {
+            max_future_skew: Some(Duration::from_millis(skew as u64)),
+        });
+        let (prepared, _) = req.prepare_at(now).unwrap();
+        assert_eq!(
+            values(&prepared, "t1"),
+            vec![(now, 1), (now, 3), (now + skew, 2)]
+        );
+    }
+
+    fn make_cmp_key(point: &Point) -> (Vec<u8>, Option<i64>) {
         let mut series_key = point.table.as_bytes().to_vec();
         let tagks_key = make_tags_key(&point.tags);
         series_key.extend(tagks_key);
@@ -409,4 +1700,229 @@ mod test {
             cmp_key1.cmp(&cmp_key2)
         });
     }
+
+    fn encode(req: &Request) -> Vec<u8> {
+        let req_pb = WriteRequestPb {
+            context: None,
+            table_requests: WriteTableRequestPbsBuilder(req.clone()).build().unwrap(),
+        };
+        req_pb.encode_to_vec()
+    }
+
+    fn uniform_batch(series: usize, timestamps: usize, fields: usize) -> Request {
+        let mut req = Request::default();
+        for s in 0..series {
+            for t in 0..timestamps {
+                let mut builder = PointBuilder::new("uniform".to_string())
+                    .timestamp(1_600_000_000_000 + t as i64 * 1000)
+                    .tag("host".to_string(), Value::String(format!("host{s}")))
+                    .tag("region".to_string(), Value::String("region1".to_string()));
+                for f in 0..fields {
+                    builder = builder.field(format!("field_{f:02}"), Value::Double(f as f64));
+                }
+                req.add_point(builder.build().unwrap());
+            }
+        }
+        req
+    }
+
+    #[test]
+    fn test_field_compression() {
+        let text = "CeresDB is a timeseries database. ".repeat(64);
+        let point = PointBuilder::new("t1".to_string())
+            .timestamp(1_700_000_000_000)
+            .tag("host", Value::String(text.clone()))
+            .field("message", Value::Varbinary(text.clone().into_bytes()))
+            .field("payload", Value::Varbinary(text.clone().into_bytes()))
+            .field("short", Value::Varbinary(b"short".to_vec()))
+            .field("other", Value::String(text.clone()))
+            .build()
+            .unwrap();
+        let mut req = Request::default();
+        req.add_point(point.clone());
+
+        // Nothing is compressed if no field is listed.
+        req.field_compression = Some(FieldCompression::default());
+        assert!(matches!(req.prepare().unwrap().0, Cow::Borrowed(_)));
+
+        let fields = ["message", "payload", "short"];
+        req.field_compression = Some(FieldCompression {
+            fields: fields.iter().map(|name| name.to_string()).collect(),
+            min_bytes: 16,
+            ..Default::default()
+        });
+        let (prepared, _) = req.prepare().unwrap();
+        let prepared_point = &prepared.point_groups["t1"][0];
+        for name in ["message", "payload"] {
+            let compressed = match &prepared_point.fields[name] {
+                Value::Varbinary(v) => v,
+                value => panic!("field {name} is not compressed, value:{value:?}"),
+            };
+            assert!(compressed.starts_with(FieldCompression::MARKER));
+            assert!(compressed.len() < text.len());
+            let restored = FieldCompression::decompress(compressed).unwrap();
+            assert_eq!(restored, Some(text.clone().into_bytes()));
+        }
+        // The short value, the fields not listed and the tags are kept as is.
+        assert_eq!(prepared_point.fields["short"], point.fields["short"]);
+        assert_eq!(prepared_point.fields["other"], point.fields["other"]);
+        assert_eq!(prepared_point.tags, point.tags);
+        assert_eq!(FieldCompression::decompress(b"short").unwrap(), None);
+    }
+
+    #[test]
+    fn test_field_compression_checks_values() {
+        let field_compression = FieldCompression {
+            fields: HashSet::from(["message".to_string()]),
+            min_bytes: 16,
+            ..Default::default()
+        };
+        let build_req = |message: Value| {
+            let point = PointBuilder::new("t1".to_string())
+                .timestamp(1_700_000_000_000)
+                .field("message", message)
+                .build()
+                .unwrap();
+            let mut req = Request::default();
+            req.add_point(point);
+            req.field_compression = Some(field_compression.clone());
+            req
+        };
+
+        // The raw short value starting with the marker is compressed to be
+        // restored as is, e.g. the value compressed by the user already.
+        let mut raw = FieldCompression::MARKER.to_vec();
+        raw.extend_from_slice(b"raw");
+        let req = build_req(Value::Varbinary(raw.clone()));
+        let (prepared, _) = req.prepare().unwrap();
+        let escaped = match &prepared.point_groups["t1"][0].fields["message"] {
+            Value::Varbinary(v) => v.clone(),
+            value => panic!("value is not varbinary, value:{value:?}"),
+        };
+        assert_ne!(escaped, raw);
+        assert_eq!(FieldCompression::decompress(&escaped).unwrap(), Some(raw));
+
+        // The string values are rejected rather than written as varbinary, even
+        // if they are short.
+        let req = build_req(Value::String("short".to_string()));
+        for result in [req.prepare().map(|_| ()), req.check().map(|_| ())] {
+            match result {
+                Err(Error::InvalidPoint {
+                    table,
+                    index: 0,
+                    column: Some(column),
+                    ..
+                }) => assert_eq!((table.as_str(), column.as_str()), ("t1", "message")),
+                result => panic!("unexpected result:{result:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_encoded_size_estimate() {
+        // 1000 points of 30 fields.
+        let req = uniform_batch(10, 100, 30);
+        let bytes = encode(&req);
+        assert_eq!(req.encoded_size_estimate(), bytes.len());
+        assert_eq!(req.check().unwrap(), bytes.len());
+
+        // The encoding is deterministic and the names are sent once.
+        assert_eq!(encode(&req), bytes);
+        let decoded = WriteRequestPb::decode(bytes.as_slice()).unwrap();
+        let table_req = &decoded.table_requests[0];
+        assert_eq!(table_req.tag_names.len(), 2);
+        assert_eq!(table_req.field_names.len(), 30);
+        assert_eq!(table_req.entries.len(), 10);
+        assert!(table_req
+            .entries
+            .iter()
+            .all(|entry| entry.field_groups.len() == 100));
+
+        // Smaller than 60% of sending the points one by one.
+        let single_point = encode(&uniform_batch(1, 1, 30)).len();
+        assert!(bytes.len() < single_point * 600);
+
+        // Ragged field sets and missing values.
+        let mut ragged = Request::default();
+        ragged.add_points(vec![
+            PointBuilder::new("ragged".to_string())
+                .timestamp(1_600_000_000_001)
+                .tag("host".to_string(), Value::String("host1".to_string()))
+                .field("a".to_string(), Value::Int64(1))
+                .build()
+                .unwrap(),
+            PointBuilder::new("ragged".to_string())
+                .timestamp(1_600_000_000_002)
+                .tag("host".to_string(), Value::String("host1".to_string()))
+                .field("b".to_string(), Value::String("b".to_string()))
+                .field("c".to_string(), Value::Null)
+                .build()
+                .unwrap(),
+            PointBuilder::new("ragged".to_string())
+                .timestamp(1_600_000_000_002)
+                .tag("host".to_string(), Value::String("host2".to_string()))
+                .tag("zone".to_string(), Value::Int32(3))
+                .field("a".to_string(), Value::Double(0.5))
+                .build()
+                .unwrap(),
+            PointBuilder::new("other".to_string())
+                .timestamp(1_600_000_000_003)
+                .tag("host".to_string(), Value::String("host1".to_string()))
+                .field("a".to_string(), Value::Boolean(true))
+                .build()
+                .unwrap(),
+        ]);
+        assert_eq!(ragged.encoded_size_estimate(), encode(&ragged).len());
+    }
+
+    #[test]
+    fn test_split_table_requests() {
+        let mut req = uniform_batch(2, 3, 1);
+        for (table, rows) in [("medium", 3), ("small", 1)] {
+            for i in 0..rows {
+                let point = PointBuilder::new(table.to_string())
+                    .timestamp(1_600_000_000_000 + i)
+                    .field("value".to_string(), Value::Int64(i))
+                    .build()
+                    .unwrap();
+                req.add_point(point);
+            }
+        }
+        let mut table_requests = WriteTableRequestPbsBuilder(req).build().unwrap();
+        table_requests.sort_by(|a, b| a.table.cmp(&b.table));
+        let uniform = table_requests[2].clone();
+
+        let sub_requests = split_table_requests(table_requests, 4);
+        let tables: Vec<Vec<_>> = sub_requests
+            .iter()
+            .map(|sub_request| sub_request.iter().map(|t| t.table.as_str()).collect())
+            .collect();
+        assert_eq!(
+            tables,
+            vec![vec!["medium", "small"], vec!["uniform"], vec!["uniform"]]
+        );
+        let rows: Vec<_> = sub_requests.iter().map(|s| row_count(s)).collect();
+        assert_eq!(rows, vec![4, 4, 2]);
+
+        // The parts of the split table keep its schema and rows.
+        let parts: Vec<_> = sub_requests[1..].iter().map(|s| s[0].clone()).collect();
+        assert!(parts
+            .iter()
+            .all(|part| part.tag_names == uniform.tag_names
+                && part.field_names == uniform.field_names));
+        let split_points: Vec<_> = parts
+            .into_iter()
+            .flat_map(|part| points_from_pb(part).unwrap())
+            .collect();
+        assert_eq!(split_points, points_from_pb(uniform).unwrap());
+
+        // No split within the limit.
+        let table_requests = WriteTableRequestPbsBuilder(uniform_batch(2, 3, 1))
+            .build()
+            .unwrap();
+        assert_eq!(
+            split_table_requests(table_requests.clone(), 6),
+            vec![table_requests]
+        );
+    }
 }
@@ -2,6 +2,8 @@
 
 use ceresdbproto::storage::WriteResponse as WriteResponsePb;
 
+use crate::model::{execution_info::ExecutionInfo, warning::ServerWarning};
+
 /// The response for the [`WriteRequest`](crate::model::write::Request).
 #[derive(Clone, Debug)]
 pub struct Response {
@@ -9,19 +11,159 @@ pub struct Response {
     pub success: u32,
     /// The number of the rows which fail to write
     pub failed: u32,
+    /// The number of the points dropped by the validation in
+    /// [`ValidationMode::Warn`](crate::model::write::ValidationMode::Warn)
+    pub dropped: u32,
+    /// The number of the duplicate points dropped by the
+    /// [`Normalization`](crate::model::write::Normalization)
+    pub deduplicated: u32,
+    /// The execution info of the write measured by the client
+    pub execution_info: ExecutionInfo,
+    /// The partitions retried in the cluster mode, and only the response of
+    /// the last attempt of every partition is counted in the response.
+    pub retried_partitions: Vec<RetriedPartition>,
+    /// The warnings reported by the server, including the ones of all the
+    /// partitions in the cluster mode.
+    pub warnings: Vec<ServerWarning>,
+}
+
+/// Partition of the write sent to an endpoint and retried.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RetriedPartition {
+    pub endpoint: String,
+    pub tables: Vec<String>,
+    /// The number of the attempts, including the first one.
+    pub attempts: usize,
 }
 
 impl Response {
     pub fn new(success: u32, failed: u32) -> Self {
-        Self { success, failed }
+        Self {
+            success,
+            failed,
+            dropped: 0,
+            deduplicated: 0,
+            execution_info: ExecutionInfo::default(),
+            retried_partitions: Vec::new(),
+            warnings: Vec::new(),
+        }
+    }
+
+    /// Whether the server warns that the write is partially applied.
+    pub fn is_partial(&self) -> bool {
+        self.warnings.iter().any(ServerWarning::is_partial)
+    }
+
+    /// Merge the responses of the writes sent concurrently, e.g. the
+    /// partitions of a write split across the endpoints.
+    ///
+    /// The counts are summed, the retried partitions and the warnings are
+    /// concatenated, and the execution info is merged with the execution info
+    /// of every response as a partition, whose latency is the max one.
+    pub fn merge(responses: impl IntoIterator<Item = Response>) -> Self {
+        Self::combine(responses, ExecutionInfo::merge)
+    }
+
+    /// Concatenate the responses of the writes sent one after another, e.g.
+    /// the requests of a write stream.
+    ///
+    /// Same as [`merge`](Self::merge) except that the latencies are summed.
+    pub(crate) fn concat(responses: impl IntoIterator<Item = Response>) -> Self {
+        Self::combine(responses, ExecutionInfo::concat)
+    }
+
+    fn combine(
+        responses: impl IntoIterator<Item = Response>,
+        combine_execution_infos: fn(Vec<ExecutionInfo>) -> ExecutionInfo,
+    ) -> Self {
+        let mut combined = Response::new(0, 0);
+        let mut execution_infos = Vec::new();
+        for resp in responses {
+            // Destructured to combine the new fields as well.
+            let Response {
+                success,
+                failed,
+                dropped,
+                deduplicated,
+                execution_info,
+                retried_partitions,
+                warnings,
+            } = resp;
+            combined.success += success;
+            combined.failed += failed;
+            combined.dropped += dropped;
+            combined.deduplicated += deduplicated;
+            combined.retried_partitions.extend(retried_partitions);
+            combined.warnings.extend(warnings);
+            execution_infos.push(execution_info);
+        }
+        combined.execution_info = combine_execution_infos(execution_infos);
+
+        combined
     }
 }
 
 impl From<WriteResponsePb> for Response {
     fn from(resp_pb: WriteResponsePb) -> Self {
-        Response {
-            success: resp_pb.success,
-            failed: resp_pb.failed,
-        }
+        Response::new(resp_pb.success, resp_pb.failed)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::{Response, RetriedPartition};
+    use crate::model::{execution_info::ExecutionInfo, route::Endpoint};
+
+    #[test]
+    fn test_merge() {
+        let mut resp1 = Response::new(3, 1);
+        resp1.dropped = 2;
+        resp1.execution_info = ExecutionInfo {
+            endpoint: Some(Endpoint::new("192.168.0.1".to_string(), 11)),
+            request_bytes: 100,
+            response_bytes: 10,
+            latency: Duration::from_millis(20),
+            retries: 1,
+            partitions: Vec::new(),
+        };
+        resp1.retried_partitions = vec![RetriedPartition {
+            endpoint: "192.168.0.1:11".to_string(),
+            tables: vec!["table1".to_string()],
+            attempts: 2,
+        }];
+        let mut resp2 = Response::new(5, 0);
+        resp2.execution_info = ExecutionInfo {
+            endpoint: Some(Endpoint::new("192.168.0.2".to_string(), 12)),
+            request_bytes: 200,
+            response_bytes: 10,
+            latency: Duration::from_millis(30),
+            retries: 0,
+            partitions: Vec::new(),
+        };
+
+        let merged = Response::merge([resp1.clone(), resp2.clone()]);
+        assert_eq!((merged.success, merged.failed, merged.dropped), (8, 1, 2));
+        assert_eq!(merged.retried_partitions, resp1.retried_partitions);
+        let info = &merged.execution_info;
+        assert_eq!(info.endpoint, None);
+        assert_eq!((info.request_bytes, info.response_bytes), (300, 20));
+        assert_eq!((info.latency, info.retries), (Duration::from_millis(30), 1));
+        assert_eq!(
+            info.partitions,
+            vec![resp1.execution_info.clone(), resp2.execution_info.clone()]
+        );
+
+        // The latencies of the responses one after another are summed.
+        let concatenated = Response::concat([resp1.clone(), resp2]);
+        let info = &concatenated.execution_info;
+        assert_eq!(concatenated.retried_partitions, resp1.retried_partitions);
+        assert_eq!((info.latency, info.retries), (Duration::from_millis(50), 1));
+        assert_eq!(info.partitions.len(), 2);
+
+        let merged = Response::merge(Vec::new());
+        assert_eq!((merged.success, merged.failed, merged.dropped), (0, 0, 0));
+        assert!(merged.execution_info.partitions.is_empty());
     }
 }
@@ -0,0 +1,531 @@
+// Copyright 2022 CeresDB Project Authors. Licensed under Apache-2.0.
+
+//! Backends of the route cache of the [`RouterImpl`](crate::router::RouterImpl)
+
+use std::{
+    fmt, fs, io,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, PoisonError, Weak,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use dashmap::{mapref::entry::Entry, DashMap};
+
+use crate::{
+    config::FileRouteCacheConfig,
+    model::route::Endpoint,
+    router::{next_route_generation, RouteGeneration},
+    Error, Result,
+};
+
+/// Key of the route cache: (database, table).
+pub type RouteKey = (String, String);
+
+/// Cached candidate endpoints, the routing epoch when they are fetched and the
+/// generation of the entry, and the imported entry expires at `expire_at`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RouteEntry {
+    /// Never empty, and the first one is the leader, see
+    /// [`Route`](crate::model::route::Route).
+    pub endpoints: Vec<Endpoint>,
+    pub epoch: u64,
+    pub generation: RouteGeneration,
+    pub expire_at: Option<Instant>,
+}
+
+impl RouteEntry {
+    #[inline]
+    pub(crate) fn is_outdated(&self, current_epoch: u64, now: Instant) -> bool {
+        self.epoch < current_epoch || matches!(self.expire_at, Some(expire_at) if expire_at <= now)
+    }
+}
+
+/// Storage of the routes cached by the [`RouterImpl`], e.g. to share the
+/// routes between the processes so that a fleet restart doesn't trigger a
+/// storm of the route rpcs.
+///
+/// The entries are read and written in batches, so the routing of the tables
+/// all found in the cache is done by one [`get_batch`](Self::get_batch). The
+/// router decides whether an entry is outdated, and the cache just stores
+/// them.
+///
+/// [`RouterImpl`]: crate::router::RouterImpl
+pub trait RouteCache: fmt::Debug + Send + Sync {
+    /// Get the entries of the `keys` in order, and none for the missing ones.
+    fn get_batch(&self, keys: &[RouteKey]) -> Vec<Option<RouteEntry>>;
+
+    /// Insert the `entries`, which replace the cached ones of the same keys.
+    fn insert_batch(&self, entries: Vec<(RouteKey, RouteEntry)>);
+
+    /// Insert the `entry` if the `key` is not cached or `replace` holds for
+    /// the cached one, and whether it is inserted is returned.
+    fn insert_if(
+        &self,
+        key: RouteKey,
+        entry: RouteEntry,
+        replace: &dyn Fn(&RouteEntry) -> bool,
+    ) -> bool;
+
+    /// Remove the entry of the `key` only if `remove` holds for it, and
+    /// whether it is removed is returned.
+    fn remove_if(&self, key: &RouteKey, remove: &dyn Fn(&RouteEntry) -> bool) -> bool;
+
+    /// Remove the entry of the `key`, and whether it is cached is returned.
+    fn remove(&self, key: &RouteKey) -> bool {
+        self.remove_if(key, &|_| true)
+    }
+
+    /// Get the number of the cached entries.
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Remove all the entries.
+    fn clear(&self);
+
+    /// Get all the cached entries in no particular order.
+    fn entries(&self) -> Vec<(RouteKey, RouteEntry)>;
+
+    /// Get the number of the shards the cache is split into to reduce the
+    /// lock contention, and it is 1 if the cache isn't sharded.
+    fn shard_amount(&self) -> usize {
+        1
+    }
+}
+
+/// The default [`RouteCache`] in the memory of the process, which is a
+/// `DashMap` split into shards.
+#[derive(Debug)]
+pub struct MemoryRouteCache {
+    entries: DashMap<RouteKey, RouteEntry>,
+    shard_amount: usize,
+}
+
+impl MemoryRouteCache {
+    /// Split the cache into `shard_amount` shards, which is rounded up to a
+    /// power of two and at least 2.
+    pub fn with_shard_amount(shard_amount: usize) -> Self {
+        let shard_amount = shard_amount.max(2).next_power_of_two();
+        Self {
+            entries: DashMap::with_shard_amount(shard_amount),
+            shard_amount,
+        }
+    }
+}
+
+impl Default for MemoryRouteCache {
+    /// Split the cache into 4 times the number of the cpu cores, the same as
+    /// the default of the `DashMap`.
+    fn default() -> Self {
+        let cores = std::thread::available_parallelism().map_or(1, usize::from);
+        Self::with_shard_amount(cores * 4)
+    }
+}
+
+impl RouteCache for MemoryRouteCache {
+    fn get_batch(&self, keys: &[RouteKey]) -> Vec<Option<RouteEntry>> {
+        keys.iter()
+            .map(|key| self.entries.get(key).map(|entry| entry.value().clone()))
+            .collect()
+    }
+
+    fn insert_batch(&self, entries: Vec<(RouteKey, RouteEntry)>) {
+        for (key, entry) in entries {
+            self.entries.insert(key, entry);
+        }
+    }
+
+    fn insert_if(
+        &self,
+        key: RouteKey,
+        entry: RouteEntry,
+        replace: &dyn Fn(&RouteEntry) -> bool,
+    ) -> bool {
+        match self.entries.entry(key) {
+            Entry::Occupied(mut cached) => {
+                if replace(cached.get()) {
+                    cached.insert(entry);
+                    return true;
+                }
+                false
+            }
+            Entry::Vacant(vacant) => {
+                vacant.insert(entry);
+                true
+            }
+        }
+    }
+
+    fn remove_if(&self, key: &RouteKey, remove: &dyn Fn(&RouteEntry) -> bool) -> bool {
+        self.entries
+            .remove_if(key, |_, entry| remove(entry))
+            .is_some()
+    }
+
+    fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    fn clear(&self) {
+        self.entries.clear();
+    }
+
+    fn entries(&self) -> Vec<(RouteKey, RouteEntry)> {
+        self.entries
+            .iter()
+            .map(|entry| (entry.key().clone(), entry.value().clone()))
+            .collect()
+    }
+
+    fn shard_amount(&self) -> usize {
+        self.shard_amount
+    }
+}
+
+/// Magic of the snapshot file of the [`FileRouteCache`].
+const SNAPSHOT_MAGIC: &[u8; 4] = b"CRRC";
+const SNAPSHOT_VERSION: u8 = 1;
+/// The persisted ttl of the entry never expiring.
+const NO_EXPIRATION: u64 = u64::MAX;
+
+/// [`RouteCache`] in the memory persisted to a local file, so the routes are
+/// kept across the restarts of the process.
+///
+/// The changed routes are flushed to the file periodically in the background
+/// and on drop, and the snapshot in the file is loaded on open if it is not
+/// older than the [`max_staleness`](FileRouteCacheConfig::max_staleness).
+/// The loaded routes expire once they are that old, and they are outdated
+/// by the newer routing epochs like the fetched ones.
+///
+/// The snapshot is the magic `CRRC`, the version byte, the saving time in
+/// milliseconds since the unix epoch and the entries, and every entry is the
+/// database, the table, the epoch, the remaining ttl in milliseconds and the
+/// endpoints. All the integers are big-endian, and the strings and the lists
+/// are prefixed by their lengths as the `u32`.
+#[derive(Debug)]
+pub struct FileRouteCache {
+    cache: MemoryRouteCache,
+    path: PathBuf,
+    dirty: AtomicBool,
+    // Serialize the flushes.
+    flush_lock: Mutex<()>,
+}
+
+impl FileRouteCache {
+    /// Open the cache persisted to the file at `path`, which is created on
+    /// the first flush if not exists.
+    ///
+    /// The periodic flushing is started only if it is called in a tokio
+    /// runtime, otherwise the cache is flushed by [`flush`](Self::flush) and
+    /// on drop. It fails if the file can't be read or is malformed.
+    pub fn open(path: impl Into<PathBuf>, config: FileRouteCacheConfig) -> Result<Arc<Self>> {
+        let cache = Arc::new(Self {
+            cache: MemoryRouteCache::default(),
+            path: path.into(),
+            dirty: AtomicBool::new(false),
+            flush_lock: Mutex::new(()),
+        });
+        cache.load(config.max_staleness)?;
+
+        if !config.flush_interval.is_zero() {
+            if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+                runtime.spawn(flush_periodically(
+                    Arc::downgrade(&cache),
+                    config.flush_interval,
+                ));
+            }
+        }
+
+        Ok(cache)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Write the cached routes to the file if they are changed since the last
+    /// flush, and the file is replaced as a whole.
+    pub fn flush(&self) -> Result<()> {
+        let _guard = self
+            .flush_lock
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if !self.dirty.swap(false, Ordering::AcqRel) {
+            return Ok(());
+        }
+
+        let result = self.write_snapshot();
+        if result.is_err() {
+            self.dirty.store(true, Ordering::Release);
+        }
+        result
+    }
+
+    fn write_snapshot(&self) -> Result<()> {
+        let snapshot = encode_snapshot(&self.cache.entries(), SystemTime::now(), Instant::now());
+        let mut tmp_path = self.path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        fs::write(&tmp_path, snapshot)
+            .and_then(|_| fs::rename(&tmp_path, &self.path))
+            .map_err(|e| {
+                Error::Client(format!(
+                    "failed to write route cache file, path:{}, err:{e}",
+                    self.path.display()
+                ))
+            })
+    }
+
+    fn load(&self, max_staleness: Duration) -> Result<()> {
+        let bytes = match fs::read(&self.path) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => {
+                return Err(Error::Client(format!(
+                    "failed to read route cache file, path:{}, err:{e}",
+                    self.path.display()
+                )))
+            }
+        };
+        let snapshot = decode_snapshot(&bytes).map_err(|e| {
+            Error::Client(format!(
+                "malformed route cache file, path:{}, err:{e}",
+                self.path.display()
+            ))
+        })?;
+
+        // The snapshot saved in the future is regarded as fresh.
+        let age = SystemTime::now()
+            .duration_since(snapshot.saved_at)
+            .unwrap_or_default();
+        let remaining = match max_staleness.checked_sub(age) {
+            Some(remaining) if !remaining.is_zero() => remaining,
+            _ => return Ok(()),
+        };
+        let now = Instant::now();
+        let entries = snapshot
+            .entries
+            .into_iter()
+            .map(|(key, endpoints, epoch, ttl)| {
+                let ttl = ttl.map_or(remaining, |ttl| ttl.min(remaining));
+                let entry = RouteEntry {
+                    endpoints,
+                    epoch,
+                    generation: next_route_generation(),
+                    expire_at: Some(now + ttl),
+                };
+                (key, entry)
+            })
+            .collect();
+        self.cache.insert_batch(entries);
+
+        Ok(())
+    }
+
+    #[inline]
+    fn mark_dirty(&self, changed: bool) -> bool {
+        if changed {
+            self.dirty.store(true, Ordering::Release);
+        }
+        changed
+    }
+}
+
+impl Drop for FileRouteCache {
+    fn drop(&mut self) {
+        if let Err(_e) = self.flush() {
+            #[cfg(feature = "tracing")]
+            tracing::warn!(error = %_e, "failed to flush the route cache on drop");
+        }
+    }
+}
+
+impl RouteCache for FileRouteCache {
+    fn get_batch(&self, keys: &[RouteKey]) -> Vec<Option<RouteEntry>> {
+        self.cache.get_batch(keys)
+    }
+
+    fn insert_batch(&self, entries: Vec<(RouteKey, RouteEntry)>) {
+        self.mark_dirty(!entries.is_empty());
+        self.cache.insert_batch(entries);
+    }
+
+    fn insert_if(
+        &self,
+        key: RouteKey,
+        entry: RouteEntry,
+        replace: &dyn Fn(&RouteEntry) -> bool,
+    ) -> bool {
+        let inserted = self.cache.insert_if(key, entry, replace);
+        self.mark_dirty(inserted)
+    }
+
+    fn remove_if(&self, key: &RouteKey, remove: &dyn Fn(&RouteEntry) -> bool) -> bool {
+        let removed = self.cache.remove_if(key, remove);
+        self.mark_dirty(removed)
+    }
+
+    fn len(&self) -> usize {
+        self.cache.len()
+    }
+
+    fn clear(&self) {
+        self.mark_dirty(!self.cache.is_empty());
+        self.cache.clear();
+    }
+
+    fn entries(&self) -> Vec<(RouteKey, RouteEntry)> {
+        self.cache.entries()
+    }
+
+    fn shard_amount(&self) -> usize {
+        self.cache.shard_amount()
+    }
+}
+
+/// Flush the `cache` every `interval` until it is dropped.
+async fn flush_periodically(cache: Weak<FileRouteCache>, interval: Duration) {
+    loop {
+        tokio::time::sleep(interval).await;
+        let cache = match cache.upgrade() {
+            Some(cache) => cache,
+            None => return,
+        };
+        let flushed = tokio::task::spawn_blocking(move || cache.flush()).await;
+        if let Ok(Err(_e)) = flushed {
+            #[cfg(feature = "tracing")]
+            tracing::warn!(error = %_e, "failed to flush the route cache");
+        }
+    }
+}
+
+/// Routes decoded from the snapshot file, and every entry is the key, the
+/// endpoints, the epoch and the remaining ttl.
+struct Snapshot {
+    saved_at: SystemTime,
+    entries: Vec<(RouteKey, Vec<Endpoint>, u64, Option<Duration>)>,
+}
+
+fn encode_snapshot(
+    entries: &[(RouteKey, RouteEntry)],
+    saved_at: SystemTime,
+    now: Instant,
+) -> Vec<u8> {
+    fn put_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+        buf.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+        buf.extend_from_slice(bytes);
+    }
+
+    // The expired entries are not persisted.
+    let entries: Vec<_> = entries
+        .iter()
+        .filter(|(_, entry)| !matches!(entry.expire_at, Some(expire_at) if expire_at <= now))
+        .collect();
+    let saved_at = saved_at
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64;
+
+    let mut buf = Vec::new();
+    buf.extend_from_slice(SNAPSHOT_MAGIC);
+    buf.push(SNAPSHOT_VERSION);
+    buf.extend_from_slice(&saved_at.to_be_bytes());
+    buf.extend_from_slice(&(entries.len() as u32).to_be_bytes());
+    for ((database, table), entry) in entries {
+        put_bytes(&mut buf, database.as_bytes());
+        put_bytes(&mut buf, table.as_bytes());
+        buf.extend_from_slice(&entry.epoch.to_be_bytes());
+        let ttl = match entry.expire_at {
+            Some(expire_at) => (expire_at - now).as_millis() as u64,
+            None => NO_EXPIRATION,
+        };
+        buf.extend_from_slice(&ttl.to_be_bytes());
+        buf.extend_from_slice(&(entry.endpoints.len() as u32).to_be_bytes());
+        for endpoint in &entry.endpoints {
+            put_bytes(&mut buf, endpoint.addr.as_bytes());
+            buf.extend_from_slice(&endpoint.port.to_be_bytes());
+        }
+    }
+
+    buf
+}
+
+fn decode_snapshot(bytes: &[u8]) -> io::Result<Snapshot> {
+    let mut reader = SnapshotReader(bytes);
+    if reader.take(SNAPSHOT_MAGIC.len())? != SNAPSHOT_MAGIC {
+        return Err(invalid_data("unknown magic".to_string()));
+    }
+    let version = reader.take(1)?[0];
+    if version != SNAPSHOT_VERSION {
+        return Err(invalid_data(format!("unknown version:{version}")));
+    }
+    let saved_at = UNIX_EPOCH + Duration::from_millis(reader.u64()?);
+
+    let count = reader.u32()?;
+    let mut entries = Vec::new();
+    for _ in 0..count {
+        let database = reader.string()?;
+        let table = reader.string()?;
+        let epoch = reader.u64()?;
+        let ttl = match reader.u64()? {
+            NO_EXPIRATION => None,
+            ttl => Some(Duration::from_millis(ttl)),
+        };
+        let endpoint_count = reader.u32()?;
+        let mut endpoints = Vec::new();
+        for _ in 0..endpoint_count {
+            let addr = reader.string()?;
+            let port = reader.u32()?;
+            endpoints.push(Endpoint::new(addr, port));
+        }
+        if endpoints.is_empty() {
+            return Err(invalid_data(format!("no endpoint of table:{table}")));
+        }
+        entries.push(((database, table), endpoints, epoch, ttl));
+    }
+    if !reader.0.is_empty() {
+        return Err(invalid_data("trailing bytes".to_string()));
+    }
+
+    Ok(Snapshot { saved_at, entries })
+}
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Reader of the snapshot checking the lengths against the remaining bytes,
+/// so the corrupted lengths fail instead of allocating too much.
+struct SnapshotReader<'a>(&'a [u8]);
+
+impl<'a> SnapshotReader<'a> {
+    fn take(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        if len > self.0.len() {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+        }
+        let (head, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(head)
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        let mut buf = [0; 4];
+        buf.copy_from_slice(self.take(4)?);
+        Ok(u32::from_be_bytes(buf))
+    }
+
+    fn u64(&mut self) -> io::Result<u64> {
+        let mut buf = [0; 8];
+        buf.copy_from_slice(self.take(8)?);
+        Ok(u64::from_be_bytes(buf))
+    }
+
+    fn string(&mut self) -> io::Result<String> {
+        let len = self.u32()? as usize;
+        String::from_utf8(self.take(len)?.to_vec()).map_err(|e| invalid_data(e.to_string()))
+    }
+}
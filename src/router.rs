@@ -2,173 +2,1670 @@
 
 //! [Router] in client
 
-use std::{collections::HashMap, sync::Arc};
+#![cfg_attr(not(test), deny(clippy::unwrap_used))]
+
+use std::{
+    collections::{hash_map::RandomState, HashMap, HashSet},
+    fmt,
+    hash::{BuildHasher, Hasher},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, PoisonError, RwLock,
+    },
+    time::Duration,
+};
 
 use async_trait::async_trait;
 use ceresdbproto::storage::{self, RouteRequest};
-use dashmap::DashMap;
+use dashmap::{mapref::entry::Entry, DashMap};
 
 use crate::{
+    clock::{Clock, SystemClock},
+    config::LoadBalancePolicy,
     errors::Result,
-    model::route::Endpoint,
-    rpc_client::{RpcClient, RpcContext},
+    metrics::ClientMetrics,
+    model::route::{Endpoint, Route, TableRoute},
+    route_cache::{MemoryRouteCache, RouteCache, RouteEntry, RouteKey},
+    rpc_client::{RouteResponse, RpcClient, RpcContext},
+    util::record_span_outcome,
     Error,
 };
 
+/// The default endpoints of the cluster, which are swapped as a whole when
+/// they are rediscovered, so the requests in flight keep using the set they
+/// have loaded.
+///
+/// The first one is the primary, to which the route rpcs and the tables
+/// unknown to the route service go.
+#[derive(Debug, Default)]
+pub(crate) struct DefaultEndpoints {
+    endpoints: RwLock<Arc<Vec<Endpoint>>>,
+}
+
+impl DefaultEndpoints {
+    pub fn new(endpoints: Vec<Endpoint>) -> Self {
+        Self {
+            endpoints: RwLock::new(Arc::new(endpoints)),
+        }
+    }
+
+    #[inline]
+    pub fn load(&self) -> Arc<Vec<Endpoint>> {
+        self.endpoints
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    #[inline]
+    pub fn primary(&self) -> Option<Endpoint> {
+        self.load().first().cloned()
+    }
+
+    /// Replace the endpoints, and the removed ones are returned.
+    pub fn swap(&self, endpoints: Vec<Endpoint>) -> Vec<Endpoint> {
+        let old = std::mem::replace(
+            &mut *self
+                .endpoints
+                .write()
+                .unwrap_or_else(PoisonError::into_inner),
+            Arc::new(endpoints),
+        );
+        let new = self.load();
+        old.iter()
+            .filter(|endpoint| !new.contains(endpoint))
+            .cloned()
+            .collect()
+    }
+}
+
+/// Generation of a cached route, which is bumped on every insertion into the
+/// cache and unique among all the routers.
+pub type RouteGeneration = u64;
+
+static NEXT_ROUTE_GENERATION: AtomicU64 = AtomicU64::new(0);
+
+#[inline]
+pub(crate) fn next_route_generation() -> RouteGeneration {
+    NEXT_ROUTE_GENERATION.fetch_add(1, Ordering::Relaxed)
+}
+
 /// Used to route tables to endpoints.
 #[async_trait]
 pub trait Router: Send + Sync {
-    async fn route(&self, tables: &[String], ctx: &RpcContext) -> Result<Vec<Option<Endpoint>>>;
+    async fn route(&self, tables: &[String], ctx: &RpcContext) -> Result<Vec<Option<Endpoint>>> {
+        let routes = self.route_tables(tables, ctx, false).await?;
+        Ok(routes.into_iter().map(TableRoute::into_endpoint).collect())
+    }
+
+    /// Route the single `table` like [`route`](Router::route).
+    async fn route_one(&self, table: &str, ctx: &RpcContext) -> Result<Option<Endpoint>> {
+        let mut routes = self.route(&[table.to_string()], ctx).await?;
+        Ok(routes.pop().flatten())
+    }
+
+    /// Route the tables, and the routes are fetched from remote and
+    /// repopulated into the cache without reading the cache if
+    /// `force_refresh` is set. The cached routes of the tables no longer
+    /// routed by the server are removed when refreshing.
+    ///
+    /// The routes are in the order of the `tables`, including the duplicated
+    /// ones, and the empty table name is rejected before any rpc.
+    async fn route_tables(
+        &self,
+        tables: &[String],
+        ctx: &RpcContext,
+        force_refresh: bool,
+    ) -> Result<Vec<TableRoute>> {
+        let routes = self
+            .route_tables_with_generations(tables, ctx, force_refresh)
+            .await?;
+        Ok(routes.into_iter().map(|(route, _)| route).collect())
+    }
+
+    /// Route the tables like [`route_tables`](Router::route_tables), and the
+    /// generation of the cached route is returned with every route, which is
+    /// none if the route is not cached.
+    ///
+    /// The generations can be passed to
+    /// [`evict_if_stale`](Router::evict_if_stale) if the routes turn out to be
+    /// outdated.
+    async fn route_tables_with_generations(
+        &self,
+        tables: &[String],
+        ctx: &RpcContext,
+        force_refresh: bool,
+    ) -> Result<Vec<(TableRoute, Option<RouteGeneration>)>>;
+
+    /// Route the tables through the cache like
+    /// [`route_tables`](Router::route_tables), and all the replicas of the
+    /// tables routed by the route service are returned in the order of the
+    /// `tables`, none for the ones routed to the default endpoint or not
+    /// routed.
+    ///
+    /// The router not tracking the replicas returns the endpoint it routes to
+    /// as the only replica.
+    async fn route_replicas(
+        &self,
+        tables: &[String],
+        ctx: &RpcContext,
+    ) -> Result<Vec<Option<Route>>> {
+        let routes = self.route_tables(tables, ctx, false).await?;
+        let replicas = tables
+            .iter()
+            .zip(routes)
+            .map(|(table, route)| match route {
+                TableRoute::Routed(endpoint) => {
+                    Some(Route::from_candidates(table.clone(), vec![endpoint]))
+                }
+                TableRoute::Default(_) | TableRoute::NoRoute => None,
+            })
+            .collect();
+
+        Ok(replicas)
+    }
+
+    /// Re-resolve the `table` and update its cached route in place.
+    ///
+    /// Return the new endpoint, and none if the table is not routed by the
+    /// server, in which case the cached route is removed.
+    async fn refresh(&self, table: &str, ctx: &RpcContext) -> Result<Option<Endpoint>> {
+        let routes = self.route_tables(&[table.to_string()], ctx, true).await?;
+        match routes.into_iter().next() {
+            Some(TableRoute::Routed(endpoint)) => Ok(Some(endpoint)),
+            _ => Ok(None),
+        }
+    }
+
+    fn evict(&self, tables: &[String], ctx: &RpcContext);
+
+    /// Evict the cached route of the `table` only if it is still of the
+    /// `generation`, so that the route refreshed by others won't be evicted.
+    fn evict_if_stale(&self, table: &str, generation: RouteGeneration, ctx: &RpcContext);
+
+    /// Evict the cached routes of all the tables in all the databases.
+    fn evict_all(&self);
+
+    /// Evict the cached routes of all the tables in all the databases whose
+    /// candidate endpoints include the `endpoint`, e.g. when the node is taken
+    /// out of service, and the number of the evicted routes is returned.
+    fn evict_by_endpoint(&self, endpoint: &Endpoint) -> usize;
+
+    /// Get the distinct endpoints in the route cache, including the default
+    /// endpoint.
+    fn cached_endpoints(&self) -> Vec<Endpoint>;
+
+    /// Resolve the endpoint of the `table` by a fresh route rpc, and the cache
+    /// is neither read nor updated.
+    ///
+    /// The default endpoint is not used, and none is returned if the table is
+    /// not routed by the server.
+    async fn resolve_uncached(&self, table: &str, ctx: &RpcContext) -> Result<Option<Endpoint>>;
+}
+
+/// Function deriving the related tables of a table.
+type RelatedTablesFn = Arc<dyn Fn(&str) -> Vec<String> + Send + Sync>;
+
+/// Derive the tables related to a table, whose routes are prefetched along
+/// with the table on the cache miss.
+#[derive(Clone)]
+pub struct RelatedTables(RelatedTablesFn);
+
+impl RelatedTables {
+    pub fn new(related: impl Fn(&str) -> Vec<String> + Send + Sync + 'static) -> Self {
+        Self(Arc::new(related))
+    }
+}
+
+impl fmt::Debug for RelatedTables {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("RelatedTables")
+    }
+}
+
+/// Function called with the table, its old endpoint and its new endpoint.
+type RouteChangeFn = Arc<dyn Fn(&str, Option<Endpoint>, Endpoint) + Send + Sync>;
+
+/// Hook called when a route refresh finds a table placed on another
+/// endpoint, e.g. after a rebalance or a node failure, see
+/// [`Builder::on_route_changed`](crate::Builder::on_route_changed).
+///
+/// The hook keeps the last endpoint observed of every table beyond the route
+/// cache, so the table evicted and routed to the same endpoint again isn't
+/// changed, and neither is the first route of a table. The old endpoint is
+/// none if the table wasn't routed by the server at the last refresh. The
+/// endpoint of a table with multiple candidates is the first one.
+///
+/// At most `max_tables` tables are kept, and once there are more, only the
+/// ones observed by the latest half of `max_tables` routes are kept. The
+/// next route of a forgotten table is taken as its first one.
+///
+/// The clones share the observed endpoints and the count of the changes.
+#[derive(Clone)]
+pub struct RouteChangeHook {
+    hook: RouteChangeFn,
+    placements: Arc<DashMap<RouteKey, Placement>>,
+    max_tables: usize,
+    // The sequence of the observations, by which the placements are aged.
+    observations: Arc<AtomicU64>,
+    changes: Arc<AtomicU64>,
+}
+
+/// The last endpoint observed of a table, and none if not routed.
+struct Placement {
+    endpoint: Option<Endpoint>,
+    observed: u64,
+}
+
+const DEFAULT_ROUTE_CHANGE_MAX_TABLES: usize = 65536;
+
+impl RouteChangeHook {
+    /// Call `on_route_changed(table, old, new)` on every change.
+    pub fn new(
+        on_route_changed: impl Fn(&str, Option<Endpoint>, Endpoint) + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            hook: Arc::new(on_route_changed),
+            placements: Arc::new(DashMap::new()),
+            max_tables: DEFAULT_ROUTE_CHANGE_MAX_TABLES,
+            observations: Arc::new(AtomicU64::new(0)),
+            changes: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Keep the placements of at most `max_tables` tables, and the default is
+    /// 65536.
+    pub fn with_max_tables(mut self, max_tables: usize) -> Self {
+        self.max_tables = max_tables.max(1);
+        self
+    }
+
+    /// Get the number of the route changes observed.
+    pub fn changes(&self) -> u64 {
+        self.changes.load(Ordering::Relaxed)
+    }
+
+    /// Observe the `endpoint` of the table while the route is inserted into
+    /// the cache by `insert`, so the concurrent refreshes of the table see
+    /// the changes one by one, and the hook is called once per change.
+    fn observe(&self, key: RouteKey, endpoint: Endpoint, insert: impl FnOnce()) {
+        let table = key.1.clone();
+        let observed = self.observations.fetch_add(1, Ordering::Relaxed);
+        let old = match self.placements.entry(key) {
+            Entry::Occupied(mut placement) => {
+                insert();
+                let placement = placement.get_mut();
+                placement.observed = observed;
+                if placement.endpoint.as_ref() == Some(&endpoint) {
+                    return;
+                }
+                placement.endpoint.replace(endpoint.clone())
+            }
+            Entry::Vacant(placement) => {
+                insert();
+                placement.insert(Placement {
+                    endpoint: Some(endpoint),
+                    observed,
+                });
+                // Pruned without the lock of the placement.
+                self.prune(observed);
+                return;
+            }
+        };
+
+        // Called without the lock of the placement.
+        self.changes.fetch_add(1, Ordering::Relaxed);
+        (self.hook)(&table, old, endpoint);
+    }
+
+    /// Observe that the table is no longer routed by the server.
+    fn observe_unrouted(&self, key: &RouteKey) {
+        if let Some(mut placement) = self.placements.get_mut(key) {
+            placement.endpoint = None;
+        }
+    }
+
+    /// Forget the tables not observed by the latest half of `max_tables`
+    /// observations if there are more than `max_tables` tables, so the
+    /// pruning is amortized over the observations.
+    fn prune(&self, observed: u64) {
+        if self.placements.len() <= self.max_tables {
+            return;
+        }
+        let kept = (self.max_tables / 2) as u64;
+        self.placements
+            .retain(|_, placement| placement.observed + kept > observed);
+    }
+}
+
+impl fmt::Debug for RouteChangeHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RouteChangeHook")
+            .field("changes", &self.changes())
+            .finish()
+    }
+}
+
+/// Count the requests in flight to an endpoint, by which the
+/// [`LeastConnections`](LoadBalancePolicy::LeastConnections) policy picks.
+#[derive(Clone)]
+pub(crate) struct InFlightCounter(Arc<dyn Fn(&Endpoint) -> usize + Send + Sync>);
+
+impl InFlightCounter {
+    pub fn new(count: impl Fn(&Endpoint) -> usize + Send + Sync + 'static) -> Self {
+        Self(Arc::new(count))
+    }
+}
+
+/// Tell whether an endpoint is healthy, and the unhealthy candidates are
+/// picked only if all of them are unhealthy.
+#[derive(Clone)]
+pub(crate) struct HealthFilter(Arc<dyn Fn(&Endpoint) -> bool + Send + Sync>);
+
+impl HealthFilter {
+    pub fn new(is_healthy: impl Fn(&Endpoint) -> bool + Send + Sync + 'static) -> Self {
+        Self(Arc::new(is_healthy))
+    }
+}
+
+/// Picker of the endpoint among the candidates of a table by the policy.
+struct LoadBalancer {
+    policy: LoadBalancePolicy,
+    // Shared by all the tables for the round robin and the random.
+    next: AtomicUsize,
+    // The least connections policy picks the first candidate without it.
+    in_flight: Option<InFlightCounter>,
+    health: Option<HealthFilter>,
+}
+
+impl LoadBalancer {
+    fn select(&self, candidates: &[Endpoint]) -> Endpoint {
+        if candidates.len() == 1 {
+            return candidates[0].clone();
+        }
 
-    fn evict(&self, tables: &[String]);
+        let healthy: Vec<_>;
+        let candidates = match &self.health {
+            Some(health) if candidates.iter().any(|endpoint| !(health.0)(endpoint)) => {
+                healthy = candidates
+                    .iter()
+                    .filter(|endpoint| (health.0)(endpoint))
+                    .cloned()
+                    .collect();
+                match healthy.len() {
+                    0 => candidates,
+                    1 => return healthy[0].clone(),
+                    _ => &healthy,
+                }
+            }
+            _ => candidates,
+        };
+
+        let idx = match (self.policy, &self.in_flight) {
+            (LoadBalancePolicy::First, _) | (LoadBalancePolicy::LeastConnections, None) => 0,
+            (LoadBalancePolicy::RoundRobin, _) => {
+                self.next.fetch_add(1, Ordering::Relaxed) % candidates.len()
+            }
+            (LoadBalancePolicy::Random, _) => {
+                let mut hasher = RandomState::new().build_hasher();
+                hasher.write_usize(self.next.fetch_add(1, Ordering::Relaxed));
+                hasher.finish() as usize % candidates.len()
+            }
+            (LoadBalancePolicy::LeastConnections, Some(in_flight)) => candidates
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, endpoint)| (in_flight.0)(endpoint))
+                .map(|(idx, _)| idx)
+                .unwrap_or_default(),
+        };
+        candidates[idx].clone()
+    }
 }
 
 /// Implementation for [`Router`].
 ///
 /// There is cache in [`RouterImpl`], it will return endpoints in cache first.
+/// The tables unknown to the route service are routed to the default endpoint
+/// if it is set, otherwise to none.
 /// If returned endpoints is outdated, you should call [`evict`] to remove them.
 /// And [`RouterImpl`] will fetch new endpoints when you call ['route'] again.
 ///
+/// The endpoints are cached by the database and table, so tables with the
+/// same name in different databases won't interfere with each other.
+///
+/// Every cached endpoint is tagged with the routing epoch of the response it
+/// comes from. Once a newer epoch is observed, the entries of older epochs
+/// are regarded as outdated and will be fetched again.
+///
+/// All the candidate endpoints of a table returned by the route service are
+/// cached, and one of them is picked on every routing by the
+/// [`LoadBalancePolicy`].
+///
+/// The routes are cached in the memory by default, and the cache can be
+/// replaced by [`with_route_cache`](RouterImpl::with_route_cache), e.g. to
+/// persist the routes by the [`FileRouteCache`](crate::FileRouteCache).
+///
 /// [`route`]: RouterImpl::route
 /// [`evict`]: RouterImpl::evict
 pub struct RouterImpl {
-    default_endpoint: Endpoint,
-    cache: DashMap<String, Endpoint>,
+    default_endpoint: Option<Endpoint>,
+    // Overrides the `default_endpoint` if set.
+    default_endpoints: Option<Arc<DefaultEndpoints>>,
+    cache: Arc<dyn RouteCache>,
+    epoch: AtomicU64,
     rpc_client: Arc<dyn RpcClient>,
+    route_timeout: Duration,
+    related_tables: Option<RelatedTables>,
+    load_balancer: LoadBalancer,
+    route_change_hook: Option<RouteChangeHook>,
+    metrics: Option<ClientMetrics>,
+    clock: Arc<dyn Clock>,
 }
 
 impl RouterImpl {
-    pub fn new(default_endpoint: Endpoint, rpc_client: Arc<dyn RpcClient>) -> Self {
+    pub fn new(
+        default_endpoint: Option<Endpoint>,
+        rpc_client: Arc<dyn RpcClient>,
+        route_timeout: Duration,
+    ) -> Self {
         Self {
             default_endpoint,
-            cache: DashMap::new(),
+            default_endpoints: None,
+            cache: Arc::new(MemoryRouteCache::default()),
+            epoch: AtomicU64::new(0),
             rpc_client,
+            route_timeout,
+            related_tables: None,
+            load_balancer: LoadBalancer {
+                policy: LoadBalancePolicy::default(),
+                next: AtomicUsize::new(0),
+                in_flight: None,
+                health: None,
+            },
+            route_change_hook: None,
+            metrics: None,
+            clock: Arc::new(SystemClock),
         }
     }
-}
 
-#[async_trait]
-impl Router for RouterImpl {
-    async fn route(&self, tables: &[String], ctx: &RpcContext) -> Result<Vec<Option<Endpoint>>> {
-        assert!(ctx.database.is_some());
+    /// Pick the endpoint of a table among its candidates by the `policy`.
+    pub fn with_load_balance_policy(mut self, policy: LoadBalancePolicy) -> Self {
+        self.load_balancer.policy = policy;
+        self
+    }
+
+    /// Count the requests in flight to the endpoints by the `in_flight` for
+    /// the [`LeastConnections`](LoadBalancePolicy::LeastConnections) policy.
+    pub(crate) fn with_in_flight_counter(mut self, in_flight: Option<InFlightCounter>) -> Self {
+        self.load_balancer.in_flight = in_flight;
+        self
+    }
+
+    /// Deprioritize the candidates found unhealthy by the `health`.
+    pub(crate) fn with_health_filter(mut self, health: Option<HealthFilter>) -> Self {
+        self.load_balancer.health = health;
+        self
+    }
+
+    /// Call the `hook` when the routes fetched differ from the ones observed
+    /// before, and nothing is called if it is none.
+    pub fn with_route_change_hook(mut self, hook: Option<RouteChangeHook>) -> Self {
+        self.route_change_hook = hook;
+        self
+    }
+
+    /// Count the hits and the misses of the route cache in the `metrics`.
+    pub(crate) fn with_metrics(mut self, metrics: Option<ClientMetrics>) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// Measure the ttls of the imported routes by the `clock` instead of the
+    /// system time.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Resolve the uncached tables derived by `related_tables` in the same
+    /// route rpc on the cache miss, so that they are found in the cache when
+    /// routed later.
+    pub fn with_related_tables(mut self, related_tables: Option<RelatedTables>) -> Self {
+        self.related_tables = related_tables;
+        self
+    }
+
+    /// Split the default route cache into `shard_amount` shards, which is
+    /// rounded up to a power of two and at least 2, and the default of the
+    /// `DashMap` is kept if it is none.
+    ///
+    /// It should be called before any table is routed, because the cached
+    /// routes are dropped, and it replaces the cache set by
+    /// [`with_route_cache`](RouterImpl::with_route_cache).
+    pub fn with_shard_amount(mut self, shard_amount: Option<usize>) -> Self {
+        if let Some(shard_amount) = shard_amount {
+            self.cache = Arc::new(MemoryRouteCache::with_shard_amount(shard_amount));
+        }
+        self
+    }
 
-        let mut target_endpoints = vec![Some(self.default_endpoint.clone()); tables.len()];
+    /// Cache the routes in the `cache` instead of the default
+    /// [`MemoryRouteCache`], which is kept if it is none.
+    ///
+    /// The `cache` may be shared by multiple routers, and the routes already
+    /// in it are served.
+    pub fn with_route_cache(mut self, cache: Option<Arc<dyn RouteCache>>) -> Self {
+        if let Some(cache) = cache {
+            self.cache = cache;
+        }
+        self
+    }
+
+    /// Get the number of the shards of the route cache.
+    pub fn shard_amount(&self) -> usize {
+        self.cache.shard_amount()
+    }
+
+    /// Export the cached routes of the tables in the `database`, sorted by the
+    /// tables, and the outdated ones are excluded. A table with multiple
+    /// candidate endpoints is exported once for every candidate in order.
+    ///
+    /// They can be persisted and imported by [`import_cache`] after restarts
+    /// to warm the cache.
+    ///
+    /// [`import_cache`]: RouterImpl::import_cache
+    pub fn export_cache(&self, database: &str) -> Vec<(String, Endpoint)> {
+        let current_epoch = self.epoch.load(Ordering::Acquire);
+        let now = self.clock.now();
+        let mut entries: Vec<_> = self
+            .cache
+            .entries()
+            .into_iter()
+            .filter(|((cached_database, _), entry)| {
+                cached_database == database && !entry.is_outdated(current_epoch, now)
+            })
+            .flat_map(|((_, table), entry)| {
+                entry
+                    .endpoints
+                    .into_iter()
+                    .map(move |endpoint| (table.clone(), endpoint))
+            })
+            .collect();
+        // The stable sorting keeps the order of the candidates.
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+
+        entries
+    }
+
+    /// Import the routes of the tables in the `database` exported by
+    /// [`export_cache`], and the number of the imported tables is returned.
+    ///
+    /// The endpoints of the same table are imported as its candidates in
+    /// order.
+    ///
+    /// The imported routes expire after `ttl` from now, and they are outdated
+    /// by the newer routing epochs like the fetched ones. The tables already
+    /// cached aren't overwritten, since their routes are fresher.
+    ///
+    /// It fails without importing anything if any table name is empty or any
+    /// endpoint is invalid.
+    ///
+    /// [`export_cache`]: RouterImpl::export_cache
+    pub fn import_cache(
+        &self,
+        database: &str,
+        entries: Vec<(String, Endpoint)>,
+        ttl: Duration,
+    ) -> Result<usize> {
+        for (idx, (table, endpoint)) in entries.iter().enumerate() {
+            if table.is_empty() {
+                return Err(Error::Client(format!(
+                    "table name to import is empty, index:{idx}"
+                )));
+            }
+            let valid_port = (1..=u16::MAX as u32).contains(&endpoint.port);
+            if endpoint.addr.is_empty() || !valid_port || endpoint.to_uri(false).is_err() {
+                return Err(Error::Client(format!(
+                    "invalid endpoint to import, table:{table}, endpoint:{endpoint}"
+                )));
+            }
+        }
+
+        let epoch = self.epoch.load(Ordering::Acquire);
+        let now = self.clock.now();
+        let expire_at = now + ttl;
+        let mut imported = 0;
+        for (table, endpoints) in group_candidates(entries) {
+            let entry = RouteEntry {
+                endpoints,
+                epoch,
+                generation: next_route_generation(),
+                expire_at: Some(expire_at),
+            };
+            let inserted = self
+                .cache
+                .insert_if((database.to_string(), table), entry, &|cached| {
+                    cached.is_outdated(epoch, now)
+                });
+            if inserted {
+                imported += 1;
+            }
+        }
+
+        Ok(imported)
+    }
+
+    /// Route the unknown tables to the primary of the `default_endpoints`
+    /// instead of the fixed default endpoint, which follows the rediscovered
+    /// endpoints.
+    pub(crate) fn with_default_endpoints(
+        mut self,
+        default_endpoints: Option<Arc<DefaultEndpoints>>,
+    ) -> Self {
+        self.default_endpoints = default_endpoints;
+        self
+    }
+
+    fn default_endpoint(&self) -> Option<Endpoint> {
+        match &self.default_endpoints {
+            Some(default_endpoints) => default_endpoints.primary(),
+            None => self.default_endpoint.clone(),
+        }
+    }
+
+    /// Collect the tables related to the `misses` that are neither missed nor
+    /// cached.
+    fn related_tables_to_prefetch(
+        &self,
+        database: &str,
+        misses: &HashMap<String, Vec<usize>>,
+    ) -> HashSet<String> {
+        let related_tables = match &self.related_tables {
+            Some(related_tables) => related_tables,
+            None => return HashSet::new(),
+        };
+
+        let mut candidates = Vec::new();
+        for table in misses.keys() {
+            for related in (related_tables.0)(table) {
+                if !misses.contains_key(&related) && !candidates.contains(&related) {
+                    candidates.push(related);
+                }
+            }
+        }
+        let cached = self.get_batch_from_cache(database, &candidates);
+        candidates
+            .into_iter()
+            .zip(cached)
+            .filter(|(_, cached)| cached.is_none())
+            .map(|(table, _)| table)
+            .collect()
+    }
+
+    /// Get the endpoints picked among the candidates and the generations of
+    /// the `tables` from cache in order, the outdated entries will be removed.
+    fn get_batch_from_cache(
+        &self,
+        database: &str,
+        tables: &[String],
+    ) -> Vec<Option<(Endpoint, RouteGeneration)>> {
+        let keys: Vec<RouteKey> = tables
+            .iter()
+            .map(|table| (database.to_string(), table.clone()))
+            .collect();
+        let current_epoch = self.epoch.load(Ordering::Acquire);
+        let now = self.clock.now();
+        self.cache
+            .get_batch(&keys)
+            .into_iter()
+            .zip(&keys)
+            .map(|(entry, key)| match entry {
+                Some(entry) if !entry.is_outdated(current_epoch, now) => {
+                    let endpoint = self.load_balancer.select(&entry.endpoints);
+                    Some((endpoint, entry.generation))
+                }
+                Some(_) => {
+                    // Only remove the entry still outdated, it may be refreshed concurrently.
+                    self.cache
+                        .remove_if(key, &|entry| entry.is_outdated(current_epoch, now));
+                    None
+                }
+                None => None,
+            })
+            .collect()
+    }
 
-        // Find from cache firstly and collect misses.
+    async fn fetch_routes(&self, tables: Vec<String>, ctx: &RpcContext) -> Result<RouteResponse> {
+        let req_ctx = storage::RequestContext {
+            database: ctx.database.clone().ok_or(Error::NoDatabase)?,
+        };
+        let req = RouteRequest {
+            context: Some(req_ctx),
+            tables,
+        };
+        // The route timeout can't exceed the timeout of the whole request.
+        let route_timeout = match ctx.timeout {
+            Some(timeout) => timeout.min(self.route_timeout),
+            None => self.route_timeout,
+        };
+        let route_ctx = ctx.clone().timeout(route_timeout);
+
+        ctx.run_cancellable(self.rpc_client.route(&route_ctx, req))
+            .await
+    }
+
+    async fn route_internal(
+        &self,
+        tables: &[String],
+        ctx: &RpcContext,
+        force_refresh: bool,
+    ) -> Result<Vec<(TableRoute, Option<RouteGeneration>)>> {
+        let database = ctx.database.clone().ok_or(Error::NoDatabase)?;
+        if tables.is_empty() {
+            return Ok(Vec::new());
+        }
+        if let Some(idx) = tables.iter().position(|table| table.is_empty()) {
+            return Err(Error::Client(format!(
+                "table name to route is empty, index:{idx}"
+            )));
+        }
+
+        let default_route = match self.default_endpoint() {
+            Some(endpoint) => TableRoute::Default(endpoint),
+            None => TableRoute::NoRoute,
+        };
+        let mut target_routes = vec![(default_route, None); tables.len()];
+
+        // Find from cache firstly and collect misses with the indexes of all their
+        // occurrences, and all are misses if forced to refresh.
         let misses = {
+            let cached = if force_refresh {
+                vec![None; tables.len()]
+            } else {
+                self.get_batch_from_cache(&database, tables)
+            };
             let mut misses = HashMap::new();
-            for (idx, table) in tables.iter().enumerate() {
-                match self.cache.get(table) {
-                    Some(pair) => {
-                        target_endpoints[idx] = Some(pair.value().clone());
+            for (idx, (table, cached)) in tables.iter().zip(cached).enumerate() {
+                match cached {
+                    Some((endpoint, generation)) => {
+                        target_routes[idx] = (TableRoute::Routed(endpoint), Some(generation));
                     }
 
                     None => {
-                        misses.insert(table.clone(), idx);
+                        misses
+                            .entry(table.clone())
+                            .or_insert_with(Vec::new)
+                            .push(idx);
                     }
                 }
             }
+            if let (Some(metrics), false) = (&self.metrics, force_refresh) {
+                let miss_count = misses.values().map(Vec::len).sum();
+                metrics.record_route_cache(tables.len() - miss_count, miss_count);
+            }
             misses
         };
 
-        // Get endpoints of misses from remote.
-        let req_ctx = storage::RequestContext {
-            database: ctx.database.clone().unwrap(),
-        };
-        let miss_tables = misses.keys().cloned().collect();
-        let req = RouteRequest {
-            context: Some(req_ctx),
-            tables: miss_tables,
+        if misses.is_empty() {
+            return Ok(target_routes);
+        }
+
+        // Get endpoints of misses from remote, along with the related tables to warm
+        // the cache.
+        let prefetched = self.related_tables_to_prefetch(&database, &misses);
+        let miss_tables = misses.keys().chain(prefetched.iter()).cloned().collect();
+        let resp = self.fetch_routes(miss_tables, ctx).await?;
+
+        // Observe the epoch of the response, and the entries of older epochs will be
+        // regarded as outdated.
+        let resp_epoch = match resp.epoch {
+            Some(epoch) => {
+                self.epoch.fetch_max(epoch, Ordering::AcqRel);
+                epoch
+            }
+            None => self.epoch.load(Ordering::Acquire),
         };
-        let resp = self.rpc_client.route(ctx, req).await?;
+        let cacheable = resp_epoch >= self.epoch.load(Ordering::Acquire);
 
         // Fill miss endpoint and update cache.
-        for route in resp.routes {
-            // Endpoint may be none, and not cache it when it is none.
-            if route.endpoint.is_none() {
-                continue;
+        // Endpoint may be none, and not cache it when it is none.
+        let routes = resp.resp.routes.into_iter().filter_map(|route| {
+            let endpoint = route.endpoint?;
+            Some((route.table, Endpoint::from(endpoint)))
+        });
+        let mut entries = Vec::new();
+        for (table, endpoints) in group_candidates(routes) {
+            // The table served from the cache may be routed too, e.g. when the route
+            // rpcs are coalesced, and only its cached route is updated.
+            let idxs = misses.get(&table);
+            if idxs.is_none() && !prefetched.contains(&table) && !tables.contains(&table) {
+                return Err(Error::MalformedResponse {
+                    detail: format!("route of the table not requested, table:{table}"),
+                });
+            }
+            let endpoint = self.load_balancer.select(&endpoints);
+            // The response of an older epoch may arrive late, don't cache it.
+            let generation = cacheable.then(next_route_generation);
+            for idx in idxs.into_iter().flatten() {
+                target_routes[*idx] = (TableRoute::Routed(endpoint.clone()), generation);
             }
+            if let Some(generation) = generation {
+                let primary = endpoints[0].clone();
+                let key = (database.clone(), table);
+                let entry = RouteEntry {
+                    endpoints,
+                    epoch: resp_epoch,
+                    generation,
+                    expire_at: None,
+                };
+                match &self.route_change_hook {
+                    Some(hook) => hook.observe(key.clone(), primary, || {
+                        self.cache.insert_batch(vec![(key, entry)])
+                    }),
+                    None => entries.push((key, entry)),
+                }
+            }
+        }
+        self.cache.insert_batch(entries);
+
+        if force_refresh {
+            for (table, idxs) in &misses {
+                if !matches!(target_routes[idxs[0]].0, TableRoute::Routed(_)) {
+                    let key = (database.clone(), table.clone());
+                    self.cache.remove(&key);
+                    if let Some(hook) = &self.route_change_hook {
+                        hook.observe_unrouted(&key);
+                    }
+                }
+            }
+        }
 
-            // Impossible to get none.
-            let idx = misses.get(&route.table).ok_or_else(|| {
-                Error::Unknown(format!("Unknown table:{} in response", route.table))
-            })?;
-            let endpoint: Endpoint = route.endpoint.unwrap().into();
-            self.cache.insert(route.table, endpoint.clone());
-            target_endpoints[*idx] = Some(endpoint);
+        Ok(target_routes)
+    }
+}
+
+/// Group the endpoints of every table as its candidates, and the tables and
+/// the candidates are in the order of their first occurrences.
+fn group_candidates(
+    routes: impl IntoIterator<Item = (String, Endpoint)>,
+) -> Vec<(String, Vec<Endpoint>)> {
+    let mut candidates: Vec<(String, Vec<Endpoint>)> = Vec::new();
+    let mut table_idxs: HashMap<String, usize> = HashMap::new();
+    for (table, endpoint) in routes {
+        match table_idxs.get(&table) {
+            Some(&idx) => {
+                let endpoints = &mut candidates[idx].1;
+                if !endpoints.contains(&endpoint) {
+                    endpoints.push(endpoint);
+                }
+            }
+            None => {
+                table_idxs.insert(table.clone(), candidates.len());
+                candidates.push((table, vec![endpoint]));
+            }
         }
+    }
+
+    candidates
+}
+
+#[async_trait]
+impl Router for RouterImpl {
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            name = "route",
+            skip_all,
+            fields(
+                table_count = tables.len(),
+                outcome = tracing::field::Empty,
+            )
+        )
+    )]
+    async fn route_tables_with_generations(
+        &self,
+        tables: &[String],
+        ctx: &RpcContext,
+        force_refresh: bool,
+    ) -> Result<Vec<(TableRoute, Option<RouteGeneration>)>> {
+        let result = self.route_internal(tables, ctx, force_refresh).await;
+        record_span_outcome(&result);
+        result
+    }
+
+    async fn route_replicas(
+        &self,
+        tables: &[String],
+        ctx: &RpcContext,
+    ) -> Result<Vec<Option<Route>>> {
+        let routes = self.route_internal(tables, ctx, false).await?;
+        let database = ctx.database.clone().unwrap_or_default();
+        let keys: Vec<RouteKey> = tables
+            .iter()
+            .map(|table| (database.clone(), table.clone()))
+            .collect();
+        let entries = self.cache.get_batch(&keys);
+        let replicas = tables
+            .iter()
+            .zip(routes)
+            .zip(entries)
+            .map(|((table, (route, generation)), entry)| {
+                let endpoint = match route {
+                    TableRoute::Routed(endpoint) => endpoint,
+                    TableRoute::Default(_) | TableRoute::NoRoute => return None,
+                };
+                // The route of the response of an older epoch isn't cached, and the cached
+                // one may be replaced concurrently.
+                let endpoints = match entry {
+                    Some(entry) if Some(entry.generation) == generation => entry.endpoints,
+                    _ => vec![endpoint],
+                };
+                Some(Route::from_candidates(table.clone(), endpoints))
+            })
+            .collect();
 
-        Ok(target_endpoints)
+        Ok(replicas)
     }
 
-    fn evict(&self, tables: &[String]) {
+    fn evict(&self, tables: &[String], ctx: &RpcContext) {
+        let database = ctx.database.clone().unwrap_or_default();
         tables.iter().for_each(|e| {
-            self.cache.remove(e.as_str());
+            self.cache.remove(&(database.clone(), e.clone()));
         })
     }
-}
 
-#[cfg(test)]
-mod test {
-    use std::sync::Arc;
+    fn evict_if_stale(&self, table: &str, generation: RouteGeneration, ctx: &RpcContext) {
+        let database = ctx.database.clone().unwrap_or_default();
+        self.cache
+            .remove_if(&(database, table.to_string()), &|entry| {
+                entry.generation == generation
+            });
+    }
 
-    use dashmap::DashMap;
+    fn evict_all(&self) {
+        self.cache.clear();
+    }
 
-    use super::{Router, RouterImpl};
-    use crate::{
-        model::route::Endpoint,
-        rpc_client::{MockRpcClient, RpcContext},
-    };
+    // The cache is scanned rather than indexed by the endpoints, as it may be
+    // shared and updated by others, and the route refreshed to another endpoint
+    // meanwhile is kept.
+    fn evict_by_endpoint(&self, endpoint: &Endpoint) -> usize {
+        self.cache
+            .entries()
+            .into_iter()
+            .filter(|(_, entry)| entry.endpoints.contains(endpoint))
+            .filter(|(key, _)| {
+                self.cache
+                    .remove_if(key, &|entry| entry.endpoints.contains(endpoint))
+            })
+            .count()
+    }
 
-    #[tokio::test]
-    async fn test_basic_flow() {
-        // Init mock route table
-        let table1 = "table1".to_string();
-        let table2 = "table2".to_string();
-        let table3 = "table3".to_string();
-        let table4 = "table4".to_string();
-        let endpoint1 = Endpoint::new("192.168.0.1".to_string(), 11);
-        let endpoint2 = Endpoint::new("192.168.0.2".to_string(), 12);
-        let endpoint3 = Endpoint::new("192.168.0.3".to_string(), 13);
-        let endpoint4 = Endpoint::new("192.168.0.4".to_string(), 14);
-        let default_endpoint = Endpoint::new("192.168.0.5".to_string(), 15);
+    fn cached_endpoints(&self) -> Vec<Endpoint> {
+        let mut endpoints: HashSet<_> = self
+            .cache
+            .entries()
+            .into_iter()
+            .flat_map(|(_, entry)| entry.endpoints)
+            .collect();
+        match &self.default_endpoints {
+            Some(default_endpoints) => endpoints.extend(default_endpoints.load().iter().cloned()),
+            None => endpoints.extend(self.default_endpoint.clone()),
+        }
 
-        // Init mock client with route1 and route2
-        let route_table = Arc::new(DashMap::default());
-        let mock_rpc_client = MockRpcClient {
-            route_table: route_table.clone(),
-        };
-        mock_rpc_client
-            .route_table
-            .insert(table1.clone(), endpoint1.clone());
-        mock_rpc_client
-            .route_table
-            .insert(table2.clone(), endpoint2.clone());
+        endpoints.into_iter().collect()
+    }
 
-        // Follow these steps to check wether cache is used or not:
-        // route --> change route_table --> route again.
-        let ctx = RpcContext {
-            database: Some("db".to_string()),
-            timeout: None,
-        };
-        let tables = vec![table1.clone(), table2.clone()];
-        let route_client = RouterImpl::new(default_endpoint.clone(), Arc::new(mock_rpc_client));
-        let route_res1 = route_client.route(&tables, &ctx).await.unwrap();
-        assert_eq!(&endpoint1, route_res1.get(0).unwrap().as_ref().unwrap());
-        assert_eq!(&endpoint2, route_res1.get(1).unwrap().as_ref().unwrap());
+    async fn resolve_uncached(&self, table: &str, ctx: &RpcContext) -> Result<Option<Endpoint>> {
+        let resp = self.fetch_routes(vec![table.to_string()], ctx).await?;
+        let endpoint = resp
+            .resp
+            .routes
+            .into_iter()
+            .find(|route| route.table == table)
+            .and_then(|route| route.endpoint)
+            .map(Endpoint::from);
 
-        route_table.insert(table1.clone(), endpoint3.clone());
-        route_table.insert(table2.clone(), endpoint4.clone());
+        Ok(endpoint)
+    }
+}
 
-        let route_res2 = route_client.route(&tables, &ctx).await.unwrap();
-        assert_eq!(&endpoint1, route_res2.get(0).unwrap().as_ref().unwrap());
-        assert_eq!(&endpoint2, route_res2.get(1).unwrap().as_ref().unwrap());
+/// [`Router`] trying the primary router first, and falling through to the
+/// secondary one for the tables the primary can't resolve, or for all the
+/// tables if the primary fails.
+///
+/// It is useful when migrating to a new route service.
+pub struct FallbackRouter {
+    primary: Box<dyn Router>,
+    secondary: Box<dyn Router>,
+}
 
-        route_client.evict(&[table1.clone(), table2.clone()]);
+impl FallbackRouter {
+    pub fn new(primary: Box<dyn Router>, secondary: Box<dyn Router>) -> Self {
+        Self { primary, secondary }
+    }
+}
 
-        let route_res3 = route_client.route(&tables, &ctx).await.unwrap();
-        assert_eq!(&endpoint3, route_res3.get(0).unwrap().as_ref().unwrap());
-        assert_eq!(&endpoint4, route_res3.get(1).unwrap().as_ref().unwrap());
+#[async_trait]
+impl Router for FallbackRouter {
+    async fn route_tables_with_generations(
+        &self,
+        tables: &[String],
+        ctx: &RpcContext,
+        force_refresh: bool,
+    ) -> Result<Vec<(TableRoute, Option<RouteGeneration>)>> {
+        let mut target_routes = match self
+            .primary
+            .route_tables_with_generations(tables, ctx, force_refresh)
+            .await
+        {
+            Ok(routes) => routes,
+            Err(_) => {
+                return self
+                    .secondary
+                    .route_tables_with_generations(tables, ctx, force_refresh)
+                    .await
+            }
+        };
+
+        let unresolved_idxs: Vec<_> = target_routes
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, (route, _))| (*route == TableRoute::NoRoute).then_some(idx))
+            .collect();
+        if unresolved_idxs.is_empty() {
+            return Ok(target_routes);
+        }
+
+        let unresolved_tables: Vec<_> = unresolved_idxs
+            .iter()
+            .map(|idx| tables[*idx].clone())
+            .collect();
+        let fallback_routes = self
+            .secondary
+            .route_tables_with_generations(&unresolved_tables, ctx, force_refresh)
+            .await?;
+        for (idx, route) in unresolved_idxs.into_iter().zip(fallback_routes) {
+            target_routes[idx] = route;
+        }
+
+        Ok(target_routes)
+    }
+
+    // The primary router has no default endpoint, so the tables it doesn't
+    // route fall through like in the routing.
+    async fn route_replicas(
+        &self,
+        tables: &[String],
+        ctx: &RpcContext,
+    ) -> Result<Vec<Option<Route>>> {
+        let mut replicas = match self.primary.route_replicas(tables, ctx).await {
+            Ok(replicas) => replicas,
+            Err(_) => return self.secondary.route_replicas(tables, ctx).await,
+        };
+
+        let unresolved_idxs: Vec<_> = replicas
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, route)| route.is_none().then_some(idx))
+            .collect();
+        if unresolved_idxs.is_empty() {
+            return Ok(replicas);
+        }
+
+        let unresolved_tables: Vec<_> = unresolved_idxs
+            .iter()
+            .map(|idx| tables[*idx].clone())
+            .collect();
+        let fallback_replicas = self
+            .secondary
+            .route_replicas(&unresolved_tables, ctx)
+            .await?;
+        for (idx, route) in unresolved_idxs.into_iter().zip(fallback_replicas) {
+            replicas[idx] = route;
+        }
+
+        Ok(replicas)
+    }
+
+    fn evict(&self, tables: &[String], ctx: &RpcContext) {
+        self.primary.evict(tables, ctx);
+        self.secondary.evict(tables, ctx);
+    }
+
+    // The generations are unique among the routers, so only the one caching the
+    // route of the generation evicts it.
+    fn evict_if_stale(&self, table: &str, generation: RouteGeneration, ctx: &RpcContext) {
+        self.primary.evict_if_stale(table, generation, ctx);
+        self.secondary.evict_if_stale(table, generation, ctx);
+    }
+
+    fn evict_all(&self) {
+        self.primary.evict_all();
+        self.secondary.evict_all();
+    }
+
+    fn evict_by_endpoint(&self, endpoint: &Endpoint) -> usize {
+        self.primary.evict_by_endpoint(endpoint) + self.secondary.evict_by_endpoint(endpoint)
+    }
+
+    fn cached_endpoints(&self) -> Vec<Endpoint> {
+        let mut endpoints: HashSet<_> = self.primary.cached_endpoints().into_iter().collect();
+        endpoints.extend(self.secondary.cached_endpoints());
+
+        endpoints.into_iter().collect()
+    }
+
+    async fn resolve_uncached(&self, table: &str, ctx: &RpcContext) -> Result<Option<Endpoint>> {
+        match self.primary.resolve_uncached(table, ctx).await {
+            Ok(Some(endpoint)) => Ok(Some(endpoint)),
+            Ok(None) | Err(_) => self.secondary.resolve_uncached(table, ctx).await,
+        }
+    }
+}
+
+/// Key of the route overrides: (database, table), and the override without
+/// the database applies to the table in all the databases.
+pub(crate) type OverrideKey = (Option<String>, String);
+
+/// Routes pinned to the endpoints by the configuration, which take precedence
+/// over the cached routes and the ones from the route service.
+#[derive(Debug, Default)]
+pub(crate) struct RouteOverrides {
+    overrides: RwLock<HashMap<OverrideKey, Endpoint>>,
+}
+
+impl RouteOverrides {
+    pub fn new(overrides: HashMap<OverrideKey, Endpoint>) -> Self {
+        Self {
+            overrides: RwLock::new(overrides),
+        }
+    }
+
+    /// Pin the `table` to the `endpoint`, and the endpoint it was pinned to
+    /// is returned.
+    pub fn insert(
+        &self,
+        database: Option<&str>,
+        table: &str,
+        endpoint: Endpoint,
+    ) -> Option<Endpoint> {
+        let key = (database.map(str::to_string), table.to_string());
+        self.overrides
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(key, endpoint)
+    }
+
+    pub fn remove(&self, database: Option<&str>, table: &str) -> Option<Endpoint> {
+        let key = (database.map(str::to_string), table.to_string());
+        self.overrides
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(&key)
+    }
+
+    /// Get the endpoint the `table` in the `database` is pinned to, and the
+    /// override in the database takes precedence over the one for all the
+    /// databases.
+    pub fn get(&self, database: Option<&str>, table: &str) -> Option<Endpoint> {
+        let overrides = self
+            .overrides
+            .read()
+            .unwrap_or_else(PoisonError::into_inner);
+        if overrides.is_empty() {
+            return None;
+        }
+
+        database
+            .and_then(|database| overrides.get(&(Some(database.to_string()), table.to_string())))
+            .or_else(|| overrides.get(&(None, table.to_string())))
+            .cloned()
+    }
+
+    fn endpoints(&self) -> Vec<Endpoint> {
+        self.overrides
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .values()
+            .cloned()
+            .collect()
+    }
+}
+
+/// [`Router`] routing the overridden tables to their pinned endpoints without
+/// asking the inner router, so their routes are neither fetched from the
+/// route service nor evicted, and the other tables are routed by the inner
+/// router.
+///
+/// The pinned routes have no generations, as they are not cached.
+pub(crate) struct OverridingRouter {
+    overrides: Arc<RouteOverrides>,
+    inner: Box<dyn Router>,
+}
+
+impl OverridingRouter {
+    pub fn new(overrides: Arc<RouteOverrides>, inner: Box<dyn Router>) -> Self {
+        Self { overrides, inner }
+    }
+}
+
+#[async_trait]
+impl Router for OverridingRouter {
+    async fn route_tables_with_generations(
+        &self,
+        tables: &[String],
+        ctx: &RpcContext,
+        force_refresh: bool,
+    ) -> Result<Vec<(TableRoute, Option<RouteGeneration>)>> {
+        let database = ctx.database.as_deref();
+        let overridden: Vec<_> = tables
+            .iter()
+            .map(|table| self.overrides.get(database, table))
+            .collect();
+        let rest_tables: Vec<_> = tables
+            .iter()
+            .zip(&overridden)
+            .filter(|(_, endpoint)| endpoint.is_none())
+            .map(|(table, _)| table.clone())
+            .collect();
+
+        let mut rest_routes = if rest_tables.is_empty() {
+            Vec::new().into_iter()
+        } else {
+            self.inner
+                .route_tables_with_generations(&rest_tables, ctx, force_refresh)
+                .await?
+                .into_iter()
+        };
+        // The inner router returns the routes in the order of the rest tables.
+        let routes = overridden
+            .into_iter()
+            .map(|endpoint| match endpoint {
+                Some(endpoint) => (TableRoute::Routed(endpoint), None),
+                None => rest_routes.next().unwrap_or((TableRoute::NoRoute, None)),
+            })
+            .collect();
+
+        Ok(routes)
+    }
+
+    // The pinned endpoint is the only replica of the overridden table.
+    async fn route_replicas(
+        &self,
+        tables: &[String],
+        ctx: &RpcContext,
+    ) -> Result<Vec<Option<Route>>> {
+        let database = ctx.database.as_deref();
+        let overridden: Vec<_> = tables
+            .iter()
+            .map(|table| self.overrides.get(database, table))
+            .collect();
+        let rest_tables: Vec<_> = tables
+            .iter()
+            .zip(&overridden)
+            .filter(|(_, endpoint)| endpoint.is_none())
+            .map(|(table, _)| table.clone())
+            .collect();
+
+        let mut rest_replicas = if rest_tables.is_empty() {
+            Vec::new().into_iter()
+        } else {
+            self.inner
+                .route_replicas(&rest_tables, ctx)
+                .await?
+                .into_iter()
+        };
+        let replicas = tables
+            .iter()
+            .zip(overridden)
+            .map(|(table, endpoint)| match endpoint {
+                Some(endpoint) => Some(Route::from_candidates(table.clone(), vec![endpoint])),
+                None => rest_replicas.next().flatten(),
+            })
+            .collect();
+
+        Ok(replicas)
+    }
+
+    fn evict(&self, tables: &[String], ctx: &RpcContext) {
+        self.inner.evict(tables, ctx);
+    }
+
+    fn evict_if_stale(&self, table: &str, generation: RouteGeneration, ctx: &RpcContext) {
+        self.inner.evict_if_stale(table, generation, ctx);
+    }
+
+    fn evict_all(&self) {
+        self.inner.evict_all();
+    }
+
+    // The overrides are pinned by the user, and only removed by the user.
+    fn evict_by_endpoint(&self, endpoint: &Endpoint) -> usize {
+        self.inner.evict_by_endpoint(endpoint)
+    }
+
+    fn cached_endpoints(&self) -> Vec<Endpoint> {
+        let mut endpoints: HashSet<_> = self.inner.cached_endpoints().into_iter().collect();
+        endpoints.extend(self.overrides.endpoints());
+
+        endpoints.into_iter().collect()
+    }
+
+    async fn resolve_uncached(&self, table: &str, ctx: &RpcContext) -> Result<Option<Endpoint>> {
+        match self.overrides.get(ctx.database.as_deref(), table) {
+            Some(endpoint) => Ok(Some(endpoint)),
+            None => self.inner.resolve_uncached(table, ctx).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        fs,
+        path::PathBuf,
+        sync::{
+            atomic::{AtomicU64, AtomicUsize, Ordering},
+            Arc, Mutex,
+        },
+        time::Duration,
+    };
+
+    use async_trait::async_trait;
+    use ceresdbproto::storage::{
+        Endpoint as EndpointPb, Route as RoutePb, RouteResponse as RouteResponsePb,
+    };
+    use dashmap::{DashMap, DashSet};
+    use futures::future;
+
+    use super::{
+        FallbackRouter, HealthFilter, InFlightCounter, OverridingRouter, RelatedTables,
+        RouteChangeHook, RouteGeneration, RouteOverrides, Router, RouterImpl,
+    };
+    use crate::{
+        clock::ManualClock,
+        config::{FileRouteCacheConfig, LoadBalancePolicy},
+        errors::Result,
+        interceptor::OperationKind,
+        model::route::{Endpoint, Replica, ReplicaRole, Route, TableRoute},
+        route_cache::{FileRouteCache, RouteCache, RouteEntry, RouteKey},
+        rpc_client::{MockRpcClient, RouteResponse, RpcContext},
+        Error,
+    };
+
+    /// [`MockRpcClient`] returning the routes of all the tables in the database
+    /// besides the requested ones, like the coalesced route rpcs.
+    fn extra_routes_rpc_client(
+        route_table: Arc<DashMap<(String, String), Endpoint>>,
+    ) -> MockRpcClient {
+        let base = MockRpcClient::default().with_route_table(route_table);
+        base.clone().on_route(move |_, mut req| {
+            let database = req.context.clone().unwrap().database;
+            for entry in base.route_table.iter() {
+                let (entry_database, table) = entry.key();
+                if *entry_database == database && !req.tables.contains(table) {
+                    req.tables.push(table.clone());
+                }
+            }
+            future::ready(Ok(base.route_by_table(req)))
+        })
+    }
+
+    /// [`MockRpcClient`] responding the routes without endpoints.
+    fn malformed_routes_rpc_client() -> MockRpcClient {
+        MockRpcClient::default().on_route(|_, req| {
+            let routes = req
+                .tables
+                .into_iter()
+                .map(|table| RoutePb {
+                    table,
+                    endpoint: None,
+                })
+                .collect();
+            future::ready(Ok(RouteResponse {
+                epoch: None,
+                resp: RouteResponsePb {
+                    header: None,
+                    routes,
+                },
+            }))
+        })
+    }
+
+    /// [`MockRpcClient`] routing every table to all the `replicas`.
+    fn replicas_rpc_client(replicas: Vec<Endpoint>) -> MockRpcClient {
+        MockRpcClient::default().on_route(move |_, req| {
+            let routes = req
+                .tables
+                .iter()
+                .flat_map(|table| {
+                    replicas.iter().map(|endpoint| RoutePb {
+                        table: table.clone(),
+                        endpoint: Some(EndpointPb {
+                            ip: endpoint.addr.clone(),
+                            port: endpoint.port,
+                        }),
+                    })
+                })
+                .collect();
+            future::ready(Ok(RouteResponse {
+                epoch: None,
+                resp: RouteResponsePb {
+                    header: None,
+                    routes,
+                },
+            }))
+        })
+    }
+
+    struct FailingRouter;
+
+    #[async_trait]
+    impl Router for FailingRouter {
+        async fn route_tables_with_generations(
+            &self,
+            _tables: &[String],
+            _ctx: &RpcContext,
+            _force_refresh: bool,
+        ) -> Result<Vec<(TableRoute, Option<RouteGeneration>)>> {
+            Err(Error::Unknown("route service is down".to_string()))
+        }
+
+        fn evict(&self, _tables: &[String], _ctx: &RpcContext) {}
+
+        fn evict_if_stale(&self, _table: &str, _generation: RouteGeneration, _ctx: &RpcContext) {}
+
+        fn evict_all(&self) {}
+
+        fn evict_by_endpoint(&self, _endpoint: &Endpoint) -> usize {
+            0
+        }
+
+        fn cached_endpoints(&self) -> Vec<Endpoint> {
+            Vec::new()
+        }
+
+        async fn resolve_uncached(
+            &self,
+            _table: &str,
+            _ctx: &RpcContext,
+        ) -> Result<Option<Endpoint>> {
+            Err(Error::Unknown("route service is down".to_string()))
+        }
+    }
+
+    static NEXT_CACHE_FILE: AtomicUsize = AtomicUsize::new(0);
+
+    /// Backend of the route cache the router tests run against.
+    #[derive(Debug, Clone, Copy)]
+    enum Backend {
+        Memory,
+        File,
+    }
+
+    impl Backend {
+        /// Build a fresh cache, and none for the default one in the memory.
+        fn cache(self) -> Option<Arc<dyn RouteCache>> {
+            match self {
+                Backend::Memory => None,
+                Backend::File => {
+                    let path = temp_cache_path();
+                    let cache = FileRouteCache::open(&path, flushed_on_drop()).unwrap();
+                    Some(Arc::new(TempFileRouteCache(cache)))
+                }
+            }
+        }
+    }
+
+    fn temp_cache_path() -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "ceresdb-route-cache-{}-{}",
+            std::process::id(),
+            NEXT_CACHE_FILE.fetch_add(1, Ordering::Relaxed)
+        ));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    fn flushed_on_drop() -> FileRouteCacheConfig {
+        FileRouteCacheConfig {
+            flush_interval: Duration::ZERO,
+            ..Default::default()
+        }
+    }
+
+    /// [`FileRouteCache`] removing its file on drop.
+    #[derive(Debug)]
+    struct TempFileRouteCache(Arc<FileRouteCache>);
+
+    impl Drop for TempFileRouteCache {
+        fn drop(&mut self) {
+            self.0.flush().unwrap();
+            // Nothing is written if nothing is cached.
+            let _ = fs::remove_file(self.0.path());
+        }
+    }
+
+    impl RouteCache for TempFileRouteCache {
+        fn get_batch(&self, keys: &[RouteKey]) -> Vec<Option<RouteEntry>> {
+            self.0.get_batch(keys)
+        }
+
+        fn insert_batch(&self, entries: Vec<(RouteKey, RouteEntry)>) {
+            self.0.insert_batch(entries)
+        }
+
+        fn insert_if(
+            &self,
+            key: RouteKey,
+            entry: RouteEntry,
+            replace: &dyn Fn(&RouteEntry) -> bool,
+        ) -> bool {
+            self.0.insert_if(key, entry, replace)
+        }
+
+        fn remove_if(&self, key: &RouteKey, remove: &dyn Fn(&RouteEntry) -> bool) -> bool {
+            self.0.remove_if(key, remove)
+        }
+
+        fn len(&self) -> usize {
+            self.0.len()
+        }
+
+        fn clear(&self) {
+            self.0.clear()
+        }
+
+        fn entries(&self) -> Vec<(RouteKey, RouteEntry)> {
+            self.0.entries()
+        }
+    }
+
+    /// Run the router tests against both the backends of the route cache.
+    macro_rules! test_backends {
+        ($($test:ident),+ $(,)?) => {
+            paste::paste! {
+                $(
+                    #[tokio::test]
+                    async fn [<$test _memory>]() {
+                        $test(Backend::Memory).await
+                    }
+
+                    #[tokio::test]
+                    async fn [<$test _file>]() {
+                        $test(Backend::File).await
+                    }
+                )+
+            }
+        };
+    }
+
+    fn mock_router(
+        route_table: &Arc<DashMap<(String, String), Endpoint>>,
+        default_endpoint: Option<Endpoint>,
+        backend: Backend,
+    ) -> Box<dyn Router> {
+        let mock_rpc_client = MockRpcClient::default().with_route_table(route_table.clone());
+        Box::new(
+            RouterImpl::new(
+                default_endpoint,
+                Arc::new(mock_rpc_client),
+                Duration::from_secs(5),
+            )
+            .with_route_cache(backend.cache()),
+        )
+    }
+
+    async fn test_basic_flow(backend: Backend) {
+        // Init mock route table
+        let table1 = "table1".to_string();
+        let table2 = "table2".to_string();
+        let table3 = "table3".to_string();
+        let table4 = "table4".to_string();
+        let endpoint1 = Endpoint::new("192.168.0.1".to_string(), 11);
+        let endpoint2 = Endpoint::new("192.168.0.2".to_string(), 12);
+        let endpoint3 = Endpoint::new("192.168.0.3".to_string(), 13);
+        let endpoint4 = Endpoint::new("192.168.0.4".to_string(), 14);
+        let default_endpoint = Endpoint::new("192.168.0.5".to_string(), 15);
+
+        // Init mock client with route1 and route2
+        let route_table = Arc::new(DashMap::default());
+        let mock_rpc_client = MockRpcClient::default().with_route_table(route_table.clone());
+        let db = "db".to_string();
+        mock_rpc_client
+            .route_table
+            .insert((db.clone(), table1.clone()), endpoint1.clone());
+        mock_rpc_client
+            .route_table
+            .insert((db.clone(), table2.clone()), endpoint2.clone());
+
+        // Follow these steps to check wether cache is used or not:
+        // route --> change route_table --> route again.
+        let ctx = RpcContext::default().database(db.clone());
+        let tables = vec![table1.clone(), table2.clone()];
+        let route_client = RouterImpl::new(
+            Some(default_endpoint.clone()),
+            Arc::new(mock_rpc_client),
+            Duration::from_secs(5),
+        )
+        .with_route_cache(backend.cache());
+        let route_res1 = route_client.route(&tables, &ctx).await.unwrap();
+        assert_eq!(&endpoint1, route_res1.get(0).unwrap().as_ref().unwrap());
+        assert_eq!(&endpoint2, route_res1.get(1).unwrap().as_ref().unwrap());
+
+        route_table.insert((db.clone(), table1.clone()), endpoint3.clone());
+        route_table.insert((db, table2.clone()), endpoint4.clone());
+
+        let route_res2 = route_client.route(&tables, &ctx).await.unwrap();
+        assert_eq!(&endpoint1, route_res2.get(0).unwrap().as_ref().unwrap());
+        assert_eq!(&endpoint2, route_res2.get(1).unwrap().as_ref().unwrap());
+
+        route_client.evict(&[table1.clone(), table2.clone()], &ctx);
+
+        let route_res3 = route_client.route(&tables, &ctx).await.unwrap();
+        assert_eq!(&endpoint3, route_res3.get(0).unwrap().as_ref().unwrap());
+        assert_eq!(&endpoint4, route_res3.get(1).unwrap().as_ref().unwrap());
 
         let route_res4 = route_client.route(&[table3, table4], &ctx).await.unwrap();
         assert_eq!(
@@ -180,4 +1677,1157 @@ mod test {
             route_res4.get(1).unwrap().as_ref().unwrap()
         );
     }
+
+    async fn test_route_duplicates_and_empties(backend: Backend) {
+        let db = "db".to_string();
+        let table1 = "table1".to_string();
+        let table2 = "table2".to_string();
+        let endpoint1 = Endpoint::new("192.168.0.1".to_string(), 11);
+        let endpoint2 = Endpoint::new("192.168.0.2".to_string(), 12);
+        let default_endpoint = Endpoint::new("192.168.0.5".to_string(), 15);
+
+        let route_table = Arc::new(DashMap::default());
+        route_table.insert((db.clone(), table1.clone()), endpoint1.clone());
+        route_table.insert((db.clone(), table2.clone()), endpoint2.clone());
+        let rpc_client = MockRpcClient::default().with_route_table(route_table);
+        let router = RouterImpl::new(
+            Some(default_endpoint),
+            Arc::new(rpc_client.clone()),
+            Duration::from_secs(5),
+        )
+        .with_route_cache(backend.cache());
+        let ctx = RpcContext::default().database(db);
+
+        // Nothing to route, and no rpc is sent.
+        assert!(router.route(&[], &ctx).await.unwrap().is_empty());
+        let err = router
+            .route(&[table1.clone(), String::new()], &ctx)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::Client(_)), "err:{err:?}");
+        assert_eq!(rpc_client.calls(OperationKind::Route), 0);
+
+        // Every occurrence of the duplicated table is routed.
+        let tables = [table1.clone(), table2, table1];
+        let routes = router.route(&tables, &ctx).await.unwrap();
+        assert_eq!(
+            routes,
+            vec![
+                Some(endpoint1.clone()),
+                Some(endpoint2),
+                Some(endpoint1.clone())
+            ]
+        );
+        assert_eq!(rpc_client.calls(OperationKind::Route), 1);
+
+        let routes = router.route(&tables, &ctx).await.unwrap();
+        assert_eq!(routes[2], Some(endpoint1));
+        assert_eq!(rpc_client.calls(OperationKind::Route), 1);
+    }
+
+    async fn test_route_one(backend: Backend) {
+        let db = "db".to_string();
+        let endpoint1 = Endpoint::new("192.168.0.1".to_string(), 11);
+        let default_endpoint = Endpoint::new("192.168.0.5".to_string(), 15);
+        let route_table = Arc::new(DashMap::default());
+        route_table.insert((db.clone(), "table1".to_string()), endpoint1.clone());
+        let ctx = RpcContext::default().database(db);
+
+        // The missing table is routed to none without the default endpoint.
+        let router = mock_router(&route_table, None, backend);
+        let route_res = router.route_one("table1", &ctx).await.unwrap();
+        assert_eq!(route_res, Some(endpoint1));
+        assert_eq!(router.route_one("missing", &ctx).await.unwrap(), None);
+        let err = router.route_one("", &ctx).await.unwrap_err();
+        assert!(matches!(err, Error::Client(_)), "err:{err:?}");
+
+        let router = mock_router(&route_table, Some(default_endpoint.clone()), backend);
+        let route_res = router.route_one("missing", &ctx).await.unwrap();
+        assert_eq!(route_res, Some(default_endpoint));
+    }
+
+    async fn test_unexpected_extra_route(backend: Backend) {
+        let db = "db".to_string();
+        let table1 = "table1".to_string();
+        let table2 = "table2".to_string();
+        let endpoint1 = Endpoint::new("192.168.0.1".to_string(), 11);
+        let endpoint2 = Endpoint::new("192.168.0.2".to_string(), 12);
+        let endpoint3 = Endpoint::new("192.168.0.3".to_string(), 13);
+
+        let route_table = Arc::new(DashMap::default());
+        route_table.insert((db.clone(), table1.clone()), endpoint1.clone());
+        let router = RouterImpl::new(
+            None,
+            Arc::new(extra_routes_rpc_client(route_table.clone())),
+            Duration::from_secs(5),
+        )
+        .with_route_cache(backend.cache());
+        let ctx = RpcContext::default().database(db.clone());
+        let tables = [table1.clone(), table2.clone()];
+        let routes = router.route(&tables[..1], &ctx).await.unwrap();
+        assert_eq!(routes, vec![Some(endpoint1.clone())]);
+
+        // The route of table1 served from the cache comes with the one of table2,
+        // and only its cached route is updated.
+        route_table.insert((db.clone(), table1), endpoint3.clone());
+        route_table.insert((db.clone(), table2), endpoint2.clone());
+        let routes = router.route(&tables, &ctx).await.unwrap();
+        assert_eq!(routes, vec![Some(endpoint1), Some(endpoint2)]);
+        let routes = router.route(&tables[..1], &ctx).await.unwrap();
+        assert_eq!(routes, vec![Some(endpoint3)]);
+
+        // The route of the table neither requested nor prefetched is unexpected.
+        route_table.insert(
+            (db, "table3".to_string()),
+            Endpoint::new("192.168.0.4".to_string(), 14),
+        );
+        let err = router
+            .route(&["table4".to_string()], &ctx)
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(err, Error::MalformedResponse { .. }),
+            "err:{err:?}"
+        );
+    }
+
+    async fn test_malformed_routes(backend: Backend) {
+        let router = RouterImpl::new(
+            None,
+            Arc::new(malformed_routes_rpc_client()),
+            Duration::from_secs(5),
+        )
+        .with_route_cache(backend.cache());
+        let tables = ["table1".to_string(), "table2".to_string()];
+
+        // The routes without endpoints are regarded as not routed.
+        let ctx = RpcContext::default().database("db".to_string());
+        let routes = router.route(&tables, &ctx).await.unwrap();
+        assert_eq!(routes, vec![None, None]);
+
+        let err = router
+            .route(&tables, &RpcContext::default())
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::NoDatabase), "err:{err:?}");
+    }
+
+    async fn test_epoch_invalidation(backend: Backend) {
+        let table1 = "table1".to_string();
+        let table2 = "table2".to_string();
+        let endpoint1 = Endpoint::new("192.168.0.1".to_string(), 11);
+        let endpoint2 = Endpoint::new("192.168.0.2".to_string(), 12);
+        let endpoint3 = Endpoint::new("192.168.0.3".to_string(), 13);
+        let default_endpoint = Endpoint::new("192.168.0.5".to_string(), 15);
+
+        let route_table = Arc::new(DashMap::default());
+        let route_epoch = Arc::new(AtomicU64::new(1));
+        let mock_rpc_client = MockRpcClient::default()
+            .with_route_table(route_table.clone())
+            .with_route_epoch(route_epoch.clone());
+        let db = "db".to_string();
+        route_table.insert((db.clone(), table1.clone()), endpoint1.clone());
+        route_table.insert((db.clone(), table2.clone()), endpoint2.clone());
+
+        let ctx = RpcContext::default().database(db.clone());
+        let route_client = RouterImpl::new(
+            Some(default_endpoint),
+            Arc::new(mock_rpc_client),
+            Duration::from_secs(5),
+        )
+        .with_route_cache(backend.cache());
+        let tables = vec![table1.clone(), table2.clone()];
+        let route_res1 = route_client.route(&tables, &ctx).await.unwrap();
+        assert_eq!(&endpoint1, route_res1[0].as_ref().unwrap());
+        assert_eq!(&endpoint2, route_res1[1].as_ref().unwrap());
+
+        // Topology changes, and the newer epoch is observed when routing table1.
+        route_table.insert((db.clone(), table1), endpoint3.clone());
+        route_table.insert((db, table2), endpoint3.clone());
+        route_epoch.store(2, Ordering::Relaxed);
+        route_client.evict(&tables[..1], &ctx);
+        let route_res2 = route_client.route(&tables[..1], &ctx).await.unwrap();
+        assert_eq!(&endpoint3, route_res2[0].as_ref().unwrap());
+
+        // Entry of table2 from older epoch should be invalidated without evicting.
+        let route_res3 = route_client.route(&tables[1..], &ctx).await.unwrap();
+        assert_eq!(&endpoint3, route_res3[0].as_ref().unwrap());
+    }
+
+    async fn test_route_in_databases(backend: Backend) {
+        let table = "table".to_string();
+        let endpoint1 = Endpoint::new("192.168.0.1".to_string(), 11);
+        let endpoint2 = Endpoint::new("192.168.0.2".to_string(), 12);
+        let default_endpoint = Endpoint::new("192.168.0.5".to_string(), 15);
+
+        let route_table = Arc::new(DashMap::default());
+        let mock_rpc_client = MockRpcClient::default().with_route_table(route_table.clone());
+        route_table.insert(("db1".to_string(), table.clone()), endpoint1.clone());
+        route_table.insert(("db2".to_string(), table.clone()), endpoint2.clone());
+
+        let route_client = RouterImpl::new(
+            Some(default_endpoint),
+            Arc::new(mock_rpc_client),
+            Duration::from_secs(5),
+        )
+        .with_route_cache(backend.cache());
+        let ctx1 = RpcContext::default().database("db1".to_string());
+        let ctx2 = RpcContext::default().database("db2".to_string());
+        let tables = vec![table];
+        for _ in 0..2 {
+            let route_res1 = route_client.route(&tables, &ctx1).await.unwrap();
+            assert_eq!(&endpoint1, route_res1[0].as_ref().unwrap());
+            let route_res2 = route_client.route(&tables, &ctx2).await.unwrap();
+            assert_eq!(&endpoint2, route_res2[0].as_ref().unwrap());
+        }
+
+        // Evicting in one database doesn't affect the other.
+        route_table.insert(("db1".to_string(), tables[0].clone()), endpoint2.clone());
+        route_table.insert(("db2".to_string(), tables[0].clone()), endpoint1.clone());
+        route_client.evict(&tables, &ctx1);
+        let route_res1 = route_client.route(&tables, &ctx1).await.unwrap();
+        assert_eq!(&endpoint2, route_res1[0].as_ref().unwrap());
+        let route_res2 = route_client.route(&tables, &ctx2).await.unwrap();
+        assert_eq!(&endpoint2, route_res2[0].as_ref().unwrap());
+    }
+
+    async fn test_evict_all(backend: Backend) {
+        let tables = vec!["table1".to_string(), "table2".to_string()];
+        let endpoint1 = Endpoint::new("192.168.0.1".to_string(), 11);
+        let endpoint2 = Endpoint::new("192.168.0.2".to_string(), 12);
+
+        let route_table = Arc::new(DashMap::default());
+        for db in ["db1", "db2"] {
+            for table in &tables {
+                route_table.insert((db.to_string(), table.clone()), endpoint1.clone());
+            }
+        }
+        let router = mock_router(&route_table, None, backend);
+        let ctxs = [
+            RpcContext::default().database("db1".to_string()),
+            RpcContext::default().database("db2".to_string()),
+        ];
+        for ctx in &ctxs {
+            let routes = router.route(&tables, ctx).await.unwrap();
+            assert!(routes.iter().all(|r| r.as_ref() == Some(&endpoint1)));
+        }
+
+        // All the cached routes in all the databases are fetched again.
+        for mut entry in route_table.iter_mut() {
+            *entry.value_mut() = endpoint2.clone();
+        }
+        router.evict_all();
+        for ctx in &ctxs {
+            let routes = router.route(&tables, ctx).await.unwrap();
+            assert!(routes.iter().all(|r| r.as_ref() == Some(&endpoint2)));
+        }
+    }
+
+    async fn test_fallback_router(backend: Backend) {
+        let db = "db".to_string();
+        let table1 = "table1".to_string();
+        let table2 = "table2".to_string();
+        let table3 = "table3".to_string();
+        let endpoint1 = Endpoint::new("192.168.0.1".to_string(), 11);
+        let endpoint2 = Endpoint::new("192.168.0.2".to_string(), 12);
+        let endpoint3 = Endpoint::new("192.168.0.3".to_string(), 13);
+        let default_endpoint = Endpoint::new("192.168.0.5".to_string(), 15);
+
+        // The new route service only knows table1.
+        let primary_table = Arc::new(DashMap::default());
+        primary_table.insert((db.clone(), table1.clone()), endpoint1.clone());
+        let secondary_table = Arc::new(DashMap::default());
+        secondary_table.insert((db.clone(), table1.clone()), endpoint3.clone());
+        secondary_table.insert((db.clone(), table2.clone()), endpoint2.clone());
+
+        let router = FallbackRouter::new(
+            mock_router(&primary_table, None, backend),
+            mock_router(&secondary_table, Some(default_endpoint.clone()), backend),
+        );
+        let ctx = RpcContext::default().database(db.clone());
+        let tables = vec![table1.clone(), table2.clone(), table3];
+        let route_res = router.route(&tables, &ctx).await.unwrap();
+        assert_eq!(&endpoint1, route_res[0].as_ref().unwrap());
+        assert_eq!(&endpoint2, route_res[1].as_ref().unwrap());
+        assert_eq!(&default_endpoint, route_res[2].as_ref().unwrap());
+
+        // Evicting is propagated to both routers.
+        primary_table.insert((db.clone(), table1), endpoint3.clone());
+        secondary_table.insert((db, table2), endpoint3.clone());
+        router.evict(&tables[..2], &ctx);
+        let route_res = router.route(&tables[..2], &ctx).await.unwrap();
+        assert_eq!(&endpoint3, route_res[0].as_ref().unwrap());
+        assert_eq!(&endpoint3, route_res[1].as_ref().unwrap());
+    }
+
+    async fn test_fallback_on_primary_failure(backend: Backend) {
+        let db = "db".to_string();
+        let table = "table".to_string();
+        let endpoint = Endpoint::new("192.168.0.1".to_string(), 11);
+
+        let secondary_table = Arc::new(DashMap::default());
+        secondary_table.insert((db.clone(), table.clone()), endpoint.clone());
+        let router = FallbackRouter::new(
+            Box::new(FailingRouter),
+            mock_router(&secondary_table, None, backend),
+        );
+        let ctx = RpcContext::default().database(db);
+        let route_res = router.route(&[table], &ctx).await.unwrap();
+        assert_eq!(&endpoint, route_res[0].as_ref().unwrap());
+    }
+
+    async fn test_resolve_uncached(backend: Backend) {
+        let db = "db".to_string();
+        let table1 = "table1".to_string();
+        let table2 = "table2".to_string();
+        let endpoint1 = Endpoint::new("192.168.0.1".to_string(), 11);
+        let endpoint2 = Endpoint::new("192.168.0.2".to_string(), 12);
+        let default_endpoint = Endpoint::new("192.168.0.5".to_string(), 15);
+
+        let route_table = Arc::new(DashMap::default());
+        route_table.insert((db.clone(), table1.clone()), endpoint1.clone());
+        let router = mock_router(&route_table, Some(default_endpoint), backend);
+        let ctx = RpcContext::default().database(db.clone());
+        let tables = [table1.clone(), table2];
+        let route_res = router.route(&tables[..1], &ctx).await.unwrap();
+        assert_eq!(&endpoint1, route_res[0].as_ref().unwrap());
+
+        // Always resolved by the server without reading the cache.
+        route_table.insert((db, table1), endpoint2.clone());
+        let resolved = router.resolve_uncached(&tables[0], &ctx).await.unwrap();
+        assert_eq!(Some(endpoint2), resolved);
+
+        // The cache is not updated.
+        let route_res = router.route(&tables[..1], &ctx).await.unwrap();
+        assert_eq!(&endpoint1, route_res[0].as_ref().unwrap());
+
+        // The default endpoint is not used for unknown tables.
+        let resolved = router.resolve_uncached(&tables[1], &ctx).await.unwrap();
+        assert!(resolved.is_none());
+    }
+
+    async fn test_route_tables_force_refresh(backend: Backend) {
+        let db = "db".to_string();
+        let table1 = "table1".to_string();
+        let table2 = "table2".to_string();
+        let endpoint1 = Endpoint::new("192.168.0.1".to_string(), 11);
+        let endpoint2 = Endpoint::new("192.168.0.2".to_string(), 12);
+        let default_endpoint = Endpoint::new("192.168.0.5".to_string(), 15);
+
+        let route_table = Arc::new(DashMap::default());
+        route_table.insert((db.clone(), table1.clone()), endpoint1.clone());
+        let rpc_client = MockRpcClient::default().with_route_table(route_table.clone());
+        let router = RouterImpl::new(
+            Some(default_endpoint.clone()),
+            Arc::new(rpc_client.clone()),
+            Duration::from_secs(5),
+        )
+        .with_route_cache(backend.cache());
+        let ctx = RpcContext::default().database(db.clone());
+        let tables = [table1.clone(), table2];
+
+        // The unknown table is distinguished from the routed one.
+        let routes = router.route_tables(&tables, &ctx, false).await.unwrap();
+        assert_eq!(
+            routes,
+            vec![
+                TableRoute::Routed(endpoint1.clone()),
+                TableRoute::Default(default_endpoint)
+            ]
+        );
+        assert_eq!(rpc_client.calls(OperationKind::Route), 1);
+
+        // Hit the cache without forcing to refresh.
+        route_table.insert((db, table1), endpoint2.clone());
+        let routes = router
+            .route_tables(&tables[..1], &ctx, false)
+            .await
+            .unwrap();
+        assert_eq!(routes, vec![TableRoute::Routed(endpoint1.clone())]);
+        assert_eq!(rpc_client.calls(OperationKind::Route), 1);
+
+        // Fetch from remote again and repopulate the cache.
+        let routes = router.route_tables(&tables[..1], &ctx, true).await.unwrap();
+        assert_eq!(routes, vec![TableRoute::Routed(endpoint2.clone())]);
+        assert_eq!(rpc_client.calls(OperationKind::Route), 2);
+        let routes = router
+            .route_tables(&tables[..1], &ctx, false)
+            .await
+            .unwrap();
+        assert_eq!(routes, vec![TableRoute::Routed(endpoint2)]);
+        assert_eq!(rpc_client.calls(OperationKind::Route), 2);
+    }
+
+    async fn test_prefetch_related_tables(backend: Backend) {
+        let db = "db".to_string();
+        let endpoint1 = Endpoint::new("192.168.0.1".to_string(), 11);
+        let endpoint2 = Endpoint::new("192.168.0.2".to_string(), 12);
+        let default_endpoint = Endpoint::new("192.168.0.5".to_string(), 15);
+
+        let route_table = Arc::new(DashMap::default());
+        for (table, endpoint) in [
+            ("metrics_01", &endpoint1),
+            ("metrics_02", &endpoint2),
+            ("metrics_03", &endpoint1),
+        ] {
+            route_table.insert((db.clone(), table.to_string()), endpoint.clone());
+        }
+        let rpc_client = MockRpcClient::default().with_route_table(route_table.clone());
+        // The next month is related, and the unknown table is prefetched too.
+        let related_tables = RelatedTables::new(|table| match table {
+            "metrics_01" => vec!["metrics_02".to_string(), "unknown".to_string()],
+            "metrics_02" => vec!["metrics_03".to_string()],
+            _ => vec![],
+        });
+        let router = RouterImpl::new(
+            Some(default_endpoint.clone()),
+            Arc::new(rpc_client.clone()),
+            Duration::from_secs(5),
+        )
+        .with_route_cache(backend.cache())
+        .with_related_tables(Some(related_tables));
+        let ctx = RpcContext::default().database(db.clone());
+
+        // Only the requested tables are returned.
+        let route_res = router
+            .route(&["metrics_01".to_string()], &ctx)
+            .await
+            .unwrap();
+        assert_eq!(route_res, vec![Some(endpoint1.clone())]);
+        assert_eq!(rpc_client.calls(OperationKind::Route), 1);
+
+        // The related table is found in the cache.
+        route_table.insert((db.clone(), "metrics_02".to_string()), endpoint1.clone());
+        let route_res = router
+            .route(&["metrics_02".to_string()], &ctx)
+            .await
+            .unwrap();
+        assert_eq!(route_res, vec![Some(endpoint2)]);
+        assert_eq!(rpc_client.calls(OperationKind::Route), 1);
+
+        // The unknown table is not cached, and the related tables of a table in
+        // the cache are not prefetched.
+        let route_res = router.route(&["unknown".to_string()], &ctx).await.unwrap();
+        assert_eq!(route_res, vec![Some(default_endpoint)]);
+        assert_eq!(rpc_client.calls(OperationKind::Route), 2);
+        assert_eq!(router.cache.len(), 2);
+    }
+
+    async fn test_evict_if_stale(backend: Backend) {
+        let db = "db".to_string();
+        let table = "table".to_string();
+        let endpoint1 = Endpoint::new("192.168.0.1".to_string(), 11);
+        let endpoint2 = Endpoint::new("192.168.0.2".to_string(), 12);
+
+        let route_table = Arc::new(DashMap::default());
+        route_table.insert((db.clone(), table.clone()), endpoint1.clone());
+        let rpc_client = MockRpcClient::default().with_route_table(route_table.clone());
+        let router = Arc::new(
+            RouterImpl::new(None, Arc::new(rpc_client.clone()), Duration::from_secs(5))
+                .with_route_cache(backend.cache()),
+        );
+        let ctx = RpcContext::default().database(db.clone());
+        let tables = vec![table.clone()];
+
+        // Task A routes the table to the old endpoint.
+        let routes = router
+            .route_tables_with_generations(&tables, &ctx, false)
+            .await
+            .unwrap();
+        let (route, stale_generation) = routes[0].clone();
+        assert_eq!(route, TableRoute::Routed(endpoint1));
+        let stale_generation = stale_generation.unwrap();
+
+        // Task B refreshes the route after the table is moved.
+        route_table.insert((db, table.clone()), endpoint2.clone());
+        let refreshing = {
+            let router = router.clone();
+            let ctx = ctx.clone();
+            let tables = tables.clone();
+            tokio::spawn(async move {
+                router
+                    .route_tables_with_generations(&tables, &ctx, true)
+                    .await
+            })
+        };
+        let routes = refreshing.await.unwrap().unwrap();
+        let fresh_generation = routes[0].1.unwrap();
+        assert_ne!(stale_generation, fresh_generation);
+
+        // Task A and others fail on the old endpoint, and try to evict concurrently.
+        let evicts: Vec<_> = (0..4)
+            .map(|_| {
+                let router = router.clone();
+                let ctx = ctx.clone();
+                let table = table.clone();
+                tokio::spawn(async move { router.evict_if_stale(&table, stale_generation, &ctx) })
+            })
+            .collect();
+        for evict in evicts {
+            evict.await.unwrap();
+        }
+
+        // The fresh route survives, and no route rpc is needed.
+        let routes = router.route(&tables, &ctx).await.unwrap();
+        assert_eq!(routes[0].as_ref(), Some(&endpoint2));
+        assert_eq!(rpc_client.calls(OperationKind::Route), 2);
+
+        // The route of the current generation is evicted.
+        router.evict_if_stale(&table, fresh_generation, &ctx);
+        router.route(&tables, &ctx).await.unwrap();
+        assert_eq!(rpc_client.calls(OperationKind::Route), 3);
+    }
+
+    async fn test_refresh(backend: Backend) {
+        let db = "db".to_string();
+        let table1 = "table1".to_string();
+        let table2 = "table2".to_string();
+        let endpoint1 = Endpoint::new("192.168.0.1".to_string(), 11);
+        let endpoint2 = Endpoint::new("192.168.0.2".to_string(), 12);
+        let default_endpoint = Endpoint::new("192.168.0.5".to_string(), 15);
+
+        let route_table = Arc::new(DashMap::default());
+        route_table.insert((db.clone(), table1.clone()), endpoint1.clone());
+        route_table.insert((db.clone(), table2.clone()), endpoint1.clone());
+        let router = mock_router(&route_table, Some(default_endpoint.clone()), backend);
+        let ctx = RpcContext::default().database(db.clone());
+        let tables = [table1, table2];
+        let route_res = router.route(&tables, &ctx).await.unwrap();
+        assert_eq!(&endpoint1, route_res[0].as_ref().unwrap());
+        assert_eq!(&endpoint1, route_res[1].as_ref().unwrap());
+
+        // Only the refreshed table is updated in the cache.
+        route_table.insert((db.clone(), tables[0].clone()), endpoint2.clone());
+        route_table.insert((db.clone(), tables[1].clone()), endpoint2.clone());
+        let refreshed = router.refresh(&tables[0], &ctx).await.unwrap();
+        assert_eq!(Some(endpoint2.clone()), refreshed);
+        let route_res = router.route(&tables, &ctx).await.unwrap();
+        assert_eq!(&endpoint2, route_res[0].as_ref().unwrap());
+        assert_eq!(&endpoint1, route_res[1].as_ref().unwrap());
+
+        // The cached route is removed if the table is no longer routed.
+        route_table.remove(&(db, tables[0].clone()));
+        let refreshed = router.refresh(&tables[0], &ctx).await.unwrap();
+        assert!(refreshed.is_none());
+        let route_res = router.route(&tables[..1], &ctx).await.unwrap();
+        assert_eq!(&default_endpoint, route_res[0].as_ref().unwrap());
+    }
+
+    async fn test_evict_by_endpoint(backend: Backend) {
+        let db = "db".to_string();
+        let endpoint1 = Endpoint::new("192.168.0.1".to_string(), 11);
+        let endpoint2 = Endpoint::new("192.168.0.2".to_string(), 12);
+        let tables: Vec<_> = (1..=4).map(|i| format!("table{i}")).collect();
+
+        let route_table = Arc::new(DashMap::default());
+        route_table.insert((db.clone(), tables[0].clone()), endpoint1.clone());
+        route_table.insert((db.clone(), tables[1].clone()), endpoint1.clone());
+        route_table.insert((db.clone(), tables[2].clone()), endpoint2.clone());
+        route_table.insert(
+            ("other_db".to_string(), tables[3].clone()),
+            endpoint1.clone(),
+        );
+        let router = mock_router_impl(&route_table, None, backend);
+        let ctx = RpcContext::default().database(db.clone());
+        let other_ctx = RpcContext::default().database("other_db".to_string());
+        router.route(&tables[..3], &ctx).await.unwrap();
+        router.route(&tables[3..], &other_ctx).await.unwrap();
+
+        // The routes in all the databases are evicted.
+        assert_eq!(router.evict_by_endpoint(&endpoint1), 3);
+        assert_eq!(router.evict_by_endpoint(&endpoint1), 0);
+        assert_eq!(router.cached_endpoints(), vec![endpoint2.clone()]);
+
+        // The route moved away from the endpoint is not evicted by it.
+        router.route(&tables[..3], &ctx).await.unwrap();
+        route_table.insert((db.clone(), tables[0].clone()), endpoint2.clone());
+        router.refresh(&tables[0], &ctx).await.unwrap();
+        assert_eq!(router.evict_by_endpoint(&endpoint1), 1);
+        let routes = router.route(&tables[..3], &ctx).await.unwrap();
+        assert_eq!(
+            routes,
+            vec![
+                Some(endpoint2.clone()),
+                Some(endpoint1),
+                Some(endpoint2.clone())
+            ]
+        );
+        assert_eq!(router.evict_by_endpoint(&endpoint2), 2);
+    }
+
+    async fn test_route_change_hook(backend: Backend) {
+        let db = "db".to_string();
+        let table = "table1".to_string();
+        let endpoint1 = Endpoint::new("192.168.0.1".to_string(), 11);
+        let endpoint2 = Endpoint::new("192.168.0.2".to_string(), 12);
+        let endpoint3 = Endpoint::new("192.168.0.3".to_string(), 13);
+
+        let changes = Arc::new(Mutex::new(Vec::new()));
+        let hook = {
+            let changes = changes.clone();
+            RouteChangeHook::new(move |table, old, new| {
+                changes.lock().unwrap().push((table.to_string(), old, new));
+            })
+        };
+        let route_table = Arc::new(DashMap::default());
+        route_table.insert((db.clone(), table.clone()), endpoint1.clone());
+        let router = mock_router_impl(&route_table, None, backend)
+            .with_route_change_hook(Some(hook.clone()));
+        let ctx = RpcContext::default().database(db.clone());
+        let tables = [table.clone()];
+
+        // The first placement of a table is not a change.
+        router.route(&tables, &ctx).await.unwrap();
+        assert!(changes.lock().unwrap().is_empty());
+
+        // The change is observed by the refresh rather than the cached route.
+        route_table.insert((db.clone(), table.clone()), endpoint2.clone());
+        router.route(&tables, &ctx).await.unwrap();
+        assert!(changes.lock().unwrap().is_empty());
+        router.refresh(&table, &ctx).await.unwrap();
+        assert_eq!(
+            changes.lock().unwrap().drain(..).collect::<Vec<_>>(),
+            vec![(table.clone(), Some(endpoint1), endpoint2.clone())]
+        );
+        assert_eq!(hook.changes(), 1);
+
+        // Refreshing or re-routing to the same endpoint is not a change.
+        router.refresh(&table, &ctx).await.unwrap();
+        router.evict(&tables, &ctx);
+        router.route(&tables, &ctx).await.unwrap();
+        assert!(changes.lock().unwrap().is_empty());
+
+        // The concurrent refreshes observing the same change notify it once.
+        route_table.insert((db.clone(), table.clone()), endpoint3.clone());
+        let (res1, res2) =
+            futures::join!(router.refresh(&table, &ctx), router.refresh(&table, &ctx));
+        res1.unwrap();
+        res2.unwrap();
+        assert_eq!(
+            changes.lock().unwrap().drain(..).collect::<Vec<_>>(),
+            vec![(table.clone(), Some(endpoint2), endpoint3.clone())]
+        );
+        assert_eq!(hook.changes(), 2);
+
+        // The table routed again after being unrouted has no old endpoint.
+        route_table.remove(&(db.clone(), table.clone()));
+        router.refresh(&table, &ctx).await.unwrap();
+        assert!(changes.lock().unwrap().is_empty());
+        route_table.insert((db, table.clone()), endpoint3.clone());
+        router.route(&tables, &ctx).await.unwrap();
+        assert_eq!(
+            changes.lock().unwrap().drain(..).collect::<Vec<_>>(),
+            vec![(table, None, endpoint3)]
+        );
+        assert_eq!(hook.changes(), 3);
+    }
+
+    #[test]
+    fn test_route_change_hook_max_tables() {
+        let changes = Arc::new(Mutex::new(Vec::new()));
+        let hook = {
+            let changes = changes.clone();
+            RouteChangeHook::new(move |table, old, new| {
+                changes.lock().unwrap().push((table.to_string(), old, new));
+            })
+            .with_max_tables(4)
+        };
+        let endpoint1 = Endpoint::new("192.168.0.1".to_string(), 11);
+        let endpoint2 = Endpoint::new("192.168.0.2".to_string(), 12);
+        let key = |i: usize| ("db".to_string(), format!("table{i}"));
+
+        for i in 0..4 {
+            hook.observe(key(i), endpoint1.clone(), || {});
+        }
+        // The latest table is observed again to keep it.
+        hook.observe(key(3), endpoint1.clone(), || {});
+        assert_eq!(hook.placements.len(), 4);
+
+        // Only the tables observed lately are kept beyond the max tables.
+        hook.observe(key(4), endpoint1.clone(), || {});
+        assert_eq!(hook.placements.len(), 2);
+        assert!(hook.placements.contains_key(&key(3)));
+        assert!(hook.placements.contains_key(&key(4)));
+
+        // The forgotten table is taken as routed for the first time.
+        hook.observe(key(0), endpoint2.clone(), || {});
+        hook.observe(key(3), endpoint2.clone(), || {});
+        assert_eq!(
+            *changes.lock().unwrap(),
+            vec![("table3".to_string(), Some(endpoint1), endpoint2)]
+        );
+        assert_eq!(hook.changes(), 1);
+    }
+
+    #[test]
+    fn test_shard_amount() {
+        let router = mock_router_impl(&Arc::new(DashMap::default()), None, Backend::Memory);
+        assert!(router.shard_amount().is_power_of_two());
+
+        for (shard_amount, expected) in [(1, 2), (3, 4), (64, 64)] {
+            let router = mock_router_impl(
+                &Arc::new(DashMap::default()),
+                Some(shard_amount),
+                Backend::Memory,
+            );
+            assert_eq!(router.shard_amount(), expected);
+        }
+    }
+
+    async fn test_export_import_cache(backend: Backend) {
+        let db = "db".to_string();
+        let ctx = RpcContext::default().database(db.clone());
+        let tables = vec!["table1".to_string(), "table2".to_string()];
+        let endpoint1 = Endpoint::new("192.168.0.1".to_string(), 11);
+        let endpoint2 = Endpoint::new("192.168.0.2".to_string(), 12);
+        let route_table = Arc::new(DashMap::default());
+        route_table.insert((db.clone(), tables[0].clone()), endpoint1.clone());
+        route_table.insert((db.clone(), tables[1].clone()), endpoint2.clone());
+
+        let router = mock_router_impl(&route_table, None, backend);
+        router.route(&tables, &ctx).await.unwrap();
+        let exported = router.export_cache(&db);
+        assert_eq!(
+            exported,
+            vec![
+                (tables[0].clone(), endpoint1.clone()),
+                (tables[1].clone(), endpoint2.clone())
+            ]
+        );
+        assert!(router.export_cache("other_db").is_empty());
+
+        // The restarted router knows no table from remote, and its cache is warmed
+        // by the imported routes.
+        let clock = ManualClock::new();
+        let ttl = Duration::from_secs(10);
+        let router = mock_router_impl(&Arc::new(DashMap::default()), None, backend)
+            .with_clock(Arc::new(clock.clone()));
+        for invalid in [
+            (String::new(), endpoint1.clone()),
+            (tables[0].clone(), Endpoint::new(String::new(), 11)),
+            (
+                tables[0].clone(),
+                Endpoint::new("192.168.0.1".to_string(), 0),
+            ),
+            (tables[0].clone(), Endpoint::new("in valid".to_string(), 11)),
+        ] {
+            let entries = vec![exported[0].clone(), invalid];
+            let result = router.import_cache(&db, entries, ttl);
+            assert!(matches!(result, Err(Error::Client(_))));
+        }
+        assert!(router.export_cache(&db).is_empty());
+
+        assert_eq!(router.import_cache(&db, exported.clone(), ttl).unwrap(), 2);
+        let routes = router.route(&tables, &ctx).await.unwrap();
+        assert_eq!(routes, vec![Some(endpoint1.clone()), Some(endpoint2)]);
+        assert_eq!(router.export_cache(&db), exported);
+
+        // The imported routes expire after the ttl from the import.
+        clock.advance(ttl);
+        assert!(router.export_cache(&db).is_empty());
+        let routes = router.route(&tables, &ctx).await.unwrap();
+        assert_eq!(routes, vec![None, None]);
+
+        // The fresher routes in the cache aren't overwritten.
+        let router = mock_router_impl(&route_table, None, backend);
+        router.route(&tables[..1], &ctx).await.unwrap();
+        let stale = vec![(
+            tables[0].clone(),
+            Endpoint::new("192.168.0.3".to_string(), 13),
+        )];
+        assert_eq!(router.import_cache(&db, stale, ttl).unwrap(), 0);
+        let routes = router.route(&tables[..1], &ctx).await.unwrap();
+        assert_eq!(routes, vec![Some(endpoint1)]);
+    }
+
+    async fn test_load_balance(backend: Backend) {
+        let db = "db".to_string();
+        let ctx = RpcContext::default().database(db.clone());
+        let tables = vec!["table".to_string()];
+        let replicas: Vec<_> = (1..=3)
+            .map(|i| Endpoint::new(format!("192.168.0.{i}"), 10 + i))
+            .collect();
+        let replicas_router = |policy| {
+            let rpc_client = replicas_rpc_client(replicas.clone());
+            RouterImpl::new(None, Arc::new(rpc_client), Duration::from_secs(5))
+                .with_route_cache(backend.cache())
+                .with_load_balance_policy(policy)
+        };
+        let route_times = |router: RouterImpl, times: usize| {
+            let (tables, ctx) = (&tables, &ctx);
+            async move {
+                let mut endpoints = Vec::new();
+                for _ in 0..times {
+                    let routes = router.route(tables, ctx).await.unwrap();
+                    endpoints.push(routes[0].clone().unwrap());
+                }
+                endpoints
+            }
+        };
+
+        let router = replicas_router(LoadBalancePolicy::First);
+        assert_eq!(router.cached_endpoints().len(), 0);
+        let endpoints = route_times(router, 3).await;
+        assert_eq!(endpoints, vec![replicas[0].clone(); 3]);
+
+        let router = replicas_router(LoadBalancePolicy::RoundRobin);
+        let endpoints = route_times(router, 4).await;
+        let mut expected = replicas.clone();
+        expected.push(replicas[0].clone());
+        assert_eq!(endpoints, expected);
+
+        let router = replicas_router(LoadBalancePolicy::Random);
+        let endpoints = route_times(router, 10).await;
+        assert!(endpoints.iter().all(|endpoint| replicas.contains(endpoint)));
+
+        // The earlier one of the least loaded candidates is picked, and the first
+        // one without the counter.
+        let in_flight: Arc<DashMap<Endpoint, usize>> = Arc::new(DashMap::new());
+        in_flight.insert(replicas[0].clone(), 2);
+        in_flight.insert(replicas[1].clone(), 1);
+        in_flight.insert(replicas[2].clone(), 1);
+        let counter = {
+            let in_flight = in_flight.clone();
+            InFlightCounter::new(move |endpoint| *in_flight.get(endpoint).unwrap())
+        };
+        let router = replicas_router(LoadBalancePolicy::LeastConnections)
+            .with_in_flight_counter(Some(counter));
+        router.route(&tables, &ctx).await.unwrap();
+        in_flight.insert(replicas[1].clone(), 3);
+        let endpoints = route_times(router, 2).await;
+        assert_eq!(endpoints, vec![replicas[2].clone(); 2]);
+        let router = replicas_router(LoadBalancePolicy::LeastConnections);
+        let endpoints = route_times(router, 2).await;
+        assert_eq!(endpoints, vec![replicas[0].clone(); 2]);
+
+        // The unhealthy candidates are skipped unless all of them are unhealthy.
+        let unhealthy: Arc<DashSet<Endpoint>> = Arc::new(DashSet::new());
+        let health = {
+            let unhealthy = unhealthy.clone();
+            HealthFilter::new(move |endpoint| !unhealthy.contains(endpoint))
+        };
+        let router =
+            replicas_router(LoadBalancePolicy::RoundRobin).with_health_filter(Some(health));
+        unhealthy.insert(replicas[1].clone());
+        let mut endpoints = Vec::new();
+        for _ in 0..4 {
+            endpoints.push(router.route_one(&tables[0], &ctx).await.unwrap().unwrap());
+        }
+        assert!(!endpoints.contains(&replicas[1]));
+        unhealthy.insert(replicas[0].clone());
+        let endpoint = router.route_one(&tables[0], &ctx).await.unwrap();
+        assert_eq!(endpoint, Some(replicas[2].clone()));
+        unhealthy.insert(replicas[2].clone());
+        let endpoint = router.route_one(&tables[0], &ctx).await.unwrap();
+        assert!(replicas.contains(&endpoint.unwrap()));
+
+        // All the candidates are cached, exported and imported in order.
+        let router = replicas_router(LoadBalancePolicy::First);
+        router.route(&tables, &ctx).await.unwrap();
+        let mut cached = router.cached_endpoints();
+        cached.sort_by_key(|endpoint| endpoint.to_string());
+        assert_eq!(cached, replicas);
+        let exported = router.export_cache(&db);
+        let expected: Vec<_> = replicas
+            .iter()
+            .map(|endpoint| (tables[0].clone(), endpoint.clone()))
+            .collect();
+        assert_eq!(exported, expected);
+        let router = mock_router_impl(&Arc::new(DashMap::default()), None, backend)
+            .with_load_balance_policy(LoadBalancePolicy::RoundRobin);
+        let imported = router
+            .import_cache(&db, exported, Duration::from_secs(10))
+            .unwrap();
+        assert_eq!(imported, 1);
+        let endpoints = route_times(router, 3).await;
+        assert_eq!(endpoints, replicas);
+    }
+
+    async fn test_route_replicas(backend: Backend) {
+        let db = "db".to_string();
+        let ctx = RpcContext::default().database(db.clone());
+        let tables = vec!["table1".to_string(), "table2".to_string()];
+        let replicas: Vec<_> = (1..=3)
+            .map(|i| Endpoint::new(format!("192.168.0.{i}"), 10 + i))
+            .collect();
+        let rpc_client = replicas_rpc_client(replicas.clone());
+        let inner = RouterImpl::new(None, Arc::new(rpc_client), Duration::from_secs(5))
+            .with_route_cache(backend.cache())
+            .with_load_balance_policy(LoadBalancePolicy::RoundRobin);
+        let overrides = Arc::new(RouteOverrides::default());
+        let router = OverridingRouter::new(overrides.clone(), Box::new(inner));
+
+        // The first candidate is the leader, and all of them are kept in the cache
+        // no matter which one is picked.
+        let routes = router.route_replicas(&tables, &ctx).await.unwrap();
+        let route = routes[0].clone().unwrap();
+        assert_eq!(route.table, tables[0]);
+        assert_eq!(route.leader(), Some(&replicas[0]));
+        assert_eq!(
+            route.followers().collect::<Vec<_>>(),
+            vec![&replicas[1], &replicas[2]]
+        );
+        router.route(&tables, &ctx).await.unwrap();
+        let cached = router.route_replicas(&tables, &ctx).await.unwrap();
+        assert_eq!(cached, routes);
+
+        // The pinned endpoint is the only replica.
+        let pinned = Endpoint::new("192.168.0.8".to_string(), 18);
+        overrides.insert(Some(&db), &tables[1], pinned.clone());
+        let routes = router.route_replicas(&tables, &ctx).await.unwrap();
+        assert_eq!(routes[0], cached[0]);
+        let expected = Route {
+            table: tables[1].clone(),
+            replicas: vec![Replica {
+                endpoint: pinned,
+                role: ReplicaRole::Leader,
+            }],
+        };
+        assert_eq!(routes[1], Some(expected));
+    }
+
+    async fn test_route_overrides(backend: Backend) {
+        let db = "db".to_string();
+        let table1 = "table1".to_string();
+        let table2 = "table2".to_string();
+        let table3 = "table3".to_string();
+        let endpoint1 = Endpoint::new("192.168.0.1".to_string(), 11);
+        let endpoint2 = Endpoint::new("192.168.0.2".to_string(), 12);
+        let pinned = Endpoint::new("192.168.0.8".to_string(), 18);
+        let db_pinned = Endpoint::new("192.168.0.9".to_string(), 19);
+
+        let route_table = Arc::new(DashMap::default());
+        route_table.insert((db.clone(), table1.clone()), endpoint1.clone());
+        route_table.insert((db.clone(), table2.clone()), endpoint2.clone());
+        let rpc_client = MockRpcClient::default().with_route_table(route_table.clone());
+        let inner = RouterImpl::new(None, Arc::new(rpc_client.clone()), Duration::from_secs(5))
+            .with_route_cache(backend.cache());
+        let overrides = Arc::new(RouteOverrides::default());
+        let router = OverridingRouter::new(overrides.clone(), Box::new(inner));
+        let ctx = RpcContext::default().database(db.clone());
+        let tables = vec![table1.clone(), table2.clone()];
+
+        // Cache the remote routes before pinning table1.
+        let routes = router.route(&tables, &ctx).await.unwrap();
+        assert_eq!(
+            routes,
+            vec![Some(endpoint1.clone()), Some(endpoint2.clone())]
+        );
+        assert_eq!(rpc_client.calls(OperationKind::Route), 1);
+
+        // The pinned route takes precedence over the cached one, and the one
+        // pinned in the database over the one for all the databases.
+        overrides.insert(None, &table1, pinned.clone());
+        overrides.insert(None, &table3, pinned.clone());
+        overrides.insert(Some(&db), &table3, db_pinned.clone());
+        let tables = vec![table1.clone(), table2.clone(), table3.clone()];
+        let routes = router
+            .route_tables_with_generations(&tables, &ctx, false)
+            .await
+            .unwrap();
+        assert_eq!(routes[0], (TableRoute::Routed(pinned.clone()), None));
+        assert_eq!(routes[1].0, TableRoute::Routed(endpoint2.clone()));
+        assert_eq!(routes[2], (TableRoute::Routed(db_pinned.clone()), None));
+        assert_eq!(rpc_client.calls(OperationKind::Route), 1);
+
+        // The pinned tables never hit the route service, even if refreshing.
+        let routes = router.route(&tables[..1], &ctx).await.unwrap();
+        assert_eq!(routes, vec![Some(pinned.clone())]);
+        router.route_tables(&tables[2..], &ctx, true).await.unwrap();
+        let resolved = router.resolve_uncached(&table1, &ctx).await.unwrap();
+        assert_eq!(resolved, Some(pinned.clone()));
+        let other_ctx = RpcContext::default().database("other_db".to_string());
+        let routes = router.route(&tables[2..], &other_ctx).await.unwrap();
+        assert_eq!(routes, vec![Some(pinned.clone())]);
+        assert_eq!(rpc_client.calls(OperationKind::Route), 1);
+
+        // Evicting leaves the pinned routes untouched.
+        router.evict(&tables, &ctx);
+        router.evict_all();
+        let routes = router.route(&tables, &ctx).await.unwrap();
+        assert_eq!(
+            routes,
+            vec![
+                Some(pinned.clone()),
+                Some(endpoint2.clone()),
+                Some(db_pinned.clone())
+            ]
+        );
+        assert_eq!(rpc_client.calls(OperationKind::Route), 2);
+        let mut endpoints = router.cached_endpoints();
+        endpoints.sort_by_key(|endpoint| endpoint.to_string());
+        assert_eq!(endpoints, vec![endpoint2, pinned.clone(), db_pinned]);
+
+        // The unpinned table is routed by the route service again.
+        assert_eq!(overrides.remove(None, &table1), Some(pinned));
+        route_table.insert((db, table1.clone()), endpoint1.clone());
+        let routes = router.route(&[table1], &ctx).await.unwrap();
+        assert_eq!(routes, vec![Some(endpoint1)]);
+        assert_eq!(rpc_client.calls(OperationKind::Route), 3);
+    }
+
+    fn mock_router_impl(
+        route_table: &Arc<DashMap<(String, String), Endpoint>>,
+        shard_amount: Option<usize>,
+        backend: Backend,
+    ) -> RouterImpl {
+        let mock_rpc_client = MockRpcClient::default().with_route_table(route_table.clone());
+        RouterImpl::new(None, Arc::new(mock_rpc_client), Duration::from_secs(5))
+            .with_shard_amount(shard_amount)
+            .with_route_cache(backend.cache())
+    }
+
+    test_backends!(
+        test_basic_flow,
+        test_route_duplicates_and_empties,
+        test_route_one,
+        test_unexpected_extra_route,
+        test_malformed_routes,
+        test_epoch_invalidation,
+        test_route_in_databases,
+        test_evict_all,
+        test_fallback_router,
+        test_fallback_on_primary_failure,
+        test_resolve_uncached,
+        test_route_tables_force_refresh,
+        test_prefetch_related_tables,
+        test_evict_if_stale,
+        test_refresh,
+        test_export_import_cache,
+        test_load_balance,
+        test_route_replicas,
+        test_route_overrides,
+        test_route_change_hook,
+        test_evict_by_endpoint,
+    );
+
+    #[tokio::test]
+    async fn test_file_route_cache_restart() {
+        let db = "db".to_string();
+        let ctx = RpcContext::default().database(db.clone());
+        let tables = vec!["table1".to_string(), "table2".to_string()];
+        let endpoint1 = Endpoint::new("192.168.0.1".to_string(), 11);
+        let endpoint2 = Endpoint::new("192.168.0.2".to_string(), 12);
+        let route_table = Arc::new(DashMap::default());
+        route_table.insert((db.clone(), tables[0].clone()), endpoint1.clone());
+        route_table.insert((db.clone(), tables[1].clone()), endpoint2.clone());
+        let path = temp_cache_path();
+
+        let cache = FileRouteCache::open(&path, flushed_on_drop()).unwrap();
+        let router = mock_router_impl(&route_table, None, Backend::Memory)
+            .with_route_cache(Some(cache.clone()));
+        router.route(&tables, &ctx).await.unwrap();
+        drop(router);
+        drop(cache);
+
+        // The restarted router serves the persisted routes without any route rpc.
+        let rpc_client = MockRpcClient::default().with_route_table(Arc::new(DashMap::default()));
+        let cache = FileRouteCache::open(&path, flushed_on_drop()).unwrap();
+        assert_eq!(cache.len(), 2);
+        let router = RouterImpl::new(None, Arc::new(rpc_client.clone()), Duration::from_secs(5))
+            .with_route_cache(Some(cache.clone()));
+        let routes = router.route(&tables, &ctx).await.unwrap();
+        assert_eq!(routes, vec![Some(endpoint1.clone()), Some(endpoint2)]);
+        assert_eq!(rpc_client.calls(OperationKind::Route), 0);
+
+        // The evicted routes are not persisted.
+        router.evict(&tables[1..], &ctx);
+        drop(router);
+        drop(cache);
+        let cache = FileRouteCache::open(&path, flushed_on_drop()).unwrap();
+        let entries = cache.entries();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].0, (db, tables[0].clone()));
+        assert_eq!(entries[0].1.endpoints, vec![endpoint1]);
+        drop(cache);
+
+        // The stale snapshot is ignored.
+        let config = FileRouteCacheConfig {
+            max_staleness: Duration::ZERO,
+            ..flushed_on_drop()
+        };
+        assert!(FileRouteCache::open(&path, config).unwrap().is_empty());
+
+        // The malformed snapshot fails to open.
+        let mut bytes = fs::read(&path).unwrap();
+        bytes.truncate(bytes.len() - 1);
+        fs::write(&path, bytes).unwrap();
+        assert!(FileRouteCache::open(&path, flushed_on_drop()).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_file_route_cache_flush_periodically() {
+        let path = temp_cache_path();
+        let config = FileRouteCacheConfig {
+            flush_interval: Duration::from_millis(10),
+            ..Default::default()
+        };
+        let cache = FileRouteCache::open(&path, config).unwrap();
+        let entry = RouteEntry {
+            endpoints: vec![Endpoint::new("192.168.0.1".to_string(), 11)],
+            epoch: 0,
+            generation: 0,
+            expire_at: None,
+        };
+        cache.insert_batch(vec![(("db".to_string(), "table".to_string()), entry)]);
+
+        // Flushed in the background while the cache is alive.
+        let mut flushed = false;
+        for _ in 0..100 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            if path.exists() {
+                flushed = true;
+                break;
+            }
+        }
+        assert!(flushed);
+        drop(cache);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_route_timeout() {
+        let db = "db".to_string();
+        let route_table = Arc::new(DashMap::default());
+        route_table.insert(
+            (db.clone(), "table1".to_string()),
+            Endpoint::new("192.168.0.1".to_string(), 11),
+        );
+        let timeouts = Arc::new(Mutex::new(Vec::new()));
+        let base = MockRpcClient::default().with_route_table(route_table);
+        let rpc_client = {
+            let timeouts = timeouts.clone();
+            base.clone().on_route(move |ctx, req| {
+                timeouts.lock().unwrap().push(ctx.timeout);
+                future::ready(Ok(base.route_by_table(req)))
+            })
+        };
+        let router = RouterImpl::new(None, Arc::new(rpc_client), Duration::from_secs(5));
+        let tables = ["table1".to_string()];
+
+        // The route rpc runs within the route timeout, or the timeout of the
+        // request if it is shorter.
+        let ctx = RpcContext::default().database(db);
+        for timeout in [
+            None,
+            Some(Duration::from_secs(10)),
+            Some(Duration::from_secs(1)),
+        ] {
+            let ctx = RpcContext {
+                timeout,
+                ..ctx.clone()
+            };
+            router.evict(&tables, &ctx);
+            router.route(&tables, &ctx).await.unwrap();
+        }
+        assert_eq!(
+            *timeouts.lock().unwrap(),
+            vec![
+                Some(Duration::from_secs(5)),
+                Some(Duration::from_secs(5)),
+                Some(Duration::from_secs(1)),
+            ]
+        );
+    }
 }
@@ -0,0 +1,113 @@
+// Copyright 2022 CeresDB Project Authors. Licensed under Apache-2.0.
+
+//! Rpc client filling the context of the grpc errors
+
+use std::{future::Future, sync::Arc};
+
+use async_trait::async_trait;
+use ceresdbproto::storage::{
+    RouteRequest as RouteRequestPb, SqlQueryRequest as QueryRequestPb,
+    SqlQueryResponse as QueryResponsePb, WriteRequest as WriteRequestPb,
+    WriteResponse as WriteResponsePb,
+};
+use futures::channel::mpsc::UnboundedReceiver;
+
+use crate::{
+    clock::Clock,
+    interceptor::OperationKind,
+    rpc_client::{RouteResponse, RpcClient, RpcContext, RpcResponse, ServerCapabilities},
+    Error, Result,
+};
+
+/// Rpc client filling the operation, the `endpoint`, the tables and the
+/// elapsed time into the [`Error::Rpc`] returned by the inner client.
+pub(crate) struct ErrorContextRpcClient {
+    inner: Arc<dyn RpcClient>,
+    endpoint: String,
+    clock: Arc<dyn Clock>,
+}
+
+impl ErrorContextRpcClient {
+    pub fn new(inner: Arc<dyn RpcClient>, endpoint: String, clock: Arc<dyn Clock>) -> Self {
+        Self {
+            inner,
+            endpoint,
+            clock,
+        }
+    }
+
+    async fn call<T, Fut>(
+        &self,
+        operation: OperationKind,
+        tables: Vec<String>,
+        call: Fut,
+    ) -> Result<T>
+    where
+        Fut: Future<Output = Result<T>>,
+    {
+        let begin = self.clock.now();
+        call.await.map_err(|e| match e {
+            Error::Rpc(mut rpc_error) => {
+                let elapsed = self.clock.now().saturating_duration_since(begin);
+                rpc_error.fill_context(operation, &self.endpoint, tables, elapsed);
+                Error::Rpc(rpc_error)
+            }
+            e => e,
+        })
+    }
+}
+
+#[async_trait]
+impl RpcClient for ErrorContextRpcClient {
+    async fn sql_query(
+        &self,
+        ctx: &RpcContext,
+        req: QueryRequestPb,
+    ) -> Result<RpcResponse<QueryResponsePb>> {
+        let tables = req.tables.clone();
+        self.call(
+            OperationKind::SqlQuery,
+            tables,
+            self.inner.sql_query(ctx, req),
+        )
+        .await
+    }
+
+    async fn write(
+        &self,
+        ctx: &RpcContext,
+        req: WriteRequestPb,
+    ) -> Result<RpcResponse<WriteResponsePb>> {
+        let tables = req
+            .table_requests
+            .iter()
+            .map(|table_request| table_request.table.clone())
+            .collect();
+        self.call(OperationKind::Write, tables, self.inner.write(ctx, req))
+            .await
+    }
+
+    async fn route(&self, ctx: &RpcContext, req: RouteRequestPb) -> Result<RouteResponse> {
+        let tables = req.tables.clone();
+        self.call(OperationKind::Route, tables, self.inner.route(ctx, req))
+            .await
+    }
+
+    /// The tables are unknown because the requests are still coming.
+    async fn stream_write(
+        &self,
+        ctx: &RpcContext,
+        reqs: UnboundedReceiver<WriteRequestPb>,
+    ) -> Result<RpcResponse<WriteResponsePb>> {
+        self.call(
+            OperationKind::StreamWrite,
+            Vec::new(),
+            self.inner.stream_write(ctx, reqs),
+        )
+        .await
+    }
+
+    async fn probe_capabilities(&self, ctx: &RpcContext) -> Result<ServerCapabilities> {
+        self.inner.probe_capabilities(ctx).await
+    }
+}
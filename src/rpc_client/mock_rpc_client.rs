@@ -2,7 +2,14 @@
 
 //! Mock rpc client
 
-use std::sync::Arc;
+use std::{
+    future::Future,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
 
 use async_trait::async_trait;
 use ceresdbproto::storage::{
@@ -12,35 +19,164 @@ use ceresdbproto::storage::{
     WriteResponse as WriteResponsePb,
 };
 use dashmap::DashMap;
+use futures::{channel::mpsc::UnboundedReceiver, future::BoxFuture, FutureExt, StreamExt};
 
 use crate::{
-    model::route::Endpoint,
-    rpc_client::{RpcClient, RpcContext},
-    Result,
+    interceptor::OperationKind,
+    model::{route::Endpoint, write::row_count},
+    rpc_client::{RouteResponse, RpcClient, RpcClientFactory, RpcContext, RpcResponse},
+    Error, Result,
 };
 
-/// Rpc client used for testing.
+/// Hook serving the requests of an operation.
+type Hook<Req, Resp> =
+    Arc<dyn Fn(RpcContext, Req) -> BoxFuture<'static, Result<Resp>> + Send + Sync>;
+
+/// Hook deciding whether a request of the operation fails before it is served.
+type FailureHook = Arc<dyn Fn(OperationKind) -> Option<Error> + Send + Sync>;
+
+/// Rpc client used for testing, whose requests are served by the hooks set by
+/// the `on_*` methods, and the operations without any hook get the default
+/// responses:
+///  - sql_query: the empty response.
+///  - write: all the rows are written successfully.
+///  - route: the endpoints in the `route_table`.
+///  - stream_write: every request is served as a write.
+///
+/// Every request is counted, takes the latency set for its operation, and then
+/// fails if the failure hook returns an error for it. The clones share the
+/// route table and the counts.
+#[derive(Clone, Default)]
 pub struct MockRpcClient {
-    pub route_table: Arc<DashMap<String, Endpoint>>,
+    /// Endpoints of the tables, keyed by (database, table).
+    pub route_table: Arc<DashMap<(String, String), Endpoint>>,
+    pub route_epoch: Arc<AtomicU64>,
+    calls: Arc<Mutex<Vec<OperationKind>>>,
+    latencies: Vec<(OperationKind, Duration)>,
+    failure: Option<FailureHook>,
+    sql_query: Option<Hook<QueryRequestPb, RpcResponse<QueryResponsePb>>>,
+    write: Option<Hook<WriteRequestPb, RpcResponse<WriteResponsePb>>>,
+    route: Option<Hook<RouteRequestPb, RouteResponse>>,
+    stream_write: Option<Hook<UnboundedReceiver<WriteRequestPb>, RpcResponse<WriteResponsePb>>>,
 }
 
-#[async_trait]
-impl RpcClient for MockRpcClient {
-    async fn sql_query(&self, _ctx: &RpcContext, _req: QueryRequestPb) -> Result<QueryResponsePb> {
-        todo!()
+fn hook<Req, Resp, F, Fut>(f: F) -> Hook<Req, Resp>
+where
+    F: Fn(RpcContext, Req) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<Resp>> + Send + 'static,
+{
+    Arc::new(move |ctx, req| f(ctx, req).boxed())
+}
+
+impl MockRpcClient {
+    /// Route the tables by the `route_table`.
+    pub fn with_route_table(
+        mut self,
+        route_table: Arc<DashMap<(String, String), Endpoint>>,
+    ) -> Self {
+        self.route_table = route_table;
+        self
+    }
+
+    pub fn with_route_epoch(mut self, route_epoch: Arc<AtomicU64>) -> Self {
+        self.route_epoch = route_epoch;
+        self
+    }
+
+    /// Delay every request of the `operation` by the `latency`.
+    pub fn with_latency(mut self, operation: OperationKind, latency: Duration) -> Self {
+        self.latencies.push((operation, latency));
+        self
+    }
+
+    /// Fail the requests whose operations the `failure` returns an error for.
+    pub fn with_failure<F>(mut self, failure: F) -> Self
+    where
+        F: Fn(OperationKind) -> Option<Error> + Send + Sync + 'static,
+    {
+        self.failure = Some(Arc::new(failure));
+        self
     }
 
-    async fn write(&self, _ctx: &RpcContext, _req: WriteRequestPb) -> Result<WriteResponsePb> {
-        todo!()
+    pub fn on_sql_query<F, Fut>(mut self, f: F) -> Self
+    where
+        F: Fn(RpcContext, QueryRequestPb) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<RpcResponse<QueryResponsePb>>> + Send + 'static,
+    {
+        self.sql_query = Some(hook(f));
+        self
     }
 
-    async fn route(&self, _ctx: &RpcContext, req: RouteRequestPb) -> Result<RouteResponsePb> {
-        let route_tables = self.route_table.clone();
+    pub fn on_write<F, Fut>(mut self, f: F) -> Self
+    where
+        F: Fn(RpcContext, WriteRequestPb) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<RpcResponse<WriteResponsePb>>> + Send + 'static,
+    {
+        self.write = Some(hook(f));
+        self
+    }
+
+    pub fn on_route<F, Fut>(mut self, f: F) -> Self
+    where
+        F: Fn(RpcContext, RouteRequestPb) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<RouteResponse>> + Send + 'static,
+    {
+        self.route = Some(hook(f));
+        self
+    }
+
+    pub fn on_stream_write<F, Fut>(mut self, f: F) -> Self
+    where
+        F: Fn(RpcContext, UnboundedReceiver<WriteRequestPb>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<RpcResponse<WriteResponsePb>>> + Send + 'static,
+    {
+        self.stream_write = Some(hook(f));
+        self
+    }
+
+    /// The number of the requests of the `operation` received so far.
+    pub fn calls(&self, operation: OperationKind) -> usize {
+        let calls = self.calls.lock().unwrap();
+        calls.iter().filter(|op| **op == operation).count()
+    }
+
+    /// Count the request of the `operation`, take its latency and check its
+    /// failure.
+    async fn enter(&self, operation: OperationKind) -> Result<()> {
+        self.calls.lock().unwrap().push(operation);
+        for (_, latency) in self.latencies.iter().filter(|(op, _)| *op == operation) {
+            tokio::time::sleep(*latency).await;
+        }
+        match self.failure.as_ref().and_then(|failure| failure(operation)) {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    async fn serve_write(
+        &self,
+        ctx: &RpcContext,
+        req: WriteRequestPb,
+    ) -> Result<RpcResponse<WriteResponsePb>> {
+        match &self.write {
+            Some(write) => write(ctx.clone(), req).await,
+            None => Ok(WriteResponsePb {
+                header: None,
+                success: row_count(&req.table_requests) as u32,
+                failed: 0,
+            }
+            .into()),
+        }
+    }
+
+    /// The default response of the route request, by the `route_table`.
+    pub fn route_by_table(&self, req: RouteRequestPb) -> RouteResponse {
+        let database = req.context.map(|ctx| ctx.database).unwrap_or_default();
         let routes: Vec<_> = req
             .tables
             .iter()
             .filter_map(|m| {
-                let endpoint = match route_tables.get(m.as_str()) {
+                let endpoint = match self.route_table.get(&(database.clone(), m.clone())) {
                     Some(v) => v.value().clone(),
                     None => return None,
                 };
@@ -58,6 +194,111 @@ impl RpcClient for MockRpcClient {
             header: None,
             routes,
         };
-        Ok(route_resp)
+        RouteResponse {
+            epoch: Some(self.route_epoch.load(Ordering::Relaxed)),
+            resp: route_resp,
+        }
+    }
+}
+
+#[async_trait]
+impl RpcClient for MockRpcClient {
+    async fn sql_query(
+        &self,
+        ctx: &RpcContext,
+        req: QueryRequestPb,
+    ) -> Result<RpcResponse<QueryResponsePb>> {
+        self.enter(OperationKind::SqlQuery).await?;
+        match &self.sql_query {
+            Some(sql_query) => sql_query(ctx.clone(), req).await,
+            None => Ok(QueryResponsePb::default().into()),
+        }
+    }
+
+    async fn write(
+        &self,
+        ctx: &RpcContext,
+        req: WriteRequestPb,
+    ) -> Result<RpcResponse<WriteResponsePb>> {
+        self.enter(OperationKind::Write).await?;
+        self.serve_write(ctx, req).await
+    }
+
+    async fn route(&self, ctx: &RpcContext, req: RouteRequestPb) -> Result<RouteResponse> {
+        self.enter(OperationKind::Route).await?;
+        match &self.route {
+            Some(route) => route(ctx.clone(), req).await,
+            None => Ok(self.route_by_table(req)),
+        }
+    }
+
+    async fn stream_write(
+        &self,
+        ctx: &RpcContext,
+        mut reqs: UnboundedReceiver<WriteRequestPb>,
+    ) -> Result<RpcResponse<WriteResponsePb>> {
+        self.enter(OperationKind::StreamWrite).await?;
+        if let Some(stream_write) = &self.stream_write {
+            return stream_write(ctx.clone(), reqs).await;
+        }
+
+        let mut merged = RpcResponse::from(WriteResponsePb::default());
+        while let Some(req) = reqs.next().await {
+            let RpcResponse { warnings, resp } = self.serve_write(ctx, req).await?;
+            merged.warnings.extend(warnings);
+            merged.resp.success += resp.success;
+            merged.resp.failed += resp.failed;
+        }
+        Ok(merged)
+    }
+}
+
+/// Hook building the client to the endpoint, along with the index of the
+/// building.
+type BuildHook =
+    Arc<dyn Fn(String, usize) -> BoxFuture<'static, Result<Arc<dyn RpcClient>>> + Send + Sync>;
+
+/// Factory building the clients by the hook, and counting the buildings.
+pub struct MockRpcClientFactory {
+    build: BuildHook,
+    builds: AtomicUsize,
+}
+
+impl MockRpcClientFactory {
+    /// Build the mock client to every endpoint by `f`.
+    pub fn new<F>(f: F) -> Self
+    where
+        F: Fn(String) -> MockRpcClient + Send + Sync + 'static,
+    {
+        Self::with_build(move |endpoint, _| {
+            let client: Arc<dyn RpcClient> = Arc::new(f(endpoint));
+            futures::future::ready(Ok(client))
+        })
+    }
+
+    /// Build any client to the endpoint by `f`, with the index of the
+    /// building, e.g. to fail or delay some of the buildings.
+    pub fn with_build<F, Fut>(f: F) -> Self
+    where
+        F: Fn(String, usize) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<Arc<dyn RpcClient>>> + Send + 'static,
+    {
+        Self {
+            build: Arc::new(move |endpoint, build_idx| f(endpoint, build_idx).boxed()),
+            builds: AtomicUsize::new(0),
+        }
+    }
+
+    /// The number of the buildings, including the failed ones.
+    pub fn builds(&self) -> usize {
+        self.builds.load(Ordering::Relaxed)
+    }
+}
+
+#[async_trait]
+impl RpcClientFactory for MockRpcClientFactory {
+    async fn build(&self, endpoint: String) -> Result<Arc<dyn RpcClient>> {
+        let build_idx = self.builds.fetch_add(1, Ordering::Relaxed);
+        (self.build)(endpoint, build_idx).await
     }
 }
@@ -2,10 +2,13 @@
 
 //! Rpc client
 
+mod error_context;
+#[cfg(test)]
 mod mock_rpc_client;
+mod proxy;
 mod rpc_client_impl;
 
-use std::{sync::Arc, time::Duration};
+use std::{collections::HashMap, future::Future, sync::Arc, time::Duration};
 
 use async_trait::async_trait;
 use ceresdbproto::storage::{
@@ -13,16 +16,141 @@ use ceresdbproto::storage::{
     SqlQueryRequest as QueryRequestPb, SqlQueryResponse as QueryResponsePb,
     WriteRequest as WriteRequestPb, WriteResponse as WriteResponsePb,
 };
-pub use mock_rpc_client::MockRpcClient;
+pub(crate) use error_context::ErrorContextRpcClient;
+use futures::channel::mpsc::{self, UnboundedReceiver};
+#[cfg(test)]
+pub(crate) use mock_rpc_client::{MockRpcClient, MockRpcClientFactory};
+pub(crate) use rpc_client_impl::check_msg_len;
 pub use rpc_client_impl::RpcClientImplFactory;
+use tokio_util::sync::CancellationToken;
+use tonic::Code;
 
-use crate::errors::Result;
+use crate::{
+    errors::{Error, Result},
+    model::{route::Endpoint, warning::ServerWarning},
+    QueryGuardConfig, RpcConfig,
+};
+
+/// Name of the session setting carrying the timeout of the sql query to the
+/// server, see [`RpcContext::query_timeout`].
+pub(crate) const QUERY_TIMEOUT_SETTING: &str = "query_timeout";
 
 /// Context for rpc request.
 #[derive(Clone, Debug, Default)]
 pub struct RpcContext {
     pub database: Option<String>,
+    /// The timeout of the whole request, including the routing, the retries
+    /// and the writes to all the endpoints in the cluster mode, which share
+    /// the deadline and are cancelled once it is exceeded.
     pub timeout: Option<Duration>,
+    /// Override the [`max_send_msg_len`](crate::RpcConfig::max_send_msg_len)
+    /// for this request, and it should be positive or -1 (unlimited).
+    pub max_send_msg_len_override: Option<i32>,
+    /// The consistency hint sent to the server, and none is sent if not set.
+    ///
+    /// It wins over the read consistency of the session settings.
+    pub consistency: Option<Consistency>,
+    /// The session settings of the sql query, which override the defaults in
+    /// the [`RpcConfig::session_settings`] one by one.
+    pub settings: Option<SessionSettings>,
+    /// The priority hint sent to the server, see [`Priority`].
+    pub priority: Priority,
+    /// The endpoint preferred to serve the sql query, e.g. a replica for the
+    /// cache locality, see [`RpcContext::preferred_endpoint`].
+    pub preferred_endpoint: Option<Endpoint>,
+    /// The token to cancel the request, see [`RpcContext::cancel`].
+    pub cancel: Option<CancellationToken>,
+    /// The guardrails of the sql query, which override the
+    /// [`Builder::query_guard`](crate::Builder::query_guard) as a whole.
+    pub query_guard: Option<QueryGuardConfig>,
+}
+
+/// Consistency level of the request, which is sent to the server as a hint
+/// and may be regarded as advisory.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Consistency {
+    /// The read should see the writes completed before it.
+    Strong,
+    /// The read may miss the latest writes.
+    Eventual,
+}
+
+impl Consistency {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Consistency::Strong => "strong",
+            Consistency::Eventual => "eventual",
+        }
+    }
+}
+
+/// Settings of the session the sql query runs in, which are sent with every
+/// query instead of the `SET` statements, see
+/// [`SettingsTransport`](crate::SettingsTransport).
+///
+/// The names of the extra settings should consist of the ASCII alphanumerics
+/// and underscores, otherwise the query fails with [`Error::Client`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SessionSettings {
+    pub timezone: Option<String>,
+    pub read_consistency: Option<Consistency>,
+    pub extra: HashMap<String, String>,
+}
+
+impl SessionSettings {
+    pub fn timezone(mut self, timezone: String) -> Self {
+        self.timezone = Some(timezone);
+        self
+    }
+
+    pub fn read_consistency(mut self, read_consistency: Consistency) -> Self {
+        self.read_consistency = Some(read_consistency);
+        self
+    }
+
+    /// Set the extra setting with the `name`.
+    pub fn setting(mut self, name: String, value: String) -> Self {
+        self.extra.insert(name, value);
+        self
+    }
+
+    /// Merge the `overrides` into the settings, which win on the conflicts.
+    pub(crate) fn overridden_by(&self, overrides: &SessionSettings) -> SessionSettings {
+        let mut extra = self.extra.clone();
+        extra.extend(
+            overrides
+                .extra
+                .iter()
+                .map(|(name, value)| (name.clone(), value.clone())),
+        );
+
+        SessionSettings {
+            timezone: overrides.timezone.clone().or_else(|| self.timezone.clone()),
+            read_consistency: overrides.read_consistency.or(self.read_consistency),
+            extra,
+        }
+    }
+}
+
+/// Priority of the request, which is sent to the server as a hint for the
+/// QoS in a shared cluster, e.g. to serve the interactive queries before the
+/// batch backfill writes, and the server may ignore it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Priority {
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+impl Priority {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Priority::Low => "low",
+            Priority::Normal => "normal",
+            Priority::High => "high",
+        }
+    }
 }
 
 impl RpcContext {
@@ -35,19 +163,252 @@ impl RpcContext {
         self.timeout = Some(timeout);
         self
     }
+
+    /// Time out the sql query after `timeout` on both the client and the
+    /// server, and the two are kept in sync: it is set as the
+    /// [`timeout`](Self::timeout) of the request, and sent to the server as the
+    /// `query_timeout` session setting in milliseconds, e.g. `1500ms`.
+    ///
+    /// The setting is carried as the other session settings by the
+    /// [`SettingsTransport`](crate::SettingsTransport), i.e. in the metadata or
+    /// as a `SET` statement before the sql, and the server may ignore it.
+    pub fn query_timeout(mut self, timeout: Duration) -> Self {
+        // Rounded down so that the server gives up no later than the client.
+        let hint = format!("{}ms", timeout.as_millis().max(1));
+        let settings = self.settings.take().unwrap_or_default();
+        self.settings = Some(settings.setting(QUERY_TIMEOUT_SETTING.to_string(), hint));
+        self.timeout(timeout)
+    }
+
+    pub fn max_send_msg_len_override(mut self, max_send_msg_len: i32) -> Self {
+        self.max_send_msg_len_override = Some(max_send_msg_len);
+        self
+    }
+
+    pub fn consistency(mut self, consistency: Consistency) -> Self {
+        self.consistency = Some(consistency);
+        self
+    }
+
+    pub fn settings(mut self, settings: SessionSettings) -> Self {
+        self.settings = Some(settings);
+        self
+    }
+
+    pub fn priority(mut self, priority: Priority) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    /// Prefer the `endpoint` to serve the sql query in the cluster mode if it
+    /// is among the candidates, i.e. the routed endpoints of the queried tables
+    /// and the default endpoints, and it is not known to be unhealthy.
+    /// Otherwise the query goes to the routed endpoint as usual.
+    ///
+    /// The writes always go to the routed endpoints.
+    pub fn preferred_endpoint(mut self, endpoint: Endpoint) -> Self {
+        self.preferred_endpoint = Some(endpoint);
+        self
+    }
+
+    /// Cancel the request once the `token` is cancelled, and it fails with
+    /// [`Error::Cancelled`].
+    ///
+    /// The rpcs in flight are dropped, which resets their grpc streams, and no
+    /// more retry, hedge or page is issued. The partitions of a write in the
+    /// cluster mode are cancelled as a whole, while some of them may have been
+    /// written.
+    pub fn cancel(mut self, token: CancellationToken) -> Self {
+        self.cancel = Some(token);
+        self
+    }
+
+    /// Bound the sql query by the `config` instead of the one set on the
+    /// [`Builder::query_guard`](crate::Builder::query_guard), e.g.
+    /// [`QueryGuardConfig::default`] for the trusted callers to run the
+    /// unbounded queries.
+    pub fn query_guard(mut self, config: QueryGuardConfig) -> Self {
+        self.query_guard = Some(config);
+        self
+    }
+
+    /// Fill the fields not set in the `overrides` by the context, and the
+    /// session settings are merged one by one.
+    ///
+    /// The [`Priority::Normal`] is regarded as not set.
+    pub(crate) fn overridden_by(&self, overrides: &RpcContext) -> RpcContext {
+        let settings = match (&self.settings, &overrides.settings) {
+            (Some(settings), Some(overrides)) => Some(settings.overridden_by(overrides)),
+            (settings, overrides) => overrides.clone().or_else(|| settings.clone()),
+        };
+        let priority = match overrides.priority {
+            Priority::Normal => self.priority,
+            priority => priority,
+        };
+
+        RpcContext {
+            database: overrides.database.clone().or_else(|| self.database.clone()),
+            timeout: overrides.timeout.or(self.timeout),
+            max_send_msg_len_override: overrides
+                .max_send_msg_len_override
+                .or(self.max_send_msg_len_override),
+            consistency: overrides.consistency.or(self.consistency),
+            settings,
+            priority,
+            preferred_endpoint: overrides
+                .preferred_endpoint
+                .clone()
+                .or_else(|| self.preferred_endpoint.clone()),
+            cancel: overrides.cancel.clone().or_else(|| self.cancel.clone()),
+            query_guard: overrides
+                .query_guard
+                .clone()
+                .or_else(|| self.query_guard.clone()),
+        }
+    }
+
+    /// Fail with [`Error::Cancelled`] if the request is cancelled.
+    pub(crate) fn check_cancelled(&self) -> Result<()> {
+        match &self.cancel {
+            Some(token) if token.is_cancelled() => Err(Error::Cancelled),
+            _ => Ok(()),
+        }
+    }
+
+    /// Run the `fut` until it completes or the request is cancelled, when it
+    /// is dropped and [`Error::Cancelled`] is returned.
+    pub(crate) async fn run_cancellable<T, Fut>(&self, fut: Fut) -> Result<T>
+    where
+        Fut: Future<Output = Result<T>>,
+    {
+        match &self.cancel {
+            Some(token) => tokio::select! {
+                biased;
+                _ = token.cancelled() => Err(Error::Cancelled),
+                result = fut => result,
+            },
+            None => fut.await,
+        }
+    }
+}
+
+/// Route response along with the routing epoch reported by the server.
+///
+/// The epoch is bumped by the server when the cluster topology changes, and
+/// it is absent if the server doesn't report it.
+#[derive(Clone, Debug, Default)]
+pub struct RouteResponse {
+    pub epoch: Option<u64>,
+    pub resp: RouteResponsePb,
+}
+
+/// Response along with the [`ServerWarning`]s reported by the server, which
+/// are carried in the metadata of the response.
+#[derive(Clone, Debug, Default)]
+pub struct RpcResponse<T> {
+    pub warnings: Vec<ServerWarning>,
+    pub resp: T,
+}
+
+impl<T> From<T> for RpcResponse<T> {
+    fn from(resp: T) -> Self {
+        Self {
+            warnings: Vec::new(),
+            resp,
+        }
+    }
+}
+
+/// The newer features supported by a server, which are detected by the probe
+/// requests on the first contact if
+/// [`RpcConfig::negotiate_capabilities`](crate::RpcConfig::negotiate_capabilities)
+/// is set, and the unsupported ones are disabled for the server, e.g. an
+/// older one during a rolling upgrade.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ServerCapabilities {
+    /// The streaming write rpc, without which the streams of writes are sent
+    /// by the unary writes one by one.
+    pub stream_write: bool,
+    /// The gzip compression of the messages, without which the messages are
+    /// sent uncompressed.
+    ///
+    /// It is never probed and regarded unsupported if the compression is not
+    /// enabled by
+    /// [`RpcConfig::enable_compression`](crate::RpcConfig::enable_compression).
+    pub compression: bool,
 }
+
+/// Interpret the outcome of a probe request, whose feature is unsupported only
+/// if the server answers it unimplemented, and the transport failures are
+/// returned as nothing is detected.
+pub(crate) fn probe_outcome<T>(result: Result<T>) -> Result<bool> {
+    match result {
+        Err(Error::Rpc(e)) if e.code() == Code::Unimplemented => Ok(false),
+        Err(Error::Rpc(e))
+            if matches!(
+                e.code(),
+                Code::Unavailable | Code::DeadlineExceeded | Code::Cancelled
+            ) =>
+        {
+            Err(Error::Rpc(e))
+        }
+        Err(e @ Error::Connect { .. }) => Err(e),
+        _ => Ok(true),
+    }
+}
+
+/// Probe the streaming write rpc by an empty stream, so nothing is written.
+pub(crate) async fn probe_stream_write<C: RpcClient + ?Sized>(
+    client: &C,
+    ctx: &RpcContext,
+) -> Result<bool> {
+    let (_, reqs) = mpsc::unbounded();
+    probe_outcome(client.stream_write(ctx, reqs).await)
+}
+
 #[async_trait]
 pub trait RpcClient: Send + Sync {
-    async fn sql_query(&self, ctx: &RpcContext, req: QueryRequestPb) -> Result<QueryResponsePb>;
-    async fn write(&self, ctx: &RpcContext, req: WriteRequestPb) -> Result<WriteResponsePb>;
-    async fn route(&self, ctx: &RpcContext, req: RouteRequestPb) -> Result<RouteResponsePb>;
+    async fn sql_query(
+        &self,
+        ctx: &RpcContext,
+        req: QueryRequestPb,
+    ) -> Result<RpcResponse<QueryResponsePb>>;
+    async fn write(
+        &self,
+        ctx: &RpcContext,
+        req: WriteRequestPb,
+    ) -> Result<RpcResponse<WriteResponsePb>>;
+    async fn route(&self, ctx: &RpcContext, req: RouteRequestPb) -> Result<RouteResponse>;
+    /// Write the requests through one streaming rpc, and the response comes
+    /// after the stream of requests ends.
+    async fn stream_write(
+        &self,
+        ctx: &RpcContext,
+        reqs: UnboundedReceiver<WriteRequestPb>,
+    ) -> Result<RpcResponse<WriteResponsePb>>;
+    /// Detect the [`ServerCapabilities`] by the probe requests, and the ones
+    /// unsupported are disabled for the following requests.
+    ///
+    /// Only the streaming write is probed by default, and the compression is
+    /// regarded unsupported.
+    async fn probe_capabilities(&self, ctx: &RpcContext) -> Result<ServerCapabilities> {
+        Ok(ServerCapabilities {
+            stream_write: probe_stream_write(self, ctx).await?,
+            compression: false,
+        })
+    }
 }
 
 #[async_trait]
-pub trait RpcClientFactory: Send + Sync {
+pub trait RpcClientFactory: Send + Sync + 'static {
     /// Build `RpcClient`.
     ///
     /// It may fail because of invalid endpoint. Any caller calls this method
     /// should handle the potential error.
     async fn build(&self, endpoint: String) -> Result<Arc<dyn RpcClient>>;
+
+    /// Get the config of the built clients.
+    fn config(&self) -> RpcConfig {
+        RpcConfig::default()
+    }
 }
@@ -2,46 +2,355 @@
 
 //! Rpc client impl
 
-use std::{sync::Arc, time::Duration};
+use std::{
+    future::Future,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
 use async_trait::async_trait;
 use ceresdbproto::{
     common::ResponseHeader,
     storage::{
-        storage_service_client::StorageServiceClient, RouteRequest as RouteRequestPb,
-        RouteResponse as RouteResponsePb, SqlQueryRequest, SqlQueryResponse,
-        WriteRequest as WriteRequestPb, WriteResponse as WriteResponsePb,
+        storage_service_client::StorageServiceClient, RequestContext,
+        RouteRequest as RouteRequestPb, RouteResponse as RouteResponsePb, SqlQueryRequest,
+        SqlQueryResponse, WriteRequest as WriteRequestPb, WriteResponse as WriteResponsePb,
     },
 };
+use futures::channel::mpsc::{self, UnboundedReceiver};
+use prost::Message;
 use tonic::{
+    codec::CompressionEncoding,
+    metadata::{Ascii, MetadataKey, MetadataMap, MetadataValue},
     transport::{Channel, Endpoint},
-    Request,
+    Code, Request, Status,
 };
 
 use crate::{
-    config::RpcConfig,
+    auth::{Authenticator, AUTHORIZATION_KEY},
+    capture::{CapturingRpcClient, RequestCapture},
+    config::{RpcConfig, SettingsTransport},
     errors::{Error, Result, ServerError},
-    rpc_client::{RpcClient, RpcClientFactory, RpcContext},
+    interceptor::{Interceptors, OperationKind},
+    metrics::ClientMetrics,
+    model::{route::Endpoint as RouteEndpoint, warning::ServerWarning},
+    rpc_client::{
+        probe_outcome,
+        proxy::{resolve_proxy, ProxyConnector},
+        RouteResponse, RpcClient, RpcClientFactory, RpcContext, RpcResponse, ServerCapabilities,
+        SessionSettings,
+    },
+    spawner::Spawner,
     util::is_ok,
 };
 
+/// Metadata key of the routing epoch in the route response.
+///
+/// The epoch is carried in the metadata because there is no such field in
+/// the [`RouteResponsePb`].
+const ROUTE_EPOCH_KEY: &str = "x-ceresdb-route-epoch";
+
+/// Metadata key of the [`ServerWarning`]s in the response, which is repeated
+/// for every warning.
+const WARNING_KEY: &str = "x-ceresdb-warning-bin";
+
+/// Metadata key of the [`Consistency`](crate::Consistency) hint of the request.
+const CONSISTENCY_KEY: &str = "x-ceresdb-consistency";
+
+/// Metadata key of the [`Priority`](crate::Priority) hint of the request.
+const PRIORITY_KEY: &str = "x-ceresdb-priority";
+
+/// Metadata key of the timezone in the [`SessionSettings`].
+const TIMEZONE_KEY: &str = "x-ceresdb-timezone";
+
+/// Prefix of the metadata keys of the extra [`SessionSettings`], followed by
+/// the lowercase names.
+const SETTING_KEY_PREFIX: &str = "x-ceresdb-setting-";
+
+/// Decode the warnings in the `metadata` of the response, and the malformed
+/// ones are skipped.
+fn decode_warnings(metadata: &MetadataMap) -> Vec<ServerWarning> {
+    metadata
+        .get_all_bin(WARNING_KEY)
+        .iter()
+        .filter_map(|value| ServerWarning::decode(&value.to_bytes().ok()?))
+        .collect()
+}
+
+/// Carry the consistency hint of the `ctx` in the `metadata` if it is set.
+fn insert_consistency(ctx: &RpcContext, metadata: &mut MetadataMap) {
+    if let Some(consistency) = ctx.consistency {
+        metadata.insert(
+            CONSISTENCY_KEY,
+            MetadataValue::from_static(consistency.as_str()),
+        );
+    }
+}
+
+/// Carry the priority hint of the `ctx` in the `metadata`, which is always
+/// sent so that the server can tell the normal priority from the clients not
+/// aware of it.
+fn insert_priority(ctx: &RpcContext, metadata: &mut MetadataMap) {
+    metadata.insert(
+        PRIORITY_KEY,
+        MetadataValue::from_static(ctx.priority.as_str()),
+    );
+}
+
+/// Resolve the session settings of the query, where the settings of the `ctx`
+/// override the `defaults`, and the consistency of the `ctx` wins over both.
+fn resolve_settings(defaults: &SessionSettings, ctx: &RpcContext) -> SessionSettings {
+    let mut settings = match &ctx.settings {
+        Some(settings) => defaults.overridden_by(settings),
+        None => defaults.clone(),
+    };
+    if ctx.consistency.is_some() {
+        settings.read_consistency = ctx.consistency;
+    }
+
+    settings
+}
+
+fn check_setting_name(name: &str) -> Result<()> {
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        return Err(Error::Client(format!(
+            "invalid name of the session setting, name:{name}"
+        )));
+    }
+
+    Ok(())
+}
+
+fn setting_value(name: &str, value: &str) -> Result<MetadataValue<Ascii>> {
+    value.parse().map_err(|_| {
+        Error::Client(format!(
+            "invalid value of the session setting, name:{name}, value:{value}"
+        ))
+    })
+}
+
+/// Carry the `settings` in the `metadata`.
+fn insert_settings(settings: &SessionSettings, metadata: &mut MetadataMap) -> Result<()> {
+    if let Some(consistency) = settings.read_consistency {
+        metadata.insert(
+            CONSISTENCY_KEY,
+            MetadataValue::from_static(consistency.as_str()),
+        );
+    }
+    if let Some(timezone) = &settings.timezone {
+        metadata.insert(TIMEZONE_KEY, setting_value("timezone", timezone)?);
+    }
+    for (name, value) in &settings.extra {
+        check_setting_name(name)?;
+        let key = format!("{SETTING_KEY_PREFIX}{}", name.to_ascii_lowercase());
+        let key = MetadataKey::from_bytes(key.as_bytes()).map_err(|_| {
+            Error::Client(format!("invalid name of the session setting, name:{name}"))
+        })?;
+        metadata.insert(key, setting_value(name, value)?);
+    }
+
+    Ok(())
+}
+
+/// Prepend the `settings` to the `sql` as the `SET` statements, and the extra
+/// ones are ordered by their names.
+fn prepend_settings(settings: &SessionSettings, sql: String) -> Result<String> {
+    let mut statements = Vec::new();
+    if let Some(timezone) = &settings.timezone {
+        statements.push(("timezone", timezone.as_str()));
+    }
+    if let Some(consistency) = settings.read_consistency {
+        statements.push(("read_consistency", consistency.as_str()));
+    }
+    let mut extra: Vec<_> = settings.extra.iter().collect();
+    extra.sort_unstable();
+    for (name, value) in extra {
+        check_setting_name(name)?;
+        statements.push((name.as_str(), value.as_str()));
+    }
+    if statements.is_empty() {
+        return Ok(sql);
+    }
+
+    let mut rewritten = String::new();
+    for (name, value) in statements {
+        let value = value.replace('\'', "''");
+        rewritten.push_str(&format!("SET {name} = '{value}'; "));
+    }
+    rewritten.push_str(&sql);
+    Ok(rewritten)
+}
+
+/// Decide whether to compress the message sent to server by its size.
+#[derive(Debug, Clone, Copy)]
+struct CompressionPolicy {
+    enabled: bool,
+    min_size: usize,
+}
+
+impl CompressionPolicy {
+    fn send_encoding(&self, msg_len: usize) -> Option<CompressionEncoding> {
+        if self.enabled && msg_len >= self.min_size {
+            Some(CompressionEncoding::Gzip)
+        } else {
+            None
+        }
+    }
+}
+
+/// Check the length of the message to send, and -1 `max_send_msg_len` means
+/// unlimited.
+pub(crate) fn check_msg_len(msg_len: usize, max_send_msg_len: i32) -> Result<()> {
+    if max_send_msg_len >= 0 && msg_len > max_send_msg_len as usize {
+        return Err(Error::RequestTooLarge {
+            limit: max_send_msg_len as usize,
+            estimated_size: msg_len,
+        });
+    }
+
+    Ok(())
+}
+
+/// Check the length of the message received from `endpoint`, and -1
+/// `max_recv_msg_len` means unlimited.
+///
+/// Tonic 0.8 can't enforce any limit on the received messages, so the check
+/// is done after the message is received and decoded, which stops an oversized
+/// result from being used rather than from being received. Only the query
+/// responses are checked, because the other responses are small, and failing
+/// a write which has succeeded would make the written rows retried.
+fn check_resp_len(resp_len: usize, max_recv_msg_len: i32, endpoint: &str) -> Result<()> {
+    if max_recv_msg_len >= 0 && resp_len > max_recv_msg_len as usize {
+        return Err(Error::ResponseTooLarge {
+            limit: max_recv_msg_len as usize,
+            endpoint: endpoint.to_string(),
+            partial: None,
+        });
+    }
+
+    Ok(())
+}
+
+/// Map the grpc status to the error, and the status about the message size
+/// limit of server is mapped to [`Error::RequestTooLarge`] whose limit is 0 if
+/// it can't be found in the status message.
+fn map_status(status: Status, estimated_size: usize) -> Error {
+    if !matches!(status.code(), Code::ResourceExhausted | Code::OutOfRange) {
+        return Error::Rpc(status.into());
+    }
+
+    let msg = status.message();
+    let exceeds_limit = msg.contains("message length too large")
+        || msg.contains("received message larger than max")
+        || msg.contains("message exceeds maximum size");
+    if !exceeds_limit {
+        return Error::Rpc(status.into());
+    }
+
+    Error::RequestTooLarge {
+        limit: parse_size_limit(msg).unwrap_or(0),
+        estimated_size,
+    }
+}
+
+/// Parse the size limit from the status message of the servers, e.g.
+///  - tonic: `message length too large: found 10 bytes, the limit is: 4 bytes`
+///  - grpc-go: `grpc: received message larger than max (10 vs. 4)`
+fn parse_size_limit(msg: &str) -> Option<usize> {
+    let limit = if let Some((_, rest)) = msg.split_once("the limit is: ") {
+        rest.split(' ').next()?
+    } else {
+        let (_, rest) = msg.split_once(" vs. ")?;
+        rest.split(')').next()?
+    };
+
+    limit.trim().parse().ok()
+}
+
 struct RpcClientImpl {
     channel: Channel,
+    endpoint: String,
     default_read_timeout: Duration,
     default_write_timeout: Duration,
+    default_route_timeout: Duration,
+    compression: CompressionPolicy,
+    // Cleared if the server is found not supporting the compression.
+    compression_supported: AtomicBool,
+    max_send_msg_len: i32,
+    max_recv_msg_len: i32,
+    authenticator: Option<Arc<Authenticator>>,
+    interceptors: Interceptors,
+    session_settings: SessionSettings,
+    settings_transport: SettingsTransport,
 }
 
 impl RpcClientImpl {
     fn new(
         channel: Channel,
-        default_read_timeout: Duration,
-        default_write_timeout: Duration,
+        endpoint: String,
+        rpc_config: &RpcConfig,
+        authenticator: Option<Arc<Authenticator>>,
+        interceptors: Interceptors,
     ) -> Self {
         Self {
             channel,
-            default_read_timeout,
-            default_write_timeout,
+            endpoint,
+            default_read_timeout: rpc_config.default_sql_query_timeout,
+            default_write_timeout: rpc_config.default_write_timeout,
+            default_route_timeout: rpc_config.default_route_timeout,
+            compression: CompressionPolicy {
+                enabled: rpc_config.enable_compression,
+                min_size: rpc_config.compression_min_size,
+            },
+            compression_supported: AtomicBool::new(true),
+            max_send_msg_len: rpc_config.max_send_msg_len,
+            max_recv_msg_len: rpc_config.max_recv_msg_len,
+            authenticator,
+            interceptors,
+            session_settings: rpc_config.session_settings.clone(),
+            settings_transport: rpc_config.session_settings_transport,
+        }
+    }
+
+    /// Make the grpc client for sending `msg`, which is compressed only if it
+    /// is large enough.
+    ///
+    /// Fail if `msg` exceeds the max send message length, which can be
+    /// overridden by the `ctx`, and the encoded length of `msg` is returned
+    /// with the client.
+    fn make_client<M: Message>(
+        &self,
+        ctx: &RpcContext,
+        msg: &M,
+    ) -> Result<(StorageServiceClient<Channel>, usize)> {
+        let msg_len = msg.encoded_len();
+        let max_send_msg_len = ctx
+            .max_send_msg_len_override
+            .unwrap_or(self.max_send_msg_len);
+        check_msg_len(msg_len, max_send_msg_len)?;
+
+        let mut client = StorageServiceClient::<Channel>::new(self.channel.clone());
+        if self.compression_enabled() {
+            client = client.accept_compressed(CompressionEncoding::Gzip);
+            if let Some(encoding) = self.compression.send_encoding(msg_len) {
+                client = client.send_compressed(encoding);
+            }
         }
+
+        Ok((client, msg_len))
+    }
+
+    /// Whether the compression is enabled and not found unsupported by the
+    /// server.
+    fn compression_enabled(&self) -> bool {
+        self.compression.enabled && self.compression_supported.load(Ordering::Relaxed)
+    }
+
+    fn check_resp_len<M: Message>(&self, resp: &M) -> Result<()> {
+        check_resp_len(resp.encoded_len(), self.max_recv_msg_len, &self.endpoint)
     }
 
     fn check_status(header: ResponseHeader) -> Result<()> {
@@ -55,84 +364,338 @@ impl RpcClientImpl {
         Ok(())
     }
 
-    fn make_request<T>(ctx: &RpcContext, req: T, default_timeout: Duration) -> Request<T> {
-        let timeout = ctx.timeout.unwrap_or(default_timeout);
+    fn make_request<T>(req: T, timeout: Duration, metadata: &MetadataMap) -> Request<T> {
         let mut req = Request::new(req);
+        *req.metadata_mut() = metadata.clone();
         req.set_timeout(timeout);
         req
     }
 
-    fn make_query_request<T>(&self, ctx: &RpcContext, req: T) -> Request<T> {
-        Self::make_request(ctx, req, self.default_read_timeout)
-    }
+    /// Call the rpc with `req` of `req_len` bytes and the `metadata`, and the
+    /// token is injected into the request if the authenticator is set.
+    async fn call<T, O, C, Fut>(
+        &self,
+        req: T,
+        timeout: Duration,
+        req_len: usize,
+        metadata: &MetadataMap,
+        call: C,
+    ) -> Result<O>
+    where
+        T: Clone,
+        C: Fn(Request<T>) -> Fut,
+        Fut: Future<Output = std::result::Result<O, Status>>,
+    {
+        let result = match &self.authenticator {
+            Some(authenticator) => {
+                authenticator
+                    .call(|token| {
+                        let mut req = Self::make_request(req.clone(), timeout, metadata);
+                        req.metadata_mut().insert(AUTHORIZATION_KEY, token);
+                        call(req)
+                    })
+                    .await?
+            }
+            None => call(Self::make_request(req, timeout, metadata)).await,
+        };
 
-    fn make_write_request<T>(&self, ctx: &RpcContext, req: T) -> Request<T> {
-        Self::make_request(ctx, req, self.default_write_timeout)
+        result.map_err(|e| map_status(e, req_len))
     }
 }
 
 #[async_trait]
 impl RpcClient for RpcClientImpl {
-    async fn sql_query(&self, ctx: &RpcContext, req: SqlQueryRequest) -> Result<SqlQueryResponse> {
-        let mut client = StorageServiceClient::<Channel>::new(self.channel.clone());
+    async fn sql_query(
+        &self,
+        ctx: &RpcContext,
+        req: SqlQueryRequest,
+    ) -> Result<RpcResponse<SqlQueryResponse>> {
+        self.interceptors
+            .run(ctx, OperationKind::SqlQuery, |ctx, metadata| {
+                self.sql_query_intercepted(ctx, metadata, req)
+            })
+            .await
+    }
 
-        let resp = client
-            .sql_query(self.make_query_request(ctx, req))
+    async fn write(
+        &self,
+        ctx: &RpcContext,
+        req: WriteRequestPb,
+    ) -> Result<RpcResponse<WriteResponsePb>> {
+        self.interceptors
+            .run(ctx, OperationKind::Write, |ctx, metadata| {
+                self.write_intercepted(ctx, metadata, req)
+            })
+            .await
+    }
+
+    async fn route(&self, ctx: &RpcContext, req: RouteRequestPb) -> Result<RouteResponse> {
+        self.interceptors
+            .run(ctx, OperationKind::Route, |ctx, metadata| {
+                self.route_intercepted(ctx, metadata, req)
+            })
             .await
-            .map_err(Error::Rpc)?;
+    }
+
+    async fn stream_write(
+        &self,
+        ctx: &RpcContext,
+        reqs: UnboundedReceiver<WriteRequestPb>,
+    ) -> Result<RpcResponse<WriteResponsePb>> {
+        self.interceptors
+            .run(ctx, OperationKind::StreamWrite, |ctx, metadata| {
+                self.stream_write_intercepted(ctx, metadata, reqs)
+            })
+            .await
+    }
+
+    /// Probe the compression before the streaming write, which is sent
+    /// uncompressed if the compression is found unsupported. The probes are
+    /// bounded by the route timeout unless the timeout is set in the `ctx`,
+    /// and they skip the interceptors since they are not the requests of the
+    /// user.
+    async fn probe_capabilities(&self, ctx: &RpcContext) -> Result<ServerCapabilities> {
+        let mut ctx = ctx.clone();
+        ctx.timeout.get_or_insert(self.default_route_timeout);
+        let compression = self.compression.enabled && self.probe_compression(&ctx).await?;
+        self.compression_supported
+            .store(compression, Ordering::Relaxed);
+
+        // The stream ends without any request, so nothing is written.
+        let (_, reqs) = mpsc::unbounded();
+        let stream_write = self
+            .stream_write_intercepted(ctx, MetadataMap::new(), reqs)
+            .await;
+        Ok(ServerCapabilities {
+            stream_write: probe_outcome(stream_write)?,
+            compression,
+        })
+    }
+}
+
+impl RpcClientImpl {
+    async fn sql_query_intercepted(
+        &self,
+        ctx: RpcContext,
+        mut metadata: MetadataMap,
+        mut req: SqlQueryRequest,
+    ) -> Result<RpcResponse<SqlQueryResponse>> {
+        let settings = resolve_settings(&self.session_settings, &ctx);
+        match self.settings_transport {
+            SettingsTransport::Metadata => insert_settings(&settings, &mut metadata)?,
+            SettingsTransport::Sql => {
+                insert_consistency(&ctx, &mut metadata);
+                req.sql = prepend_settings(&settings, req.sql)?;
+            }
+        }
+        insert_priority(&ctx, &mut metadata);
+        let (client, req_len) = self.make_client(&ctx, &req)?;
+
+        let timeout = ctx.timeout.unwrap_or(self.default_read_timeout);
+        let resp = self
+            .call(req, timeout, req_len, &metadata, |req| {
+                let mut client = client.clone();
+                async move { client.sql_query(req).await }
+            })
+            .await?;
+        let warnings = decode_warnings(resp.metadata());
         let mut resp = resp.into_inner();
+        self.check_resp_len(&resp)?;
 
         if let Some(header) = resp.header.take() {
             Self::check_status(header)?;
         }
 
-        Ok(resp)
+        Ok(RpcResponse { warnings, resp })
     }
 
-    async fn write(&self, ctx: &RpcContext, req: WriteRequestPb) -> Result<WriteResponsePb> {
-        let mut client = StorageServiceClient::<Channel>::new(self.channel.clone());
+    async fn write_intercepted(
+        &self,
+        ctx: RpcContext,
+        mut metadata: MetadataMap,
+        req: WriteRequestPb,
+    ) -> Result<RpcResponse<WriteResponsePb>> {
+        insert_consistency(&ctx, &mut metadata);
+        insert_priority(&ctx, &mut metadata);
+        let (client, req_len) = self.make_client(&ctx, &req)?;
 
-        let resp = client
-            .write(self.make_write_request(ctx, req))
-            .await
-            .map_err(Error::Rpc)?;
+        let timeout = ctx.timeout.unwrap_or(self.default_write_timeout);
+        let resp = self
+            .call(req, timeout, req_len, &metadata, |req| {
+                let mut client = client.clone();
+                async move { client.write(req).await }
+            })
+            .await?;
+        let warnings = decode_warnings(resp.metadata());
         let mut resp = resp.into_inner();
 
         if let Some(header) = resp.header.take() {
             Self::check_status(header)?;
         }
 
-        Ok(resp)
+        Ok(RpcResponse { warnings, resp })
     }
 
-    async fn route(&self, ctx: &RpcContext, req: RouteRequestPb) -> Result<RouteResponsePb> {
+    /// Write the stream of requests, and no timeout is set unless it is set in
+    /// the `ctx`, because the stream may last long.
+    ///
+    /// The messages are compressed if compression is enabled, regardless of
+    /// their sizes.
+    async fn stream_write_intercepted(
+        &self,
+        ctx: RpcContext,
+        mut metadata: MetadataMap,
+        reqs: UnboundedReceiver<WriteRequestPb>,
+    ) -> Result<RpcResponse<WriteResponsePb>> {
+        insert_consistency(&ctx, &mut metadata);
+        insert_priority(&ctx, &mut metadata);
         let mut client = StorageServiceClient::<Channel>::new(self.channel.clone());
+        if self.compression_enabled() {
+            client = client
+                .accept_compressed(CompressionEncoding::Gzip)
+                .send_compressed(CompressionEncoding::Gzip);
+        }
 
-        // use the write timeout for the route request.
-        let route_req = Self::make_request(ctx, req, self.default_write_timeout);
-        let resp = client.route(route_req).await.map_err(Error::Rpc)?;
+        let mut req = Request::new(reqs);
+        *req.metadata_mut() = metadata;
+        if let Some(timeout) = ctx.timeout {
+            req.set_timeout(timeout);
+        }
+        let result = match &self.authenticator {
+            Some(authenticator) => {
+                authenticator
+                    .call_once(|token| {
+                        req.metadata_mut().insert(AUTHORIZATION_KEY, token);
+                        async move { client.stream_write(req).await }
+                    })
+                    .await?
+            }
+            None => client.stream_write(req).await,
+        };
+        let resp = result.map_err(|e| map_status(e, 0))?;
+        let warnings = decode_warnings(resp.metadata());
         let mut resp = resp.into_inner();
 
         if let Some(header) = resp.header.take() {
             Self::check_status(header)?;
         }
 
-        Ok(resp)
+        Ok(RpcResponse { warnings, resp })
+    }
+
+    /// Probe the compression by a compressed route request without any table,
+    /// which is rejected as unimplemented by the server not supporting the
+    /// compression.
+    async fn probe_compression(&self, ctx: &RpcContext) -> Result<bool> {
+        let req = RouteRequestPb {
+            context: Some(RequestContext {
+                database: ctx.database.clone().unwrap_or_default(),
+            }),
+            tables: Vec::new(),
+        };
+        let client = StorageServiceClient::<Channel>::new(self.channel.clone())
+            .accept_compressed(CompressionEncoding::Gzip)
+            .send_compressed(CompressionEncoding::Gzip);
+        let timeout = ctx.timeout.unwrap_or(self.default_route_timeout);
+        let req_len = req.encoded_len();
+        let result = self
+            .call(req, timeout, req_len, &MetadataMap::new(), |req| {
+                let mut client = client.clone();
+                async move { client.route(req).await }
+            })
+            .await;
+
+        probe_outcome(result)
+    }
+
+    async fn route_intercepted(
+        &self,
+        ctx: RpcContext,
+        mut metadata: MetadataMap,
+        req: RouteRequestPb,
+    ) -> Result<RouteResponse> {
+        insert_consistency(&ctx, &mut metadata);
+        insert_priority(&ctx, &mut metadata);
+        let (client, req_len) = self.make_client(&ctx, &req)?;
+
+        let timeout = ctx.timeout.unwrap_or(self.default_route_timeout);
+        let resp = self
+            .call(req, timeout, req_len, &metadata, |req| {
+                let mut client = client.clone();
+                async move { client.route(req).await }
+            })
+            .await?;
+        let epoch = resp
+            .metadata()
+            .get(ROUTE_EPOCH_KEY)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse().ok());
+        let mut resp: RouteResponsePb = resp.into_inner();
+
+        if let Some(header) = resp.header.take() {
+            Self::check_status(header)?;
+        }
+
+        Ok(RouteResponse { epoch, resp })
     }
 }
 
 pub struct RpcClientImplFactory {
     rpc_config: RpcConfig,
+    authenticator: Option<Arc<Authenticator>>,
+    interceptors: Interceptors,
+    capture: Option<Arc<dyn RequestCapture>>,
+    metrics: Option<ClientMetrics>,
+    spawner: Spawner,
 }
 
 impl RpcClientImplFactory {
-    pub fn new(rpc_config: RpcConfig) -> Self {
-        Self { rpc_config }
+    pub fn new(
+        rpc_config: RpcConfig,
+        authenticator: Option<Arc<Authenticator>>,
+        interceptors: Interceptors,
+    ) -> Self {
+        Self {
+            rpc_config,
+            authenticator,
+            interceptors,
+            capture: None,
+            metrics: None,
+            spawner: Spawner::default(),
+        }
+    }
+
+    /// Connect on the runtime of the `spawner`, so that the tasks driving the
+    /// channels live as long as it instead of the runtime building them.
+    pub(crate) fn with_spawner(mut self, spawner: Spawner) -> Self {
+        self.spawner = spawner;
+        self
+    }
+
+    /// Pass the requests to the `capture` before sending them, and nothing is
+    /// captured by default.
+    pub fn with_capture(mut self, capture: Option<Arc<dyn RequestCapture>>) -> Self {
+        self.capture = capture;
+        self
     }
 
-    #[inline]
-    fn make_endpoint_with_scheme(endpoint: &str) -> String {
-        format!("http://{endpoint}")
+    /// Record the rpcs of the built clients in the `metrics`, including the
+    /// ones aborted by the interceptors.
+    pub(crate) fn with_metrics(mut self, metrics: Option<ClientMetrics>) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// The interceptors of the client to the `endpoint`, and the metrics are
+    /// recorded around the registered ones.
+    fn endpoint_interceptors(&self, endpoint: &str) -> Interceptors {
+        match &self.metrics {
+            Some(metrics) => Interceptors::new()
+                .with_clock(self.interceptors.clock())
+                .with(Arc::new(metrics.interceptor(endpoint.to_string())))
+                .with(Arc::new(self.interceptors.clone())),
+            None => self.interceptors.clone(),
+        }
     }
 }
 
@@ -140,12 +703,20 @@ impl RpcClientImplFactory {
 impl RpcClientFactory for RpcClientImplFactory {
     /// The endpoint should be in the form: `{ip_addr}:{port}`.
     async fn build(&self, endpoint: String) -> Result<Arc<dyn RpcClient>> {
-        let endpoint_with_scheme = Self::make_endpoint_with_scheme(&endpoint);
-        let configured_endpoint =
-            Endpoint::from_shared(endpoint_with_scheme).map_err(|e| Error::Connect {
+        let route_endpoint = endpoint
+            .parse::<RouteEndpoint>()
+            .map_err(|e| Error::Connect {
                 addr: endpoint.clone(),
-                source: Box::new(e),
+                source: e,
             })?;
+        let uri = route_endpoint.to_uri(false).map_err(|e| Error::Connect {
+            addr: endpoint.clone(),
+            source: e.into(),
+        })?;
+        let proxy = resolve_proxy(self.rpc_config.proxy.as_ref(), &uri, |name| {
+            std::env::var(name).ok()
+        });
+        let configured_endpoint = Endpoint::from(uri);
 
         let configured_endpoint = match self.rpc_config.keep_alive_while_idle {
             true => configured_endpoint
@@ -157,17 +728,501 @@ impl RpcClientFactory for RpcClientImplFactory {
                 .connect_timeout(self.rpc_config.connect_timeout)
                 .keep_alive_while_idle(false),
         };
-        let channel = configured_endpoint
-            .connect()
-            .await
+        let connector = proxy.map(|proxy| ProxyConnector::new(&proxy)).transpose()?;
+        let connect = async move {
+            match connector {
+                Some(connector) => configured_endpoint.connect_with_connector(connector).await,
+                None => configured_endpoint.connect().await,
+            }
+        };
+        let channel = self
+            .spawner
+            .run(connect)
+            .await?
             .map_err(|e| Error::Connect {
-                addr: endpoint,
+                addr: endpoint.clone(),
                 source: Box::new(e),
             })?;
-        Ok(Arc::new(RpcClientImpl::new(
+        let interceptors = self.endpoint_interceptors(&endpoint);
+        let client = Arc::new(RpcClientImpl::new(
             channel,
-            self.rpc_config.default_sql_query_timeout,
-            self.rpc_config.default_write_timeout,
-        )))
+            endpoint,
+            &self.rpc_config,
+            self.authenticator.clone(),
+            interceptors,
+        ));
+
+        Ok(match &self.capture {
+            Some(capture) => Arc::new(CapturingRpcClient::new(
+                client,
+                route_endpoint,
+                capture.clone(),
+            )),
+            None => client,
+        })
+    }
+
+    fn config(&self) -> RpcConfig {
+        self.rpc_config.clone()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        convert::Infallible,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use ceresdbproto::storage::{
+        sql_query_response::Output as OutputPb, ArrowPayload, SqlQueryRequest, SqlQueryResponse,
+        WriteRequest as WriteRequestPb, WriteResponse as WriteResponsePb, WriteTableRequest,
+    };
+    use futures::{future, stream};
+    use prost::Message;
+    use tokio::net::TcpListener;
+    use tonic::{
+        body::BoxBody,
+        codec::{CompressionEncoding, ProstCodec},
+        codegen::{http, BoxFuture, Context, Poll, Service},
+        metadata::MetadataMap,
+        server::{Grpc, NamedService, UnaryService},
+        transport::{Body, Endpoint, Server},
+        Code, Status,
+    };
+
+    use super::{
+        check_msg_len, check_resp_len, insert_consistency, insert_priority, insert_settings,
+        map_status, prepend_settings, resolve_settings, CompressionPolicy, RpcClientImpl,
+        CONSISTENCY_KEY, PRIORITY_KEY, TIMEZONE_KEY,
+    };
+    use crate::{
+        errors::Error,
+        interceptor::Interceptors,
+        rpc_client::{Consistency, Priority, RpcClient, SessionSettings},
+        RpcConfig, RpcContext,
+    };
+
+    /// In-process storage service answering every sql query by `query_resp`
+    /// and every write by `write_result`, and the writes are counted.
+    #[derive(Clone)]
+    struct TestServer {
+        query_resp: SqlQueryResponse,
+        write_result: Result<WriteResponsePb, Status>,
+        writes: Arc<AtomicUsize>,
+    }
+
+    impl TestServer {
+        fn new(query_resp: SqlQueryResponse) -> Self {
+            Self {
+                query_resp,
+                write_result: Ok(WriteResponsePb {
+                    header: None,
+                    success: 1,
+                    failed: 0,
+                }),
+                writes: Arc::new(AtomicUsize::new(0)),
+            }
+        }
+
+        /// Serve on a random port, and connect the client configured by
+        /// `rpc_config` to it.
+        async fn connect(&self, rpc_config: &RpcConfig) -> RpcClientImpl {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let incoming = stream::unfold(listener, |listener| async move {
+                let conn = listener.accept().await.map(|(conn, _)| conn);
+                Some((conn, listener))
+            });
+            let server = Server::builder()
+                .add_service(self.clone())
+                .serve_with_incoming(incoming);
+            tokio::spawn(server);
+
+            let channel = Endpoint::from_shared(format!("http://{addr}"))
+                .unwrap()
+                .connect()
+                .await
+                .unwrap();
+            RpcClientImpl::new(
+                channel,
+                addr.to_string(),
+                rpc_config,
+                None,
+                Interceptors::default(),
+            )
+        }
+    }
+
+    /// Handler of the unary requests to the [`TestServer`].
+    struct Unary<F>(F);
+
+    impl<Req, Resp, F> UnaryService<Req> for Unary<F>
+    where
+        F: FnMut(Req) -> Result<Resp, Status>,
+    {
+        type Future = future::Ready<Result<tonic::Response<Resp>, Status>>;
+        type Response = Resp;
+
+        fn call(&mut self, req: tonic::Request<Req>) -> Self::Future {
+            future::ready((self.0)(req.into_inner()).map(tonic::Response::new))
+        }
+    }
+
+    impl NamedService for TestServer {
+        const NAME: &'static str = "storage.StorageService";
+    }
+
+    impl Service<http::Request<Body>> for TestServer {
+        type Error = Infallible;
+        type Future = BoxFuture<Self::Response, Self::Error>;
+        type Response = http::Response<BoxBody>;
+
+        fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, req: http::Request<Body>) -> Self::Future {
+            let server = self.clone();
+            Box::pin(async move {
+                let resp = match req.uri().path() {
+                    "/storage.StorageService/SqlQuery" => {
+                        let query = Unary(|_: SqlQueryRequest| Ok(server.query_resp.clone()));
+                        Grpc::new(ProstCodec::default()).unary(query, req).await
+                    }
+                    "/storage.StorageService/Write" => {
+                        let write = Unary(|_: WriteRequestPb| {
+                            server.writes.fetch_add(1, Ordering::Relaxed);
+                            server.write_result.clone()
+                        });
+                        Grpc::new(ProstCodec::default()).unary(write, req).await
+                    }
+                    path => Status::unimplemented(path).to_http(),
+                };
+                Ok(resp)
+            })
+        }
+    }
+
+    fn write_request() -> WriteRequestPb {
+        WriteRequestPb {
+            context: None,
+            table_requests: vec![WriteTableRequest {
+                table: "test_table".to_string(),
+                ..Default::default()
+            }],
+        }
+    }
+
+    #[test]
+    fn test_compression_policy() {
+        let policy = CompressionPolicy {
+            enabled: true,
+            min_size: 1024,
+        };
+        assert_eq!(policy.send_encoding(0), None);
+        assert_eq!(policy.send_encoding(1023), None);
+        assert_eq!(policy.send_encoding(1024), Some(CompressionEncoding::Gzip));
+
+        let policy = CompressionPolicy {
+            enabled: false,
+            min_size: 0,
+        };
+        assert_eq!(policy.send_encoding(1 << 20), None);
+    }
+
+    #[test]
+    fn test_insert_consistency() {
+        let mut metadata = MetadataMap::new();
+        insert_consistency(&RpcContext::default(), &mut metadata);
+        assert!(metadata.is_empty());
+
+        for (consistency, expected) in [
+            (Consistency::Strong, "strong"),
+            (Consistency::Eventual, "eventual"),
+        ] {
+            let ctx = RpcContext::default().consistency(consistency);
+            insert_consistency(&ctx, &mut metadata);
+            assert_eq!(metadata.len(), 1);
+            assert_eq!(metadata.get(CONSISTENCY_KEY).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn test_insert_priority() {
+        let mut metadata = MetadataMap::new();
+        insert_priority(&RpcContext::default(), &mut metadata);
+        assert_eq!(metadata.get(PRIORITY_KEY).unwrap(), "normal");
+
+        for (priority, expected) in [(Priority::Low, "low"), (Priority::High, "high")] {
+            let ctx = RpcContext::default().priority(priority);
+            insert_priority(&ctx, &mut metadata);
+            assert_eq!(metadata.len(), 1);
+            assert_eq!(metadata.get(PRIORITY_KEY).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn test_resolve_settings() {
+        let defaults = SessionSettings::default()
+            .timezone("UTC".to_string())
+            .read_consistency(Consistency::Eventual)
+            .setting("a".to_string(), "1".to_string())
+            .setting("b".to_string(), "2".to_string());
+        assert_eq!(
+            resolve_settings(&defaults, &RpcContext::default()),
+            defaults
+        );
+
+        // The settings of the context win.
+        let ctx = RpcContext::default().settings(
+            SessionSettings::default()
+                .timezone("Asia/Shanghai".to_string())
+                .setting("b".to_string(), "3".to_string()),
+        );
+        let settings = resolve_settings(&defaults, &ctx);
+        assert_eq!(settings.timezone.as_deref(), Some("Asia/Shanghai"));
+        assert_eq!(settings.read_consistency, Some(Consistency::Eventual));
+        assert_eq!(settings.extra["a"], "1");
+        assert_eq!(settings.extra["b"], "3");
+
+        // The consistency of the context wins over the settings.
+        let ctx = ctx
+            .settings(SessionSettings::default().read_consistency(Consistency::Eventual))
+            .consistency(Consistency::Strong);
+        let settings = resolve_settings(&defaults, &ctx);
+        assert_eq!(settings.timezone.as_deref(), Some("UTC"));
+        assert_eq!(settings.read_consistency, Some(Consistency::Strong));
+    }
+
+    #[test]
+    fn test_insert_settings() {
+        let mut metadata = MetadataMap::new();
+        insert_settings(&SessionSettings::default(), &mut metadata).unwrap();
+        assert!(metadata.is_empty());
+
+        let settings = SessionSettings::default()
+            .timezone("UTC".to_string())
+            .read_consistency(Consistency::Strong)
+            .setting("Query_Limit".to_string(), "10".to_string());
+        insert_settings(&settings, &mut metadata).unwrap();
+        assert_eq!(metadata.len(), 3);
+        assert_eq!(metadata.get(TIMEZONE_KEY).unwrap(), "UTC");
+        assert_eq!(metadata.get(CONSISTENCY_KEY).unwrap(), "strong");
+        assert_eq!(metadata.get("x-ceresdb-setting-query_limit").unwrap(), "10");
+
+        for settings in [
+            SessionSettings::default().setting("a-b".to_string(), "1".to_string()),
+            SessionSettings::default().setting("a".to_string(), "\n".to_string()),
+        ] {
+            assert!(matches!(
+                insert_settings(&settings, &mut MetadataMap::new()),
+                Err(Error::Client(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_prepend_settings() {
+        let sql = "SELECT * FROM t".to_string();
+        assert_eq!(
+            prepend_settings(&SessionSettings::default(), sql.clone()).unwrap(),
+            sql
+        );
+
+        let settings = SessionSettings::default()
+            .timezone("UTC".to_string())
+            .read_consistency(Consistency::Eventual)
+            .setting("b".to_string(), "it's".to_string())
+            .setting("a".to_string(), "1".to_string());
+        assert_eq!(
+            prepend_settings(&settings, sql.clone()).unwrap(),
+            "SET timezone = 'UTC'; SET read_consistency = 'eventual'; SET a = '1'; SET b = \
+             'it''s'; SELECT * FROM t"
+        );
+
+        let settings = SessionSettings::default().setting("a; DROP".to_string(), "1".to_string());
+        assert!(matches!(
+            prepend_settings(&settings, sql),
+            Err(Error::Client(_))
+        ));
+    }
+
+    #[test]
+    fn test_query_timeout() {
+        let ctx = RpcContext::default()
+            .settings(SessionSettings::default().timezone("UTC".to_string()))
+            .query_timeout(Duration::from_micros(1_500_900));
+        assert_eq!(ctx.timeout, Some(Duration::from_micros(1_500_900)));
+        let settings = resolve_settings(&SessionSettings::default(), &ctx);
+        assert_eq!(settings.timezone.as_deref(), Some("UTC"));
+
+        let mut metadata = MetadataMap::new();
+        insert_settings(&settings, &mut metadata).unwrap();
+        assert_eq!(
+            metadata.get("x-ceresdb-setting-query_timeout").unwrap(),
+            "1500ms"
+        );
+        assert_eq!(
+            prepend_settings(&settings, "SELECT 1".to_string()).unwrap(),
+            "SET timezone = 'UTC'; SET query_timeout = '1500ms'; SELECT 1"
+        );
+    }
+
+    #[test]
+    fn test_check_msg_len() {
+        assert!(check_msg_len(1024, 1024).is_ok());
+        assert!(matches!(
+            check_msg_len(1025, 1024),
+            Err(Error::RequestTooLarge {
+                limit: 1024,
+                estimated_size: 1025
+            })
+        ));
+        assert!(check_msg_len(usize::MAX, -1).is_ok());
+    }
+
+    #[test]
+    fn test_check_resp_len() {
+        assert!(check_resp_len(1024, 1024, "127.0.0.1:8831").is_ok());
+        assert!(check_resp_len(usize::MAX, -1, "127.0.0.1:8831").is_ok());
+
+        let err = check_resp_len(1025, 1024, "127.0.0.1:8831").unwrap_err();
+        assert!(err.to_string().contains("sql_query_paged"));
+        match err {
+            Error::ResponseTooLarge {
+                limit,
+                endpoint,
+                partial,
+            } => {
+                assert_eq!(limit, 1024);
+                assert_eq!(endpoint, "127.0.0.1:8831");
+                assert!(partial.is_none());
+            }
+            e => panic!("unexpected error:{e}"),
+        }
+    }
+
+    #[test]
+    fn test_map_status() {
+        let statuses = [
+            Status::out_of_range(
+                "Error, message length too large: found 10 bytes, the limit is: 4 bytes",
+            ),
+            Status::resource_exhausted("grpc: received message larger than max (10 vs. 4)"),
+        ];
+        for status in statuses {
+            assert!(matches!(
+                map_status(status, 10),
+                Error::RequestTooLarge {
+                    limit: 4,
+                    estimated_size: 10
+                }
+            ));
+        }
+
+        let status = Status::resource_exhausted("gRPC message exceeds maximum size");
+        assert!(matches!(
+            map_status(status, 10),
+            Error::RequestTooLarge {
+                limit: 0,
+                estimated_size: 10
+            }
+        ));
+
+        let status = Status::resource_exhausted("too many requests");
+        assert!(
+            matches!(map_status(status, 10), Error::Rpc(s) if s.code() == Code::ResourceExhausted)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_response_too_large() {
+        let query_resp = SqlQueryResponse {
+            header: None,
+            output: Some(OutputPb::Arrow(ArrowPayload {
+                record_batches: vec![vec![0; 64]],
+                compression: 0,
+            })),
+        };
+        let resp_len = query_resp.encoded_len();
+        let server = TestServer::new(query_resp);
+
+        let client = server.connect(&RpcConfig::default()).await;
+        let resp = client
+            .sql_query(&RpcContext::default(), SqlQueryRequest::default())
+            .await
+            .unwrap();
+        assert_eq!(resp.resp.encoded_len(), resp_len);
+
+        let rpc_config = RpcConfig {
+            max_recv_msg_len: 16,
+            ..Default::default()
+        };
+        let client = server.connect(&rpc_config).await;
+        let err = client
+            .sql_query(&RpcContext::default(), SqlQueryRequest::default())
+            .await
+            .unwrap_err();
+        match err {
+            Error::ResponseTooLarge {
+                limit, endpoint, ..
+            } => {
+                assert_eq!(limit, 16);
+                assert_eq!(endpoint, client.endpoint);
+            }
+            e => panic!("unexpected error:{e}"),
+        }
+
+        // The write responses are not checked, so the written rows are not
+        // retried.
+        let rpc_config = RpcConfig {
+            max_recv_msg_len: 0,
+            ..Default::default()
+        };
+        let client = server.connect(&rpc_config).await;
+        let resp = client
+            .write(&RpcContext::default(), write_request())
+            .await
+            .unwrap();
+        assert_eq!(resp.resp.success, 1);
+    }
+
+    #[tokio::test]
+    async fn test_request_too_large() {
+        let req_len = write_request().encoded_len();
+        let mut server = TestServer::new(SqlQueryResponse::default());
+
+        let rpc_config = RpcConfig {
+            max_send_msg_len: 4,
+            ..Default::default()
+        };
+        let client = server.connect(&rpc_config).await;
+        let err = client
+            .write(&RpcContext::default(), write_request())
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(err, Error::RequestTooLarge { limit: 4, estimated_size } if estimated_size == req_len)
+        );
+        assert_eq!(server.writes.load(Ordering::Relaxed), 0);
+
+        // Rejected by the size limit of the server.
+        server.write_result = Err(Status::resource_exhausted(format!(
+            "grpc: received message larger than max ({req_len} vs. 4)"
+        )));
+        let client = server.connect(&RpcConfig::default()).await;
+        let err = client
+            .write(&RpcContext::default(), write_request())
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(err, Error::RequestTooLarge { limit: 4, estimated_size } if estimated_size == req_len)
+        );
+        assert_eq!(server.writes.load(Ordering::Relaxed), 1);
     }
 }
@@ -0,0 +1,86 @@
+// Copyright 2022 CeresDB Project Authors. Licensed under Apache-2.0.
+
+//! Runtime of the background tasks of the client
+
+use std::future::Future;
+
+use tokio::{runtime::Handle, task::JoinHandle};
+
+use crate::{Error, Result};
+
+/// Spawner of the background tasks on the runtime of the `handle`, or on the
+/// current runtime when the task is spawned if it is none.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Spawner {
+    handle: Option<Handle>,
+}
+
+impl Spawner {
+    pub fn new(handle: Option<Handle>) -> Self {
+        Self { handle }
+    }
+
+    /// Spawn on the given runtime, or the current one if none is given, and it
+    /// must be called in the context of a tokio runtime in that case.
+    pub fn spawn<Fut>(&self, future: Fut) -> JoinHandle<Fut::Output>
+    where
+        Fut: Future + Send + 'static,
+        Fut::Output: Send + 'static,
+    {
+        match &self.handle {
+            Some(handle) => handle.spawn(future),
+            None => tokio::spawn(future),
+        }
+    }
+
+    /// Run the `future` on the given runtime, e.g. so that the tasks spawned by
+    /// it live as long as that runtime instead of the calling one, or in place
+    /// if none is given.
+    pub async fn run<Fut>(&self, future: Fut) -> Result<Fut::Output>
+    where
+        Fut: Future + Send + 'static,
+        Fut::Output: Send + 'static,
+    {
+        match &self.handle {
+            Some(handle) => handle
+                .spawn(future)
+                .await
+                .map_err(|e| Error::Client(format!("task on the client runtime failed, err:{e}"))),
+            None => Ok(future.await),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Spawner;
+
+    #[test]
+    fn test_spawn_on_handle() {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .enable_all()
+            .build()
+            .unwrap();
+        let spawner = Spawner::new(Some(runtime.handle().clone()));
+
+        // The calling runtime is gone before the task completes.
+        let other = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let (tx, rx) = std::sync::mpsc::channel();
+        other.block_on(async {
+            spawner.spawn(async move {
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+                tx.send(std::thread::current().id()).unwrap();
+            });
+        });
+        drop(other);
+        let worker = rx.recv().unwrap();
+        assert_ne!(worker, std::thread::current().id());
+
+        let output = runtime.block_on(spawner.run(async { 42 })).unwrap();
+        assert_eq!(output, 42);
+    }
+}
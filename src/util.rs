@@ -2,6 +2,8 @@
 
 //! Utils in client
 
+use crate::Result;
+
 /// Server status code
 #[allow(dead_code)]
 #[derive(Debug, Clone, Copy)]
@@ -27,7 +29,58 @@ pub fn is_ok(code: u32) -> bool {
 // TODO may change in future.
 #[inline]
 pub fn should_refresh(code: u32, msg: &str) -> bool {
+    is_table_not_found(code, msg)
+}
+
+#[inline]
+pub fn is_table_not_found(code: u32, msg: &str) -> bool {
     code == StatusCode::InvalidArgument.as_u32()
         && msg.contains("Table")
         && msg.contains("not found")
 }
+
+/// Table of the CRC-32 (IEEE) by the bytes.
+const CRC32_TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// Compute the CRC-32 (IEEE) checksum of the `bytes`.
+pub fn crc32(bytes: &[u8]) -> u32 {
+    !bytes.iter().fold(!0u32, |crc, byte| {
+        CRC32_TABLE[((crc ^ *byte as u32) & 0xFF) as usize] ^ (crc >> 8)
+    })
+}
+
+/// Record the outcome of the operation in the field `outcome` of the current
+/// span.
+///
+/// The spans of the operations are the children of the current span of the
+/// caller, since the [`RpcContext`](crate::rpc_client::RpcContext) carries no
+/// metadata yet, and no trace context propagated from another process can be
+/// extracted from it.
+#[cfg(feature = "tracing")]
+#[inline]
+pub fn record_span_outcome<T>(result: &Result<T>) {
+    let outcome = if result.is_ok() { "ok" } else { "error" };
+    tracing::Span::current().record("outcome", outcome);
+}
+
+#[cfg(not(feature = "tracing"))]
+#[inline]
+pub fn record_span_outcome<T>(_result: &Result<T>) {}